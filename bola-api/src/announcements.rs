@@ -0,0 +1,635 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Error};
+use aws_sdk_dynamodb::model::{AttributeValue, ReturnConsumedCapacity, ReturnValue};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use log::error;
+use mangle_api_core::{
+    distributed::Node,
+    openapi::{enum_schema, object_schema, ApiSchema, OpenApi, Operation},
+    pagination::{Page, Pagination},
+    parking_lot::RwLock,
+    rand::{distributions::Alphanumeric, thread_rng, Rng},
+    serde_json::Value,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select, spawn,
+    sync::{
+        broadcast::{channel, Receiver, Sender},
+        Notify,
+    },
+    time::sleep,
+};
+
+use crate::{
+    budget::OperationClass,
+    db::DB,
+    network::{AnnouncementUpdate, NetworkMessage, SiblingNetworkHandler},
+    state::GlobalState,
+};
+
+const ANNOUNCEMENT_BUFFER_SIZE: usize = 8;
+const ANNOUNCEMENT_ID_LENGTH: usize = 12;
+const FALLBACK_LOCALE: &str = "en";
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Audience {
+    #[serde(rename = "all")]
+    All,
+    #[serde(rename = "logged_in")]
+    LoggedIn,
+    #[serde(rename = "guests")]
+    Guests,
+}
+
+impl Audience {
+    fn name(self) -> &'static str {
+        match self {
+            Audience::All => "all",
+            Audience::LoggedIn => "logged_in",
+            Audience::Guests => "guests",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "all" => Some(Audience::All),
+            "logged_in" => Some(Audience::LoggedIn),
+            "guests" => Some(Audience::Guests),
+            _ => None,
+        }
+    }
+
+    pub fn includes(self, logged_in: bool) -> bool {
+        match self {
+            Audience::All => true,
+            Audience::LoggedIn => logged_in,
+            Audience::Guests => !logged_in,
+        }
+    }
+}
+
+impl ApiSchema for Audience {
+    fn schema() -> Value {
+        enum_schema(&["all", "logged_in", "guests"])
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Announcement {
+    pub id: String,
+    /// Unix time in seconds
+    pub start_time: u64,
+    /// Unix time in seconds
+    pub end_time: u64,
+    pub audience: Audience,
+    /// The text of the announcement for each locale, such as `en` or `pt-BR`
+    pub messages: HashMap<String, String>,
+}
+
+impl ApiSchema for Announcement {
+    fn schema() -> Value {
+        object_schema(
+            [
+                ("id", String::schema()),
+                ("start_time", u64::schema()),
+                ("end_time", u64::schema()),
+                ("audience", Audience::schema()),
+                ("messages", HashMap::<String, String>::schema()),
+            ],
+            &["id", "start_time", "end_time", "audience", "messages"],
+        )
+    }
+}
+
+impl Announcement {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.start_time <= now && now < self.end_time
+    }
+
+    /// Picks the best text for the given locale, falling back to the language
+    /// of the locale, then to english, then to anything
+    pub fn localize(&self, locale: Option<&str>) -> Option<&str> {
+        if let Some(locale) = locale {
+            if let Some(msg) = self.messages.get(locale) {
+                return Some(msg);
+            }
+            if let Some((language, _)) = locale.split_once('-') {
+                if let Some(msg) = self.messages.get(language) {
+                    return Some(msg);
+                }
+            }
+        }
+        self.messages
+            .get(FALLBACK_LOCALE)
+            .or_else(|| self.messages.values().next())
+            .map(String::as_str)
+    }
+}
+
+#[derive(Serialize)]
+pub struct AnnouncementView<'a> {
+    pub announcement_id: &'a str,
+    pub announcement: &'a str,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+pub struct AnnouncementSubscription(Receiver<Arc<Announcement>>);
+
+impl AnnouncementSubscription {
+    /// Waits for the next announcement to be created
+    ///
+    /// Never returns if the sender has been dropped
+    pub async fn wait_for_announcement(&mut self) -> Arc<Announcement> {
+        loop {
+            match self.0.recv().await {
+                Ok(x) => break x,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                    std::future::pending::<()>().await
+                }
+            }
+        }
+    }
+}
+
+pub struct Announcements {
+    announcements: RwLock<Vec<Arc<Announcement>>>,
+    announcement_updater: Sender<Arc<Announcement>>,
+    /// Wakes `push_due` whenever an announcement is added
+    schedule_changed: Notify,
+    db: &'static DB,
+    node: &'static Node<SiblingNetworkHandler>,
+}
+
+impl Announcements {
+    pub async fn new(
+        db: &'static DB,
+        node: &'static Node<SiblingNetworkHandler>,
+    ) -> Result<&'static Self, Error> {
+        let now = now();
+        let announcements = Self::pull_announcements(db)
+            .await
+            .context("Pulling announcements")?
+            .into_iter()
+            .filter(|x| x.end_time > now)
+            .map(Arc::new)
+            .collect();
+
+        let announcements = manglext::immut_leak(Self {
+            announcements: RwLock::new(announcements),
+            announcement_updater: channel(ANNOUNCEMENT_BUFFER_SIZE).0,
+            schedule_changed: Notify::new(),
+            db,
+            node,
+        });
+        spawn(announcements.push_due());
+        let mut subscription = node.get_handler().subscribe_to_announcement_update();
+
+        spawn(async move {
+            loop {
+                let Some(update) = subscription.wait_for_update().await else {
+                    break;
+                };
+                match update {
+                    AnnouncementUpdate::Created(announcement) => {
+                        announcements.local_add(announcement)
+                    }
+                    AnnouncementUpdate::Removed(id) => {
+                        announcements.local_remove(&id);
+                    }
+                }
+            }
+        });
+
+        Ok(announcements)
+    }
+
+    async fn pull_announcements(db: &DB) -> Result<Vec<Announcement>, Error> {
+        let mut out = vec![];
+        let mut start_key = None;
+
+        loop {
+            let output = db
+                .client
+                .scan()
+                .table_name(db.bola_announcements_table.clone())
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+
+            for item in output.items().unwrap_or_default() {
+                out.push(Self::map_to_announcement(item)?);
+            }
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break Ok(out);
+            }
+        }
+    }
+
+    fn map_to_announcement(map: &HashMap<String, AttributeValue>) -> Result<Announcement, Error> {
+        macro_rules! field {
+            ($field:literal, $op:ident) => {
+                map.get($field).and_then(|x| x.$op().ok()).ok_or_else(|| {
+                    anyhow!("Could not deserialize field: {} in announcement", $field)
+                })?
+            };
+        }
+
+        let mut messages = HashMap::new();
+        for (locale, msg) in field!("messages", as_m) {
+            messages.insert(
+                locale.clone(),
+                msg.as_s()
+                    .map_err(|_| anyhow!("Message for {locale} is not a string"))?
+                    .clone(),
+            );
+        }
+
+        Ok(Announcement {
+            id: field!("id", as_s).clone(),
+            start_time: field!("start_time", as_n).parse()?,
+            end_time: field!("end_time", as_n).parse()?,
+            audience: Audience::from_name(field!("audience", as_s))
+                .ok_or_else(|| anyhow!("Unknown audience in announcement"))?,
+            messages,
+        })
+    }
+
+    fn local_add(&self, announcement: Announcement) {
+        let now = now();
+        {
+            let mut announcements = self.announcements.write();
+            announcements.retain(|x| x.end_time > now && x.id != announcement.id);
+            announcements.push(Arc::new(announcement));
+        }
+        self.schedule_changed.notify_one();
+    }
+
+    /// Pushes each announcement to connected clients once it starts, or as
+    /// soon as it is added if it already has
+    async fn push_due(&self) {
+        // Clients are given the announcements that already started when they connect
+        let now = now();
+        let mut pushed: HashSet<String> = self
+            .announcements
+            .read()
+            .iter()
+            .filter(|x| x.start_time <= now)
+            .map(|x| x.id.clone())
+            .collect();
+
+        loop {
+            let now = now();
+            let (due, next_start) = {
+                let announcements = self.announcements.read();
+                pushed.retain(|id| announcements.iter().any(|x| &x.id == id));
+                let due: Vec<_> = announcements
+                    .iter()
+                    .filter(|x| x.is_active(now) && !pushed.contains(&x.id))
+                    .cloned()
+                    .collect();
+                let next_start = announcements
+                    .iter()
+                    .map(|x| x.start_time)
+                    .filter(|x| *x > now)
+                    .min();
+                (due, next_start)
+            };
+            for announcement in due {
+                pushed.insert(announcement.id.clone());
+                let _ = self.announcement_updater.send(announcement);
+            }
+
+            let wait = async {
+                match next_start {
+                    Some(start_time) => {
+                        let start = UNIX_EPOCH + Duration::from_secs(start_time);
+                        sleep(start.duration_since(SystemTime::now()).unwrap_or_default()).await
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            select! {
+                () = wait => {}
+                () = self.schedule_changed.notified() => {}
+            }
+        }
+    }
+
+    fn local_remove(&self, id: &str) -> bool {
+        let mut announcements = self.announcements.write();
+        let len = announcements.len();
+        announcements.retain(|x| x.id != id);
+        announcements.len() != len
+    }
+
+    /// Persists the announcement, then pushes it to every connected client,
+    /// including those connected to siblings
+    pub async fn create(&self, mut announcement: Announcement) -> Result<Announcement, Error> {
+        announcement.id = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(ANNOUNCEMENT_ID_LENGTH)
+            .map(char::from)
+            .collect();
+
+        let permit = self
+            .db
+            .budgets
+            .acquire(&self.db.bola_announcements_table, OperationClass::Write)
+            .await?;
+        let output = self
+            .db
+            .client
+            .put_item()
+            .table_name(self.db.bola_announcements_table.clone())
+            .item("id", AttributeValue::S(announcement.id.clone()))
+            .item(
+                "start_time",
+                AttributeValue::N(announcement.start_time.to_string()),
+            )
+            .item(
+                "end_time",
+                AttributeValue::N(announcement.end_time.to_string()),
+            )
+            .item(
+                "audience",
+                AttributeValue::S(announcement.audience.name().into()),
+            )
+            .item(
+                "messages",
+                AttributeValue::M(
+                    announcement
+                        .messages
+                        .iter()
+                        .map(|(locale, msg)| (locale.clone(), AttributeValue::S(msg.clone())))
+                        .collect(),
+                ),
+            )
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+
+        self.local_add(announcement.clone());
+
+        for (domain, err) in self
+            .node
+            .broadcast_message(
+                &NetworkMessage::AnnouncementUpdate(AnnouncementUpdate::Created(
+                    announcement.clone(),
+                ))
+                .traced()
+                .signed(),
+            )
+            .await
+        {
+            error!(target: "announcements", "Error broadcasting announcement to {}: {:?}", domain, err);
+        }
+
+        Ok(announcement)
+    }
+
+    /// Deletes the announcement so that it is no longer delivered on connect
+    ///
+    /// Returns false if the announcement exists neither in the table nor on
+    /// this node
+    pub async fn remove(&self, id: String) -> Result<bool, Error> {
+        let permit = self
+            .db
+            .budgets
+            .acquire(&self.db.bola_announcements_table, OperationClass::Write)
+            .await?;
+        let output = self
+            .db
+            .client
+            .delete_item()
+            .table_name(self.db.bola_announcements_table.clone())
+            .key("id", AttributeValue::S(id.clone()))
+            .return_values(ReturnValue::AllOld)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+
+        // Announcements that ended are not cached, but are still in the table
+        let removed = self.local_remove(&id) || output.attributes().is_some();
+
+        for (domain, err) in self
+            .node
+            .broadcast_message(
+                &NetworkMessage::AnnouncementUpdate(AnnouncementUpdate::Removed(id))
+                    .traced()
+                    .signed(),
+            )
+            .await
+        {
+            error!(target: "announcements", "Error broadcasting announcement removal to {}: {:?}", domain, err);
+        }
+
+        Ok(removed)
+    }
+
+    pub fn get_all(&self) -> Vec<Arc<Announcement>> {
+        self.announcements.read().clone()
+    }
+
+    /// Gets every announcement that is currently active for the given audience
+    pub fn get_active(&self, logged_in: bool) -> Vec<Arc<Announcement>> {
+        let now = now();
+        self.announcements
+            .read()
+            .iter()
+            .filter(|x| x.is_active(now) && x.audience.includes(logged_in))
+            .cloned()
+            .collect()
+    }
+
+    pub fn subscribe(&self) -> AnnouncementSubscription {
+        AnnouncementSubscription(self.announcement_updater.subscribe())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewAnnouncement {
+    start_time: u64,
+    end_time: u64,
+    audience: Audience,
+    messages: HashMap<String, String>,
+}
+
+impl ApiSchema for NewAnnouncement {
+    fn schema() -> Value {
+        object_schema(
+            [
+                ("start_time", u64::schema()),
+                ("end_time", u64::schema()),
+                ("audience", Audience::schema()),
+                ("messages", HashMap::<String, String>::schema()),
+            ],
+            &["start_time", "end_time", "audience", "messages"],
+        )
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnnouncementFilter {
+    audience: Option<Audience>,
+    /// If true, only announcements that are currently active are listed
+    #[serde(default)]
+    active: bool,
+}
+
+impl AnnouncementFilter {
+    fn matches(&self, announcement: &Announcement, now: u64) -> bool {
+        self.audience
+            .map_or(true, |audience| announcement.audience == audience)
+            && (!self.active || announcement.is_active(now))
+    }
+}
+
+pub async fn list_announcements(
+    State(state): State<GlobalState>,
+    pagination: Pagination,
+    Query(filter): Query<AnnouncementFilter>,
+) -> Json<Page<Announcement>> {
+    let now = now();
+    let mut announcements = state.announcements.get_all();
+    announcements.retain(|x| filter.matches(x, now));
+    announcements.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+
+    Json(
+        Page::from_sorted(announcements, &pagination, |x| x.id.clone())
+            .map(|x| Announcement::clone(&x)),
+    )
+}
+
+pub async fn create_announcement(
+    State(state): State<GlobalState>,
+    Json(new): Json<NewAnnouncement>,
+) -> Result<Json<Announcement>, (StatusCode, &'static str)> {
+    if new.end_time <= new.start_time {
+        return Err((StatusCode::BAD_REQUEST, "end_time must be after start_time"));
+    }
+    if new.messages.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "messages must not be empty"));
+    }
+
+    state
+        .announcements
+        .create(Announcement {
+            id: String::new(),
+            start_time: new.start_time,
+            end_time: new.end_time,
+            audience: new.audience,
+            messages: new.messages,
+        })
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!(target: "announcements", "{:?}", e.context("creating announcement"));
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Error")
+        })
+}
+
+pub async fn delete_announcement(
+    State(state): State<GlobalState>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    match state.announcements.remove(id).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(target: "announcements", "{:?}", e.context("deleting announcement"));
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Documents the admin routes for announcements
+pub fn document(openapi: OpenApi) -> OpenApi {
+    openapi
+        .document(
+            "/admin/announcements",
+            Operation::get()
+                .set_summary("Lists announcements")
+                .add_query_param::<Audience>("audience", false)
+                .add_query_param::<bool>("active", false)
+                .add_query_param::<String>("cursor", false)
+                .add_query_param::<usize>("limit", false)
+                .set_response::<Page<Announcement>>(),
+        )
+        .document(
+            "/admin/announcements",
+            Operation::post()
+                .set_summary("Creates an announcement")
+                .set_request::<NewAnnouncement>()
+                .set_response::<Announcement>(),
+        )
+        .document(
+            "/admin/announcements/:id",
+            Operation::delete().set_summary("Deletes an announcement"),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(audience: Audience, start_time: u64, end_time: u64) -> Announcement {
+        Announcement {
+            id: String::new(),
+            start_time,
+            end_time,
+            audience,
+            messages: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn filters_by_audience() {
+        let filter = AnnouncementFilter {
+            audience: Some(Audience::Guests),
+            active: false,
+        };
+        assert!(filter.matches(&announcement(Audience::Guests, 0, 10), 20));
+        assert!(!filter.matches(&announcement(Audience::All, 0, 10), 20));
+    }
+
+    #[test]
+    fn filters_by_activity() {
+        let filter = AnnouncementFilter {
+            audience: None,
+            active: true,
+        };
+        assert!(filter.matches(&announcement(Audience::All, 0, 10), 0));
+        assert!(!filter.matches(&announcement(Audience::All, 0, 10), 10));
+        assert!(!filter.matches(&announcement(Audience::LoggedIn, 5, 10), 4));
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = AnnouncementFilter {
+            audience: None,
+            active: false,
+        };
+        assert!(filter.matches(&announcement(Audience::LoggedIn, 5, 10), 20));
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    fs::read_to_string,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Error};
+use axum::{
+    extract::State,
+    http::{request::Parts, StatusCode},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use constant_time_eq::constant_time_eq;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use mangle_api_core::{
+    rand::{thread_rng, RngCore},
+    serde_json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::state::GlobalState;
+
+const PLAY_INTEGRITY_API_BASE: &str = "https://playintegrity.googleapis.com/v1";
+const DEVICE_CHECK_URL: &str = "https://api.devicecheck.apple.com/v1/validate_device_token";
+/// How long a verdict is remembered for a given token
+const VERDICT_CACHE_DURATION: Duration = Duration::from_secs(600);
+/// Expired verdicts are only cleared once the cache grows past this size
+const VERDICT_CACHE_PRUNE_SIZE: usize = 4096;
+/// How long the platforms may take to answer, so that connections are not
+/// held up by an unresponsive one
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a client has to get an integrity token for a nonce and connect with it
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+/// Play Integrity requires at least 16 bytes
+const NONCE_SIZE: usize = 24;
+/// Expired nonces are only cleared once this many are pending
+const NONCE_PRUNE_SIZE: usize = 4096;
+/// No more nonces are issued while this many are pending
+const MAX_PENDING_NONCES: usize = 100_000;
+
+const ATTESTATION_KIND_HEADER: &str = "Attestation-Kind";
+const ATTESTATION_TOKEN_HEADER: &str = "Attestation-Token";
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AttestationKind {
+    #[serde(rename = "play_integrity")]
+    PlayIntegrity,
+    #[serde(rename = "device_check")]
+    DeviceCheck,
+    #[serde(rename = "build_token")]
+    BuildToken,
+}
+
+impl AttestationKind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "play_integrity" => Some(AttestationKind::PlayIntegrity),
+            "device_check" => Some(AttestationKind::DeviceCheck),
+            "build_token" => Some(AttestationKind::BuildToken),
+            _ => None,
+        }
+    }
+
+    /// Whether tokens of this kind carry a nonce issued by `issue_nonce`, so
+    /// that each can only be used once
+    ///
+    /// DeviceCheck and build tokens cannot carry one
+    fn is_nonce_bound(self) -> bool {
+        matches!(self, AttestationKind::PlayIntegrity)
+    }
+}
+
+/// The outcome of checking the attestation a client presented when connecting
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AttestationVerdict {
+    Verified(AttestationKind),
+    Rejected(AttestationKind),
+    /// The attestation could not be checked, such as when the platform is unreachable
+    Unknown(AttestationKind),
+    Missing,
+}
+
+impl AttestationVerdict {
+    pub fn is_verified(self) -> bool {
+        matches!(self, AttestationVerdict::Verified(_))
+    }
+}
+
+/// What should happen to scores submitted by clients that are not verified
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AttestationPolicy {
+    #[serde(rename = "off")]
+    Off,
+    /// Accept the score, but log it as suspicious
+    #[serde(rename = "log")]
+    Log,
+    /// Reject the score
+    #[serde(rename = "enforce")]
+    Enforce,
+}
+
+#[derive(Deserialize)]
+struct AttestationParams {
+    attestation_kind: String,
+    attestation_token: String,
+}
+
+pub struct Attestation {
+    client: reqwest::Client,
+    policy: AttestationPolicy,
+    android_package_name: String,
+    google_token_path: String,
+    device_check_token_path: String,
+    build_token_secret: String,
+    /// Keyed by the hash of the kind and the whole token
+    verdicts: DashMap<[u8; 32], (Instant, AttestationVerdict)>,
+    /// Nonces that were issued but not used yet, and when they were issued
+    nonces: DashMap<String, Instant>,
+}
+
+impl Attestation {
+    pub fn new(
+        policy: AttestationPolicy,
+        android_package_name: String,
+        google_token_path: String,
+        device_check_token_path: String,
+        build_token_secret: String,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(VERIFY_TIMEOUT)
+                .build()
+                .expect("the attestation client to build"),
+            policy,
+            android_package_name,
+            google_token_path,
+            device_check_token_path,
+            build_token_secret,
+            verdicts: Default::default(),
+            nonces: Default::default(),
+        }
+    }
+
+    /// Issues a nonce for the client to request an integrity token with, or
+    /// None if too many are pending
+    pub fn issue_nonce(&self) -> Option<String> {
+        if self.nonces.len() >= NONCE_PRUNE_SIZE {
+            self.nonces
+                .retain(|_, issued_at| issued_at.elapsed() < NONCE_LIFETIME);
+        }
+        if self.nonces.len() >= MAX_PENDING_NONCES {
+            return None;
+        }
+        let mut nonce = [0; NONCE_SIZE];
+        thread_rng().fill_bytes(&mut nonce);
+        let nonce = URL_SAFE_NO_PAD.encode(nonce);
+        self.nonces.insert(nonce.clone(), Instant::now());
+        Some(nonce)
+    }
+
+    /// Uses up the nonce, returning whether it was issued and has not expired
+    fn take_nonce(&self, nonce: &str) -> bool {
+        self.nonces
+            .remove(nonce)
+            .map_or(false, |(_, issued_at)| issued_at.elapsed() < NONCE_LIFETIME)
+    }
+
+    pub fn get_policy(&self) -> AttestationPolicy {
+        self.policy
+    }
+
+    /// Verifies the attestation in the headers or query of the request, if any
+    ///
+    /// Clients that cannot set headers on WebSockets may use the
+    /// `attestation_kind` and `attestation_token` query parameters instead
+    ///
+    /// Play Integrity tokens must be requested with a nonce from `issue_nonce`,
+    /// and are only accepted once
+    pub async fn verify_request(&self, parts: &Parts) -> AttestationVerdict {
+        if self.policy == AttestationPolicy::Off {
+            return AttestationVerdict::Missing;
+        }
+
+        let header = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(ToString::to_string)
+        };
+
+        let (kind, token) = match (
+            header(ATTESTATION_KIND_HEADER),
+            header(ATTESTATION_TOKEN_HEADER),
+        ) {
+            (Some(kind), Some(token)) => (kind, token),
+            _ => match parts
+                .uri
+                .query()
+                .and_then(|x| serde_urlencoded::from_str::<AttestationParams>(x).ok())
+            {
+                Some(params) => (params.attestation_kind, params.attestation_token),
+                None => return AttestationVerdict::Missing,
+            },
+        };
+
+        let Some(kind) = AttestationKind::from_name(&kind) else {
+            return AttestationVerdict::Missing;
+        };
+
+        self.verify(kind, token).await
+    }
+
+    pub async fn verify(&self, kind: AttestationKind, token: String) -> AttestationVerdict {
+        let mut hasher = Sha256::new();
+        // The kind is hashed as well, so that a verdict cannot be reused for
+        // a token of another kind
+        hasher.update([kind as u8]);
+        hasher.update(token.as_bytes());
+        let key: [u8; 32] = hasher.finalize().into();
+
+        if let Some(entry) = self.verdicts.get(&key) {
+            let (verified_at, verdict) = *entry;
+            if verified_at.elapsed() < VERDICT_CACHE_DURATION {
+                if kind.is_nonce_bound() {
+                    warn!(target: "suspicious_security", "Rejected replayed {kind:?} attestation");
+                    return AttestationVerdict::Rejected(kind);
+                }
+                return verdict;
+            }
+        }
+
+        let result = match kind {
+            AttestationKind::PlayIntegrity => self.verify_play_integrity(&token).await,
+            AttestationKind::DeviceCheck => self.verify_device_check(&token).await,
+            AttestationKind::BuildToken => Ok(self.verify_build_token(&token)),
+        };
+
+        let verdict = match result {
+            Ok(true) => AttestationVerdict::Verified(kind),
+            Ok(false) => {
+                warn!(target: "suspicious_security", "Rejected {kind:?} attestation");
+                AttestationVerdict::Rejected(kind)
+            }
+            Err(e) => {
+                error!(target: "attestation", "{:?}", e.context(format!("verifying {kind:?} attestation")));
+                // Not cached so that the next connection tries again
+                return AttestationVerdict::Unknown(kind);
+            }
+        };
+
+        if self.verdicts.len() >= VERDICT_CACHE_PRUNE_SIZE {
+            self.verdicts
+                .retain(|_, (verified_at, _)| verified_at.elapsed() < VERDICT_CACHE_DURATION);
+        }
+        self.verdicts.insert(key, (Instant::now(), verdict));
+
+        verdict
+    }
+
+    fn read_token(path: &str) -> Result<String, Error> {
+        // These tokens are refreshed externally, so they are read every time
+        read_to_string(path)
+            .map(|x| x.trim().to_string())
+            .context(format!("Reading {}", path))
+    }
+
+    async fn verify_play_integrity(&self, token: &str) -> Result<bool, Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct AppIntegrity {
+            app_recognition_verdict: String,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct DeviceIntegrity {
+            #[serde(default)]
+            device_recognition_verdict: Vec<String>,
+        }
+        #[derive(Deserialize)]
+        struct RequestDetails {
+            #[serde(default)]
+            nonce: String,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct TokenPayload {
+            request_details: RequestDetails,
+            app_integrity: AppIntegrity,
+            device_integrity: DeviceIntegrity,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct DecodeResponse {
+            token_payload_external: TokenPayload,
+        }
+
+        let response = self
+            .client
+            .post(format!(
+                "{PLAY_INTEGRITY_API_BASE}/{}:decodeIntegrityToken",
+                self.android_package_name
+            ))
+            .bearer_auth(Self::read_token(&self.google_token_path)?)
+            .json(&serde_json::json!({ "integrity_token": token }))
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::BAD_REQUEST => return Ok(false),
+            status => return Err(anyhow!("Play Integrity responded with {status}")),
+        }
+
+        let payload = response
+            .json::<DecodeResponse>()
+            .await?
+            .token_payload_external;
+
+        // Tokens of other servers or earlier connections carry other nonces
+        if !self.take_nonce(&payload.request_details.nonce) {
+            return Ok(false);
+        }
+
+        Ok(
+            payload.app_integrity.app_recognition_verdict == "PLAY_RECOGNIZED"
+                && payload
+                    .device_integrity
+                    .device_recognition_verdict
+                    .iter()
+                    .any(|x| x == "MEETS_DEVICE_INTEGRITY"),
+        )
+    }
+
+    async fn verify_device_check(&self, token: &str) -> Result<bool, Error> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_millis();
+
+        let response = self
+            .client
+            .post(DEVICE_CHECK_URL)
+            .bearer_auth(Self::read_token(&self.device_check_token_path)?)
+            .json(&serde_json::json!({
+                "device_token": token,
+                "transaction_id": format!("{timestamp:x}"),
+                "timestamp": timestamp,
+            }))
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(true),
+            StatusCode::BAD_REQUEST => Ok(false),
+            status => Err(anyhow!("DeviceCheck responded with {status}")),
+        }
+    }
+
+    /// Build tokens have the form `build_id.signature`, where the signature is the
+    /// hex encoded HMAC-SHA256 of the build id using the build token secret
+    fn verify_build_token(&self, token: &str) -> bool {
+        if self.build_token_secret.is_empty() {
+            return false;
+        }
+        let Some((build_id, signature)) = token.rsplit_once('.') else {
+            return false;
+        };
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.build_token_secret.as_bytes())
+            .expect("HMAC to accept keys of any size");
+        mac.update(build_id.as_bytes());
+
+        constant_time_eq(&mac.finalize().into_bytes(), &signature)
+    }
+}
+
+#[derive(Serialize)]
+pub struct NonceView {
+    nonce: String,
+}
+
+/// Issues a nonce that Play Integrity tokens must be requested with
+pub async fn attestation_nonce(
+    State(state): State<GlobalState>,
+) -> Result<Json<NonceView>, (StatusCode, &'static str)> {
+    match state.attestation.issue_nonce() {
+        Some(nonce) => Ok(Json(NonceView { nonce })),
+        None => Err((StatusCode::SERVICE_UNAVAILABLE, "Too many pending nonces")),
+    }
+}
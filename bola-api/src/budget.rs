@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use aws_sdk_dynamodb::model::ConsumedCapacity;
+use mangle_api_core::{neo_api::latency::record_dependency, parking_lot::Mutex};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+/// The capacity units an operation is assumed to use until DynamoDB reports
+/// what it actually used
+const ESTIMATED_UNITS: f64 = 1.0;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum OperationClass {
+    Read,
+    Write,
+}
+
+impl Display for OperationClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OperationClass::Read => write!(f, "read"),
+            OperationClass::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub enum BudgetPolicy {
+    /// Fails operations right away while the budget is exhausted
+    Shed,
+    /// Holds operations until the budget refills, failing those that would
+    /// wait longer than `max_wait`
+    Queue { max_wait: Duration },
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct BudgetConfig {
+    /// The capacity units restored every second
+    pub units_per_sec: f64,
+    /// The most capacity units that can be spent in a burst
+    pub burst: f64,
+    pub policy: BudgetPolicy,
+}
+
+impl BudgetConfig {
+    /// Refuses budgets that would never refill, as operations would wait on them forever
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(self.units_per_sec > 0.0 && self.units_per_sec.is_finite()) {
+            return Err(Error::msg("units_per_sec must be a number above 0"));
+        }
+        if !(self.burst > 0.0 && self.burst.is_finite()) {
+            return Err(Error::msg("burst must be a number above 0"));
+        }
+        Ok(())
+    }
+}
+
+/// The budgets of a single table. Operations without a budget are only counted
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+pub struct TableBudgets {
+    #[serde(default = "Default::default")]
+    pub read: Option<BudgetConfig>,
+    #[serde(default = "Default::default")]
+    pub write: Option<BudgetConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BudgetStats {
+    pub table: String,
+    pub class: OperationClass,
+    pub budget: Option<BudgetConfig>,
+    /// The capacity units that can be spent right now, which is negative
+    /// while operations used more than they were assumed to
+    pub available: f64,
+    /// The capacity units that DynamoDB reported as consumed
+    pub consumed: f64,
+    pub shed: u64,
+    pub queued: u64,
+}
+
+struct Bucket {
+    budget: Option<BudgetConfig>,
+    available: f64,
+    last_refill: Instant,
+    consumed: f64,
+    shed: u64,
+    queued: u64,
+}
+
+impl Bucket {
+    fn new(budget: Option<BudgetConfig>) -> Self {
+        Self {
+            available: budget.map(|x| x.burst).unwrap_or_default(),
+            budget,
+            last_refill: Instant::now(),
+            consumed: 0.0,
+            shed: 0,
+            queued: 0,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(budget) = &self.budget {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.available = (self.available + elapsed * budget.units_per_sec).min(budget.burst);
+        }
+        self.last_refill = now;
+    }
+}
+
+/// Token buckets of DynamoDB capacity units, per table and operation class
+///
+/// Budgets keep bursts of traffic, such as leaderboard refreshes during an
+/// event, from spending all of the provisioned capacity of a table
+pub struct CapacityBudgets {
+    buckets: Mutex<HashMap<(String, OperationClass), Bucket>>,
+}
+
+impl CapacityBudgets {
+    pub fn new(budgets: HashMap<String, TableBudgets>) -> Self {
+        let mut buckets = HashMap::new();
+        for (table, budgets) in budgets {
+            buckets.insert(
+                (table.clone(), OperationClass::Read),
+                Bucket::new(budgets.read),
+            );
+            buckets.insert((table, OperationClass::Write), Bucket::new(budgets.write));
+        }
+        Self {
+            buckets: Mutex::new(buckets),
+        }
+    }
+
+    /// Waits for the budget of the table to allow another operation, according
+    /// to its policy
+    ///
+    /// The returned permit must be given the consumed capacity of the operation
+    pub async fn acquire(
+        &self,
+        table: &str,
+        class: OperationClass,
+    ) -> Result<CapacityPermit<'_>, Error> {
+        let start = Instant::now();
+        let mut queued = false;
+
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock();
+                let bucket = buckets
+                    .entry((table.to_string(), class))
+                    .or_insert_with(|| Bucket::new(None));
+                let Some(budget) = bucket.budget else { break };
+                bucket.refill();
+                if bucket.available >= ESTIMATED_UNITS {
+                    bucket.available -= ESTIMATED_UNITS;
+                    break;
+                }
+
+                let wait = Duration::from_secs_f64(
+                    (ESTIMATED_UNITS - bucket.available) / budget.units_per_sec.max(f64::EPSILON),
+                );
+                let max_wait = match budget.policy {
+                    BudgetPolicy::Shed => Duration::ZERO,
+                    BudgetPolicy::Queue { max_wait } => max_wait,
+                };
+                if start.elapsed() + wait > max_wait {
+                    bucket.shed += 1;
+                    return Err(Error::msg(format!(
+                        "The {class} capacity budget of {table} is exhausted"
+                    )));
+                }
+                if !queued {
+                    queued = true;
+                    bucket.queued += 1;
+                }
+                wait
+            };
+            sleep(wait).await;
+        }
+
+        Ok(CapacityPermit {
+            budgets: self,
+            table: table.to_string(),
+            class,
+            start,
+        })
+    }
+
+    /// Replaces the budget of a table, or removes it if `budget` is None
+    ///
+    /// The new budget starts full
+    pub fn set_budget(&self, table: String, class: OperationClass, budget: Option<BudgetConfig>) {
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets
+            .entry((table, class))
+            .or_insert_with(|| Bucket::new(None));
+        bucket.budget = budget;
+        bucket.available = budget.map(|x| x.burst).unwrap_or_default();
+        bucket.last_refill = Instant::now();
+    }
+
+    pub fn get_stats(&self) -> Vec<BudgetStats> {
+        let mut buckets = self.buckets.lock();
+        let mut stats: Vec<_> = buckets
+            .iter_mut()
+            .map(|((table, class), bucket)| {
+                bucket.refill();
+                BudgetStats {
+                    table: table.clone(),
+                    class: *class,
+                    budget: bucket.budget,
+                    available: bucket.available,
+                    consumed: bucket.consumed,
+                    shed: bucket.shed,
+                    queued: bucket.queued,
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| (&a.table, a.class as u8).cmp(&(&b.table, b.class as u8)));
+        stats
+    }
+}
+
+/// The capacity that an operation spanning several tables, such as a
+/// transaction, consumed on the table
+pub fn table_capacity<'a>(
+    capacities: Option<&'a [ConsumedCapacity]>,
+    table: &str,
+) -> Option<&'a ConsumedCapacity> {
+    capacities?.iter().find(|x| x.table_name() == Some(table))
+}
+
+/// Permission to perform a single operation against a table
+///
+/// The operation is timed from when the permit was requested until it is
+/// dropped, so that slow WebSocket messages report their DynamoDB calls
+#[must_use]
+pub struct CapacityPermit<'a> {
+    budgets: &'a CapacityBudgets,
+    table: String,
+    class: OperationClass,
+    start: Instant,
+}
+
+impl<'a> CapacityPermit<'a> {
+    /// Charges the budget with the capacity the operation consumed, in place of
+    /// the estimate it was charged when the permit was acquired
+    ///
+    /// Operations that failed or did not return their consumed capacity keep the estimate
+    pub fn consume(self, capacity: Option<&ConsumedCapacity>) {
+        let Some(units) = capacity.and_then(ConsumedCapacity::capacity_units) else {
+            return;
+        };
+        let mut buckets = self.budgets.buckets.lock();
+        let Some(bucket) = buckets.get_mut(&(self.table.clone(), self.class)) else {
+            return;
+        };
+        bucket.consumed += units;
+        if bucket.budget.is_some() {
+            bucket.available -= units - ESTIMATED_UNITS;
+        }
+    }
+}
+
+impl<'a> Drop for CapacityPermit<'a> {
+    fn drop(&mut self) {
+        record_dependency(
+            format!("dynamodb {} {}", self.class, self.table),
+            self.start.elapsed(),
+        );
+    }
+}
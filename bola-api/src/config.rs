@@ -1,39 +1,232 @@
-use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{read_to_string, File},
+    net::SocketAddr,
+    path::Path,
+    time::Duration,
+};
 
-use mangle_api_core::BindAddress;
-use serde::Deserialize;
+use anyhow::{Context, Error, Result};
 
-#[derive(Deserialize)]
+#[cfg(feature = "aws")]
+use mangle_api_core::route53::Route53Config;
+use mangle_api_core::{
+    app::ServiceConfig,
+    auth::{
+        auth_pages::{AuthPages, AuthPagesSrc},
+        bearer::ScopedToken,
+        oidc_providers::OIDCProviderConfig,
+        openid::apple::AppleSignInConfig,
+    },
+    config::read_config,
+    cors::CorsConfig,
+    data_channel::DataChannelConfig,
+    distributed::signing::SigningConfig,
+    ip_filter::{Cidr, IpFilter},
+    log_format::LogFormats,
+    neo_api::{bandwidth::BandwidthLimits, MessageFormat},
+    redact::redact,
+    reload::HttpSettings,
+    serde_json,
+    shutdown::ShutdownConfig,
+    static_routes::{AliasConfig, RedirectConfig, StaticDirConfig, StaticRoutes},
+    tcp::TcpConfig,
+    telemetry::TelemetryConfig,
+    trusted_proxies::ForwardedHeader,
+    BindAddress,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    attestation::AttestationPolicy, budget::TableBudgets, session_metrics::SessionMetricsConfig,
+};
+
+/// Environment variables that start with this override fields of the config,
+/// such as `BOLA_API_TOKEN`
+pub const ENV_PREFIX: &str = "BOLA";
+
+#[derive(Deserialize, Serialize)]
 pub struct Config {
     pub bind_address: BindAddress,
+    /// Addresses served alongside `bind_address`, such as a local socket for a reverse proxy
+    #[serde(default = "Default::default")]
+    pub extra_bind_addresses: Vec<BindAddress>,
     #[serde(default = "stderr_log")]
     pub stderr_log: String,
     #[serde(default = "routing_log")]
     pub routing_log: String,
     #[serde(default = "suspicious_security_log")]
     pub security_log: String,
+    /// Where log levels changed with the `log_level` command are kept
+    #[serde(default = "log_levels_file")]
+    pub log_levels_file: Option<String>,
+    /// Whether each log output is text or JSON lines
+    #[serde(default = "Default::default")]
+    pub log_format: LogFormats,
+    /// The levels of log targets, by target. Levels changed with the
+    /// `log_level` command take precedence until the next reload
+    #[serde(default = "Default::default")]
+    pub log_levels: BTreeMap<String, String>,
+    #[serde(default = "Default::default")]
+    pub cors: CorsConfig,
+    /// Deprecated, added to `cors.allowed_methods`
     #[serde(default = "Default::default")]
     pub cors_allowed_methods: Vec<String>,
+    /// Deprecated, added to `cors.allowed_origins`
     #[serde(default = "Default::default")]
     pub cors_allowed_origins: Vec<String>,
+    /// Regexes of paths that need no API token, on top of the public routes
+    #[serde(default = "Default::default")]
+    pub public_paths: Vec<String>,
     #[serde(default = "network_port")]
     pub network_port: u16,
+    /// Applies to both the web server and connections to siblings
+    #[serde(default = "Default::default")]
+    pub tcp: TcpConfig,
 
     pub google_client_secret_path: String,
+    /// The secret of a Google client of the TVs and Limited Input devices type,
+    /// which console and TV builds log in with. Device logins are refused without it
+    #[serde(default = "Default::default")]
+    pub google_device_client_secret_path: Option<String>,
+    /// Providers that users can log in with by name, on top of Google
+    #[serde(default = "Default::default")]
+    pub oidc_providers: HashMap<String, OIDCProviderConfig>,
+    /// Offered as the apple provider, which iOS builds must offer besides Google
+    #[serde(default = "Default::default")]
+    pub apple_sign_in: Option<AppleSignInConfig>,
     #[serde(default = "bola_profiles_table")]
     pub bola_profiles_table: String,
+    #[serde(default = "bola_purchases_table")]
+    pub bola_purchases_table: String,
+    #[serde(default = "bola_announcements_table")]
+    pub bola_announcements_table: String,
+    #[serde(default = "bola_stats_table")]
+    pub bola_stats_table: String,
+    /// The review queue of usernames flagged by screening
+    #[serde(default = "bola_moderation_table")]
+    pub bola_moderation_table: String,
+    /// Revoked login tokens, so that they stay revoked after a restart
+    #[serde(default = "bola_revocations_table")]
+    pub bola_revocations_table: String,
+    /// Limits the capacity units spent on each table, keyed by table name.
+    /// Budgets can be changed while running with the `budgets` command
+    #[serde(default = "Default::default")]
+    pub capacity_budgets: HashMap<String, TableBudgets>,
+    /// Identifies this node among its siblings, so it must be unique. It must also
+    /// be the domain that siblings know this node by, as unique IDs rely on it,
+    /// so the default is refused when there are siblings
+    #[serde(default = "node_name")]
+    pub node_name: String,
     pub oidc_redirect_base: String,
     // pub github_client_secret_path: String,
+    #[serde(serialize_with = "redact")]
     pub api_token: String,
+    /// Tokens that can only access some paths, such as for monitoring
+    #[serde(default = "Default::default")]
+    pub scoped_tokens: Vec<ScopedToken>,
     #[serde(default = "token_duration")]
     pub token_duration: Duration,
+    /// Extends login tokens by the token duration whenever they are used.
+    /// Signed tokens never slide
+    #[serde(default = "Default::default")]
+    pub sliding_token_expiry: bool,
+    /// Sends a refresh token after every login token, which can be exchanged
+    /// for a new pair of tokens until it expires. Not sent if unset
+    #[serde(default = "Default::default")]
+    pub refresh_token_duration: Option<Duration>,
+    /// Shared by every node so that they can verify the login tokens of each
+    /// other, even while the issuing node is down. Tokens are not signed if this is empty
+    #[serde(default = "Default::default", serialize_with = "redact")]
+    pub token_signing_key: String,
     #[serde(default = "Default::default")]
     pub sibling_domains: HashMap<String, SocketAddr>,
+    /// Signs the messages sent to siblings, and verifies the messages received from them
+    #[serde(default = "Default::default")]
+    pub message_signing: Option<SigningConfig>,
+    /// How many messages that failed to reach a sibling are kept for redelivery
+    #[serde(default = "dead_letter_capacity")]
+    pub dead_letter_capacity: usize,
+    #[serde(default = "dead_letter_ttl")]
+    pub dead_letter_ttl: Duration,
+    /// Where messages that failed to reach a sibling are saved, so that they
+    /// are still redelivered after a restart
+    #[serde(default = "dead_letter_path")]
+    pub dead_letter_path: String,
+    /// How often the clocks of siblings are compared with the clock of this node
+    #[serde(default = "clock_probe_interval")]
+    pub clock_probe_interval: Duration,
+    /// Siblings whose clocks are off by more than this are logged, as they
+    /// could end tournament weeks and login tokens at the wrong time
+    #[serde(default = "clock_skew_warning")]
+    pub clock_skew_warning: Duration,
 
     pub start_week_time: Duration,
+    /// Scores submitted this long before the end of a tournament week are
+    /// hidden until the week ends
+    #[serde(default = "Default::default")]
+    pub tournament_blind_period: Option<Duration>,
+    /// Where scores are kept while the write budget of the profiles table is
+    /// exhausted, so that they are written later even across restarts
+    #[serde(default = "score_queue_dir")]
+    pub score_queue_dir: String,
+    /// How many scores can be queued, past which scores are refused
+    #[serde(default = "score_queue_capacity")]
+    pub score_queue_capacity: usize,
+    /// How long the host or a peer of a multiplayer session has to resume it
+    /// after disconnecting
+    #[serde(default = "multiplayer_reconnect_grace")]
+    pub multiplayer_reconnect_grace: Duration,
+    /// WebSocket messages that take at least this long to handle are logged
+    /// under the `slow_handlers` target, along with the DynamoDB calls they made
+    #[serde(default = "slow_handler_threshold")]
+    pub slow_handler_threshold: Duration,
+    /// Serves Prometheus metrics at this path if set, such as `/metrics`,
+    /// which needs the API token like any other route
+    #[serde(default = "Default::default")]
+    pub metrics_path: Option<String>,
+    /// HTTP requests that take longer are answered with 408
+    #[serde(default = "Default::default")]
+    pub request_timeout: Option<Duration>,
+    /// HTTP requests with larger bodies, in bytes, are answered with 413
+    #[serde(default = "Default::default")]
+    pub max_body_size: Option<usize>,
+    /// Addresses allowed or denied by CIDR range, such as `10.0.0.0/8`
+    #[serde(default = "Default::default")]
+    pub ip_filter: Option<IpFilter>,
+    /// Load balancers whose forwarding headers give the client address.
+    /// Without any, clients are identified by the address they connect from
+    #[serde(default = "Default::default")]
+    pub trusted_proxies: Vec<Cidr>,
+    /// The header that the trusted proxies write, either `x_forwarded_for`
+    /// or `forwarded`
+    #[serde(default = "Default::default")]
+    pub forwarded_header: ForwardedHeader,
+    /// Players whose login tokens can use the `/staff` routes, such as to
+    /// ban users
+    ///
+    /// Reloadable, so that admins can be added or removed without a restart
+    #[serde(default = "Default::default")]
+    pub admin_emails: Vec<String>,
+    /// How many wrong API tokens, login tokens or OAuth codes an IP can give
+    /// before it is locked out
+    #[serde(default = "auth_allowed_failures")]
+    pub auth_allowed_failures: u32,
+    /// How long the first lockout lasts, doubling with every further failure
+    #[serde(default = "auth_lockout")]
+    pub auth_lockout: Duration,
+    #[serde(default = "auth_max_lockout")]
+    pub auth_max_lockout: Duration,
 
-    #[serde(default = "stylesheet_path")]
-    pub stylesheet_path: String,
+    /// Routes that only redirect, such as `/` to the marketing site
+    #[serde(default = "redirects")]
+    pub redirects: Vec<RedirectConfig>,
+    /// Files served as they are, such as the stylesheet of the auth pages
+    #[serde(default = "aliases")]
+    pub aliases: Vec<AliasConfig>,
+    /// Directories whose files are served under a path, such as game assets
+    #[serde(default = "Default::default")]
+    pub static_dirs: Vec<StaticDirConfig>,
     #[serde(default = "invalid_path")]
     pub invalid_path: String,
     #[serde(default = "invalid_path")]
@@ -42,6 +235,78 @@ pub struct Config {
     pub success_path: String,
     #[serde(default = "late_path")]
     pub late_path: String,
+    /// The languages that the auth pages are translated to, whose variants are
+    /// named like `success.fr.html`
+    #[serde(default = "Default::default")]
+    pub auth_page_languages: Vec<String>,
+
+    #[serde(default = "Default::default")]
+    pub apple_bundle_id: String,
+    #[serde(default = "Default::default", serialize_with = "redact")]
+    pub apple_shared_secret: String,
+    #[serde(default = "Default::default")]
+    pub google_play_package_name: String,
+    #[serde(default = "google_play_token_path")]
+    pub google_play_token_path: String,
+    /// Store webhooks must pass this in the `Webhook-Token` header.
+    /// Webhooks are rejected if this is empty
+    #[serde(default = "Default::default", serialize_with = "redact")]
+    pub purchase_webhook_token: String,
+
+    #[serde(default = "attestation_policy")]
+    pub attestation_policy: AttestationPolicy,
+    #[serde(default = "device_check_token_path")]
+    pub device_check_token_path: String,
+    /// Used to verify signed build tokens. Build tokens are rejected if this is empty
+    #[serde(default = "Default::default", serialize_with = "redact")]
+    pub build_token_secret: String,
+
+    #[serde(default = "bandwidth_limits")]
+    pub bandwidth_limits: BandwidthLimits,
+    /// How many username searches each client is served over HTTP each minute
+    #[serde(default = "username_search_rate")]
+    pub username_search_rate: u32,
+    /// Shared by every node so that the cursors of username searches work on
+    /// any of them. Cursors only work on the node that issued them if this is empty
+    #[serde(default = "Default::default", serialize_with = "redact")]
+    pub search_cursor_key: String,
+    /// How many profiles are read each second while screening usernames,
+    /// unless the `screen_usernames` command gives another rate
+    #[serde(default = "username_screening_rate")]
+    pub username_screening_rate: u32,
+    /// How many messages are read from each WebSocket session every
+    /// `ws_message_period`. Throttled clients are sent a `SessionNotice`
+    #[serde(default = "ws_message_limit")]
+    pub ws_message_limit: usize,
+    #[serde(default = "ws_message_period")]
+    pub ws_message_period: Duration,
+    /// The formats that WebSocket clients may pick from. The first is used
+    /// by clients that do not pick one
+    #[serde(default = "ws_formats")]
+    pub ws_formats: Vec<MessageFormat>,
+    /// The percentage of WebSocket sessions that are also run through a second
+    /// handler, which logs wherever its responses differ. Its writes happen
+    /// too, so this should only be above 0 on staging nodes
+    #[serde(default = "Default::default")]
+    pub ws_mirror_percentage: f32,
+    /// How WebSocket sessions are closed when the server stops
+    #[serde(default = "Default::default")]
+    pub shutdown: ShutdownConfig,
+    /// Lets logged in clients move their connection onto a WebRTC data channel
+    #[serde(default = "Default::default")]
+    pub data_channels: Option<DataChannelConfig>,
+    /// Where traces are exported to, if anywhere
+    #[serde(default = "Default::default")]
+    pub telemetry: Option<TelemetryConfig>,
+    /// Where hourly WebSocket session metrics are persisted, if anywhere
+    #[serde(default = "Default::default")]
+    pub session_metrics: Option<SessionMetricsConfig>,
+
+    /// The weighted record of this node, which is taken out of rotation whenever
+    /// the node is not ready
+    #[cfg(feature = "aws")]
+    #[serde(default = "Default::default")]
+    pub route53: Option<Route53Config>,
 
     #[serde(default = "Default::default")]
     pub https: bool,
@@ -51,6 +316,153 @@ pub struct Config {
     pub certs_path: String,
     #[serde(default = "key_path")]
     pub key_path: String,
+    /// How long before it expires the HTTPS certificate is renewed
+    #[serde(default = "certificate_renew_before")]
+    pub certificate_renew_before: Duration,
+    /// How often the expiry of the HTTPS certificate is checked
+    #[serde(default = "certificate_check_interval")]
+    pub certificate_check_interval: Duration,
+}
+
+impl Config {
+    pub fn read(path: &str) -> Result<Self> {
+        read_config(path, Some(ENV_PREFIX))
+    }
+
+    /// The settings of the web server that the `reload` command applies
+    pub fn http_settings(&self) -> Result<HttpSettings> {
+        let mut cors = self.cors.clone();
+        cors.allowed_methods
+            .extend(self.cors_allowed_methods.iter().cloned());
+        cors.allowed_origins
+            .extend(self.cors_allowed_origins.iter().cloned());
+        HttpSettings::from_cors_config(&cors, &self.public_paths)
+    }
+
+    /// Reads the pages shown at the end of OpenID logins, and their translations
+    pub fn admin_emails(&self) -> HashSet<String> {
+        self.admin_emails.iter().cloned().collect()
+    }
+
+    pub fn auth_pages(&self) -> Result<AuthPages> {
+        let read = |path: &str| read_to_string(path).context(format!("Reading {path}"));
+        let read_pages = |language: Option<&str>| -> Result<AuthPages> {
+            let path = |path: &str| match language {
+                Some(language) => localized_path(path, language),
+                None => path.to_string(),
+            };
+            Ok(AuthPages::new(AuthPagesSrc {
+                internal_error: read(&path(&self.internal_error_path))?,
+                late: read(&path(&self.late_path))?,
+                invalid: read(&path(&self.invalid_path))?,
+                success: read(&path(&self.success_path))?,
+            }))
+        };
+        let mut pages = read_pages(None)?;
+        for language in &self.auth_page_languages {
+            pages = pages.add_locale(language, read_pages(Some(language))?);
+        }
+        Ok(pages)
+    }
+
+    /// The effective configuration with every secret redacted, which is safe to log
+    pub fn echo(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_else(|e| format!("Unserializable config: {e}"))
+    }
+}
+
+impl ServiceConfig for Config {
+    fn stderr_log(&self) -> &str {
+        &self.stderr_log
+    }
+
+    fn routing_log(&self) -> &str {
+        &self.routing_log
+    }
+
+    fn security_log(&self) -> &str {
+        &self.security_log
+    }
+
+    fn telemetry(&self) -> Option<&TelemetryConfig> {
+        self.telemetry.as_ref()
+    }
+
+    fn log_levels_file(&self) -> Option<&str> {
+        self.log_levels_file.as_deref()
+    }
+
+    fn log_levels(&self) -> Option<&BTreeMap<String, String>> {
+        Some(&self.log_levels)
+    }
+
+    fn log_formats(&self) -> LogFormats {
+        self.log_format
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.https {
+            if self.https_domain.is_empty() {
+                return Err(Error::msg("https is true, but https_domain is empty"));
+            }
+            // Missing certificates are obtained on start, but existing ones must be readable
+            for path in [&self.certs_path, &self.key_path] {
+                if Path::new(path).exists() {
+                    File::open(path).context(format!("Reading {path}"))?;
+                }
+            }
+        }
+        // Node IDs are derived from node names, so every node must be named
+        if !self.sibling_domains.is_empty() && self.node_name == node_name() {
+            return Err(Error::msg(
+                "node_name must be set to the domain of this node when there are siblings",
+            ));
+        }
+        // Rates of 0 would refuse everything, and some of them cannot be waited out
+        for (name, rate) in [
+            ("username_search_rate", self.username_search_rate),
+            ("username_screening_rate", self.username_screening_rate),
+        ] {
+            if rate == 0 {
+                return Err(Error::msg(format!("{name} must be above 0")));
+            }
+        }
+        if self.ws_message_limit == 0 || self.ws_message_period.is_zero() {
+            return Err(Error::msg(
+                "ws_message_limit and ws_message_period must be above 0",
+            ));
+        }
+        if self.ws_formats.is_empty() {
+            return Err(Error::msg("ws_formats must allow at least one format"));
+        }
+        if !(0.0..=100.0).contains(&self.ws_mirror_percentage) {
+            return Err(Error::msg("ws_mirror_percentage must be between 0 and 100"));
+        }
+        for (table, budgets) in &self.capacity_budgets {
+            for budget in budgets.read.iter().chain(&budgets.write) {
+                budget
+                    .validate()
+                    .context(format!("Validating the capacity budget of {table}"))?;
+            }
+        }
+        self.http_settings()?;
+        for token in &self.scoped_tokens {
+            token.validate()?;
+        }
+        self.auth_pages()?;
+        StaticRoutes::new(&self.redirects, &self.aliases)
+            .context("Validating redirects and aliases")?;
+        for dir in &self.static_dirs {
+            if !Path::new(&dir.dir).is_dir() {
+                return Err(Error::msg(format!("{} is not a directory", dir.dir)));
+            }
+        }
+        Ok(())
+    }
+
+    fn effective_config(&self) -> Option<String> {
+        Some(self.echo())
+    }
 }
 
 fn stderr_log() -> String {
@@ -65,21 +477,164 @@ fn suspicious_security_log() -> String {
     "security.log".into()
 }
 
+fn log_levels_file() -> Option<String> {
+    Some("log_levels.json".into())
+}
+
 fn bola_profiles_table() -> String {
     "bola_profiles".into()
 }
 
+fn bola_purchases_table() -> String {
+    "bola_purchases".into()
+}
+
+fn bola_announcements_table() -> String {
+    "bola_announcements".into()
+}
+
+fn bola_stats_table() -> String {
+    "bola_stats".into()
+}
+
+fn bola_moderation_table() -> String {
+    "bola_moderation".into()
+}
+
+fn bola_revocations_table() -> String {
+    "bola_revocations".into()
+}
+
+fn node_name() -> String {
+    "bola".into()
+}
+
+fn google_play_token_path() -> String {
+    "google_play_token.txt".into()
+}
+
+fn attestation_policy() -> AttestationPolicy {
+    AttestationPolicy::Log
+}
+
+fn device_check_token_path() -> String {
+    "device_check_token.txt".into()
+}
+
+fn bandwidth_limits() -> BandwidthLimits {
+    BandwidthLimits {
+        window: Duration::from_secs(60),
+        soft_limit: 1024 * 1024,
+        throttle_limit: 4 * 1024 * 1024,
+        hard_limit: 16 * 1024 * 1024,
+    }
+}
+
+fn username_search_rate() -> u32 {
+    30
+}
+
+fn username_screening_rate() -> u32 {
+    50
+}
+
+fn ws_message_limit() -> usize {
+    100
+}
+
+fn ws_formats() -> Vec<MessageFormat> {
+    vec![MessageFormat::Json]
+}
+
+fn ws_message_period() -> Duration {
+    Duration::from_secs(1)
+}
+
 fn token_duration() -> Duration {
     // 30 days
     Duration::from_secs(60 * 60 * 24 * 30)
 }
 
+fn dead_letter_capacity() -> usize {
+    256
+}
+
+fn dead_letter_path() -> String {
+    "dead_letters".into()
+}
+
+fn score_queue_dir() -> String {
+    "score_queue".into()
+}
+
+fn score_queue_capacity() -> usize {
+    10_000
+}
+
+fn auth_allowed_failures() -> u32 {
+    10
+}
+
+fn auth_lockout() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn auth_max_lockout() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn dead_letter_ttl() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn clock_probe_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn clock_skew_warning() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn multiplayer_reconnect_grace() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn slow_handler_threshold() -> Duration {
+    Duration::from_secs(1)
+}
+
 fn network_port() -> u16 {
     10419
 }
 
-fn stylesheet_path() -> String {
-    "manglemix.css".into()
+fn redirects() -> Vec<RedirectConfig> {
+    vec![RedirectConfig {
+        from: "/".into(),
+        to: "https://bola.manglemix.com".into(),
+        status: 307,
+    }]
+}
+
+fn aliases() -> Vec<AliasConfig> {
+    vec![AliasConfig {
+        path: "/manglemix.css".into(),
+        file: "manglemix.css".into(),
+        content_type: "text/css".into(),
+    }]
+}
+
+/// Inserts the language before the extension, so `success.html` becomes
+/// `success.fr.html`
+fn localized_path(path: &str, language: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{stem}.{language}.{}", extension.to_string_lossy()),
+        None => format!("{stem}.{language}"),
+    };
+    path.with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
 }
 
 fn invalid_path() -> String {
@@ -101,3 +656,11 @@ fn certs_path() -> String {
 fn key_path() -> String {
     "https/key.pem".into()
 }
+
+fn certificate_renew_before() -> Duration {
+    Duration::from_secs(30 * 24 * 60 * 60)
+}
+
+fn certificate_check_interval() -> Duration {
+    Duration::from_secs(12 * 60 * 60)
+}
@@ -0,0 +1,101 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use log::warn;
+use mangle_api_core::neo_api::bandwidth::BandwidthRegistry;
+use tokio::time::sleep;
+
+/// How often claims without a live connection are reclaimed
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Claims are made before the WebSocket is upgraded, so they are given this
+/// long for the connection to show up in the bandwidth registry
+const RECLAIM_GRACE: Duration = Duration::from_secs(60);
+
+struct Claim {
+    id: u64,
+    claimed_at: Instant,
+    metered: bool,
+}
+
+/// The emails of the users with a WebSocket session on this node, so that
+/// each user has at most one session at a time
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    claims: DashMap<String, Claim>,
+}
+
+impl Connections {
+    /// Claims the given email for a session, or returns None if another
+    /// session already has it
+    ///
+    /// `metered` must only be set if the bandwidth registry identifies the
+    /// connection of the session by this email, as only those claims are swept
+    pub fn claim(&'static self, email: String, metered: bool) -> Option<ConnectionGuard> {
+        let Entry::Vacant(slot) = self.claims.entry(email.clone()) else {
+            return None;
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        slot.insert(Claim {
+            id,
+            claimed_at: Instant::now(),
+            metered,
+        });
+        Some(ConnectionGuard {
+            connections: self,
+            email,
+            id,
+        })
+    }
+
+    /// How many users have a session on this node
+    pub fn count(&self) -> usize {
+        self.claims.len()
+    }
+
+    /// Removes the claims of users without a live connection, which are left
+    /// behind if a session never releases its claim
+    ///
+    /// Returns how many claims were removed
+    pub fn sweep(&self, bandwidth: &BandwidthRegistry) -> usize {
+        let mut reclaimed = 0;
+        self.claims.retain(|email, claim| {
+            if !claim.metered
+                || claim.claimed_at.elapsed() < RECLAIM_GRACE
+                || bandwidth.is_user_connected(email)
+            {
+                return true;
+            }
+            warn!(target: "ws_sessions", "Reclaimed stale connection of {email}");
+            reclaimed += 1;
+            false
+        });
+        reclaimed
+    }
+
+    /// Calls `sweep` every `interval`, forever
+    pub async fn sweep_stale(&self, bandwidth: &BandwidthRegistry, interval: Duration) {
+        loop {
+            sleep(interval).await;
+            self.sweep(bandwidth);
+        }
+    }
+}
+
+/// Holds the claim of a session on an email, releasing it when dropped
+pub struct ConnectionGuard {
+    connections: &'static Connections,
+    email: String,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections
+            .claims
+            .remove_if(&self.email, |_, claim| claim.id == self.id);
+    }
+}
@@ -1,16 +1,247 @@
-use std::{future::Future, pin::Pin, task::Poll};
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
+};
 
+use anyhow::Result;
 use axum::async_trait;
-use log::error;
-use messagist::{pipes::ListenerErrorHandler, ExclusiveMessageHandler, MessageStream};
+use log::{error, info};
+use mangle_api_core::{
+    auth::auth_pages::AuthPages,
+    dead_letters::{DeadLetterStats, PeerDeadLetters},
+    distributed::Node,
+    log_buffer::{self, LogFilter, LogRecord},
+    log_levels,
+    metrics::Metrics,
+    readiness::Readiness,
+    reload::{HttpSettings, ReloadableConfig},
+    status::StatusResponse,
+    tasks::{TaskState, TaskStatus, TaskStatuses},
+};
+use messagist::{
+    pipes::ListenerErrorHandler, wire::WireType, AliasableMessageHandler, ExclusiveMessageHandler,
+    MessageStream,
+};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    budget::{BudgetConfig, BudgetPolicy, BudgetStats, OperationClass},
+    config::Config,
+    db::DB,
+    moderation::Moderation,
+    network::{SiblingClockSkew, SiblingNetworkHandler},
+    state::GlobalState,
+    ws_api::WsApi,
+};
 
 #[derive(Serialize, Deserialize)]
-pub enum ControlServerMessage {}
+pub enum ControlServerMessage {
+    Status(StatusResponse),
+    DeadLetters(DeadLetterStats),
+    DeadLettersFlushed {
+        delivered: usize,
+    },
+    DeadLettersCleared {
+        discarded: usize,
+    },
+    Log(LogRecord),
+    Budgets(Vec<BudgetStats>),
+    LogLevel {
+        target: String,
+        level: String,
+    },
+    Error(String),
+    /// The effective configuration after a reload, with secrets redacted
+    Reloaded {
+        config: String,
+    },
+    /// The results of screening are only logged, as it can take hours
+    ScreeningStarted,
+    /// Every target whose level can be changed, with its current level
+    LogLevels(Vec<(String, String)>),
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum ControlClientMessage {
     Stop,
+    /// Takes this node out of rotation, such as before a deploy
+    Drain,
+    Undrain,
+    Status,
+    DeadLetters,
+    /// Tries to redeliver the undelivered sibling messages right away
+    FlushDeadLetters,
+    /// Discards the undelivered messages of a sibling, or of every sibling
+    ClearDeadLetters {
+        domain: Option<String>,
+    },
+    /// Sends the recent logs that pass the filter, followed by new logs if
+    /// `follow` is set, which is only allowed over the log pipe
+    Logs {
+        filter: LogFilter,
+        follow: bool,
+    },
+    Budgets,
+    /// Replaces the capacity budget of a table, or removes it if `budget` is None
+    SetBudget {
+        table: String,
+        class: OperationClass,
+        budget: Option<BudgetConfig>,
+    },
+    /// Gets the log level of a target, setting it first if `new_level` is given
+    LogLevel {
+        target: String,
+        new_level: Option<String>,
+    },
+    /// Reads the config file again, applying the settings that can change while running
+    Reload,
+    /// Flags the usernames that the profanity filter now rejects, reading at
+    /// most `rate` profiles a second, and renames them if `rename` is set
+    ScreenUsernames {
+        rate: Option<u32>,
+        rename: bool,
+    },
+    LogLevels,
+}
+
+impl WireType for ControlServerMessage {
+    fn wire_samples() -> Vec<Self> {
+        vec![
+            ControlServerMessage::Status(StatusResponse {
+                version: "1.0.0".into(),
+                uptime: Duration::from_secs(90061),
+                bind_addresses: vec!["0.0.0.0:443".into()],
+                ready: true,
+                draining: false,
+                connections: vec![("ws_sessions".into(), 1), ("users".into(), 1)],
+                clock_skew: vec![("sibling".into(), -250)],
+                tasks: vec![TaskStatus {
+                    name: "route53".into(),
+                    state: TaskState::Failed("error".into()),
+                    restarts: 1,
+                }],
+                config: "{}".into(),
+            }),
+            ControlServerMessage::DeadLetters(DeadLetterStats {
+                peers: [(
+                    "sibling".to_string(),
+                    PeerDeadLetters {
+                        queued: 1,
+                        oldest_age: Some(Duration::from_secs(1)),
+                    },
+                )]
+                .into(),
+                dropped: 0,
+                expired: 0,
+                redelivered: 0,
+            }),
+            ControlServerMessage::DeadLettersFlushed { delivered: 1 },
+            ControlServerMessage::DeadLettersCleared { discarded: 1 },
+            ControlServerMessage::Log(LogRecord {
+                level: "INFO".into(),
+                target: "bola".into(),
+                message: "message".into(),
+            }),
+            ControlServerMessage::Budgets(vec![BudgetStats {
+                table: "bola_profiles".into(),
+                class: OperationClass::Read,
+                budget: Some(BudgetConfig {
+                    units_per_sec: 10.0,
+                    burst: 20.0,
+                    policy: BudgetPolicy::Shed,
+                }),
+                available: -0.5,
+                consumed: 0.5,
+                shed: 1,
+                queued: 1,
+            }]),
+            ControlServerMessage::LogLevel {
+                target: "login".into(),
+                level: "DEBUG".into(),
+            },
+            ControlServerMessage::Error("Unknown log target: bola".into()),
+            ControlServerMessage::Reloaded {
+                config: "{}".into(),
+            },
+            ControlServerMessage::ScreeningStarted,
+            ControlServerMessage::LogLevels(vec![
+                ("critical".into(), "WARN".into()),
+                ("login".into(), "TRACE".into()),
+            ]),
+        ]
+    }
+}
+
+impl WireType for ControlClientMessage {
+    fn wire_samples() -> Vec<Self> {
+        vec![
+            ControlClientMessage::Stop,
+            ControlClientMessage::Drain,
+            ControlClientMessage::Undrain,
+            ControlClientMessage::Status,
+            ControlClientMessage::DeadLetters,
+            ControlClientMessage::FlushDeadLetters,
+            ControlClientMessage::ClearDeadLetters {
+                domain: Some("sibling".into()),
+            },
+            ControlClientMessage::Logs {
+                filter: LogFilter {
+                    target: None,
+                    level: "info".into(),
+                },
+                follow: true,
+            },
+            ControlClientMessage::Budgets,
+            ControlClientMessage::SetBudget {
+                table: "bola_profiles".into(),
+                class: OperationClass::Write,
+                budget: Some(BudgetConfig {
+                    units_per_sec: 10.0,
+                    burst: 10.0,
+                    policy: BudgetPolicy::Queue {
+                        max_wait: Duration::from_millis(250),
+                    },
+                }),
+            },
+            ControlClientMessage::LogLevel {
+                target: "login".into(),
+                new_level: Some("debug".into()),
+            },
+            ControlClientMessage::Reload,
+            ControlClientMessage::ScreenUsernames {
+                rate: Some(50),
+                rename: true,
+            },
+            ControlClientMessage::LogLevels,
+        ]
+    }
+}
+
+impl ControlClientMessage {
+    fn name(&self) -> &'static str {
+        match self {
+            ControlClientMessage::Stop => "stop",
+            ControlClientMessage::Drain => "drain",
+            ControlClientMessage::Undrain => "undrain",
+            ControlClientMessage::Status => "status",
+            ControlClientMessage::DeadLetters => "dead_letters",
+            ControlClientMessage::FlushDeadLetters => "flush_dead_letters",
+            ControlClientMessage::ClearDeadLetters { .. } => "clear_dead_letters",
+            ControlClientMessage::Logs { .. } => "logs",
+            ControlClientMessage::Budgets => "budgets",
+            ControlClientMessage::SetBudget { .. } => "set_budget",
+            ControlClientMessage::LogLevel { .. } => "log_level",
+            ControlClientMessage::Reload => "reload",
+            ControlClientMessage::ScreenUsernames { .. } => "screen_usernames",
+            ControlClientMessage::LogLevels => "log_levels",
+        }
+    }
 }
 
 pub struct ControlHandlerReceiver {
@@ -34,16 +265,76 @@ impl Future for ControlHandlerReceiver {
 
 pub struct ControlHandler {
     stop_sender: tokio::sync::mpsc::Sender<()>,
+    readiness: &'static Readiness,
+    config_echo: String,
+    config_path: String,
+    http_settings: ReloadableConfig<HttpSettings>,
+    auth_pages: &'static ReloadableConfig<AuthPages>,
+    admin_emails: &'static ReloadableConfig<HashSet<String>>,
+    node: &'static Node<SiblingNetworkHandler>,
+    db: &'static DB,
+    moderation: &'static Moderation,
+    ws_api: &'static WsApi,
+    clock_skew: &'static SiblingClockSkew,
+    bind_addresses: Vec<String>,
+    started_at: Instant,
+    metrics: Arc<Metrics>,
+    tasks: TaskStatuses,
 }
 
-pub fn new_control_handler() -> (ControlHandler, ControlHandlerReceiver) {
+pub(crate) fn new_control_handler(
+    state: &GlobalState,
+    config_echo: String,
+    config_path: String,
+    http_settings: ReloadableConfig<HttpSettings>,
+    bind_addresses: Vec<String>,
+    metrics: Arc<Metrics>,
+    tasks: TaskStatuses,
+) -> (ControlHandler, ControlHandlerReceiver) {
     let (stop_sender, stop_recv) = tokio::sync::mpsc::channel(1);
     (
-        ControlHandler { stop_sender },
+        ControlHandler {
+            stop_sender,
+            readiness: state.readiness,
+            config_echo,
+            config_path,
+            http_settings,
+            auth_pages: state.auth_pages,
+            admin_emails: state.admin_emails,
+            node: state.node,
+            db: state.db,
+            moderation: state.moderation,
+            ws_api: state.ws_api,
+            clock_skew: state.clock_skew,
+            bind_addresses,
+            started_at: Instant::now(),
+            metrics,
+            tasks,
+        },
         ControlHandlerReceiver { stop_recv },
     )
 }
 
+impl ControlHandler {
+    /// Reads the config file again, applying nothing unless every reloadable
+    /// setting in it is valid
+    ///
+    /// Every other setting only takes effect after a restart
+    fn reload(&mut self) -> Result<()> {
+        let config = Config::read(&self.config_path)?;
+        let http_settings = config.http_settings()?;
+        let auth_pages = config.auth_pages()?;
+        log_levels::set_levels(&config.log_levels)?;
+
+        self.http_settings.reload(http_settings);
+        self.auth_pages.reload(auth_pages);
+        self.admin_emails.reload(config.admin_emails());
+        self.config_echo = config.echo();
+        info!("Reloaded {}", self.config_path);
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl ExclusiveMessageHandler for ControlHandler {
     type SessionState = ();
@@ -60,10 +351,116 @@ impl ExclusiveMessageHandler for ControlHandler {
                 return;
             }
         };
-        match msg {
+        self.metrics.record_command(msg.name());
+        let reply = match msg {
             ControlClientMessage::Stop => {
                 let _ = self.stop_sender.send(()).await;
+                return;
+            }
+            ControlClientMessage::Drain => {
+                self.readiness.drain();
+                return;
+            }
+            ControlClientMessage::Undrain => {
+                self.readiness.undrain();
+                return;
             }
+            ControlClientMessage::Logs { filter, follow } => {
+                // Following would hold up every other command until it stops
+                if follow {
+                    ControlServerMessage::Error(
+                        "Logs can only be followed over the log pipe".into(),
+                    )
+                } else {
+                    send_logs(&mut stream, &filter, false).await;
+                    return;
+                }
+            }
+            ControlClientMessage::Status => ControlServerMessage::Status(StatusResponse {
+                version: env!("CARGO_PKG_VERSION").into(),
+                uptime: self.started_at.elapsed(),
+                bind_addresses: self.bind_addresses.clone(),
+                ready: self.readiness.is_ready(),
+                draining: self.readiness.is_draining(),
+                connections: vec![
+                    ("ws_sessions".into(), self.ws_api.get_metrics().get_active()),
+                    (
+                        "users".into(),
+                        self.ws_api.get_handler().get_inner().get_connected_users() as u64,
+                    ),
+                ],
+                clock_skew: self
+                    .clock_skew
+                    .get_estimates()
+                    .into_iter()
+                    .map(|(domain, estimate)| (domain, estimate.offset_millis))
+                    .collect(),
+                tasks: self.tasks.get(),
+                config: self.config_echo.clone(),
+            }),
+            ControlClientMessage::DeadLetters => {
+                ControlServerMessage::DeadLetters(self.node.get_dead_letter_stats())
+            }
+            ControlClientMessage::FlushDeadLetters => ControlServerMessage::DeadLettersFlushed {
+                delivered: self.node.redeliver_dead_letters().await,
+            },
+            ControlClientMessage::ClearDeadLetters { domain } => {
+                ControlServerMessage::DeadLettersCleared {
+                    discarded: self.node.clear_dead_letters(domain.as_deref()).await,
+                }
+            }
+            ControlClientMessage::Budgets => {
+                ControlServerMessage::Budgets(self.db.budgets.get_stats())
+            }
+            ControlClientMessage::SetBudget {
+                table,
+                class,
+                budget,
+            } => match budget.as_ref().map(BudgetConfig::validate).transpose() {
+                Ok(_) => {
+                    self.db.budgets.set_budget(table, class, budget);
+                    ControlServerMessage::Budgets(self.db.budgets.get_stats())
+                }
+                Err(e) => ControlServerMessage::Error(e.to_string()),
+            },
+            ControlClientMessage::LogLevel { target, new_level } => {
+                match log_levels::get_or_set_level(&target, new_level.as_deref()) {
+                    Ok(level) => ControlServerMessage::LogLevel {
+                        target,
+                        level: level.to_string(),
+                    },
+                    Err(e) => ControlServerMessage::Error(e.to_string()),
+                }
+            }
+            ControlClientMessage::Reload => match self.reload() {
+                Ok(()) => ControlServerMessage::Reloaded {
+                    config: self.config_echo.clone(),
+                },
+                Err(e) => {
+                    let e = e.context("Reloading config");
+                    error!("{e:?}");
+                    ControlServerMessage::Error(format!("{e:?}"))
+                }
+            },
+            ControlClientMessage::LogLevels => ControlServerMessage::LogLevels(
+                log_levels::get_levels()
+                    .into_iter()
+                    .map(|(target, level)| (target, level.to_string()))
+                    .collect(),
+            ),
+            ControlClientMessage::ScreenUsernames { rate, rename } => {
+                if rate == Some(0) {
+                    ControlServerMessage::Error("The screening rate must be above 0".into())
+                } else if self.moderation.start_screening(rate, rename) {
+                    ControlServerMessage::ScreeningStarted
+                } else {
+                    ControlServerMessage::Error("Usernames are already being screened".into())
+                }
+            }
+        };
+
+        if let Err(e) = stream.send_message(reply).await {
+            error!("Error sending reply: {e}");
         }
     }
 }
@@ -74,3 +471,77 @@ impl ListenerErrorHandler for ControlHandler {
         error!("Error accepting stream: {err}")
     }
 }
+
+/// The pipe that logs are followed over, next to the control pipe
+pub fn log_pipe_name(pipe_name: &OsStr) -> OsString {
+    let mut log_pipe_name = pipe_name.to_os_string();
+    log_pipe_name.push(".logs");
+    log_pipe_name
+}
+
+/// Sends the recent logs that pass the filter, then new logs until the
+/// connection closes if `follow` is set
+async fn send_logs<S: MessageStream>(stream: &mut S, filter: &LogFilter, follow: bool) {
+    // Subscribing first so that nothing is missed between the two
+    let mut live = follow.then(log_buffer::subscribe);
+    for record in log_buffer::recent(filter) {
+        if stream
+            .send_message(ControlServerMessage::Log(record))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    let Some(live) = &mut live else { return };
+    loop {
+        let record = match live.recv().await {
+            Ok(x) => x,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        if !filter.matches(&record) {
+            continue;
+        }
+        if stream
+            .send_message(ControlServerMessage::Log(record))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Serves `Logs` over the log pipe, with every connection in its own task so
+/// that followers neither hold up each other nor the control pipe
+#[derive(Clone, Copy)]
+pub struct LogFollower;
+
+#[async_trait]
+impl AliasableMessageHandler for LogFollower {
+    type SessionState = ();
+
+    async fn handle<S: MessageStream>(&self, mut stream: S, _session_state: Self::SessionState) {
+        match stream.recv_message().await {
+            Ok(ControlClientMessage::Logs { filter, follow }) => {
+                send_logs(&mut stream, &filter, follow).await
+            }
+            Ok(_) => {
+                let _ = stream
+                    .send_message(ControlServerMessage::Error(
+                        "Only logs are served over the log pipe".into(),
+                    ))
+                    .await;
+            }
+            Err(e) => error!("Error receiving message: {e}"),
+        }
+    }
+}
+
+#[async_trait]
+impl ListenerErrorHandler for LogFollower {
+    async fn handle_error(&self, err: std::io::Error) {
+        error!("Error accepting log stream: {err}")
+    }
+}
@@ -1,14 +1,30 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{anyhow, Error};
+use anyhow::{anyhow, Context, Error};
 use aws_sdk_dynamodb::{
-    error::GetItemErrorKind,
-    model::{AttributeAction, AttributeValue, AttributeValueUpdate},
+    error::{
+        GetItemErrorKind, PutItemErrorKind, TransactWriteItemsErrorKind, UpdateItemErrorKind,
+    },
+    model::{
+        AttributeAction, AttributeValue, AttributeValueUpdate, Put, ReturnConsumedCapacity,
+        ReturnValue, TransactWriteItem, Update,
+    },
     Client,
 };
 use aws_types::SdkConfig;
+use axum::async_trait;
+use mangle_api_core::auth::token::RevocationStore;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    budget::{table_capacity, CapacityBudgets, OperationClass, TableBudgets},
+    difficulty::Difficulty,
+    profile_transfer::{ProfilePage, ProfileRecord, ProfileStore},
+};
+
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct UserProfile {
     pub username: String,
@@ -20,64 +36,329 @@ pub struct UserProfile {
     pub expert_highscore: u16,
     #[serde(default = "Default::default")]
     pub tournament_wins: Vec<u16>,
+    #[serde(default = "Default::default")]
+    pub cosmetics: Vec<String>,
+    #[serde(default = "Default::default")]
+    pub avatar_url: Option<String>,
+    /// An ISO 3166-1 alpha-2 country code
+    #[serde(default = "Default::default")]
+    pub country: Option<String>,
+    /// The username that moderation replaced, which is kept until the user
+    /// has been shown it on login
+    #[serde(default = "Default::default")]
+    pub renamed_from: Option<String>,
+    /// When an admin banned the user, in Unix time in seconds
+    #[serde(default = "Default::default")]
+    pub banned_at: Option<u64>,
 }
 
+impl UserProfile {
+    pub fn highscore(&self, difficulty: Difficulty) -> u16 {
+        match difficulty {
+            Difficulty::Easy => self.easy_highscore,
+            Difficulty::Normal => self.normal_highscore,
+            Difficulty::Expert => self.expert_highscore,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+/// How many times a profile lookup may follow an alias left behind by an email change
+const MAX_ALIAS_DEPTH: usize = 8;
+/// How many writes DynamoDB allows in a single transaction
+const MAX_TRANSACTION_ITEMS: usize = 100;
+/// How many times an email change is tried while the profile keeps changing
+const MAX_EMAIL_CHANGE_ATTEMPTS: usize = 3;
+
 pub struct DB {
     pub client: Client,
     pub bola_profiles_table: String,
+    pub bola_purchases_table: String,
+    pub bola_announcements_table: String,
+    pub bola_stats_table: String,
+    pub bola_moderation_table: String,
+    /// Revoked login tokens, which should expire items by `expires_at`
+    pub bola_revocations_table: String,
+    pub budgets: CapacityBudgets,
+}
+
+pub enum ChangeEmailResult {
+    Changed,
+    /// A profile already exists under the new email
+    EmailTaken,
+    NoProfile,
+}
+
+pub enum GrantResult {
+    Granted,
+    AlreadyGranted,
+    /// The user has no profile to add the product to
+    NoProfile,
 }
 
 impl DB {
-    pub fn new(config: &SdkConfig, bola_profiles_table: String) -> Self {
+    pub fn new(
+        config: &SdkConfig,
+        bola_profiles_table: String,
+        bola_purchases_table: String,
+        bola_announcements_table: String,
+        bola_stats_table: String,
+        bola_moderation_table: String,
+        bola_revocations_table: String,
+        budgets: HashMap<String, TableBudgets>,
+    ) -> Self {
         Self {
             client: Client::new(config),
             bola_profiles_table,
+            bola_purchases_table,
+            bola_announcements_table,
+            bola_stats_table,
+            bola_moderation_table,
+            bola_revocations_table,
+            budgets: CapacityBudgets::new(budgets),
         }
     }
 
-    pub async fn is_username_taken(&self, username: impl Into<String>) -> Result<bool, Error> {
+    /// Checks that DynamoDB is reachable, without consuming any capacity
+    pub async fn ping(&self) -> Result<(), Error> {
         self.client
+            .describe_table()
+            .table_name(self.bola_profiles_table.clone())
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn is_username_taken(&self, username: impl Into<String>) -> Result<bool, Error> {
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Read)
+            .await?;
+        let query = self
+            .client
             .query()
             .table_name(self.bola_profiles_table.clone())
             .index_name("username-index")
             .key_condition_expression("username = :check_username")
             .expression_attribute_values(":check_username", AttributeValue::S(username.into()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
             .send()
-            .await
-            .map(|x| x.count() > 0)
-            .map_err(Into::into)
+            .await?;
+        permit.consume(query.consumed_capacity());
+        Ok(query.count() > 0)
     }
 
     pub async fn get_user_profile_by_email(
         &self,
         email: impl Into<String>,
     ) -> Result<Option<UserProfile>, Error> {
-        let email = email.into();
+        let mut email = email.into();
+
+        for _ in 0..MAX_ALIAS_DEPTH {
+            let permit = self
+                .budgets
+                .acquire(&self.bola_profiles_table, OperationClass::Read)
+                .await?;
+            let item = match self
+                .client
+                .get_item()
+                .table_name(self.bola_profiles_table.clone())
+                .key("email", AttributeValue::S(email))
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await
+                .map_err(|e| e.into_service_error())
+            {
+                Ok(x) => {
+                    permit.consume(x.consumed_capacity());
+                    Ok(x)
+                }
+                Err(e) => match &e.kind {
+                    GetItemErrorKind::ResourceNotFoundException(_) => return Ok(None),
+                    _ => Err(e),
+                },
+            }?;
+
+            let Some(item) = item.item() else {
+                return Ok(None)
+            };
 
-        let item = match self
+            // Profiles that changed email leave an alias behind under the old email
+            if let Some(alias_of) = item.get("alias_of") {
+                email = alias_of
+                    .as_s()
+                    .map_err(|_| anyhow!("Could not deserialize field: alias_of in user profile"))?
+                    .clone();
+                continue;
+            }
+
+            return Some(Self::map_to_user_profile(item)).transpose();
+        }
+
+        Err(anyhow!("Too many aliases for user profile"))
+    }
+
+    /// Moves the profile under `old_email` to `new_email`, leaving an alias under
+    /// `old_email` so that anything still referring to it finds the new profile
+    ///
+    /// The aliases of earlier emails are pointed at the new email as well, so
+    /// that lookups never follow more than one alias. Every write happens in a
+    /// single transaction, which is retried if the profile changed since it
+    /// was read
+    pub async fn change_email(
+        &self,
+        old_email: String,
+        new_email: String,
+    ) -> Result<ChangeEmailResult, Error> {
+        for _ in 0..MAX_EMAIL_CHANGE_ATTEMPTS {
+            if let Some(result) = self
+                .try_change_email(old_email.clone(), new_email.clone())
+                .await?
+            {
+                return Ok(result);
+            }
+        }
+        Err(anyhow!(
+            "User profile kept changing while changing its email"
+        ))
+    }
+
+    /// Returns None if the profile changed since it was read
+    async fn try_change_email(
+        &self,
+        old_email: String,
+        new_email: String,
+    ) -> Result<Option<ChangeEmailResult>, Error> {
+        let Some(mut item) = self
             .client
             .get_item()
             .table_name(self.bola_profiles_table.clone())
-            .key("email", AttributeValue::S(email))
+            .key("email", AttributeValue::S(old_email.clone()))
+            .consistent_read(true)
+            .send()
+            .await?
+            .item()
+            .cloned() else {
+            return Ok(Some(ChangeEmailResult::NoProfile))
+        };
+        if item.contains_key("alias_of") {
+            return Ok(Some(ChangeEmailResult::NoProfile));
+        }
+        let mut aliases = match item.get("aliases") {
+            Some(x) => x
+                .as_ss()
+                .map_err(|_| anyhow!("Could not deserialize field: aliases in user profile"))?
+                .clone(),
+            None => vec![],
+        };
+        if aliases.len() + 2 > MAX_TRANSACTION_ITEMS {
+            return Err(anyhow!("Too many aliases for user profile"));
+        }
+
+        // Guards against the profile changing since it was read, so that no
+        // write to it is lost
+        let mut unchanged = Put::builder()
+            .table_name(self.bola_profiles_table.clone())
+            .item("email", AttributeValue::S(old_email.clone()))
+            .item("alias_of", AttributeValue::S(new_email.clone()));
+        let mut conditions = vec!["attribute_not_exists(alias_of)".to_string()];
+        for (i, (name, value)) in item.iter().filter(|(name, _)| *name != "email").enumerate() {
+            conditions.push(format!("#field{i} = :field{i}"));
+            unchanged = unchanged
+                .expression_attribute_names(format!("#field{i}"), name)
+                .expression_attribute_values(format!(":field{i}"), value.clone());
+        }
+        let unchanged = unchanged
+            .condition_expression(conditions.join(" AND "))
+            .build();
+
+        let alias_writes: Vec<_> = aliases
+            .iter()
+            .map(|alias| {
+                TransactWriteItem::builder()
+                    .put(
+                        Put::builder()
+                            .table_name(self.bola_profiles_table.clone())
+                            .item("email", AttributeValue::S(alias.clone()))
+                            .item("alias_of", AttributeValue::S(new_email.clone()))
+                            .condition_expression("alias_of = :old_email")
+                            .expression_attribute_values(
+                                ":old_email",
+                                AttributeValue::S(old_email.clone()),
+                            )
+                            .build(),
+                    )
+                    .build()
+            })
+            .collect();
+
+        aliases.push(old_email);
+        item.insert("email".into(), AttributeValue::S(new_email));
+        item.insert("aliases".into(), AttributeValue::Ss(aliases));
+
+        // The profile is written first, so that the first cancellation reason
+        // tells whether the new email is taken
+        let mut writes = vec![
+            TransactWriteItem::builder()
+                .put(
+                    Put::builder()
+                        .table_name(self.bola_profiles_table.clone())
+                        .set_item(Some(item))
+                        // An old alias may be replaced, but not a profile
+                        .condition_expression(
+                            "attribute_not_exists(email) OR attribute_exists(alias_of)",
+                        )
+                        .build(),
+                )
+                .build(),
+            TransactWriteItem::builder().put(unchanged).build(),
+        ];
+        writes.extend(alias_writes);
+
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        match self
+            .client
+            .transact_write_items()
+            .set_transact_items(Some(writes))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
             .send()
             .await
             .map_err(|e| e.into_service_error())
         {
-            Ok(x) => Ok(x),
-            Err(e) => match &e.kind {
-                GetItemErrorKind::ResourceNotFoundException(_) => return Ok(None),
-                _ => Err(e),
-            },
-        }?;
-
-        let Some(item) = item.item() else {
-            return Ok(None)
-        };
+            Ok(x) => permit.consume(table_capacity(
+                x.consumed_capacity(),
+                &self.bola_profiles_table,
+            )),
+            Err(e) => {
+                return match &e.kind {
+                    TransactWriteItemsErrorKind::TransactionCanceledException(cancelled) => {
+                        let email_taken = cancelled
+                            .cancellation_reasons()
+                            .and_then(|x| x.first())
+                            .and_then(|x| x.code())
+                            == Some("ConditionalCheckFailed");
+                        Ok(email_taken.then_some(ChangeEmailResult::EmailTaken))
+                    }
+                    _ => Err(e.into()),
+                };
+            }
+        }
 
-        Some(Self::map_to_user_profile(item)).transpose()
+        Ok(Some(ChangeEmailResult::Changed))
     }
 
-    fn map_to_user_profile(map: &HashMap<String, AttributeValue>) -> Result<UserProfile, Error> {
+    pub(crate) fn map_to_user_profile(
+        map: &HashMap<String, AttributeValue>,
+    ) -> Result<UserProfile, Error> {
         macro_rules! err {
             ($field_name:literal) => {
                 anyhow!(
@@ -116,42 +397,311 @@ impl DB {
                     None => vec![],
                 }
             },
+            cosmetics: deser!("cosmetics", as_ss).cloned().unwrap_or_default(),
+            avatar_url: deser!("avatar_url", as_s).cloned(),
+            country: deser!("country", as_s).cloned(),
+            renamed_from: deser!("renamed_from", as_s).cloned(),
+            banned_at: deser!(num "banned_at")?,
             username: deser!("username", as_s)
                 .ok_or_else(|| anyhow!("Missing username in user profile"))?
                 .clone(),
         })
     }
 
+    /// Creates the profile of a new user, with their highscores, in a single
+    /// write, so that a sign up that times out either created the whole
+    /// profile or nothing
+    ///
+    /// Returns false if a profile already existed
     pub async fn create_user_profile(
         &self,
         email: String,
-        username: String,
-        tournament_wins: Vec<u16>,
-    ) -> Result<(), Error> {
+        profile: &UserProfile,
+    ) -> Result<bool, Error> {
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
         let mut req = self
             .client
             .put_item()
             .table_name(self.bola_profiles_table.clone())
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
             .item("email", AttributeValue::S(email))
-            .item("easy_highscore", AttributeValue::N("0".into()))
-            .item("normal_highscore", AttributeValue::N("0".into()))
-            .item("expert_highscore", AttributeValue::N("0".into()))
-            .item("username", AttributeValue::S(username))
-            .item("unused", AttributeValue::N("0".into()));
+            .item("username", AttributeValue::S(profile.username.clone()))
+            .item("unused", AttributeValue::N("0".into()))
+            .item("created_at", AttributeValue::N(now().to_string()))
+            .condition_expression("attribute_not_exists(email)");
+
+        for difficulty in Difficulty::ALL {
+            req = req.item(
+                difficulty.highscore_field(),
+                AttributeValue::N(profile.highscore(difficulty).to_string()),
+            );
+        }
 
-        if !tournament_wins.is_empty() {
+        if !profile.tournament_wins.is_empty() {
             req = req.item(
                 "tournament_wins",
-                AttributeValue::Ns(tournament_wins.iter().map(ToString::to_string).collect()),
+                AttributeValue::Ns(
+                    profile
+                        .tournament_wins
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect(),
+                ),
             );
         }
 
-        match req.send().await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+        match req.send().await.map_err(|e| e.into_service_error()) {
+            Ok(x) => {
+                permit.consume(x.consumed_capacity());
+                Ok(true)
+            }
+            Err(e) => match &e.kind {
+                PutItemErrorKind::ConditionalCheckFailedException(_) => Ok(false),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Writes a whole profile, such as one from an export, unless a profile
+    /// already exists under the email
+    ///
+    /// Returns false if a profile already existed
+    pub async fn put_user_profile(
+        &self,
+        email: String,
+        profile: UserProfile,
+        created_at: Option<u64>,
+    ) -> Result<bool, Error> {
+        let mut req = self
+            .client
+            .put_item()
+            .table_name(self.bola_profiles_table.clone())
+            .item("email", AttributeValue::S(email))
+            .item("username", AttributeValue::S(profile.username.clone()))
+            .item(
+                "created_at",
+                AttributeValue::N(created_at.unwrap_or_else(now).to_string()),
+            )
+            .condition_expression("attribute_not_exists(email)");
+
+        // Banned users are kept off the leaderboard indices
+        req = match profile.banned_at {
+            Some(banned_at) => req.item("banned_at", AttributeValue::N(banned_at.to_string())),
+            None => req.item("unused", AttributeValue::N("0".into())),
+        };
+
+        for difficulty in Difficulty::ALL {
+            req = req.item(
+                difficulty.highscore_field(),
+                AttributeValue::N(profile.highscore(difficulty).to_string()),
+            );
+        }
+        if !profile.tournament_wins.is_empty() {
+            req = req.item(
+                "tournament_wins",
+                AttributeValue::Ns(
+                    profile
+                        .tournament_wins
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect(),
+                ),
+            );
+        }
+        if !profile.cosmetics.is_empty() {
+            req = req.item("cosmetics", AttributeValue::Ss(profile.cosmetics));
+        }
+        if let Some(avatar_url) = profile.avatar_url {
+            req = req.item("avatar_url", AttributeValue::S(avatar_url));
+        }
+        if let Some(country) = profile.country {
+            req = req.item("country", AttributeValue::S(country));
+        }
+
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        match req
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|e| e.into_service_error())
+        {
+            Ok(x) => {
+                permit.consume(x.consumed_capacity());
+                Ok(true)
+            }
+            Err(e) => match &e.kind {
+                PutItemErrorKind::ConditionalCheckFailedException(_) => Ok(false),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Changes the username of the profile, as long as it still has the old
+    /// username, remembering the old one so that the user can be told
+    ///
+    /// Returns false if the profile does not have the old username
+    pub async fn rename_user(
+        &self,
+        email: String,
+        old_username: String,
+        new_username: String,
+    ) -> Result<bool, Error> {
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        match self
+            .client
+            .update_item()
+            .table_name(self.bola_profiles_table.clone())
+            .key("email", AttributeValue::S(email))
+            .update_expression("SET username = :new_username, renamed_from = :old_username")
+            .condition_expression("username = :old_username AND attribute_not_exists(alias_of)")
+            .expression_attribute_values(":new_username", AttributeValue::S(new_username))
+            .expression_attribute_values(":old_username", AttributeValue::S(old_username))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|e| e.into_service_error())
+        {
+            Ok(x) => {
+                permit.consume(x.consumed_capacity());
+                Ok(true)
+            }
+            Err(e) => match &e.kind {
+                UpdateItemErrorKind::ConditionalCheckFailedException(_) => Ok(false),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Forgets the username that moderation replaced, once the user has been told
+    pub async fn clear_renamed_from(&self, email: String) -> Result<(), Error> {
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        let output = self
+            .client
+            .update_item()
+            .table_name(self.bola_profiles_table.clone())
+            .key("email", AttributeValue::S(email))
+            .update_expression("REMOVE renamed_from")
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+        Ok(())
+    }
+
+    /// Sets or removes the avatar and country shown next to the scores of the
+    /// user, which are read when their next score is added to a leaderboard
+    ///
+    /// Returns false if the user has no profile
+    pub async fn set_profile_details(
+        &self,
+        email: String,
+        avatar_url: Option<String>,
+        country: Option<String>,
+    ) -> Result<bool, Error> {
+        let mut set = vec![];
+        let mut remove = vec![];
+        let mut req = self
+            .client
+            .update_item()
+            .table_name(self.bola_profiles_table.clone())
+            .key("email", AttributeValue::S(email))
+            .condition_expression("attribute_exists(username)");
+        match avatar_url {
+            Some(avatar_url) => {
+                set.push("avatar_url = :avatar_url");
+                req = req.expression_attribute_values(":avatar_url", AttributeValue::S(avatar_url));
+            }
+            None => remove.push("avatar_url"),
+        }
+        match country {
+            Some(country) => {
+                set.push("country = :country");
+                req = req.expression_attribute_values(":country", AttributeValue::S(country));
+            }
+            None => remove.push("country"),
+        }
+        let update = [("SET", set), ("REMOVE", remove)]
+            .into_iter()
+            .filter(|(_, attributes)| !attributes.is_empty())
+            .map(|(action, attributes)| format!("{action} {}", attributes.join(", ")))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        match req
+            .update_expression(update)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|e| e.into_service_error())
+        {
+            Ok(x) => {
+                permit.consume(x.consumed_capacity());
+                Ok(true)
+            }
+            Err(e) => match &e.kind {
+                UpdateItemErrorKind::ConditionalCheckFailedException(_) => Ok(false),
+                _ => Err(e.into()),
+            },
         }
     }
 
+    /// Bans the user, which also takes them off every leaderboard index, as
+    /// those only hold profiles with the `unused` key
+    ///
+    /// Returns the username of the user, or None if they have no profile
+    pub async fn ban_user(&self, email: String) -> Result<Option<String>, Error> {
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        let output = match self
+            .client
+            .update_item()
+            .table_name(self.bola_profiles_table.clone())
+            .key("email", AttributeValue::S(email))
+            .update_expression("SET banned_at = if_not_exists(banned_at, :now) REMOVE unused")
+            .condition_expression("attribute_exists(username)")
+            .expression_attribute_values(":now", AttributeValue::N(now().to_string()))
+            .return_values(ReturnValue::AllNew)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|e| e.into_service_error())
+        {
+            Ok(x) => x,
+            Err(e) => {
+                return match &e.kind {
+                    UpdateItemErrorKind::ConditionalCheckFailedException(_) => Ok(None),
+                    _ => Err(e.into()),
+                }
+            }
+        };
+        permit.consume(output.consumed_capacity());
+        output
+            .attributes()
+            .and_then(|x| x.get("username"))
+            .and_then(|x| x.as_s().ok())
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| anyhow!("Missing username in banned user profile"))
+    }
+
     pub async fn win_tournament(&self, week: u64, email: String) -> Result<(), Error> {
         let mut tournament_wins = self
             .client
@@ -177,7 +727,11 @@ impl DB {
             tournament_wins.push(week);
         }
 
-        match self
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        let output = self
             .client
             .update_item()
             .table_name(self.bola_profiles_table.clone())
@@ -189,11 +743,371 @@ impl DB {
                     .value(AttributeValue::Ns(tournament_wins))
                     .build(),
             )
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+        Ok(())
+    }
+
+    /// Records the purchase and adds the product to the cosmetics of the user
+    ///
+    /// Purchases are keyed by store and transaction, so granting twice does nothing.
+    /// Both are written in one transaction, so a purchase is never recorded
+    /// without its product
+    pub async fn grant_purchase(
+        &self,
+        email: String,
+        store: &str,
+        transaction_id: String,
+        product_id: String,
+    ) -> Result<GrantResult, Error> {
+        // The purchase is written first, so that the first cancellation reason
+        // tells whether it was already granted
+        let writes = vec![
+            TransactWriteItem::builder()
+                .put(
+                    Put::builder()
+                        .table_name(self.bola_purchases_table.clone())
+                        .item(
+                            "transaction_id",
+                            AttributeValue::S(format!("{store}:{transaction_id}")),
+                        )
+                        .item("email", AttributeValue::S(email.clone()))
+                        .item("product_id", AttributeValue::S(product_id.clone()))
+                        .item("status", AttributeValue::S("granted".into()))
+                        .condition_expression("attribute_not_exists(transaction_id)")
+                        .build(),
+                )
+                .build(),
+            TransactWriteItem::builder()
+                .update(
+                    Update::builder()
+                        .table_name(self.bola_profiles_table.clone())
+                        .key("email", AttributeValue::S(email))
+                        .update_expression("ADD cosmetics :product")
+                        .expression_attribute_values(
+                            ":product",
+                            AttributeValue::Ss(vec![product_id]),
+                        )
+                        // ADD would otherwise create a profile with only cosmetics.
+                        // Aliases left behind by email changes have no username
+                        .condition_expression("attribute_exists(username)")
+                        .build(),
+                )
+                .build(),
+        ];
+
+        // The transaction writes to both tables, so it needs the budget of each
+        let purchases_permit = self
+            .budgets
+            .acquire(&self.bola_purchases_table, OperationClass::Write)
+            .await?;
+        let profiles_permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        match self
+            .client
+            .transact_write_items()
+            .set_transact_items(Some(writes))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
             .send()
             .await
+            .map_err(|e| e.into_service_error())
         {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+            Ok(x) => {
+                purchases_permit.consume(table_capacity(
+                    x.consumed_capacity(),
+                    &self.bola_purchases_table,
+                ));
+                profiles_permit.consume(table_capacity(
+                    x.consumed_capacity(),
+                    &self.bola_profiles_table,
+                ));
+                Ok(GrantResult::Granted)
+            }
+            Err(e) => match &e.kind {
+                TransactWriteItemsErrorKind::TransactionCanceledException(cancelled) => {
+                    let failed = |i: usize| {
+                        cancelled
+                            .cancellation_reasons()
+                            .and_then(|x| x.get(i))
+                            .and_then(|x| x.code())
+                            == Some("ConditionalCheckFailed")
+                    };
+                    if failed(0) {
+                        Ok(GrantResult::AlreadyGranted)
+                    } else if failed(1) {
+                        Ok(GrantResult::NoProfile)
+                    } else {
+                        Err(e.into())
+                    }
+                }
+                _ => Err(e.into()),
+            },
         }
     }
+
+    /// Marks the purchase as revoked and removes the product from the cosmetics of the user
+    ///
+    /// Returns the email of the user, or None if the purchase is unknown
+    pub async fn revoke_purchase(
+        &self,
+        store: &str,
+        transaction_id: String,
+    ) -> Result<Option<String>, Error> {
+        let Some((email, product_id)) = self
+            .set_purchase_status(store, transaction_id, "revoked")
+            .await? else {
+            return Ok(None)
+        };
+
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        let output = self
+            .client
+            .update_item()
+            .table_name(self.bola_profiles_table.clone())
+            .key("email", AttributeValue::S(email.clone()))
+            .update_expression("DELETE cosmetics :product")
+            .expression_attribute_values(":product", AttributeValue::Ss(vec![product_id]))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+
+        Ok(Some(email))
+    }
+
+    /// Marks the purchase as granted again, restoring the product if it was removed
+    ///
+    /// Returns false if the purchase is unknown
+    pub async fn renew_purchase(&self, store: &str, transaction_id: String) -> Result<bool, Error> {
+        let Some((email, product_id)) = self
+            .set_purchase_status(store, transaction_id, "granted")
+            .await? else {
+            return Ok(false)
+        };
+
+        self.add_cosmetic(email, product_id).await?;
+        Ok(true)
+    }
+
+    async fn add_cosmetic(&self, email: String, product_id: String) -> Result<(), Error> {
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Write)
+            .await?;
+        let output = self
+            .client
+            .update_item()
+            .table_name(self.bola_profiles_table.clone())
+            .key("email", AttributeValue::S(email))
+            .update_expression("ADD cosmetics :product")
+            .expression_attribute_values(":product", AttributeValue::Ss(vec![product_id]))
+            .condition_expression("attribute_exists(username)")
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+        Ok(())
+    }
+
+    /// Returns the email and product of the purchase, or None if it does not exist
+    async fn set_purchase_status(
+        &self,
+        store: &str,
+        transaction_id: String,
+        status: &str,
+    ) -> Result<Option<(String, String)>, Error> {
+        let permit = self
+            .budgets
+            .acquire(&self.bola_purchases_table, OperationClass::Write)
+            .await?;
+        let output = match self
+            .client
+            .update_item()
+            .table_name(self.bola_purchases_table.clone())
+            .key(
+                "transaction_id",
+                AttributeValue::S(format!("{store}:{transaction_id}")),
+            )
+            .update_expression("SET #status = :status")
+            .condition_expression("attribute_exists(transaction_id)")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":status", AttributeValue::S(status.into()))
+            .return_values(ReturnValue::AllNew)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|e| e.into_service_error())
+        {
+            Ok(x) => {
+                permit.consume(x.consumed_capacity());
+                x
+            }
+            Err(e) => {
+                return match &e.kind {
+                    UpdateItemErrorKind::ConditionalCheckFailedException(_) => Ok(None),
+                    _ => Err(e.into()),
+                }
+            }
+        };
+
+        let attributes = output
+            .attributes()
+            .ok_or_else(|| anyhow!("Missing attributes in purchase"))?;
+        let email = attributes
+            .get("email")
+            .and_then(|x| x.as_s().ok())
+            .ok_or_else(|| anyhow!("Missing email in purchase"))?;
+        let product_id = attributes
+            .get("product_id")
+            .and_then(|x| x.as_s().ok())
+            .ok_or_else(|| anyhow!("Missing product_id in purchase"))?;
+
+        Ok(Some((email.clone(), product_id.clone())))
+    }
+}
+
+#[async_trait]
+impl RevocationStore for &'static DB {
+    async fn save_revocation(&self, token_hash: &str, expires_at: u64) -> Result<(), Error> {
+        let permit = self
+            .budgets
+            .acquire(&self.bola_revocations_table, OperationClass::Write)
+            .await?;
+        let output = self
+            .client
+            .put_item()
+            .table_name(self.bola_revocations_table.clone())
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .item("token_hash", AttributeValue::S(token_hash.to_string()))
+            .item("expires_at", AttributeValue::N(expires_at.to_string()))
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+        Ok(())
+    }
+
+    async fn load_revocations(&self) -> Result<Vec<(String, u64)>, Error> {
+        let mut out = vec![];
+        let mut start_key = None;
+
+        loop {
+            let output = self
+                .client
+                .scan()
+                .table_name(self.bola_revocations_table.clone())
+                .filter_expression("expires_at > :now")
+                .expression_attribute_values(":now", AttributeValue::N(now().to_string()))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+
+            for item in output.items().unwrap_or_default() {
+                let token_hash = item.get("token_hash").and_then(|x| x.as_s().ok());
+                let expires_at = item
+                    .get("expires_at")
+                    .and_then(|x| x.as_n().ok())
+                    .and_then(|x| x.parse().ok());
+                if let (Some(token_hash), Some(expires_at)) = (token_hash, expires_at) {
+                    out.push((token_hash.clone(), expires_at));
+                }
+            }
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break Ok(out);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ProfileStore for DB {
+    async fn scan_profiles(
+        &self,
+        since: Option<u64>,
+        limit: u32,
+        after: Option<String>,
+    ) -> Result<ProfilePage, Error> {
+        let mut req = self
+            .client
+            .scan()
+            .table_name(self.bola_profiles_table.clone())
+            .limit(limit as i32)
+            .set_exclusive_start_key(
+                after.map(|email| HashMap::from([("email".into(), AttributeValue::S(email))])),
+            )
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+        if let Some(since) = since {
+            req = req
+                .filter_expression("created_at >= :since")
+                .expression_attribute_values(":since", AttributeValue::N(since.to_string()));
+        }
+
+        let permit = self
+            .budgets
+            .acquire(&self.bola_profiles_table, OperationClass::Read)
+            .await?;
+        let output = req.send().await.context("Scanning profiles")?;
+        permit.consume(output.consumed_capacity());
+
+        let mut page = ProfilePage {
+            profiles: vec![],
+            aliases: 0,
+            scanned: output.scanned_count() as u32,
+            last_email: output
+                .last_evaluated_key()
+                .and_then(|x| x.get("email"))
+                .and_then(|x| x.as_s().ok())
+                .cloned(),
+        };
+        for item in output.items().unwrap_or_default() {
+            if item.contains_key("alias_of") {
+                page.aliases += 1;
+            } else {
+                page.profiles.push(map_to_profile_record(item));
+            }
+        }
+        Ok(page)
+    }
+
+    async fn is_username_taken(&self, username: String) -> Result<bool, Error> {
+        DB::is_username_taken(self, username).await
+    }
+
+    async fn put_user_profile(
+        &self,
+        email: String,
+        profile: UserProfile,
+        created_at: Option<u64>,
+    ) -> Result<bool, Error> {
+        DB::put_user_profile(self, email, profile, created_at).await
+    }
+}
+
+fn map_to_profile_record(item: &HashMap<String, AttributeValue>) -> Result<ProfileRecord, Error> {
+    let email = item
+        .get("email")
+        .and_then(|x| x.as_s().ok())
+        .context("Missing email")?
+        .clone();
+    let created_at = item
+        .get("created_at")
+        .and_then(|x| x.as_n().ok())
+        .map(|x| x.parse())
+        .transpose()
+        .context(format!("Parsing created_at of {email}"))?;
+    let profile = DB::map_to_user_profile(item).context(format!("Validating {email}"))?;
+
+    Ok(ProfileRecord {
+        email,
+        created_at,
+        profile,
+    })
 }
@@ -0,0 +1,78 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+/// The difficulty a score was set on
+///
+/// Serialized as its lowercase name, so it is compatible with the plain
+/// strings used by clients and older nodes
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(try_from = "String", into = "&'static str")]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Expert,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 3] = [Difficulty::Easy, Difficulty::Normal, Difficulty::Expert];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Normal => "normal",
+            Difficulty::Expert => "expert",
+        }
+    }
+
+    /// The attribute of a user profile that holds the highscore on this difficulty
+    pub fn highscore_field(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy_highscore",
+            Difficulty::Normal => "normal_highscore",
+            Difficulty::Expert => "expert_highscore",
+        }
+    }
+
+    /// The attribute of a user profile that holds when its highscore on this
+    /// difficulty is revealed, if it was submitted during a blind period
+    pub fn hidden_until_field(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy_hidden_until",
+            Difficulty::Normal => "normal_hidden_until",
+            Difficulty::Expert => "expert_hidden_until",
+        }
+    }
+}
+
+impl Display for Difficulty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|difficulty| difficulty.as_str() == s)
+            .ok_or_else(|| anyhow!("Unknown difficulty {s}"))
+    }
+}
+
+impl TryFrom<String> for Difficulty {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Difficulty> for &'static str {
+    fn from(value: Difficulty) -> Self {
+        value.as_str()
+    }
+}
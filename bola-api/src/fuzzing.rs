@@ -0,0 +1,28 @@
+//! What the fuzz targets exercise, as they can only reach what the library exports
+use std::io::Cursor;
+
+use messagist::{bin::BinaryMessageStream, MessageStream};
+
+use crate::{network::NetworkMessage, ws_api::WSAPIMessage};
+
+/// Receives messages of the WebSocket API until one cannot be received, which
+/// is when `WsApiHandler` ends the session, returning how many were received
+///
+/// The messages are dropped rather than handled, as handling them needs the
+/// database
+pub async fn recv_ws_messages<S: MessageStream>(stream: &mut S) -> usize {
+    let mut received = 0;
+    while stream.recv_message::<WSAPIMessage>().await.is_ok() {
+        received += 1;
+    }
+    received
+}
+
+/// Receives a sibling message from the bytes sent by a sibling, returning
+/// whether it could be received
+pub async fn recv_sibling_message(bytes: Vec<u8>) -> bool {
+    BinaryMessageStream::from(Cursor::new(bytes))
+        .recv_message::<NetworkMessage>()
+        .await
+        .is_ok()
+}
@@ -1,22 +1,46 @@
-use std::{ops::Deref, sync::Arc, time::Instant};
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, Context};
-use aws_sdk_dynamodb::model::{AttributeAction, AttributeValue, AttributeValueUpdate};
+use aws_sdk_dynamodb::{
+    error::UpdateItemErrorKind,
+    model::{
+        AttributeAction, AttributeValue, AttributeValueUpdate, ReturnConsumedCapacity, ReturnValue,
+    },
+};
 use derive_more::{Display, Error};
-use log::error;
-use mangle_api_core::{distributed::Node, parking_lot::RwLock};
-use serde::Serialize;
+use log::{error, warn};
+use mangle_api_core::{
+    distributed::Node,
+    parking_lot::{Mutex, RwLock},
+    persistent_queue::PersistentQueue,
+};
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use tokio::{
     spawn,
-    sync::broadcast::{channel, Sender},
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    time::sleep,
 };
 
 use crate::{
+    budget::{CapacityPermit, OperationClass},
     db::DB,
-    network::{HighscoreUpdate, NetworkMessage, SiblingNetworkHandler},
+    difficulty::Difficulty,
+    network::{HighscoreUpdate, LeaderboardReset, NetworkMessage, SiblingNetworkHandler},
+    tournament::Tournament,
 };
 
-const LEADERBOARD_UPDATE_BUFFER_SIZE: usize = 8;
+/// Used when the tournament week could not be calculated
+const REVEAL_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How long queued scores wait after the budget shed them or a write failed
+const SCORE_RETRY_DELAY: Duration = Duration::from_secs(5);
 
 #[derive(Serialize)]
 pub enum LeaderboardUpdate {
@@ -28,10 +52,109 @@ pub enum LeaderboardUpdate {
     Expert(Vec<LeaderboardEntry>),
 }
 
-#[derive(PartialOrd, Ord, PartialEq, Eq, Clone, Serialize)]
+impl LeaderboardUpdate {
+    fn new(difficulty: Difficulty, leaderboard: Vec<LeaderboardEntry>) -> Self {
+        match difficulty {
+            Difficulty::Easy => Self::Easy(leaderboard),
+            Difficulty::Normal => Self::Normal(leaderboard),
+            Difficulty::Expert => Self::Expert(leaderboard),
+        }
+    }
+
+    pub fn get_difficulty(&self) -> Difficulty {
+        match self {
+            Self::Easy(_) => Difficulty::Easy,
+            Self::Normal(_) => Difficulty::Normal,
+            Self::Expert(_) => Difficulty::Expert,
+        }
+    }
+
+    /// The full standings of the difficulty after the update, with hidden
+    /// entries masked
+    pub fn get_entries(&self) -> &[LeaderboardEntry] {
+        match self {
+            Self::Easy(x) | Self::Normal(x) | Self::Expert(x) => x,
+        }
+    }
+}
+
+/// What a subscription does once its buffer fills up and updates are dropped
+#[derive(Clone, Copy, Debug)]
+pub enum LagPolicy {
+    /// Carries on with the updates after the dropped ones, which suits
+    /// consumers that only care about the latest score
+    Skip,
+    /// Replaces the buffered updates with the current standings of every
+    /// difficulty, which suits consumers that mirror the leaderboard
+    Resync,
+    /// Ends the subscription, for consumers that must see every update
+    Close,
+}
+
+struct Subscriber {
+    sender: Sender<Arc<LeaderboardUpdate>>,
+    lagged: Arc<AtomicBool>,
+}
+
+#[derive(PartialOrd, Ord, PartialEq, Eq, Clone, Default)]
 pub struct LeaderboardEntry {
     pub score: u16,
     pub username: String,
+    /// Filled in from the profile of the user when the entry is added
+    pub avatar_url: Option<String>,
+    pub country: Option<String>,
+    /// Set on scores submitted during the blind period of a tournament, which
+    /// are shown as "?" until the tournament week ends
+    ///
+    /// Persisted as when the score is revealed, so that restarts keep it hidden
+    pub hidden: bool,
+}
+
+impl Serialize for LeaderboardEntry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let len = 2 + self.avatar_url.is_some() as usize + self.country.is_some() as usize;
+        let mut state = serializer.serialize_struct("LeaderboardEntry", len)?;
+        if self.hidden {
+            state.serialize_field("score", "?")?;
+        } else {
+            state.serialize_field("score", &self.score)?;
+        }
+        state.serialize_field("username", &self.username)?;
+        if let Some(avatar_url) = &self.avatar_url {
+            state.serialize_field("avatar_url", avatar_url)?;
+        } else {
+            state.skip_field("avatar_url")?;
+        }
+        if let Some(country) = &self.country {
+            state.serialize_field("country", country)?;
+        } else {
+            state.skip_field("country")?;
+        }
+        state.end()
+    }
+}
+
+/// Moves hidden entries to the end so that their rank does not reveal their score
+fn mask(leaderboard: &[LeaderboardEntry]) -> Vec<LeaderboardEntry> {
+    let (mut visible, mut hidden): (Vec<_>, Vec<_>) =
+        leaderboard.iter().cloned().partition(|entry| !entry.hidden);
+    hidden.sort_by(|a, b| a.username.cmp(&b.username));
+    visible.append(&mut hidden);
+    visible
+}
+
+/// Reads an optional string attribute, as the highscore indices may not project it
+fn optional_string(record: &HashMap<String, AttributeValue>, name: &str) -> Option<String> {
+    record.get(name).and_then(|x| x.as_s().ok()).cloned()
+}
+
+/// A score that the write budget shed, which is written once the budget allows
+#[derive(Deserialize, Serialize)]
+pub struct QueuedScore {
+    difficulty: Difficulty,
+    email: String,
+    username: String,
+    score: u16,
 }
 
 pub struct Leaderboard {
@@ -43,10 +166,14 @@ pub struct Leaderboard {
 
     leaderboard_span: usize,
 
-    leaderboard_updater: Sender<Arc<LeaderboardUpdate>>,
+    /// Each subscriber has its own buffer, so a slow consumer only ever
+    /// drops its own updates
+    subscribers: Mutex<Vec<Subscriber>>,
 
     db: &'static DB,
     node: &'static Node<SiblingNetworkHandler>,
+    tournament: &'static Tournament,
+    score_queue: &'static PersistentQueue<QueuedScore>,
 }
 
 #[derive(Error, Display, Debug)]
@@ -62,11 +189,58 @@ pub struct LeaderboardView {
 }
 
 impl Leaderboard {
+    /// Whether the highscore of the user on the difficulty was submitted
+    /// during a blind period that has not ended
+    ///
+    /// Read from the table, as the highscore indices may not project it
+    async fn pull_hidden(
+        db: &DB,
+        difficulty: Difficulty,
+        email: &str,
+    ) -> Result<bool, anyhow::Error> {
+        let field = difficulty.hidden_until_field();
+        let permit = db
+            .budgets
+            .acquire(&db.bola_profiles_table, OperationClass::Read)
+            .await?;
+        let output = db
+            .client
+            .get_item()
+            .table_name(db.bola_profiles_table.clone())
+            .key("email", AttributeValue::S(email.to_string()))
+            .projection_expression("#hidden_until")
+            .expression_attribute_names("#hidden_until", field)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+
+        let Some(hidden_until) = output.item().and_then(|item| item.get(field)) else {
+            return Ok(false);
+        };
+        let hidden_until: u64 = hidden_until
+            .as_n()
+            .map_err(|e| anyhow!("{field} is not a number {e:?} for {email}"))?
+            .parse()
+            .context(format!("Parsing {field} for {email}"))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        Ok(hidden_until > now)
+    }
+
     async fn pull_leaderboard(
         db: &DB,
-        leaderboard_name: &str,
+        difficulty: Difficulty,
         leaderboard_span: usize,
+        tournament: &Tournament,
     ) -> Result<Vec<LeaderboardEntry>, anyhow::Error> {
+        let leaderboard_name = difficulty.highscore_field();
+        let permit = db
+            .budgets
+            .acquire(&db.bola_profiles_table, OperationClass::Read)
+            .await?;
         let query = db
             .client
             .query()
@@ -76,12 +250,14 @@ impl Leaderboard {
             .expression_attribute_values(":partitionkeyval", AttributeValue::N("0".into()))
             .scan_index_forward(false)
             .limit(leaderboard_span as i32)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
             .send()
             .await?;
+        permit.consume(query.consumed_capacity());
 
         let items = query
             .items()
-            .ok_or(anyhow!("No items in easy_highscore query"))?;
+            .ok_or(anyhow!("No items in {leaderboard_name} query"))?;
         let mut leaderboard = Vec::with_capacity(leaderboard_span);
 
         for record in items {
@@ -107,7 +283,20 @@ impl Leaderboard {
                 .map_err(|e| anyhow!("username is not a string {e:?} for {email}"))?
                 .clone();
 
-            leaderboard.push(LeaderboardEntry { score, username });
+            // Without a blind period, no score could have been hidden
+            let hidden = if tournament.has_blind_period() {
+                Self::pull_hidden(db, difficulty, &email).await?
+            } else {
+                false
+            };
+
+            leaderboard.push(LeaderboardEntry {
+                score,
+                username,
+                avatar_url: optional_string(record, "avatar_url"),
+                country: optional_string(record, "country"),
+                hidden,
+            });
         }
 
         Ok(leaderboard)
@@ -116,23 +305,29 @@ impl Leaderboard {
     pub async fn new(
         db: &'static DB,
         node: &'static Node<SiblingNetworkHandler>,
+        tournament: &'static Tournament,
+        score_queue: &'static PersistentQueue<QueuedScore>,
         leaderboard_span: usize,
     ) -> Result<&'static Self, anyhow::Error> {
         let leaderboard = manglext::immut_leak(Self {
             easy_leaderboard: RwLock::new(
-                Self::pull_leaderboard(&db, "easy_highscore", leaderboard_span).await?,
+                Self::pull_leaderboard(&db, Difficulty::Easy, leaderboard_span, tournament).await?,
             ),
             normal_leaderboard: RwLock::new(
-                Self::pull_leaderboard(&db, "normal_highscore", leaderboard_span).await?,
+                Self::pull_leaderboard(&db, Difficulty::Normal, leaderboard_span, tournament)
+                    .await?,
             ),
             expert_leaderboard: RwLock::new(
-                Self::pull_leaderboard(&db, "expert_highscore", leaderboard_span).await?,
+                Self::pull_leaderboard(&db, Difficulty::Expert, leaderboard_span, tournament)
+                    .await?,
             ),
             last_update: RwLock::new(Instant::now()),
             leaderboard_span,
-            leaderboard_updater: channel(LEADERBOARD_UPDATE_BUFFER_SIZE).0,
+            subscribers: Default::default(),
             db,
             node,
+            tournament,
+            score_queue,
         });
         let mut subscription = node.get_handler().subscribe_to_highscore_update();
 
@@ -142,58 +337,261 @@ impl Leaderboard {
                     break
                 };
 
-                match msg.difficulty.as_str() {
-                    "easy" => leaderboard.local_update_leaderboard(
-                        &leaderboard.easy_leaderboard,
-                        LeaderboardEntry {
-                            score: msg.score,
-                            username: msg.username,
-                        },
-                        LeaderboardUpdate::Easy,
-                    ),
-                    "normal" => leaderboard.local_update_leaderboard(
-                        &leaderboard.normal_leaderboard,
-                        LeaderboardEntry {
-                            score: msg.score,
-                            username: msg.username,
-                        },
-                        LeaderboardUpdate::Normal,
-                    ),
-                    "expert" => leaderboard.local_update_leaderboard(
-                        &leaderboard.expert_leaderboard,
-                        LeaderboardEntry {
-                            score: msg.score,
-                            username: msg.username,
-                        },
-                        LeaderboardUpdate::Expert,
-                    ),
-                    s => {
-                        error!(target: "leaderboard", "Found unexpected leaderboard_difficulty: {s}");
-                        continue;
-                    }
+                leaderboard.local_update_leaderboard(
+                    msg.difficulty,
+                    LeaderboardEntry {
+                        score: msg.score,
+                        username: msg.username,
+                        avatar_url: msg.avatar_url,
+                        country: msg.country,
+                        hidden: tournament.is_blind(),
+                    },
+                );
+            }
+        });
+
+        let mut subscription = node.get_handler().subscribe_to_username_change();
+
+        spawn(async move {
+            loop {
+                let Some(change) = subscription.wait_for_change().await else {
+                    break
+                };
+                leaderboard.rename_user(&change.old_username, &change.new_username);
+            }
+        });
+
+        spawn(leaderboard.write_queued_scores());
+
+        let mut subscription = node.get_handler().subscribe_to_user_ban();
+
+        spawn(async move {
+            loop {
+                let Some(ban) = subscription.wait_for_ban().await else {
+                    break
                 };
+                leaderboard.remove_user(&ban.username);
+            }
+        });
+
+        let mut subscription = node.get_handler().subscribe_to_leaderboard_reset();
+
+        spawn(async move {
+            loop {
+                let Some(reset) = subscription.wait_for_reset().await else {
+                    break
+                };
+                leaderboard.local_reset(reset.difficulty);
+            }
+        });
+
+        spawn(async move {
+            loop {
+                let Some(remaining) = tournament.time_until_week_end() else {
+                    sleep(REVEAL_RETRY_DELAY).await;
+                    continue
+                };
+                sleep(remaining).await;
+                leaderboard.reveal();
             }
         });
 
         Ok(leaderboard)
     }
 
-    fn local_update_leaderboard(
-        &self,
-        leaderboard: &RwLock<Vec<LeaderboardEntry>>,
-        entry: LeaderboardEntry,
-        update_fn: impl Fn(Vec<LeaderboardEntry>) -> LeaderboardUpdate,
-    ) -> bool {
-        let mut leaderboard_writer = leaderboard.write();
+    /// Reveals every hidden score and sends out the full standings
+    fn reveal(&self) {
+        for difficulty in Difficulty::ALL {
+            let mut leaderboard_writer = self.leaderboard(difficulty).write();
+            if !leaderboard_writer.iter().any(|entry| entry.hidden) {
+                continue;
+            }
+            leaderboard_writer
+                .iter_mut()
+                .for_each(|entry| entry.hidden = false);
+            *self.last_update.write() = Instant::now();
+            self.publish(LeaderboardUpdate::new(
+                difficulty,
+                leaderboard_writer.clone(),
+            ));
+        }
+    }
+
+    fn publish(&self, update: LeaderboardUpdate) {
+        let update = Arc::new(update);
+        self.subscribers.lock().retain(|subscriber| {
+            match subscriber.sender.try_send(update.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.lagged.store(true, Ordering::Release);
+                    true
+                }
+                // The subscription was dropped
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+
+    /// Replaces the username of every entry of the given user, sending out
+    /// the standings that changed
+    pub fn rename_user(&self, old_username: &str, new_username: &str) {
+        for difficulty in Difficulty::ALL {
+            let mut leaderboard_writer = self.leaderboard(difficulty).write();
+            let Some(entry) = leaderboard_writer
+                .iter_mut()
+                .find(|entry| entry.username == old_username)
+            else {
+                continue;
+            };
+            entry.username = new_username.to_string();
+            *self.last_update.write() = Instant::now();
+            self.publish(LeaderboardUpdate::new(
+                difficulty,
+                mask(&leaderboard_writer),
+            ));
+        }
+    }
+
+    /// Takes every entry of the given user off the standings, such as once
+    /// they are banned
+    pub fn remove_user(&self, username: &str) {
+        for difficulty in Difficulty::ALL {
+            let mut leaderboard_writer = self.leaderboard(difficulty).write();
+            let len = leaderboard_writer.len();
+            leaderboard_writer.retain(|entry| entry.username != username);
+            if leaderboard_writer.len() == len {
+                continue;
+            }
+            *self.last_update.write() = Instant::now();
+            self.publish(LeaderboardUpdate::new(
+                difficulty,
+                mask(&leaderboard_writer),
+            ));
+        }
+    }
+
+    fn local_reset(&self, difficulty: Difficulty) {
+        self.leaderboard(difficulty).write().clear();
+        *self.last_update.write() = Instant::now();
+        self.publish(LeaderboardUpdate::new(difficulty, vec![]));
+    }
+
+    /// Sets every highscore on the difficulty back to 0, such as for a new
+    /// season, and clears the standings of every node
+    ///
+    /// Scores that are set while the reset runs are kept. Returns how many
+    /// highscores were reset
+    pub async fn reset(&self, difficulty: Difficulty) -> Result<usize, anyhow::Error> {
+        let field = difficulty.highscore_field();
+        let mut reset = 0;
+        let mut start_key = None;
+
+        loop {
+            let permit = self
+                .db
+                .budgets
+                .acquire(&self.db.bola_profiles_table, OperationClass::Read)
+                .await?;
+            let output = self
+                .db
+                .client
+                .query()
+                .table_name(self.db.bola_profiles_table.clone())
+                .index_name(format!("unused-{field}-index"))
+                .key_condition_expression("unused = :unused AND #score > :zero")
+                .projection_expression("email, #score")
+                .expression_attribute_names("#score", field)
+                .expression_attribute_values(":unused", AttributeValue::N("0".into()))
+                .expression_attribute_values(":zero", AttributeValue::N("0".into()))
+                .set_exclusive_start_key(start_key)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await
+                .context(format!("Querying {field}"))?;
+            permit.consume(output.consumed_capacity());
+
+            for item in output.items().unwrap_or_default() {
+                let (Some(email), Some(score)) = (item.get("email"), item.get(field)) else {
+                    continue;
+                };
+                let permit = self
+                    .db
+                    .budgets
+                    .acquire(&self.db.bola_profiles_table, OperationClass::Write)
+                    .await?;
+                match self
+                    .db
+                    .client
+                    .update_item()
+                    .table_name(self.db.bola_profiles_table.clone())
+                    .key("email", email.clone())
+                    .update_expression("SET #score = :zero REMOVE #hidden_until")
+                    // Leaves scores that were beaten since the query alone
+                    .condition_expression("#score = :score")
+                    .expression_attribute_names("#score", field)
+                    .expression_attribute_names("#hidden_until", difficulty.hidden_until_field())
+                    .expression_attribute_values(":zero", AttributeValue::N("0".into()))
+                    .expression_attribute_values(":score", score.clone())
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .send()
+                    .await
+                    .map_err(|e| e.into_service_error())
+                {
+                    Ok(x) => {
+                        permit.consume(x.consumed_capacity());
+                        reset += 1;
+                    }
+                    Err(e) => match &e.kind {
+                        UpdateItemErrorKind::ConditionalCheckFailedException(_) => {}
+                        _ => {
+                            return Err(anyhow::Error::from(e).context(format!("Resetting {field}")))
+                        }
+                    },
+                }
+            }
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break;
+            }
+        }
+
+        self.local_reset(difficulty);
+        for (domain, err) in self
+            .node
+            .broadcast_message(
+                &NetworkMessage::LeaderboardReset(LeaderboardReset { difficulty })
+                    .traced()
+                    .signed(),
+            )
+            .await
+        {
+            error!(target: "leaderboard", "Error broadcasting reset to {}: {:?}", domain, err);
+        }
+
+        Ok(reset)
+    }
+
+    fn leaderboard(&self, difficulty: Difficulty) -> &RwLock<Vec<LeaderboardEntry>> {
+        match difficulty {
+            Difficulty::Easy => &self.easy_leaderboard,
+            Difficulty::Normal => &self.normal_leaderboard,
+            Difficulty::Expert => &self.expert_leaderboard,
+        }
+    }
+
+    fn local_update_leaderboard(&self, difficulty: Difficulty, entry: LeaderboardEntry) -> bool {
+        let mut leaderboard_writer = self.leaderboard(difficulty).write();
 
         macro_rules! update {
             () => {{
                 *self.last_update.write() = Instant::now();
                 leaderboard_writer.sort_by(|a, b| b.cmp(a));
 
-                let _ = self
-                    .leaderboard_updater
-                    .send(Arc::new((update_fn)(leaderboard_writer.clone())));
+                self.publish(LeaderboardUpdate::new(
+                    difficulty,
+                    mask(&leaderboard_writer),
+                ));
 
                 return true;
             }};
@@ -233,50 +631,151 @@ impl Leaderboard {
         update!()
     }
 
-    async fn add_leaderboard_entry(
+    /// Writes the score, or queues it if the write budget of profiles is
+    /// exhausted, so that it is written once the budget allows
+    pub async fn add_entry(
         &self,
-        leaderboard: &RwLock<Vec<LeaderboardEntry>>,
+        difficulty: Difficulty,
         email: String,
         entry: LeaderboardEntry,
-        leaderboard_difficulty: &str,
-        update_fn: impl Fn(Vec<LeaderboardEntry>) -> LeaderboardUpdate,
     ) -> Result<(), AddLeaderboardEntryError> {
-        assert!(matches!(
-            leaderboard_difficulty,
-            "easy" | "normal" | "expert"
-        ));
+        let permit = match self
+            .db
+            .budgets
+            .acquire(&self.db.bola_profiles_table, OperationClass::Write)
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(target: "leaderboard", "Queueing score of {email}: {e}");
+                let queued = QueuedScore {
+                    difficulty,
+                    email,
+                    username: entry.username,
+                    score: entry.score,
+                };
+                return match self.score_queue.push(&queued).await {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        error!(target: "leaderboard", "Not updating item for {}: {e}", queued.email);
+                        Err(AddLeaderboardEntryError::InternalError)
+                    }
+                };
+            }
+        };
+        self.write_entry(permit, difficulty, email, entry).await
+    }
 
-        if let Err(e) = self
+    /// Writes the scores that were queued, including those left over from
+    /// before a restart
+    async fn write_queued_scores(&'static self) {
+        loop {
+            let queued = self.score_queue.recv().await;
+            let permit = match self
+                .db
+                .budgets
+                .acquire(&self.db.bola_profiles_table, OperationClass::Write)
+                .await
+            {
+                Ok(x) => x,
+                // Delivered again once dropped
+                Err(_) => {
+                    drop(queued);
+                    sleep(SCORE_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let entry = LeaderboardEntry {
+                score: queued.score,
+                username: queued.username.clone(),
+                ..Default::default()
+            };
+            match self
+                .write_entry(permit, queued.difficulty, queued.email.clone(), entry)
+                .await
+            {
+                Ok(()) => {
+                    if let Err(e) = queued.ack() {
+                        error!(target: "leaderboard", "{:?}", e.context("acknowledging queued score"));
+                    }
+                }
+                Err(_) => {
+                    drop(queued);
+                    sleep(SCORE_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn write_entry(
+        &self,
+        permit: CapacityPermit<'_>,
+        difficulty: Difficulty,
+        email: String,
+        mut entry: LeaderboardEntry,
+    ) -> Result<(), AddLeaderboardEntryError> {
+        let blind_until = self.tournament.blind_until();
+        let hidden_until = match blind_until {
+            Some(blind_until) => AttributeValueUpdate::builder()
+                .action(AttributeAction::Put)
+                .value(AttributeValue::N(blind_until.to_string()))
+                .build(),
+            None => AttributeValueUpdate::builder()
+                .action(AttributeAction::Delete)
+                .build(),
+        };
+        let profile = match self
             .db
             .client
             .update_item()
             .table_name(self.db.bola_profiles_table.clone())
             .key("email", AttributeValue::S(email.clone()))
             .attribute_updates(
-                format!("{leaderboard_difficulty}_highscore"),
+                difficulty.highscore_field(),
                 AttributeValueUpdate::builder()
                     .action(AttributeAction::Put)
                     .value(AttributeValue::N(entry.score.to_string()))
                     .build(),
             )
+            .attribute_updates(difficulty.hidden_until_field(), hidden_until)
+            .return_values(ReturnValue::AllNew)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
             .send()
             .await
         {
-            error!(target: "leaderboard", "Error updating item for {}: {e:?}", email);
-            return Err(AddLeaderboardEntryError::InternalError);
+            Ok(x) => {
+                permit.consume(x.consumed_capacity());
+                x
+            }
+            Err(e) => {
+                error!(target: "leaderboard", "Error updating item for {}: {e:?}", email);
+                return Err(AddLeaderboardEntryError::InternalError);
+            }
+        };
+
+        if let Some(profile) = profile.attributes() {
+            entry.avatar_url = optional_string(profile, "avatar_url");
+            entry.country = optional_string(profile, "country");
         }
+        entry.hidden = blind_until.is_some();
 
-        if !self.local_update_leaderboard(leaderboard, entry.clone(), update_fn) {
+        if !self.local_update_leaderboard(difficulty, entry.clone()) {
             return Ok(());
         };
 
         for (domain, err) in self
             .node
-            .broadcast_message(&NetworkMessage::HighscoreUpdate(HighscoreUpdate {
-                username: entry.username,
-                difficulty: leaderboard_difficulty.into(),
-                score: entry.score,
-            }))
+            .broadcast_message(
+                &NetworkMessage::HighscoreUpdate(HighscoreUpdate {
+                    username: entry.username,
+                    difficulty,
+                    score: entry.score,
+                    avatar_url: entry.avatar_url,
+                    country: entry.country,
+                })
+                .traced()
+                .signed(),
+            )
             .await
         {
             error!(target: "leaderboard", "Error broadcasting message to {}: {:?}", domain, err);
@@ -284,53 +783,11 @@ impl Leaderboard {
 
         Ok(())
     }
-    pub async fn add_easy_entry(
-        &self,
-        email: String,
-        entry: LeaderboardEntry,
-    ) -> Result<(), AddLeaderboardEntryError> {
-        self.add_leaderboard_entry(
-            &self.easy_leaderboard,
-            email,
-            entry,
-            "easy",
-            LeaderboardUpdate::Easy,
-        )
-        .await
-    }
-    pub async fn add_normal_entry(
-        &self,
-        email: String,
-        entry: LeaderboardEntry,
-    ) -> Result<(), AddLeaderboardEntryError> {
-        self.add_leaderboard_entry(
-            &self.normal_leaderboard,
-            email,
-            entry,
-            "normal",
-            LeaderboardUpdate::Normal,
-        )
-        .await
-    }
-    pub async fn add_expert_entry(
-        &self,
-        email: String,
-        entry: LeaderboardEntry,
-    ) -> Result<(), AddLeaderboardEntryError> {
-        self.add_leaderboard_entry(
-            &self.expert_leaderboard,
-            email,
-            entry,
-            "expert",
-            LeaderboardUpdate::Expert,
-        )
-        .await
-    }
     pub fn get_leaderboard(&self) -> LeaderboardView {
         LeaderboardView {
-            easy: self.easy_leaderboard.read().clone(),
-            normal: self.normal_leaderboard.read().clone(),
-            expert: self.expert_leaderboard.read().clone(),
+            easy: mask(&self.easy_leaderboard.read()),
+            normal: mask(&self.normal_leaderboard.read()),
+            expert: mask(&self.expert_leaderboard.read()),
         }
     }
     pub fn get_leaderboard_since(&self, since: Instant) -> Option<LeaderboardView> {
@@ -340,7 +797,83 @@ impl Leaderboard {
             None
         }
     }
-    pub async fn wait_for_update(&self) -> Option<Arc<LeaderboardUpdate>> {
-        self.leaderboard_updater.subscribe().recv().await.ok()
+
+    /// Subscribes to every update made to the leaderboard from now on,
+    /// buffering up to `buffer_size` updates before `policy` applies
+    pub fn subscribe(
+        &'static self,
+        buffer_size: usize,
+        policy: LagPolicy,
+    ) -> LeaderboardSubscription {
+        let (sender, receiver) = channel(buffer_size);
+        let lagged = Arc::new(AtomicBool::new(false));
+        self.subscribers.lock().push(Subscriber {
+            sender,
+            lagged: lagged.clone(),
+        });
+        LeaderboardSubscription {
+            leaderboard: self,
+            receiver,
+            lagged,
+            policy,
+            resync: VecDeque::new(),
+        }
+    }
+}
+
+pub struct LeaderboardSubscription {
+    leaderboard: &'static Leaderboard,
+    receiver: Receiver<Arc<LeaderboardUpdate>>,
+    lagged: Arc<AtomicBool>,
+    policy: LagPolicy,
+    resync: VecDeque<Arc<LeaderboardUpdate>>,
+}
+
+impl LeaderboardSubscription {
+    /// Waits for the next update, or returns None if the subscription was
+    /// closed by its lag policy
+    pub async fn wait_for_update(&mut self) -> Option<Arc<LeaderboardUpdate>> {
+        if self.lagged.swap(false, Ordering::Acquire) {
+            match self.policy {
+                LagPolicy::Skip => {}
+                LagPolicy::Resync => {
+                    // The buffered updates are older than the current standings
+                    while self.receiver.try_recv().is_ok() {}
+                    self.resync = Difficulty::ALL
+                        .into_iter()
+                        .map(|difficulty| {
+                            Arc::new(LeaderboardUpdate::new(
+                                difficulty,
+                                mask(&self.leaderboard.leaderboard(difficulty).read()),
+                            ))
+                        })
+                        .collect();
+                }
+                LagPolicy::Close => {
+                    self.receiver.close();
+                    return None;
+                }
+            }
+        }
+        if let Some(update) = self.resync.pop_front() {
+            return Some(update);
+        }
+        self.receiver.recv().await
+    }
+
+    /// Waits for the next update of the given subscription
+    ///
+    /// Never returns if there is no subscription, and drops it once it closes
+    pub async fn next_update(subscription: &mut Option<Self>) -> Arc<LeaderboardUpdate> {
+        let Some(inner) = subscription else {
+            return std::future::pending().await;
+        };
+        match inner.wait_for_update().await {
+            Some(update) => update,
+            None => {
+                *subscription = None;
+                std::future::pending().await
+            }
+        }
     }
 }
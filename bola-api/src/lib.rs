@@ -0,0 +1,901 @@
+#![feature(trivial_bounds)]
+#![feature(string_leak)]
+#![feature(map_try_insert)]
+#![feature(vec_push_within_capacity)]
+#![feature(never_type)]
+
+use std::{iter::once, sync::Arc, time::Duration};
+
+use control::{log_pipe_name, new_control_handler, LogFollower};
+use mangle_api_core::{
+    auth::openid::openid_redirect,
+    daemon,
+    log_buffer::LogFilter,
+    metrics::Metrics,
+    neo_api::{layer::RateLimited, long_poll::POLL_TIMEOUT},
+    prelude::*,
+    rejection::not_found,
+    static_routes::StaticRoutes,
+};
+use messagist::pipes::start_concurrent_listener;
+use serde::{Deserialize, Serialize};
+use state::GlobalState;
+
+mod announcements;
+mod attestation;
+mod budget;
+mod config;
+mod connections;
+mod control;
+mod db;
+mod difficulty;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod leaderboard;
+mod moderation;
+mod multiplayer;
+mod network;
+mod profile_transfer;
+mod purchases;
+mod room_chat;
+mod search;
+mod session_metrics;
+mod state;
+mod stats;
+mod tournament;
+mod ws_api;
+
+use config::Config;
+
+use ws_api::{SessionState, WsApiHandler};
+
+use crate::{
+    budget::{BudgetConfig, BudgetPolicy, OperationClass},
+    control::{ControlClientMessage, ControlServerMessage},
+};
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct LoginTokenData {
+    username: String,
+    email: String,
+}
+
+const WS_PING_DELAY: Duration = Duration::from_secs(45);
+/// Where `start --daemon` writes the PID of the server
+const PID_FILE: &str = "bola_server.pid";
+
+#[derive(Default)]
+struct LoginRoles {
+    admin: bool,
+}
+
+enum LoginTokenConfig {}
+
+impl TokenConfig for LoginTokenConfig {
+    type TokenIdentifier = LoginTokenData;
+    type Roles = LoginRoles;
+    const TOKEN_LENGTH: usize = 32;
+}
+
+/// Players that can moderate other players from the game
+enum Admin {}
+
+impl Role for Admin {
+    type Config = LoginTokenConfig;
+    const NAME: &'static str = "admin";
+
+    fn is_granted(roles: &LoginRoles) -> bool {
+        roles.admin
+    }
+}
+
+impl HeaderTokenConfig for LoginTokenConfig {
+    const HEADER_NAME: &'static str = "Login-Token";
+}
+
+type LoginTokenGranter = TokenGranter<LoginTokenConfig>;
+
+/// The contact for the Let's Encrypt account that HTTPS certificates are obtained with
+const HTTPS_EMAIL: &str = "shabouza030@gmail.com";
+
+/// The targets whose levels can be changed with the `log_level` command
+const LOG_TARGETS: [&str; 9] = [
+    "login",
+    "purchases",
+    "leaderboard",
+    "tournament",
+    "announcements",
+    "tokens",
+    "ws_sessions",
+    "room_chat",
+    "moderation",
+];
+
+/// Runs the command given on the command line, which is the server unless
+/// another command is given
+pub async fn run() -> anyhow::Result<()> {
+    let app = ApiApp::with_log_targets(
+        "BolaAPI",
+        env!("CARGO_PKG_VERSION"),
+        "The API for Bola",
+        LOG_TARGETS,
+    )
+    .set_pipe_name("BOLA_SOCKET_NAME", "/dev/bola_server.sock")
+    .set_pid_file(PID_FILE)
+    .set_env_prefix(config::ENV_PREFIX)
+    .add_subcommands(|app| {
+        profile_transfer::add_subcommands(app)
+            .subcommand(
+                Command::new("dead_letters")
+                    .about("Inspects the messages that could not be delivered to siblings")
+                    .arg(arg!(--flush "Tries to redeliver the messages right away"))
+                    .arg(arg!(--clear "Discards the messages"))
+                    .arg(arg!(--domain <DOMAIN> "Only discards the messages of this sibling")),
+            )
+            .subcommand(
+                Command::new("budgets")
+                    .about("Shows or changes the DynamoDB capacity budgets")
+                    .arg(arg!(--table <TABLE> "The table whose budget is changed"))
+                    .arg(
+                        arg!(--class <CLASS> "The operations that the budget applies to")
+                            .value_parser(["read", "write"])
+                            .default_value("read"),
+                    )
+                    .arg(
+                        arg!(--rate <UNITS> "The capacity units restored every second")
+                            .value_parser(value_parser!(f64)),
+                    )
+                    .arg(
+                        arg!(--burst <UNITS> "The most capacity units spent at once, or the rate")
+                            .value_parser(value_parser!(f64)),
+                    )
+                    .arg(
+                        arg!(--queue <MILLIS> "Holds operations up to this long instead of failing")
+                            .value_parser(value_parser!(u64)),
+                    )
+                    .arg(arg!(--remove "Removes the budget of the table")),
+            )
+            .subcommand(
+                Command::new("screen_usernames")
+                    .about("Flags existing usernames that the profanity filter now rejects")
+                    .arg(
+                        arg!(--rate <PROFILES> "The most profiles read every second")
+                            .value_parser(value_parser!(u32).range(1..)),
+                    )
+                    .arg(arg!(--rename "Renames flagged users, telling them on their next login")),
+            )
+            .subcommand(
+                Command::new("signing_key")
+                    .about("Generates a key for signing the messages sent to siblings"),
+            )
+    });
+
+    let StartedApp {
+        config,
+        config_path,
+        pipe_name,
+        // Deletes the PID file once the server stops
+        pid_file: _pid_file,
+    } = match app.start::<Config>().await? {
+        AppStart::Started(x) => x,
+        AppStart::Done => return Ok(()),
+        AppStart::Command {
+            name,
+            matches,
+            pipe_name,
+        } => match (name.as_str(), &matches) {
+            ("stop", _) => {
+                let mut conn =
+                    match connect_with_retry(pipe_name.as_os_str(), RetryConfig::default()).await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            let Some(pid) = daemon::read_pid(PID_FILE) else {
+                                return Err(e).context("Connecting to server");
+                            };
+                            daemon::stop_pid(PID_FILE)?;
+                            println!(
+                                "Control pipe is unreachable, so stopped PID {pid} with SIGINT"
+                            );
+                            return Ok(());
+                        }
+                    };
+                conn.send_message(ControlClientMessage::Stop)
+                    .await
+                    .context("Sending Stop to server")?;
+                println!("Stop command issued...");
+                conn.wait_for_error().await;
+                println!("Server stopped succesfully");
+                return Ok(());
+            }
+            (cmd @ ("drain" | "undrain"), _) => {
+                let mut conn = connect_with_retry(pipe_name.as_os_str(), RetryConfig::default())
+                    .await
+                    .context("Connecting to server")?;
+                let msg = if cmd == "drain" {
+                    ControlClientMessage::Drain
+                } else {
+                    ControlClientMessage::Undrain
+                };
+                conn.send_message(msg)
+                    .await
+                    .context(format!("Sending {cmd} to server"))?;
+                println!("Server {cmd}ed successfully");
+                return Ok(());
+            }
+            (cmd @ ("export-profiles" | "import-profiles"), matches) => {
+                let config_path = matches
+                    .get_one::<String>("config")
+                    .cloned()
+                    .unwrap_or("configs.toml".into());
+                let config = Config::read(&config_path)?;
+                let aws_config = aws_config::from_env().load().await;
+                let db = db::DB::new(
+                    &aws_config,
+                    config.bola_profiles_table,
+                    config.bola_purchases_table,
+                    config.bola_announcements_table,
+                    config.bola_stats_table,
+                    config.bola_moderation_table,
+                    config.bola_revocations_table,
+                    config.capacity_budgets,
+                );
+                let rate = matches
+                    .get_one::<u32>("rate")
+                    .copied()
+                    .unwrap_or(profile_transfer::DEFAULT_RATE);
+
+                if cmd == "export-profiles" {
+                    let since = matches
+                        .get_one::<String>("since")
+                        .map(|since| {
+                            mangle_api_core::chrono::NaiveDate::parse_from_str(since, "%Y-%m-%d")
+                                .map(|date| date.and_hms_opt(0, 0, 0).unwrap().timestamp() as u64)
+                                .context(format!("Parsing {since}"))
+                        })
+                        .transpose()?;
+                    let out = matches.get_one::<String>("out").unwrap();
+                    let summary =
+                        profile_transfer::export_profiles(&db, out.as_ref(), since, rate).await?;
+                    println!(
+                        "Exported {} profiles, skipped {} aliases and {} invalid profiles",
+                        summary.exported, summary.aliases, summary.invalid
+                    );
+                } else {
+                    let input = matches.get_one::<String>("input").unwrap();
+                    let summary =
+                        profile_transfer::import_profiles(&db, input.as_ref(), rate).await?;
+                    println!(
+                        "Imported {} profiles, skipped {} existing, {} taken usernames, {} invalid",
+                        summary.imported,
+                        summary.already_existed,
+                        summary.username_taken,
+                        summary.invalid
+                    );
+                }
+                return Ok(());
+            }
+            ("status", _) => {
+                let mut conn =
+                    match connect_with_retry(pipe_name.as_os_str(), RetryConfig::default()).await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            let Some(pid) = daemon::read_pid(PID_FILE) else {
+                                return Err(e).context("Connecting to server");
+                            };
+                            println!("Running with PID {pid}, but the control pipe is unreachable");
+                            return Ok(());
+                        }
+                    };
+                conn.send_message(ControlClientMessage::Status)
+                    .await
+                    .context("Sending Status to server")?;
+                let ControlServerMessage::Status(status) = conn
+                    .recv_message()
+                    .await
+                    .context("Receiving Status from server")? else {
+                    return Err(anyhow::Error::msg("Unexpected reply from server"))
+                };
+                println!("{status}");
+                return Ok(());
+            }
+            ("screen_usernames", matches) => {
+                let mut conn = connect_with_retry(pipe_name.as_os_str(), RetryConfig::default())
+                    .await
+                    .context("Connecting to server")?;
+                conn.send_message(ControlClientMessage::ScreenUsernames {
+                    rate: matches.get_one::<u32>("rate").copied(),
+                    rename: matches.get_flag("rename"),
+                })
+                .await
+                .context("Sending ScreenUsernames to server")?;
+                match conn
+                    .recv_message()
+                    .await
+                    .context("Receiving ScreenUsernames from server")?
+                {
+                    ControlServerMessage::ScreeningStarted => {
+                        println!("Screening usernames, with results in the moderation logs");
+                    }
+                    ControlServerMessage::Error(e) => return Err(anyhow::Error::msg(e)),
+                    _ => return Err(anyhow::Error::msg("Unexpected reply from server")),
+                }
+                return Ok(());
+            }
+            ("reload", _) => {
+                let mut conn = connect_with_retry(pipe_name.as_os_str(), RetryConfig::default())
+                    .await
+                    .context("Connecting to server")?;
+                conn.send_message(ControlClientMessage::Reload)
+                    .await
+                    .context("Sending Reload to server")?;
+                match conn
+                    .recv_message()
+                    .await
+                    .context("Receiving Reload from server")?
+                {
+                    ControlServerMessage::Reloaded { config } => {
+                        println!("Reloaded with config: {config}");
+                    }
+                    ControlServerMessage::Error(e) => return Err(anyhow::Error::msg(e)),
+                    _ => return Err(anyhow::Error::msg("Unexpected reply from server")),
+                }
+                return Ok(());
+            }
+            ("log_level", matches) => {
+                let mut conn = connect_with_retry(pipe_name.as_os_str(), RetryConfig::default())
+                    .await
+                    .context("Connecting to server")?;
+                let msg = match matches.get_one::<String>("target") {
+                    Some(target) => ControlClientMessage::LogLevel {
+                        target: target.clone(),
+                        new_level: matches.get_one::<String>("new_level").cloned(),
+                    },
+                    None => ControlClientMessage::LogLevels,
+                };
+                conn.send_message(msg)
+                    .await
+                    .context("Sending LogLevel to server")?;
+                match conn
+                    .recv_message()
+                    .await
+                    .context("Receiving LogLevel from server")?
+                {
+                    ControlServerMessage::LogLevel { target, level } => {
+                        println!("{target}: {level}");
+                    }
+                    ControlServerMessage::LogLevels(levels) => {
+                        for (target, level) in levels {
+                            println!("{target}: {level}");
+                        }
+                    }
+                    ControlServerMessage::Error(e) => return Err(anyhow::Error::msg(e)),
+                    _ => return Err(anyhow::Error::msg("Unexpected reply from server")),
+                }
+                return Ok(());
+            }
+            ("logs", matches) => {
+                let filter = LogFilter {
+                    target: matches.get_one::<String>("target").cloned(),
+                    level: matches.get_one::<String>("level").unwrap().clone(),
+                };
+                let mut conn = connect_with_retry(
+                    log_pipe_name(&pipe_name).as_os_str(),
+                    RetryConfig::default(),
+                )
+                .await
+                .context("Connecting to server")?;
+                conn.send_message(ControlClientMessage::Logs {
+                    filter,
+                    follow: matches.get_flag("follow"),
+                })
+                .await
+                .context("Sending Logs to server")?;
+                // The server closes the connection once every log has been sent
+                while let Ok(ControlServerMessage::Log(record)) = conn.recv_message().await {
+                    println!("{record}");
+                }
+                return Ok(());
+            }
+            ("dead_letters", matches) => {
+                let msg = if matches.get_flag("flush") {
+                    ControlClientMessage::FlushDeadLetters
+                } else if matches.get_flag("clear") {
+                    ControlClientMessage::ClearDeadLetters {
+                        domain: matches.get_one::<String>("domain").cloned(),
+                    }
+                } else {
+                    ControlClientMessage::DeadLetters
+                };
+                let mut conn = connect_with_retry(pipe_name.as_os_str(), RetryConfig::default())
+                    .await
+                    .context("Connecting to server")?;
+                conn.send_message(msg)
+                    .await
+                    .context("Sending dead letter command to server")?;
+                match conn
+                    .recv_message()
+                    .await
+                    .context("Receiving dead letters from server")?
+                {
+                    ControlServerMessage::DeadLetters(stats) => {
+                        for (domain, peer) in stats.peers {
+                            println!(
+                                "{domain}: {} queued, oldest {:?}",
+                                peer.queued, peer.oldest_age
+                            );
+                        }
+                        println!(
+                            "Dropped: {}\nExpired: {}\nRedelivered: {}",
+                            stats.dropped, stats.expired, stats.redelivered
+                        );
+                    }
+                    ControlServerMessage::DeadLettersFlushed { delivered } => {
+                        println!("Redelivered {delivered} messages");
+                    }
+                    ControlServerMessage::DeadLettersCleared { discarded } => {
+                        println!("Discarded {discarded} messages");
+                    }
+                    _ => return Err(anyhow::Error::msg("Unexpected reply from server")),
+                }
+                return Ok(());
+            }
+            ("signing_key", _) => {
+                let (seed, public_key) = mangle_api_core::distributed::signing::generate_key()?;
+                println!("Private key (keep this in private_key_path): {seed}");
+                println!("Public key (give this to every sibling): {public_key}");
+                return Ok(());
+            }
+            ("budgets", matches) => {
+                let msg = if let Some(table) = matches.get_one::<String>("table") {
+                    let class = if matches.get_one::<String>("class").unwrap() == "write" {
+                        OperationClass::Write
+                    } else {
+                        OperationClass::Read
+                    };
+                    let budget = if matches.get_flag("remove") {
+                        None
+                    } else {
+                        let units_per_sec = *matches
+                            .get_one::<f64>("rate")
+                            .context("--rate is required to set a budget")?;
+                        let budget = BudgetConfig {
+                            units_per_sec,
+                            burst: matches
+                                .get_one::<f64>("burst")
+                                .copied()
+                                .unwrap_or(units_per_sec),
+                            policy: match matches.get_one::<u64>("queue") {
+                                Some(&max_wait) => BudgetPolicy::Queue {
+                                    max_wait: Duration::from_millis(max_wait),
+                                },
+                                None => BudgetPolicy::Shed,
+                            },
+                        };
+                        budget.validate()?;
+                        Some(budget)
+                    };
+                    ControlClientMessage::SetBudget {
+                        table: table.clone(),
+                        class,
+                        budget,
+                    }
+                } else {
+                    ControlClientMessage::Budgets
+                };
+                let mut conn = connect_with_retry(pipe_name.as_os_str(), RetryConfig::default())
+                    .await
+                    .context("Connecting to server")?;
+                conn.send_message(msg)
+                    .await
+                    .context("Sending budget command to server")?;
+                let ControlServerMessage::Budgets(budgets) = conn
+                    .recv_message()
+                    .await
+                    .context("Receiving budgets from server")? else {
+                    return Err(anyhow::Error::msg("Unexpected reply from server"));
+                };
+                for stats in budgets {
+                    let budget = match stats.budget {
+                        Some(budget) => format!(
+                            "{}/s, burst {}, {:?}",
+                            budget.units_per_sec, budget.burst, budget.policy
+                        ),
+                        None => "unlimited".into(),
+                    };
+                    println!("{} {}: {budget}", stats.table, stats.class);
+                    println!(
+                        "\tAvailable: {:.1}\n\tConsumed: {:.1}\n\tShed: {}\n\tQueued: {}",
+                        stats.available, stats.consumed, stats.shed, stats.queued
+                    );
+                }
+                return Ok(());
+            }
+            _ => unreachable!(),
+        },
+    };
+
+    let builder = aws_config::from_env();
+    #[cfg(debug_assertions)]
+    let builder = builder.region(aws_types::region::Region::from_static("us-east-2"));
+    let aws_config = builder.load().await;
+
+    let config_echo = config.echo();
+    info!(
+        "Starting BolaAPI {} with config: {config_echo}",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let (https_identity, certificate_renewal) = if config.https {
+        let renewal = CertificateRenewal::new(
+            config.bind_address.clone(),
+            config.certs_path.clone(),
+            config.key_path.clone(),
+            HTTPS_EMAIL.into(),
+            config.https_domain.clone(),
+        )
+        .set_renew_before(config.certificate_renew_before)
+        .set_check_interval(config.certificate_check_interval);
+        let tmp = Some(
+            get_https_credentials(
+                config.bind_address.clone(),
+                &config.certs_path,
+                &config.key_path,
+                HTTPS_EMAIL.into(),
+                config.https_domain,
+            )
+            .await?,
+        );
+        info!("HTTPS certificates loaded successfully");
+        (tmp, Some(renewal))
+    } else {
+        (None, None)
+    };
+
+    let static_routes = StaticRoutes::new(&config.redirects, &config.aliases)
+        .context("Validating redirects and aliases")?;
+    let static_dirs = config
+        .static_dirs
+        .iter()
+        .map(|dir| StaticDir::from_config(dir).context(format!("Loading {}", dir.dir)))
+        .collect::<Result<Vec<_>>>()?;
+    let http_settings = ReloadableConfig::new(config.http_settings()?);
+
+    #[cfg(feature = "aws")]
+    let route53_client = mangle_api_core::aws_sdk_route53::Client::new(&aws_config);
+    #[cfg(feature = "aws")]
+    let route53 = config.route53.clone();
+
+    let state: GlobalState = new_global!(config, https_identity, aws_config);
+    // Siblings connect with the same certificate, so it must be renewed for them too
+    let certificate_renewal = match (certificate_renewal, state.node.get_tls_acceptor()) {
+        (Some(renewal), Some(acceptor)) => Some(renewal.add_acceptor(acceptor.clone())),
+        (renewal, _) => renewal,
+    };
+
+    let tasks = TaskManager::default();
+    #[cfg(feature = "aws")]
+    let tasks = match route53 {
+        Some(route53) => {
+            let readiness = state.readiness;
+            tasks.add_task(
+                "route53",
+                RestartPolicy::Always(Duration::from_secs(10)),
+                move || {
+                    let client = route53_client.clone();
+                    let route53 = route53.clone();
+                    async move {
+                        mangle_api_core::route53::sync_route53_weight(client, route53, readiness)
+                            .await;
+                        Ok(())
+                    }
+                },
+            )
+        }
+        None => tasks,
+    };
+
+    let bind_addresses = once(&config.bind_address)
+        .chain(&config.extra_bind_addresses)
+        .map(ToString::to_string)
+        .collect();
+    let metrics = Arc::new(Metrics::default().add_gauge(
+        "ws_sessions_active",
+        "Open WebSocket sessions",
+        move || state.ws_api.get_metrics().get_active() as f64,
+    ));
+    let (control_handler, control_handler_recv) = new_control_handler(
+        &state,
+        config_echo,
+        config_path,
+        http_settings.clone(),
+        bind_addresses,
+        metrics.clone(),
+        tasks.statuses(),
+    );
+
+    // Logs are followed over their own pipe, so that followers do not hold up
+    // the control pipe
+    let log_pipe = log_pipe_name(&pipe_name);
+    let _log_listener = match start_concurrent_listener(log_pipe.as_os_str(), LogFollower) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("Logs cannot be followed, as their pipe could not be set up: {e}");
+            None
+        }
+    };
+
+    let ws_api = state.ws_api;
+    let mut api = new_api()
+        .set_state(state)
+        .set_pipe_name(pipe_name)
+        .set_api_token(HeaderValue::from_str(&config.api_token).context("parsing api_token")?)
+        .set_scoped_tokens(config.scoped_tokens)
+        .set_auth_audit(state.auth_audit.clone())
+        .set_bind_address(config.bind_address)
+        .set_tcp_config(config.tcp)
+        .set_http_settings(http_settings)
+        .set_public_paths([
+            "^/oidc/",
+            "^/purchases/",
+            "^/health$",
+            "^/leaderboard/search$",
+            "^/attestation/nonce$",
+        ])
+        .set_routes([
+            ("/oidc/redirect", openid_redirect()),
+            (
+                "/admin/announcements",
+                axum::routing::get(announcements::list_announcements)
+                    .post(announcements::create_announcement),
+            ),
+            (
+                "/admin/announcements/:id",
+                axum::routing::delete(announcements::delete_announcement),
+            ),
+            ("/admin/bandwidth", axum::routing::get(ws_api::top_talkers)),
+            (
+                "/admin/latencies",
+                axum::routing::get(ws_api::message_latencies),
+            ),
+            ("/admin/stats", axum::routing::get(stats::get_stats)),
+            (
+                "/admin/session_metrics",
+                axum::routing::get(session_metrics::export_session_metrics),
+            ),
+            (
+                "/admin/moderation",
+                axum::routing::get(moderation::list_flagged_usernames),
+            ),
+            (
+                "/admin/moderation/:email/approve",
+                axum::routing::post(moderation::approve_username),
+            ),
+            (
+                "/admin/moderation/:email/rename",
+                axum::routing::post(moderation::rename_flagged_user),
+            ),
+            (
+                "/admin/tokens",
+                axum::routing::get(|State(state): State<GlobalState>| async move {
+                    Json(state.login_tokens.get_stats())
+                }),
+            ),
+            (
+                "/staff/users/:email/ban",
+                axum::routing::post(moderation::ban_user),
+            ),
+            (
+                "/staff/leaderboard/:difficulty/reset",
+                axum::routing::post(moderation::reset_leaderboard),
+            ),
+            ("/health", health_route()),
+            (
+                "/attestation/nonce",
+                axum::routing::get(attestation::attestation_nonce),
+            ),
+            (
+                "/leaderboard/search",
+                axum::routing::get(search::search_route),
+            ),
+            (
+                "/purchases/app_store",
+                axum::routing::post(purchases::app_store_webhook),
+            ),
+            (
+                "/purchases/google_play",
+                axum::routing::post(purchases::google_play_webhook),
+            ),
+            (
+                "/ws_api",
+                ws_api_route::<_, _, RateLimited<WsApiHandler>, SessionState>(),
+            ),
+            (
+                "/ws_api/poll",
+                long_poll_route::<_, RateLimited<WsApiHandler>, SessionState>(),
+            ),
+        ])
+        // Admins moderate from the game with their login token
+        .add_route_layers(
+            "/staff/users/:email/ban",
+            [RouteLayer::require_role::<Admin>(state)],
+        )
+        .add_route_layers(
+            "/staff/leaderboard/:difficulty/reset",
+            [RouteLayer::require_role::<Admin>(state)],
+        )
+        // Polls are held open for longer than the request timeout may allow
+        .add_route_layers(
+            "/ws_api/poll",
+            [RouteLayer::Timeout(POLL_TIMEOUT + Duration::from_secs(5))],
+        )
+        // Searches are the same for every player, and each one queries DynamoDB
+        .add_route_layers(
+            "/leaderboard/search",
+            [RouteLayer::Cache(ResponseCache::new(
+                Duration::from_secs(30),
+                1000,
+            ))],
+        )
+        .set_static_routes(static_routes)
+        .set_public_fallback(not_found)
+        .set_openapi(announcements::document(OpenApi::new(
+            "bola-api",
+            env!("CARGO_PKG_VERSION"),
+        )))
+        .set_rate_limits(
+            RateLimits::from([
+                ("/ws_api", RateLimit::per_minute(10)),
+                // Each session polls about twice a minute, besides what it sends
+                ("/ws_api/poll", RateLimit::per_minute(120)),
+                ("/oidc/redirect", RateLimit::per_minute(20)),
+                ("/leaderboard/search", RateLimit::per_minute(30)),
+                ("/attestation/nonce", RateLimit::per_minute(30)),
+            ])
+            .set_token_header(LoginTokenConfig::HEADER_NAME),
+        )
+        .set_control_handler(control_handler)
+        .set_task_manager(tasks)
+        .set_concurrent_future(control_handler_recv);
+    for bind_address in config.extra_bind_addresses {
+        api = api.add_bind_address(bind_address);
+    }
+    for dir in static_dirs {
+        api = api.add_static_dir(dir);
+    }
+
+    let mut health_probes = HealthProbes::default()
+        .set_readiness(state.readiness)
+        .add_probe("dynamodb", move || state.db.ping());
+    for domain in state.node.get_sibling_domains() {
+        let domain = domain.to_string();
+        health_probes =
+            health_probes.add_informational_probe(format!("sibling:{domain}"), move || {
+                let domain = domain.clone();
+                async move { state.clock_skew.probe_sibling(&domain).await.map(drop) }
+            });
+    }
+    api = api.set_health_probes(health_probes);
+    if let Some(metrics_path) = config.metrics_path {
+        api = api.set_metrics(metrics_path, metrics);
+    }
+    if let Some(request_timeout) = config.request_timeout {
+        api = api.set_request_timeout(request_timeout);
+    }
+    if let Some(max_body_size) = config.max_body_size {
+        api = api.set_max_body_size(max_body_size);
+    }
+    if let Some(ip_filter) = config.ip_filter {
+        api = api.set_ip_filter(ip_filter);
+    }
+    if !config.trusted_proxies.is_empty() {
+        api = api.set_trusted_proxies(config.trusted_proxies, config.forwarded_header);
+    }
+    if let Some(drain_timeout) = config.shutdown.drain_timeout {
+        api = api
+            .set_drain_timeout(drain_timeout)
+            .add_drained_sessions(ws_api.get_shutdown());
+    }
+
+    let result = if let (Some(https_der), Some(renewal)) = (https_identity, certificate_renewal) {
+        api.set_https_identity(https_der)
+            .set_certificate_renewal(renewal)
+            .run()
+            .await
+    } else {
+        api.run().await
+    };
+    let remaining = ws_api
+        .get_shutdown()
+        .shutdown(config.shutdown.grace_period)
+        .await;
+    if remaining > 0 {
+        warn!("{remaining} WebSocket sessions did not close within the grace period");
+    }
+    shutdown_telemetry();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        extract::FromRequestParts,
+        http::{Request, StatusCode},
+        response::IntoResponse,
+    };
+    use mangle_api_core::{
+        auth::{
+            roles::RequireRole,
+            token::{HeaderTokenConfig, TokenGranter},
+        },
+        distributed::lock::{LockRequest, LockResponse},
+    };
+    use messagist::wire::WireCheck;
+
+    use crate::{
+        control::{ControlClientMessage, ControlServerMessage},
+        network::NetworkMessage,
+        Admin, LoginRoles, LoginTokenConfig, LoginTokenData,
+    };
+
+    struct RoleState(TokenGranter<LoginTokenConfig>);
+
+    impl AsRef<TokenGranter<LoginTokenConfig>> for RoleState {
+        fn as_ref(&self) -> &TokenGranter<LoginTokenConfig> {
+            &self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_role_is_refused_to_other_tokens() {
+        let state = RoleState(TokenGranter::new(Duration::from_secs(60)).set_roles(
+            |identifier: &LoginTokenData| LoginRoles {
+                admin: identifier.email == "admin@example.com",
+            },
+        ));
+        for (email, admin) in [("admin@example.com", true), ("player@example.com", false)] {
+            let token = state
+                .0
+                .create_token(LoginTokenData {
+                    username: email.to_string(),
+                    email: email.to_string(),
+                })
+                .access
+                .token;
+            let (mut parts, ()) = Request::builder()
+                .uri("/staff/bans")
+                .header(LoginTokenConfig::HEADER_NAME, token)
+                .body(())
+                .unwrap()
+                .into_parts();
+            match RequireRole::<Admin>::from_request_parts(&mut parts, &state).await {
+                Ok(_) => assert!(admin, "{email} was given the admin role"),
+                Err(rejection) => {
+                    assert!(!admin, "{email} was refused the admin role");
+                    assert_eq!(rejection.into_response().status(), StatusCode::FORBIDDEN);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn protocol_types_survive_every_format() {
+        let Err(failures) = WireCheck::default()
+            .check::<NetworkMessage>()
+            .check::<LockRequest>()
+            .check::<LockResponse>()
+            .check::<ControlClientMessage>()
+            .check::<ControlServerMessage>()
+            .finish()
+        else {
+            return;
+        };
+        let failures: Vec<_> = failures.iter().map(ToString::to_string).collect();
+        panic!(
+            "{} incompatibilities found:\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+    }
+}
@@ -0,0 +1,611 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{anyhow, Context, Error};
+use aws_sdk_dynamodb::{
+    error::PutItemErrorKind,
+    model::{AttributeValue, ReturnConsumedCapacity},
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use log::{error, info, warn};
+use mangle_api_core::{
+    distributed::Node,
+    pagination::{Page, Pagination},
+    rand::{thread_rng, Rng},
+};
+use rustrict::CensorStr;
+use serde::{Deserialize, Serialize};
+use tokio::spawn;
+
+use crate::{
+    announcements::now,
+    budget::OperationClass,
+    db::DB,
+    difficulty::Difficulty,
+    leaderboard::Leaderboard,
+    network::{NetworkMessage, SiblingNetworkHandler, TokenRevocation, UserBan, UsernameChange},
+    profile_transfer::RateLimiter,
+    state::GlobalState,
+    LoginTokenData, LoginTokenGranter,
+};
+
+/// How many generated usernames are tried before giving up on a rename
+const RENAME_ATTEMPTS: usize = 8;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReviewStatus {
+    #[serde(rename = "pending")]
+    Pending,
+    /// A moderator decided that the username is fine, so it is not flagged again
+    #[serde(rename = "approved")]
+    Approved,
+    #[serde(rename = "renamed")]
+    Renamed,
+}
+
+impl ReviewStatus {
+    fn name(self) -> &'static str {
+        match self {
+            ReviewStatus::Pending => "pending",
+            ReviewStatus::Approved => "approved",
+            ReviewStatus::Renamed => "renamed",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pending" => Some(ReviewStatus::Pending),
+            "approved" => Some(ReviewStatus::Approved),
+            "renamed" => Some(ReviewStatus::Renamed),
+            _ => None,
+        }
+    }
+}
+
+/// A username in the moderation review queue
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FlaggedUsername {
+    pub email: String,
+    pub username: String,
+    /// Unix time in seconds
+    pub flagged_at: u64,
+    pub status: ReviewStatus,
+    /// The username that replaced the flagged one
+    pub renamed_to: Option<String>,
+}
+
+#[derive(Default, Debug)]
+pub struct ScreeningSummary {
+    pub screened: usize,
+    pub flagged: usize,
+    pub renamed: usize,
+}
+
+/// Screens the usernames of existing profiles, such as after the profanity
+/// dictionary is updated, and keeps the review queue of flagged usernames
+pub struct Moderation {
+    db: &'static DB,
+    node: &'static Node<SiblingNetworkHandler>,
+    leaderboard: &'static Leaderboard,
+    login_tokens: &'static LoginTokenGranter,
+    default_rate: u32,
+    screening: AtomicBool,
+}
+
+impl Moderation {
+    pub fn new(
+        db: &'static DB,
+        node: &'static Node<SiblingNetworkHandler>,
+        leaderboard: &'static Leaderboard,
+        login_tokens: &'static LoginTokenGranter,
+        default_rate: u32,
+    ) -> Self {
+        Self {
+            db,
+            node,
+            leaderboard,
+            login_tokens,
+            default_rate,
+            screening: AtomicBool::new(false),
+        }
+    }
+
+    /// Screens every username in the background, reading at most `rate`
+    /// profiles a second, and renaming flagged users if `rename` is set
+    ///
+    /// Returns false if a screening is already running on this node
+    pub fn start_screening(&'static self, rate: Option<u32>, rename: bool) -> bool {
+        if self.screening.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        let rate = rate.unwrap_or(self.default_rate);
+        info!(target: "moderation", "Screening usernames at {rate} profiles a second");
+
+        spawn(async move {
+            match self.screen_usernames(rate, rename).await {
+                Ok(summary) => info!(
+                    target: "moderation",
+                    "Screened {} usernames, flagging {} and renaming {}",
+                    summary.screened, summary.flagged, summary.renamed
+                ),
+                Err(e) => error!(target: "moderation", "{:?}", e.context("screening usernames")),
+            }
+            self.screening.store(false, Ordering::Release);
+        });
+
+        true
+    }
+
+    async fn screen_usernames(&self, rate: u32, rename: bool) -> Result<ScreeningSummary, Error> {
+        let mut summary = ScreeningSummary::default();
+        let mut limiter = RateLimiter::new(rate);
+        let mut start_key = None;
+
+        loop {
+            let permit = self
+                .db
+                .budgets
+                .acquire(&self.db.bola_profiles_table, OperationClass::Read)
+                .await?;
+            let output = self
+                .db
+                .client
+                .scan()
+                .table_name(self.db.bola_profiles_table.clone())
+                .projection_expression("email, username")
+                .limit(rate as i32)
+                .set_exclusive_start_key(start_key)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await
+                .context("Scanning profiles")?;
+            permit.consume(output.consumed_capacity());
+
+            for item in output.items().unwrap_or_default() {
+                // Aliases left behind by email changes have no username
+                let (Some(email), Some(username)) = (
+                    item.get("email").and_then(|x| x.as_s().ok()),
+                    item.get("username").and_then(|x| x.as_s().ok()),
+                ) else {
+                    continue;
+                };
+                summary.screened += 1;
+                if !username.is_inappropriate() {
+                    continue;
+                }
+
+                match self.flag(email.clone(), username.clone()).await {
+                    Ok(true) => {}
+                    // Already flagged or reviewed
+                    Ok(false) => continue,
+                    Err(e) => {
+                        error!(target: "moderation", "{:?}", e.context(format!("flagging {email}")));
+                        continue;
+                    }
+                }
+                warn!(target: "moderation", "Flagged the username of {email}");
+                summary.flagged += 1;
+
+                if !rename {
+                    continue;
+                }
+                match self.rename(email.clone(), username.clone()).await {
+                    Ok(Some(_)) => summary.renamed += 1,
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!(target: "moderation", "{:?}", e.context(format!("renaming {email}")))
+                    }
+                }
+            }
+
+            limiter.tick(output.scanned_count() as u32).await;
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break Ok(summary);
+            }
+        }
+    }
+
+    /// Adds the username to the review queue, unless that username of the
+    /// user was already flagged
+    ///
+    /// Returns false if it was
+    async fn flag(&self, email: String, username: String) -> Result<bool, Error> {
+        let permit = self
+            .db
+            .budgets
+            .acquire(&self.db.bola_moderation_table, OperationClass::Write)
+            .await?;
+        match self
+            .db
+            .client
+            .put_item()
+            .table_name(self.db.bola_moderation_table.clone())
+            .item("email", AttributeValue::S(email))
+            .item("username", AttributeValue::S(username.clone()))
+            .item("flagged_at", AttributeValue::N(now().to_string()))
+            .item(
+                "status",
+                AttributeValue::S(ReviewStatus::Pending.name().into()),
+            )
+            .condition_expression("attribute_not_exists(email) OR username <> :username")
+            .expression_attribute_values(":username", AttributeValue::S(username))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|e| e.into_service_error())
+        {
+            Ok(x) => {
+                permit.consume(x.consumed_capacity());
+                Ok(true)
+            }
+            Err(e) => match &e.kind {
+                PutItemErrorKind::ConditionalCheckFailedException(_) => Ok(false),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Replaces the username of the user with a generated one, which they
+    /// are told about on their next login
+    ///
+    /// Returns None if the user no longer has the given username
+    pub async fn rename(&self, email: String, username: String) -> Result<Option<String>, Error> {
+        for _ in 0..RENAME_ATTEMPTS {
+            let new_username = format!("player{:08}", thread_rng().gen_range(0..100_000_000));
+            if self.db.is_username_taken(new_username.clone()).await? {
+                continue;
+            }
+            if !self
+                .db
+                .rename_user(email.clone(), username.clone(), new_username.clone())
+                .await?
+            {
+                return Ok(None);
+            }
+
+            self.set_status(&email, ReviewStatus::Renamed, Some(&new_username))
+                .await?;
+            let change = UsernameChange {
+                email,
+                old_username: username,
+                new_username: new_username.clone(),
+            };
+            apply_username_change(self.leaderboard, self.login_tokens, &change).await;
+            for (domain, err) in self
+                .node
+                .broadcast_message(&NetworkMessage::UsernameChange(change).traced().signed())
+                .await
+            {
+                error!(target: "moderation", "Error broadcasting username change to {}: {:?}", domain, err);
+            }
+
+            return Ok(Some(new_username));
+        }
+
+        Err(anyhow!("No free username after {RENAME_ATTEMPTS} attempts"))
+    }
+
+    /// Bans the user, logging them out and taking them off the leaderboard on
+    /// every node, and refusing their future logins
+    ///
+    /// Returns false if the user has no profile
+    pub async fn ban(&self, email: String) -> Result<bool, Error> {
+        let Some(username) = self.db.ban_user(email.clone()).await? else {
+            return Ok(false);
+        };
+        warn!(target: "moderation", "Banned {email}");
+        let ban = UserBan { email, username };
+        self.leaderboard.remove_user(&ban.username);
+        revoke_banned_token(self.node, self.login_tokens, &ban).await;
+        for (domain, err) in self
+            .node
+            .broadcast_message(&NetworkMessage::UserBan(ban).traced().signed())
+            .await
+        {
+            error!(target: "moderation", "Error broadcasting ban to {}: {:?}", domain, err);
+        }
+        Ok(true)
+    }
+
+    /// Marks the username as fine, so that it is not flagged again
+    ///
+    /// Returns false if the user is not in the review queue
+    pub async fn approve(&self, email: &str) -> Result<bool, Error> {
+        if self.get_flagged(email).await?.is_none() {
+            return Ok(false);
+        }
+        self.set_status(email, ReviewStatus::Approved, None).await?;
+        Ok(true)
+    }
+
+    async fn set_status(
+        &self,
+        email: &str,
+        status: ReviewStatus,
+        renamed_to: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut req = self
+            .db
+            .client
+            .update_item()
+            .table_name(self.db.bola_moderation_table.clone())
+            .key("email", AttributeValue::S(email.to_string()))
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":status", AttributeValue::S(status.name().into()));
+        req = match renamed_to {
+            Some(renamed_to) => req
+                .update_expression("SET #status = :status, renamed_to = :renamed_to")
+                .expression_attribute_values(
+                    ":renamed_to",
+                    AttributeValue::S(renamed_to.to_string()),
+                ),
+            None => req.update_expression("SET #status = :status"),
+        };
+        let permit = self
+            .db
+            .budgets
+            .acquire(&self.db.bola_moderation_table, OperationClass::Write)
+            .await?;
+        let output = req
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+        Ok(())
+    }
+
+    pub async fn get_flagged(&self, email: &str) -> Result<Option<FlaggedUsername>, Error> {
+        let permit = self
+            .db
+            .budgets
+            .acquire(&self.db.bola_moderation_table, OperationClass::Read)
+            .await?;
+        let output = self
+            .db
+            .client
+            .get_item()
+            .table_name(self.db.bola_moderation_table.clone())
+            .key("email", AttributeValue::S(email.to_string()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+        output.item().map(map_to_flagged_username).transpose()
+    }
+
+    pub async fn get_all_flagged(&self) -> Result<Vec<FlaggedUsername>, Error> {
+        let mut out = vec![];
+        let mut start_key = None;
+
+        loop {
+            let permit = self
+                .db
+                .budgets
+                .acquire(&self.db.bola_moderation_table, OperationClass::Read)
+                .await?;
+            let output = self
+                .db
+                .client
+                .scan()
+                .table_name(self.db.bola_moderation_table.clone())
+                .set_exclusive_start_key(start_key)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await?;
+            permit.consume(output.consumed_capacity());
+
+            for item in output.items().unwrap_or_default() {
+                out.push(map_to_flagged_username(item)?);
+            }
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break Ok(out);
+            }
+        }
+    }
+}
+
+fn map_to_flagged_username(
+    map: &HashMap<String, AttributeValue>,
+) -> Result<FlaggedUsername, Error> {
+    macro_rules! field {
+        ($field:literal, $op:ident) => {
+            map.get($field).and_then(|x| x.$op().ok()).ok_or_else(|| {
+                anyhow!(
+                    "Could not deserialize field: {} in flagged username",
+                    $field
+                )
+            })?
+        };
+    }
+
+    Ok(FlaggedUsername {
+        email: field!("email", as_s).clone(),
+        username: field!("username", as_s).clone(),
+        flagged_at: field!("flagged_at", as_n).parse()?,
+        status: ReviewStatus::from_name(field!("status", as_s))
+            .ok_or_else(|| anyhow!("Unknown status in flagged username"))?,
+        renamed_to: map.get("renamed_to").and_then(|x| x.as_s().ok()).cloned(),
+    })
+}
+
+/// Moves the leaderboard entries and login tokens of the user on this node
+/// to their new username
+async fn apply_username_change(
+    leaderboard: &Leaderboard,
+    login_tokens: &LoginTokenGranter,
+    change: &UsernameChange,
+) {
+    leaderboard.rename_user(&change.old_username, &change.new_username);
+    login_tokens
+        .reassign_token(
+            &LoginTokenData {
+                username: change.old_username.clone(),
+                email: change.email.clone(),
+            },
+            LoginTokenData {
+                username: change.new_username.clone(),
+                email: change.email.clone(),
+            },
+        )
+        .await;
+}
+
+/// Revokes the login token of the banned user that this node issued, telling
+/// siblings to refuse it as well, as they accept signed tokens
+async fn revoke_banned_token(
+    node: &Node<SiblingNetworkHandler>,
+    login_tokens: &LoginTokenGranter,
+    ban: &UserBan,
+) {
+    let Some(token) = login_tokens
+        .revoke_identifier(&LoginTokenData {
+            username: ban.username.clone(),
+            email: ban.email.clone(),
+        })
+        .await
+    else {
+        return;
+    };
+    let Ok(token) = token.to_str() else { return };
+    for (domain, err) in node
+        .broadcast_message(
+            &NetworkMessage::TokenRevocation(TokenRevocation {
+                token: token.to_string(),
+            })
+            .traced()
+            .signed(),
+        )
+        .await
+    {
+        error!(target: "moderation", "Error broadcasting token revocation to {}: {:?}", domain, err);
+    }
+}
+
+/// Revokes the login tokens of users that siblings banned
+pub fn sync_user_bans(
+    node: &'static Node<SiblingNetworkHandler>,
+    login_tokens: &'static LoginTokenGranter,
+) {
+    let mut subscription = node.get_handler().subscribe_to_user_ban();
+
+    spawn(async move {
+        loop {
+            let Some(ban) = subscription.wait_for_ban().await else {
+                break;
+            };
+            revoke_banned_token(node, login_tokens, &ban).await;
+        }
+    });
+}
+
+#[derive(Deserialize)]
+pub struct FlaggedUsernameFilter {
+    status: Option<ReviewStatus>,
+}
+
+pub async fn list_flagged_usernames(
+    State(state): State<GlobalState>,
+    pagination: Pagination,
+    Query(filter): Query<FlaggedUsernameFilter>,
+) -> Result<Json<Page<FlaggedUsername>>, StatusCode> {
+    let mut flagged = state.moderation.get_all_flagged().await.map_err(|e| {
+        error!(target: "moderation", "{:?}", e.context("listing flagged usernames"));
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    flagged.retain(|x| filter.status.map_or(true, |status| x.status == status));
+    flagged.sort_unstable_by(|a, b| a.email.cmp(&b.email));
+
+    Ok(Json(Page::from_sorted(flagged, &pagination, |x| {
+        x.email.clone()
+    })))
+}
+
+pub async fn approve_username(
+    State(state): State<GlobalState>,
+    Path(email): Path<String>,
+) -> StatusCode {
+    match state.moderation.approve(&email).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(target: "moderation", "{:?}", e.context(format!("approving {email}")));
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Renames a user in the review queue, for usernames that screening flagged
+/// without renaming
+pub async fn rename_flagged_user(
+    State(state): State<GlobalState>,
+    Path(email): Path<String>,
+) -> Result<Json<FlaggedUsername>, StatusCode> {
+    let internal_error = |e: Error| {
+        error!(target: "moderation", "{:?}", e.context(format!("renaming {email}")));
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let Some(mut flagged) = state
+        .moderation
+        .get_flagged(&email)
+        .await
+        .map_err(internal_error)?
+    else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let Some(new_username) = state
+        .moderation
+        .rename(email.clone(), flagged.username.clone())
+        .await
+        .map_err(internal_error)?
+    else {
+        // The user was already renamed or changed their username
+        return Err(StatusCode::CONFLICT);
+    };
+    flagged.status = ReviewStatus::Renamed;
+    flagged.renamed_to = Some(new_username);
+    Ok(Json(flagged))
+}
+
+/// Bans a user, for admins moderating from the game
+pub async fn ban_user(State(state): State<GlobalState>, Path(email): Path<String>) -> StatusCode {
+    match state.moderation.ban(email.clone()).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(target: "moderation", "{:?}", e.context(format!("banning {email}")));
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Resets the leaderboard of the difficulty in the background, as it updates
+/// every profile with a highscore on it
+pub async fn reset_leaderboard(
+    State(state): State<GlobalState>,
+    Path(difficulty): Path<Difficulty>,
+) -> StatusCode {
+    let leaderboard = state.leaderboard;
+    spawn(async move {
+        match leaderboard.reset(difficulty).await {
+            Ok(reset) => {
+                info!(target: "moderation", "Reset {reset} highscores on the {difficulty} leaderboard")
+            }
+            Err(e) => error!(
+                target: "moderation",
+                "{:?}",
+                e.context(format!("resetting the {difficulty} leaderboard"))
+            ),
+        }
+    });
+    StatusCode::ACCEPTED
+}
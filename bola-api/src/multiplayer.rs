@@ -1,9 +1,28 @@
-use std::num::{NonZeroU16, TryFromIntError};
+use std::{
+    collections::VecDeque,
+    num::{NonZeroU16, TryFromIntError},
+    ops::RangeInclusive,
+    time::Duration,
+};
 
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
 use mangle_api_core::{
-    rand::{thread_rng, Rng},
-    webrtc::{RandomID, WebRTCSessionManager},
+    rand::{Rng, RngCore},
+    webrtc::{
+        ICECandidate, ICESender, JoinRequest, JoinRequestReceiver, JoinSessionError,
+        MemberConnectionReceiver, RandomID, SDPAnswer, SDPAnswerStreamReceivers,
+        SDPAnswerStreamSender, SDPOffer, SDPOfferStream, SDPOfferStreamSender, SendOffersError,
+        WebRTCSessionManager,
+    },
 };
+use tokio::select;
+
+/// How often members that did not resume their session in time are removed
+pub const REAP_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a late joiner waits for a session to take it
+pub const BACKFILL_WAIT: Duration = Duration::from_secs(60);
+/// The most members a hosted session may have, including the host
+pub const MAX_SESSION_SIZE: usize = 8;
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy, derive_more::Display, Debug)]
 pub struct RoomCode(NonZeroU16);
@@ -16,10 +35,363 @@ impl TryFrom<u16> for RoomCode {
     }
 }
 
+impl From<RoomCode> for u16 {
+    fn from(value: RoomCode) -> Self {
+        value.0.get()
+    }
+}
+
 impl RandomID for RoomCode {
-    fn generate() -> Self {
-        RoomCode(thread_rng().gen_range(1000..=9999).try_into().unwrap())
+    fn generate(rng: &mut dyn RngCore) -> Self {
+        RoomCode(rng.gen_range(1000..=9999).try_into().unwrap())
     }
 }
 
 pub type Multiplayer = WebRTCSessionManager<RoomCode>;
+
+#[derive(Debug)]
+pub enum MultiplayerError {
+    AlreadyInSession,
+    NotInSession,
+    BadSize,
+    NotFound,
+    Full,
+    NotHost,
+    NotJoining,
+    /// The offers must be sent again, one for each of the given number of members
+    WrongOfferCount(usize),
+    SessionEnded,
+    NoOffer,
+    NoJoinRequest,
+    /// The ICE candidate of the member was already sent, or it never answered
+    NoAnswer,
+}
+
+impl From<JoinSessionError> for MultiplayerError {
+    fn from(value: JoinSessionError) -> Self {
+        match value {
+            JoinSessionError::NotFound => Self::NotFound,
+            JoinSessionError::Full => Self::Full,
+        }
+    }
+}
+
+/// What happened in the multiplayer session of a client
+pub enum MultiplayerEvent {
+    /// A joiner sent its offer, which must be answered with `send_answer`
+    Offer(SDPOffer),
+    /// The ICE candidate of a joiner that was sent an answer
+    JoinerIce(ICECandidate),
+    /// A member answered the offer of this joiner, and is waiting on its ICE
+    /// candidate through `send_ice`
+    Answer {
+        index: usize,
+        sdp_answer: SDPAnswer,
+        ice: ICECandidate,
+    },
+    /// A late joiner asks to join the session, which must be answered with
+    /// `answer_join_request`
+    JoinRequest {
+        rating: u32,
+    },
+    /// A slot was reserved in a session, which the offers must now be sent to
+    BackfillFound {
+        code: RoomCode,
+        member_count: usize,
+    },
+    BackfillNotFound,
+    SessionEnded,
+}
+
+struct Membership {
+    code: RoomCode,
+    host: bool,
+    conn: MemberConnectionReceiver<'static, RoomCode>,
+    /// Only set while the session is open for backfill
+    join_requests: Option<JoinRequestReceiver>,
+    /// Join requests are shown to the host one at a time
+    join_request: Option<JoinRequest>,
+    /// Offers sent to the client, which are answered in the same order
+    offers: VecDeque<SDPAnswerStreamSender>,
+    /// The ICE candidates of joiners that were answered, gathered concurrently
+    ice: FuturesUnordered<BoxFuture<'static, Option<ICECandidate>>>,
+}
+
+impl Membership {
+    fn new(code: RoomCode, host: bool, conn: MemberConnectionReceiver<'static, RoomCode>) -> Self {
+        Self {
+            code,
+            host,
+            conn,
+            join_requests: None,
+            join_request: None,
+            offers: VecDeque::new(),
+            ice: FuturesUnordered::new(),
+        }
+    }
+
+    /// Never returns if the client is not in a session
+    async fn next_event(membership: &mut Option<Self>) -> MultiplayerEvent {
+        let Some(inner) = membership else {
+            return std::future::pending().await;
+        };
+        loop {
+            select! {
+                conn = inner.conn.wait_for_conn() => {
+                    let Some(SDPOfferStream { sdp_offer, answer_stream }) = conn else {
+                        *membership = None;
+                        return MultiplayerEvent::SessionEnded;
+                    };
+                    inner.offers.push_back(answer_stream);
+                    return MultiplayerEvent::Offer(sdp_offer);
+                }
+                Some(ice) = inner.ice.next(), if !inner.ice.is_empty() => {
+                    // None if the joiner left before sending it
+                    if let Some(ice) = ice {
+                        return MultiplayerEvent::JoinerIce(ice);
+                    }
+                }
+                request = next_join_request(&mut inner.join_requests), if inner.join_request.is_none() => {
+                    let Some(request) = request else {
+                        inner.join_requests = None;
+                        continue;
+                    };
+                    let rating = request.get_rating();
+                    inner.join_request = Some(request);
+                    return MultiplayerEvent::JoinRequest { rating };
+                }
+            }
+        }
+    }
+}
+
+async fn next_join_request(join_requests: &mut Option<JoinRequestReceiver>) -> Option<JoinRequest> {
+    match join_requests {
+        Some(join_requests) => join_requests.wait_for_request().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_answer(
+    answers: &mut Option<SDPAnswerStreamReceivers>,
+) -> Option<(usize, SDPAnswer, ICECandidate, ICESender)> {
+    match answers {
+        Some(answers) => answers.wait_for_an_answer().await,
+        None => std::future::pending().await,
+    }
+}
+
+type BackfillSearch = BoxFuture<'static, Option<SDPOfferStreamSender<'static, RoomCode>>>;
+
+async fn next_backfill(
+    search: &mut Option<BackfillSearch>,
+) -> Option<SDPOfferStreamSender<'static, RoomCode>> {
+    match search {
+        Some(search) => search.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The multiplayer session of a single client, from hosting or joining it
+/// to exchanging the offers, answers and ICE candidates of its members
+pub struct MultiplayerState {
+    multiplayer: &'static Multiplayer,
+    member: Option<Membership>,
+    /// The slot reserved for this client while it has yet to send its offers
+    slot: Option<SDPOfferStreamSender<'static, RoomCode>>,
+    /// The answers of the members to the offers of this client
+    answers: Option<SDPAnswerStreamReceivers>,
+    /// Taken once the ICE candidate for that member is sent
+    ice_senders: Vec<Option<ICESender>>,
+    backfill_search: Option<BackfillSearch>,
+}
+
+impl MultiplayerState {
+    pub fn new(multiplayer: &'static Multiplayer) -> Self {
+        Self {
+            multiplayer,
+            member: None,
+            slot: None,
+            answers: None,
+            ice_senders: Vec::new(),
+            backfill_search: None,
+        }
+    }
+
+    fn check_not_in_session(&self) -> Result<(), MultiplayerError> {
+        if self.member.is_some() || self.slot.is_some() || self.backfill_search.is_some() {
+            Err(MultiplayerError::AlreadyInSession)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn host_membership(&mut self) -> Result<&mut Membership, MultiplayerError> {
+        match &mut self.member {
+            Some(member) if member.host => Ok(member),
+            Some(_) => Err(MultiplayerError::NotHost),
+            None => Err(MultiplayerError::NotInSession),
+        }
+    }
+
+    /// Hosts a session with a random code, which is returned
+    pub fn host(&mut self, max_size: usize) -> Result<RoomCode, MultiplayerError> {
+        self.check_not_in_session()?;
+        if !(2..=MAX_SESSION_SIZE).contains(&max_size) {
+            return Err(MultiplayerError::BadSize);
+        }
+        let (conn, code) = self.multiplayer.host_session_random_id(max_size);
+        self.member = Some(Membership::new(code, true, conn));
+        Ok(code)
+    }
+
+    /// Reserves a slot in the session, returning how many offers must be sent
+    pub fn start_join(&mut self, code: RoomCode) -> Result<usize, MultiplayerError> {
+        self.check_not_in_session()?;
+        let slot = self.multiplayer.join_session(&code)?;
+        let member_count = slot.get_member_count();
+        self.slot = Some(slot);
+        Ok(member_count)
+    }
+
+    /// Sends an offer to each member of the session that a slot was reserved in
+    pub async fn send_offers(&mut self, offers: Vec<String>) -> Result<(), MultiplayerError> {
+        let slot = self.slot.take().ok_or(MultiplayerError::NotJoining)?;
+        let code = *slot.get_id();
+        let offer_count = offers.len();
+        match slot
+            .send_sdp_offers(offers.into_iter().map(SDPOffer).collect())
+            .await
+        {
+            Ok((conn, answers)) => {
+                self.ice_senders = (0..offer_count).map(|_| None).collect();
+                self.answers = Some(answers);
+                self.member = Some(Membership::new(code, false, conn));
+                Ok(())
+            }
+            Err(SendOffersError::WrongOfferCount(slot)) => {
+                let member_count = slot.get_member_count();
+                self.slot = Some(slot);
+                Err(MultiplayerError::WrongOfferCount(member_count))
+            }
+            Err(SendOffersError::SessionEnded) => Err(MultiplayerError::SessionEnded),
+        }
+    }
+
+    /// Sends the ICE candidate of this client to the member with the given index
+    pub fn send_ice(&mut self, index: usize, ice: String) -> Result<(), MultiplayerError> {
+        let ice_sender = self
+            .ice_senders
+            .get_mut(index)
+            .and_then(Option::take)
+            .ok_or(MultiplayerError::NoAnswer)?;
+        ice_sender.send(ICECandidate(ice));
+        Ok(())
+    }
+
+    /// Answers the oldest offer of a joiner that was not answered yet
+    pub fn send_answer(&mut self, sdp_answer: String, ice: String) -> Result<(), MultiplayerError> {
+        let member = self.member.as_mut().ok_or(MultiplayerError::NotInSession)?;
+        let answer_stream = member.offers.pop_front().ok_or(MultiplayerError::NoOffer)?;
+        // The joiner may have left already, in which case there is nothing to wait on
+        if let Some(ice_recv) = answer_stream.send_answer(SDPAnswer(sdp_answer), ICECandidate(ice))
+        {
+            member.ice.push(ice_recv.get_ice().boxed());
+        }
+        Ok(())
+    }
+
+    /// Lets late joiners within the rating band ask to join the hosted session
+    pub fn open_backfill(
+        &mut self,
+        rating_band: RangeInclusive<u32>,
+    ) -> Result<(), MultiplayerError> {
+        let multiplayer = self.multiplayer;
+        let member = self.host_membership()?;
+        member.join_requests = Some(
+            multiplayer
+                .open_for_backfill(&member.code, rating_band)
+                .ok_or(MultiplayerError::SessionEnded)?,
+        );
+        Ok(())
+    }
+
+    pub fn close_backfill(&mut self) -> Result<(), MultiplayerError> {
+        let multiplayer = self.multiplayer;
+        let member = self.host_membership()?;
+        multiplayer.close_backfill(&member.code);
+        member.join_requests = None;
+        member.join_request = None;
+        Ok(())
+    }
+
+    pub fn answer_join_request(&mut self, accept: bool) -> Result<(), MultiplayerError> {
+        let request = self
+            .host_membership()?
+            .join_request
+            .take()
+            .ok_or(MultiplayerError::NoJoinRequest)?;
+        if accept {
+            request.accept();
+        } else {
+            request.decline();
+        }
+        Ok(())
+    }
+
+    /// Looks for a session open for backfill that takes a joiner of the given
+    /// rating, which is reported through `next_event`
+    pub fn find_backfill(&mut self, rating: u32) -> Result<(), MultiplayerError> {
+        self.check_not_in_session()?;
+        self.backfill_search = Some(
+            self.multiplayer
+                .request_backfill(rating, BACKFILL_WAIT)
+                .boxed(),
+        );
+        Ok(())
+    }
+
+    /// Leaves the session, or stops joining one
+    pub fn leave(&mut self) {
+        *self = Self::new(self.multiplayer);
+    }
+
+    /// Never returns if the client is not in a session and is not joining one
+    ///
+    /// Cancel safe, so it can be raced against the messages of the client
+    pub async fn next_event(&mut self) -> MultiplayerEvent {
+        loop {
+            select! {
+                event = Membership::next_event(&mut self.member) => {
+                    if let MultiplayerEvent::SessionEnded = event {
+                        self.leave();
+                    }
+                    return event;
+                }
+                answer = next_answer(&mut self.answers) => {
+                    let Some((index, sdp_answer, ice, ice_sender)) = answer else {
+                        // Every member answered or left
+                        self.answers = None;
+                        continue;
+                    };
+                    if let Some(slot) = self.ice_senders.get_mut(index) {
+                        *slot = Some(ice_sender);
+                    }
+                    return MultiplayerEvent::Answer { index, sdp_answer, ice };
+                }
+                slot = next_backfill(&mut self.backfill_search) => {
+                    self.backfill_search = None;
+                    let Some(slot) = slot else {
+                        return MultiplayerEvent::BackfillNotFound;
+                    };
+                    let event = MultiplayerEvent::BackfillFound {
+                        code: *slot.get_id(),
+                        member_count: slot.get_member_count(),
+                    };
+                    self.slot = Some(slot);
+                    return event;
+                }
+            }
+        }
+    }
+}
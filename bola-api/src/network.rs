@@ -1,20 +1,69 @@
+use std::{sync::Arc, time::Duration};
+
 use axum::async_trait;
 use derive_more::From;
-use log::error;
-use mangle_api_core::distributed::ServerName;
-use messagist::{ExclusiveMessageHandler, MessageStream};
+use log::{error, info, warn};
+use mangle_api_core::{
+    distributed::{
+        clock::{ClockProbe, ClockSkew},
+        lock::{LockRequest, LockTable},
+        signing::{self, SignatureVerifier, SignedPayload},
+        Node, ServerName,
+    },
+    opentelemetry::{
+        trace::{FutureExt, SpanKind, TraceContextExt, Tracer},
+        KeyValue,
+    },
+    telemetry::{self, TraceContext},
+};
+use messagist::{wire::WireType, ExclusiveMessageHandler, MessageStream};
 use serde::{Deserialize, Serialize};
-use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::{
+    spawn,
+    sync::broadcast::{channel, error::RecvError, Receiver, Sender},
+    time::sleep,
+};
+
+use crate::{
+    announcements::{Announcement, Audience},
+    difficulty::Difficulty,
+    room_chat::ChatEvent,
+};
 
 const MESSAGE_ROUTER_BUFFER_SIZE: usize = 8;
 
+pub type SiblingClockSkew = ClockSkew<SiblingNetworkHandler, NetworkMessage>;
+const DEAD_LETTER_RETRY_DELAY: Duration = Duration::from_secs(30);
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct HighscoreUpdate {
-    pub difficulty: String,
+    pub difficulty: Difficulty,
+    pub username: String,
+    pub score: u16,
+    pub avatar_url: Option<String>,
+    pub country: Option<String>,
+}
+
+/// The highscore update sent by nodes from before avatars and countries were added
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LegacyHighscoreUpdate {
+    pub difficulty: Difficulty,
     pub username: String,
     pub score: u16,
 }
 
+impl From<LegacyHighscoreUpdate> for HighscoreUpdate {
+    fn from(value: LegacyHighscoreUpdate) -> Self {
+        Self {
+            difficulty: value.difficulty,
+            username: value.username,
+            score: value.score,
+            avatar_url: None,
+            country: None,
+        }
+    }
+}
+
 pub struct HighScoreUpdateSubscription(Receiver<HighscoreUpdate>);
 
 impl HighScoreUpdateSubscription {
@@ -23,17 +72,182 @@ impl HighScoreUpdateSubscription {
     }
 }
 
+#[derive(Clone, Deserialize, Serialize)]
+pub enum AnnouncementUpdate {
+    Created(Announcement),
+    Removed(String),
+}
+
+pub struct AnnouncementUpdateSubscription(Receiver<AnnouncementUpdate>);
+
+impl AnnouncementUpdateSubscription {
+    pub async fn wait_for_update(&mut self) -> Option<AnnouncementUpdate> {
+        self.0.recv().await.ok()
+    }
+}
+
+/// Sent when a user moves their profile to a different email
+#[derive(Clone, Deserialize, Serialize)]
+pub struct EmailChange {
+    pub old_email: String,
+    pub new_email: String,
+    pub username: String,
+}
+
+pub struct EmailChangeSubscription(Receiver<EmailChange>);
+
+impl EmailChangeSubscription {
+    pub async fn wait_for_change(&mut self) -> Option<EmailChange> {
+        self.0.recv().await.ok()
+    }
+}
+
+/// Sent when a login token is revoked, as siblings accept signed tokens that
+/// they did not issue
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TokenRevocation {
+    pub token: String,
+}
+
+pub struct TokenRevocationSubscription(Receiver<TokenRevocation>);
+
+impl TokenRevocationSubscription {
+    pub async fn wait_for_revocation(&mut self) -> Option<TokenRevocation> {
+        loop {
+            match self.0.recv().await {
+                Ok(x) => break Some(x),
+                // Missing a revocation would leave the token usable, so this must keep going
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break None,
+            }
+        }
+    }
+}
+
+/// Sent when moderation renames a user
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UsernameChange {
+    pub email: String,
+    pub old_username: String,
+    pub new_username: String,
+}
+
+pub struct UsernameChangeSubscription(Receiver<UsernameChange>);
+
+impl UsernameChangeSubscription {
+    pub async fn wait_for_change(&mut self) -> Option<UsernameChange> {
+        loop {
+            match self.0.recv().await {
+                Ok(x) => break Some(x),
+                // Missing a change would leave the old username on the leaderboard
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break None,
+            }
+        }
+    }
+}
+
+/// Sent when an admin bans a user
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UserBan {
+    pub email: String,
+    pub username: String,
+}
+
+pub struct UserBanSubscription(Receiver<UserBan>);
+
+impl UserBanSubscription {
+    pub async fn wait_for_ban(&mut self) -> Option<UserBan> {
+        loop {
+            match self.0.recv().await {
+                Ok(x) => break Some(x),
+                // Missing a ban would leave the user logged in
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break None,
+            }
+        }
+    }
+}
+
+/// Sent when an admin resets the leaderboard of a difficulty
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LeaderboardReset {
+    pub difficulty: Difficulty,
+}
+
+pub struct LeaderboardResetSubscription(Receiver<LeaderboardReset>);
+
+impl LeaderboardResetSubscription {
+    pub async fn wait_for_reset(&mut self) -> Option<LeaderboardReset> {
+        loop {
+            match self.0.recv().await {
+                Ok(x) => break Some(x),
+                // Missing a reset would leave the old scores on the leaderboard
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break None,
+            }
+        }
+    }
+}
+
+/// An event of the chat of a multiplayer room, relayed to the siblings that need it
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RoomChatEvent {
+    pub room: u16,
+    pub event: ChatEvent,
+}
+
+/// Gives each event with the domain of the sibling that sent it
+pub struct RoomChatSubscription(Receiver<(Arc<str>, RoomChatEvent)>);
+
+impl RoomChatSubscription {
+    pub async fn wait_for_event(&mut self) -> Option<(Arc<str>, RoomChatEvent)> {
+        loop {
+            match self.0.recv().await {
+                Ok(x) => break Some(x),
+                // Missing an event would leave the members of rooms out of sync
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break None,
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct SiblingNetworkHandler {
     highscore_updater: Sender<HighscoreUpdate>,
+    announcement_updater: Sender<AnnouncementUpdate>,
+    email_change_updater: Sender<EmailChange>,
+    token_revocation_updater: Sender<TokenRevocation>,
+    room_chat_updater: Sender<(Arc<str>, RoomChatEvent)>,
+    username_change_updater: Sender<UsernameChange>,
+    user_ban_updater: Sender<UserBan>,
+    leaderboard_reset_updater: Sender<LeaderboardReset>,
+    lock_table: &'static LockTable,
+    verifier: Option<&'static SignatureVerifier>,
 }
 
 impl SiblingNetworkHandler {
     pub fn new() -> Self {
         Self {
             highscore_updater: channel(MESSAGE_ROUTER_BUFFER_SIZE).0,
+            announcement_updater: channel(MESSAGE_ROUTER_BUFFER_SIZE).0,
+            email_change_updater: channel(MESSAGE_ROUTER_BUFFER_SIZE).0,
+            token_revocation_updater: channel(MESSAGE_ROUTER_BUFFER_SIZE).0,
+            room_chat_updater: channel(MESSAGE_ROUTER_BUFFER_SIZE).0,
+            username_change_updater: channel(MESSAGE_ROUTER_BUFFER_SIZE).0,
+            user_ban_updater: channel(MESSAGE_ROUTER_BUFFER_SIZE).0,
+            leaderboard_reset_updater: channel(MESSAGE_ROUTER_BUFFER_SIZE).0,
+            lock_table: manglext::immut_leak(LockTable::default()),
+            verifier: None,
         }
     }
+
+    /// Verifies signed messages, and drops unsigned messages if the verifier requires signatures
+    pub fn set_verifier(mut self, verifier: SignatureVerifier) -> Self {
+        self.verifier = Some(manglext::immut_leak(verifier));
+        self
+    }
 }
 
 #[async_trait]
@@ -42,12 +256,94 @@ impl ExclusiveMessageHandler for SiblingNetworkHandler {
 
     async fn handle<S: MessageStream>(&mut self, mut stream: S, server_name: Self::SessionState) {
         let server_name = server_name.0;
-        match stream.recv_message().await {
-            Ok(NetworkMessage::HighscoreUpdate(msg)) => {
-                let _ = self.highscore_updater.send(msg);
+        let msg = match (stream.recv_message().await, self.verifier) {
+            (Ok(NetworkMessage::Signed(signed)), Some(verifier)) => {
+                match verifier.verify(&signed) {
+                    Ok(msg) => Ok(msg),
+                    Err(e) => {
+                        warn!(target: "suspicious_security", "Dropped message from {server_name}: {e}");
+                        return;
+                    }
+                }
+            }
+            (Ok(NetworkMessage::Signed(_)), None) => {
+                error!("Received signed message from {server_name} without any keys to verify it");
+                return;
+            }
+            (Ok(_), Some(verifier)) if verifier.requires_signatures() => {
+                warn!(target: "suspicious_security", "Dropped unsigned message from {server_name}");
+                return;
+            }
+            (msg, _) => msg,
+        };
+        let (msg, parent) = match msg {
+            Ok(NetworkMessage::Traced { context, message }) => (Ok(*message), context.to_context()),
+            msg => (msg, Default::default()),
+        };
+        let tracer = telemetry::tracer();
+        let context = match &msg {
+            Ok(msg) => {
+                let span = tracer
+                    .span_builder(format!("sibling {}", msg.name()))
+                    .with_kind(SpanKind::Server)
+                    .with_attributes([KeyValue::new("sibling.domain", server_name.to_string())])
+                    .start_with_context(&tracer, &parent);
+                parent.with_span(span)
+            }
+            Err(_) => parent,
+        };
+
+        // The span is current while the message is handled, and ends afterwards
+        async {
+            match msg {
+                Ok(NetworkMessage::LegacyHighscoreUpdate(msg)) => {
+                    let _ = self.highscore_updater.send(msg.into());
+                }
+                Ok(NetworkMessage::HighscoreUpdate(msg)) => {
+                    let _ = self.highscore_updater.send(msg);
+                }
+                Ok(NetworkMessage::AnnouncementUpdate(msg)) => {
+                    let _ = self.announcement_updater.send(msg);
+                }
+                Ok(NetworkMessage::EmailChange(msg)) => {
+                    let _ = self.email_change_updater.send(msg);
+                }
+                Ok(NetworkMessage::Lock(request)) => {
+                    if let Err(e) = stream.send_message(self.lock_table.handle(request)).await {
+                        error!("Error replying to lock request from {server_name}: {e}");
+                    }
+                }
+                Ok(NetworkMessage::TokenRevocation(msg)) => {
+                    let _ = self.token_revocation_updater.send(msg);
+                }
+                Ok(NetworkMessage::RoomChat(msg)) => {
+                    let _ = self.room_chat_updater.send((server_name.clone(), msg));
+                }
+                Ok(NetworkMessage::UsernameChange(msg)) => {
+                    let _ = self.username_change_updater.send(msg);
+                }
+                Ok(NetworkMessage::ClockProbe(probe)) => {
+                    if let Err(e) = stream.send_message(probe.answer()).await {
+                        error!("Error replying to clock probe from {server_name}: {e}");
+                    }
+                }
+                Ok(NetworkMessage::UserBan(msg)) => {
+                    let _ = self.user_ban_updater.send(msg);
+                }
+                Ok(NetworkMessage::LeaderboardReset(msg)) => {
+                    let _ = self.leaderboard_reset_updater.send(msg);
+                }
+                Ok(NetworkMessage::Traced { .. }) => {
+                    error!("Received doubly traced node message from {server_name}")
+                }
+                Ok(NetworkMessage::Signed(_)) => {
+                    error!("Received doubly signed node message from {server_name}")
+                }
+                Err(e) => error!("Error receiving node message: {e} from {server_name}"),
             }
-            Err(e) => error!("Error receiving node message: {e} from {server_name}"),
         }
+        .with_context(context)
+        .await
     }
 }
 
@@ -55,9 +351,196 @@ impl SiblingNetworkHandler {
     pub fn subscribe_to_highscore_update(&self) -> HighScoreUpdateSubscription {
         HighScoreUpdateSubscription(self.highscore_updater.subscribe())
     }
+
+    pub fn subscribe_to_announcement_update(&self) -> AnnouncementUpdateSubscription {
+        AnnouncementUpdateSubscription(self.announcement_updater.subscribe())
+    }
+
+    pub fn subscribe_to_email_change(&self) -> EmailChangeSubscription {
+        EmailChangeSubscription(self.email_change_updater.subscribe())
+    }
+
+    pub fn subscribe_to_token_revocation(&self) -> TokenRevocationSubscription {
+        TokenRevocationSubscription(self.token_revocation_updater.subscribe())
+    }
+
+    pub fn subscribe_to_room_chat(&self) -> RoomChatSubscription {
+        RoomChatSubscription(self.room_chat_updater.subscribe())
+    }
+
+    pub fn subscribe_to_username_change(&self) -> UsernameChangeSubscription {
+        UsernameChangeSubscription(self.username_change_updater.subscribe())
+    }
+
+    pub fn subscribe_to_user_ban(&self) -> UserBanSubscription {
+        UserBanSubscription(self.user_ban_updater.subscribe())
+    }
+
+    pub fn subscribe_to_leaderboard_reset(&self) -> LeaderboardResetSubscription {
+        LeaderboardResetSubscription(self.leaderboard_reset_updater.subscribe())
+    }
+
+    /// The votes of this node on distributed locks
+    pub fn get_lock_table(&self) -> &'static LockTable {
+        self.lock_table
+    }
 }
 
+/// Messages are encoded with bincode, which identifies variants by their position,
+/// so new variants must only ever be appended
 #[derive(Clone, Deserialize, Serialize, From)]
 pub enum NetworkMessage {
+    LegacyHighscoreUpdate(LegacyHighscoreUpdate),
+    AnnouncementUpdate(AnnouncementUpdate),
+    EmailChange(EmailChange),
     HighscoreUpdate(HighscoreUpdate),
+    Lock(LockRequest),
+    /// Carries the trace context of the span that sent the message
+    Traced {
+        context: TraceContext,
+        message: Box<NetworkMessage>,
+    },
+    TokenRevocation(TokenRevocation),
+    /// Carries a message signed by the sending node
+    Signed(SignedPayload),
+    RoomChat(RoomChatEvent),
+    UsernameChange(UsernameChange),
+    ClockProbe(ClockProbe),
+    UserBan(UserBan),
+    LeaderboardReset(LeaderboardReset),
+}
+
+impl NetworkMessage {
+    /// Attaches the current trace context, unless tracing is disabled
+    ///
+    /// Nodes from before tracing was added cannot read traced messages, so
+    /// tracing should only be enabled once every node has been updated
+    pub fn traced(self) -> Self {
+        if !telemetry::is_enabled() {
+            return self;
+        }
+        NetworkMessage::Traced {
+            context: TraceContext::current(),
+            message: Box::new(self),
+        }
+    }
+
+    /// Signs the message with the key of this node, if message signing is enabled
+    ///
+    /// Messages should be traced before being signed
+    pub fn signed(self) -> Self {
+        match signing::sign(&self) {
+            Some(Ok(signed)) => NetworkMessage::Signed(signed),
+            Some(Err(e)) => {
+                error!("Could not sign node message: {e}");
+                self
+            }
+            None => self,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            NetworkMessage::LegacyHighscoreUpdate(_) => "LegacyHighscoreUpdate",
+            NetworkMessage::AnnouncementUpdate(_) => "AnnouncementUpdate",
+            NetworkMessage::EmailChange(_) => "EmailChange",
+            NetworkMessage::HighscoreUpdate(_) => "HighscoreUpdate",
+            NetworkMessage::Lock(_) => "Lock",
+            NetworkMessage::Traced { .. } => "Traced",
+            NetworkMessage::TokenRevocation(_) => "TokenRevocation",
+            NetworkMessage::Signed(_) => "Signed",
+            NetworkMessage::RoomChat(_) => "RoomChat",
+            NetworkMessage::UsernameChange(_) => "UsernameChange",
+            NetworkMessage::ClockProbe(_) => "ClockProbe",
+            NetworkMessage::UserBan(_) => "UserBan",
+            NetworkMessage::LeaderboardReset(_) => "LeaderboardReset",
+        }
+    }
+}
+
+impl WireType for NetworkMessage {
+    fn wire_samples() -> Vec<Self> {
+        let email_change = EmailChange {
+            old_email: "old@example.com".into(),
+            new_email: "new@example.com".into(),
+            username: "user".into(),
+        };
+        let mut messages = vec![
+            NetworkMessage::LegacyHighscoreUpdate(LegacyHighscoreUpdate {
+                difficulty: Difficulty::Easy,
+                username: "user".into(),
+                score: u16::MAX,
+            }),
+            NetworkMessage::AnnouncementUpdate(AnnouncementUpdate::Created(Announcement {
+                id: "announcement".into(),
+                start_time: 0,
+                end_time: u64::MAX,
+                audience: Audience::LoggedIn,
+                messages: [("en".to_string(), "Hello".to_string())].into(),
+            })),
+            NetworkMessage::AnnouncementUpdate(AnnouncementUpdate::Removed("announcement".into())),
+            NetworkMessage::EmailChange(email_change.clone()),
+            NetworkMessage::HighscoreUpdate(HighscoreUpdate {
+                difficulty: Difficulty::Expert,
+                username: "user".into(),
+                score: 1,
+                avatar_url: Some("https://example.com/avatar.png".into()),
+                country: None,
+            }),
+            NetworkMessage::Traced {
+                context: TraceContext::default(),
+                message: Box::new(NetworkMessage::EmailChange(email_change)),
+            },
+            NetworkMessage::TokenRevocation(TokenRevocation {
+                token: "token".into(),
+            }),
+            NetworkMessage::Signed(SignedPayload {
+                sender: "node".into(),
+                key_id: "key".into(),
+                timestamp: 0,
+                nonce: u64::MAX,
+                payload: vec![0, 1, 2],
+                signature: vec![3; 64],
+            }),
+            NetworkMessage::RoomChat(RoomChatEvent {
+                room: 1234,
+                event: ChatEvent::Message {
+                    username: "user".into(),
+                    text: "Hello".into(),
+                },
+            }),
+            NetworkMessage::UsernameChange(UsernameChange {
+                email: "user@example.com".into(),
+                old_username: "user".into(),
+                new_username: "player12345678".into(),
+            }),
+            NetworkMessage::ClockProbe(ClockProbe),
+            NetworkMessage::UserBan(UserBan {
+                email: "user@example.com".into(),
+                username: "user".into(),
+            }),
+            NetworkMessage::LeaderboardReset(LeaderboardReset {
+                difficulty: Difficulty::Normal,
+            }),
+        ];
+        messages.extend(
+            LockRequest::wire_samples()
+                .into_iter()
+                .map(NetworkMessage::Lock),
+        );
+        messages
+    }
+}
+
+/// Periodically resends the messages that siblings failed to receive
+pub fn redeliver_dead_letters(node: &'static Node<SiblingNetworkHandler>) {
+    spawn(async move {
+        loop {
+            sleep(DEAD_LETTER_RETRY_DELAY).await;
+            let delivered = node.redeliver_dead_letters().await;
+            if delivered > 0 {
+                info!("Redelivered {delivered} messages to siblings");
+            }
+        }
+    });
 }
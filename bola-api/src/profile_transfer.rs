@@ -0,0 +1,242 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Error};
+use axum::async_trait;
+use mangle_api_core::{
+    clap::{arg, value_parser, Command},
+    serde_json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::db::UserProfile;
+
+/// Adds the `export-profiles` and `import-profiles` subcommands
+pub fn add_subcommands(app: Command) -> Command {
+    app.subcommand(
+        Command::new("export-profiles")
+            .about("Writes every user profile to a newline delimited JSON file")
+            .arg(arg!(--out <PATH> "The file to write the profiles to").required(true))
+            .arg(arg!(--since <DATE> "Only exports profiles created since this date (YYYY-MM-DD)"))
+            .arg(arg!(--config <PATH> "The config file with the tables to export from"))
+            .arg(
+                arg!(--rate <PROFILES> "The maximum number of profiles read per second")
+                    .value_parser(value_parser!(u32).range(1..)),
+            ),
+    )
+    .subcommand(
+        Command::new("import-profiles")
+            .about("Adds the profiles in an export, skipping existing emails and usernames")
+            .arg(arg!(--input <PATH> "The file to read the profiles from").required(true))
+            .arg(arg!(--config <PATH> "The config file with the tables to import into"))
+            .arg(
+                arg!(--rate <PROFILES> "The maximum number of profiles written per second")
+                    .value_parser(value_parser!(u32).range(1..)),
+            ),
+    )
+}
+
+pub const DEFAULT_RATE: u32 = 100;
+
+/// One line of an export
+#[derive(Serialize, Deserialize)]
+pub struct ProfileRecord {
+    pub email: String,
+    #[serde(default = "Default::default")]
+    pub created_at: Option<u64>,
+    #[serde(flatten)]
+    pub profile: UserProfile,
+}
+
+/// Where profiles are exported from and imported into, which charges its
+/// reads and writes to the budgets of its tables
+#[async_trait]
+pub trait ProfileStore: Send + Sync {
+    /// Reads up to `limit` items after the profile with the email `after`,
+    /// only keeping the profiles created since `since`
+    async fn scan_profiles(
+        &self,
+        since: Option<u64>,
+        limit: u32,
+        after: Option<String>,
+    ) -> Result<ProfilePage, Error>;
+
+    async fn is_username_taken(&self, username: String) -> Result<bool, Error>;
+
+    /// Adds the profile unless a profile already exists under the email
+    ///
+    /// Returns false if a profile already existed
+    async fn put_user_profile(
+        &self,
+        email: String,
+        profile: UserProfile,
+        created_at: Option<u64>,
+    ) -> Result<bool, Error>;
+}
+
+/// The items read by one `ProfileStore::scan_profiles`
+pub struct ProfilePage {
+    /// The profiles read, or why they are invalid
+    pub profiles: Vec<Result<ProfileRecord, Error>>,
+    /// Items left behind by email changes
+    pub aliases: usize,
+    /// How many items were read, including those created before `since`
+    pub scanned: u32,
+    /// The email to continue the scan after, or None once every item is read
+    pub last_email: Option<String>,
+}
+
+#[derive(Default, Debug)]
+pub struct ExportSummary {
+    pub exported: usize,
+    /// Items left behind by email changes
+    pub aliases: usize,
+    pub invalid: usize,
+}
+
+#[derive(Default, Debug)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub already_existed: usize,
+    /// Profiles whose username another user already has, as sign up refuses them
+    pub username_taken: usize,
+    pub invalid: usize,
+}
+
+/// Waits out the rest of a second for every `rate` items processed
+pub(crate) struct RateLimiter {
+    rate: u32,
+    count: u32,
+    window_start: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            count: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    pub(crate) async fn tick(&mut self, count: u32) {
+        self.count += count;
+        if self.count < self.rate {
+            return;
+        }
+        let elapsed = self.window_start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            sleep(Duration::from_secs(1) - elapsed).await;
+        }
+        self.count = 0;
+        self.window_start = Instant::now();
+    }
+}
+
+pub async fn export_profiles(
+    store: &dyn ProfileStore,
+    out: &Path,
+    since: Option<u64>,
+    rate: u32,
+) -> Result<ExportSummary, Error> {
+    let mut writer =
+        BufWriter::new(File::create(out).context(format!("Creating {}", out.display()))?);
+    let mut summary = ExportSummary::default();
+    let mut limiter = RateLimiter::new(rate);
+    let mut after = None;
+
+    loop {
+        let page = store.scan_profiles(since, rate, after).await?;
+        summary.aliases += page.aliases;
+
+        for profile in page.profiles {
+            match profile {
+                Ok(record) => {
+                    serde_json::to_writer(&mut writer, &record)?;
+                    writer.write_all(b"\n")?;
+                    summary.exported += 1;
+                }
+                Err(e) => {
+                    eprintln!("{:?}", e.context("Skipping invalid profile"));
+                    summary.invalid += 1;
+                }
+            }
+        }
+
+        limiter.tick(page.scanned).await;
+
+        after = page.last_email;
+        if after.is_none() {
+            break;
+        }
+    }
+
+    writer.flush()?;
+    Ok(summary)
+}
+
+pub async fn import_profiles(
+    store: &dyn ProfileStore,
+    input: &Path,
+    rate: u32,
+) -> Result<ImportSummary, Error> {
+    let reader = BufReader::new(File::open(input).context(format!("Opening {}", input.display()))?);
+    let mut summary = ImportSummary::default();
+    let mut limiter = RateLimiter::new(rate);
+    // The username index is eventually consistent, so it may not have the
+    // usernames imported just before
+    let mut imported_usernames = HashSet::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: ProfileRecord = match serde_json::from_str(&line) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Skipping invalid profile on line {}: {e}", i + 1);
+                summary.invalid += 1;
+                continue;
+            }
+        };
+
+        let username = record.profile.username.clone();
+        if imported_usernames.contains(&username)
+            || store
+                .is_username_taken(username.clone())
+                .await
+                .context(format!("Checking the username of {}", record.email))?
+        {
+            eprintln!(
+                "Skipping {} as the username {username} is taken",
+                record.email
+            );
+            summary.username_taken += 1;
+            limiter.tick(1).await;
+            continue;
+        }
+
+        if store
+            .put_user_profile(record.email.clone(), record.profile, record.created_at)
+            .await
+            .context(format!("Importing {}", record.email))?
+        {
+            imported_usernames.insert(username);
+            summary.imported += 1;
+        } else {
+            summary.already_existed += 1;
+        }
+
+        limiter.tick(1).await;
+    }
+
+    Ok(summary)
+}
@@ -0,0 +1,557 @@
+use std::fs::read_to_string;
+
+use anyhow::{anyhow, Context, Error};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use constant_time_eq::constant_time_eq;
+use log::{error, info, warn};
+use mangle_api_core::serde_json;
+use reqwest::Url;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{
+    db::{GrantResult, DB},
+    state::GlobalState,
+};
+
+const APPLE_VERIFY_URL: &str = "https://buy.itunes.apple.com/verifyReceipt";
+const APPLE_SANDBOX_VERIFY_URL: &str = "https://sandbox.itunes.apple.com/verifyReceipt";
+/// Returned by the production endpoint when given a sandbox receipt
+const APPLE_SANDBOX_RECEIPT_STATUS: u32 = 21007;
+/// The SHA-256 fingerprint of the Apple Root CA - G3, which App Store JWS
+/// certificate chains end with
+const APPLE_ROOT_CA_G3_FINGERPRINT: [u8; 32] = [
+    0x63, 0x34, 0x3a, 0xbf, 0xb8, 0x9a, 0x6a, 0x03, 0xeb, 0xb5, 0x7e, 0x9b, 0x3f, 0x5f, 0xa7, 0xbe,
+    0x7c, 0x4f, 0x5c, 0x75, 0x6f, 0x30, 0x17, 0xb3, 0xa8, 0xc4, 0x88, 0xc3, 0x65, 0x3e, 0x91, 0x79,
+];
+/// Marks certificates that Apple uses to sign App Store receipts and notifications
+const APPLE_RECEIPT_SIGNING_OID: &str = "1.2.840.113635.100.6.11.1";
+/// The header that store webhooks must pass the webhook token in
+///
+/// Kept out of the query string so that it is not written to access logs.
+/// The stores cannot set headers, so the gateway in front of the webhooks must add it
+pub const WEBHOOK_TOKEN_HEADER: &str = "Webhook-Token";
+const GOOGLE_PLAY_API_BASE: &str =
+    "https://androidpublisher.googleapis.com/androidpublisher/v3/applications";
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Store {
+    #[serde(rename = "app_store")]
+    AppStore,
+    #[serde(rename = "google_play")]
+    GooglePlay,
+}
+
+impl Store {
+    pub fn name(self) -> &'static str {
+        match self {
+            Store::AppStore => "app_store",
+            Store::GooglePlay => "google_play",
+        }
+    }
+}
+
+/// A purchase that the store has confirmed to be genuine
+pub struct VerifiedPurchase {
+    pub store: Store,
+    pub product_id: String,
+    /// An identifier that stays the same across renewals, used to make grants idempotent
+    pub transaction_id: String,
+}
+
+#[derive(Debug)]
+pub enum RedeemError {
+    InvalidReceipt,
+    InternalError(Error),
+}
+
+impl From<Error> for RedeemError {
+    fn from(value: Error) -> Self {
+        Self::InternalError(value)
+    }
+}
+
+pub struct Purchases {
+    client: reqwest::Client,
+    db: &'static DB,
+    apple_bundle_id: String,
+    apple_shared_secret: String,
+    google_play_package_name: String,
+    google_play_token_path: String,
+    webhook_token: String,
+}
+
+impl Purchases {
+    pub fn new(
+        db: &'static DB,
+        apple_bundle_id: String,
+        apple_shared_secret: String,
+        google_play_package_name: String,
+        google_play_token_path: String,
+        webhook_token: String,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            db,
+            apple_bundle_id,
+            apple_shared_secret,
+            google_play_package_name,
+            google_play_token_path,
+            webhook_token,
+        }
+    }
+
+    /// Validates the receipt with the store, then grants the product to the given user
+    ///
+    /// Redeeming the same purchase more than once is harmless
+    pub async fn redeem(
+        &self,
+        email: String,
+        store: Store,
+        product_id: String,
+        receipt: String,
+    ) -> Result<GrantResult, RedeemError> {
+        let purchase = match store {
+            Store::AppStore => self.verify_app_store(&product_id, receipt).await?,
+            Store::GooglePlay => self.verify_google_play(&product_id, receipt).await?,
+        };
+
+        let Some(purchase) = purchase else {
+            return Err(RedeemError::InvalidReceipt)
+        };
+
+        let result = self
+            .db
+            .grant_purchase(
+                email,
+                purchase.store.name(),
+                purchase.transaction_id.clone(),
+                purchase.product_id.clone(),
+            )
+            .await
+            .context("granting purchase")?;
+
+        if purchase.store == Store::GooglePlay && !matches!(result, GrantResult::NoProfile) {
+            // Unacknowledged purchases are automatically refunded after 3 days
+            if let Err(e) = self
+                .acknowledge_google_play(&purchase.product_id, &purchase.transaction_id)
+                .await
+            {
+                error!(target: "purchases", "{:?}", e.context("acknowledging google play purchase"));
+            }
+        }
+
+        Ok(result)
+    }
+
+    async fn verify_app_store(
+        &self,
+        product_id: &str,
+        receipt: String,
+    ) -> Result<Option<VerifiedPurchase>, Error> {
+        #[derive(Serialize)]
+        struct VerifyRequest<'a> {
+            #[serde(rename = "receipt-data")]
+            receipt_data: &'a str,
+            password: &'a str,
+            #[serde(rename = "exclude-old-transactions")]
+            exclude_old_transactions: bool,
+        }
+        #[derive(Deserialize)]
+        struct InApp {
+            product_id: String,
+            original_transaction_id: String,
+        }
+        #[derive(Deserialize)]
+        struct Receipt {
+            bundle_id: String,
+            #[serde(default)]
+            in_app: Vec<InApp>,
+        }
+        #[derive(Deserialize)]
+        struct VerifyResponse {
+            status: u32,
+            receipt: Option<Receipt>,
+        }
+
+        let request = VerifyRequest {
+            receipt_data: &receipt,
+            password: &self.apple_shared_secret,
+            exclude_old_transactions: false,
+        };
+
+        let mut response: VerifyResponse = self
+            .client
+            .post(APPLE_VERIFY_URL)
+            .json(&request)
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        if response.status == APPLE_SANDBOX_RECEIPT_STATUS {
+            response = self
+                .client
+                .post(APPLE_SANDBOX_VERIFY_URL)
+                .json(&request)
+                .send()
+                .await?
+                .json()
+                .await?;
+        }
+
+        if response.status != 0 {
+            warn!(target: "purchases", "App Store rejected receipt with status {}", response.status);
+            return Ok(None);
+        }
+        let Some(receipt) = response.receipt else {
+            return Ok(None)
+        };
+        if receipt.bundle_id != self.apple_bundle_id {
+            warn!(target: "purchases", "Got receipt for another app: {}", receipt.bundle_id);
+            return Ok(None);
+        }
+
+        Ok(receipt
+            .in_app
+            .into_iter()
+            .find(|x| x.product_id == product_id)
+            .map(|x| VerifiedPurchase {
+                store: Store::AppStore,
+                product_id: x.product_id,
+                transaction_id: x.original_transaction_id,
+            }))
+    }
+
+    fn google_play_token(&self) -> Result<String, Error> {
+        // The token is refreshed externally, so it is read every time
+        read_to_string(&self.google_play_token_path)
+            .map(|x| x.trim().to_string())
+            .context(format!("Reading {}", self.google_play_token_path))
+    }
+
+    /// The URL of the purchase, whose segments are percent-encoded as they
+    /// are given by clients
+    fn google_play_url(&self, product_id: &str, token_segment: &str) -> Result<Url, Error> {
+        let mut url = Url::parse(GOOGLE_PLAY_API_BASE)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Google Play API base cannot have segments"))?
+            .extend([
+                self.google_play_package_name.as_str(),
+                "purchases",
+                "products",
+                product_id,
+                "tokens",
+                token_segment,
+            ]);
+        Ok(url)
+    }
+
+    async fn verify_google_play(
+        &self,
+        product_id: &str,
+        purchase_token: String,
+    ) -> Result<Option<VerifiedPurchase>, Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct ProductPurchase {
+            /// 0 is purchased, 1 is canceled, 2 is pending
+            purchase_state: u8,
+        }
+
+        let response = self
+            .client
+            .get(self.google_play_url(product_id, &purchase_token)?)
+            .bearer_auth(self.google_play_token()?)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND => return Ok(None),
+            status => return Err(anyhow!("Google Play responded with {status}")),
+        }
+
+        let purchase: ProductPurchase = response.json().await?;
+
+        if purchase.purchase_state != 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(VerifiedPurchase {
+            store: Store::GooglePlay,
+            product_id: product_id.into(),
+            transaction_id: purchase_token,
+        }))
+    }
+
+    async fn acknowledge_google_play(
+        &self,
+        product_id: &str,
+        purchase_token: &str,
+    ) -> Result<(), Error> {
+        self.client
+            .post(self.google_play_url(product_id, &format!("{purchase_token}:acknowledge"))?)
+            .bearer_auth(self.google_play_token()?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn revoke(&self, store: Store, transaction_id: String) {
+        match self.db.revoke_purchase(store.name(), transaction_id.clone()).await {
+            Ok(Some(email)) => {
+                info!(target: "purchases", "Revoked {} purchase {transaction_id} from {email}", store.name())
+            }
+            Ok(None) => {
+                warn!(target: "purchases", "Got revocation for unknown {} purchase {transaction_id}", store.name())
+            }
+            Err(e) => {
+                error!(target: "purchases", "{:?}", e.context(format!("revoking {transaction_id}")))
+            }
+        }
+    }
+
+    async fn renew(&self, store: Store, transaction_id: String) {
+        match self.db.renew_purchase(store.name(), transaction_id.clone()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(target: "purchases", "Got renewal for unknown {} purchase {transaction_id}", store.name())
+            }
+            Err(e) => {
+                error!(target: "purchases", "{:?}", e.context(format!("renewing {transaction_id}")))
+            }
+        }
+    }
+
+    fn is_webhook_authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = headers.get(WEBHOOK_TOKEN_HEADER) else {
+            return false
+        };
+        !self.webhook_token.is_empty()
+            && constant_time_eq(token.as_bytes(), self.webhook_token.as_bytes())
+    }
+}
+
+/// Decodes the payload of a JWS signed by the App Store
+///
+/// The certificate chain in the header must lead up to the Apple Root CA - G3,
+/// and the leaf certificate must have signed the JWS
+fn verify_jws_payload<T: DeserializeOwned>(jws: &str) -> Result<T, Error> {
+    #[derive(Deserialize)]
+    struct Header {
+        alg: String,
+        x5c: Vec<String>,
+    }
+
+    let mut parts = jws.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(anyhow!("Malformed JWS"))
+    };
+    let Header { alg, x5c } = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+    if alg != "ES256" {
+        return Err(anyhow!("Unexpected JWS algorithm: {alg}"));
+    }
+
+    let ders = x5c
+        .iter()
+        .map(|x| STANDARD.decode(x))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [leaf, intermediate, root] = ders.as_slice() else {
+        return Err(anyhow!("Expected 3 certificates in JWS, got {}", ders.len()))
+    };
+    if !constant_time_eq(&Sha256::digest(root), &APPLE_ROOT_CA_G3_FINGERPRINT) {
+        return Err(anyhow!("JWS is not rooted at the Apple Root CA - G3"));
+    }
+
+    let (_, leaf) = X509Certificate::from_der(leaf)?;
+    let (_, intermediate) = X509Certificate::from_der(intermediate)?;
+    let (_, root) = X509Certificate::from_der(root)?;
+    for cert in [&leaf, &intermediate] {
+        if !cert.validity().is_valid() {
+            return Err(anyhow!("Expired certificate in JWS: {}", cert.subject()));
+        }
+    }
+    intermediate
+        .verify_signature(Some(root.public_key()))
+        .context("verifying intermediate certificate of JWS")?;
+    leaf.verify_signature(Some(intermediate.public_key()))
+        .context("verifying leaf certificate of JWS")?;
+    if !leaf
+        .extensions()
+        .iter()
+        .any(|x| x.oid.to_id_string() == APPLE_RECEIPT_SIGNING_OID)
+    {
+        return Err(anyhow!("JWS was not signed by an App Store certificate"));
+    }
+
+    UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_FIXED,
+        &leaf.public_key().subject_public_key.data,
+    )
+    .verify(
+        // The signing input is the header and payload as they were given
+        &jws.as_bytes()[..header.len() + 1 + payload.len()],
+        &URL_SAFE_NO_PAD.decode(signature)?,
+    )
+    .map_err(|_| anyhow!("Invalid JWS signature"))?;
+
+    Ok(serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?)
+}
+
+/// Receives App Store Server Notifications (V2)
+///
+/// Notifications must be signed by the App Store for the configured bundle,
+/// and carry the secret webhook token in the `Webhook-Token` header
+pub async fn app_store_webhook(
+    headers: HeaderMap,
+    State(state): State<GlobalState>,
+    Json(body): Json<serde_json::Value>,
+) -> StatusCode {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct NotificationData {
+        bundle_id: String,
+        signed_transaction_info: String,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Notification {
+        notification_type: String,
+        data: NotificationData,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TransactionInfo {
+        bundle_id: String,
+        original_transaction_id: String,
+    }
+
+    let purchases = state.purchases;
+
+    if !purchases.is_webhook_authorized(&headers) {
+        warn!(target: "suspicious_security", "Unauthorized App Store webhook call");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let Some(signed_payload) = body.get("signedPayload").and_then(|x| x.as_str()) else {
+        return StatusCode::BAD_REQUEST
+    };
+
+    let (notification, transaction) = match verify_jws_payload::<Notification>(signed_payload)
+        .and_then(|x| {
+            let transaction =
+                verify_jws_payload::<TransactionInfo>(&x.data.signed_transaction_info)?;
+            Ok((x, transaction))
+        }) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(target: "purchases", "{:?}", e.context("decoding App Store notification"));
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    // Apple signs the notifications of every app, so they must be checked to be for this one
+    for bundle_id in [&notification.data.bundle_id, &transaction.bundle_id] {
+        if bundle_id != &purchases.apple_bundle_id {
+            warn!(target: "purchases", "Got App Store notification for another app: {bundle_id}");
+            return StatusCode::BAD_REQUEST;
+        }
+    }
+
+    match notification.notification_type.as_str() {
+        "REFUND" | "REVOKE" => {
+            purchases
+                .revoke(Store::AppStore, transaction.original_transaction_id)
+                .await
+        }
+        "DID_RENEW" => {
+            purchases
+                .renew(Store::AppStore, transaction.original_transaction_id)
+                .await
+        }
+        _ => {}
+    }
+
+    StatusCode::OK
+}
+
+/// Receives Google Play Real-time developer notifications pushed through Pub/Sub
+///
+/// Notifications must carry the secret webhook token in the `Webhook-Token` header
+pub async fn google_play_webhook(
+    headers: HeaderMap,
+    State(state): State<GlobalState>,
+    Json(body): Json<serde_json::Value>,
+) -> StatusCode {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct VoidedPurchaseNotification {
+        purchase_token: String,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct SubscriptionNotification {
+        /// 2 is renewed
+        notification_type: u8,
+        purchase_token: String,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct DeveloperNotification {
+        voided_purchase_notification: Option<VoidedPurchaseNotification>,
+        subscription_notification: Option<SubscriptionNotification>,
+    }
+
+    let purchases = state.purchases;
+
+    if !purchases.is_webhook_authorized(&headers) {
+        warn!(target: "suspicious_security", "Unauthorized Google Play webhook call");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let Some(data) = body
+        .get("message")
+        .and_then(|x| x.get("data"))
+        .and_then(|x| x.as_str()) else {
+        return StatusCode::BAD_REQUEST
+    };
+
+    let notification: DeveloperNotification = match STANDARD
+        .decode(data)
+        .map_err(Error::from)
+        .and_then(|x| serde_json::from_slice(&x).map_err(Error::from))
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!(target: "purchases", "{:?}", e.context("decoding Google Play notification"));
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    if let Some(voided) = notification.voided_purchase_notification {
+        purchases
+            .revoke(Store::GooglePlay, voided.purchase_token)
+            .await;
+    }
+    if let Some(subscription) = notification.subscription_notification {
+        if subscription.notification_type == 2 {
+            purchases
+                .renew(Store::GooglePlay, subscription.purchase_token)
+                .await;
+        }
+    }
+
+    StatusCode::OK
+}
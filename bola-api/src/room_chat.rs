@@ -0,0 +1,445 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use log::error;
+use mangle_api_core::distributed::Node;
+use rustrict::CensorStr;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    spawn,
+    sync::broadcast::{channel, error::RecvError, Receiver, Sender},
+};
+
+use crate::{
+    multiplayer::RoomCode,
+    network::{NetworkMessage, RoomChatEvent, SiblingNetworkHandler},
+};
+
+const ROOM_CHAT_BUFFER_SIZE: usize = 32;
+/// How often a single member may send a message
+pub const ROOM_CHAT_INTERVAL: Duration = Duration::from_millis(500);
+/// In characters
+pub const MAX_ROOM_CHAT_LENGTH: usize = 200;
+/// How often a single session may join a room chat, so that rooms cannot be
+/// found by trying every code
+pub const ROOM_JOIN_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub enum ChatEvent {
+    /// Sent when the first member joins, who becomes the host
+    Opened {
+        host: String,
+    },
+    Joined {
+        username: String,
+    },
+    Left {
+        username: String,
+    },
+    Message {
+        username: String,
+        text: String,
+    },
+    Muted {
+        username: String,
+    },
+    Kicked {
+        username: String,
+    },
+    /// Sent when the host leaves, which ends the room for everyone
+    Closed,
+    /// Lets the user join the room, which nobody else can
+    Invited {
+        username: String,
+    },
+}
+
+#[derive(Serialize)]
+pub struct RoomChatView<'a> {
+    pub room_chat: &'a ChatEvent,
+}
+
+#[derive(Debug)]
+pub enum RoomChatError {
+    Kicked,
+    NotInvited,
+    NotInRoom,
+    NotHost,
+    MemberNotFound,
+    Muted,
+    TooLong,
+    TooManyMessages,
+}
+
+struct Room {
+    host: String,
+    /// Every member of the room, including those connected to siblings, with
+    /// the sibling they are connected to
+    members: HashMap<String, Option<Arc<str>>>,
+    invited: HashSet<String>,
+    muted: HashSet<String>,
+    kicked: HashSet<String>,
+    /// Notifies the members connected to this node
+    sender: Sender<Arc<ChatEvent>>,
+}
+
+impl Room {
+    fn new(host: String, node: Option<Arc<str>>) -> Self {
+        Self {
+            members: [(host.clone(), node)].into(),
+            host,
+            invited: Default::default(),
+            muted: Default::default(),
+            kicked: Default::default(),
+            sender: channel(ROOM_CHAT_BUFFER_SIZE).0,
+        }
+    }
+
+    /// The siblings that members of the room are connected to
+    fn member_nodes(&self) -> HashSet<Arc<str>> {
+        self.members.values().flatten().cloned().collect()
+    }
+}
+
+/// A chat for the members of each multiplayer room, so that they can
+/// coordinate before their WebRTC connection is up
+///
+/// Only users invited by the host can join a room. Every node keeps track of
+/// every room, as membership events are relayed to all siblings, while
+/// messages are only relayed to the siblings that members are connected to
+pub struct RoomChat {
+    rooms: DashMap<RoomCode, Room>,
+    node: &'static Node<SiblingNetworkHandler>,
+}
+
+impl RoomChat {
+    pub fn new(node: &'static Node<SiblingNetworkHandler>) -> &'static Self {
+        let room_chat = manglext::immut_leak(Self {
+            rooms: Default::default(),
+            node,
+        });
+        let mut subscription = node.get_handler().subscribe_to_room_chat();
+
+        spawn(async move {
+            loop {
+                let Some((sibling, RoomChatEvent { room, event })) =
+                    subscription.wait_for_event().await
+                else {
+                    break;
+                };
+                let Ok(code) = RoomCode::try_from(room) else {
+                    continue;
+                };
+                room_chat.apply(Some(sibling), code, event);
+            }
+        });
+
+        room_chat
+    }
+
+    /// Joins the chat of the given room, opening it with the given user as
+    /// host if nobody is in it
+    ///
+    /// Rooms that are open can only be joined by the users their host invited
+    pub fn join(
+        &'static self,
+        code: RoomCode,
+        username: String,
+    ) -> Result<RoomMembership, RoomChatError> {
+        let (receiver, event) = match self.rooms.entry(code) {
+            Entry::Occupied(room) => {
+                let room = room.get();
+                if room.kicked.contains(&username) {
+                    return Err(RoomChatError::Kicked);
+                }
+                if room.host != username && !room.invited.contains(&username) {
+                    return Err(RoomChatError::NotInvited);
+                }
+                (
+                    room.sender.subscribe(),
+                    ChatEvent::Joined {
+                        username: username.clone(),
+                    },
+                )
+            }
+            Entry::Vacant(slot) => {
+                let room = slot.insert(Room::new(username.clone(), None));
+                (
+                    room.sender.subscribe(),
+                    ChatEvent::Opened {
+                        host: username.clone(),
+                    },
+                )
+            }
+        };
+        self.publish(code, event);
+
+        Ok(RoomMembership {
+            room_chat: self,
+            code,
+            username,
+            receiver,
+            last_message: None,
+            ended: false,
+        })
+    }
+
+    /// Applies the event to this node, then relays it to the siblings that
+    /// need it
+    fn publish(&self, code: RoomCode, event: ChatEvent) {
+        // Messages only matter to the siblings that members are connected to,
+        // and are relayed to them before a member who left is forgotten
+        let targets: Vec<String> = match &event {
+            ChatEvent::Message { .. } => self
+                .rooms
+                .get(&code)
+                .map(|room| room.member_nodes())
+                .unwrap_or_default()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            _ => self
+                .node
+                .get_sibling_domains()
+                .map(ToString::to_string)
+                .collect(),
+        };
+        self.apply(None, code, event.clone());
+        if targets.is_empty() {
+            return;
+        }
+
+        // Chat is stale by the time a dead letter would be redelivered, so it
+        // is sent to each sibling once instead of being broadcast
+        let message = NetworkMessage::RoomChat(RoomChatEvent {
+            room: code.into(),
+            event,
+        })
+        .traced()
+        .signed();
+        let node = self.node;
+
+        spawn(async move {
+            for domain in targets {
+                if let Err(e) = node.send_message(&domain, &message).await {
+                    error!(target: "room_chat", "Error relaying room chat to {domain}: {e:?}");
+                }
+            }
+        });
+    }
+
+    /// Applies an event that happened on the given sibling, or on this node if None
+    fn apply(&self, sibling: Option<Arc<str>>, code: RoomCode, event: ChatEvent) {
+        if let ChatEvent::Closed = event {
+            if let Some((_, room)) = self.rooms.remove(&code) {
+                let _ = room.sender.send(Arc::new(event));
+            }
+            return;
+        }
+
+        let mut room = match (self.rooms.entry(code), &event) {
+            (Entry::Occupied(room), _) => room.into_ref(),
+            (Entry::Vacant(slot), ChatEvent::Opened { host }) => {
+                slot.insert(Room::new(host.clone(), sibling))
+            }
+            // The room was closed before the event arrived
+            (Entry::Vacant(_), _) => return,
+        };
+        match &event {
+            // Two nodes opened the same room at once, so the host seen first is kept
+            ChatEvent::Opened { .. } => {}
+            ChatEvent::Joined { username } => {
+                room.members.insert(username.clone(), sibling);
+            }
+            ChatEvent::Left { username } => {
+                room.members.remove(username);
+            }
+            ChatEvent::Message { .. } => {}
+            ChatEvent::Muted { username } => {
+                room.muted.insert(username.clone());
+            }
+            ChatEvent::Kicked { username } => {
+                room.members.remove(username);
+                room.kicked.insert(username.clone());
+            }
+            ChatEvent::Invited { username } => {
+                room.invited.insert(username.clone());
+            }
+            ChatEvent::Closed => unreachable!(),
+        }
+        let _ = room.sender.send(Arc::new(event));
+    }
+}
+
+/// Membership of a room chat, which leaves the room when dropped
+///
+/// The room closes when its host leaves
+pub struct RoomMembership {
+    room_chat: &'static RoomChat,
+    code: RoomCode,
+    username: String,
+    receiver: Receiver<Arc<ChatEvent>>,
+    last_message: Option<Instant>,
+    /// Set once the room has closed or this member was kicked
+    ended: bool,
+}
+
+impl RoomMembership {
+    /// Sends a message to the room, with any profanity censored
+    pub fn send(&mut self, text: &str) -> Result<(), RoomChatError> {
+        if text.chars().count() > MAX_ROOM_CHAT_LENGTH {
+            return Err(RoomChatError::TooLong);
+        }
+        if self
+            .last_message
+            .map_or(false, |x| x.elapsed() < ROOM_CHAT_INTERVAL)
+        {
+            return Err(RoomChatError::TooManyMessages);
+        }
+        {
+            let room = self
+                .room_chat
+                .rooms
+                .get(&self.code)
+                .ok_or(RoomChatError::NotInRoom)?;
+            if !room.members.contains_key(&self.username) {
+                return Err(RoomChatError::NotInRoom);
+            }
+            if room.muted.contains(&self.username) {
+                return Err(RoomChatError::Muted);
+            }
+        }
+        self.last_message = Some(Instant::now());
+        self.room_chat.publish(
+            self.code,
+            ChatEvent::Message {
+                username: self.username.clone(),
+                text: text.censor(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Lets the given user join the room, which only the host can do
+    pub fn invite(&self, username: &str) -> Result<(), RoomChatError> {
+        {
+            let room = self
+                .room_chat
+                .rooms
+                .get(&self.code)
+                .ok_or(RoomChatError::NotInRoom)?;
+            if room.host != self.username {
+                return Err(RoomChatError::NotHost);
+            }
+        }
+        self.room_chat.publish(
+            self.code,
+            ChatEvent::Invited {
+                username: username.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn mute(&self, username: &str) -> Result<(), RoomChatError> {
+        self.check_host_of(username)?;
+        self.room_chat.publish(
+            self.code,
+            ChatEvent::Muted {
+                username: username.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes the given member from the room, who cannot join it again
+    pub fn kick(&self, username: &str) -> Result<(), RoomChatError> {
+        self.check_host_of(username)?;
+        self.room_chat.publish(
+            self.code,
+            ChatEvent::Kicked {
+                username: username.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Checks that this member is the host, and that the given user is
+    /// another member of the room
+    fn check_host_of(&self, username: &str) -> Result<(), RoomChatError> {
+        let room = self
+            .room_chat
+            .rooms
+            .get(&self.code)
+            .ok_or(RoomChatError::NotInRoom)?;
+        if room.host != self.username {
+            return Err(RoomChatError::NotHost);
+        }
+        if username == self.username || !room.members.contains_key(username) {
+            return Err(RoomChatError::MemberNotFound);
+        }
+        Ok(())
+    }
+
+    async fn wait_for_event(&mut self) -> Arc<ChatEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => {
+                    match &*event {
+                        ChatEvent::Closed => self.ended = true,
+                        ChatEvent::Kicked { username } if *username == self.username => {
+                            self.ended = true
+                        }
+                        _ => {}
+                    }
+                    break event;
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => {
+                    self.ended = true;
+                    break Arc::new(ChatEvent::Closed);
+                }
+            }
+        }
+    }
+
+    /// Waits for the next event of the room the session is in, dropping the
+    /// membership once the room closes or the member is kicked
+    ///
+    /// Never returns if the session is not in a room
+    pub async fn next_event(membership: &mut Option<Self>) -> Arc<ChatEvent> {
+        let Some(inner) = membership else {
+            return std::future::pending().await;
+        };
+        let event = inner.wait_for_event().await;
+        if inner.ended {
+            *membership = None;
+        }
+        event
+    }
+}
+
+impl Drop for RoomMembership {
+    fn drop(&mut self) {
+        if self.ended {
+            return;
+        }
+        let is_host = self
+            .room_chat
+            .rooms
+            .get(&self.code)
+            .map_or(false, |room| room.host == self.username);
+        let event = if is_host {
+            ChatEvent::Closed
+        } else {
+            ChatEvent::Left {
+                username: self.username.clone(),
+            }
+        };
+        self.room_chat.publish(self.code, event);
+    }
+}
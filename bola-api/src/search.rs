@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use aws_sdk_dynamodb::model::{AttributeValue, ReturnConsumedCapacity};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use log::error;
+use mangle_api_core::{
+    auth::audit::ClientIp,
+    pagination::{Cursor, Page, Pagination},
+    parking_lot::Mutex,
+    serde_json,
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{budget::OperationClass, db::DB, state::GlobalState};
+
+/// Shorter prefixes match too much of the board, which makes scraping easy
+pub const MIN_PREFIX_LENGTH: usize = 3;
+/// Searches return smaller pages than other listings, for the same reason
+const MAX_SEARCH_LIMIT: usize = 20;
+/// How often a single WebSocket connection may search
+pub const SESSION_SEARCH_INTERVAL: Duration = Duration::from_secs(1);
+const SEARCH_WINDOW: Duration = Duration::from_secs(60);
+/// Windows are forgotten once this many exist, if they have ended
+const PRUNE_THRESHOLD: usize = 10_000;
+/// Windows are pruned at most this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+/// Clients beyond this many are refused until older windows end
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// The highscores of a player found by a search
+#[derive(Serialize, Debug)]
+pub struct PlayerView {
+    pub username: String,
+    pub easy_highscore: u16,
+    pub normal_highscore: u16,
+    pub expert_highscore: u16,
+}
+
+pub enum SearchError {
+    PrefixTooShort,
+    InvalidCursor,
+    InternalError(Error),
+}
+
+/// Finds the players whose username starts with `prefix`, in order of username
+///
+/// Usernames are matched case sensitively, using the `unused-username-index`
+/// GSI of the profiles table, which is keyed by `unused` and sorted by `username`
+pub async fn search_usernames(
+    db: &DB,
+    cursor_key: &CursorKey,
+    prefix: &str,
+    pagination: &Pagination,
+) -> Result<Page<PlayerView>, SearchError> {
+    if prefix.chars().count() < MIN_PREFIX_LENGTH {
+        return Err(SearchError::PrefixTooShort);
+    }
+    let start_key = pagination
+        .cursor
+        .as_ref()
+        .map(|cursor| decode_cursor(cursor_key, cursor))
+        .transpose()
+        .map_err(|_| SearchError::InvalidCursor)?;
+    let limit = pagination.limit.min(MAX_SEARCH_LIMIT);
+
+    let permit = db
+        .budgets
+        .acquire(&db.bola_profiles_table, OperationClass::Read)
+        .await
+        .map_err(SearchError::InternalError)?;
+    let query = db
+        .client
+        .query()
+        .table_name(db.bola_profiles_table.clone())
+        .index_name("unused-username-index")
+        .key_condition_expression("unused = :zero AND begins_with(username, :prefix)")
+        .expression_attribute_values(":zero", AttributeValue::N("0".into()))
+        .expression_attribute_values(":prefix", AttributeValue::S(prefix.into()))
+        .set_exclusive_start_key(start_key)
+        .limit(limit as i32)
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await
+        .map_err(|e| SearchError::InternalError(e.into()))?;
+    permit.consume(query.consumed_capacity());
+
+    let items = query
+        .items()
+        .unwrap_or_default()
+        .iter()
+        .map(|item| {
+            DB::map_to_user_profile(item).map(|profile| PlayerView {
+                username: profile.username,
+                easy_highscore: profile.easy_highscore,
+                normal_highscore: profile.normal_highscore,
+                expert_highscore: profile.expert_highscore,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(SearchError::InternalError)?;
+    let next_cursor = query
+        .last_evaluated_key()
+        .map(|key| encode_cursor(cursor_key, key))
+        .transpose()
+        .map_err(SearchError::InternalError)?;
+
+    Ok(Page { items, next_cursor })
+}
+
+/// Encrypts the cursors of searches, so that clients cannot read the emails
+/// in them or forge them
+pub struct CursorKey(LessSafeKey);
+
+impl CursorKey {
+    /// Cursors work on every node with the same secret. If the secret is empty,
+    /// a random key is used, so cursors only work on this node until it restarts
+    pub fn new(secret: &str) -> Self {
+        let mut key = [0; 32];
+        if secret.is_empty() {
+            SystemRandom::new()
+                .fill(&mut key)
+                .expect("the system to have randomness");
+        } else {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC to accept keys of any size");
+            mac.update(b"search cursor");
+            key.copy_from_slice(&mac.finalize().into_bytes());
+        }
+        Self(LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, &key).expect("AES-256 keys to be 32 bytes"),
+        ))
+    }
+
+    /// The random nonce followed by the encrypted position and its tag
+    fn seal(&self, position: &[u8]) -> Result<Cursor, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Generating the nonce of a cursor"))?;
+        let mut sealed = position.to_vec();
+        self.0
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow!("Encrypting a cursor"))?;
+        let mut bytes = nonce.to_vec();
+        bytes.append(&mut sealed);
+        Ok(Cursor::new(URL_SAFE_NO_PAD.encode(bytes)))
+    }
+
+    fn open(&self, cursor: &Cursor) -> Result<Vec<u8>, Error> {
+        let mut nonce = URL_SAFE_NO_PAD.decode(cursor.as_str())?;
+        if nonce.len() < NONCE_LEN {
+            return Err(anyhow!("Cursor is too short"));
+        }
+        let mut sealed = nonce.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&nonce)
+            .map_err(|_| anyhow!("Invalid nonce in cursor"))?;
+        let position = self
+            .0
+            .open_in_place(nonce, Aad::empty(), &mut sealed)
+            .map_err(|_| anyhow!("Cursor was not issued by this key"))?;
+        Ok(position.to_vec())
+    }
+}
+
+/// The cursor of a search is the last evaluated key of its query, which is
+/// made of the email, `unused` and username of the last profile, encrypted
+fn encode_cursor(
+    cursor_key: &CursorKey,
+    key: &HashMap<String, AttributeValue>,
+) -> Result<Cursor, Error> {
+    let mut parts = Vec::with_capacity(3);
+    for field in ["email", "unused", "username"] {
+        let value = key
+            .get(field)
+            .ok_or_else(|| anyhow!("Missing {field} in last evaluated key"))?;
+        let value = match value {
+            AttributeValue::S(x) | AttributeValue::N(x) => x.clone(),
+            _ => return Err(anyhow!("Unexpected type of {field} in last evaluated key")),
+        };
+        parts.push(value);
+    }
+    cursor_key.seal(&serde_json::to_vec(&parts)?)
+}
+
+fn decode_cursor(
+    cursor_key: &CursorKey,
+    cursor: &Cursor,
+) -> Result<HashMap<String, AttributeValue>, Error> {
+    let [email, unused, username]: [String; 3] = serde_json::from_slice(&cursor_key.open(cursor)?)?;
+    Ok([
+        ("email".to_string(), AttributeValue::S(email)),
+        ("unused".to_string(), AttributeValue::N(unused)),
+        ("username".to_string(), AttributeValue::S(username)),
+    ]
+    .into())
+}
+
+struct SearchWindows {
+    /// When the window of each client started, and how many searches it made in it
+    windows: HashMap<Option<IpAddr>, (Instant, u32)>,
+    last_prune: Instant,
+}
+
+/// Limits how many searches each client is served over HTTP each minute
+///
+/// Clients whose address is unknown share a single window
+pub struct SearchLimiter {
+    max_per_minute: u32,
+    windows: Mutex<SearchWindows>,
+}
+
+impl SearchLimiter {
+    pub fn new(max_per_minute: u32) -> Self {
+        Self {
+            max_per_minute,
+            windows: Mutex::new(SearchWindows {
+                windows: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    fn try_acquire(&self, ip: Option<IpAddr>) -> bool {
+        let now = Instant::now();
+        let mut guard = self.windows.lock();
+        let SearchWindows {
+            windows,
+            last_prune,
+        } = &mut *guard;
+        if windows.len() >= PRUNE_THRESHOLD && now.duration_since(*last_prune) >= PRUNE_INTERVAL {
+            *last_prune = now;
+            windows.retain(|_, (start, _)| now.duration_since(*start) < SEARCH_WINDOW);
+        }
+        if windows.len() >= MAX_TRACKED_CLIENTS && !windows.contains_key(&ip) {
+            return false;
+        }
+        let (start, count) = windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= SEARCH_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.max_per_minute {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    prefix: String,
+}
+
+pub async fn search_route(
+    State(state): State<GlobalState>,
+    ClientIp(ip): ClientIp,
+    pagination: Pagination,
+    Query(SearchQuery { prefix }): Query<SearchQuery>,
+) -> Result<Json<Page<PlayerView>>, (StatusCode, &'static str)> {
+    if !state.search_limiter.try_acquire(ip) {
+        return Err((StatusCode::TOO_MANY_REQUESTS, "Too many searches"));
+    }
+    match search_usernames(state.db, state.search_cursors, &prefix, &pagination).await {
+        Ok(page) => Ok(Json(page)),
+        Err(SearchError::PrefixTooShort) => Err((
+            StatusCode::BAD_REQUEST,
+            "prefix must be at least 3 characters",
+        )),
+        Err(SearchError::InvalidCursor) => Err((StatusCode::BAD_REQUEST, "Invalid cursor")),
+        Err(SearchError::InternalError(e)) => {
+            error!(target: "search", "{:?}", e.context(format!("searching for {prefix}")));
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal Error"))
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Error};
+use aws_sdk_dynamodb::model::{AttributeValue, ReturnConsumedCapacity};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::error;
+use mangle_api_core::{
+    neo_api::metrics::{SessionMetrics, SessionMetricsSnapshot},
+    rand::{thread_rng, RngCore},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{spawn, time::sleep};
+
+use crate::{budget::OperationClass, db::DB, state::GlobalState};
+
+const HOUR_SECS: u64 = 60 * 60;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SessionMetricsConfig {
+    /// Keyed by `node` (string) and `hour` (number), with TTL enabled on `expires_at`
+    pub table: String,
+    /// How long each hourly snapshot is kept
+    #[serde(default = "retention")]
+    pub retention: Duration,
+}
+
+fn retention() -> Duration {
+    // 90 days
+    Duration::from_secs(HOUR_SECS * 24 * 90)
+}
+
+/// The session metrics of a node over a single hour
+#[derive(Serialize, Debug)]
+pub struct HourlySessionMetrics {
+    /// The name of the node, followed by the ID of the process that ran it
+    pub node: String,
+    /// Hours since the unix epoch
+    pub hour: u64,
+    #[serde(flatten)]
+    pub metrics: SessionMetricsSnapshot,
+}
+
+/// Persists a snapshot of the session metrics of this node every hour, for
+/// week over week capacity planning
+pub struct SessionMetricsStore {
+    db: &'static DB,
+    config: SessionMetricsConfig,
+    /// Unique to this process, as nodes can share a name such as the default
+    node_key: String,
+}
+
+impl SessionMetricsStore {
+    pub fn new(
+        db: &'static DB,
+        config: SessionMetricsConfig,
+        node_name: String,
+        metrics: &'static SessionMetrics,
+    ) -> &'static Self {
+        let store = manglext::immut_leak(Self {
+            db,
+            config,
+            node_key: format!("{node_name}#{:08x}", thread_rng().next_u32()),
+        });
+
+        spawn(async move {
+            // Discards the counts from before the first full hour
+            metrics.take_snapshot();
+            loop {
+                sleep(Duration::from_secs(HOUR_SECS - now_secs() % HOUR_SECS)).await;
+                // The snapshot covers the hour that just ended
+                let hour = now_secs() / HOUR_SECS - 1;
+                if let Err(e) = store.save(hour, metrics.take_snapshot()).await {
+                    error!(target: "session_metrics", "{:?}", e.context("saving session metrics"));
+                }
+            }
+        });
+
+        store
+    }
+
+    async fn save(&self, hour: u64, metrics: SessionMetricsSnapshot) -> Result<(), Error> {
+        let expires_at = (hour + 1) * HOUR_SECS + self.config.retention.as_secs();
+        let permit = self
+            .db
+            .budgets
+            .acquire(&self.config.table, OperationClass::Write)
+            .await?;
+        let output = self
+            .db
+            .client
+            .put_item()
+            .table_name(self.config.table.clone())
+            .item("node", AttributeValue::S(self.node_key.clone()))
+            .item("hour", AttributeValue::N(hour.to_string()))
+            .item("active", AttributeValue::N(metrics.active.to_string()))
+            .item(
+                "peak_active",
+                AttributeValue::N(metrics.peak_active.to_string()),
+            )
+            .item("opened", AttributeValue::N(metrics.opened.to_string()))
+            .item(
+                "messages_in",
+                AttributeValue::N(metrics.messages_in.to_string()),
+            )
+            .item(
+                "messages_out",
+                AttributeValue::N(metrics.messages_out.to_string()),
+            )
+            .item("expires_at", AttributeValue::N(expires_at.to_string()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await?;
+        permit.consume(output.consumed_capacity());
+        Ok(())
+    }
+
+    /// Gets the snapshots of every node since the given hour, oldest first
+    pub async fn pull(&self, since_hour: u64) -> Result<Vec<HourlySessionMetrics>, Error> {
+        let mut out = vec![];
+        let mut start_key = None;
+
+        loop {
+            let permit = self
+                .db
+                .budgets
+                .acquire(&self.config.table, OperationClass::Read)
+                .await?;
+            let output = self
+                .db
+                .client
+                .scan()
+                .table_name(self.config.table.clone())
+                .filter_expression("#hour >= :since")
+                .expression_attribute_names("#hour", "hour")
+                .expression_attribute_values(":since", AttributeValue::N(since_hour.to_string()))
+                .set_exclusive_start_key(start_key)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await?;
+            permit.consume(output.consumed_capacity());
+
+            for item in output.items().unwrap_or_default() {
+                out.push(Self::map_to_metrics(item).context("Parsing session metrics")?);
+            }
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break;
+            }
+        }
+
+        out.sort_unstable_by(|a, b| (a.hour, &a.node).cmp(&(b.hour, &b.node)));
+        Ok(out)
+    }
+
+    fn map_to_metrics(
+        map: &HashMap<String, AttributeValue>,
+    ) -> Result<HourlySessionMetrics, Error> {
+        macro_rules! field {
+            ($field:literal, $op:ident) => {
+                map.get($field).and_then(|x| x.$op().ok()).ok_or_else(|| {
+                    anyhow!("Could not deserialize field: {} in session metrics", $field)
+                })?
+            };
+        }
+        macro_rules! count {
+            ($field:literal) => {
+                field!($field, as_n).parse()?
+            };
+        }
+
+        Ok(HourlySessionMetrics {
+            node: field!("node", as_s).clone(),
+            hour: count!("hour"),
+            metrics: SessionMetricsSnapshot {
+                active: count!("active"),
+                peak_active: count!("peak_active"),
+                opened: count!("opened"),
+                messages_in: count!("messages_in"),
+                messages_out: count!("messages_out"),
+            },
+        })
+    }
+}
+
+fn to_csv(metrics: &[HourlySessionMetrics]) -> String {
+    let mut csv = "node,hour,active,peak_active,opened,messages_in,messages_out\n".to_string();
+    for x in metrics {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{},{}",
+            x.node,
+            x.hour,
+            x.metrics.active,
+            x.metrics.peak_active,
+            x.metrics.opened,
+            x.metrics.messages_in,
+            x.metrics.messages_out
+        );
+    }
+    csv
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default = "Default::default")]
+    format: ExportFormat,
+    /// Hours since the unix epoch. Defaults to the past week
+    since: Option<u64>,
+}
+
+/// Exports the hourly session metrics of every node as json or csv
+pub async fn export_session_metrics(
+    State(state): State<GlobalState>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let Some(store) = state.session_metrics else {
+        return Err((StatusCode::NOT_FOUND, "Session metrics are not persisted"));
+    };
+    let since = query
+        .since
+        .unwrap_or_else(|| (now_secs() / HOUR_SECS).saturating_sub(24 * 7));
+    let metrics = store.pull(since).await.map_err(|e| {
+        error!(target: "session_metrics", "{:?}", e.context("exporting session metrics"));
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Error")
+    })?;
+
+    Ok(match query.format {
+        ExportFormat::Json => Json(metrics).into_response(),
+        ExportFormat::Csv => {
+            ([(header::CONTENT_TYPE, "text/csv")], to_csv(&metrics)).into_response()
+        }
+    })
+}
@@ -1,15 +1,32 @@
+use std::{collections::HashSet, sync::Arc};
+
 use axum::extract::FromRef;
 use mangle_api_core::{
     auth::{
+        audit::AuthAudit,
         auth_pages::AuthPages,
         openid::{google::GoogleOIDC, OIDCState},
     },
-    neo_api::NeoApiConfig,
+    distributed::{ids::IdGenerator, lock::DistributedLocks, Node},
+    readiness::Readiness,
+    reload::ReloadableConfig,
 };
 
 use crate::{
-    db::DB, leaderboard::Leaderboard, multiplayer::Multiplayer, tournament::Tournament,
-    ws_api::WsApiHandler, LoginTokenGranter,
+    announcements::Announcements,
+    attestation::Attestation,
+    db::DB,
+    leaderboard::Leaderboard,
+    moderation::Moderation,
+    multiplayer::Multiplayer,
+    network::{SiblingClockSkew, SiblingNetworkHandler},
+    purchases::Purchases,
+    search::{CursorKey, SearchLimiter},
+    session_metrics::SessionMetricsStore,
+    stats::Stats,
+    tournament::Tournament,
+    ws_api::WsApi,
+    LoginTokenGranter,
 };
 
 #[derive(Clone, Copy)]
@@ -17,12 +34,30 @@ pub(crate) struct GlobalState {
     pub db: &'static DB,
     pub oidc_state: &'static OIDCState,
     pub goidc: &'static GoogleOIDC<&'static OIDCState>,
-    pub auth_pages: &'static AuthPages,
+    /// Replaced by the `reload` command
+    pub auth_pages: &'static ReloadableConfig<AuthPages>,
+    /// The players with the admin role, replaced by the `reload` command
+    pub admin_emails: &'static ReloadableConfig<HashSet<String>>,
     pub login_tokens: &'static LoginTokenGranter,
+    /// Shared by the API token, login tokens and OpenID logins
+    pub auth_audit: &'static Arc<AuthAudit>,
     pub leaderboard: &'static Leaderboard,
-    pub ws_api: &'static NeoApiConfig<WsApiHandler>,
+    pub ws_api: &'static WsApi,
     pub tournament: &'static Tournament,
     pub multiplayer: &'static Multiplayer,
+    pub purchases: &'static Purchases,
+    pub announcements: &'static Announcements,
+    pub attestation: &'static Attestation,
+    pub stats: &'static Stats,
+    pub moderation: &'static Moderation,
+    pub readiness: &'static Readiness,
+    pub node: &'static Node<SiblingNetworkHandler>,
+    pub clock_skew: &'static SiblingClockSkew,
+    pub locks: &'static DistributedLocks,
+    pub ids: &'static IdGenerator,
+    pub search_limiter: &'static SearchLimiter,
+    pub search_cursors: &'static CursorKey,
+    pub session_metrics: Option<&'static SessionMetricsStore>,
 }
 
 impl AsRef<LoginTokenGranter> for GlobalState {
@@ -31,12 +66,18 @@ impl AsRef<LoginTokenGranter> for GlobalState {
     }
 }
 
-impl AsRef<NeoApiConfig<WsApiHandler>> for GlobalState {
-    fn as_ref(&self) -> &NeoApiConfig<WsApiHandler> {
+impl AsRef<WsApi> for GlobalState {
+    fn as_ref(&self) -> &WsApi {
         self.ws_api
     }
 }
 
+impl AsRef<Readiness> for GlobalState {
+    fn as_ref(&self) -> &Readiness {
+        self.readiness
+    }
+}
+
 impl AsRef<OIDCState> for GlobalState {
     fn as_ref(&self) -> &OIDCState {
         self.oidc_state
@@ -45,79 +86,306 @@ impl AsRef<OIDCState> for GlobalState {
 
 impl FromRef<GlobalState> for AuthPages {
     fn from_ref(input: &GlobalState) -> Self {
-        input.auth_pages.clone()
+        AuthPages::clone(&input.auth_pages.get())
     }
 }
 
 #[macro_export]
 macro_rules! new_global {
     ($config:expr, $https_identity:expr, $aws_config:expr) => {{
-        use std::fs::read_to_string;
-
-        let internal_error_page = read_to_string(&$config.internal_error_path)
-            .context(format!("Reading {}", $config.internal_error_path))?;
-        let invalid_page = read_to_string(&$config.invalid_path)
-            .context(format!("Reading {}", $config.invalid_path))?;
-        let success_page = read_to_string(&$config.success_path)
-            .context(format!("Reading {}", $config.success_path))?;
-        let late_page =
-            read_to_string(&$config.late_path).context(format!("Reading {}", $config.late_path))?;
-        let auth_pages = manglext::immut_leak(mangle_api_core::auth::auth_pages::AuthPages::new(
-            mangle_api_core::auth::auth_pages::AuthPagesSrc {
-                internal_error: internal_error_page,
-                late: late_page,
-                invalid: invalid_page,
-                success: success_page,
-            },
+        let auth_pages = manglext::immut_leak(mangle_api_core::reload::ReloadableConfig::new(
+            $config.auth_pages()?,
         ));
 
-        let oidc_state = manglext::immut_leak(mangle_api_core::auth::openid::OIDCState::default());
+        let auth_audit = manglext::immut_leak(std::sync::Arc::new(
+            mangle_api_core::auth::audit::AuthAudit::new(
+                $config.auth_allowed_failures,
+                $config.auth_lockout,
+            )
+            .set_max_lockout($config.auth_max_lockout),
+        ));
+        let oidc_state = manglext::immut_leak(
+            mangle_api_core::auth::openid::OIDCState::default().set_audit(auth_audit.clone()),
+        );
 
+        let mut network_handler = $crate::network::SiblingNetworkHandler::new();
+        if let Some(signing) = &$config.message_signing {
+            mangle_api_core::distributed::signing::set_signer(
+                mangle_api_core::distributed::signing::MessageSigner::from_config(
+                    signing,
+                    $config.node_name.clone(),
+                )?,
+            )?;
+            network_handler = network_handler.set_verifier(
+                mangle_api_core::distributed::signing::SignatureVerifier::from_config(signing)?,
+            );
+        }
         let node = manglext::immut_leak(
             mangle_api_core::distributed::Node::new(
                 $config.sibling_domains,
                 $config.network_port,
                 $https_identity.clone(),
-                $crate::network::SiblingNetworkHandler::new(),
+                $config.tcp,
+                network_handler,
+            )
+            .await?
+            .set_dead_letter_limits($config.dead_letter_capacity, $config.dead_letter_ttl)
+            .set_dead_letter_path(&$config.dead_letter_path)
+            .context("loading dead letters")?,
+        );
+        $crate::network::redeliver_dead_letters(node);
+        let clock_skew = manglext::immut_leak(
+            mangle_api_core::distributed::clock::ClockSkew::new(node, |probe| {
+                $crate::network::NetworkMessage::ClockProbe(probe).signed()
+            })
+            .set_warn_threshold($config.clock_skew_warning),
+        );
+        tokio::spawn(clock_skew.probe_forever($config.clock_probe_interval));
+        let ids = manglext::immut_leak(
+            mangle_api_core::distributed::ids::IdGenerator::from_membership(
+                &$config.node_name,
+                node,
             )
-            .await?,
+            .context("Assigning node ID")?,
         );
+        let locks =
+            manglext::immut_leak(mangle_api_core::distributed::lock::DistributedLocks::new(
+                mangle_api_core::distributed::lock::NodeLockBackend::new(
+                    node,
+                    node.get_handler().get_lock_table(),
+                    |request| $crate::network::NetworkMessage::Lock(request).signed(),
+                ),
+            ));
         let db = manglext::immut_leak($crate::db::DB::new(
             &$aws_config,
             $config.bola_profiles_table,
+            $config.bola_purchases_table,
+            $config.bola_announcements_table,
+            $config.bola_stats_table,
+            $config.bola_moderation_table,
+            $config.bola_revocations_table,
+            $config.capacity_budgets,
+        ));
+        let purchases = manglext::immut_leak($crate::purchases::Purchases::new(
+            db,
+            $config.apple_bundle_id,
+            $config.apple_shared_secret,
+            $config.google_play_package_name.clone(),
+            $config.google_play_token_path.clone(),
+            $config.purchase_webhook_token,
         ));
 
+        let oidc_redirect_url = $config.oidc_redirect_base + "/oidc/redirect";
         let goidc = manglext::immut_leak(
             mangle_api_core::auth::openid::google::new_google_oidc_from_file(
                 $config.google_client_secret_path,
                 oidc_state.clone(),
-                &($config.oidc_redirect_base + "/oidc/redirect"),
+                &oidc_redirect_url,
             )
             .await
-            .context("parsing google oauth")?,
+            .context("parsing google oauth")?
+            .set_pkce(true),
         );
+        let device_goidc = match $config.google_device_client_secret_path {
+            Some(path) => Some(manglext::immut_leak(
+                mangle_api_core::auth::openid::google::new_google_oidc_from_file(
+                    path,
+                    oidc_state.clone(),
+                    &oidc_redirect_url,
+                )
+                .await
+                .context("parsing google device client")?
+                .enable_device_auth(),
+            )),
+            None => None,
+        };
+        let mut oidc_providers =
+            mangle_api_core::auth::oidc_providers::OIDCProviderRegistry::from_configs(
+                $config.oidc_providers,
+                &oidc_redirect_url,
+                oidc_state.clone(),
+            )
+            .await?
+            .add_provider("google", goidc.0.clone())?;
+        if let Some(apple_sign_in) = &$config.apple_sign_in {
+            let apple = mangle_api_core::auth::openid::apple::new_apple_oidc_from_file(
+                apple_sign_in,
+                oidc_state.clone(),
+                &oidc_redirect_url,
+            )
+            .await
+            .context("parsing apple sign in")?;
+            oidc_providers = oidc_providers.add_provider("apple", apple.0)?;
+        }
+        let oidc_providers = manglext::immut_leak(oidc_providers);
 
-        let leaderboard =
-            manglext::immut_leak($crate::leaderboard::Leaderboard::new(db.clone(), node, 5).await?);
-        let login_tokens = manglext::immut_leak(LoginTokenGranter::new($config.token_duration));
-        let ws_api = manglext::immut_leak(mangle_api_core::neo_api::NeoApiConfig::new(
-            WS_PING_DELAY,
-            $crate::ws_api::WsApiHandler::new(leaderboard, db, &goidc.0, login_tokens),
+        let tournament = manglext::immut_leak(
+            $crate::tournament::Tournament::new($config.start_week_time)
+                .set_blind_period($config.tournament_blind_period),
+        );
+        let score_queue = manglext::immut_leak(
+            mangle_api_core::persistent_queue::PersistentQueue::open(
+                &$config.score_queue_dir,
+                $config.score_queue_capacity,
+            )
+            .context("opening score queue")?,
+        );
+        let leaderboard = manglext::immut_leak(
+            $crate::leaderboard::Leaderboard::new(db.clone(), node, tournament, score_queue, 5)
+                .await?,
+        );
+        let announcements = $crate::announcements::Announcements::new(db, node).await?;
+        let stats = $crate::stats::Stats::new(db, $config.node_name.clone()).await?;
+        let attestation = manglext::immut_leak($crate::attestation::Attestation::new(
+            $config.attestation_policy,
+            $config.google_play_package_name,
+            $config.google_play_token_path,
+            $config.device_check_token_path,
+            $config.build_token_secret,
+        ));
+        let admin_emails = manglext::immut_leak(mangle_api_core::reload::ReloadableConfig::new(
+            $config.admin_emails(),
+        ));
+        let mut login_tokens = LoginTokenGranter::new($config.token_duration)
+            .set_roles(|identifier: &$crate::LoginTokenData| $crate::LoginRoles {
+                admin: admin_emails.get().contains(&identifier.email),
+            })
+            .set_sliding_expiry($config.sliding_token_expiry)
+            .set_audit(auth_audit.clone())
+            .set_revocation_store(db);
+        if let Some(refresh_token_duration) = $config.refresh_token_duration {
+            login_tokens = login_tokens.set_refresh_duration(refresh_token_duration);
+        }
+        if !$config.token_signing_key.is_empty() {
+            login_tokens = login_tokens.set_signing_key(
+                $config.token_signing_key,
+                $config.node_name.clone(),
+                node.get_sibling_domains(),
+            );
+        }
+        let login_tokens = manglext::immut_leak(login_tokens);
+        login_tokens
+            .restore_revocations()
+            .await
+            .context("restoring token revocations")?;
+        $crate::ws_api::sync_email_changes(node, login_tokens);
+        $crate::ws_api::sync_token_revocations(node, login_tokens);
+        $crate::ws_api::sync_username_changes(node, login_tokens);
+        $crate::moderation::sync_user_bans(node, login_tokens);
+        let moderation = manglext::immut_leak($crate::moderation::Moderation::new(
+            db,
+            node,
+            leaderboard,
+            login_tokens,
+            $config.username_screening_rate,
         ));
+        let search_cursors =
+            manglext::immut_leak($crate::search::CursorKey::new(&$config.search_cursor_key));
+        let slow_handler_threshold = $config.slow_handler_threshold;
+        let ws_api_handler = || {
+            $crate::ws_api::WsApiHandler::new(
+                leaderboard,
+                db,
+                &goidc.0,
+                device_goidc.map(|x| &x.0),
+                oidc_providers,
+                login_tokens,
+                purchases,
+                announcements,
+                attestation,
+                stats,
+                node,
+                $crate::room_chat::RoomChat::new(node),
+                search_cursors,
+                slow_handler_threshold,
+            )
+        };
+        let mut ws_api =
+            mangle_api_core::neo_api::NeoApiConfig::new(WS_PING_DELAY, ws_api_handler())
+                .set_formats($config.ws_formats)?
+                .set_bandwidth_limits($config.bandwidth_limits)
+                .set_shutdown(&$config.shutdown)
+                .layer(
+                    mangle_api_core::neo_api::layer::RateLimitLayer::new(
+                        $config.ws_message_limit,
+                        $config.ws_message_period,
+                    )
+                    .context("creating websocket rate limit")?
+                    .set_notify(true),
+                )
+                .set_bandwidth_user(
+                    |session: &$crate::ws_api::SessionState| {
+                        session.get_email().map(ToString::to_string)
+                    },
+                    $crate::ws_api::SessionState::set_bandwidth_user,
+                );
+        if $config.ws_mirror_percentage > 0.0 {
+            // Rooms of the shadow are kept apart from those of real clients
+            let shadow_multiplayer = manglext::immut_leak(
+                $crate::multiplayer::Multiplayer::default()
+                    .set_reconnect_grace($config.multiplayer_reconnect_grace),
+            );
+            tokio::spawn(shadow_multiplayer.reap_expired($crate::multiplayer::REAP_INTERVAL));
+            ws_api = ws_api
+                .set_mirror(
+                    ws_api_handler(),
+                    $config.ws_mirror_percentage,
+                    move |session: &$crate::ws_api::SessionState| {
+                        session.shadow(shadow_multiplayer)
+                    },
+                )
+                .context("mirroring websocket sessions")?;
+        }
+        if let Some(data_channels) = $config.data_channels {
+            ws_api = ws_api.set_data_channels(
+                mangle_api_core::data_channel::DataChannels::new(data_channels),
+                $crate::ws_api::SessionState::set_data_channel_handoff,
+            );
+        }
+        let ws_api = manglext::immut_leak(ws_api);
+        tokio::spawn($crate::ws_api::sweep_stale_connections(ws_api));
+        let session_metrics = $config.session_metrics.map(|session_metrics| {
+            $crate::session_metrics::SessionMetricsStore::new(
+                db,
+                session_metrics,
+                $config.node_name.clone(),
+                ws_api.get_metrics(),
+            )
+        });
+        let multiplayer = manglext::immut_leak(
+            $crate::multiplayer::Multiplayer::default()
+                .set_reconnect_grace($config.multiplayer_reconnect_grace),
+        );
+        tokio::spawn(multiplayer.reap_expired($crate::multiplayer::REAP_INTERVAL));
 
         $crate::state::GlobalState {
             goidc,
             auth_pages,
+            admin_emails,
             oidc_state,
             login_tokens,
+            auth_audit,
             leaderboard,
             db,
             // api_conn_manager: APIConnectionManager::new(WS_PING_DELAY),
-            tournament: manglext::immut_leak($crate::tournament::Tournament::new(
-                $config.start_week_time,
-            )),
-            multiplayer: manglext::immut_leak($crate::multiplayer::Multiplayer::default()),
+            tournament,
+            multiplayer,
             ws_api,
+            purchases,
+            announcements,
+            attestation,
+            stats,
+            moderation,
+            readiness: manglext::immut_leak(mangle_api_core::readiness::Readiness::default()),
+            node,
+            clock_skew,
+            locks,
+            ids,
+            search_limiter: manglext::immut_leak($crate::search::SearchLimiter::new(
+                $config.username_search_rate,
+            )),
+            search_cursors,
+            session_metrics,
         }
     }};
 }
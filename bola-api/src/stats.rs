@@ -0,0 +1,300 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Error};
+use aws_sdk_dynamodb::model::{AttributeValue, ReturnConsumedCapacity};
+use axum::{extract::State, http::StatusCode, Json};
+use log::error;
+use mangle_api_core::{parking_lot::Mutex, serde_json};
+use serde::Serialize;
+use tdigest::TDigest;
+use tokio::{spawn, time::sleep};
+
+use crate::{budget::OperationClass, db::DB, difficulty::Difficulty, state::GlobalState};
+
+const DIGEST_SIZE: usize = 100;
+/// Scores are buffered and merged into the digest in batches, as merging is costly
+const MAX_PENDING_SCORES: usize = 256;
+/// How many days of submission counts are kept
+const SUBMISSION_DAYS: u64 = 90;
+const SNAPSHOT_DELAY: Duration = Duration::from_secs(300);
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() / (60 * 60 * 24))
+        .unwrap_or_default()
+}
+
+struct DifficultyStats {
+    digest: TDigest,
+    pending: Vec<f64>,
+    /// The number of submissions on each day, counted in days since the unix epoch
+    daily_submissions: BTreeMap<u64, u64>,
+}
+
+impl Default for DifficultyStats {
+    fn default() -> Self {
+        Self {
+            digest: TDigest::new_with_size(DIGEST_SIZE),
+            pending: vec![],
+            daily_submissions: BTreeMap::new(),
+        }
+    }
+}
+
+impl DifficultyStats {
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.digest = self
+                .digest
+                .merge_unsorted(std::mem::take(&mut self.pending));
+        }
+    }
+
+    fn record(&mut self, score: u16) {
+        self.pending.push(score as f64);
+        if self.pending.len() >= MAX_PENDING_SCORES {
+            self.flush();
+        }
+
+        let today = today();
+        *self.daily_submissions.entry(today).or_default() += 1;
+        self.daily_submissions = self
+            .daily_submissions
+            .split_off(&today.saturating_sub(SUBMISSION_DAYS));
+    }
+}
+
+#[derive(Serialize)]
+pub struct DifficultyStatsView {
+    pub submissions: u64,
+    pub mean: f64,
+    pub p25: f64,
+    pub median: f64,
+    pub p75: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub daily_submissions: BTreeMap<u64, u64>,
+}
+
+impl DifficultyStatsView {
+    fn new(digest: &TDigest, daily_submissions: BTreeMap<u64, u64>) -> Self {
+        Self {
+            submissions: digest.count() as u64,
+            mean: digest.mean(),
+            p25: digest.estimate_quantile(0.25),
+            median: digest.estimate_quantile(0.5),
+            p75: digest.estimate_quantile(0.75),
+            p90: digest.estimate_quantile(0.9),
+            p99: digest.estimate_quantile(0.99),
+            daily_submissions,
+        }
+    }
+}
+
+/// Streaming score distributions for each difficulty
+///
+/// Each node keeps the distribution of the scores submitted to it, and
+/// periodically persists a snapshot of it. Views merge the snapshots of
+/// every node, replacing the snapshot of this node with its live data
+pub struct Stats {
+    difficulties: HashMap<Difficulty, Mutex<DifficultyStats>>,
+    node_name: String,
+    db: &'static DB,
+}
+
+impl Stats {
+    pub async fn new(db: &'static DB, node_name: String) -> Result<&'static Self, Error> {
+        let mut difficulties: HashMap<_, _> = Difficulty::ALL
+            .into_iter()
+            .map(|difficulty| (difficulty, Mutex::new(DifficultyStats::default())))
+            .collect();
+
+        for (node, difficulty, stats) in Self::pull_snapshots(db)
+            .await
+            .context("Pulling stats snapshots")?
+        {
+            if node != node_name {
+                continue;
+            }
+            if let Some(current) = difficulties.get_mut(&difficulty) {
+                *current.get_mut() = stats;
+            }
+        }
+
+        let stats = manglext::immut_leak(Self {
+            difficulties,
+            node_name,
+            db,
+        });
+
+        spawn(async move {
+            loop {
+                sleep(SNAPSHOT_DELAY).await;
+                if let Err(e) = stats.save_snapshots().await {
+                    error!(target: "stats", "{:?}", e.context("saving stats snapshots"));
+                }
+            }
+        });
+
+        Ok(stats)
+    }
+
+    /// Records a submitted score
+    pub fn record(&self, difficulty: Difficulty, score: u16) {
+        if let Some(stats) = self.difficulties.get(&difficulty) {
+            stats.lock().record(score);
+        }
+    }
+
+    async fn pull_snapshots(db: &DB) -> Result<Vec<(String, Difficulty, DifficultyStats)>, Error> {
+        let mut out = vec![];
+        let mut start_key = None;
+
+        loop {
+            let output = db
+                .client
+                .scan()
+                .table_name(db.bola_stats_table.clone())
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+
+            for item in output.items().unwrap_or_default() {
+                out.push(Self::map_to_snapshot(item)?);
+            }
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break Ok(out);
+            }
+        }
+    }
+
+    fn map_to_snapshot(
+        map: &HashMap<String, AttributeValue>,
+    ) -> Result<(String, Difficulty, DifficultyStats), Error> {
+        macro_rules! field {
+            ($field:literal, $op:ident) => {
+                map.get($field).and_then(|x| x.$op().ok()).ok_or_else(|| {
+                    anyhow!("Could not deserialize field: {} in stats snapshot", $field)
+                })?
+            };
+        }
+
+        let difficulty: Difficulty = field!("difficulty", as_s)
+            .parse()
+            .context("Parsing stats snapshot")?;
+
+        let mut daily_submissions = BTreeMap::new();
+        for (day, count) in field!("daily_submissions", as_m) {
+            daily_submissions.insert(
+                day.parse()?,
+                count
+                    .as_n()
+                    .map_err(|_| anyhow!("Submissions for {day} is not a number"))?
+                    .parse()?,
+            );
+        }
+
+        Ok((
+            field!("node", as_s).clone(),
+            difficulty,
+            DifficultyStats {
+                digest: serde_json::from_str(field!("digest", as_s))?,
+                pending: vec![],
+                daily_submissions,
+            },
+        ))
+    }
+
+    async fn save_snapshots(&self) -> Result<(), Error> {
+        for (difficulty, stats) in &self.difficulties {
+            let (digest, daily_submissions) = {
+                let mut stats = stats.lock();
+                stats.flush();
+                (
+                    serde_json::to_string(&stats.digest)?,
+                    stats
+                        .daily_submissions
+                        .iter()
+                        .map(|(day, count)| (day.to_string(), AttributeValue::N(count.to_string())))
+                        .collect::<HashMap<_, _>>(),
+                )
+            };
+
+            let permit = self
+                .db
+                .budgets
+                .acquire(&self.db.bola_stats_table, OperationClass::Write)
+                .await?;
+            let output = self
+                .db
+                .client
+                .put_item()
+                .table_name(self.db.bola_stats_table.clone())
+                .item(
+                    "id",
+                    AttributeValue::S(format!("{}/{difficulty}", self.node_name)),
+                )
+                .item("node", AttributeValue::S(self.node_name.clone()))
+                .item("difficulty", AttributeValue::S(difficulty.to_string()))
+                .item("digest", AttributeValue::S(digest))
+                .item("daily_submissions", AttributeValue::M(daily_submissions))
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await?;
+            permit.consume(output.consumed_capacity());
+        }
+
+        Ok(())
+    }
+
+    /// Gets the distributions of every difficulty across all nodes
+    pub async fn get_views(&self) -> Result<HashMap<Difficulty, DifficultyStatsView>, Error> {
+        let mut merged: HashMap<Difficulty, (Vec<TDigest>, BTreeMap<u64, u64>)> = HashMap::new();
+
+        for (node, difficulty, stats) in Self::pull_snapshots(self.db).await? {
+            if node == self.node_name {
+                continue;
+            }
+            let (digests, daily_submissions) = merged.entry(difficulty).or_default();
+            digests.push(stats.digest);
+            for (day, count) in stats.daily_submissions {
+                *daily_submissions.entry(day).or_default() += count;
+            }
+        }
+
+        for (difficulty, stats) in &self.difficulties {
+            let mut stats = stats.lock();
+            stats.flush();
+            let (digests, daily_submissions) = merged.entry(*difficulty).or_default();
+            digests.push(stats.digest.clone());
+            for (day, count) in &stats.daily_submissions {
+                *daily_submissions.entry(*day).or_default() += count;
+            }
+        }
+
+        Ok(merged
+            .into_iter()
+            .map(|(difficulty, (digests, daily_submissions))| {
+                (
+                    difficulty,
+                    DifficultyStatsView::new(&TDigest::merge_digests(digests), daily_submissions),
+                )
+            })
+            .collect())
+    }
+}
+
+pub async fn get_stats(
+    State(state): State<GlobalState>,
+) -> Result<Json<HashMap<Difficulty, DifficultyStatsView>>, (StatusCode, &'static str)> {
+    state.stats.get_views().await.map(Json).map_err(|e| {
+        error!(target: "stats", "{:?}", e.context("getting stats"));
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Error")
+    })
+}
@@ -8,6 +8,8 @@ const GET_TOURNAMENT_ERR_DURATION: Duration = Duration::from_secs(1);
 pub struct Tournament {
     start_time: SystemTime,
     start_time_duration: Duration,
+    week_seed: fn(u64) -> u32,
+    blind_period: Option<Duration>,
 }
 
 #[derive(Serialize)]
@@ -23,9 +25,24 @@ impl Tournament {
         Self {
             start_time: UNIX_EPOCH + start_time,
             start_time_duration: start_time,
+            week_seed: |week| StdRng::seed_from_u64(week).next_u32(),
+            blind_period: None,
         }
     }
 
+    /// Hides the scores submitted during the given period before the end of each week,
+    /// until the week is over
+    pub fn set_blind_period(mut self, blind_period: Option<Duration>) -> Self {
+        self.blind_period = blind_period;
+        self
+    }
+
+    /// Replaces how the seed of each week is derived from the week number
+    pub fn set_week_seed(mut self, week_seed: fn(u64) -> u32) -> Self {
+        self.week_seed = week_seed;
+        self
+    }
+
     pub fn get_tournament_week(&self) -> Option<TournamentData> {
         let now = Instant::now();
 
@@ -47,7 +64,64 @@ impl Tournament {
             week,
             start_time: week * 3600 * 24 * 7 + start_time,
             end_time: (week + 1) * 3600 * 24 * 7 + start_time,
-            seed: StdRng::seed_from_u64(week).next_u32(),
+            seed: (self.week_seed)(week),
         })
     }
+
+    /// How long until the current week ends
+    pub fn time_until_week_end(&self) -> Option<Duration> {
+        let data = self.get_tournament_week()?;
+        (UNIX_EPOCH + Duration::from_secs(data.end_time))
+            .duration_since(SystemTime::now())
+            .ok()
+    }
+
+    /// Whether scores submitted now should stay hidden until the week ends
+    pub fn is_blind(&self) -> bool {
+        self.blind_until().is_some()
+    }
+
+    /// When scores submitted now are revealed, in seconds since the unix
+    /// epoch, if they should be hidden at all
+    pub fn blind_until(&self) -> Option<u64> {
+        let blind_period = self.blind_period?;
+        let data = self.get_tournament_week()?;
+        let remaining = (UNIX_EPOCH + Duration::from_secs(data.end_time))
+            .duration_since(SystemTime::now())
+            .ok()?;
+        (remaining <= blind_period).then_some(data.end_time)
+    }
+
+    pub fn has_blind_period(&self) -> bool {
+        self.blind_period.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mangle_api_core::rng::SharedRng;
+
+    use super::*;
+
+    fn seeded_week(week: u64) -> u32 {
+        SharedRng::seeded(week).with(|rng| rng.next_u32())
+    }
+
+    #[test]
+    fn week_seeds_are_reproducible() {
+        let first = Tournament::new(Duration::ZERO).set_week_seed(seeded_week);
+        let second = Tournament::new(Duration::ZERO).set_week_seed(seeded_week);
+        let week = first.get_tournament_week().unwrap();
+        assert_eq!(week.seed, second.get_tournament_week().unwrap().seed);
+        assert_eq!(week.seed, seeded_week(week.week));
+        assert_ne!(seeded_week(week.week), seeded_week(week.week + 1));
+    }
+
+    #[test]
+    fn default_week_seeds_match_a_seeded_rng() {
+        let week = Tournament::new(Duration::ZERO)
+            .get_tournament_week()
+            .unwrap();
+        assert_eq!(week.seed, seeded_week(week.week));
+    }
 }
@@ -1,28 +1,64 @@
-use std::time::Instant;
+use std::{
+    net::IpAddr,
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
 
 use axum::{
     async_trait,
-    extract::{FromRequest, FromRequestParts},
-    http::{Request, StatusCode},
+    extract::{FromRequest, FromRequestParts, Query, State},
+    http::{header::ACCEPT_LANGUAGE, HeaderValue, Request, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
-use log::{error};
+use log::{error, warn};
 use mangle_api_core::{
     self,
     auth::{
-        openid::{OIDCState, OIDC},
-        token::{TokenVerificationError, VerifiedToken},
+        audit::ClientIp,
+        oidc_providers::OIDCProviderRegistry,
+        openid::{OIDCState, MAX_AUTH_WAIT_TIME, MAX_DEVICE_AUTH_WAIT_TIME, OIDC},
+        token::{TokenPair, TokenRejection, TokenVerificationError, VerifiedToken},
+    },
+    data_channel::{DataChannelHandoff, NegotiateError},
+    distributed::Node,
+    neo_api::{
+        bandwidth::{BandwidthUser, TopTalkers},
+        latency::{LatencyStats, MessageLatencies},
+        layer::RateLimited,
+        NeoApiConfig,
+    },
+    opentelemetry::{
+        trace::{FutureExt, SpanKind, TraceContextExt, Tracer},
+        Context, KeyValue,
     },
-    neo_api::NeoApiConfig,
+    pagination::{Cursor, Pagination},
+    rejection::Rejection,
+    telemetry,
+    webrtc::{ICECandidate, SDPAnswer, SDPOffer},
 };
-use messagist::{AliasableMessageHandler, MessageStream};
+use messagist::{
+    protocol::{Protocol, ProtocolError, ProtocolState, Raced, TimeoutAction},
+    AliasableMessageHandler, MessageStream,
+};
+use ring::{hmac, rand::SystemRandom};
 use rustrict::CensorStr;
-use serde::Deserialize;
-use tokio::select;
+use serde::{Deserialize, Serialize};
+use tokio::{select, spawn};
 
 use crate::{
-    db::{UserProfile, DB},
-    leaderboard::{Leaderboard, LeaderboardEntry},
+    announcements::{now, AnnouncementView, Announcements},
+    attestation::{Attestation, AttestationPolicy, AttestationVerdict},
+    connections::{ConnectionGuard, Connections, SWEEP_INTERVAL},
+    db::{ChangeEmailResult, GrantResult, UserProfile, DB},
+    difficulty::Difficulty,
+    leaderboard::{LagPolicy, Leaderboard, LeaderboardEntry, LeaderboardSubscription},
+    multiplayer::{Multiplayer, MultiplayerError, MultiplayerEvent, MultiplayerState, RoomCode},
+    network::{EmailChange, NetworkMessage, SiblingNetworkHandler, TokenRevocation},
+    purchases::{Purchases, RedeemError, Store},
+    room_chat::{RoomChat, RoomChatError, RoomChatView, RoomMembership, ROOM_JOIN_INTERVAL},
+    search::{search_usernames, CursorKey, SearchError, SESSION_SEARCH_INTERVAL},
+    stats::Stats,
     state::GlobalState,
     LoginTokenConfig, LoginTokenData, LoginTokenGranter,
 };
@@ -70,9 +106,99 @@ use crate::{
 //     }
 // }
 
+/// Updates buffered for a session before it is resynced with the full standings
+const SESSION_LEADERBOARD_BUFFER_SIZE: usize = 8;
+/// How long a session must wait between starting logins, so that a client
+/// cannot fill the pending auths by cancelling and restarting its login
+const SESSION_AUTH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Avatars are links to images that clients download, so they are kept short
+const MAX_AVATAR_URL_LEN: usize = 512;
+
+/// Avatars must be served over HTTPS, so that clients do not load them in
+/// the clear
+fn is_valid_avatar_url(avatar_url: &str) -> bool {
+    avatar_url.len() <= MAX_AVATAR_URL_LEN
+        && avatar_url.starts_with("https://")
+        && !avatar_url
+            .chars()
+            .any(|x| x.is_whitespace() || x.is_control())
+}
+
+/// Countries are ISO 3166-1 alpha-2 codes, such as US
+fn is_valid_country(country: &str) -> bool {
+    country.len() == 2 && country.bytes().all(|x| x.is_ascii_uppercase())
+}
+
+/// When the login token of the session lapses, so that the client can warn
+/// the user before being logged out
+#[derive(Serialize)]
+struct TokenExpiry {
+    /// In seconds since the unix epoch
+    expires_at: u64,
+}
+
+/// Sent instead of an auth URL while the session must wait to start another login
+#[derive(Serialize)]
+struct LoginThrottled {
+    retry_after_ms: u64,
+}
+
+/// What the client is told about its multiplayer session
+#[derive(Serialize)]
+enum MultiplayerView {
+    Hosted {
+        code: u16,
+    },
+    /// The client must send one offer for each member
+    Joining {
+        member_count: usize,
+    },
+    /// Must be answered with `SDPAnswer`
+    SDPOffer {
+        sdp_offer: String,
+    },
+    JoinerICE {
+        ice: String,
+    },
+    /// Must be followed by `JoinSessionICE` with the same index
+    SDPAnswer {
+        index: usize,
+        sdp_answer: String,
+        ice: String,
+    },
+    /// Must be answered with `AnswerJoinRequest`
+    JoinRequest {
+        rating: u32,
+    },
+    /// The client must send one offer for each member
+    BackfillFound {
+        code: u16,
+        member_count: usize,
+    },
+}
+
 pub struct SessionState {
     login_token: Option<VerifiedToken<LoginTokenConfig>>,
+    /// Released when the session ends, however it ends
+    connection: Option<ConnectionGuard>,
     last_leaderboard_retrieval: Option<Instant>,
+    /// The preferred locale of the client, taken from Accept-Language
+    locale: Option<String>,
+    /// Caps how many logins the client can have pending
+    ip: Option<IpAddr>,
+    attestation: AttestationVerdict,
+    data_channel_handoff: Option<DataChannelHandoff>,
+    /// Kept in step with the login token, so that bandwidth counts towards
+    /// whoever the session is logged in as
+    bandwidth_user: Option<BandwidthUser>,
+    last_search: Option<Instant>,
+    /// When the session last started a login
+    last_auth: Option<Instant>,
+    last_room_join: Option<Instant>,
+    room_chat: Option<RoomMembership>,
+    leaderboard_updates: Option<LeaderboardSubscription>,
+    multiplayer: MultiplayerState,
 }
 
 #[async_trait]
@@ -84,41 +210,167 @@ where
 
     async fn from_request(req: Request<B>, state: &GlobalState) -> Result<Self, Self::Rejection> {
         let (mut parts, _) = req.into_parts();
-        let login_token =
+        let locale = parts
+            .headers
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.split([',', ';']).next())
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty() && x != "*");
+        let ip = ClientIp::from_request_parts(&mut parts, state)
+            .await
+            .ok()
+            .and_then(|ClientIp(ip)| ip);
+        let attestation = state.attestation.verify_request(&parts).await;
+        let (login_token, connection) =
             match VerifiedToken::<LoginTokenConfig>::from_request_parts(&mut parts, state).await {
                 Ok(x) => {
-                    let api = AsRef::<NeoApiConfig<WsApiHandler>>::as_ref(state).get_handler();
-
-                    if api.connections.contains(&x.identifier.email) {
-                        return Err((StatusCode::CONFLICT, "Already Connected").into_response());
-                    }
-                    api.connections.insert(x.identifier.email.clone());
-                    Some(x)
+                    let api = AsRef::<WsApi>::as_ref(state).get_handler().get_inner();
+
+                    // The bandwidth registry knows this session by the same email
+                    let Some(connection) = api.connections.claim(x.identifier.email.clone(), true)
+                    else {
+                        return Err(Rejection::new(
+                            StatusCode::CONFLICT,
+                            "already_connected",
+                            "Already Connected",
+                        )
+                        .negotiate(&parts.headers)
+                        .into_response());
+                    };
+                    (Some(x), Some(connection))
                 }
-                Err(TokenVerificationError::MissingToken) => None,
+                Err(TokenRejection {
+                    error: TokenVerificationError::MissingToken,
+                    ..
+                }) => (None, None),
                 Err(e) => return Err(e.into_response()),
             };
 
         Ok(Self {
             login_token,
+            connection,
             last_leaderboard_retrieval: None,
+            locale,
+            ip,
+            attestation,
+            data_channel_handoff: None,
+            bandwidth_user: None,
+            last_search: None,
+            last_auth: None,
+            last_room_join: None,
+            room_chat: None,
+            leaderboard_updates: None,
+            multiplayer: MultiplayerState::new(state.multiplayer),
         })
     }
 }
 
+impl SessionState {
+    /// The email of the user this session connected as, if any
+    pub fn get_email(&self) -> Option<&str> {
+        self.login_token
+            .as_ref()
+            .map(|x| x.identifier.email.as_str())
+    }
+
+    pub fn set_data_channel_handoff(&mut self, handoff: DataChannelHandoff) {
+        self.data_channel_handoff = Some(handoff);
+    }
+
+    pub fn set_bandwidth_user(&mut self, bandwidth_user: BandwidthUser) {
+        self.bandwidth_user = Some(bandwidth_user);
+    }
+
+    /// The state of a mirrored copy of this session, logged in as the same user
+    ///
+    /// The copy does not hold the connection of the user, and its rooms are
+    /// hosted in the given multiplayer so that they never meet real clients
+    pub fn shadow(&self, multiplayer: &'static Multiplayer) -> Self {
+        Self {
+            login_token: self.login_token.as_ref().map(|x| VerifiedToken {
+                token: x.token.clone(),
+                identifier: x.identifier.clone(),
+            }),
+            connection: None,
+            last_leaderboard_retrieval: None,
+            locale: self.locale.clone(),
+            ip: self.ip,
+            attestation: self.attestation,
+            data_channel_handoff: None,
+            bandwidth_user: None,
+            last_search: None,
+            last_auth: None,
+            last_room_join: None,
+            room_chat: None,
+            leaderboard_updates: None,
+            multiplayer: MultiplayerState::new(multiplayer),
+        }
+    }
+
+    /// Counts the bandwidth of the session towards the user it is now logged in as
+    fn sync_bandwidth_user(&self) {
+        if let Some(bandwidth_user) = &self.bandwidth_user {
+            bandwidth_user.set(self.get_email().map(ToString::to_string));
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TopTalkersQuery {
+    #[serde(default = "default_top_talkers")]
+    count: usize,
+}
+
+fn default_top_talkers() -> usize {
+    10
+}
+
+const MAX_TOP_TALKERS: usize = 100;
+
+/// Lists the connections and users that have used the most bandwidth
+pub async fn top_talkers(
+    State(state): State<GlobalState>,
+    Query(query): Query<TopTalkersQuery>,
+) -> Json<TopTalkers> {
+    Json(
+        state
+            .ws_api
+            .get_bandwidth()
+            .top_talkers(query.count.min(MAX_TOP_TALKERS)),
+    )
+}
+
+/// How long each type of WebSocket message has taken to handle on this node
+pub async fn message_latencies(
+    State(state): State<GlobalState>,
+) -> Json<Vec<(&'static str, LatencyStats)>> {
+    Json(
+        state
+            .ws_api
+            .get_handler()
+            .get_inner()
+            .get_message_latencies(),
+    )
+}
+
 fn default_lobby_size() -> usize {
     4
 }
 
 #[derive(Deserialize)]
-enum WSAPIMessage {
+pub(crate) enum WSAPIMessage {
     ScoreUpdateRequest {
-        difficulty: String,
+        difficulty: Difficulty,
         score: u16,
     },
     Logout,
     GetLeaderboard,
     Login,
+    /// Logs in by entering a code on another device, for consoles and TVs
+    DeviceLogin,
+    /// Logs in through the provider with the given name, such as apple
+    LoginWith(String),
     GetTournament,
     WinTournament,
     HostSession {
@@ -138,14 +390,123 @@ enum WSAPIMessage {
         sdp_answer: String,
         ice_candidate: String,
     },
+    RedeemPurchase {
+        store: Store,
+        product_id: String,
+        receipt: String,
+    },
+    ChangeEmail,
+    /// Moves the connection onto a data channel, replying with the SDP answer
+    OpenDataChannel {
+        sdp_offer: String,
+    },
+    /// Finds players by the start of their username, which guests may do as well
+    SearchUsernames {
+        prefix: String,
+        #[serde(default = "Default::default")]
+        cursor: Option<Cursor>,
+    },
+    /// Joins the chat of a multiplayer room, opening it as host if nobody is
+    /// in it. Rooms that are open can only be joined once the host invites the user
+    JoinRoomChat(
+        // room code
+        u16,
+    ),
+    SendRoomChat(String),
+    MuteRoomMember(String),
+    KickRoomMember(String),
+    LeaveRoomChat,
+    /// Replies with when the login token expires
+    GetTokenExpiry,
+    /// Logs in with a refresh token, replying with a new login token and
+    /// refresh token
+    RefreshToken(String),
+    /// Lets the user with the given username join the room chat of the host
+    InviteRoomMember(String),
+    /// Lets late joiners with a rating in the band ask to join the hosted session
+    OpenBackfill {
+        min_rating: u32,
+        max_rating: u32,
+    },
+    CloseBackfill,
+    /// Accepts or declines the join request last sent to the host
+    AnswerJoinRequest(bool),
+    /// Looks for a session open for backfill, rated by the highscore of the
+    /// user in the difficulty
+    FindBackfill(Difficulty),
+    /// Leaves the multiplayer session, or stops joining one
+    LeaveSession,
+    /// Sets the avatar and country shown next to the scores of the user,
+    /// removing those that are not given
+    SetProfileDetails {
+        #[serde(default = "Default::default")]
+        avatar_url: Option<String>,
+        #[serde(default = "Default::default")]
+        country: Option<String>,
+    },
+}
+
+impl WSAPIMessage {
+    fn name(&self) -> &'static str {
+        match self {
+            WSAPIMessage::ScoreUpdateRequest { .. } => "ScoreUpdateRequest",
+            WSAPIMessage::Logout => "Logout",
+            WSAPIMessage::GetLeaderboard => "GetLeaderboard",
+            WSAPIMessage::Login => "Login",
+            WSAPIMessage::DeviceLogin => "DeviceLogin",
+            WSAPIMessage::LoginWith(_) => "LoginWith",
+            WSAPIMessage::GetTournament => "GetTournament",
+            WSAPIMessage::WinTournament => "WinTournament",
+            WSAPIMessage::HostSession { .. } => "HostSession",
+            WSAPIMessage::StartJoinSession(_) => "StartJoinSession",
+            WSAPIMessage::JoinSessionSDPOffers(_) => "JoinSessionSDPOffers",
+            WSAPIMessage::JoinSessionICE { .. } => "JoinSessionICE",
+            WSAPIMessage::SDPAnswer { .. } => "SDPAnswer",
+            WSAPIMessage::RedeemPurchase { .. } => "RedeemPurchase",
+            WSAPIMessage::ChangeEmail => "ChangeEmail",
+            WSAPIMessage::OpenDataChannel { .. } => "OpenDataChannel",
+            WSAPIMessage::SearchUsernames { .. } => "SearchUsernames",
+            WSAPIMessage::JoinRoomChat(_) => "JoinRoomChat",
+            WSAPIMessage::SendRoomChat(_) => "SendRoomChat",
+            WSAPIMessage::MuteRoomMember(_) => "MuteRoomMember",
+            WSAPIMessage::KickRoomMember(_) => "KickRoomMember",
+            WSAPIMessage::LeaveRoomChat => "LeaveRoomChat",
+            WSAPIMessage::GetTokenExpiry => "GetTokenExpiry",
+            WSAPIMessage::RefreshToken(_) => "RefreshToken",
+            WSAPIMessage::InviteRoomMember(_) => "InviteRoomMember",
+            WSAPIMessage::OpenBackfill { .. } => "OpenBackfill",
+            WSAPIMessage::CloseBackfill => "CloseBackfill",
+            WSAPIMessage::AnswerJoinRequest(_) => "AnswerJoinRequest",
+            WSAPIMessage::FindBackfill(_) => "FindBackfill",
+            WSAPIMessage::LeaveSession => "LeaveSession",
+            WSAPIMessage::SetProfileDetails { .. } => "SetProfileDetails",
+        }
+    }
 }
 
+/// The WebSocket API, which reads at most `ws_message_limit` messages from
+/// each session every `ws_message_period`
+pub type WsApi = NeoApiConfig<RateLimited<WsApiHandler>>;
+
 pub struct WsApiHandler {
-    connections: dashmap::DashSet<String>,
+    connections: &'static Connections,
     leaderboard: &'static Leaderboard,
     db: &'static DB,
     oidc: &'static OIDC<&'static OIDCState>,
+    /// Only set if device logins are allowed
+    device_oidc: Option<&'static OIDC<&'static OIDCState>>,
+    oidc_providers: &'static OIDCProviderRegistry<&'static OIDCState>,
     login_tokens: &'static LoginTokenGranter,
+    purchases: &'static Purchases,
+    announcements: &'static Announcements,
+    attestation: &'static Attestation,
+    stats: &'static Stats,
+    node: &'static Node<SiblingNetworkHandler>,
+    room_chat: &'static RoomChat,
+    search_cursors: &'static CursorKey,
+    latencies: MessageLatencies,
+    /// Hashes the emails of users into the opaque IDs that traces know them by
+    trace_user_key: hmac::Key,
 }
 
 #[async_trait]
@@ -453,125 +814,859 @@ impl AliasableMessageHandler for WsApiHandler {
                 }
             };
         }
-        loop {
-            let Ok(msg) = stream.recv_message::<WSAPIMessage>().await else { break };
-
-            if let Some(login_token) = &session_state.login_token {
-                let leaderboard = &self.leaderboard;
-
-                match msg {
-                    WSAPIMessage::ScoreUpdateRequest { difficulty, score } => {
-                        let email = &login_token.identifier.email;
-                        let username = &login_token.identifier.username;
-
-                        let res = match difficulty.as_str() {
-                            "easy" => {
-                                leaderboard
-                                    .add_easy_entry(
-                                        email.clone(),
-                                        LeaderboardEntry {
-                                            score,
-                                            username: username.clone(),
-                                        },
-                                    )
-                                    .await
-                            }
-                            "normal" => {
-                                leaderboard
-                                    .add_normal_entry(
-                                        email.clone(),
-                                        LeaderboardEntry {
-                                            score,
-                                            username: username.clone(),
-                                        },
-                                    )
-                                    .await
-                            }
-                            "expert" => {
-                                leaderboard
-                                    .add_expert_entry(
-                                        email.clone(),
-                                        LeaderboardEntry {
-                                            score,
-                                            username: username.clone(),
-                                        },
-                                    )
-                                    .await
-                            }
-                            _ => {
-                                send!("Not a valid difficulty");
-                                return;
-                            }
-                        };
+        macro_rules! send_announcement {
+            ($announcement:expr) => {
+                if let Some(text) = $announcement.localize(session_state.locale.as_deref()) {
+                    send!(AnnouncementView {
+                        announcement_id: &$announcement.id,
+                        announcement: text,
+                    });
+                }
+            };
+        }
 
-                        if let Err(_e) = res {
-                            send!("Internal Error");
-                        }
+        for announcement in self
+            .announcements
+            .get_active(session_state.login_token.is_some())
+        {
+            send_announcement!(announcement);
+        }
 
-                        send!("Success");
-                    }
-                    WSAPIMessage::Login => {
-                        send!("Already logged in");
+        let mut announcement_subscription = self.announcements.subscribe();
+
+        loop {
+            let msg = select! {
+                res = stream.recv_message::<WSAPIMessage>() => res,
+                announcement = announcement_subscription.wait_for_announcement() => {
+                    if announcement.is_active(now())
+                        && announcement
+                            .audience
+                            .includes(session_state.login_token.is_some())
+                    {
+                        send_announcement!(announcement);
                     }
-                    _ => todo!(),
+                    continue;
+                }
+                event = RoomMembership::next_event(&mut session_state.room_chat) => {
+                    send!(RoomChatView { room_chat: &event });
+                    continue;
                 }
-            } else {
-                match msg {
-                    WSAPIMessage::GetLeaderboard => {}
-                    WSAPIMessage::GetTournament => {}
-                    WSAPIMessage::Login => {
-                        match self.login(&mut session_state, &mut stream).await {
-                            Ok(StreamStatus::Closed) => break,
-                            Ok(_) => {}
-                            Err(_) => break,
+                update = LeaderboardSubscription::next_update(&mut session_state.leaderboard_updates) => {
+                    send!(&*update);
+                    continue;
+                }
+                event = session_state.multiplayer.next_event() => {
+                    match event {
+                        MultiplayerEvent::Offer(SDPOffer(sdp_offer)) => {
+                            send!(MultiplayerView::SDPOffer { sdp_offer });
+                        }
+                        MultiplayerEvent::JoinerIce(ICECandidate(ice)) => {
+                            send!(MultiplayerView::JoinerICE { ice });
+                        }
+                        MultiplayerEvent::Answer {
+                            index,
+                            sdp_answer: SDPAnswer(sdp_answer),
+                            ice: ICECandidate(ice),
+                        } => send!(MultiplayerView::SDPAnswer {
+                            index,
+                            sdp_answer,
+                            ice
+                        }),
+                        MultiplayerEvent::JoinRequest { rating } => {
+                            send!(MultiplayerView::JoinRequest { rating });
                         }
+                        MultiplayerEvent::BackfillFound { code, member_count } => {
+                            send!(MultiplayerView::BackfillFound {
+                                code: code.into(),
+                                member_count
+                            });
+                        }
+                        MultiplayerEvent::BackfillNotFound => send!("Backfill Not Found"),
+                        MultiplayerEvent::SessionEnded => send!("Session Ended"),
                     }
-
-                    _ => send!("Must be logged in"),
+                    continue;
                 }
+            };
+            let Ok(msg) = msg else { break };
+            // Every await while handling the message is within its span
+            let span_context = self.message_span(&msg, &session_state);
+            let _timer = self.latencies.start(msg.name());
+            if self
+                .handle_message(msg, &mut stream, &mut session_state)
+                .with_context(span_context)
+                .await
+                .is_break()
+            {
+                break;
             }
         }
     }
 }
 
+fn room_chat_error_message(error: RoomChatError) -> &'static str {
+    match error {
+        RoomChatError::Kicked => "Kicked",
+        RoomChatError::NotInvited => "Not Invited",
+        RoomChatError::NotInRoom => "Not In Room",
+        RoomChatError::NotHost => "Not Host",
+        RoomChatError::MemberNotFound => "Member Not Found",
+        RoomChatError::Muted => "Muted",
+        RoomChatError::TooLong => "Message Too Long",
+        RoomChatError::TooManyMessages => "Too Many Messages",
+    }
+}
+
+fn multiplayer_error_message(error: MultiplayerError) -> &'static str {
+    match error {
+        MultiplayerError::AlreadyInSession => "Already In Session",
+        MultiplayerError::NotInSession => "Not In Session",
+        MultiplayerError::BadSize => "Bad Size",
+        MultiplayerError::NotFound => "Not Found",
+        MultiplayerError::Full => "Room Full",
+        MultiplayerError::NotHost => "Not Host",
+        MultiplayerError::NotJoining => "Not Joining",
+        MultiplayerError::WrongOfferCount(_) => "Wrong Offer Count",
+        MultiplayerError::SessionEnded => "Session Ended",
+        MultiplayerError::NoOffer => "No Offer",
+        MultiplayerError::NoJoinRequest => "No Join Request",
+        MultiplayerError::NoAnswer => "No Answer",
+    }
+}
+
 enum StreamStatus {
     Ok,
     Closed,
 }
 
+/// How a session logs in
+enum LoginMethod {
+    /// Through the provider with the given name, or Google if there is none
+    Redirect(Option<String>),
+    /// By entering a code on another device
+    Device,
+}
+
+/// How long a client may take to pick a valid username during sign up
+const CHOOSE_USERNAME_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long database operations during login may take
+const LOGIN_DB_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum LoginState {
+    AwaitingAuth,
+    AwaitingDeviceAuth,
+    FetchingProfile,
+    ChoosingUsername,
+    CreatingProfile,
+}
+
+impl ProtocolState for LoginState {
+    fn timeout(&self) -> Option<Duration> {
+        Some(match self {
+            // The OIDC future gives up on its own after this long anyway
+            LoginState::AwaitingAuth => MAX_AUTH_WAIT_TIME,
+            LoginState::AwaitingDeviceAuth => MAX_DEVICE_AUTH_WAIT_TIME,
+            LoginState::FetchingProfile | LoginState::CreatingProfile => LOGIN_DB_TIMEOUT,
+            LoginState::ChoosingUsername => CHOOSE_USERNAME_TIMEOUT,
+        })
+    }
+
+    fn can_transition_to(&self, next: &Self) -> bool {
+        matches!(
+            (self, next),
+            (
+                LoginState::AwaitingAuth | LoginState::AwaitingDeviceAuth,
+                LoginState::FetchingProfile
+            ) | (LoginState::FetchingProfile, LoginState::ChoosingUsername)
+                | (LoginState::ChoosingUsername, LoginState::CreatingProfile)
+        )
+    }
+
+    fn on_timeout(&self) -> TimeoutAction<Self> {
+        TimeoutAction::Close("Login Timed Out")
+    }
+}
+
+/// How long the email change may take to be written to the database
+const EMAIL_CHANGE_DB_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EmailChangeState {
+    /// The user must prove they still own the email they are logged in with
+    VerifyingCurrent,
+    VerifyingNew,
+    Migrating,
+}
+
+impl ProtocolState for EmailChangeState {
+    fn timeout(&self) -> Option<Duration> {
+        Some(match self {
+            EmailChangeState::VerifyingCurrent | EmailChangeState::VerifyingNew => {
+                MAX_AUTH_WAIT_TIME
+            }
+            EmailChangeState::Migrating => EMAIL_CHANGE_DB_TIMEOUT,
+        })
+    }
+
+    fn can_transition_to(&self, next: &Self) -> bool {
+        matches!(
+            (self, next),
+            (
+                EmailChangeState::VerifyingCurrent,
+                EmailChangeState::VerifyingNew
+            ) | (EmailChangeState::VerifyingNew, EmailChangeState::Migrating)
+        )
+    }
+
+    fn on_timeout(&self) -> TimeoutAction<Self> {
+        TimeoutAction::Close("Email Change Timed Out")
+    }
+}
+
+/// Keeps the tokens issued by this node pointing at the right profile when
+/// users change their email through a sibling
+pub fn sync_email_changes(
+    node: &'static Node<SiblingNetworkHandler>,
+    login_tokens: &'static LoginTokenGranter,
+) {
+    let mut subscription = node.get_handler().subscribe_to_email_change();
+
+    spawn(async move {
+        loop {
+            let Some(change) = subscription.wait_for_change().await else {
+                break
+            };
+            login_tokens
+                .reassign_token(
+                    &LoginTokenData {
+                        username: change.username.clone(),
+                        email: change.old_email,
+                    },
+                    LoginTokenData {
+                        username: change.username,
+                        email: change.new_email,
+                    },
+                )
+                .await;
+        }
+    });
+}
+
+/// Moves the login tokens of users that siblings rename to their new username
+pub fn sync_username_changes(
+    node: &'static Node<SiblingNetworkHandler>,
+    login_tokens: &'static LoginTokenGranter,
+) {
+    let mut subscription = node.get_handler().subscribe_to_username_change();
+
+    spawn(async move {
+        loop {
+            let Some(change) = subscription.wait_for_change().await else {
+                break
+            };
+            login_tokens
+                .reassign_token(
+                    &LoginTokenData {
+                        username: change.old_username,
+                        email: change.email.clone(),
+                    },
+                    LoginTokenData {
+                        username: change.new_username,
+                        email: change.email,
+                    },
+                )
+                .await;
+        }
+    });
+}
+
+/// Stops accepting the signed tokens that siblings revoke
+pub fn sync_token_revocations(
+    node: &'static Node<SiblingNetworkHandler>,
+    login_tokens: &'static LoginTokenGranter,
+) {
+    let mut subscription = node.get_handler().subscribe_to_token_revocation();
+
+    spawn(async move {
+        loop {
+            let Some(revocation) = subscription.wait_for_revocation().await else {
+                break
+            };
+            let Ok(token) = HeaderValue::from_str(&revocation.token) else {
+                continue
+            };
+            login_tokens.revoke_token(&token).await;
+        }
+    });
+}
+
+/// Reclaims the connections of sessions that ended without releasing them
+pub async fn sweep_stale_connections(ws_api: &'static WsApi) {
+    ws_api
+        .get_handler()
+        .get_inner()
+        .connections
+        .sweep_stale(ws_api.get_bandwidth(), SWEEP_INTERVAL)
+        .await
+}
+
 impl WsApiHandler {
+    /// Handles a message of a session, breaking if the session must end
+    async fn handle_message<S: MessageStream>(
+        &self,
+        msg: WSAPIMessage,
+        stream: &mut S,
+        session_state: &mut SessionState,
+    ) -> ControlFlow<()> {
+        macro_rules! send {
+            ($msg:expr) => {
+                if let Err(_) = stream.send_message($msg).await {
+                    return ControlFlow::Break(());
+                }
+            };
+        }
+
+        let msg = match msg {
+            WSAPIMessage::SearchUsernames { prefix, cursor } => {
+                if session_state
+                    .last_search
+                    .map_or(false, |x| x.elapsed() < SESSION_SEARCH_INTERVAL)
+                {
+                    send!("Too Many Searches");
+                    return ControlFlow::Continue(());
+                }
+                session_state.last_search = Some(Instant::now());
+                match search_usernames(
+                    self.db,
+                    self.search_cursors,
+                    &prefix,
+                    &Pagination::new(cursor, None),
+                )
+                .await
+                {
+                    Ok(page) => send!(page),
+                    Err(SearchError::PrefixTooShort) => send!("Prefix Too Short"),
+                    Err(SearchError::InvalidCursor) => send!("Invalid Cursor"),
+                    Err(SearchError::InternalError(e)) => {
+                        error!(target: "search", "{:?}", e.context(format!("searching for {prefix}")));
+                        send!("Internal Error");
+                    }
+                }
+                return ControlFlow::Continue(());
+            }
+            WSAPIMessage::GetLeaderboard => {
+                send!(self.leaderboard.get_leaderboard());
+                if session_state.leaderboard_updates.is_none() {
+                    session_state.leaderboard_updates = Some(
+                        self.leaderboard
+                            .subscribe(SESSION_LEADERBOARD_BUFFER_SIZE, LagPolicy::Resync),
+                    );
+                }
+                return ControlFlow::Continue(());
+            }
+            msg => msg,
+        };
+
+        if let Some(login_token) = &session_state.login_token {
+            let leaderboard = &self.leaderboard;
+
+            match msg {
+                WSAPIMessage::ScoreUpdateRequest { difficulty, score } => {
+                    let email = &login_token.identifier.email;
+                    let username = &login_token.identifier.username;
+
+                    if !session_state.attestation.is_verified() {
+                        match self.attestation.get_policy() {
+                            AttestationPolicy::Off => {}
+                            AttestationPolicy::Log => {
+                                warn!(
+                                    target: "suspicious_security",
+                                    "Accepted {difficulty} score of {score} from {email} with attestation {:?}",
+                                    session_state.attestation
+                                );
+                            }
+                            AttestationPolicy::Enforce => {
+                                warn!(
+                                    target: "suspicious_security",
+                                    "Rejected {difficulty} score of {score} from {email} with attestation {:?}",
+                                    session_state.attestation
+                                );
+                                send!("Unattested Client");
+                                return ControlFlow::Continue(());
+                            }
+                        }
+                    }
+
+                    let res = leaderboard
+                        .add_entry(
+                            difficulty,
+                            email.clone(),
+                            LeaderboardEntry {
+                                score,
+                                username: username.clone(),
+                                ..Default::default()
+                            },
+                        )
+                        .await;
+
+                    if let Err(_e) = res {
+                        send!("Internal Error");
+                        return ControlFlow::Continue(());
+                    }
+                    // Only scores that made it onto the leaderboard are counted
+                    self.stats.record(difficulty, score);
+
+                    send!("Success");
+                }
+                WSAPIMessage::Login
+                | WSAPIMessage::DeviceLogin
+                | WSAPIMessage::LoginWith(_)
+                | WSAPIMessage::RefreshToken(_) => {
+                    send!("Already logged in");
+                }
+                WSAPIMessage::Logout => {
+                    let token = login_token.token.clone();
+                    self.login_tokens.revoke_token(&token).await;
+                    session_state.login_token = None;
+                    session_state.sync_bandwidth_user();
+                    session_state.connection = None;
+                    session_state.room_chat = None;
+                    session_state.multiplayer.leave();
+                    send!("Success");
+                    self.broadcast_revocation(&token).await;
+                }
+                WSAPIMessage::RedeemPurchase {
+                    store,
+                    product_id,
+                    receipt,
+                } => {
+                    match self
+                        .purchases
+                        .redeem(
+                            login_token.identifier.email.clone(),
+                            store,
+                            product_id,
+                            receipt,
+                        )
+                        .await
+                    {
+                        Ok(GrantResult::Granted) => send!("Success"),
+                        Ok(GrantResult::AlreadyGranted) => send!("Already Redeemed"),
+                        Ok(GrantResult::NoProfile) => send!("No Profile"),
+                        Err(RedeemError::InvalidReceipt) => send!("Invalid Receipt"),
+                        Err(RedeemError::InternalError(e)) => {
+                            error!(target: "purchases", "{:?}", e.context("redeeming purchase"));
+                            send!("Internal Error");
+                        }
+                    }
+                }
+                WSAPIMessage::ChangeEmail => match self.change_email(session_state, stream).await {
+                    Ok(StreamStatus::Closed) => return ControlFlow::Break(()),
+                    Ok(_) => {}
+                    Err(_) => return ControlFlow::Break(()),
+                },
+                WSAPIMessage::OpenDataChannel { sdp_offer } => {
+                    let Some(handoff) = &session_state.data_channel_handoff else {
+                        send!("Data Channels Disabled");
+                        return ControlFlow::Continue(());
+                    };
+                    match handoff.negotiate(sdp_offer.into()).await {
+                        Ok(SDPAnswer(sdp_answer)) => send!(sdp_answer),
+                        Err(NegotiateError::TooManyPeerConnections) => {
+                            send!("Too Many Data Channels")
+                        }
+                        Err(NegotiateError::InternalError(e)) => {
+                            error!(target: "data_channel", "{:?}", e.context("negotiating data channel"));
+                            send!("Internal Error");
+                        }
+                    }
+                }
+                WSAPIMessage::JoinRoomChat(code) => {
+                    let Ok(code) = RoomCode::try_from(code) else {
+                        send!("Bad code");
+                        return ControlFlow::Continue(());
+                    };
+                    if session_state.room_chat.is_some() {
+                        send!("Already In Room");
+                        return ControlFlow::Continue(());
+                    }
+                    if session_state
+                        .last_room_join
+                        .map_or(false, |x| x.elapsed() < ROOM_JOIN_INTERVAL)
+                    {
+                        send!("Too Many Joins");
+                        return ControlFlow::Continue(());
+                    }
+                    session_state.last_room_join = Some(Instant::now());
+                    match self
+                        .room_chat
+                        .join(code, login_token.identifier.username.clone())
+                    {
+                        Ok(membership) => {
+                            session_state.room_chat = Some(membership);
+                            send!("Success");
+                        }
+                        Err(e) => send!(room_chat_error_message(e)),
+                    }
+                }
+                WSAPIMessage::SendRoomChat(text) => {
+                    let Some(membership) = &mut session_state.room_chat else {
+                        send!("Not In Room");
+                        return ControlFlow::Continue(());
+                    };
+                    match membership.send(&text) {
+                        Ok(()) => send!("Success"),
+                        Err(e) => send!(room_chat_error_message(e)),
+                    }
+                }
+                WSAPIMessage::InviteRoomMember(username) => {
+                    let Some(membership) = &session_state.room_chat else {
+                        send!("Not In Room");
+                        return ControlFlow::Continue(());
+                    };
+                    match membership.invite(&username) {
+                        Ok(()) => send!("Success"),
+                        Err(e) => send!(room_chat_error_message(e)),
+                    }
+                }
+                WSAPIMessage::MuteRoomMember(username) => {
+                    let Some(membership) = &session_state.room_chat else {
+                        send!("Not In Room");
+                        return ControlFlow::Continue(());
+                    };
+                    match membership.mute(&username) {
+                        Ok(()) => send!("Success"),
+                        Err(e) => send!(room_chat_error_message(e)),
+                    }
+                }
+                WSAPIMessage::KickRoomMember(username) => {
+                    let Some(membership) = &session_state.room_chat else {
+                        send!("Not In Room");
+                        return ControlFlow::Continue(());
+                    };
+                    match membership.kick(&username) {
+                        Ok(()) => send!("Success"),
+                        Err(e) => send!(room_chat_error_message(e)),
+                    }
+                }
+                WSAPIMessage::LeaveRoomChat => {
+                    if session_state.room_chat.take().is_some() {
+                        send!("Success");
+                    } else {
+                        send!("Not In Room");
+                    }
+                }
+                WSAPIMessage::SetProfileDetails {
+                    avatar_url,
+                    country,
+                } => {
+                    if !avatar_url.as_deref().map_or(true, is_valid_avatar_url) {
+                        send!("Bad Avatar");
+                        return ControlFlow::Continue(());
+                    }
+                    if !country.as_deref().map_or(true, is_valid_country) {
+                        send!("Bad Country");
+                        return ControlFlow::Continue(());
+                    }
+                    match self
+                        .db
+                        .set_profile_details(
+                            login_token.identifier.email.clone(),
+                            avatar_url,
+                            country,
+                        )
+                        .await
+                    {
+                        Ok(true) => send!("Success"),
+                        Ok(false) => send!("No Profile"),
+                        Err(e) => {
+                            error!(target: "login", "{:?}", e.context("setting profile details"));
+                            send!("Internal Error");
+                        }
+                    }
+                }
+                WSAPIMessage::GetTokenExpiry => {
+                    match self.login_tokens.get_expiry(&login_token.token) {
+                        Some(expires_at) => send!(TokenExpiry { expires_at }),
+                        None => send!("Token Expired"),
+                    }
+                }
+                WSAPIMessage::HostSession { max_size } => {
+                    match session_state.multiplayer.host(max_size) {
+                        Ok(code) => send!(MultiplayerView::Hosted { code: code.into() }),
+                        Err(e) => send!(multiplayer_error_message(e)),
+                    }
+                }
+                WSAPIMessage::StartJoinSession(code) => {
+                    let Ok(code) = RoomCode::try_from(code) else {
+                        send!("Bad code");
+                        return ControlFlow::Continue(());
+                    };
+                    match session_state.multiplayer.start_join(code) {
+                        Ok(member_count) => send!(MultiplayerView::Joining { member_count }),
+                        Err(e) => send!(multiplayer_error_message(e)),
+                    }
+                }
+                WSAPIMessage::JoinSessionSDPOffers(offers) => {
+                    match session_state.multiplayer.send_offers(offers).await {
+                        Ok(()) => send!("Success"),
+                        Err(MultiplayerError::WrongOfferCount(member_count)) => {
+                            // A member joined or left meanwhile, so the offers must be remade
+                            send!(MultiplayerView::Joining { member_count });
+                        }
+                        Err(e) => send!(multiplayer_error_message(e)),
+                    }
+                }
+                WSAPIMessage::JoinSessionICE { index, ice } => {
+                    match session_state.multiplayer.send_ice(index, ice) {
+                        Ok(()) => send!("Success"),
+                        Err(e) => send!(multiplayer_error_message(e)),
+                    }
+                }
+                WSAPIMessage::SDPAnswer {
+                    sdp_answer,
+                    ice_candidate,
+                } => match session_state
+                    .multiplayer
+                    .send_answer(sdp_answer, ice_candidate)
+                {
+                    Ok(()) => send!("Success"),
+                    Err(e) => send!(multiplayer_error_message(e)),
+                },
+                WSAPIMessage::OpenBackfill {
+                    min_rating,
+                    max_rating,
+                } => match session_state
+                    .multiplayer
+                    .open_backfill(min_rating..=max_rating)
+                {
+                    Ok(()) => send!("Success"),
+                    Err(e) => send!(multiplayer_error_message(e)),
+                },
+                WSAPIMessage::CloseBackfill => match session_state.multiplayer.close_backfill() {
+                    Ok(()) => send!("Success"),
+                    Err(e) => send!(multiplayer_error_message(e)),
+                },
+                WSAPIMessage::AnswerJoinRequest(accept) => {
+                    match session_state.multiplayer.answer_join_request(accept) {
+                        Ok(()) => send!("Success"),
+                        Err(e) => send!(multiplayer_error_message(e)),
+                    }
+                }
+                WSAPIMessage::FindBackfill(difficulty) => {
+                    let rating = match self
+                        .db
+                        .get_user_profile_by_email(&login_token.identifier.email)
+                        .await
+                    {
+                        Ok(Some(profile)) => profile.highscore(difficulty),
+                        Ok(None) => 0,
+                        Err(e) => {
+                            error!(target: "multiplayer", "{:?}", e.context("getting rating for backfill"));
+                            send!("Internal Error");
+                            return ControlFlow::Continue(());
+                        }
+                    };
+                    match session_state.multiplayer.find_backfill(rating.into()) {
+                        Ok(()) => send!("Success"),
+                        Err(e) => send!(multiplayer_error_message(e)),
+                    }
+                }
+                WSAPIMessage::LeaveSession => {
+                    session_state.multiplayer.leave();
+                    send!("Success");
+                }
+                _ => todo!(),
+            }
+        } else {
+            match msg {
+                WSAPIMessage::GetTournament => {}
+                msg @ (WSAPIMessage::Login
+                | WSAPIMessage::DeviceLogin
+                | WSAPIMessage::LoginWith(_)) => {
+                    let method = match msg {
+                        WSAPIMessage::DeviceLogin => LoginMethod::Device,
+                        WSAPIMessage::LoginWith(provider) => LoginMethod::Redirect(Some(provider)),
+                        _ => LoginMethod::Redirect(None),
+                    };
+                    match self.login(session_state, stream, method).await {
+                        Ok(StreamStatus::Closed) => return ControlFlow::Break(()),
+                        Ok(_) => {}
+                        Err(_) => return ControlFlow::Break(()),
+                    }
+                }
+                WSAPIMessage::RefreshToken(refresh_token) => {
+                    let pair = match HeaderValue::from_str(&refresh_token) {
+                        Ok(refresh_token) => self.login_tokens.refresh(&refresh_token).await,
+                        Err(_) => None,
+                    };
+                    let Some(TokenPair { access, refresh }) = pair else {
+                        send!("Invalid Refresh Token");
+                        return ControlFlow::Continue(());
+                    };
+                    let Some(connection) = self
+                        .connections
+                        .claim(access.identifier.email.clone(), false)
+                    else {
+                        // The refresh token was used up, so the session must log in again
+                        self.login_tokens.revoke_token(&access.token).await;
+                        send!("Already Connected");
+                        return ControlFlow::Continue(());
+                    };
+                    send!("Success");
+                    send!(access.token.to_str().unwrap());
+                    if let Some(refresh) = refresh {
+                        send!(refresh.to_str().unwrap());
+                    }
+                    session_state.login_token = Some(access);
+                    session_state.connection = Some(connection);
+                    session_state.sync_bandwidth_user();
+                }
+
+                _ => send!("Must be logged in"),
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// How many users are logged in, or logging in, on this node
+    pub fn get_connected_users(&self) -> usize {
+        self.connections.count()
+    }
+
+    /// Tells siblings to stop accepting the given token
+    ///
+    /// Siblings that cannot be reached are told once they can be, as the
+    /// message is kept as a dead letter until then
+    async fn broadcast_revocation(&self, token: &HeaderValue) {
+        let Ok(token) = token.to_str() else { return };
+        for (domain, err) in self
+            .node
+            .broadcast_message(
+                &NetworkMessage::TokenRevocation(TokenRevocation {
+                    token: token.to_string(),
+                })
+                .traced()
+                .signed(),
+            )
+            .await
+        {
+            error!(target: "login", "Error broadcasting token revocation to {}: {:?}", domain, err);
+        }
+    }
+
     pub(crate) fn new(
         leaderboard: &'static Leaderboard,
         db: &'static DB,
         oidc: &'static OIDC<&'static OIDCState>,
+        device_oidc: Option<&'static OIDC<&'static OIDCState>>,
+        oidc_providers: &'static OIDCProviderRegistry<&'static OIDCState>,
         login_tokens: &'static LoginTokenGranter,
+        purchases: &'static Purchases,
+        announcements: &'static Announcements,
+        attestation: &'static Attestation,
+        stats: &'static Stats,
+        node: &'static Node<SiblingNetworkHandler>,
+        room_chat: &'static RoomChat,
+        search_cursors: &'static CursorKey,
+        slow_handler_threshold: Duration,
     ) -> Self {
         Self {
-            connections: Default::default(),
+            connections: manglext::immut_leak(Connections::default()),
             leaderboard,
             db,
             oidc,
+            device_oidc,
+            oidc_providers,
             login_tokens,
+            purchases,
+            announcements,
+            attestation,
+            stats,
+            node,
+            room_chat,
+            search_cursors,
+            latencies: MessageLatencies::new(slow_handler_threshold),
+            trace_user_key: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .expect("Generating trace user key"),
+        }
+    }
+
+    /// Starts the span that a single message is handled in
+    ///
+    /// Users are only identified by an opaque ID, which is the same for a user
+    /// until the server restarts
+    fn message_span(&self, msg: &WSAPIMessage, session_state: &SessionState) -> Context {
+        let tracer = telemetry::tracer();
+        let mut attributes = vec![KeyValue::new(
+            "session.logged_in",
+            session_state.login_token.is_some(),
+        )];
+        if let Some(login_token) = &session_state.login_token {
+            let tag = hmac::sign(
+                &self.trace_user_key,
+                login_token.identifier.email.as_bytes(),
+            );
+            attributes.push(KeyValue::new(
+                "enduser.id",
+                hex::encode(&tag.as_ref()[..16]),
+            ));
+        }
+        if let Some(locale) = &session_state.locale {
+            attributes.push(KeyValue::new("session.locale", locale.clone()));
         }
+        let span = tracer
+            .span_builder(format!("ws {}", msg.name()))
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start(&tracer);
+        Context::current_with_span(span)
     }
+
+    /// How long each type of message has taken to handle
+    pub fn get_message_latencies(&self) -> Vec<(&'static str, LatencyStats)> {
+        self.latencies.get_stats()
+    }
+
     async fn login<S: MessageStream>(
         &self,
         session_state: &mut SessionState,
         stream: &mut S,
+        method: LoginMethod,
     ) -> Result<StreamStatus, S::Error> {
+        match self.login_protocol(session_state, stream, method).await {
+            Ok(status) => Ok(status),
+            Err(ProtocolError::StreamError(e)) => Err(e),
+            Err(ProtocolError::TimedOut(state)) => {
+                warn!(target: "login", "Login timed out while {state:?}");
+                Ok(StreamStatus::Closed)
+            }
+            Err(e) => {
+                error!(target: "login", "{e}");
+                Ok(StreamStatus::Closed)
+            }
+        }
+    }
+
+    async fn login_protocol<S: MessageStream>(
+        &self,
+        session_state: &mut SessionState,
+        stream: &mut S,
+        method: LoginMethod,
+    ) -> Result<StreamStatus, ProtocolError<S::Error, LoginState>> {
         let db = &self.db;
-        let oidc = &self.oidc;
         let leaderboard = &self.leaderboard;
         let login_tokens = &self.login_tokens;
 
+        let initial_state = match method {
+            LoginMethod::Device => LoginState::AwaitingDeviceAuth,
+            LoginMethod::Redirect(_) => LoginState::AwaitingAuth,
+        };
+        let mut protocol = Protocol::new(stream, initial_state);
+
         macro_rules! send {
             ($msg:expr) => {
-                stream.send_message($msg).await?
-            };
-        }
-        macro_rules! recv {
-            () => {
-                stream.recv_message().await?
+                protocol.send($msg).await?
             };
         }
         macro_rules! close {
@@ -581,17 +1676,66 @@ impl WsApiHandler {
             }};
         }
 
-        let (auth_url, fut) = oidc.initiate_auth(["openid", "email"]);
-
-        send!(auth_url);
+        // Logins run inline, so a session has at most one pending auth, which
+        // is cancelled by the next message from the client
+        if let Some(retry_after) = session_state
+            .last_auth
+            .map(|x| SESSION_AUTH_INTERVAL.saturating_sub(x.elapsed()))
+            .filter(|x| !x.is_zero())
+        {
+            send!(LoginThrottled {
+                retry_after_ms: retry_after.as_millis() as u64,
+            });
+            return Ok(StreamStatus::Ok);
+        }
+        session_state.last_auth = Some(Instant::now());
+
+        let raced = if let LoginMethod::Redirect(provider) = method {
+            let oidc = match provider {
+                Some(provider) => match self.oidc_providers.get(&provider) {
+                    Some(x) => x,
+                    None => {
+                        send!("Unknown Provider");
+                        return Ok(StreamStatus::Ok);
+                    }
+                },
+                None => self.oidc,
+            };
+            // The pending auth is removed once `fut` is dropped, even if the
+            // session ends before the user finishes
+            let Some((auth_url, fut)) = oidc.initiate_auth(["openid", "email"], session_state.ip)
+            else {
+                send!("Too Many Logins");
+                return Ok(StreamStatus::Ok);
+            };
+            send!(auth_url);
+            protocol.wait_or_recv::<_, String>(fut).await?
+        } else {
+            let Some(device_oidc) = self.device_oidc else {
+                send!("Device Login Unavailable");
+                return Ok(StreamStatus::Ok);
+            };
+            let (authorization, fut) = match protocol
+                .wait_for(device_oidc.initiate_device_auth(["openid", "email"]))
+                .await?
+            {
+                Ok(x) => x,
+                Err(e) => {
+                    error!(target: "login", "{:?}", e.context("starting device login"));
+                    send!("Internal Error");
+                    return Ok(StreamStatus::Ok);
+                }
+            };
+            send!(authorization);
+            // Polling stops once `fut` is dropped
+            protocol.wait_or_recv::<_, String>(fut).await?
+        };
 
-        let auth_option = select! {
-            opt = fut => { opt }
-            res = stream.recv_message::<String>() => {
-                // Return if error
-                res?;
+        let auth_option = match raced {
+            Raced::Future(opt) => opt,
+            Raced::Message(_) => {
                 send!("Login Cancelled");
-                return Ok(StreamStatus::Ok)
+                return Ok(StreamStatus::Ok);
             }
         };
 
@@ -604,38 +1748,67 @@ impl WsApiHandler {
             return Ok(StreamStatus::Ok)
         };
 
-        if self.connections.contains(&email) {
+        // Released if the login does not finish
+        let Some(connection) = self.connections.claim(email.clone(), false) else {
             send!("Already Connected");
             return Ok(StreamStatus::Ok)
-        } else {
-            self.connections.insert(email.clone());
-        }
+        };
 
-        match db.get_user_profile_by_email(&email).await {
+        protocol.transition(LoginState::FetchingProfile)?;
+
+        match protocol
+            .wait_for(db.get_user_profile_by_email(&email))
+            .await?
+        {
+            Ok(Some(profile)) if profile.banned_at.is_some() => {
+                warn!(target: "login", "Refused the login of banned user {email}");
+                send!("Banned");
+            }
             Ok(Some(profile)) => {
                 send!(&profile);
-                let login_token = login_tokens.create_token(LoginTokenData {
+                // The client has shown the user that moderation renamed them
+                if profile.renamed_from.is_some() {
+                    let (db, email) = (*db, email.clone());
+                    spawn(async move {
+                        if let Err(e) = db.clear_renamed_from(email).await {
+                            error!(target: "login", "{:?}", e.context("clearing renamed_from"));
+                        }
+                    });
+                }
+                let TokenPair {
+                    access: login_token,
+                    refresh,
+                } = login_tokens.create_token(LoginTokenData {
                     email,
                     username: profile.username,
                 });
 
                 send!(login_token.token.to_str().unwrap());
+                if let Some(refresh) = refresh {
+                    send!(refresh.to_str().unwrap());
+                }
 
                 session_state.login_token = Some(login_token);
+                session_state.connection = Some(connection);
+                session_state.sync_bandwidth_user();
             }
             Ok(None) => {
+                protocol.transition(LoginState::ChoosingUsername)?;
                 send!("Sign Up");
                 let mut profile: UserProfile;
 
                 loop {
-                    profile = recv!();
+                    profile = protocol.recv().await?;
 
                     if profile.username.is_inappropriate() {
                         send!("Inappropriate username");
                         continue;
                     }
 
-                    match db.is_username_taken(&profile.username).await {
+                    match protocol
+                        .wait_for(db.is_username_taken(&profile.username))
+                        .await?
+                    {
                         Ok(true) => {
                             send!("Username already used");
                             continue;
@@ -650,68 +1823,89 @@ impl WsApiHandler {
                     break;
                 }
 
-                if let Err(e) = db
-                    .create_user_profile(
-                        email.clone(),
-                        profile.username.clone(),
-                        profile.tournament_wins,
-                    )
-                    .await
-                {
-                    error!(target: "login", "{:?}", e.context("creating user profile"));
-                    close!("Internal Error");
-                }
-
-                if let Err(e) = leaderboard
-                    .add_easy_entry(
-                        email.clone(),
-                        LeaderboardEntry {
-                            score: profile.easy_highscore,
-                            username: profile.username.clone(),
-                        },
-                    )
-                    .await
-                {
-                    let e = anyhow::Error::from(e);
-                    error!(target: "login", "{:?}", e.context(format!("adding easy entry for {email}")));
-                }
-                if let Err(e) = leaderboard
-                    .add_normal_entry(
-                        email.clone(),
-                        LeaderboardEntry {
-                            score: profile.normal_highscore,
-                            username: profile.username.clone(),
-                        },
-                    )
-                    .await
-                {
-                    let e = anyhow::Error::from(e);
-                    error!(target: "login", "{:?}", e.context(format!("adding normal entry for {email}")));
-                }
-                if let Err(e) = leaderboard
-                    .add_expert_entry(
-                        email.clone(),
-                        LeaderboardEntry {
-                            score: profile.easy_highscore,
-                            username: profile.username.clone(),
-                        },
-                    )
-                    .await
+                // Scores carried over from before signing up are held to the same
+                // policy as score updates, but refusing them does not refuse the sign up
+                let has_scores = Difficulty::ALL
+                    .into_iter()
+                    .any(|difficulty| profile.highscore(difficulty) > 0)
+                    || !profile.tournament_wins.is_empty();
+                if has_scores && !session_state.attestation.is_verified() {
+                    match self.attestation.get_policy() {
+                        AttestationPolicy::Off => {}
+                        AttestationPolicy::Log => {
+                            warn!(
+                                target: "suspicious_security",
+                                "Accepted sign up scores from {email} with attestation {:?}",
+                                session_state.attestation
+                            );
+                        }
+                        AttestationPolicy::Enforce => {
+                            warn!(
+                                target: "suspicious_security",
+                                "Dropped sign up scores from {email} with attestation {:?}",
+                                session_state.attestation
+                            );
+                            profile.easy_highscore = 0;
+                            profile.normal_highscore = 0;
+                            profile.expert_highscore = 0;
+                            profile.tournament_wins.clear();
+                        }
+                    }
+                }
+
+                protocol.transition(LoginState::CreatingProfile)?;
+
+                // The scores are already in the profile, so they are kept even
+                // if adding them to the leaderboards times out
+                match protocol
+                    .wait_for(db.create_user_profile(email.clone(), &profile))
+                    .await?
                 {
-                    let e = anyhow::Error::from(e);
-                    error!(target: "login", "{:?}", e.context(format!("adding expert entry for {email}")));
+                    Ok(true) => {}
+                    // An earlier sign up that timed out may have finished
+                    Ok(false) => close!("Already Signed Up"),
+                    Err(e) => {
+                        error!(target: "login", "{:?}", e.context("creating user profile"));
+                        close!("Internal Error");
+                    }
+                }
+
+                for difficulty in Difficulty::ALL {
+                    if let Err(e) = protocol
+                        .wait_for(leaderboard.add_entry(
+                            difficulty,
+                            email.clone(),
+                            LeaderboardEntry {
+                                score: profile.highscore(difficulty),
+                                username: profile.username.clone(),
+                                ..Default::default()
+                            },
+                        ))
+                        .await?
+                    {
+                        let e = anyhow::Error::from(e);
+                        error!(target: "login", "{:?}", e.context(format!("adding {difficulty} entry for {email}")));
+                    }
                 }
 
                 send!("Success");
 
-                let login_token = login_tokens.create_token(LoginTokenData {
+                let TokenPair {
+                    access: login_token,
+                    refresh,
+                } = login_tokens.create_token(LoginTokenData {
                     email,
                     username: profile.username,
                 });
 
                 send!(login_token.token.to_str().unwrap());
+                if let Some(refresh) = refresh {
+                    send!(refresh.to_str().unwrap());
+                }
 
                 session_state.login_token = Some(login_token);
+                session_state.connection = Some(connection);
+                session_state.sync_bandwidth_user();
             }
             Err(e) => {
                 error!(target: "login", "Faced the following error while getting user profile for {}: {e:?}", email);
@@ -721,4 +1915,163 @@ impl WsApiHandler {
 
         Ok(StreamStatus::Ok)
     }
+
+    async fn change_email<S: MessageStream>(
+        &self,
+        session_state: &mut SessionState,
+        stream: &mut S,
+    ) -> Result<StreamStatus, S::Error> {
+        match self.change_email_protocol(session_state, stream).await {
+            Ok(status) => Ok(status),
+            Err(ProtocolError::StreamError(e)) => Err(e),
+            Err(ProtocolError::TimedOut(state)) => {
+                warn!(target: "login", "Email change timed out while {state:?}");
+                Ok(StreamStatus::Closed)
+            }
+            Err(e) => {
+                error!(target: "login", "{e}");
+                Ok(StreamStatus::Closed)
+            }
+        }
+    }
+
+    /// Moves the profile of the logged in user to a different email, after
+    /// the user authenticates as both the current and the new email
+    async fn change_email_protocol<S: MessageStream>(
+        &self,
+        session_state: &mut SessionState,
+        stream: &mut S,
+    ) -> Result<StreamStatus, ProtocolError<S::Error, EmailChangeState>> {
+        let Some(login_token) = &session_state.login_token else {
+            return Ok(StreamStatus::Ok)
+        };
+        let old_email = login_token.identifier.email.clone();
+        let username = login_token.identifier.username.clone();
+
+        let mut protocol = Protocol::new(stream, EmailChangeState::VerifyingCurrent);
+
+        macro_rules! send {
+            ($msg:expr) => {
+                protocol.send($msg).await?
+            };
+        }
+        macro_rules! close {
+            ($msg:expr) => {{
+                send!($msg);
+                return Ok(StreamStatus::Closed);
+            }};
+        }
+        macro_rules! authenticate {
+            () => {{
+                let Some((auth_url, fut)) = self
+                    .oidc
+                    .initiate_auth(["openid", "email"], session_state.ip)
+                else {
+                    send!("Too Many Logins");
+                    return Ok(StreamStatus::Ok);
+                };
+                send!(auth_url);
+
+                match protocol.wait_or_recv::<_, String>(fut).await? {
+                    Raced::Future(opt) => opt.and_then(|data| data.email),
+                    Raced::Message(_) => {
+                        send!("Email Change Cancelled");
+                        return Ok(StreamStatus::Ok);
+                    }
+                }
+            }};
+        }
+
+        if authenticate!().as_ref() != Some(&old_email) {
+            send!("Auth Failed");
+            return Ok(StreamStatus::Ok);
+        }
+
+        protocol.transition(EmailChangeState::VerifyingNew)?;
+
+        let Some(new_email) = authenticate!() else {
+            send!("Auth Failed");
+            return Ok(StreamStatus::Ok)
+        };
+        if new_email == old_email {
+            send!("Same Email");
+            return Ok(StreamStatus::Ok);
+        }
+        // Released if the email change does not finish
+        let Some(connection) = self.connections.claim(new_email.clone(), false) else {
+            send!("Already Connected");
+            return Ok(StreamStatus::Ok);
+        };
+
+        protocol.transition(EmailChangeState::Migrating)?;
+
+        match protocol
+            .wait_for(self.db.change_email(old_email.clone(), new_email.clone()))
+            .await?
+        {
+            Ok(ChangeEmailResult::Changed) => {}
+            Ok(ChangeEmailResult::EmailTaken) => {
+                send!("Email Already Used");
+                return Ok(StreamStatus::Ok);
+            }
+            Ok(ChangeEmailResult::NoProfile) => {
+                error!(target: "login", "No profile to move for {old_email}");
+                close!("Internal Error");
+            }
+            Err(e) => {
+                error!(target: "login", "{:?}", e.context(format!("changing email of {old_email}")));
+                close!("Internal Error");
+            }
+        }
+
+        // Releases the claim on the old email
+        session_state.connection = Some(connection);
+
+        let old_data = LoginTokenData {
+            username: username.clone(),
+            email: old_email.clone(),
+        };
+        let new_data = LoginTokenData {
+            username: username.clone(),
+            email: new_email.clone(),
+        };
+        let old_token = session_state.login_token.as_ref().map(|x| x.token.clone());
+        let login_token = match self
+            .login_tokens
+            .reassign_token(&old_data, new_data.clone())
+            .await
+        {
+            Some(x) => x,
+            None => self.login_tokens.create_token(new_data).access,
+        };
+
+        send!("Success");
+        send!(login_token.token.to_str().unwrap());
+        // Signed tokens carry the old email, so they are replaced rather than reassigned
+        let old_token = old_token.filter(|x| *x != login_token.token);
+        session_state.login_token = Some(login_token);
+        session_state.sync_bandwidth_user();
+        if let Some(old_token) = old_token {
+            self.login_tokens.revoke_token(&old_token).await;
+            self.broadcast_revocation(&old_token).await;
+        }
+
+        for (domain, err) in self
+            .node
+            .broadcast_message(
+                &NetworkMessage::EmailChange(EmailChange {
+                    old_email,
+                    new_email,
+                    username,
+                })
+                .traced()
+                .signed(),
+            )
+            .await
+        {
+            error!(target: "login", "Error broadcasting email change to {}: {:?}", domain, err);
+        }
+
+        Ok(StreamStatus::Ok)
+    }
 }
@@ -0,0 +1,16 @@
+//! Feeds bytes through the same `BinaryMessageStream` that `SiblingNetworkHandler`
+//! receives its bincode messages from, deserializing them into `NetworkMessage`
+//!
+//! Memory is bounded by running with the limits of libFuzzer:
+//!
+//! cargo fuzz run sibling_messages -- -rss_limit_mb=512 -malloc_limit_mb=64
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: Vec<u8>| {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(bola_api::fuzzing::recv_sibling_message(bytes));
+});
@@ -0,0 +1,82 @@
+//! Feeds WebSocket frames through the same `JsonMessageStream` that `WsApiHandler`
+//! receives its messages from, deserializing them into `WSAPIMessage` with the
+//! same handling of errors
+//!
+//! Only the parsing of frames is covered. Messages are not handed to
+//! `WsApiHandler`, as it cannot be built without DynamoDB, the OIDC providers
+//! and the sibling network, so panics in the handling of a message are not found
+//! by this target
+//!
+//! Memory is bounded by running with the limits of libFuzzer:
+//!
+//! cargo fuzz run ws_frames -- -dict=dictionaries/ws_api.dict -rss_limit_mb=512 -malloc_limit_mb=64
+#![no_main]
+
+use std::collections::VecDeque;
+
+use async_trait::async_trait;
+use libfuzzer_sys::{arbitrary::Arbitrary, fuzz_target};
+use messagist::text::{JsonMessageStream, TextStream};
+
+/// Axum does not accept larger messages than this by default
+const MAX_FRAME_SIZE: usize = 64 << 20;
+
+#[derive(Arbitrary, Debug)]
+enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Bytes that may not be valid UTF-8, as sent by a misbehaving client
+    Malformed(Vec<u8>),
+}
+
+#[derive(thiserror::Error, Debug)]
+enum FuzzError {
+    #[error("Binary frame")]
+    Binary,
+    #[error("Closed")]
+    Closed,
+}
+
+/// Stands in for `ManagedWebSocket`, rejecting binary frames in the same way
+struct FuzzStream(VecDeque<Frame>);
+
+#[async_trait]
+impl TextStream for FuzzStream {
+    type Error = FuzzError;
+
+    async fn recv_string(&mut self) -> Result<String, Self::Error> {
+        match self.0.pop_front().ok_or(FuzzError::Closed)? {
+            Frame::Text(x) => Ok(x),
+            Frame::Binary(_) => Err(FuzzError::Binary),
+            Frame::Malformed(x) => Ok(String::from_utf8_lossy(&x).into_owned()),
+        }
+    }
+
+    async fn send_string(&mut self, _msg: String) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        FuzzError::Closed
+    }
+}
+
+fuzz_target!(|frames: Vec<Frame>| {
+    let frames: VecDeque<_> = frames
+        .into_iter()
+        .filter(|frame| match frame {
+            Frame::Text(x) => x.len() <= MAX_FRAME_SIZE,
+            Frame::Binary(x) | Frame::Malformed(x) => x.len() <= MAX_FRAME_SIZE,
+        })
+        .collect();
+    let frame_count = frames.len();
+
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(async move {
+            let mut stream = JsonMessageStream::from(FuzzStream(frames));
+            let received = bola_api::fuzzing::recv_ws_messages(&mut stream).await;
+            assert!(received <= frame_count);
+        });
+});
@@ -0,0 +1,59 @@
+#![feature(test)]
+
+extern crate test;
+
+use std::time::Duration;
+
+use axum::http::HeaderValue;
+use mangle_api_core::auth::token::{TokenConfig, TokenGranter};
+use test::{black_box, Bencher};
+use tokio::runtime::Runtime;
+
+/// How many other sessions are active while a token is verified
+const ACTIVE_TOKENS: u64 = 10_000;
+
+struct BenchTokenConfig;
+
+impl TokenConfig for BenchTokenConfig {
+    type TokenIdentifier = u64;
+    const TOKEN_LENGTH: usize = 32;
+}
+
+/// A granter with `ACTIVE_TOKENS` sessions, and the token of one of them
+///
+/// Tokens expire in tasks of their own, so the runtime must outlive the granter
+fn granter(runtime: &Runtime) -> (TokenGranter<BenchTokenConfig>, HeaderValue) {
+    let _guard = runtime.enter();
+    let granter = TokenGranter::new(Duration::from_secs(3600));
+    let mut token = None;
+    for id in 0..ACTIVE_TOKENS {
+        token = Some(granter.create_token(id).access.token);
+    }
+    (granter, token.unwrap())
+}
+
+#[bench]
+fn verify_valid_token(b: &mut Bencher) {
+    let runtime = Runtime::new().unwrap();
+    let (granter, token) = granter(&runtime);
+    b.iter(|| black_box(granter.verify_token(black_box(&token))));
+}
+
+#[bench]
+fn verify_unknown_token(b: &mut Bencher) {
+    let runtime = Runtime::new().unwrap();
+    let (granter, _) = granter(&runtime);
+    let token = HeaderValue::from_static("0123456789abcdefghijklmnopqrstuv");
+    b.iter(|| black_box(granter.verify_token(black_box(&token))));
+}
+
+/// Should take as long as `verify_unknown_token`, as lookups are by hash
+#[bench]
+fn verify_token_sharing_a_prefix(b: &mut Bencher) {
+    let runtime = Runtime::new().unwrap();
+    let (granter, token) = granter(&runtime);
+    let mut token = token.as_bytes().to_vec();
+    *token.last_mut().unwrap() ^= 1;
+    let token = HeaderValue::from_bytes(&token).unwrap();
+    b.iter(|| black_box(granter.verify_token(black_box(&token))));
+}
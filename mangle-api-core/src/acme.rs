@@ -0,0 +1,193 @@
+use std::{
+    fs::{read, File},
+    io::Write,
+    net::{Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{Context, Error, Result};
+use lers::{solver::Http01Solver, Directory, LETS_ENCRYPT_PRODUCTION_URL};
+use log::{error, info, warn};
+use openssl::{asn1::Asn1Time, x509::X509};
+use tokio::time::sleep;
+use tokio_native_tls::native_tls::Identity;
+
+use crate::{tls::SharedTlsAcceptor, BindAddress};
+
+/// Renews the HTTPS certificate through ACME before it expires, swapping it
+/// into the running servers
+#[derive(Clone)]
+pub struct CertificateRenewal {
+    bind_address: BindAddress,
+    certs_path: String,
+    key_path: String,
+    https_email: String,
+    https_domain: String,
+    renew_before: Duration,
+    check_interval: Duration,
+    extra_acceptors: Vec<SharedTlsAcceptor>,
+}
+
+impl CertificateRenewal {
+    /// Takes the same arguments as `get_https_credentials`
+    pub fn new(
+        bind_address: BindAddress,
+        certs_path: String,
+        key_path: String,
+        https_email: String,
+        https_domain: String,
+    ) -> Self {
+        Self {
+            bind_address,
+            certs_path,
+            key_path,
+            https_email,
+            https_domain,
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+            check_interval: Duration::from_secs(12 * 60 * 60),
+            extra_acceptors: Vec::new(),
+        }
+    }
+
+    /// How long before it expires the certificate is renewed
+    pub fn set_renew_before(mut self, renew_before: Duration) -> Self {
+        self.renew_before = renew_before;
+        self
+    }
+
+    /// How often the expiry of the certificate is checked, which is also how
+    /// long a failed renewal waits before trying again
+    pub fn set_check_interval(mut self, check_interval: Duration) -> Self {
+        self.check_interval = check_interval;
+        self
+    }
+
+    /// Also swaps renewed certificates into the acceptor, such as that of a
+    /// `Node` that shares the HTTPS identity
+    pub fn add_acceptor(mut self, acceptor: SharedTlsAcceptor) -> Self {
+        self.extra_acceptors.push(acceptor);
+        self
+    }
+
+    async fn renew_if_expiring(&self, acceptor: &SharedTlsAcceptor) -> Result<()> {
+        let certs = read(&self.certs_path).context(format!("Reading {}", self.certs_path))?;
+        if !expires_within(&certs, self.renew_before)? {
+            return Ok(());
+        }
+        warn!("HTTPS certificate expires soon, renewing...");
+
+        let (certs, key) = obtain_certificate(
+            self.bind_address.clone(),
+            self.https_email.clone(),
+            self.https_domain.clone(),
+        )
+        .await?;
+        let identity =
+            Identity::from_pkcs8(&certs, &key).context("Loading renewed HTTPS Credentials")?;
+        save_credentials(&self.certs_path, &self.key_path, &certs, &key)?;
+        for acceptor in self.extra_acceptors.iter().chain([acceptor]) {
+            acceptor
+                .set_identity(identity.clone())
+                .context("Swapping in renewed HTTPS Credentials")?;
+        }
+
+        info!("HTTPS certificate renewed");
+        Ok(())
+    }
+}
+
+/// Checks the certificate right away, then every check interval, forever
+pub(crate) async fn renew_certificate(renewal: CertificateRenewal, acceptor: SharedTlsAcceptor) {
+    loop {
+        if let Err(e) = renewal.renew_if_expiring(&acceptor).await {
+            error!("{:?}", e.context("Renewing HTTPS certificate"));
+        }
+        sleep(renewal.check_interval).await;
+    }
+}
+
+/// Whether the first certificate in the given PEM chain expires within the given duration
+fn expires_within(certs: &[u8], duration: Duration) -> Result<bool> {
+    let cert = X509::from_pem(certs).context("Parsing HTTPS certificate")?;
+    let deadline = Asn1Time::from_unix(chrono::Utc::now().timestamp() + duration.as_secs() as i64)
+        .context("Computing renewal deadline")?;
+    Ok(cert.not_after() < deadline)
+}
+
+/// Obtains a certificate from Let's Encrypt through the HTTP-01 challenge,
+/// returning the PEM encoded certificate chain and private key
+pub(crate) async fn obtain_certificate(
+    bind_address: BindAddress,
+    https_email: String,
+    https_domain: String,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut address = match bind_address {
+        BindAddress::Network(address) => address,
+        // Also accepts IPv4 wherever IPv6 sockets do, which is the default on Linux
+        BindAddress::DualStack(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 80),
+        _ => {
+            return Err(Error::msg(
+                "Failed to replace missing credentials as we are binded locally",
+            ))
+        }
+    };
+    let solver = Http01Solver::new();
+    address.set_port(80);
+    let handle = solver
+        .start(&address)
+        .context(format!("Binding ACME solver to {address}"))?;
+
+    // Create a new directory for Let's Encrypt Production
+    let directory = Directory::builder(LETS_ENCRYPT_PRODUCTION_URL)
+        .http01_solver(Box::new(solver))
+        .build()
+        .await
+        .context("Building ACME directory")?;
+
+    // Create an ACME account to order your certificate. In production, you should store
+    // the private key, so you can renew your certificate.
+    let account = directory
+        .account()
+        .terms_of_service_agreed(true)
+        .contacts(vec![format!("mailto:{https_email}")])
+        .create_if_not_exists()
+        .await
+        .context("Creating ACME account")?;
+
+    // Obtain your certificate
+    let certificate = account
+        .certificate()
+        .add_domain(https_domain)
+        .obtain()
+        .await
+        .context("Collecting certificate")?;
+
+    let certs = certificate
+        .fullchain_to_pem()
+        .context("Converting certificate to pem")?;
+
+    let key = certificate
+        .private_key_to_pem()
+        .context("Converting private key to pem")?;
+
+    handle.stop().await.context("Stopping ACME handle")?;
+
+    Ok((certs, key))
+}
+
+pub(crate) fn save_credentials(
+    certs_path: &str,
+    key_path: &str,
+    certs: &[u8],
+    key: &[u8],
+) -> Result<()> {
+    File::create(certs_path)
+        .context(format!("Opening {}", certs_path))?
+        .write_all(certs)
+        .context(format!("Writing to {}", certs_path))?;
+
+    File::create(key_path)
+        .context(format!("Opening {}", key_path))?
+        .write_all(key)
+        .context(format!("Writing to {}", key_path))
+}
@@ -0,0 +1,242 @@
+use std::{collections::BTreeMap, ffi::OsString, future::Pending};
+
+use anyhow::{Context, Result};
+use clap::{builder::IntoResettable, ArgMatches, Command};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    config::read_config,
+    daemon::{spawn_daemon, PidFile, DAEMON_FLAG},
+    get_pipe_name,
+    log_format::LogFormats,
+    log_levels, make_app, new_api, pre_matches_with_env, setup_logger_with_formats,
+    telemetry::{setup_telemetry, TelemetryConfig},
+    CommandMatchResult, Unset, API,
+};
+
+/// The parts of a config that `ApiApp` needs to start a service
+pub trait ServiceConfig: DeserializeOwned {
+    fn stderr_log(&self) -> &str;
+    fn routing_log(&self) -> &str;
+    fn security_log(&self) -> &str;
+
+    /// Where traces are exported to, if anywhere
+    fn telemetry(&self) -> Option<&TelemetryConfig> {
+        None
+    }
+
+    /// Where log levels changed at runtime are kept, so that they survive restarts
+    fn log_levels_file(&self) -> Option<&str> {
+        None
+    }
+
+    /// Levels of log targets that are set on start, before the levels in the
+    /// log levels file are restored
+    fn log_levels(&self) -> Option<&BTreeMap<String, String>> {
+        None
+    }
+
+    fn log_formats(&self) -> LogFormats {
+        LogFormats::default()
+    }
+
+    /// Checks constraints between fields that deserializing cannot, such as
+    /// files that must be readable. Called on start and by `check-config`
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The effective configuration with every secret redacted, which
+    /// `check-config` prints
+    fn effective_config(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Bundles what every service does before it can build its API: defining the
+/// command line, reading the config, and setting up logging and telemetry
+///
+/// ```ignore
+/// let started = match ApiApp::new("MyAPI", env!("CARGO_PKG_VERSION"), "My API")
+///     .set_pipe_name("MY_SOCKET_NAME", "/dev/my_server.sock")
+///     .start::<Config>()
+///     .await?
+/// {
+///     AppStart::Started(started) => started,
+///     AppStart::Command { name, matches, pipe_name } => return run_command(...).await,
+/// };
+/// started.new_api().set_state(...)...run().await
+/// ```
+pub struct ApiApp {
+    command: Command,
+    pipe_env_var: &'static str,
+    default_pipe_name: &'static str,
+    on_active_msg: Option<String>,
+    env_prefix: Option<&'static str>,
+    pid_file: &'static str,
+}
+
+pub enum AppStart<Config> {
+    /// The `start` command was given, and logging has been set up
+    Started(StartedApp<Config>),
+    /// Any other command, which the service handles itself
+    Command {
+        name: String,
+        matches: ArgMatches,
+        pipe_name: OsString,
+    },
+    /// A command that was handled by the app, such as `check-config`
+    Done,
+}
+
+pub struct StartedApp<Config> {
+    pub config: Config,
+    /// Where the config was read from, so that it can be read again on reload
+    pub config_path: String,
+    pub pipe_name: OsString,
+    /// Held until the server stops if it was started with `start --daemon`
+    pub pid_file: Option<PidFile>,
+}
+
+impl<Config> StartedApp<Config> {
+    /// Starts building an API that listens for control messages on the pipe of this app
+    pub fn new_api(&self) -> API<Unset, OsString, Unset, Unset, 0, 0, Unset, Pending<()>> {
+        new_api().set_pipe_name(self.pipe_name.clone())
+    }
+}
+
+impl ApiApp {
+    pub fn new(
+        name: &'static str,
+        version: impl IntoResettable<clap::builder::Str>,
+        about: &'static str,
+    ) -> Self {
+        Self::with_log_targets(name, version, about, [])
+    }
+
+    /// Creates the app with extra targets for the `log_level` command
+    pub fn with_log_targets<const N: usize>(
+        name: &'static str,
+        version: impl IntoResettable<clap::builder::Str>,
+        about: &'static str,
+        extra_log_targets: [&'static str; N],
+    ) -> Self {
+        Self {
+            command: make_app(name, version, about, extra_log_targets),
+            pipe_env_var: "MANGLE_SOCKET_NAME",
+            default_pipe_name: "/dev/mangle_server.sock",
+            on_active_msg: None,
+            env_prefix: None,
+            pid_file: "mangle_server.pid",
+        }
+    }
+
+    /// Sets the pipe that the control server listens on, which can be
+    /// overridden with the given environment variable
+    pub fn set_pipe_name(mut self, env_var: &'static str, default: &'static str) -> Self {
+        self.pipe_env_var = env_var;
+        self.default_pipe_name = default;
+        self
+    }
+
+    /// The error shown when starting while another server is running
+    pub fn set_on_active_msg(mut self, msg: impl Into<String>) -> Self {
+        self.on_active_msg = Some(msg.into());
+        self
+    }
+
+    /// Lets environment variables that start with `env_prefix` override any
+    /// field of the config, such as `BOLA_API_TOKEN` for the prefix `BOLA`
+    pub fn set_env_prefix(mut self, env_prefix: &'static str) -> Self {
+        self.env_prefix = Some(env_prefix);
+        self
+    }
+
+    /// Where `start --daemon` writes the PID of the server, relative to the
+    /// current directory unless absolute
+    pub fn set_pid_file(mut self, pid_file: &'static str) -> Self {
+        self.pid_file = pid_file;
+        self
+    }
+
+    /// Adds the subcommands of the service to the command line
+    pub fn add_subcommands(mut self, add: impl FnOnce(Command) -> Command) -> Self {
+        self.command = add(self.command);
+        self
+    }
+
+    /// Parses the command line, and if the service is being started, reads the
+    /// config and sets up logging and telemetry
+    pub async fn start<Config: ServiceConfig>(self) -> Result<AppStart<Config>> {
+        let matches = self.command.get_matches();
+        let pipe_name = get_pipe_name(self.pipe_env_var, self.default_pipe_name);
+
+        let config = match pre_matches_with_env::<Config>(
+            &matches,
+            pipe_name.as_os_str(),
+            self.on_active_msg,
+            self.env_prefix,
+        )
+        .await?
+        {
+            CommandMatchResult::StartProgram(config) => config,
+            CommandMatchResult::Unmatched(("check-config", matches)) => {
+                let config_path = matches
+                    .get_one::<String>("config_path")
+                    .cloned()
+                    .unwrap_or("configs.toml".into());
+                let config: Config = read_config(&config_path, self.env_prefix)?;
+                config.validate().context("Validating configuration")?;
+                if let Some(effective_config) = config.effective_config() {
+                    println!("{effective_config}");
+                }
+                eprintln!("{config_path} is valid");
+                return Ok(AppStart::Done);
+            }
+            CommandMatchResult::Unmatched((name, matches)) => {
+                return Ok(AppStart::Command {
+                    name: name.to_string(),
+                    matches: matches.clone(),
+                    pipe_name,
+                })
+            }
+        };
+
+        config.validate().context("Validating configuration")?;
+        let start_matches = matches.subcommand_matches("start");
+        if start_matches.map_or(false, |x| x.get_flag(DAEMON_FLAG)) {
+            let pid = spawn_daemon(self.pid_file, config.stderr_log(), &pipe_name).await?;
+            println!("Started in the background with PID {pid}");
+            return Ok(AppStart::Done);
+        }
+        let config_path = start_matches
+            .and_then(|x| x.get_one::<String>("config_path"))
+            .cloned()
+            .unwrap_or("configs.toml".into());
+        if let Some(levels) = config.log_levels() {
+            log_levels::set_levels(levels).context("Setting log levels")?;
+        }
+        if let Some(path) = config.log_levels_file() {
+            log_levels::set_state_file(path).context("Restoring log levels")?;
+        }
+        setup_logger_with_formats(
+            config.stderr_log(),
+            config.routing_log(),
+            config.security_log(),
+            config.log_formats(),
+        )?
+        .apply()
+        .context("Setting up logger")?;
+        if let Some(telemetry) = config.telemetry() {
+            setup_telemetry(telemetry).context("Setting up telemetry")?;
+        }
+        let pid_file = PidFile::create_if_daemon().context("Writing the PID file")?;
+
+        Ok(AppStart::Started(StartedApp {
+            config,
+            config_path,
+            pipe_name,
+            pid_file,
+        }))
+    }
+}
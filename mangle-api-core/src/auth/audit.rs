@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Display,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use log::warn;
+use parking_lot::Mutex;
+
+use crate::{log_targets, rate_limit::parts_client_ip};
+
+/// Records are forgotten once this many exist, if they have gone stale
+const PRUNE_THRESHOLD: usize = 10_000;
+/// Records are pruned at most this often, so that IPs with fresh records
+/// cannot make every failure prune
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+/// Failures of new IPs are only logged once this many records exist
+const MAX_RECORDS: usize = 100_000;
+/// Lockouts double at most this many times, so that they cannot overflow
+const MAX_LOCKOUT_DOUBLINGS: u32 = 20;
+
+/// What kind of authentication failed, as logged
+#[derive(Clone, Copy, Debug)]
+pub enum AuthFailure {
+    BearerToken,
+    TokenVerification,
+    OAuthExchange,
+    SecondFactor,
+}
+
+impl AuthFailure {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthFailure::BearerToken => "bearer_token",
+            AuthFailure::TokenVerification => "token_verification",
+            AuthFailure::OAuthExchange => "oauth_exchange",
+            AuthFailure::SecondFactor => "second_factor",
+        }
+    }
+}
+
+struct FailureRecord {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+struct Records {
+    records: HashMap<IpAddr, FailureRecord>,
+    last_prune: Instant,
+}
+
+/// Counts failed authentications by source IP, so that tokens cannot be
+/// brute forced
+///
+/// The IP is the peer address, or the client address given by a trusted proxy,
+/// so clients cannot choose it
+///
+/// Once an IP fails more than the allowed amount of times, it is locked out
+/// for the base lockout, which doubles with every further failure up to the
+/// max lockout. Failures are forgotten once an IP has not failed for as long
+/// as the max lockout
+pub struct AuthAudit {
+    records: Mutex<Records>,
+    allowed_failures: u32,
+    base_lockout: Duration,
+    max_lockout: Duration,
+}
+
+impl Default for AuthAudit {
+    fn default() -> Self {
+        Self::new(10, Duration::from_secs(1))
+    }
+}
+
+impl AuthAudit {
+    pub fn new(allowed_failures: u32, base_lockout: Duration) -> Self {
+        Self {
+            records: Mutex::new(Records {
+                records: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+            allowed_failures,
+            base_lockout,
+            max_lockout: Duration::from_secs(3600),
+        }
+    }
+
+    pub fn set_max_lockout(mut self, max_lockout: Duration) -> Self {
+        self.max_lockout = max_lockout;
+        self
+    }
+
+    fn is_stale(&self, record: &FailureRecord, now: Instant) -> bool {
+        record.locked_until.map_or(true, |x| x <= now)
+            && now.duration_since(record.last_failure) >= self.max_lockout
+    }
+
+    /// How much longer the IP is locked out for, if it is
+    pub fn locked_out(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        self.records
+            .lock()
+            .records
+            .get(&ip)?
+            .locked_until
+            .and_then(|x| x.checked_duration_since(now))
+            .filter(|x| !x.is_zero())
+    }
+
+    /// Counts a failed authentication from the IP, locking it out if it failed
+    /// too many times
+    ///
+    /// Every failure is logged to the security target as `key=value` pairs
+    pub fn record_failure(&self, ip: IpAddr, failure: AuthFailure, detail: impl Display) {
+        let now = Instant::now();
+        let mut guard = self.records.lock();
+        let Records {
+            records,
+            last_prune,
+        } = &mut *guard;
+        if records.len() >= PRUNE_THRESHOLD && now.duration_since(*last_prune) >= PRUNE_INTERVAL {
+            *last_prune = now;
+            records.retain(|_, record| !self.is_stale(record, now));
+        }
+        if records.len() >= MAX_RECORDS && !records.contains_key(&ip) {
+            warn!(
+                target: log_targets::SECURITY,
+                "auth_failure kind={} ip={ip} untracked=true detail={:?}",
+                failure.as_str(),
+                detail.to_string()
+            );
+            return;
+        }
+        let record = records.entry(ip).or_insert(FailureRecord {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if self.is_stale(record, now) {
+            record.failures = 0;
+        }
+        record.failures += 1;
+        record.last_failure = now;
+
+        let lockout = record
+            .failures
+            .checked_sub(self.allowed_failures + 1)
+            .map(|doublings| {
+                self.base_lockout
+                    .saturating_mul(1 << doublings.min(MAX_LOCKOUT_DOUBLINGS))
+                    .min(self.max_lockout)
+            });
+        if let Some(lockout) = lockout {
+            record.locked_until = Some(now + lockout);
+        }
+
+        warn!(
+            target: log_targets::SECURITY,
+            "auth_failure kind={} ip={ip} failures={} lockout_secs={} detail={:?}",
+            failure.as_str(),
+            record.failures,
+            lockout.map_or(0, |x| x.as_secs()),
+            detail.to_string()
+        );
+    }
+}
+
+/// The address of the client, as given by a reverse proxy if there is one
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(parts_client_ip(&parts.extensions)))
+    }
+}
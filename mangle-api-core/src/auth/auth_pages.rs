@@ -1,4 +1,76 @@
-use std::{borrow::Cow, marker::PhantomPinned, mem::transmute, pin::Pin, sync::Arc};
+use std::{
+    borrow::Cow, collections::HashMap, marker::PhantomPinned, mem::transmute, pin::Pin, sync::Arc,
+};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+const ERROR_CODE_SIZE: usize = 8;
+
+/// The values of the `{{name}}` variables in a page, such as `{{username}}`,
+/// `{{error_code}}` and `{{return_url}}`
+#[derive(Default, Clone, Debug)]
+pub struct PageContext {
+    vars: Vec<(&'static str, String)>,
+}
+
+impl PageContext {
+    pub fn set(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.vars.retain(|(x, _)| *x != name);
+        self.vars.push((name, value.into()));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(x, _)| *x == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A short code that identifies an authentication in the logs, so that users
+/// can quote it to support
+pub(crate) fn new_error_code() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(ERROR_CODE_SIZE)
+        .map(|x| char::from(x).to_ascii_uppercase())
+        .collect()
+}
+
+fn escape_html_into(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Replaces every `{{name}}` in the page with its HTML escaped value in the
+/// context, or nothing if the context has no such value
+pub fn render(page: &str, context: &PageContext) -> String {
+    let mut out = String::with_capacity(page.len());
+    let mut rest = page;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        if let Some(value) = context.get(after[..end].trim()) {
+            escape_html_into(&mut out, value);
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
 
 pub struct AuthPagesSrc {
     pub late: String,
@@ -14,6 +86,27 @@ pub struct AuthPages {
     pub(crate) invalid: Cow<'static, String>,
     pub(crate) internal_error: Cow<'static, String>,
     pub(crate) success: Cow<'static, String>,
+    /// The variants of these pages by lowercase language tag, such as `fr` or
+    /// `pt-br`
+    locales: Arc<HashMap<String, AuthPages>>,
+}
+
+/// The language tags of an Accept-Language header, from most to least preferred
+fn accepted_languages(accept_language: &str) -> Vec<String> {
+    let mut languages: Vec<(String, f32)> = accept_language
+        .split(',')
+        .filter_map(|x| {
+            let mut parts = x.split(';');
+            let tag = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|x| x.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |x| x.trim().parse().ok())?;
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // Stable, so equally preferred languages keep their order
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+    languages.into_iter().map(|(tag, _)| tag).collect()
 }
 
 impl AuthPages {
@@ -28,11 +121,40 @@ impl AuthPages {
                 invalid: Cow::Borrowed(transmute(&_src.0.invalid)),
                 internal_error: Cow::Borrowed(transmute(&_src.0.internal_error)),
                 success: Cow::Borrowed(transmute(&_src.0.success)),
+                locales: Default::default(),
                 _src,
             }
         }
     }
 
+    /// Adds the variant of these pages for a language tag, such as `fr`
+    pub fn add_locale(mut self, language: &str, pages: AuthPages) -> Self {
+        Arc::make_mut(&mut self.locales).insert(language.to_ascii_lowercase(), pages);
+        self
+    }
+
+    /// Picks the variant for the most preferred language of an Accept-Language
+    /// header, falling back to these pages
+    ///
+    /// A language with a region, such as `fr-ca`, also matches the variant
+    /// for the language alone
+    pub fn localize(&self, accept_language: Option<&str>) -> AuthPages {
+        let Some(accept_language) = accept_language else {
+            return self.clone();
+        };
+        for language in accepted_languages(accept_language) {
+            let primary = language.split('-').next().unwrap_or_default();
+            if let Some(pages) = self
+                .locales
+                .get(&language)
+                .or_else(|| self.locales.get(primary))
+            {
+                return pages.clone();
+            }
+        }
+        self.clone()
+    }
+
     pub fn borrow_late(&self) -> &str {
         &self.late
     }
@@ -49,6 +171,22 @@ impl AuthPages {
         &self.success
     }
 
+    pub fn render_late(&self, context: &PageContext) -> String {
+        render(&self.late, context)
+    }
+
+    pub fn render_invalid(&self, context: &PageContext) -> String {
+        render(&self.invalid, context)
+    }
+
+    pub fn render_internal_error(&self, context: &PageContext) -> String {
+        render(&self.internal_error, context)
+    }
+
+    pub fn render_success(&self, context: &PageContext) -> String {
+        render(&self.success, context)
+    }
+
     pub fn set_late(&mut self, late: String) {
         self.late = Cow::Owned(late)
     }
@@ -65,3 +203,47 @@ impl AuthPages {
         self.success = Cow::Owned(success)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_escaped_variables() {
+        let context = PageContext::default()
+            .set("username", "<b>Tom & \"Jerry\"</b>")
+            .set("error_code", "ABC123");
+        assert_eq!(
+            render("Hi {{username}}, code {{ error_code }}", &context),
+            "Hi &lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;, code ABC123"
+        );
+    }
+
+    #[test]
+    fn renders_unknown_variables_as_nothing() {
+        assert_eq!(render("a{{missing}}b", &PageContext::default()), "ab");
+    }
+
+    #[test]
+    fn keeps_unclosed_braces() {
+        let context = PageContext::default().set("username", "Tom");
+        assert_eq!(
+            render("{{username}} {{username", &context),
+            "Tom {{username"
+        );
+    }
+
+    #[test]
+    fn orders_languages_by_quality() {
+        assert_eq!(
+            accepted_languages("fr-CA;q=0.8, en;q=0.9, de, pt-BR;q=0.8"),
+            ["de", "en", "fr-ca", "pt-br"]
+        );
+    }
+
+    #[test]
+    fn skips_wildcards_and_refused_languages() {
+        assert_eq!(accepted_languages("*, es;q=0, it;q=bad, ja;q=0.5"), ["ja"]);
+        assert!(accepted_languages("").is_empty());
+    }
+}
@@ -1,15 +1,84 @@
+use anyhow::{Context, Result};
 use axum::{
-    body::HttpBody,
+    body::{BoxBody, HttpBody},
+    extract::MatchedPath,
     http::{HeaderValue, Request, Response, StatusCode},
+    response::IntoResponse,
 };
+use chrono::{DateTime, Utc};
 use constant_time_eq::constant_time_eq;
-use regex::RegexSet;
-use std::marker::PhantomData;
+use log::warn;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use std::{marker::PhantomData, sync::Arc};
 use tower_http::auth::AuthorizeRequest;
 
+use super::audit::{AuthAudit, AuthFailure};
+use crate::{
+    log_targets,
+    rate_limit::client_ip,
+    redact::redact,
+    rejection::Rejection,
+    reload::{HttpSettings, ReloadableConfig},
+};
+
+/// An API token that can only access some paths, such as a read-only token
+/// for a monitoring system, so that the main API token need not be shared
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ScopedToken {
+    /// Identifies the token in logs
+    pub name: String,
+    #[serde(serialize_with = "redact")]
+    pub token: String,
+    /// A regex of the paths that the token can access, such as `^/admin/stats$`
+    pub scope: String,
+    /// When the token stops working, in RFC 3339, such as `2024-01-01T00:00:00Z`
+    #[serde(default = "Default::default")]
+    pub expires: Option<String>,
+}
+
+impl ScopedToken {
+    fn compile(&self) -> Result<Token> {
+        Ok(Token {
+            name: self.name.clone(),
+            token: self.token.as_bytes().to_vec(),
+            scope: Some(
+                Regex::new(&self.scope)
+                    .context(format!("Parsing the scope of API token {}", self.name))?,
+            ),
+            expires: match &self.expires {
+                Some(expires) => Some(
+                    DateTime::parse_from_rfc3339(expires)
+                        .context(format!("Parsing the expiry of API token {}", self.name))?
+                        .with_timezone(&Utc),
+                ),
+                None => None,
+            },
+        })
+    }
+
+    /// Checks the scope and expiry, as done when the API runs
+    pub fn validate(&self) -> Result<()> {
+        self.compile().map(drop)
+    }
+}
+
+#[derive(Clone)]
+struct Token {
+    name: String,
+    token: Vec<u8>,
+    /// The main API token can access every path
+    scope: Option<Regex>,
+    expires: Option<DateTime<Utc>>,
+}
+
 pub struct BearerAuth<ResBody> {
-    api_token: HeaderValue,
+    /// Starting with the main API token
+    tokens: Arc<Vec<Token>>,
     public_paths: RegexSet,
+    http_settings: Option<ReloadableConfig<HttpSettings>>,
+    public_fallback: bool,
+    audit: Option<Arc<AuthAudit>>,
     _phantom: PhantomData<ResBody>,
 }
 
@@ -19,8 +88,11 @@ pub struct BearerAuth<ResBody> {
 impl<ResBody> Clone for BearerAuth<ResBody> {
     fn clone(&self) -> Self {
         Self {
-            api_token: self.api_token.clone(),
+            tokens: self.tokens.clone(),
             public_paths: self.public_paths.clone(),
+            http_settings: self.http_settings.clone(),
+            public_fallback: self.public_fallback,
+            audit: self.audit.clone(),
             _phantom: self._phantom,
         }
     }
@@ -29,19 +101,77 @@ impl<ResBody> Clone for BearerAuth<ResBody> {
 impl<ResBody> BearerAuth<ResBody> {
     pub fn new(api_token: HeaderValue, public_paths: RegexSet) -> Self {
         Self {
-            api_token,
+            tokens: Arc::new(vec![Token {
+                name: "main".into(),
+                token: api_token.as_bytes().to_vec(),
+                scope: None,
+                expires: None,
+            }]),
             public_paths,
+            http_settings: None,
+            public_fallback: false,
+            audit: None,
             _phantom: Default::default(),
         }
     }
+
+    /// Also lets through the public paths of the given settings, as they are
+    /// when each request arrives
+    pub fn set_http_settings(mut self, http_settings: ReloadableConfig<HttpSettings>) -> Self {
+        self.http_settings = Some(http_settings);
+        self
+    }
+
+    /// Lets through requests that match no route, so that the fallback can
+    /// respond to them
+    pub fn set_public_fallback(mut self, public_fallback: bool) -> Self {
+        self.public_fallback = public_fallback;
+        self
+    }
+
+    /// Records requests with a wrong API token, rejecting every request from
+    /// IPs that gave too many of them
+    pub fn set_audit(mut self, audit: Arc<AuthAudit>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Also accepts the given tokens, for the paths in their scopes
+    pub fn add_scoped_tokens(mut self, scoped_tokens: &[ScopedToken]) -> Result<Self> {
+        let tokens = Arc::make_mut(&mut self.tokens);
+        for scoped in scoped_tokens {
+            tokens.push(scoped.compile()?);
+        }
+        Ok(self)
+    }
+
+    /// The token that `given` is, comparing against every token so that the
+    /// time taken does not reveal which one matched
+    fn find_token(&self, given: &[u8]) -> Option<&Token> {
+        let mut found = None;
+        for token in self.tokens.iter() {
+            if constant_time_eq(given, &token.token) & found.is_none() {
+                found = Some(token);
+            }
+        }
+        found
+    }
+
+    fn is_public(&self, path: &str) -> bool {
+        self.public_paths.is_match(path)
+            || self
+                .http_settings
+                .as_ref()
+                .map_or(false, |x| x.get().is_public(path))
+    }
 }
 
-impl<ReqBody, ResBody> AuthorizeRequest<ReqBody> for BearerAuth<ResBody>
+// Only implemented for the body of axum responses, so that rejections can have a body
+impl<ReqBody> AuthorizeRequest<ReqBody> for BearerAuth<BoxBody>
 where
     ReqBody: HttpBody,
-    ResBody: HttpBody + Default,
 {
-    type ResponseBody = ResBody;
+    type ResponseBody = BoxBody;
 
     fn authorize(
         &mut self,
@@ -49,17 +179,37 @@ where
     ) -> Result<(), Response<Self::ResponseBody>> {
         macro_rules! unauthorized {
             () => {
-                return Err(Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .body(Default::default())
-                    .unwrap())
+                return Err(Rejection::new(
+                    StatusCode::UNAUTHORIZED,
+                    "unauthorized",
+                    "Missing or invalid API token",
+                )
+                .negotiate(request.headers())
+                .into_response())
             };
         }
-        if self.public_paths.is_match(request.uri().path()) {
+        if self.is_public(request.uri().path()) {
+            return Ok(());
+        }
+        // Only the fallback is routed without a matched path
+        if self.public_fallback && request.extensions().get::<MatchedPath>().is_none() {
             return Ok(());
         }
 
-        match request.headers().get("Authorization") {
+        let audit = self.audit.as_deref().zip(client_ip(request));
+        if let Some((audit, ip)) = audit {
+            if audit.locked_out(ip).is_some() {
+                return Err(Rejection::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "locked_out",
+                    "Too many invalid API tokens, try again later",
+                )
+                .negotiate(request.headers())
+                .into_response());
+            }
+        }
+
+        let given = match request.headers().get("Authorization") {
             Some(header) => {
                 let header = match header.to_str() {
                     Ok(x) => x,
@@ -70,25 +220,50 @@ where
                     unauthorized!()
                 }
 
-                let token = header.split_at(7).1;
+                header.split_at(7).1
+            }
+            None => match request
+                .uri()
+                .query()
+                .and_then(|query| query.split('&').find_map(|x| x.strip_prefix("api_token=")))
+            {
+                Some(x) => x,
+                None => unauthorized!(),
+            },
+        };
 
-                if constant_time_eq(token.as_bytes(), self.api_token.as_bytes()) {
-                    Ok(())
-                } else {
-                    unauthorized!()
-                }
+        let Some(token) = self.find_token(given.as_bytes()) else {
+            if let Some((audit, ip)) = audit {
+                audit.record_failure(ip, AuthFailure::BearerToken, request.uri().path());
             }
-            None => {
-                if let Some(query) = request.uri().query() {
-                    if query.contains(&format!(
-                        "api_token={}",
-                        self.api_token.to_str().expect("API Token to be utf-8")
-                    )) {
-                        return Ok(());
-                    }
-                }
-                unauthorized!()
+            unauthorized!()
+        };
+        if token.expires.map_or(false, |expires| expires <= Utc::now()) {
+            warn!(
+                target: log_targets::SECURITY,
+                "Expired API token {} was used for {}",
+                token.name,
+                request.uri().path()
+            );
+            unauthorized!()
+        }
+        if let Some(scope) = &token.scope {
+            if !scope.is_match(request.uri().path()) {
+                warn!(
+                    target: log_targets::SECURITY,
+                    "API token {} was used for {}, which is out of its scope",
+                    token.name,
+                    request.uri().path()
+                );
+                return Err(Rejection::new(
+                    StatusCode::FORBIDDEN,
+                    "out_of_scope",
+                    "This API token cannot access this path",
+                )
+                .negotiate(request.headers())
+                .into_response());
             }
         }
+        Ok(())
     }
 }
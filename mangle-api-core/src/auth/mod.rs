@@ -1,9 +1,19 @@
+pub mod audit;
 pub mod bearer;
 #[cfg(feature = "oauth2")]
 pub mod oauth2;
 #[cfg(feature = "openid")]
+pub mod oidc_providers;
+#[cfg(feature = "openid")]
 pub mod openid;
+pub mod roles;
+pub mod signed_requests;
 pub mod token;
+pub mod totp;
+#[cfg(feature = "webauthn")]
+pub mod webauthn;
 
 #[cfg(any(feature = "oauth2", feature = "openid"))]
 pub mod auth_pages;
+#[cfg(any(feature = "oauth2", feature = "openid", feature = "webauthn"))]
+pub(crate) mod pending;
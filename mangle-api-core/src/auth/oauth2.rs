@@ -1,11 +1,11 @@
-use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+use std::{future::Future, net::IpAddr, sync::Arc, time::Duration};
 
 use log::warn;
-use parking_lot::Mutex;
 
 use anyhow::Result;
 use axum::{
     extract::{FromRef, Query, State},
+    http::{header::ACCEPT_LANGUAGE, HeaderMap},
     response::Html,
 };
 use oauth2::{
@@ -67,6 +67,7 @@ fn new_oauth_client(
 pub struct OAuth<const PKCE: bool> {
     oauth_state: OAuthState,
     client: Arc<BasicClient>,
+    return_url: Option<String>,
 }
 
 impl<const PKCE: bool> OAuth<PKCE> {
@@ -89,18 +90,30 @@ impl<const PKCE: bool> OAuth<PKCE> {
                 redirect_url,
                 revocation_url,
             )),
+            return_url: None,
         }
     }
 
+    /// Sets the `{{return_url}}` of the auth pages of authorizations with
+    /// this provider
+    pub fn set_return_url(mut self, return_url: impl Into<String>) -> Self {
+        self.return_url = Some(return_url.into());
+        self
+    }
+
     /// Initiates an OAuth attempt with the given scopes
     ///
     /// Returns a tuple with the authorization Url to give to the user, and a
     /// future that resolves to Some(token) where token is the OAuth token, or
     /// None if authentication timed out or failed
+    ///
+    /// Returns None if too many authentications are already pending, in total
+    /// or for the IP of the client, if it is known
     pub fn initiate_auth(
         &self,
         scopes: impl IntoIterator<Item = impl Into<String>>,
-    ) -> (Url, impl Future<Output = Option<OAuthToken>>) {
+        ip: Option<IpAddr>,
+    ) -> Option<(Url, impl Future<Output = Option<OAuthToken>>)> {
         let mut auth_request = self.client.authorize_url(CsrfToken::new_random);
 
         for scope in scopes {
@@ -125,10 +138,14 @@ impl<const PKCE: bool> OAuth<PKCE> {
         let oauth_state = self.oauth_state.clone();
         let untracker = oauth_state.track_session(
             csrf_token,
-            pkce_code_verifier,
-            self.client.clone(),
-            ready_sender,
-        );
+            ip,
+            PendingSession {
+                ready_sender,
+                pkce_code_verifier,
+                return_url: self.return_url.clone(),
+                client: self.client.clone(),
+            },
+        )?;
 
         let fut = async move {
             let _untracker = untracker;
@@ -143,7 +160,7 @@ impl<const PKCE: bool> OAuth<PKCE> {
             }
         };
 
-        (authorize_url, fut)
+        Some((authorize_url, fut))
     }
 }
 
@@ -152,12 +169,20 @@ pub type OAuthToken = StandardTokenResponse<EmptyExtraTokenFields, BasicTokenTyp
 struct PendingSession {
     ready_sender: Sender<OAuthToken>,
     pkce_code_verifier: Option<PkceCodeVerifier>,
+    return_url: Option<String>,
     client: Arc<BasicClient>,
 }
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct OAuthState {
-    pending_auths: Arc<Mutex<HashMap<String, PendingSession>>>,
+    pending_auths: Arc<PendingAuths<PendingSession>>,
+    audit: Option<Arc<AuthAudit>>,
+}
+
+impl Default for OAuthState {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PENDING_AUTHS)
+    }
 }
 
 struct Untracker {
@@ -172,30 +197,56 @@ impl Drop for Untracker {
 }
 
 impl OAuthState {
+    /// Allows at most `max_pending_auths` authorizations to be pending at once,
+    /// so that abandoned authorizations cannot exhaust memory
+    pub fn new(max_pending_auths: usize) -> Self {
+        Self {
+            pending_auths: Arc::new(PendingAuths::new(max_pending_auths, MAX_AUTH_WAIT_TIME)),
+            audit: None,
+        }
+    }
+
+    /// Records redirects with an unknown state or an invalid code, showing the
+    /// invalid page to IPs that had too many of them
+    pub fn set_audit(mut self, audit: Arc<AuthAudit>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    fn locked_out(&self, ip: Option<IpAddr>) -> bool {
+        self.audit
+            .as_deref()
+            .zip(ip)
+            .map_or(false, |(audit, ip)| audit.locked_out(ip).is_some())
+    }
+
+    fn record_failure(&self, ip: Option<IpAddr>, detail: &str) {
+        if let Some((audit, ip)) = self.audit.as_deref().zip(ip) {
+            audit.record_failure(ip, AuthFailure::OAuthExchange, detail);
+        }
+    }
+
     fn track_session(
         &self,
         csrf_token: CsrfToken,
-        pkce_code_verifier: Option<PkceCodeVerifier>,
-        client: Arc<BasicClient>,
-        ready_sender: Sender<OAuthToken>,
-    ) -> Untracker {
-        self.pending_auths.lock().insert(
-            csrf_token.secret().clone(),
-            PendingSession {
-                ready_sender,
-                pkce_code_verifier,
-                client,
-            },
-        );
+        ip: Option<IpAddr>,
+        session: PendingSession,
+    ) -> Option<Untracker> {
+        if !self
+            .pending_auths
+            .insert(csrf_token.secret().clone(), ip, session)
+        {
+            return None;
+        }
 
-        Untracker {
+        Some(Untracker {
             oauth_state: self.clone(),
             csrf_token,
-        }
+        })
     }
 
     fn untrack_session(&self, csrf_token: &CsrfToken) {
-        let _ = self.pending_auths.lock().remove(csrf_token.secret());
+        let _ = self.pending_auths.remove(csrf_token.secret());
     }
 
     async fn verify_auth(
@@ -203,12 +254,24 @@ impl OAuthState {
         auth_code: AuthorizationCode,
         csrf_token: CsrfToken,
         pages: AuthPages,
+        ip: Option<IpAddr>,
     ) -> Html<String> {
-        let pending = if let Some(x) = self.pending_auths.lock().remove(csrf_token.secret()) {
+        if self.locked_out(ip) {
+            return Html(pages.render_invalid(&PageContext::default()));
+        }
+        let pending = if let Some(x) = self.pending_auths.remove(csrf_token.secret()) {
             x
         } else {
-            return Html(pages.late.into_owned());
+            // Usually a late or repeated login rather than an attack, and
+            // states cannot be guessed anyway
+            return Html(pages.render_late(&PageContext::default()));
         };
+        // Logged with every error so that users can quote it from the page
+        let error_code = new_error_code();
+        let mut context = PageContext::default().set("error_code", &error_code);
+        if let Some(return_url) = &pending.return_url {
+            context = context.set("return_url", return_url);
+        }
 
         let client = pending.client;
 
@@ -226,17 +289,21 @@ impl OAuthState {
                         // TODO Provide more info
                         warn!(
                             target: log_targets::SUSPICIOUS_SECURITY,
-                            "Received bad gauth response: {x:?}"
+                            "Received bad gauth response ({error_code}): {x:?}"
                         );
-                        Html(pages.invalid.into_owned())
+                        self.record_failure(ip, "invalid grant");
+                        Html(pages.render_invalid(&context))
+                    }
+                    e => {
+                        warn!("Token request failed ({error_code}): {e:?}");
+                        Html(pages.render_internal_error(&context))
                     }
-                    _ => Html(pages.internal_error.into_owned()),
                 };
             }
         };
 
         let _ = pending.ready_sender.send(token);
-        Html(pages.success.into_owned())
+        Html(pages.render_success(&context))
     }
 }
 
@@ -260,9 +327,17 @@ pub async fn oauth_redirect_handler(
     Query(AuthRedirectParams { state, code }): Query<AuthRedirectParams>,
     State(oauth_state): State<OAuthState>,
     State(pages): State<AuthPages>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
 ) -> Html<String> {
+    let pages = pages.localize(headers.get(ACCEPT_LANGUAGE).and_then(|x| x.to_str().ok()));
     oauth_state
-        .verify_auth(AuthorizationCode::new(code), CsrfToken::new(state), pages)
+        .verify_auth(
+            AuthorizationCode::new(code),
+            CsrfToken::new(state),
+            pages,
+            ip,
+        )
         .await
 }
 
@@ -275,7 +350,11 @@ macro_rules! oauth_redirect {
 
 pub use oauth_redirect;
 
-use super::auth_pages::AuthPages;
+use super::{
+    audit::{AuthAudit, AuthFailure, ClientIp},
+    auth_pages::{new_error_code, AuthPages, PageContext},
+    pending::{PendingAuths, DEFAULT_MAX_PENDING_AUTHS},
+};
 
 pub mod google {
     use std::{fs::read_to_string, path::Path};
@@ -371,3 +450,79 @@ pub mod github {
         )))
     }
 }
+
+pub mod discord {
+    use std::{fs::read_to_string, path::Path};
+
+    use serde_json::from_str;
+
+    use super::*;
+
+    /// The scopes needed for `fetch_discord_user` to return the email
+    pub const DISCORD_IDENTITY_SCOPES: [&str; 2] = ["identify", "email"];
+
+    #[derive(Clone)]
+    pub struct DiscordOAuth(pub OAuth<false>);
+
+    pub trait DiscordOAuthContainer {
+        fn get_discord_auth_state(&self) -> &DiscordOAuth;
+    }
+
+    impl<T: DiscordOAuthContainer> FromRef<T> for DiscordOAuth {
+        fn from_ref(input: &T) -> Self {
+            input.get_discord_auth_state().clone()
+        }
+    }
+
+    /// A Discord user, as returned by the Discord API
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct DiscordUser {
+        pub id: String,
+        pub username: String,
+        /// The display name, if the user set one
+        #[serde(default = "Default::default")]
+        pub global_name: Option<String>,
+        /// Only returned with the email scope
+        #[serde(default = "Default::default")]
+        pub email: Option<String>,
+        /// Whether the email was verified, only returned with the email scope
+        #[serde(default = "Default::default")]
+        pub verified: Option<bool>,
+    }
+
+    /// Fetches the user that granted the token
+    pub async fn fetch_discord_user(token: &OAuthToken) -> Result<DiscordUser> {
+        Ok(reqwest::Client::new()
+            .get("https://discord.com/api/users/@me")
+            .bearer_auth(token.access_token().secret())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub fn new_discord_oauth_from_file(
+        filename: impl AsRef<Path>,
+        redirect_url: impl Into<String>,
+        oauth_state: OAuthState,
+    ) -> Result<DiscordOAuth> {
+        #[derive(Deserialize, Debug)]
+        struct ClientSecret {
+            client_id: String,
+            client_secret: String,
+        }
+
+        let secrets: ClientSecret = from_str(&read_to_string(filename)?)?;
+
+        Ok(DiscordOAuth(OAuth::new(
+            "https://discord.com/oauth2/authorize".to_string(),
+            "https://discord.com/api/oauth2/token".to_string(),
+            secrets.client_id,
+            secrets.client_secret,
+            Some("https://discord.com/api/oauth2/token/revoke".to_string()),
+            redirect_url.into(),
+            oauth_state,
+        )))
+    }
+}
@@ -0,0 +1,123 @@
+use std::{collections::HashMap, ops::Deref};
+
+use anyhow::{Context, Error, Result};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use super::openid::{OIDCState, OIDC};
+use crate::redact::redact;
+
+/// Who issues the identities of a provider
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OIDCIssuer {
+    Google,
+    /// The client secret must be the JWT signed with the key of the Services ID.
+    /// `openid::apple` signs it from the key instead
+    Apple,
+    /// The tenant must be an ID rather than `common`, as the tokens of common
+    /// tenants do not carry it as their issuer
+    Microsoft {
+        tenant: String,
+    },
+    /// Any other provider, by the URL its discovery document is under
+    Url(String),
+}
+
+impl OIDCIssuer {
+    fn url(&self) -> Result<Url> {
+        let url = match self {
+            OIDCIssuer::Google => "https://accounts.google.com".to_string(),
+            OIDCIssuer::Apple => "https://appleid.apple.com".to_string(),
+            OIDCIssuer::Microsoft { tenant } => {
+                format!("https://login.microsoftonline.com/{tenant}/v2.0")
+            }
+            OIDCIssuer::Url(url) => url.clone(),
+        };
+        Url::parse(&url).context(format!("parsing issuer url {url}"))
+    }
+}
+
+/// A provider that users can log in with, as read from a config
+///
+/// Users are identified by the email that the provider asserts, so only
+/// providers that verify emails should be configured
+#[derive(Deserialize, Serialize, Clone)]
+pub struct OIDCProviderConfig {
+    pub issuer: OIDCIssuer,
+    pub client_id: String,
+    #[serde(serialize_with = "redact")]
+    pub client_secret: String,
+    #[serde(default = "Default::default")]
+    pub pkce: bool,
+    /// Whether to request the email from the userinfo endpoint if the ID token
+    /// does not carry it
+    #[serde(default = "Default::default")]
+    pub userinfo_fallback: bool,
+}
+
+/// The providers that users can pick to log in with, by name
+///
+/// Every provider shares the same `OIDCState`, so the redirects of all of them
+/// are handled by `openid_redirect`
+pub struct OIDCProviderRegistry<S: Deref<Target = OIDCState> + Clone> {
+    providers: HashMap<String, OIDC<S>>,
+}
+
+impl<S: Deref<Target = OIDCState> + Clone> Default for OIDCProviderRegistry<S> {
+    fn default() -> Self {
+        Self {
+            providers: Default::default(),
+        }
+    }
+}
+
+impl<S: Deref<Target = OIDCState> + Clone> OIDCProviderRegistry<S> {
+    /// Discovers every configured provider, all of which redirect to the same URL
+    pub async fn from_configs(
+        configs: HashMap<String, OIDCProviderConfig>,
+        redirect_url: &str,
+        oidc_state: S,
+    ) -> Result<Self> {
+        let mut registry = Self::default();
+        for (name, config) in configs {
+            let oidc = OIDC::new(
+                config.client_id,
+                config.client_secret,
+                redirect_url.to_string(),
+                config.issuer.url()?,
+                oidc_state.clone(),
+            )
+            .await
+            .context(format!("discovering oidc provider {name}"))?
+            .set_pkce(config.pkce)
+            .set_userinfo_fallback(config.userinfo_fallback)
+            .set_form_post(matches!(config.issuer, OIDCIssuer::Apple));
+            registry = registry.add_provider(name, oidc)?;
+        }
+        Ok(registry)
+    }
+
+    /// Adds a provider, whose authentications carry its name in their state
+    /// parameter
+    ///
+    /// Names cannot contain dots, which separate them from the rest of the state
+    pub fn add_provider(mut self, name: impl Into<String>, oidc: OIDC<S>) -> Result<Self> {
+        let name = name.into();
+        if name.contains('.') {
+            return Err(Error::msg(format!(
+                "OIDC provider name {name} cannot contain dots"
+            )));
+        }
+        self.providers.insert(name.clone(), oidc.set_provider(name));
+        Ok(self)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OIDC<S>> {
+        self.providers.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.providers.keys().map(String::as_str)
+    }
+}
@@ -1,22 +1,34 @@
-use std::{collections::HashMap, future::Future, ops::Deref, sync::Arc, time::Duration};
+use std::{future::Future, net::IpAddr, ops::Deref, sync::Arc, time::Duration};
 
+use anyhow::Context;
 use axum::{
     body::HttpBody,
     extract::{FromRef, Query, State},
+    http::{header::ACCEPT_LANGUAGE, HeaderMap},
     response::Html,
     routing::MethodRouter,
+    BoxError, Form,
 };
-use log::error;
-use openid::{error::ClientError, DiscoveredClient, Options};
-use parking_lot::Mutex;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::{future::BoxFuture, FutureExt};
+use log::{error, warn};
+use openid::{error::ClientError, Bearer, DiscoveredClient, OAuth2Error, Options, Provider};
+use parking_lot::RwLock;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use reqwest::Url;
+use sha2::{Digest, Sha256};
 
 pub use openid::Userinfo;
 
 /// How much time to wait for authentication to be granted by OpenID
-const MAX_AUTH_WAIT_TIME: Duration = Duration::from_secs(180);
+pub const MAX_AUTH_WAIT_TIME: Duration = Duration::from_secs(180);
 const CSRF_TOKEN_SIZE: usize = 32;
+/// Within the 43 to 128 characters allowed by RFC 7636
+const PKCE_CODE_VERIFIER_SIZE: usize = 64;
+/// How much time to wait for authentication to be granted on another device,
+/// even if the device code lasts longer
+pub const MAX_DEVICE_AUTH_WAIT_TIME: Duration = Duration::from_secs(900);
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
 
 async fn new_oidc_client(
     client_id: String,
@@ -27,14 +39,170 @@ async fn new_oidc_client(
     DiscoveredClient::discover(client_id, client_secret, Some(redirect_url), issuer_url).await
 }
 
+/// Exchanges the authorization code like `Client::request_token`, but proves
+/// that this client started the authentication with the PKCE code verifier
+async fn request_token_with_pkce(
+    client: &DiscoveredClient,
+    auth_code: &str,
+    code_verifier: &str,
+) -> Result<Bearer, ClientError> {
+    let mut params = vec![
+        ("grant_type", "authorization_code"),
+        ("code", auth_code),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(redirect_uri) = &client.redirect_uri {
+        params.push(("redirect_uri", redirect_uri.as_str()));
+    }
+    let json: serde_json::Value = client
+        .http_client
+        .post(client.provider.token_uri().clone())
+        .basic_auth(&client.client_id, Some(&client.client_secret))
+        .form(&params)
+        .send()
+        .await?
+        .json()
+        .await?;
+    if let Ok(error) = serde_json::from_value::<OAuth2Error>(json.clone()) {
+        return Err(error.into());
+    }
+    Ok(serde_json::from_value(json)?)
+}
+
+/// Removes the email from the userinfo unless the provider verified it
+///
+/// Accounts are found by their email, so an unverified email would let anyone
+/// who can set it on an account of the provider log in as its owner
+fn drop_unverified_email(userinfo: &mut Userinfo, provider: &str) {
+    if userinfo.email.is_some() && !userinfo.email_verified {
+        warn!(
+            target: log_targets::SECURITY,
+            "Ignored an unverified email from {provider}"
+        );
+        userinfo.email = None;
+    }
+}
+
+/// Decodes and validates the ID token in the response of the token endpoint
+///
+/// Unverified emails are removed
+fn userinfo_from_bearer(client: &DiscoveredClient, bearer: Bearer) -> anyhow::Result<Userinfo> {
+    let mut token = openid::Token::from(bearer)
+        .id_token
+        .context("token response without an id token")?;
+    client
+        .decode_token(&mut token)
+        .context("decoding openid token")?;
+    client
+        .validate_token(&token, None, None)
+        .context("validating openid token")?;
+    let mut userinfo = token.payload().unwrap().userinfo.clone();
+    drop_unverified_email(&mut userinfo, "a device login");
+    Ok(userinfo)
+}
+
+/// The response of the device authorization endpoint
+#[derive(Deserialize)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    // Google calls it verification_url
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    expires_in: u64,
+    #[serde(default = "device_poll_interval")]
+    interval: u64,
+}
+
+fn device_poll_interval() -> u64 {
+    5
+}
+
+/// What the user must do on another device to authenticate, which should be
+/// shown to them
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceAuthorization {
+    pub verification_url: String,
+    pub user_code: String,
+    /// How many seconds the user has to enter the code
+    pub expires_in: u64,
+}
+
+/// Polls the token endpoint until the user grants or denies the authentication,
+/// or the deadline passes
+async fn poll_device_token(
+    client: Arc<DiscoveredClient>,
+    device_code: String,
+    mut interval: Duration,
+    deadline: Instant,
+) -> Option<Userinfo> {
+    loop {
+        sleep(interval).await;
+        if Instant::now() >= deadline {
+            return None;
+        }
+        let response = async {
+            client
+                .http_client
+                .post(client.provider.token_uri().clone())
+                .form(&[
+                    ("client_id", client.client_id.as_str()),
+                    ("client_secret", client.client_secret.as_str()),
+                    ("device_code", device_code.as_str()),
+                    ("grant_type", DEVICE_CODE_GRANT_TYPE),
+                ])
+                .send()
+                .await?
+                .json::<serde_json::Value>()
+                .await
+        }
+        .await;
+        let json = match response {
+            Ok(x) => x,
+            Err(e) => {
+                // Polled again, as the user may still finish
+                warn!(target: "openid", "Failed to poll for device token: {e}");
+                continue;
+            }
+        };
+        match json.get("error").and_then(|x| x.as_str()) {
+            Some("authorization_pending") => {}
+            Some("slow_down") => interval += Duration::from_secs(5),
+            // The user denied access or the code expired
+            Some(_) => return None,
+            None => {
+                let userinfo = serde_json::from_value(json)
+                    .context("parsing device token response")
+                    .and_then(|bearer| userinfo_from_bearer(&client, bearer));
+                return match userinfo {
+                    Ok(x) => Some(x),
+                    Err(e) => {
+                        error!(target: "openid", "{e:?}");
+                        None
+                    }
+                };
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct OIDC<S: Deref<Target = OIDCState> + Clone> {
     oidc_state: S,
-    client: Arc<DiscoveredClient>,
+    /// Shared by every clone, so that a new client secret applies to all of them
+    client: Arc<RwLock<Arc<DiscoveredClient>>>,
+    pkce: bool,
+    device_authorization_url: Option<Url>,
+    form_post: bool,
+    userinfo_fallback: bool,
+    return_url: Option<String>,
+    /// The name of the provider in a `OIDCProviderRegistry`
+    provider: Option<String>,
 }
 
 impl<S: Deref<Target = OIDCState> + Clone> OIDC<S> {
-    async fn new(
+    /// Discovers the provider at the issuer URL
+    pub async fn new(
         client_id: String,
         client_secret: String,
         redirect_url: String,
@@ -43,26 +211,156 @@ impl<S: Deref<Target = OIDCState> + Clone> OIDC<S> {
     ) -> Result<Self, openid::error::Error> {
         Ok(Self {
             oidc_state,
-            client: Arc::new(
+            client: Arc::new(RwLock::new(Arc::new(
                 new_oidc_client(client_id, client_secret, redirect_url, issuer_url).await?,
-            ),
+            ))),
+            pkce: false,
+            device_authorization_url: None,
+            form_post: false,
+            userinfo_fallback: false,
+            return_url: None,
+            provider: None,
         })
     }
 
+    fn client(&self) -> Arc<DiscoveredClient> {
+        self.client.read().clone()
+    }
+
+    /// Discovers the provider again with a new client secret, which is used by
+    /// every authentication that starts after this returns
+    pub async fn set_client_secret(
+        &self,
+        client_secret: String,
+    ) -> Result<(), openid::error::Error> {
+        let client = self.client();
+        let refreshed = new_oidc_client(
+            client.client_id.clone(),
+            client_secret,
+            client.redirect_uri.clone().unwrap_or_default(),
+            client.config().issuer.clone(),
+        )
+        .await?;
+        *self.client.write() = Arc::new(refreshed);
+        Ok(())
+    }
+
+    /// Asks the provider to post the authorization code to the redirect URL
+    /// instead, which Apple requires when requesting scopes
+    pub fn set_form_post(mut self, form_post: bool) -> Self {
+        self.form_post = form_post;
+        self
+    }
+
+    /// Requests the userinfo endpoint of the provider when the ID token does
+    /// not carry an email, as some providers only put the email there
+    pub fn set_userinfo_fallback(mut self, userinfo_fallback: bool) -> Self {
+        self.userinfo_fallback = userinfo_fallback;
+        self
+    }
+
+    /// Sets the `{{return_url}}` of the auth pages of authentications with
+    /// this provider
+    pub fn set_return_url(mut self, return_url: impl Into<String>) -> Self {
+        self.return_url = Some(return_url.into());
+        self
+    }
+
+    pub(super) fn set_provider(mut self, provider: String) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Allows devices that cannot open a browser, such as consoles and TVs, to
+    /// authenticate with `initiate_device_auth`
+    ///
+    /// The client must be allowed to use the device flow by the provider
+    pub fn set_device_authorization_url(mut self, url: Url) -> Self {
+        self.device_authorization_url = Some(url);
+        self
+    }
+
+    /// Initiates an authentication that the user finishes on another device,
+    /// as described in RFC 8628
+    ///
+    /// Returns what to show to the user, and a future that polls the provider
+    /// until it resolves to Some(userinfo), or None if authentication timed
+    /// out, failed or was denied
+    pub async fn initiate_device_auth(
+        &self,
+        scopes: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> anyhow::Result<(DeviceAuthorization, BoxFuture<'static, Option<Userinfo>>)> {
+        let url = self
+            .device_authorization_url
+            .clone()
+            .context("no device authorization url was set")?;
+        let scope = scopes
+            .into_iter()
+            .map(|x| x.as_ref().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let client = self.client();
+        let response: DeviceCodeResponse = client
+            .http_client
+            .post(url)
+            .form(&[
+                ("client_id", client.client_id.as_str()),
+                ("scope", scope.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let wait_time = Duration::from_secs(response.expires_in).min(MAX_DEVICE_AUTH_WAIT_TIME);
+        let fut = poll_device_token(
+            client,
+            response.device_code,
+            Duration::from_secs(response.interval),
+            Instant::now() + wait_time,
+        );
+
+        Ok((
+            DeviceAuthorization {
+                verification_url: response.verification_uri,
+                user_code: response.user_code,
+                expires_in: wait_time.as_secs(),
+            },
+            fut.boxed(),
+        ))
+    }
+
+    /// Protects authentications with PKCE, so that an intercepted authorization
+    /// code cannot be exchanged by anyone else, such as in native app redirect flows
+    pub fn set_pkce(mut self, pkce: bool) -> Self {
+        self.pkce = pkce;
+        self
+    }
+
     /// Initiates an OAuth attempt with the given scopes
     ///
     /// Returns a tuple with the authorization Url to give to the user, and a
     /// future that resolves to Some(token) where token is the OAuth token, or
     /// None if authentication timed out or failed
+    ///
+    /// Returns None if too many authentications are already pending, in total
+    /// or for the IP of the client, if it is known
     pub fn initiate_auth(
         &self,
         scopes: impl IntoIterator<Item = impl AsRef<str>>,
-    ) -> (Url, impl Future<Output = Option<Userinfo>>) {
-        let csrf_token: String = thread_rng()
+        ip: Option<IpAddr>,
+    ) -> Option<(Url, impl Future<Output = Option<Userinfo>>)> {
+        let mut csrf_token: String = thread_rng()
             .sample_iter(&Alphanumeric)
             .take(CSRF_TOKEN_SIZE)
             .map(char::from)
             .collect();
+        // Tells the redirect handler which provider the authentication is for
+        if let Some(provider) = &self.provider {
+            csrf_token = format!("{provider}.{csrf_token}");
+        }
 
         let mut scope_str = String::new();
 
@@ -77,16 +375,44 @@ impl<S: Deref<Target = OIDCState> + Clone> OIDC<S> {
             state: Some(csrf_token.clone()),
             ..Default::default()
         };
-        let authorize_url = self.client.auth_url(&options);
+        let client = self.client();
+        let mut authorize_url = client.auth_url(&options);
+        if self.form_post {
+            authorize_url
+                .query_pairs_mut()
+                .append_pair("response_mode", "form_post");
+        }
+
+        let pkce_code_verifier = self.pkce.then(|| {
+            let code_verifier: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(PKCE_CODE_VERIFIER_SIZE)
+                .map(char::from)
+                .collect();
+            authorize_url
+                .query_pairs_mut()
+                .append_pair(
+                    "code_challenge",
+                    &URL_SAFE_NO_PAD.encode(Sha256::digest(&code_verifier)),
+                )
+                .append_pair("code_challenge_method", "S256");
+            code_verifier
+        });
 
         let (ready_sender, receiver) = channel();
 
         let untracker = track_session(
             self.oidc_state.clone(),
             csrf_token,
-            self.client.clone(),
-            ready_sender,
-        );
+            ip,
+            PendingSession {
+                ready_sender,
+                pkce_code_verifier,
+                userinfo_fallback: self.userinfo_fallback,
+                return_url: self.return_url.clone(),
+                client,
+            },
+        )?;
 
         let fut = async move {
             let _untracker = untracker;
@@ -101,18 +427,27 @@ impl<S: Deref<Target = OIDCState> + Clone> OIDC<S> {
             }
         };
 
-        (authorize_url, fut)
+        Some((authorize_url, fut))
     }
 }
 
 struct PendingSession {
     ready_sender: Sender<Userinfo>,
+    pkce_code_verifier: Option<String>,
+    userinfo_fallback: bool,
+    return_url: Option<String>,
     client: Arc<DiscoveredClient>,
 }
 
-#[derive(Default)]
 pub struct OIDCState {
-    pending_auths: Mutex<HashMap<String, PendingSession>>,
+    pending_auths: PendingAuths<PendingSession>,
+    audit: Option<Arc<AuthAudit>>,
+}
+
+impl Default for OIDCState {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PENDING_AUTHS)
+    }
 }
 
 struct Untracker<S: Deref<Target = OIDCState>> {
@@ -129,26 +464,54 @@ impl<S: Deref<Target = OIDCState>> Drop for Untracker<S> {
 fn track_session<S: Deref<Target = OIDCState>>(
     oauth_state: S,
     csrf_token: String,
-    client: Arc<DiscoveredClient>,
-    ready_sender: Sender<Userinfo>,
-) -> Untracker<S> {
-    oauth_state.pending_auths.lock().insert(
-        csrf_token.clone(),
-        PendingSession {
-            ready_sender,
-            client,
-        },
-    );
+    ip: Option<IpAddr>,
+    session: PendingSession,
+) -> Option<Untracker<S>> {
+    if !oauth_state
+        .pending_auths
+        .insert(csrf_token.clone(), ip, session)
+    {
+        return None;
+    }
 
-    Untracker {
+    Some(Untracker {
         oauth_state,
         csrf_token,
-    }
+    })
 }
 
 impl OIDCState {
+    /// Allows at most `max_pending_auths` authentications to be pending at once,
+    /// so that abandoned authentications cannot exhaust memory
+    pub fn new(max_pending_auths: usize) -> Self {
+        Self {
+            pending_auths: PendingAuths::new(max_pending_auths, MAX_AUTH_WAIT_TIME),
+            audit: None,
+        }
+    }
+
+    /// Records redirects with an unknown state or an invalid code or token,
+    /// showing the invalid page to IPs that had too many of them
+    pub fn set_audit(mut self, audit: Arc<AuthAudit>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    fn locked_out(&self, ip: Option<IpAddr>) -> bool {
+        self.audit
+            .as_deref()
+            .zip(ip)
+            .map_or(false, |(audit, ip)| audit.locked_out(ip).is_some())
+    }
+
+    fn record_failure(&self, ip: Option<IpAddr>, detail: &str) {
+        if let Some((audit, ip)) = self.audit.as_deref().zip(ip) {
+            audit.record_failure(ip, AuthFailure::OAuthExchange, detail);
+        }
+    }
+
     fn untrack_session(&self, csrf_token: &str) {
-        let _ = self.pending_auths.lock().remove(csrf_token);
+        let _ = self.pending_auths.remove(csrf_token);
     }
 
     async fn verify_auth(
@@ -156,45 +519,101 @@ impl OIDCState {
         auth_code: String,
         csrf_token: String,
         pages: AuthPages,
+        ip: Option<IpAddr>,
     ) -> Html<String> {
-        let pending = if let Some(x) = self.pending_auths.lock().remove(&csrf_token) {
+        if self.locked_out(ip) {
+            return Html(pages.render_invalid(&PageContext::default()));
+        }
+        let pending = if let Some(x) = self.pending_auths.remove(&csrf_token) {
             x
         } else {
-            return Html(pages.late.into_owned());
+            // Usually a late or repeated login rather than an attack, and
+            // states cannot be guessed anyway
+            return Html(pages.render_late(&PageContext::default()));
         };
+        // The pending session already has the client of the provider, which
+        // is only named for the logs
+        let provider = csrf_token.rsplit_once('.').map_or("default", |x| x.0);
+        // Logged with every error so that users can quote it from the page
+        let error_code = new_error_code();
+        let mut context = PageContext::default().set("error_code", &error_code);
+        if let Some(return_url) = &pending.return_url {
+            context = context.set("return_url", return_url);
+        }
 
         let client = pending.client;
 
-        let mut token = match client.request_token(&auth_code).await {
-            Ok(x) => match openid::Token::from(x).id_token {
-                Some(x) => x,
-                None => return Html(pages.internal_error.into_owned()),
-            },
+        let result = match &pending.pkce_code_verifier {
+            Some(code_verifier) => {
+                request_token_with_pkce(&client, &auth_code, code_verifier).await
+            }
+            None => client.request_token(&auth_code).await,
+        };
+        let mut token = match result {
+            Ok(x) => openid::Token::from(x),
             Err(e) => {
+                warn!(target: "openid", "Token request to {provider} failed ({error_code}): {e:?}");
                 return match e {
                     ClientError::OAuth2(e) => match e.error {
-                        openid::OAuth2ErrorCode::InvalidGrant => Html(pages.invalid.into_owned()),
-                        _ => Html(pages.internal_error.into_owned()),
+                        openid::OAuth2ErrorCode::InvalidGrant => {
+                            self.record_failure(ip, &format!("invalid grant for {provider}"));
+                            Html(pages.render_invalid(&context))
+                        }
+                        _ => Html(pages.render_internal_error(&context)),
                     },
-                    _ => Html(pages.internal_error.into_owned()),
-                }
+                    _ => Html(pages.render_internal_error(&context)),
+                };
+            }
+        };
+        let id_token = match &mut token.id_token {
+            Some(x) => x,
+            None => {
+                error!(target: "openid", "No id token from {provider} ({error_code})");
+                return Html(pages.render_internal_error(&context));
             }
         };
 
-        if let Err(e) = client.decode_token(&mut token) {
+        if let Err(e) = client.decode_token(id_token) {
             let e = anyhow::Error::from(e);
-            error!(target: "openid", "{:?}", e.context("decoding openid token"));
-            return Html(pages.internal_error.into_owned());
+            error!(target: "openid", "{:?}", e.context(format!("decoding openid token from {provider} ({error_code})")));
+            return Html(pages.render_internal_error(&context));
         }
-        if let Err(e) = client.validate_token(&token, None, None) {
+        if let Err(e) = client.validate_token(id_token, None, None) {
             let e = anyhow::Error::from(e);
-            error!(target: "openid", "{:?}", e.context("validating openid token"));
-            return Html(pages.invalid.into_owned());
+            error!(target: "openid", "{:?}", e.context(format!("validating openid token from {provider} ({error_code})")));
+            self.record_failure(ip, &format!("invalid id token from {provider}"));
+            return Html(pages.render_invalid(&context));
+        }
+        let mut userinfo = id_token.payload().unwrap().userinfo.clone();
+        drop_unverified_email(&mut userinfo, provider);
+
+        if userinfo.email.is_none() && pending.userinfo_fallback {
+            // The subject is checked against the ID token by request_userinfo
+            match client.request_userinfo(&token).await {
+                Ok(x) => {
+                    userinfo.email = x.email;
+                    userinfo.email_verified = x.email_verified;
+                    drop_unverified_email(&mut userinfo, provider);
+                }
+                Err(e) => {
+                    let e = anyhow::Error::from(e);
+                    error!(target: "openid", "{:?}", e.context(format!("requesting userinfo from {provider} ({error_code})")));
+                    return Html(pages.render_internal_error(&context));
+                }
+            }
+        }
+
+        if let Some(username) = userinfo
+            .preferred_username
+            .as_ref()
+            .or(userinfo.name.as_ref())
+            .or(userinfo.email.as_ref())
+        {
+            context = context.set("username", username);
         }
-        let userinfo = token.payload().unwrap().userinfo.clone();
 
         let _ = pending.ready_sender.send(userinfo);
-        Html(pages.success.into_owned())
+        Html(pages.render_success(&context))
     }
 }
 
@@ -208,31 +627,59 @@ pub async fn oidc_redirect_handler<S>(
     Query(AuthRedirectParams { state, code }): Query<AuthRedirectParams>,
     State(global_state): State<S>,
     State(pages): State<AuthPages>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
 ) -> Html<String>
 where
     S: AsRef<OIDCState>,
 {
+    let pages = pages.localize(headers.get(ACCEPT_LANGUAGE).and_then(|x| x.to_str().ok()));
     AsRef::<OIDCState>::as_ref(&global_state)
-        .verify_auth(code, state, pages)
+        .verify_auth(code, state, pages, ip)
         .await
 }
 
+/// Handles providers that post the authorization code, such as Apple
+pub async fn oidc_form_redirect_handler<S>(
+    State(global_state): State<S>,
+    State(pages): State<AuthPages>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Form(AuthRedirectParams { state, code }): Form<AuthRedirectParams>,
+) -> Html<String>
+where
+    S: AsRef<OIDCState>,
+{
+    let pages = pages.localize(headers.get(ACCEPT_LANGUAGE).and_then(|x| x.to_str().ok()));
+    AsRef::<OIDCState>::as_ref(&global_state)
+        .verify_auth(code, state, pages, ip)
+        .await
+}
+
+/// Handles the redirects of every provider that shares the `OIDCState`
 pub fn openid_redirect<S, B>() -> MethodRouter<S, B>
 where
     AuthPages: FromRef<S>,
     S: AsRef<OIDCState> + Send + Sync + Clone + 'static,
     B: Send + Sync + HttpBody + 'static, // AuthPages: FromRef<S>
+    B::Data: Send,
+    B::Error: Into<BoxError>,
 {
-    axum::routing::get(oidc_redirect_handler::<S>)
+    axum::routing::get(oidc_redirect_handler::<S>).post(oidc_form_redirect_handler::<S>)
 }
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::{
     sync::oneshot::{channel, Sender},
-    time::sleep,
+    time::{sleep, Instant},
 };
 
-use super::auth_pages::AuthPages;
+use super::{
+    audit::{AuthAudit, AuthFailure, ClientIp},
+    auth_pages::{new_error_code, AuthPages, PageContext},
+    pending::{PendingAuths, DEFAULT_MAX_PENDING_AUTHS},
+};
+use crate::log_targets;
 
 pub mod google {
     use std::{fs::read_to_string, path::Path};
@@ -243,6 +690,21 @@ pub mod google {
     #[derive(Clone)]
     pub struct GoogleOIDC<S: Deref<Target = OIDCState> + Clone>(pub OIDC<S>);
 
+    impl<S: Deref<Target = OIDCState> + Clone> GoogleOIDC<S> {
+        /// Google recommends PKCE for every client
+        pub fn set_pkce(self, pkce: bool) -> Self {
+            Self(self.0.set_pkce(pkce))
+        }
+
+        /// Allows device authentication through Google, which only works for
+        /// clients of the TVs and Limited Input devices type
+        pub fn enable_device_auth(self) -> Self {
+            Self(self.0.set_device_authorization_url(
+                Url::parse("https://oauth2.googleapis.com/device/code").expect("URL to be valid"),
+            ))
+        }
+    }
+
     pub async fn new_google_oidc_from_file<S: Deref<Target = OIDCState> + Clone>(
         filename: impl AsRef<Path>,
         oidc_state: S,
@@ -255,6 +717,8 @@ pub mod google {
         }
         #[derive(Deserialize)]
         struct WebSecret {
+            // Clients for devices are of the installed type
+            #[serde(alias = "installed")]
             web: ClientSecret,
         }
 
@@ -273,3 +737,126 @@ pub mod google {
         ))
     }
 }
+
+pub mod apple {
+    use std::{
+        fs::read_to_string,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use base64::engine::general_purpose::STANDARD;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use serde_json::json;
+    use tokio::{spawn, time::sleep};
+
+    use super::*;
+
+    /// How long generated client secrets last, which Apple limits to 6 months
+    pub const CLIENT_SECRET_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 180);
+    /// How often a new client secret is generated, so that one that failed to
+    /// be replaced still has months left
+    const CLIENT_SECRET_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+    const APPLE_ISSUER: &str = "https://appleid.apple.com";
+
+    /// The Sign in with Apple client, as read from a config
+    #[derive(Deserialize, Serialize, Clone, Debug)]
+    pub struct AppleSignInConfig {
+        /// The .p8 key that Apple gave to sign client secrets with
+        pub key_path: String,
+        pub key_id: String,
+        pub team_id: String,
+        /// The identifier of the Services ID that users sign in to
+        pub client_id: String,
+    }
+
+    #[derive(Clone)]
+    pub struct AppleOIDC<S: Deref<Target = OIDCState> + Clone>(pub OIDC<S>);
+
+    /// Apple takes a JWT signed with ES256 by the key of the client as the
+    /// client secret, instead of a fixed secret
+    fn new_client_secret(config: &AppleSignInConfig, key_pem: &str) -> anyhow::Result<String> {
+        let der = STANDARD
+            .decode(
+                key_pem
+                    .lines()
+                    .filter(|x| !x.starts_with("-----"))
+                    .collect::<String>(),
+            )
+            .context("decoding apple key")?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der)
+            .map_err(|e| anyhow::Error::msg(format!("parsing apple key: {e}")))?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let header = json!({ "alg": "ES256", "kid": config.key_id });
+        let claims = json!({
+            "iss": config.team_id,
+            "iat": now,
+            "exp": now + CLIENT_SECRET_LIFETIME.as_secs(),
+            "aud": APPLE_ISSUER,
+            "sub": config.client_id,
+        });
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = key
+            .sign(&SystemRandom::new(), signed.as_bytes())
+            .map_err(|_| anyhow::Error::msg("signing apple client secret"))?;
+        Ok(format!("{signed}.{}", URL_SAFE_NO_PAD.encode(signature)))
+    }
+
+    /// Replaces the client secret every refresh interval, forever
+    async fn refresh_client_secret<S: Deref<Target = OIDCState> + Clone>(
+        config: AppleSignInConfig,
+        key_pem: String,
+        oidc: OIDC<S>,
+    ) {
+        loop {
+            sleep(CLIENT_SECRET_REFRESH_INTERVAL).await;
+            let result = match new_client_secret(&config, &key_pem) {
+                Ok(client_secret) => oidc
+                    .set_client_secret(client_secret)
+                    .await
+                    .map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!(target: "openid", "{:?}", e.context("refreshing apple client secret"));
+            }
+        }
+    }
+
+    /// Apple posts the authorization code to the redirect URL, so it must be
+    /// routed with `openid_redirect`
+    ///
+    /// The client secret is regenerated in the background before it expires
+    pub async fn new_apple_oidc_from_file<S>(
+        config: &AppleSignInConfig,
+        oidc_state: S,
+        redirect_url: &str,
+    ) -> anyhow::Result<AppleOIDC<S>>
+    where
+        S: Deref<Target = OIDCState> + Clone + Send + Sync + 'static,
+    {
+        let key_pem = read_to_string(&config.key_path)?;
+        let client_secret = new_client_secret(config, &key_pem)?;
+
+        let oidc = OIDC::new(
+            config.client_id.clone(),
+            client_secret,
+            redirect_url.into(),
+            Url::parse(APPLE_ISSUER).expect("URL to be valid"),
+            oidc_state,
+        )
+        .await?
+        // Apple requires it when requesting the email scope
+        .set_form_post(true);
+        spawn(refresh_client_secret(config.clone(), key_pem, oidc.clone()));
+
+        Ok(AppleOIDC(oidc))
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use parking_lot::Mutex;
+
+use crate::log_targets;
+
+/// How many authentications can be pending at once by default
+pub(crate) const DEFAULT_MAX_PENDING_AUTHS: usize = 10_000;
+/// How many authentications a single IP can have pending at once by default
+pub(crate) const DEFAULT_MAX_PENDING_AUTHS_PER_IP: usize = 16;
+/// How often abandoned authentications are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Refusals are logged at most this often, so that a flood of them cannot
+/// flood the logs as well
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+struct PendingAuth<T> {
+    started: Instant,
+    ip: Option<IpAddr>,
+    session: T,
+}
+
+struct Pending<T> {
+    sessions: HashMap<String, PendingAuth<T>>,
+    by_ip: HashMap<IpAddr, usize>,
+    last_sweep: Instant,
+    last_warning: Option<Instant>,
+    /// Refusals since the last one that was logged
+    unlogged_refusals: usize,
+}
+
+impl<T> Pending<T> {
+    /// Logs the refusal unless one was logged recently, in which case it is
+    /// counted towards the next one
+    fn warn_refused(&mut self, reason: impl FnOnce() -> String) {
+        if self
+            .last_warning
+            .map_or(false, |x| x.elapsed() < WARNING_INTERVAL)
+        {
+            self.unlogged_refusals += 1;
+            return;
+        }
+        warn!(
+            target: log_targets::SECURITY,
+            "Refused an authentication as {}, along with {} unlogged refusals",
+            reason(),
+            self.unlogged_refusals
+        );
+        self.last_warning = Some(Instant::now());
+        self.unlogged_refusals = 0;
+    }
+}
+
+fn forget_ip(by_ip: &mut HashMap<IpAddr, usize>, ip: Option<IpAddr>) {
+    let Some(ip) = ip else { return };
+    if let Some(count) = by_ip.get_mut(&ip) {
+        *count -= 1;
+        if *count == 0 {
+            by_ip.remove(&ip);
+        }
+    }
+}
+
+/// The authentications that are waiting for the redirect of the provider, by
+/// their CSRF token
+///
+/// Authentications are normally removed once they finish or are dropped, but
+/// those older than the wait time are swept in case they never were
+pub(crate) struct PendingAuths<T> {
+    pending: Mutex<Pending<T>>,
+    max_pending: usize,
+    max_pending_per_ip: usize,
+    max_wait: Duration,
+}
+
+impl<T> PendingAuths<T> {
+    pub(crate) fn new(max_pending: usize, max_wait: Duration) -> Self {
+        Self {
+            pending: Mutex::new(Pending {
+                sessions: HashMap::new(),
+                by_ip: HashMap::new(),
+                last_sweep: Instant::now(),
+                last_warning: None,
+                unlogged_refusals: 0,
+            }),
+            max_pending,
+            max_pending_per_ip: DEFAULT_MAX_PENDING_AUTHS_PER_IP,
+            max_wait,
+        }
+    }
+
+    /// Returns false, logging it as suspicious, if too many authentications
+    /// are already pending, in total or for the IP
+    ///
+    /// Callers should pass the IP of the client whenever they know it, as
+    /// only then can a single client not exhaust the total
+    pub(crate) fn insert(&self, csrf_token: String, ip: Option<IpAddr>, session: T) -> bool {
+        let mut guard = self.pending.lock();
+        let Pending {
+            sessions,
+            by_ip,
+            last_sweep,
+        } = &mut *guard;
+        if sessions.len() >= self.max_pending || last_sweep.elapsed() >= SWEEP_INTERVAL {
+            sessions.retain(|_, pending| {
+                let keep = pending.started.elapsed() < self.max_wait;
+                if !keep {
+                    forget_ip(by_ip, pending.ip);
+                }
+                keep
+            });
+            *last_sweep = Instant::now();
+        }
+        if sessions.len() >= self.max_pending {
+            let count = sessions.len();
+            guard.warn_refused(|| format!("{count} are already pending"));
+            return false;
+        }
+        if let Some(ip) = ip {
+            let count = by_ip.entry(ip).or_default();
+            if *count >= self.max_pending_per_ip {
+                let count = *count;
+                guard.warn_refused(|| format!("{ip} already has {count} pending"));
+                return false;
+            }
+            *count += 1;
+        }
+        let pending = PendingAuth {
+            started: Instant::now(),
+            ip,
+            session,
+        };
+        if let Some(replaced) = sessions.insert(csrf_token, pending) {
+            forget_ip(by_ip, replaced.ip);
+        }
+        true
+    }
+
+    pub(crate) fn remove(&self, csrf_token: &str) -> Option<T> {
+        let mut guard = self.pending.lock();
+        let pending = guard.sessions.remove(csrf_token)?;
+        forget_ip(&mut guard.by_ip, pending.ip);
+        Some(pending.session)
+    }
+}
@@ -0,0 +1,104 @@
+use std::marker::PhantomData;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::{request::Parts, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::warn;
+
+use super::token::{HeaderTokenConfig, TokenConfig, TokenRejection, TokenVerifier, VerifiedToken};
+use crate::{
+    log_targets,
+    rejection::{Rejection, RejectionFormat},
+};
+
+/// A role that `RequireRole` demands of the tokens of its config
+pub trait Role: Send + Sync + 'static {
+    type Config: HeaderTokenConfig;
+    /// Names the role in logs
+    const NAME: &'static str;
+
+    fn is_granted(roles: &<Self::Config as TokenConfig>::Roles) -> bool;
+}
+
+/// A `VerifiedToken` whose roles include `R`
+pub struct RequireRole<R: Role> {
+    pub token: VerifiedToken<R::Config>,
+    _phantom: PhantomData<fn() -> R>,
+}
+
+/// Why a `RequireRole` could not be extracted
+pub enum RoleRejection {
+    Token(TokenRejection),
+    MissingRole(RejectionFormat),
+}
+
+impl IntoResponse for RoleRejection {
+    fn into_response(self) -> Response {
+        match self {
+            RoleRejection::Token(rejection) => rejection.into_response(),
+            RoleRejection::MissingRole(format) => Rejection::new(
+                StatusCode::FORBIDDEN,
+                "missing_role",
+                "This token cannot access this path",
+            )
+            .set_format(format)
+            .into_response(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S, R> FromRequestParts<S> for RequireRole<R>
+where
+    R: Role,
+    S: AsRef<<R::Config as HeaderTokenConfig>::Granter> + Sync,
+{
+    type Rejection = RoleRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = VerifiedToken::<R::Config>::from_request_parts(parts, state)
+            .await
+            .map_err(RoleRejection::Token)?;
+        let granter: &<R::Config as HeaderTokenConfig>::Granter = state.as_ref();
+        if !R::is_granted(&granter.get_roles(&token.identifier)) {
+            warn!(
+                target: log_targets::SECURITY,
+                "A token without the {} role was used for {}",
+                R::NAME,
+                parts.uri.path()
+            );
+            return Err(RoleRejection::MissingRole(RejectionFormat::negotiate(
+                &parts.headers,
+            )));
+        }
+        Ok(Self {
+            token,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+/// Rejects requests whose token lacks the role, as a middleware for
+/// `axum::middleware::from_fn_with_state`
+///
+/// `RouteLayer::require_role` applies it to a single route
+pub async fn require_role<S, R, B>(
+    State(state): State<S>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response
+where
+    R: Role,
+    S: AsRef<<R::Config as HeaderTokenConfig>::Granter> + Sync,
+    B: Send,
+{
+    let (mut parts, body) = request.into_parts();
+    if let Err(rejection) = RequireRole::<R>::from_request_parts(&mut parts, &state).await {
+        return rejection.into_response();
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{log_targets, redact::redact, rejection::Rejection};
+
+pub const KEY_ID_HEADER: &str = "x-signature-key";
+pub const TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Signed requests with larger bodies are answered with 413
+const MAX_SIGNED_BODY_SIZE: usize = 1024 * 1024;
+
+/// A secret shared with one caller of signed routes
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SigningKey {
+    pub id: String,
+    #[serde(serialize_with = "redact")]
+    pub secret: String,
+}
+
+/// The keys that requests to signed routes can be signed with
+///
+/// Callers send the ID of their key in `X-Signature-Key`, the unix time in
+/// seconds in `X-Signature-Timestamp`, and in `X-Signature` the base64 encoded
+/// HMAC-SHA256 of
+///
+/// ```text
+/// {timestamp}\n{method}\n{path and query}\n{base64 encoded SHA256 of the body}
+/// ```
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RequestSigningConfig {
+    pub keys: Vec<SigningKey>,
+    /// How far the timestamp of a request may be from the time it arrives.
+    /// Each signature is only accepted once within this window
+    #[serde(default = "replay_window")]
+    pub replay_window: Duration,
+}
+
+fn replay_window() -> Duration {
+    Duration::from_secs(300)
+}
+
+struct VerifierInner {
+    keys: HashMap<String, Vec<u8>>,
+    replay_window: u64,
+    /// Signatures that were accepted, and the unix time they can be forgotten
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+/// Checks the signatures of requests to the routes given
+/// `RouteLayer::Signed`, instead of the API token
+#[derive(Clone)]
+pub struct RequestVerifier(Arc<VerifierInner>);
+
+impl RequestVerifier {
+    pub fn new(config: &RequestSigningConfig) -> Self {
+        Self(Arc::new(VerifierInner {
+            keys: config
+                .keys
+                .iter()
+                .map(|key| (key.id.clone(), key.secret.as_bytes().to_vec()))
+                .collect(),
+            replay_window: config.replay_window.as_secs(),
+            seen: Default::default(),
+        }))
+    }
+
+    /// Why the headers of the request are not valid, if they are not, which
+    /// is checked before the body is read
+    fn check_headers(&self, headers: &HeaderMap) -> Result<SignatureHeaders<'_>, &'static str> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .ok_or("missing signature headers")
+        };
+        let key = self
+            .0
+            .keys
+            .get(header(KEY_ID_HEADER)?)
+            .ok_or("unknown key")?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| "malformed timestamp")?;
+        let signature = STANDARD
+            .decode(header(SIGNATURE_HEADER)?)
+            .map_err(|_| "malformed signature")?;
+
+        if unix_now().abs_diff(timestamp) > self.0.replay_window {
+            return Err("timestamp outside of the replay window");
+        }
+        Ok(SignatureHeaders {
+            key,
+            timestamp,
+            signature,
+        })
+    }
+
+    /// Why the request is not validly signed, if it is not
+    fn verify(&self, headers: SignatureHeaders, signed: &[u8]) -> Result<(), &'static str> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(headers.key).expect("HMAC to accept keys of any size");
+        mac.update(signed);
+        // Compares in constant time
+        mac.verify_slice(&headers.signature)
+            .map_err(|_| "invalid signature")?;
+
+        let now = unix_now();
+        let mut seen = self.0.seen.lock();
+        // Kept until the timestamp leaves the window, including the last second
+        // that it is accepted in
+        seen.retain(|_, forget_at| *forget_at >= now);
+        if seen
+            .insert(headers.signature, headers.timestamp + self.0.replay_window)
+            .is_some()
+        {
+            return Err("replayed signature");
+        }
+        Ok(())
+    }
+}
+
+/// The parsed signature headers of a request
+struct SignatureHeaders<'a> {
+    key: &'a [u8],
+    timestamp: u64,
+    signature: Vec<u8>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+/// What the signature of a request covers
+fn signed_bytes(request: &Request<Body>, timestamp: &str, body: &Bytes) -> Vec<u8> {
+    format!(
+        "{timestamp}\n{}\n{}\n{}",
+        request.method(),
+        request
+            .uri()
+            .path_and_query()
+            .map_or(request.uri().path(), |x| x.as_str()),
+        STANDARD.encode(Sha256::digest(body))
+    )
+    .into_bytes()
+}
+
+fn reject(request: &Request<Body>, status: StatusCode, code: &str, msg: &str) -> Response {
+    Rejection::new(status, code, msg)
+        .negotiate(request.headers())
+        .into_response()
+}
+
+fn reject_signature(request: &Request<Body>, reason: &str) -> Response {
+    warn!(
+        target: log_targets::SECURITY,
+        "Rejected signed request to {}: {reason}",
+        request.uri().path()
+    );
+    reject(
+        request,
+        StatusCode::UNAUTHORIZED,
+        "invalid_signature",
+        "Missing or invalid request signature",
+    )
+}
+
+/// Responds with 401 to requests that are not validly signed, logging them as
+/// suspicious
+///
+/// The signature headers are checked before the body is read, so that
+/// unsigned requests cannot make it be buffered
+pub(crate) async fn verify_signature(
+    verifier: RequestVerifier,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let headers = match verifier.check_headers(request.headers()) {
+        Ok(x) => x,
+        Err(reason) => return reject_signature(&request, reason),
+    };
+
+    let (parts, mut body) = request.into_parts();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let Ok(chunk) = chunk else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        buffer.extend_from_slice(&chunk);
+        if buffer.len() > MAX_SIGNED_BODY_SIZE {
+            let request = Request::from_parts(parts, Body::empty());
+            return reject(
+                &request,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "The body of the request is too large",
+            );
+        }
+    }
+    let body = Bytes::from(buffer);
+    let request = Request::from_parts(parts, Body::empty());
+
+    let timestamp = request
+        .headers()
+        .get(TIMESTAMP_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    let signed = signed_bytes(&request, timestamp, &body);
+    if let Err(reason) = verifier.verify(headers, &signed) {
+        return reject_signature(&request, reason);
+    }
+
+    let (parts, _) = request.into_parts();
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+    const SIGNED: &[u8] = b"signed request";
+
+    fn verifier() -> RequestVerifier {
+        RequestVerifier::new(&RequestSigningConfig {
+            keys: vec![SigningKey {
+                id: "caller".into(),
+                secret: SECRET.into(),
+            }],
+            replay_window: replay_window(),
+        })
+    }
+
+    fn signed_headers(timestamp: u64) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(SIGNED);
+        let mut headers = HeaderMap::new();
+        headers.insert(KEY_ID_HEADER, "caller".parse().unwrap());
+        headers.insert(TIMESTAMP_HEADER, timestamp.to_string().parse().unwrap());
+        headers.insert(
+            SIGNATURE_HEADER,
+            STANDARD
+                .encode(mac.finalize().into_bytes())
+                .parse()
+                .unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn accepts_each_signature_once() {
+        let verifier = verifier();
+        let headers = signed_headers(unix_now());
+        assert_eq!(
+            verifier.verify(verifier.check_headers(&headers).unwrap(), SIGNED),
+            Ok(())
+        );
+        assert_eq!(
+            verifier.verify(verifier.check_headers(&headers).unwrap(), SIGNED),
+            Err("replayed signature")
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_window() {
+        let verifier = verifier();
+        let window = replay_window().as_secs();
+        let now = unix_now();
+        for timestamp in [now - window - 10, now + window + 10] {
+            assert_eq!(
+                verifier.check_headers(&signed_headers(timestamp)).err(),
+                Some("timestamp outside of the replay window")
+            );
+        }
+        assert!(verifier
+            .check_headers(&signed_headers(now - window + 10))
+            .is_ok());
+    }
+
+    #[test]
+    fn forgets_signatures_once_they_leave_the_window() {
+        let verifier = verifier();
+        let headers = signed_headers(unix_now());
+        let expired = || {
+            let mut headers = verifier.check_headers(&headers).unwrap();
+            headers.timestamp = unix_now() - replay_window().as_secs() - 1;
+            headers
+        };
+        assert_eq!(verifier.verify(expired(), SIGNED), Ok(()));
+        // The timestamp of the first use has already left the window, so
+        // the signature was forgotten
+        assert_eq!(verifier.verify(expired(), SIGNED), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let verifier = verifier();
+        let headers = signed_headers(unix_now());
+        assert_eq!(
+            verifier.verify(verifier.check_headers(&headers).unwrap(), b"tampered"),
+            Err("invalid signature")
+        );
+    }
+}
@@ -1,19 +1,63 @@
-use std::{hash::Hash, sync::Arc, time::Duration};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeSet, HashMap},
+    convert::Infallible,
+    hash::Hash,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::Error;
 use axum::{
     async_trait,
     extract::FromRequestParts,
-    http::{request::Parts, HeaderValue, StatusCode},
-    response::IntoResponse,
+    http::{
+        header::{COOKIE, ORIGIN, SET_COOKIE},
+        request::Parts,
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, IntoResponseParts, ResponseParts},
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use bimap::BiMap;
+use constant_time_eq::constant_time_eq;
+use hmac::{Hmac, Mac};
+use log::{error, warn};
 use parking_lot::Mutex;
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use percent_encoding::percent_decode_str;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use sha2::{Digest, Sha256};
 use tokio::{spawn, task::JoinHandle, time::sleep};
 
+use crate::{
+    auth::{
+        audit::{AuthAudit, AuthFailure},
+        totp::{SecondFactor, SecondFactorUser},
+    },
+    log_targets,
+    rate_limit::parts_client_ip,
+    rejection::{Rejection, RejectionFormat},
+    rng::SharedRng,
+};
+
 struct TokenEntry<ID> {
     _expiry_handle: JoinHandle<()>,
+    /// Kept so that the token can be found by its identifier, as the map is
+    /// keyed by the hash of the token
+    token: HeaderValue,
     identifier: Arc<ID>,
+    /// In seconds since the unix epoch
+    expires_at: u64,
+    /// How far sliding expiry can extend the token
+    session_expires_at: u64,
 }
 
 impl<ID: Hash> Hash for TokenEntry<ID> {
@@ -30,25 +74,452 @@ impl<ID: PartialEq> PartialEq for TokenEntry<ID> {
 
 impl<ID: Eq> Eq for TokenEntry<ID> {}
 
+/// Entries hash and compare by their identifier, so tokens can be looked up by it
+impl<ID> Borrow<ID> for TokenEntry<ID> {
+    fn borrow(&self) -> &ID {
+        &self.identifier
+    }
+}
+
 impl<ID> Drop for TokenEntry<ID> {
     fn drop(&mut self) {
         self._expiry_handle.abort();
     }
 }
 
+#[derive(Default)]
+struct TokenCounters {
+    hits: AtomicU64,
+    signed_hits: AtomicU64,
+    stored_hits: AtomicU64,
+    misses: AtomicU64,
+    expirations: AtomicU64,
+}
+
+/// How many verifications succeeded and failed, and how many tokens expired
+/// since the granter was created
+#[derive(Serialize, Clone, Copy)]
+pub struct TokenStats {
+    pub hits: u64,
+    /// Tokens that were not issued by this granter, but had a valid signature
+    pub signed_hits: u64,
+    /// Tokens that were not in memory, but were found in the token store
+    pub stored_hits: u64,
+    pub misses: u64,
+    pub expirations: u64,
+    pub active: usize,
+}
+
+/// Sliding expiry only extends tokens by at least this many seconds, so that
+/// the token store is not updated on every request
+const MIN_EXPIRY_SLIDE: u64 = 60;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedClaims<ID> {
+    id: ID,
+    /// When the token expires, in seconds since the unix epoch
+    exp: u64,
+    /// The node that issued the token
+    iss: String,
+}
+
+/// Signs tokens so that any node with the same key can verify them, even if
+/// the node that issued them is down
+///
+/// Signed tokens have the form `random.claims.signature`, where the claims are
+/// the identifier, expiry and issuer as base64 encoded JSON, and the signature
+/// is the base64 encoded HMAC-SHA256 of everything before it
+struct TokenSigner {
+    key: Vec<u8>,
+    issuer: String,
+    /// Issuers besides this one whose tokens are accepted
+    other_issuers: Vec<String>,
+}
+
+impl TokenSigner {
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC to accept keys of any size");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn sign<ID: Serialize>(&self, mut token: Vec<u8>, id: &ID, exp: u64) -> Vec<u8> {
+        let claims = serde_json::to_vec(&SignedClaims {
+            id,
+            exp,
+            iss: self.issuer.clone(),
+        })
+        .unwrap();
+        token.push(b'.');
+        token.extend(URL_SAFE_NO_PAD.encode(claims).into_bytes());
+        let signature = self.mac(&token);
+        token.push(b'.');
+        token.extend(URL_SAFE_NO_PAD.encode(signature).into_bytes());
+        token
+    }
+
+    /// The claims of the token if its signature is valid, it has not expired
+    /// and it was issued by an accepted issuer
+    fn verify<ID: DeserializeOwned>(&self, token: &[u8]) -> Option<SignedClaims<ID>> {
+        let token = std::str::from_utf8(token).ok()?;
+        let (signed, signature) = token.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        if !constant_time_eq(&self.mac(signed.as_bytes()), &signature) {
+            return None;
+        }
+        let claims: SignedClaims<ID> = parse_claims(signed)?;
+        let accepted = claims.iss == self.issuer || self.other_issuers.contains(&claims.iss);
+        (accepted && claims.exp > unix_now()).then_some(claims)
+    }
+}
+
+/// Reads the claims of a signed token without checking its signature
+fn parse_claims<ID: DeserializeOwned>(token: &str) -> Option<SignedClaims<ID>> {
+    let mut parts = token.split('.');
+    let claims = parts.nth(1)?;
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()
+}
+
+/// What tokens are kept and looked up by in a `TokenStore` and in memory, so that
+/// the store cannot be read to log in, and lookups take no longer for tokens that
+/// share a prefix with a real one
+fn token_hash(token: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token))
+}
+
+/// A token as kept by a `TokenStore`
+#[derive(Serialize, Deserialize)]
+pub struct StoredToken<ID> {
+    /// The SHA-256 hash of the token, as base64
+    pub token_hash: String,
+    pub identifier: ID,
+    /// In seconds since the unix epoch
+    pub expires_at: u64,
+}
+
+/// Persists tokens outside of the granter, so that they survive restarts and
+/// can be shared between siblings
+///
+/// Tokens are only given to stores as hashes. Stores should forget tokens
+/// once they expire
+#[async_trait]
+pub trait TokenStore<ID>: Send + Sync {
+    async fn save(&self, token: StoredToken<ID>) -> Result<(), Error>;
+    async fn remove(&self, token_hash: &str) -> Result<(), Error>;
+    async fn load(&self, token_hash: &str) -> Result<Option<StoredToken<ID>>, Error>;
+}
+
+/// Persists revocations outside of the granter, so that revoked signed tokens
+/// stay revoked after a restart
+///
+/// Stores should forget revocations once their token expires
+#[async_trait]
+pub trait RevocationStore: Send + Sync {
+    async fn save_revocation(&self, token_hash: &str, expires_at: u64) -> Result<(), Error>;
+    /// The hash of every revoked token that has not expired, along with when it expires
+    async fn load_revocations(&self) -> Result<Vec<(String, u64)>, Error>;
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisTokenStore;
+
+struct RefreshEntry<ID> {
+    identifier: Arc<ID>,
+    /// The access token issued along with this refresh token
+    access: HeaderValue,
+    /// In seconds since the unix epoch
+    expires_at: u64,
+    /// When the session that was started by logging in must end, however
+    /// often it is refreshed
+    session_expires_at: u64,
+}
+
+/// The refresh tokens of a granter, indexed by expiry and by the access token
+/// they were issued with, so that neither pruning nor revoking goes through
+/// all of them
+struct RefreshTokens<ID> {
+    entries: HashMap<HeaderValue, RefreshEntry<ID>>,
+    by_expiry: BTreeSet<(u64, HeaderValue)>,
+    by_access: HashMap<HeaderValue, HeaderValue>,
+}
+
+impl<ID> Default for RefreshTokens<ID> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_expiry: BTreeSet::new(),
+            by_access: HashMap::new(),
+        }
+    }
+}
+
+impl<ID> RefreshTokens<ID> {
+    fn insert(&mut self, token: HeaderValue, entry: RefreshEntry<ID>) {
+        self.by_expiry.insert((entry.expires_at, token.clone()));
+        self.by_access.insert(entry.access.clone(), token.clone());
+        self.entries.insert(token, entry);
+    }
+
+    fn remove(&mut self, token: &HeaderValue) -> Option<RefreshEntry<ID>> {
+        let entry = self.entries.remove(token)?;
+        self.by_expiry.remove(&(entry.expires_at, token.clone()));
+        self.by_access.remove(&entry.access);
+        Some(entry)
+    }
+
+    fn remove_by_access(&mut self, access: &HeaderValue) {
+        if let Some(token) = self.by_access.get(access).cloned() {
+            self.remove(&token);
+        }
+    }
+
+    /// Forgets the refresh tokens that have expired
+    fn prune(&mut self, now: u64) {
+        while let Some((expires_at, token)) = self.by_expiry.first().cloned() {
+            if expires_at > now {
+                break;
+            }
+            self.remove(&token);
+        }
+    }
+}
+
+/// An access token, and the refresh token that can replace it
+pub struct TokenPair<C: TokenConfig> {
+    pub access: VerifiedToken<C>,
+    /// Only issued if refresh tokens were enabled with `set_refresh_duration`
+    pub refresh: Option<HeaderValue>,
+}
+
 pub struct TokenGranter<C: TokenConfig> {
     // When the sender gets dropped, the task responsible for expiring the token will complete
-    tokens: Arc<Mutex<BiMap<HeaderValue, TokenEntry<C::TokenIdentifier>>>>,
+    /// Keyed by the hash of each token
+    tokens: Arc<Mutex<BiMap<String, TokenEntry<C::TokenIdentifier>>>>,
     token_duration: Duration,
+    refresh_duration: Option<Duration>,
+    session_lifetime: Option<Duration>,
+    sliding_expiry: bool,
+    refresh_tokens: Mutex<RefreshTokens<C::TokenIdentifier>>,
+    rng: SharedRng,
+    counters: Arc<TokenCounters>,
+    signer: Option<TokenSigner>,
+    /// The hashes of revoked tokens, along with when they expire, so that
+    /// signed tokens and copies in the store are refused
+    revoked: Mutex<HashMap<String, u64>>,
+    store: Option<Arc<dyn TokenStore<C::TokenIdentifier>>>,
+    revocation_store: Option<Arc<dyn RevocationStore>>,
+    audit: Option<Arc<AuthAudit>>,
+    second_factor_required: Option<Arc<SecondFactorCheck<C::TokenIdentifier>>>,
+    roles: Option<Arc<RoleLookup<C>>>,
 }
 
+/// Whether an identifier needs a second factor before a session is started
+type SecondFactorCheck<ID> = dyn Fn(&ID) -> bool + Send + Sync;
+/// The roles of the holder of a token with the identifier
+type RoleLookup<C> =
+    dyn Fn(&<C as TokenConfig>::TokenIdentifier) -> <C as TokenConfig>::Roles + Send + Sync;
+
+/// The identifier needs a `SecondFactor` to start a session
+#[derive(thiserror::Error, Debug)]
+#[error("A second factor is required")]
+pub struct SecondFactorRequired;
+
 pub trait TokenConfig: Send + Sync + 'static {
-    type TokenIdentifier: Send + Sync + Hash + Eq + 'static;
+    type TokenIdentifier: Send + Sync + Hash + Eq + Clone + Serialize + DeserializeOwned + 'static;
+    /// What the holder of a token may do, as checked by `RequireRole`
+    ///
+    /// Tokens have the default roles unless `TokenGranter::set_roles` is used
+    type Roles: Default + Send + Sync = ();
+    /// The length of the random part of each token
     const TOKEN_LENGTH: usize;
 }
 
-pub trait HeaderTokenConfig: TokenConfig {
+pub trait HeaderTokenConfig: TokenConfig + Sized {
     const HEADER_NAME: &'static str;
+    /// The query parameter that the token can be passed in instead, such as by
+    /// WebSocket clients. Defaults to the header name in lowercase
+    const QUERY_PARAM: Option<&'static str> = None;
+    /// Also reads the token from this cookie when it is not in the header or query
+    const COOKIE: Option<CookieTokenConfig> = None;
+    /// What the state verifies tokens with when extracting a `VerifiedToken`
+    type Granter: TokenVerifier<Self> = TokenGranter<Self>;
+}
+
+/// Whether browsers send a token cookie along with requests from other sites
+#[derive(Clone, Copy)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept this if the cookie is also secure
+    None,
+}
+
+impl SameSite {
+    fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// A cookie that browsers keep the token in, so that browser clients do not
+/// have to manage the token header in scripts
+///
+/// The cookie is always `HttpOnly`, so scripts cannot read it either. As
+/// browsers also send it along with requests made by other sites, it is only
+/// accepted from requests whose `Origin` is allowed
+#[derive(Clone, Copy)]
+pub struct CookieTokenConfig {
+    name: &'static str,
+    same_site: SameSite,
+    secure: bool,
+    allowed_origins: &'static [&'static str],
+}
+
+impl CookieTokenConfig {
+    /// `secure` makes browsers only send the cookie over HTTPS, which is
+    /// required for `SameSite::None`
+    pub const fn new(name: &'static str, same_site: SameSite, secure: bool) -> Self {
+        assert!(
+            secure || !matches!(same_site, SameSite::None),
+            "SameSite=None cookies must be secure"
+        );
+        Self {
+            name,
+            same_site,
+            secure,
+            allowed_origins: &[],
+        }
+    }
+
+    /// The origins that may send requests with the cookie, such as
+    /// `https://play.example.com`. No origin may by default
+    pub const fn set_allowed_origins(mut self, allowed_origins: &'static [&'static str]) -> Self {
+        self.allowed_origins = allowed_origins;
+        self
+    }
+
+    fn allows_origin(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(ORIGIN)
+            .and_then(|x| x.to_str().ok())
+            .map_or(false, |origin| self.allowed_origins.contains(&origin))
+    }
+}
+
+/// Sets or clears the token cookie of a `HeaderTokenConfig` on a response
+///
+/// Does nothing if the config has no cookie
+pub struct SetTokenCookie(Option<HeaderValue>);
+
+impl SetTokenCookie {
+    /// Sets the cookie to the token, which browsers keep for `max_age`
+    pub fn new<C: HeaderTokenConfig>(token: &VerifiedToken<C>, max_age: Duration) -> Self {
+        Self::build::<C>(token.token.to_str().unwrap_or_default(), max_age)
+    }
+
+    /// Tells browsers to forget the cookie, such as when logging out
+    pub fn clear<C: HeaderTokenConfig>() -> Self {
+        Self::build::<C>("", Duration::ZERO)
+    }
+
+    fn build<C: HeaderTokenConfig>(value: &str, max_age: Duration) -> Self {
+        let Some(cookie) = C::COOKIE else {
+            return Self(None);
+        };
+        let mut header = format!(
+            "{}={value}; Path=/; Max-Age={}; HttpOnly; SameSite={}",
+            cookie.name,
+            max_age.as_secs(),
+            cookie.same_site.as_str()
+        );
+        if cookie.secure {
+            header.push_str("; Secure");
+        }
+        Self(HeaderValue::from_str(&header).ok())
+    }
+}
+
+impl IntoResponseParts for SetTokenCookie {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        if let Some(cookie) = self.0 {
+            res.headers_mut().append(SET_COOKIE, cookie);
+        }
+        Ok(res)
+    }
+}
+
+/// The value of the cookie with the given name, if the request has it
+fn find_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(';'))
+        .filter_map(|x| x.trim().split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+/// The still percent encoded value of the token query parameter of `C`, if
+/// the query has it
+fn find_query_param<C: HeaderTokenConfig>(query: &str) -> Option<&str> {
+    let lowercase;
+    let name = match C::QUERY_PARAM {
+        Some(name) => name,
+        None => {
+            lowercase = C::HEADER_NAME.to_lowercase();
+            &lowercase
+        }
+    };
+    query
+        .split('&')
+        .filter_map(|x| x.split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+/// Verifies the tokens of a `VerifiedToken` as it is extracted
+#[async_trait]
+pub trait TokenVerifier<C: TokenConfig>: Send + Sync {
+    async fn verify(&self, token: &HeaderValue) -> Option<VerifiedToken<C>>;
+
+    /// Where failed extractions are recorded, locking out IPs that fail too often
+    fn audit(&self) -> Option<&AuthAudit> {
+        None
+    }
+
+    /// The roles of the holder of a token with the identifier
+    fn get_roles(&self, _identifier: &C::TokenIdentifier) -> C::Roles {
+        Default::default()
+    }
+}
+
+#[async_trait]
+impl<C: TokenConfig> TokenVerifier<C> for TokenGranter<C> {
+    async fn verify(&self, token: &HeaderValue) -> Option<VerifiedToken<C>> {
+        self.verify_token_with_store(token).await
+    }
+
+    fn audit(&self) -> Option<&AuthAudit> {
+        self.audit.as_deref()
+    }
+
+    fn get_roles(&self, identifier: &C::TokenIdentifier) -> C::Roles {
+        self.roles
+            .as_ref()
+            .map_or_else(Default::default, |lookup| lookup(identifier))
+    }
 }
 
 impl<C: TokenConfig> TokenGranter<C> {
@@ -56,32 +527,280 @@ impl<C: TokenConfig> TokenGranter<C> {
         Self {
             tokens: Default::default(),
             token_duration,
+            refresh_duration: None,
+            session_lifetime: None,
+            sliding_expiry: false,
+            refresh_tokens: Default::default(),
+            rng: SharedRng::thread(),
+            counters: Default::default(),
+            signer: None,
+            revoked: Default::default(),
+            store: None,
+            revocation_store: None,
+            audit: None,
+            second_factor_required: None,
+            roles: None,
         }
     }
 
-    pub fn create_token(&self, id: impl Into<Arc<C::TokenIdentifier>>) -> VerifiedToken<C> {
-        let id = id.into();
+    /// Signs every new token with the given key as the issuer, and accepts
+    /// tokens that were signed with it by the other issuers, such as siblings
+    ///
+    /// Revocations are only shared through a revocation store, so siblings
+    /// without one must be told to revoke tokens as well
+    pub fn set_signing_key<I: Into<String>>(
+        mut self,
+        key: impl Into<Vec<u8>>,
+        issuer: impl Into<String>,
+        other_issuers: impl IntoIterator<Item = I>,
+    ) -> Self {
+        self.signer = Some(TokenSigner {
+            key: key.into(),
+            issuer: issuer.into(),
+            other_issuers: other_issuers.into_iter().map(Into::into).collect(),
+        });
+        self
+    }
 
-        let bytes: Vec<u8> = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(C::TOKEN_LENGTH)
-            .collect();
+    /// Issues a refresh token along with every access token, which can be
+    /// exchanged with `refresh` for a new pair of tokens until it expires
+    ///
+    /// Refresh tokens are only kept in memory, so they are not shared with
+    /// siblings and do not survive restarts
+    pub fn set_refresh_duration(mut self, refresh_duration: Duration) -> Self {
+        self.refresh_duration = Some(refresh_duration);
+        self
+    }
 
-        let token = unsafe { HeaderValue::from_maybe_shared_unchecked(bytes) };
-        let token2 = token.clone();
+    /// Limits how long a session can last from the moment the first token was
+    /// created, however often it is refreshed
+    pub fn set_session_lifetime(mut self, session_lifetime: Duration) -> Self {
+        self.session_lifetime = Some(session_lifetime);
+        self
+    }
 
-        let tokens = self.tokens.clone();
-        let token_duration = self.token_duration;
+    /// Extends the expiry of a token by the token duration every time it is
+    /// verified, up to the session lifetime
+    ///
+    /// Signed tokens carry their expiry, so they never slide
+    pub fn set_sliding_expiry(mut self, sliding_expiry: bool) -> Self {
+        self.sliding_expiry = sliding_expiry;
+        self
+    }
+
+    /// Records the tokens that fail to be extracted as a `VerifiedToken`, so
+    /// that IPs guessing tokens get locked out
+    pub fn set_audit(mut self, audit: Arc<AuthAudit>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Makes `create_token_checked` refuse to start sessions for identifiers
+    /// that the check returns true for, unless given a `SecondFactor`
+    pub fn set_second_factor_required(
+        mut self,
+        check: impl Fn(&C::TokenIdentifier) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.second_factor_required = Some(Arc::new(check));
+        self
+    }
+
+    /// Looks up the roles of the holders of tokens with the lookup, which is
+    /// asked on every check so that roles can change while tokens are held
+    pub fn set_roles(
+        mut self,
+        lookup: impl Fn(&C::TokenIdentifier) -> C::Roles + Send + Sync + 'static,
+    ) -> Self {
+        self.roles = Some(Arc::new(lookup));
+        self
+    }
+
+    /// Sets the source of randomness used to generate tokens
+    pub fn set_rng(mut self, rng: SharedRng) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Saves every new token to the given store, and looks up tokens that are
+    /// not in memory in it, such as those issued by siblings or before a restart
+    pub fn set_store(mut self, store: impl TokenStore<C::TokenIdentifier> + 'static) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Saves every revocation to the given store, so that they survive restarts
+    ///
+    /// Call `restore_revocations` to load the revocations in the store up front
+    pub fn set_revocation_store(mut self, store: impl RevocationStore + 'static) -> Self {
+        self.revocation_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Loads every revocation in the revocation store, returning how many were loaded
+    pub async fn restore_revocations(&self) -> Result<usize, Error> {
+        let Some(store) = &self.revocation_store else {
+            return Ok(0);
+        };
+        let now = unix_now();
+        let revocations = store.load_revocations().await?;
+        let mut revoked = self.revoked.lock();
+        let before = revoked.len();
+        revoked.extend(revocations.into_iter().filter(|(_, exp)| *exp > now));
+        Ok(revoked.len() - before)
+    }
+
+    /// Runs the given operation on the store in the background, if there is a store
+    fn with_store<F>(&self, f: impl FnOnce(Arc<dyn TokenStore<C::TokenIdentifier>>) -> F)
+    where
+        F: std::future::Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let Some(store) = &self.store else { return };
+        let fut = f(store.clone());
+        spawn(async move {
+            if let Err(e) = fut.await {
+                error!(target: "tokens", "{:?}", e.context("updating token store"));
+            }
+        });
+    }
+
+    fn save_token(&self, token: &HeaderValue, identifier: &C::TokenIdentifier, expires_at: u64) {
+        let Ok(token) = token.to_str() else { return };
+        let stored = StoredToken {
+            token_hash: token_hash(token),
+            identifier: identifier.clone(),
+            expires_at,
+        };
+        self.with_store(|store| async move { store.save(stored).await });
+    }
 
-        let entry = TokenEntry {
+    /// Keeps the token in memory until it expires
+    fn insert_token(
+        &self,
+        token: HeaderValue,
+        identifier: Arc<C::TokenIdentifier>,
+        expires_at: u64,
+        session_expires_at: u64,
+    ) {
+        let entry = self.new_entry(&token, identifier, expires_at, session_expires_at);
+        self.tokens.lock().insert(token_hash(&token), entry);
+    }
+
+    /// An entry that removes the token from memory once it expires
+    fn new_entry(
+        &self,
+        token: &HeaderValue,
+        identifier: Arc<C::TokenIdentifier>,
+        expires_at: u64,
+        session_expires_at: u64,
+    ) -> TokenEntry<C::TokenIdentifier> {
+        let hash = token_hash(token);
+        let tokens = self.tokens.clone();
+        let counters = self.counters.clone();
+        let token_duration = Duration::from_secs(expires_at.saturating_sub(unix_now()));
+        TokenEntry {
             _expiry_handle: spawn(async move {
                 sleep(token_duration).await;
-                tokens.lock().remove_by_left(&token2);
+                if tokens.lock().remove_by_left(&hash).is_some() {
+                    counters.expirations.fetch_add(1, Ordering::Relaxed);
+                }
             }),
-            identifier: id.clone(),
-        };
+            token: token.clone(),
+            identifier,
+            expires_at,
+            session_expires_at,
+        }
+    }
+
+    fn random_bytes(&self) -> Vec<u8> {
+        self.rng.with(|rng| {
+            rng.sample_iter(&Alphanumeric)
+                .take(C::TOKEN_LENGTH)
+                .collect()
+        })
+    }
 
-        self.tokens.lock().insert(token.clone(), entry);
+    /// Starts a new session for the identifier, if it does not need a second
+    /// factor or one was given for the same user
+    pub fn create_token_checked(
+        &self,
+        id: impl Into<Arc<C::TokenIdentifier>>,
+        second_factor: Option<SecondFactor>,
+    ) -> Result<TokenPair<C>, SecondFactorRequired>
+    where
+        C::TokenIdentifier: SecondFactorUser,
+    {
+        let id = id.into();
+        if let Some(second_factor) = &second_factor {
+            if second_factor.user() != id.second_factor_user() {
+                warn!(
+                    target: log_targets::SECURITY,
+                    "The second factor of {} was given for {}",
+                    second_factor.user(),
+                    id.second_factor_user()
+                );
+                return Err(SecondFactorRequired);
+            }
+        }
+        let required = self
+            .second_factor_required
+            .as_ref()
+            .map_or(false, |check| check(&id));
+        if required && second_factor.is_none() {
+            return Err(SecondFactorRequired);
+        }
+        Ok(self.create_token(id))
+    }
+
+    /// Starts a new session for the identifier
+    ///
+    /// Does not check whether it needs a second factor, which logins should
+    /// do with `create_token_checked`
+    pub fn create_token(&self, id: impl Into<Arc<C::TokenIdentifier>>) -> TokenPair<C> {
+        let session_expires_at = self
+            .session_lifetime
+            .map_or(u64::MAX, |x| unix_now() + x.as_secs());
+        self.issue_pair(id.into(), session_expires_at)
+    }
+
+    fn issue_pair(&self, id: Arc<C::TokenIdentifier>, session_expires_at: u64) -> TokenPair<C> {
+        let now = unix_now();
+        let access = self.issue_token(
+            id.clone(),
+            (now + self.token_duration.as_secs()).min(session_expires_at),
+            session_expires_at,
+        );
+        let refresh = self.refresh_duration.map(|refresh_duration| {
+            let token = unsafe { HeaderValue::from_maybe_shared_unchecked(self.random_bytes()) };
+            let mut refresh_tokens = self.refresh_tokens.lock();
+            refresh_tokens.prune(now);
+            refresh_tokens.insert(
+                token.clone(),
+                RefreshEntry {
+                    identifier: id,
+                    access: access.token.clone(),
+                    expires_at: (now + refresh_duration.as_secs()).min(session_expires_at),
+                    session_expires_at,
+                },
+            );
+            token
+        });
+        TokenPair { access, refresh }
+    }
+
+    fn issue_token(
+        &self,
+        id: Arc<C::TokenIdentifier>,
+        expires_at: u64,
+        session_expires_at: u64,
+    ) -> VerifiedToken<C> {
+        let mut bytes = self.random_bytes();
+        if let Some(signer) = &self.signer {
+            bytes = signer.sign(bytes, &*id, expires_at);
+        }
+
+        let token = unsafe { HeaderValue::from_maybe_shared_unchecked(bytes) };
+        self.save_token(&token, &id, expires_at);
+        self.insert_token(token.clone(), id.clone(), expires_at, session_expires_at);
 
         VerifiedToken {
             token,
@@ -89,16 +808,336 @@ impl<C: TokenConfig> TokenGranter<C> {
         }
     }
 
-    pub fn revoke_token(&self, token: &HeaderValue) {
-        self.tokens.lock().remove_by_left(token);
+    /// Exchanges a refresh token for a new pair of tokens, revoking it along
+    /// with the access token it was issued with
+    ///
+    /// Returns `None` if the refresh token is unknown or expired, its access
+    /// token was revoked, or the session has reached its lifetime
+    pub async fn refresh(&self, refresh_token: &HeaderValue) -> Option<TokenPair<C>> {
+        let entry = self.refresh_tokens.lock().remove(refresh_token)?;
+        if entry.expires_at <= unix_now() || self.is_revoked(&entry.access) {
+            return None;
+        }
+        self.revoke_token(&entry.access).await;
+        Some(self.issue_pair(entry.identifier, entry.session_expires_at))
     }
 
-    pub fn verify_token(&self, token: &HeaderValue) -> Option<VerifiedToken<C>> {
+    /// Makes the refresh token issued with `old` refer to the new token
+    fn reassign_refresh_tokens(&self, old: &HeaderValue, new: &VerifiedToken<C>) {
+        let mut refresh_tokens = self.refresh_tokens.lock();
+        let Some(token) = refresh_tokens.by_access.get(old).cloned() else {
+            return;
+        };
+        if let Some(mut entry) = refresh_tokens.remove(&token) {
+            entry.access = new.token.clone();
+            entry.identifier = new.identifier.clone();
+            refresh_tokens.insert(token, entry);
+        }
+    }
+
+    /// Revokes the token and the refresh token issued with it, even if it
+    /// was issued by another granter
+    ///
+    /// Only returns once the token is removed from the store, if there is one
+    pub async fn revoke_token(&self, token: &HeaderValue) {
+        let hash = token_hash(token);
+        let expires_at = self
+            .tokens
+            .lock()
+            .remove_by_left(&hash)
+            .map(|(_, entry)| entry.expires_at);
+        self.refresh_tokens.lock().remove_by_access(token);
+        let Ok(token) = token.to_str() else { return };
+        let expires_at = expires_at
+            .or_else(|| parse_claims::<IgnoredAny>(token).map(|claims| claims.exp))
+            .unwrap_or_else(|| unix_now() + self.token_duration.as_secs());
+
+        {
+            let now = unix_now();
+            let mut revoked = self.revoked.lock();
+            revoked.retain(|_, exp| *exp > now);
+            if expires_at <= now {
+                return;
+            }
+            revoked.insert(hash.clone(), expires_at);
+        }
+
+        if let Some(store) = &self.store {
+            if let Err(e) = store.remove(&hash).await {
+                error!(target: "tokens", "{:?}", e.context("removing token from store"));
+            }
+        }
+        if let Some(store) = &self.revocation_store {
+            if let Err(e) = store.save_revocation(&hash, expires_at).await {
+                error!(target: "tokens", "{:?}", e.context("saving token revocation"));
+            }
+        }
+    }
+
+    /// Revokes the token that this granter issued to the identifier, if any,
+    /// along with its refresh token
+    ///
+    /// Returns the revoked token, so that siblings can be told about it
+    pub async fn revoke_identifier(&self, identifier: &C::TokenIdentifier) -> Option<HeaderValue> {
+        let token = {
+            let lock = self.tokens.lock();
+            let hash = lock.get_by_right(identifier)?;
+            lock.get_by_left(hash)?.token.clone()
+        };
+        self.revoke_token(&token).await;
+        Some(token)
+    }
+
+    fn is_revoked(&self, token: &HeaderValue) -> bool {
+        token.to_str().map_or(false, |token| {
+            self.revoked.lock().contains_key(&token_hash(token))
+        })
+    }
+
+    /// Makes the token of the `old` identifier refer to `new` instead, without
+    /// changing the token itself or when it expires
+    ///
+    /// Signed tokens carry their identifier, so they are revoked and replaced
+    /// by a new token instead
+    ///
+    /// Returns the token if there was one
+    pub async fn reassign_token(
+        &self,
+        old: &C::TokenIdentifier,
+        new: impl Into<Arc<C::TokenIdentifier>>,
+    ) -> Option<VerifiedToken<C>> {
+        if self.signer.is_some() {
+            let (token, expires_at, session_expires_at) = {
+                let lock = self.tokens.lock();
+                let entry = lock.get_by_left(lock.get_by_right(old)?)?;
+                (
+                    entry.token.clone(),
+                    entry.expires_at,
+                    entry.session_expires_at,
+                )
+            };
+            let verified = self.issue_token(new.into(), expires_at, session_expires_at);
+            self.reassign_refresh_tokens(&token, &verified);
+            self.revoke_token(&token).await;
+            return Some(verified);
+        }
+
         let mut lock = self.tokens.lock();
-        let (token, entry) = lock.remove_by_left(token)?;
+        let (hash, mut entry) = lock.remove_by_right(old)?;
+        entry.identifier = new.into();
+        let token = entry.token.clone();
         let identifier = entry.identifier.clone();
-        lock.insert(token.clone(), entry);
-        Some(VerifiedToken { token, identifier })
+        let expires_at = entry.expires_at;
+        lock.insert(hash, entry);
+        drop(lock);
+        self.save_token(&token, &identifier, expires_at);
+        let verified = VerifiedToken { token, identifier };
+        self.reassign_refresh_tokens(&verified.token, &verified);
+        Some(verified)
+    }
+
+    /// Verifies the token against the tokens in memory, falling back to the
+    /// token store if there is one
+    pub async fn verify_token_with_store(&self, token: &HeaderValue) -> Option<VerifiedToken<C>> {
+        if let Some(verified) = self.verify_local(token) {
+            return Some(verified);
+        }
+        if let (Some(store), Ok(token_str)) = (&self.store, token.to_str()) {
+            match store.load(&token_hash(token_str)).await {
+                // Revoked before the store forgot it, such as while the removal was in flight
+                Ok(Some(_)) if self.is_revoked(token) => {}
+                Ok(Some(stored)) if stored.expires_at > unix_now() => {
+                    self.counters.stored_hits.fetch_add(1, Ordering::Relaxed);
+                    let identifier = Arc::new(stored.identifier);
+                    self.insert_token(
+                        token.clone(),
+                        identifier.clone(),
+                        stored.expires_at,
+                        u64::MAX,
+                    );
+                    return Some(VerifiedToken {
+                        token: token.clone(),
+                        identifier,
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => error!(target: "tokens", "{:?}", e.context("loading token from store")),
+            }
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    pub fn verify_token(&self, token: &HeaderValue) -> Option<VerifiedToken<C>> {
+        let verified = self.verify_local(token);
+        if verified.is_none() {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        verified
+    }
+
+    fn verify_local(&self, token: &HeaderValue) -> Option<VerifiedToken<C>> {
+        let hash = token_hash(token);
+        let identifier = {
+            let mut lock = self.tokens.lock();
+            match lock.get_by_left(&hash) {
+                Some(entry) => {
+                    let identifier = entry.identifier.clone();
+                    if let Some(expires_at) = self.slid_expiry(entry) {
+                        // Replaced while locked, so that a revocation in between is not undone
+                        let entry = self.new_entry(
+                            token,
+                            identifier.clone(),
+                            expires_at,
+                            entry.session_expires_at,
+                        );
+                        self.save_token(token, &identifier, expires_at);
+                        lock.insert(hash, entry);
+                    }
+                    Some(identifier)
+                }
+                None => None,
+            }
+        };
+
+        let identifier = match identifier {
+            Some(x) => {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                x
+            }
+            None => {
+                let identifier = self.verify_signed_token(token)?;
+                self.counters.signed_hits.fetch_add(1, Ordering::Relaxed);
+                identifier
+            }
+        };
+
+        Some(VerifiedToken {
+            token: token.clone(),
+            identifier,
+        })
+    }
+
+    /// The new expiry of the token if it should slide
+    fn slid_expiry(&self, entry: &TokenEntry<C::TokenIdentifier>) -> Option<u64> {
+        if !self.sliding_expiry || self.signer.is_some() {
+            return None;
+        }
+        let expires_at = (unix_now() + self.token_duration.as_secs()).min(entry.session_expires_at);
+        (expires_at >= entry.expires_at + MIN_EXPIRY_SLIDE).then_some(expires_at)
+    }
+
+    /// When the token expires in seconds since the unix epoch, if it is valid
+    pub fn get_expiry(&self, token: &HeaderValue) -> Option<u64> {
+        if let Some(entry) = self.tokens.lock().get_by_left(&token_hash(token)) {
+            return Some(entry.expires_at);
+        }
+        let signer = self.signer.as_ref()?;
+        if self.is_revoked(token) {
+            return None;
+        }
+        signer
+            .verify::<IgnoredAny>(token.as_bytes())
+            .map(|claims| claims.exp)
+    }
+
+    fn verify_signed_token(&self, token: &HeaderValue) -> Option<Arc<C::TokenIdentifier>> {
+        let signer = self.signer.as_ref()?;
+        if self.is_revoked(token) {
+            return None;
+        }
+        signer
+            .verify(token.as_bytes())
+            .map(|claims| Arc::new(claims.id))
+    }
+
+    pub fn get_stats(&self) -> TokenStats {
+        TokenStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            signed_hits: self.counters.signed_hits.load(Ordering::Relaxed),
+            stored_hits: self.counters.stored_hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            expirations: self.counters.expirations.load(Ordering::Relaxed),
+            active: self.tokens.lock().len(),
+        }
+    }
+}
+
+/// The header of every token issued by a `SignedTokenGranter`
+const JWT_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// Issues JSON Web Tokens signed with HS256, carrying the identifier, expiry
+/// and issuer as the `id`, `exp` and `iss` claims
+///
+/// Nothing is kept in memory, so any node with the same key can verify the
+/// tokens. In return, tokens cannot be revoked before they expire, so the
+/// token duration should be kept short
+pub struct SignedTokenGranter<C: TokenConfig> {
+    signer: TokenSigner,
+    token_duration: Duration,
+    audit: Option<Arc<AuthAudit>>,
+    _phantom: PhantomData<fn() -> C>,
+}
+
+impl<C: TokenConfig> SignedTokenGranter<C> {
+    pub fn new(
+        key: impl Into<Vec<u8>>,
+        issuer: impl Into<String>,
+        token_duration: Duration,
+    ) -> Self {
+        Self {
+            signer: TokenSigner {
+                key: key.into(),
+                issuer: issuer.into(),
+                other_issuers: vec![],
+            },
+            token_duration,
+            audit: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Records the tokens that fail to be extracted as a `VerifiedToken`, so
+    /// that IPs guessing tokens get locked out
+    pub fn set_audit(mut self, audit: Arc<AuthAudit>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn create_token(&self, id: impl Into<Arc<C::TokenIdentifier>>) -> VerifiedToken<C> {
+        let id = id.into();
+        let header = URL_SAFE_NO_PAD.encode(JWT_HEADER).into_bytes();
+        let expires_at = unix_now() + self.token_duration.as_secs();
+        let bytes = self.signer.sign(header, &*id, expires_at);
+
+        VerifiedToken {
+            token: unsafe { HeaderValue::from_maybe_shared_unchecked(bytes) },
+            identifier: id,
+        }
+    }
+
+    pub fn verify_token(&self, token: &HeaderValue) -> Option<VerifiedToken<C>> {
+        // Only HS256 is accepted, whatever algorithm the token claims to use
+        let header = token.to_str().ok()?.split('.').next()?;
+        if URL_SAFE_NO_PAD.decode(header).ok()? != JWT_HEADER.as_bytes() {
+            return None;
+        }
+        let claims = self.signer.verify(token.as_bytes())?;
+        Some(VerifiedToken {
+            token: token.clone(),
+            identifier: Arc::new(claims.id),
+        })
+    }
+}
+
+#[async_trait]
+impl<C: TokenConfig> TokenVerifier<C> for SignedTokenGranter<C> {
+    async fn verify(&self, token: &HeaderValue) -> Option<VerifiedToken<C>> {
+        self.verify_token(token)
+    }
+
+    fn audit(&self) -> Option<&AuthAudit> {
+        self.audit.as_deref()
     }
 }
 
@@ -111,20 +1150,61 @@ pub enum TokenVerificationError {
     MissingToken,
     InvalidTokenLength,
     InvalidToken,
+    /// The client failed to give a valid token too many times
+    LockedOut,
+    /// The token was in a cookie, but the request came from an origin that
+    /// may not use it
+    DisallowedOrigin,
 }
 
-impl IntoResponse for TokenVerificationError {
-    fn into_response(self) -> axum::response::Response {
+impl TokenVerificationError {
+    pub fn to_rejection(&self) -> Rejection {
         match self {
-            TokenVerificationError::MissingToken => (StatusCode::UNAUTHORIZED, "Missing token"),
-            TokenVerificationError::InvalidToken => {
-                (StatusCode::UNAUTHORIZED, "Invalid or expired token")
-            }
-            TokenVerificationError::InvalidTokenLength => {
-                (StatusCode::UNAUTHORIZED, "Invalid length for token")
+            TokenVerificationError::MissingToken => {
+                Rejection::new(StatusCode::UNAUTHORIZED, "missing_token", "Missing token")
             }
+            TokenVerificationError::InvalidToken => Rejection::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_token",
+                "Invalid or expired token",
+            ),
+            TokenVerificationError::InvalidTokenLength => Rejection::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_token_length",
+                "Invalid length for token",
+            ),
+            TokenVerificationError::LockedOut => Rejection::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "locked_out",
+                "Too many invalid tokens, try again later",
+            ),
+            TokenVerificationError::DisallowedOrigin => Rejection::new(
+                StatusCode::FORBIDDEN,
+                "disallowed_origin",
+                "The token cookie cannot be used from this origin",
+            ),
         }
-        .into_response()
+    }
+}
+
+impl IntoResponse for TokenVerificationError {
+    fn into_response(self) -> axum::response::Response {
+        self.to_rejection().into_response()
+    }
+}
+
+/// Why a `VerifiedToken` could not be extracted, in the format the client accepts
+pub struct TokenRejection {
+    pub error: TokenVerificationError,
+    format: RejectionFormat,
+}
+
+impl IntoResponse for TokenRejection {
+    fn into_response(self) -> axum::response::Response {
+        self.error
+            .to_rejection()
+            .set_format(self.format)
+            .into_response()
     }
 }
 
@@ -132,35 +1212,254 @@ impl IntoResponse for TokenVerificationError {
 impl<S, C> FromRequestParts<S> for VerifiedToken<C>
 where
     C: HeaderTokenConfig,
-    S: AsRef<TokenGranter<C>> + Sync,
+    S: AsRef<C::Granter> + Sync,
 {
-    type Rejection = TokenVerificationError;
+    type Rejection = TokenRejection;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Self::verify_parts(parts, state)
+            .await
+            .map_err(|error| TokenRejection {
+                error,
+                format: RejectionFormat::negotiate(&parts.headers),
+            })
+    }
+}
+
+impl<C: HeaderTokenConfig> VerifiedToken<C> {
+    async fn verify_parts<S>(parts: &Parts, state: &S) -> Result<Self, TokenVerificationError>
+    where
+        S: AsRef<C::Granter> + Sync,
+    {
+        let audit = state
+            .as_ref()
+            .audit()
+            .zip(parts_client_ip(&parts.extensions));
+        if let Some((audit, ip)) = audit {
+            if audit.locked_out(ip).is_some() {
+                return Err(TokenVerificationError::LockedOut);
+            }
+        }
+
+        let result = Self::find_and_verify(parts, state).await;
+        if let (
+            Some((audit, ip)),
+            Err(TokenVerificationError::InvalidToken | TokenVerificationError::InvalidTokenLength),
+        ) = (audit, &result)
+        {
+            audit.record_failure(ip, AuthFailure::TokenVerification, parts.uri.path());
+        }
+        result
+    }
+
+    /// Verifies the token in the header, query or cookie, in that order
+    async fn find_and_verify<S>(parts: &Parts, state: &S) -> Result<Self, TokenVerificationError>
+    where
+        S: AsRef<C::Granter> + Sync,
+    {
         if let Some(token) = parts.headers.get(C::HEADER_NAME) {
-            if token.len() != C::TOKEN_LENGTH {
+            // Signed tokens are longer than the random part
+            if token.len() < C::TOKEN_LENGTH {
                 return Err(TokenVerificationError::InvalidTokenLength);
             }
 
             return state
                 .as_ref()
-                .verify_token(token)
+                .verify(token)
+                .await
                 .ok_or(TokenVerificationError::InvalidToken);
         }
 
-        if let Some(query) = parts.uri.query() {
-            if let Some(idx) = query.find(&C::HEADER_NAME.to_lowercase()) {
-                return if let Some(token) = query.get((idx + 12)..(idx + 12 + C::TOKEN_LENGTH)) {
-                    state
-                        .as_ref()
-                        .verify_token(&HeaderValue::from_str(token).unwrap())
-                        .ok_or(TokenVerificationError::InvalidToken)
-                } else {
-                    Err(TokenVerificationError::InvalidTokenLength)
-                };
+        if let Some(token) = parts.uri.query().and_then(find_query_param::<C>) {
+            let Ok(token) = percent_decode_str(token).decode_utf8() else {
+                return Err(TokenVerificationError::InvalidToken);
+            };
+            return Self::verify_str(&token, state).await;
+        }
+
+        if let Some(cookie) = C::COOKIE {
+            if let Some(token) = find_cookie(&parts.headers, cookie.name) {
+                if !cookie.allows_origin(&parts.headers) {
+                    return Err(TokenVerificationError::DisallowedOrigin);
+                }
+                return Self::verify_str(token, state).await;
             }
         }
 
         Err(TokenVerificationError::MissingToken)
     }
+
+    async fn verify_str<S>(token: &str, state: &S) -> Result<Self, TokenVerificationError>
+    where
+        S: AsRef<C::Granter> + Sync,
+    {
+        if token.len() < C::TOKEN_LENGTH {
+            return Err(TokenVerificationError::InvalidTokenLength);
+        }
+        let Ok(token) = HeaderValue::from_str(token) else {
+            return Err(TokenVerificationError::InvalidToken);
+        };
+        state
+            .as_ref()
+            .verify(&token)
+            .await
+            .ok_or(TokenVerificationError::InvalidToken)
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::marker::PhantomData;
+
+    use anyhow::Error;
+    use axum::async_trait;
+    use serde::{de::DeserializeOwned, Serialize};
+    use tokio::task::spawn_blocking;
+
+    use super::{unix_now, RevocationStore, StoredToken, TokenStore};
+    use crate::db::redis::RedisClient;
+
+    /// Token hashes by when they expire
+    const REVOCATIONS_KEY: &str = "tokens:revoked";
+
+    fn token_key(token_hash: &str) -> String {
+        format!("tokens:{token_hash}")
+    }
+
+    /// Keeps each token as a key that expires on its own, and revocations in
+    /// a sorted set by expiry
+    pub struct RedisTokenStore<ID> {
+        client: RedisClient,
+        _phantom: PhantomData<fn() -> ID>,
+    }
+
+    impl<ID> RedisTokenStore<ID> {
+        pub fn new(client: RedisClient) -> Self {
+            Self {
+                client,
+                _phantom: PhantomData,
+            }
+        }
+
+        /// Runs the given commands on a blocking thread, dropping the connection if they fail
+        async fn run<T: Send + 'static>(
+            &self,
+            f: impl FnOnce(&mut redis::cluster::ClusterConnection) -> redis::RedisResult<T>
+                + Send
+                + 'static,
+        ) -> Result<T, Error> {
+            let client = self.client.clone();
+            spawn_blocking(move || {
+                let mut connection = client.get_connection()?;
+                match f(&mut *connection) {
+                    Ok(x) => Ok(x),
+                    Err(e) => {
+                        connection.invalidate();
+                        Err(e.into())
+                    }
+                }
+            })
+            .await?
+        }
+    }
+
+    #[async_trait]
+    impl<ID> TokenStore<ID> for RedisTokenStore<ID>
+    where
+        ID: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        async fn save(&self, token: StoredToken<ID>) -> Result<(), Error> {
+            let ttl = token.expires_at.saturating_sub(unix_now());
+            if ttl == 0 {
+                return Ok(());
+            }
+            let key = token_key(&token.token_hash);
+            let value = serde_json::to_string(&token)?;
+            self.run(move |connection| {
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(value)
+                    .arg("EX")
+                    .arg(ttl)
+                    .query(connection)
+            })
+            .await
+        }
+
+        async fn remove(&self, token_hash: &str) -> Result<(), Error> {
+            let key = token_key(token_hash);
+            self.run(move |connection| redis::cmd("DEL").arg(key).query(connection))
+                .await
+        }
+
+        async fn load(&self, token_hash: &str) -> Result<Option<StoredToken<ID>>, Error> {
+            let key = token_key(token_hash);
+            let value: Option<String> = self
+                .run(move |connection| redis::cmd("GET").arg(key).query(connection))
+                .await?;
+            Ok(value.map(|x| serde_json::from_str(&x)).transpose()?)
+        }
+    }
+
+    #[async_trait]
+    impl<ID: Send + Sync + 'static> RevocationStore for RedisTokenStore<ID> {
+        async fn save_revocation(&self, token_hash: &str, expires_at: u64) -> Result<(), Error> {
+            let token_hash = token_hash.to_string();
+            self.run(move |connection| {
+                redis::cmd("ZADD")
+                    .arg(REVOCATIONS_KEY)
+                    .arg(expires_at)
+                    .arg(token_hash)
+                    .query(connection)
+            })
+            .await
+        }
+
+        async fn load_revocations(&self) -> Result<Vec<(String, u64)>, Error> {
+            let now = unix_now();
+            self.run(move |connection| {
+                redis::cmd("ZREMRANGEBYSCORE")
+                    .arg(REVOCATIONS_KEY)
+                    .arg("-inf")
+                    .arg(now)
+                    .query::<()>(connection)?;
+                redis::cmd("ZRANGE")
+                    .arg(REVOCATIONS_KEY)
+                    .arg(0)
+                    .arg(-1)
+                    .arg("WITHSCORES")
+                    .query(connection)
+            })
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestTokenConfig;
+
+    impl TokenConfig for TestTokenConfig {
+        type TokenIdentifier = String;
+        const TOKEN_LENGTH: usize = 32;
+    }
+
+    fn seeded_granter(seed: u64) -> TokenGranter<TestTokenConfig> {
+        TokenGranter::new(Duration::from_secs(60)).set_rng(SharedRng::seeded(seed))
+    }
+
+    #[tokio::test]
+    async fn seeded_granters_issue_the_same_tokens() {
+        let first = seeded_granter(3);
+        let second = seeded_granter(3);
+        let other = seeded_granter(4);
+        for user in ["first", "second"] {
+            let token = first.create_token(user.to_string()).access.token;
+            assert_eq!(token, second.create_token(user.to_string()).access.token);
+            assert_ne!(token, other.create_token(user.to_string()).access.token);
+            assert_eq!(token.len(), TestTokenConfig::TOKEN_LENGTH);
+        }
+    }
 }
@@ -0,0 +1,259 @@
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use constant_time_eq::constant_time_eq;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::audit::{AuthAudit, AuthFailure};
+
+/// How long each code lasts, in seconds
+pub const TOTP_PERIOD: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// 160 bits, as recommended by RFC 4226
+const SECRET_SIZE: usize = 20;
+/// How many steps before or after the current one are accepted by default, so
+/// that codes keep working on clocks that are slightly off
+pub const DEFAULT_DRIFT_STEPS: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_SIZE: usize = 10;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+/// The HOTP code of RFC 4226 for the counter
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let tag = tag.as_ref();
+    let offset = (tag[tag.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes(
+        tag[offset..offset + 4]
+            .try_into()
+            .expect("HMAC-SHA1 tags to be 20 bytes"),
+    ) & 0x7fff_ffff;
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+/// Recovery codes are compared without the dashes and spaces they are shown with
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .map(|x| x.to_ascii_uppercase())
+        .collect();
+    STANDARD.encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/// Proof that a user gave a valid second factor, which is required by
+/// `TokenGranter::create_token_checked` for identifiers that need one
+///
+/// Can only be made by verifying a `TotpSecret`, and only starts sessions for
+/// the user it was verified for
+#[derive(Debug)]
+pub struct SecondFactor {
+    user: String,
+}
+
+impl SecondFactor {
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+}
+
+/// The identifier of tokens whose holders can have a second factor
+pub trait SecondFactorUser {
+    /// Unique for each user, such as an email
+    fn second_factor_user(&self) -> &str;
+}
+
+/// What to show to a user that is enabling TOTP, which is only available once
+#[derive(Serialize, Clone, Debug)]
+pub struct TotpProvisioning {
+    /// For authenticator apps that cannot scan QR codes
+    pub secret: String,
+    /// The `otpauth://` URI to encode as a QR code
+    pub uri: String,
+    /// Each of these can be used once instead of a code
+    pub recovery_codes: Vec<String>,
+}
+
+/// The TOTP secret of a user, as stored in a database
+///
+/// Codes use HMAC-SHA1, 6 digits and a 30 second period, as those are the
+/// only settings that every authenticator app supports
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TotpSecret {
+    secret: Vec<u8>,
+    /// Codes of this step or earlier are refused, so that they cannot be replayed
+    #[serde(default = "Default::default")]
+    last_step: u64,
+    /// Hashed, and removed once used
+    #[serde(default = "Default::default")]
+    recovery_codes: Vec<String>,
+}
+
+impl TotpSecret {
+    /// Generates a secret for the given account, shown to the user as issued
+    /// by the issuer
+    pub fn generate(issuer: &str, account: &str) -> (Self, TotpProvisioning) {
+        let mut rng = thread_rng();
+        let mut secret = vec![0; SECRET_SIZE];
+        rng.fill_bytes(&mut secret);
+
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let code: String = (&mut rng)
+                    .sample_iter(&Alphanumeric)
+                    .take(RECOVERY_CODE_SIZE)
+                    .map(|x| char::from(x).to_ascii_uppercase())
+                    .collect();
+                format!(
+                    "{}-{}",
+                    &code[..RECOVERY_CODE_SIZE / 2],
+                    &code[RECOVERY_CODE_SIZE / 2..]
+                )
+            })
+            .collect();
+
+        let totp = Self {
+            secret,
+            last_step: 0,
+            recovery_codes: recovery_codes
+                .iter()
+                .map(|x| hash_recovery_code(x))
+                .collect(),
+        };
+        let provisioning = TotpProvisioning {
+            secret: totp.base32_secret(),
+            uri: totp.provisioning_uri(issuer, account),
+            recovery_codes,
+        };
+        (totp, provisioning)
+    }
+
+    pub fn base32_secret(&self) -> String {
+        base32_encode(&self.secret)
+    }
+
+    /// The `otpauth://` URI that authenticator apps read from QR codes
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> String {
+        let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC);
+        let account = utf8_percent_encode(account, NON_ALPHANUMERIC);
+        format!(
+            "otpauth://totp/{issuer}:{account}?secret={}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_PERIOD}",
+            self.base32_secret()
+        )
+    }
+
+    /// Checks a code of the user, accepting those of up to `drift_steps`
+    /// periods before or after the current one
+    ///
+    /// Each code is only accepted once, so the secret must be saved again
+    /// after it is verified
+    pub fn verify(&mut self, user: &str, code: &str, drift_steps: u64) -> Option<SecondFactor> {
+        let code = code.trim();
+        if code.len() != TOTP_DIGITS as usize {
+            return None;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        let step = now / TOTP_PERIOD;
+
+        let mut accepted = None;
+        // Every step is checked so that the time taken does not reveal which matched
+        for candidate in step.saturating_sub(drift_steps)..=step + drift_steps {
+            if constant_time_eq(hotp(&self.secret, candidate).as_bytes(), code.as_bytes())
+                & (candidate > self.last_step)
+            {
+                accepted = Some(candidate);
+            }
+        }
+        self.last_step = accepted?;
+        Some(SecondFactor {
+            user: user.to_string(),
+        })
+    }
+
+    /// Like `verify`, but refuses codes from IPs that the audit locked out and
+    /// records wrong codes in it, so that codes cannot be guessed
+    pub fn verify_audited(
+        &mut self,
+        user: &str,
+        code: &str,
+        drift_steps: u64,
+        audit: &AuthAudit,
+        ip: IpAddr,
+    ) -> Option<SecondFactor> {
+        if audit.locked_out(ip).is_some() {
+            return None;
+        }
+        let second_factor = self.verify(user, code, drift_steps);
+        if second_factor.is_none() {
+            audit.record_failure(ip, AuthFailure::SecondFactor, user);
+        }
+        second_factor
+    }
+
+    /// Accepts one of the recovery codes instead of a TOTP code, which cannot
+    /// be used again
+    pub fn use_recovery_code(&mut self, user: &str, code: &str) -> Option<SecondFactor> {
+        let hash = hash_recovery_code(code);
+        let index = self
+            .recovery_codes
+            .iter()
+            .position(|x| constant_time_eq(x.as_bytes(), hash.as_bytes()))?;
+        self.recovery_codes.remove(index);
+        Some(SecondFactor {
+            user: user.to_string(),
+        })
+    }
+
+    pub fn remaining_recovery_codes(&self) -> usize {
+        self.recovery_codes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_rfc_4226_vectors() {
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (counter, code) in expected.into_iter().enumerate() {
+            assert_eq!(hotp(b"12345678901234567890", counter as u64), code);
+        }
+    }
+}
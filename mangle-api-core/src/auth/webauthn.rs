@@ -0,0 +1,421 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    async_trait,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::{error, warn};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
+use ring::{hmac, rand::SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, Passkey, PasskeyAuthentication, PasskeyRegistration,
+    PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url, Uuid,
+    Webauthn, WebauthnBuilder,
+};
+
+use super::{
+    audit::ClientIp,
+    pending::{PendingAuths, DEFAULT_MAX_PENDING_AUTHS},
+    token::{HeaderTokenConfig, VerifiedToken},
+};
+use crate::{log_targets, rejection::Rejection};
+
+/// How much time users have to use their passkey once a ceremony starts
+pub const MAX_CEREMONY_WAIT_TIME: Duration = Duration::from_secs(300);
+const CEREMONY_ID_SIZE: usize = 32;
+const CHALLENGE_SIZE: usize = 32;
+/// Namespaces the user handles given to authenticators
+const USER_HANDLE_NAMESPACE: Uuid = Uuid::from_u128(0x6d61_6e67_6c65_2d70_6173_736b_6579_7321);
+
+/// Where the passkeys of every user are kept, such as a database table
+#[async_trait]
+pub trait PasskeyStore: Send + Sync + 'static {
+    async fn load_passkeys(&self, user: &str) -> Result<Vec<Passkey>>;
+    /// Replaces every passkey of the user
+    async fn save_passkeys(&self, user: &str, passkeys: Vec<Passkey>) -> Result<()>;
+}
+
+/// The identifier of tokens whose holders can register passkeys
+pub trait PasskeyUser {
+    /// Unique and never changing for each user, such as an email
+    fn passkey_user(&self) -> &str;
+
+    /// What authenticators show the passkey as
+    fn passkey_display_name(&self) -> &str {
+        self.passkey_user()
+    }
+}
+
+/// Logs users in once they prove they hold one of their passkeys
+#[async_trait]
+pub trait PasskeyLogin {
+    /// Responds with how the user is now logged in, such as a new token
+    async fn passkey_login(&self, user: String) -> Response;
+}
+
+/// Registers passkeys and logs users in with them, as described by WebAuthn
+///
+/// Ceremonies are kept in memory until they finish, the same way pending
+/// OAuth authentications are
+pub struct Passkeys {
+    webauthn: Webauthn,
+    rp_id: String,
+    store: Arc<dyn PasskeyStore>,
+    registrations: PendingAuths<(String, PasskeyRegistration)>,
+    /// Users without passkeys have no authentication, so that they can still
+    /// start logins that look like any other
+    authentications: PendingAuths<(String, Option<PasskeyAuthentication>)>,
+    /// Derives the credentials offered to users without passkeys
+    dummy_key: hmac::Key,
+}
+
+fn new_ceremony_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CEREMONY_ID_SIZE)
+        .map(char::from)
+        .collect()
+}
+
+/// A ceremony that was started, which the client passes to the authenticator
+#[derive(Serialize)]
+pub struct CeremonyStart<T> {
+    /// Given back when finishing the ceremony
+    pub ceremony: String,
+    pub options: T,
+}
+
+impl Passkeys {
+    /// Passkeys are bound to the relying party ID, which is the domain of the
+    /// origin or a parent of it, such as `example.com` for
+    /// `https://play.example.com`
+    pub fn new(rp_id: &str, rp_origin: &Url, store: impl PasskeyStore) -> Result<Self> {
+        Ok(Self {
+            webauthn: WebauthnBuilder::new(rp_id, rp_origin)
+                .context("configuring webauthn")?
+                .timeout(MAX_CEREMONY_WAIT_TIME)
+                .build()
+                .context("configuring webauthn")?,
+            rp_id: rp_id.to_string(),
+            store: Arc::new(store),
+            registrations: PendingAuths::new(DEFAULT_MAX_PENDING_AUTHS, MAX_CEREMONY_WAIT_TIME),
+            authentications: PendingAuths::new(DEFAULT_MAX_PENDING_AUTHS, MAX_CEREMONY_WAIT_TIME),
+            dummy_key: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .map_err(|_| anyhow!("Could not generate passkey dummy key"))?,
+        })
+    }
+
+    /// Starts registering a new passkey for the user
+    ///
+    /// Returns None if too many ceremonies are already pending, in total or for
+    /// the IP
+    pub async fn start_registration(
+        &self,
+        user: &str,
+        display_name: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Option<CeremonyStart<CreationChallengeResponse>>> {
+        let existing = self
+            .store
+            .load_passkeys(user)
+            .await?
+            .iter()
+            .map(|x| x.cred_id().clone())
+            .collect();
+        let (options, registration) = self.webauthn.start_passkey_registration(
+            // The same user handle lets authenticators replace old passkeys
+            Uuid::new_v5(&USER_HANDLE_NAMESPACE, user.as_bytes()),
+            user,
+            display_name,
+            Some(existing),
+        )?;
+        let ceremony = new_ceremony_id();
+        if !self
+            .registrations
+            .insert(ceremony.clone(), ip, (user.to_string(), registration))
+        {
+            return Ok(None);
+        }
+        Ok(Some(CeremonyStart { ceremony, options }))
+    }
+
+    /// Saves the passkey if the credential answers the challenge of the
+    /// ceremony, which must have been started for the same user
+    ///
+    /// Returns false if the ceremony is unknown or the credential is invalid
+    pub async fn finish_registration(
+        &self,
+        user: &str,
+        ceremony: &str,
+        credential: &RegisterPublicKeyCredential,
+    ) -> Result<bool> {
+        let Some((ceremony_user, registration)) = self.registrations.remove(ceremony) else {
+            return Ok(false);
+        };
+        if ceremony_user != user {
+            warn!(
+                target: log_targets::SECURITY,
+                "{user} tried to finish the passkey registration of {ceremony_user}"
+            );
+            return Ok(false);
+        }
+        let passkey = match self
+            .webauthn
+            .finish_passkey_registration(credential, &registration)
+        {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(target: log_targets::SECURITY, "Invalid passkey registration by {user}: {e}");
+                return Ok(false);
+            }
+        };
+        let mut passkeys = self.store.load_passkeys(user).await?;
+        passkeys.push(passkey);
+        self.store.save_passkeys(user, passkeys).await?;
+        Ok(true)
+    }
+
+    /// Starts logging in the user with one of their passkeys
+    ///
+    /// Users without passkeys get a ceremony that can never finish, but looks
+    /// like any other, so that users cannot be enumerated
+    ///
+    /// Returns None if too many ceremonies are already pending, in total or for
+    /// the IP
+    pub async fn start_login(
+        &self,
+        user: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Option<CeremonyStart<RequestChallengeResponse>>> {
+        let passkeys = self.store.load_passkeys(user).await?;
+        let (options, authentication) = if passkeys.is_empty() {
+            (self.dummy_login_options(user)?, None)
+        } else {
+            let (options, authentication) =
+                self.webauthn.start_passkey_authentication(&passkeys)?;
+            (options, Some(authentication))
+        };
+        let ceremony = new_ceremony_id();
+        if !self
+            .authentications
+            .insert(ceremony.clone(), ip, (user.to_string(), authentication))
+        {
+            return Ok(None);
+        }
+        Ok(Some(CeremonyStart { ceremony, options }))
+    }
+
+    /// The options of `start_passkey_authentication` for users without
+    /// passkeys, which always offer the same made up credential for the same
+    /// user, as real ones do
+    fn dummy_login_options(&self, user: &str) -> Result<RequestChallengeResponse> {
+        let mut challenge = [0; CHALLENGE_SIZE];
+        thread_rng().fill_bytes(&mut challenge);
+        let credential = hmac::sign(&self.dummy_key, user.as_bytes());
+        serde_json::from_value(json!({
+            "publicKey": {
+                "challenge": URL_SAFE_NO_PAD.encode(challenge),
+                "timeout": MAX_CEREMONY_WAIT_TIME.as_millis() as u64,
+                "rpId": self.rp_id,
+                "allowCredentials": [{
+                    "type": "public-key",
+                    "id": URL_SAFE_NO_PAD.encode(credential),
+                }],
+                "userVerification": "required",
+            }
+        }))
+        .context("building dummy passkey login options")
+    }
+
+    /// Returns the user that the ceremony was for if the credential answers
+    /// its challenge, updating the counter of the passkey that was used
+    pub async fn finish_login(
+        &self,
+        ceremony: &str,
+        credential: &PublicKeyCredential,
+    ) -> Result<Option<String>> {
+        let Some((user, authentication)) = self.authentications.remove(ceremony) else {
+            return Ok(None);
+        };
+        let Some(authentication) = authentication else {
+            warn!(target: log_targets::SECURITY, "Passkey login for {user}, who has no passkeys");
+            return Ok(None);
+        };
+        let result = match self
+            .webauthn
+            .finish_passkey_authentication(credential, &authentication)
+        {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(target: log_targets::SECURITY, "Invalid passkey login for {user}: {e}");
+                return Ok(None);
+            }
+        };
+        if result.needs_update() {
+            let mut passkeys = self.store.load_passkeys(&user).await?;
+            for passkey in &mut passkeys {
+                passkey.update_credential(&result);
+            }
+            self.store.save_passkeys(&user, passkeys).await?;
+        }
+        Ok(Some(user))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FinishRegistration {
+    ceremony: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Deserialize)]
+pub struct StartLogin {
+    user: String,
+}
+
+#[derive(Deserialize)]
+pub struct FinishLogin {
+    ceremony: String,
+    credential: PublicKeyCredential,
+}
+
+fn internal_error(headers: &HeaderMap, e: anyhow::Error) -> Response {
+    error!(target: "webauthn", "{e:?}");
+    Rejection::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+        "Internal Error",
+    )
+    .negotiate(headers)
+    .into_response()
+}
+
+fn too_many_ceremonies(headers: &HeaderMap) -> Response {
+    Rejection::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "too_many_ceremonies",
+        "Too many passkey ceremonies are pending",
+    )
+    .negotiate(headers)
+    .into_response()
+}
+
+fn invalid_credential(headers: &HeaderMap) -> Response {
+    Rejection::new(
+        StatusCode::UNAUTHORIZED,
+        "invalid_credential",
+        "Invalid or expired passkey credential",
+    )
+    .negotiate(headers)
+    .into_response()
+}
+
+pub async fn start_registration_handler<S, C>(
+    State(state): State<S>,
+    token: VerifiedToken<C>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+) -> Response
+where
+    S: AsRef<Passkeys>,
+    C: HeaderTokenConfig,
+    C::TokenIdentifier: PasskeyUser,
+{
+    let id = &token.identifier;
+    match state
+        .as_ref()
+        .start_registration(id.passkey_user(), id.passkey_display_name(), ip)
+        .await
+    {
+        Ok(Some(start)) => Json(start).into_response(),
+        Ok(None) => too_many_ceremonies(&headers),
+        Err(e) => internal_error(&headers, e),
+    }
+}
+
+pub async fn finish_registration_handler<S, C>(
+    State(state): State<S>,
+    token: VerifiedToken<C>,
+    headers: HeaderMap,
+    Json(FinishRegistration {
+        ceremony,
+        credential,
+    }): Json<FinishRegistration>,
+) -> Response
+where
+    S: AsRef<Passkeys>,
+    C: HeaderTokenConfig,
+    C::TokenIdentifier: PasskeyUser,
+{
+    match state
+        .as_ref()
+        .finish_registration(token.identifier.passkey_user(), &ceremony, &credential)
+        .await
+    {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => invalid_credential(&headers),
+        Err(e) => internal_error(&headers, e),
+    }
+}
+
+pub async fn start_login_handler<S>(
+    State(state): State<S>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Json(StartLogin { user }): Json<StartLogin>,
+) -> Response
+where
+    S: AsRef<Passkeys>,
+{
+    match state.as_ref().start_login(&user, ip).await {
+        Ok(Some(start)) => Json(start).into_response(),
+        Ok(None) => too_many_ceremonies(&headers),
+        Err(e) => internal_error(&headers, e),
+    }
+}
+
+pub async fn finish_login_handler<S>(
+    State(state): State<S>,
+    headers: HeaderMap,
+    Json(FinishLogin {
+        ceremony,
+        credential,
+    }): Json<FinishLogin>,
+) -> Response
+where
+    S: AsRef<Passkeys> + PasskeyLogin,
+{
+    match state.as_ref().finish_login(&ceremony, &credential).await {
+        Ok(Some(user)) => state.passkey_login(user).await,
+        Ok(None) => invalid_credential(&headers),
+        Err(e) => internal_error(&headers, e),
+    }
+}
+
+/// The routes of both ceremonies, to be nested under a path such as
+/// `/passkeys`
+///
+/// Registering needs a token of `C`, while logging in is public
+pub fn passkey_router<S, C>() -> Router<S>
+where
+    S: AsRef<Passkeys> + AsRef<C::Granter> + PasskeyLogin + Send + Sync + Clone + 'static,
+    C: HeaderTokenConfig,
+    C::TokenIdentifier: PasskeyUser,
+{
+    Router::new()
+        .route("/register/start", post(start_registration_handler::<S, C>))
+        .route(
+            "/register/finish",
+            post(finish_registration_handler::<S, C>),
+        )
+        .route("/login/start", post(start_login_handler::<S>))
+        .route("/login/finish", post(finish_login_handler::<S>))
+}
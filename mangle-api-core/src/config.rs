@@ -0,0 +1,185 @@
+use std::{collections::BTreeMap, env, fs::read_to_string, mem::take};
+
+use anyhow::{Context, Error, Result};
+use serde::{
+    de::{value::MapDeserializer, DeserializeOwned, IntoDeserializer, Visitor},
+    forward_to_deserialize_any, Deserializer,
+};
+use toml::{de::Error as TomlError, value::Table, Value};
+
+/// Separates the fields of nested tables in the names of environment variables
+const NESTING_SEPARATOR: &str = "__";
+
+/// Reads a TOML config, where any field can be overridden by an environment
+/// variable named after it, such as `BOLA_API_TOKEN` for `api_token` with the
+/// prefix `BOLA`
+///
+/// Environment variables take precedence over the file, which takes
+/// precedence over the defaults of the config
+pub fn read_config<Config: DeserializeOwned>(
+    path: &str,
+    env_prefix: Option<&str>,
+) -> Result<Config> {
+    let err_msg = format!("Reading configuration file: {path}");
+    let table: Table =
+        toml::from_str(&read_to_string(path).context(err_msg.clone())?).context(err_msg.clone())?;
+    let layered = match env_prefix {
+        Some(env_prefix) => apply_env_overrides(table, env_prefix, env::vars())?,
+        None => Layered::Table(Layered::split_table(table)),
+    };
+    Config::deserialize(layered).context(err_msg)
+}
+
+/// A config table where some fields were set by environment variables
+///
+/// Those fields are only parsed once the type of the field is known, so that
+/// a variable such as `BOLA_NODE_NAME=123` still sets a string
+enum Layered {
+    File(Value),
+    Env(String),
+    Table(BTreeMap<String, Layered>),
+}
+
+impl Layered {
+    fn split_table(table: Table) -> BTreeMap<String, Self> {
+        table
+            .into_iter()
+            .map(|(key, value)| (key, Self::File(value)))
+            .collect()
+    }
+
+    /// The value as it would have been written in the file
+    fn into_value(self) -> Value {
+        match self {
+            Layered::File(x) => x,
+            Layered::Env(raw) => parse_value(raw),
+            Layered::Table(x) => Value::Table(
+                x.into_iter()
+                    .map(|(key, value)| (key, value.into_value()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<'de> IntoDeserializer<'de, TomlError> for Layered {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> Deserializer<'de> for Layered {
+    type Error = TomlError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TomlError> {
+        match self {
+            Layered::File(x) => x.deserialize_any(visitor),
+            Layered::Env(raw) => parse_value(raw).deserialize_any(visitor),
+            Layered::Table(x) => {
+                let mut map = MapDeserializer::new(x.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TomlError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TomlError> {
+        match self {
+            Layered::Env(raw) => visitor.visit_string(raw),
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TomlError> {
+        match self {
+            Layered::File(x) => x.deserialize_option(visitor),
+            other => visitor.visit_some(other),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TomlError> {
+        match self {
+            Layered::File(x) => x.deserialize_newtype_struct(name, visitor),
+            other => visitor.visit_newtype_struct(other),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TomlError> {
+        self.into_value().deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Sets the fields named by the given environment variables that start with
+/// `{env_prefix}_`
+///
+/// Fields of nested tables are separated by two underscores, such as
+/// `BOLA_TCP__NODELAY` for `nodelay` in `tcp`. Values are parsed as the type
+/// of their field, or as TOML where the type is not known, so tables can be
+/// given inline. Fields that are strings are always set to strings
+fn apply_env_overrides(
+    table: Table,
+    env_prefix: &str,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Layered> {
+    let prefix = format!("{env_prefix}_");
+    let mut root = Layered::split_table(table);
+    for (name, raw) in vars {
+        let Some(field) = name.strip_prefix(&prefix) else {
+            continue;
+        };
+        let field = field.to_ascii_lowercase();
+        let mut path: Vec<_> = field.split(NESTING_SEPARATOR).collect();
+        let Some(last) = path.pop().filter(|x| !x.is_empty()) else {
+            return Err(Error::msg(format!("{name} does not name a field")));
+        };
+
+        let mut current = &mut root;
+        for key in path {
+            let entry = current
+                .entry(key.to_string())
+                .or_insert_with(|| Layered::Table(BTreeMap::new()));
+            if let Layered::File(Value::Table(table)) = entry {
+                *entry = Layered::Table(Layered::split_table(take(table)));
+            }
+            current = match entry {
+                Layered::Table(x) => x,
+                _ => {
+                    return Err(Error::msg(format!(
+                        "{name} sets a field in {key}, which is not a table"
+                    )))
+                }
+            };
+        }
+        current.insert(last.to_string(), Layered::Env(raw));
+    }
+    Ok(Layered::Table(root))
+}
+
+/// Parses a single TOML value, or keeps it as a string
+fn parse_value(raw: String) -> Value {
+    toml::from_str::<Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut x| x.remove("value"))
+        .unwrap_or(Value::String(raw))
+}
@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result};
+use axum::http::{header::HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
+
+/// Headers that browser clients can always read, so that they can back off
+/// before they are rate limited
+pub(crate) const ALWAYS_EXPOSED: [&str; 3] = [
+    "x-ratelimit-limit",
+    "x-ratelimit-remaining",
+    "x-ratelimit-reset",
+];
+
+/// The CORS settings of an API, as read from a config
+///
+/// Any list can be `["*"]` to allow everything, unless credentials are allowed
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct CorsConfig {
+    #[serde(default = "Default::default")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "Default::default")]
+    pub allowed_origins: Vec<String>,
+    /// Request headers that browsers may send, such as `Login-Token`
+    #[serde(default = "Default::default")]
+    pub allowed_headers: Vec<String>,
+    /// Response headers that browsers may read, on top of the rate limit headers
+    #[serde(default = "Default::default")]
+    pub exposed_headers: Vec<String>,
+    /// Whether browsers may send cookies and authorization headers
+    #[serde(default = "Default::default")]
+    pub allow_credentials: bool,
+    /// How long browsers may cache the response to a preflight request
+    #[serde(default = "Default::default")]
+    pub max_age: Option<Duration>,
+}
+
+fn is_wildcard(list: &[String]) -> bool {
+    list.iter().any(|x| x == "*")
+}
+
+fn parse_all<T>(list: &[String], kind: &str) -> Result<Vec<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    list.iter()
+        .map(|x| x.parse().context(format!("Parsing CORS {kind} {x}")))
+        .collect()
+}
+
+impl CorsConfig {
+    pub(crate) fn to_layer(&self) -> Result<CorsLayer> {
+        if self.allow_credentials {
+            for (name, list) in [
+                ("allowed_methods", &self.allowed_methods),
+                ("allowed_origins", &self.allowed_origins),
+                ("allowed_headers", &self.allowed_headers),
+                ("exposed_headers", &self.exposed_headers),
+            ] {
+                if is_wildcard(list) {
+                    return Err(Error::msg(format!(
+                        "CORS {name} cannot be * when credentials are allowed"
+                    )));
+                }
+            }
+        }
+
+        let methods = if is_wildcard(&self.allowed_methods) {
+            AllowMethods::any()
+        } else {
+            parse_all::<Method>(&self.allowed_methods, "method")?.into()
+        };
+        let origins = if is_wildcard(&self.allowed_origins) {
+            AllowOrigin::any()
+        } else {
+            parse_all::<HeaderValue>(&self.allowed_origins, "origin")?.into()
+        };
+        let headers = if is_wildcard(&self.allowed_headers) {
+            AllowHeaders::any()
+        } else {
+            parse_all::<HeaderName>(&self.allowed_headers, "header")?.into()
+        };
+        let exposed = if is_wildcard(&self.exposed_headers) {
+            ExposeHeaders::any()
+        } else {
+            let mut exposed = parse_all::<HeaderName>(&self.exposed_headers, "header")?;
+            exposed.extend(ALWAYS_EXPOSED.map(HeaderName::from_static));
+            exposed.into()
+        };
+
+        let mut layer = CorsLayer::new()
+            .allow_methods(methods)
+            .allow_origin(origins)
+            .allow_headers(headers)
+            .expose_headers(exposed)
+            .allow_credentials(self.allow_credentials);
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(max_age);
+        }
+        Ok(layer)
+    }
+}
@@ -0,0 +1,77 @@
+use std::{any::Any, collections::VecDeque, future::Future, panic::AssertUnwindSafe};
+
+use futures::FutureExt;
+use log::error;
+use parking_lot::Mutex;
+use serde_json::Value;
+
+/// How many of the most recent messages are kept for crash reports
+const RECENT_MESSAGES: usize = 8;
+const REDACTED: &str = "<redacted>";
+
+/// The recent messages of a session, which are logged if its handler panics
+#[derive(Default)]
+pub struct CrashContext {
+    recent: Mutex<VecDeque<String>>,
+}
+
+impl CrashContext {
+    fn push(&self, msg: String) {
+        let mut recent = self.recent.lock();
+        if recent.len() >= RECENT_MESSAGES {
+            recent.pop_front();
+        }
+        recent.push_back(msg);
+    }
+
+    /// Records a text message with every string in it redacted, leaving only its shape
+    pub fn record_text(&self, msg: &str) {
+        self.push(match serde_json::from_str::<Value>(msg) {
+            Ok(mut value) => {
+                redact_strings(&mut value);
+                value.to_string()
+            }
+            Err(_) => format!("<{} bytes of text>", msg.len()),
+        });
+    }
+
+    pub fn record_binary(&self, msg: &[u8]) {
+        self.push(format!("<{} bytes>", msg.len()));
+    }
+
+    /// Logs a crash report for the given session
+    pub fn report(&self, session: &str, panic: &(dyn Any + Send)) {
+        let recent = self.recent.lock();
+        error!(
+            target: "crash",
+            "Handler for {session} panicked: {}. Last {} messages: [{}]",
+            panic_message(panic),
+            recent.len(),
+            recent.iter().map(String::as_str).collect::<Vec<_>>().join(", ")
+        );
+    }
+}
+
+fn redact_strings(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = REDACTED.into(),
+        Value::Array(values) => values.iter_mut().for_each(redact_strings),
+        Value::Object(map) => map.values_mut().for_each(redact_strings),
+        _ => {}
+    }
+}
+
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Runs the given future, catching any panic so that it only affects a single session
+pub async fn supervise<F: Future>(fut: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    AssertUnwindSafe(fut).catch_unwind().await
+}
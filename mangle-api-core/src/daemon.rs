@@ -0,0 +1,183 @@
+use std::{ffi::OsStr, time::Duration};
+
+use anyhow::{Error, Result};
+
+/// The flag of the `start` command that runs the server in the background
+pub(crate) const DAEMON_FLAG: &str = "daemon";
+/// Tells the daemon where to write its PID
+const PID_FILE_ENV: &str = "MANGLE_DAEMON_PID_FILE";
+/// How long `spawn_daemon` waits for the daemon to open its control pipe
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Starts this program again in the background, with the same arguments but
+/// `--daemon`, which writes its PID to `pid_file`
+///
+/// The new process is detached from the terminal, and its stderr is appended
+/// to `stderr_log`, so that output from before logging is set up is kept.
+/// Only returns once the new process is reachable on `pipe_name`, and fails
+/// if it exits before then
+#[cfg(unix)]
+pub async fn spawn_daemon(pid_file: &str, stderr_log: &str, pipe_name: &OsStr) -> Result<u32> {
+    use anyhow::Context;
+    use messagist::pipes::start_connection;
+    use std::{
+        env,
+        fs::OpenOptions,
+        io,
+        os::unix::process::CommandExt,
+        process::{Command, Stdio},
+    };
+    use tokio::time::{sleep, Instant};
+
+    let stderr = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(stderr_log)
+        .context(format!("Opening {stderr_log}"))?;
+    let daemon_arg = format!("--{DAEMON_FLAG}");
+    let mut command = Command::new(env::current_exe().context("Finding the current executable")?);
+    command
+        .args(
+            env::args_os()
+                .skip(1)
+                .filter(|arg| arg != daemon_arg.as_str()),
+        )
+        .env(PID_FILE_ENV, pid_file)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(stderr);
+    // SAFETY: setsid is async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+    let mut child = command.spawn().context("Spawning the daemon")?;
+    let pid = child.id();
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait().context("Checking on the daemon")? {
+            return Err(Error::msg(format!(
+                "The daemon exited during startup with {status}, see {stderr_log}"
+            )));
+        }
+        // The control pipe is only opened once the server has finished setting up
+        if read_pid(pid_file) == Some(pid) && start_connection(pipe_name).await.is_ok() {
+            return Ok(pid);
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::msg(format!(
+                "The daemon with PID {pid} has not finished starting after {}s, see {stderr_log}",
+                STARTUP_TIMEOUT.as_secs()
+            )));
+        }
+        sleep(STARTUP_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn spawn_daemon(_pid_file: &str, _stderr_log: &str, _pipe_name: &OsStr) -> Result<u32> {
+    Err(Error::msg(
+        "Running in the background is only supported on Unix",
+    ))
+}
+
+/// The PID file of a daemon, which stays locked for as long as the daemon runs
+/// and is deleted once it is dropped
+pub struct PidFile {
+    path: String,
+    _file: std::fs::File,
+}
+
+impl PidFile {
+    /// Writes and locks the PID file if this process was started by `spawn_daemon`
+    #[cfg(unix)]
+    pub(crate) fn create_if_daemon() -> Result<Option<Self>> {
+        use anyhow::Context;
+        use std::{env, fs::OpenOptions, io::Write, os::fd::AsRawFd, process};
+
+        let Ok(path) = env::var(PID_FILE_ENV) else {
+            return Ok(None);
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .context(format!("Opening {path}"))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            return Err(Error::new(std::io::Error::last_os_error())
+                .context(format!("Locking {path}, which another server may hold")));
+        }
+        // Only truncated once locked, so that the PID of a running server is never lost
+        file.set_len(0).context(format!("Writing {path}"))?;
+        write!(file, "{}", process::id()).context(format!("Writing {path}"))?;
+        Ok(Some(Self { path, _file: file }))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn create_if_daemon() -> Result<Option<Self>> {
+        Ok(None)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Deleted while still locked, so that no other server is using it yet
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Opens `pid_file` and reads its PID, if the daemon that wrote it still runs
+///
+/// The daemon locks the file until it stops, so stale PID files, such as those
+/// left behind by crashes, are ignored even if their PID was reused
+#[cfg(unix)]
+fn open_locked_pid(pid_file: &str) -> Option<(std::fs::File, u32)> {
+    use std::{io::Read, os::fd::AsRawFd};
+
+    let mut file = std::fs::File::open(pid_file).ok()?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0
+        || std::io::Error::last_os_error().raw_os_error() != Some(libc::EWOULDBLOCK)
+    {
+        return None;
+    }
+    let mut pid = String::new();
+    file.read_to_string(&mut pid).ok()?;
+    let pid = pid.trim().parse().ok()?;
+    Some((file, pid))
+}
+
+/// The PID in `pid_file`, if that process is still running
+#[cfg(unix)]
+pub fn read_pid(pid_file: &str) -> Option<u32> {
+    open_locked_pid(pid_file).map(|(_, pid)| pid)
+}
+
+#[cfg(not(unix))]
+pub fn read_pid(_pid_file: &str) -> Option<u32> {
+    None
+}
+
+/// Stops the process in `pid_file` as if Ctrl-C was pressed, returning its PID,
+/// for when the control pipe cannot be reached
+#[cfg(unix)]
+pub fn stop_pid(pid_file: &str) -> Result<u32> {
+    // Kept open while signalling, so that the lock was checked just before
+    let (_file, pid) = open_locked_pid(pid_file)
+        .ok_or_else(|| Error::msg(format!("{pid_file} does not name a running server")))?;
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGINT) } == -1 {
+        return Err(Error::new(std::io::Error::last_os_error()).context(format!("Stopping {pid}")));
+    }
+    Ok(pid)
+}
+
+#[cfg(not(unix))]
+pub fn stop_pid(_pid_file: &str) -> Result<u32> {
+    Err(Error::msg("Stopping by PID is only supported on Unix"))
+}
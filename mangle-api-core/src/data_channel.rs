@@ -0,0 +1,280 @@
+use std::{sync::Arc, time::Duration};
+
+use ::webrtc::{
+    api::{APIBuilder, API},
+    data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel},
+    ice_transport::ice_server::RTCIceServer,
+    peer_connection::{
+        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+};
+use anyhow::{Context, Error};
+use axum::extract::ws::Message;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    spawn,
+    sync::{
+        mpsc::{self, unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, OwnedSemaphorePermit, Semaphore,
+    },
+    time::timeout,
+};
+
+use crate::webrtc::{SDPAnswer, SDPOffer};
+
+/// How long a client may take to open its data channel after receiving the answer
+const OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DataChannelConfig {
+    /// The STUN or TURN servers used to find candidates, such as `stun:stun.l.google.com:19302`
+    ///
+    /// Without any, only the local addresses of this node are offered
+    #[serde(default = "Default::default")]
+    pub ice_servers: Vec<String>,
+    /// The most peer connections that can be open on this node at once
+    #[serde(default = "max_peer_connections")]
+    pub max_peer_connections: usize,
+    /// The most peer connections that a single connection can have open at once
+    #[serde(default = "max_session_peer_connections")]
+    pub max_session_peer_connections: usize,
+}
+
+fn max_peer_connections() -> usize {
+    1000
+}
+
+fn max_session_peer_connections() -> usize {
+    // One more than needed, so that a data channel can be replaced while it is open
+    2
+}
+
+impl Default for DataChannelConfig {
+    fn default() -> Self {
+        Self {
+            ice_servers: vec![],
+            max_peer_connections: max_peer_connections(),
+            max_session_peer_connections: max_session_peer_connections(),
+        }
+    }
+}
+
+pub enum NegotiateError {
+    /// This node, or the connection, has too many open peer connections
+    TooManyPeerConnections,
+    InternalError(Error),
+}
+
+impl From<Error> for NegotiateError {
+    fn from(e: Error) -> Self {
+        NegotiateError::InternalError(e)
+    }
+}
+
+/// Negotiates the data channels that WebSocket connections can move onto
+pub struct DataChannels {
+    api: API,
+    ice_servers: Vec<RTCIceServer>,
+    peer_connections: Arc<Semaphore>,
+    max_session_peer_connections: usize,
+}
+
+impl DataChannels {
+    pub fn new(config: DataChannelConfig) -> Self {
+        Self {
+            api: APIBuilder::new().build(),
+            ice_servers: vec![RTCIceServer {
+                urls: config.ice_servers,
+                ..Default::default()
+            }],
+            peer_connections: Arc::new(Semaphore::new(config.max_peer_connections)),
+            max_session_peer_connections: config.max_session_peer_connections,
+        }
+    }
+
+    /// Creates the handoff of a single connection, along with the receiver its
+    /// WebSocket takes the data channel from
+    pub(crate) fn handoff(self: &Arc<Self>) -> (DataChannelHandoff, mpsc::Receiver<DataChannel>) {
+        let (sender, receiver) = mpsc::channel(1);
+        (
+            DataChannelHandoff {
+                data_channels: self.clone(),
+                sender,
+                peer_connections: Arc::new(Semaphore::new(self.max_session_peer_connections)),
+            },
+            receiver,
+        )
+    }
+}
+
+/// An open data channel, which a WebSocket sends its messages through instead
+pub(crate) struct DataChannel {
+    peer: Arc<RTCPeerConnection>,
+    channel: Arc<RTCDataChannel>,
+    /// None once the channel or the peer connection closes
+    events: UnboundedReceiver<Option<DataChannelMessage>>,
+}
+
+impl DataChannel {
+    /// Receives the next message, or None if the channel has closed
+    pub(crate) async fn recv(&mut self) -> Option<Message> {
+        let msg = self.events.recv().await.flatten()?;
+        if msg.is_string {
+            Some(Message::Text(
+                String::from_utf8_lossy(&msg.data).into_owned(),
+            ))
+        } else {
+            Some(Message::Binary(msg.data.to_vec()))
+        }
+    }
+
+    pub(crate) async fn send(&self, msg: &Message) -> Result<(), Error> {
+        match msg {
+            Message::Text(msg) => self.channel.send_text(msg.clone()).await?,
+            Message::Binary(msg) => self.channel.send(&Bytes::from(msg.clone())).await?,
+            _ => unreachable!(),
+        };
+        Ok(())
+    }
+}
+
+impl Drop for DataChannel {
+    fn drop(&mut self) {
+        let peer = self.peer.clone();
+        spawn(async move {
+            let _ = peer.close().await;
+        });
+    }
+}
+
+/// Lets a connection move its messages onto a WebRTC data channel
+///
+/// The connection keeps its WebSocket, which it falls back to whenever the data
+/// channel closes or fails to send
+#[derive(Clone)]
+pub struct DataChannelHandoff {
+    data_channels: Arc<DataChannels>,
+    sender: mpsc::Sender<DataChannel>,
+    /// The peer connections of this connection
+    peer_connections: Arc<Semaphore>,
+}
+
+impl DataChannelHandoff {
+    /// Answers an offer from the client
+    ///
+    /// The answer contains every candidate of this node, so no further ICE
+    /// candidates are exchanged. The connection moves onto the first data
+    /// channel that the client opens, replacing any earlier data channel
+    ///
+    /// Peer connections count against the limits until they close
+    pub async fn negotiate(&self, offer: SDPOffer) -> Result<SDPAnswer, NegotiateError> {
+        let permits = match (
+            self.peer_connections.clone().try_acquire_owned(),
+            self.data_channels
+                .peer_connections
+                .clone()
+                .try_acquire_owned(),
+        ) {
+            (Ok(session), Ok(node)) => (session, node),
+            _ => return Err(NegotiateError::TooManyPeerConnections),
+        };
+        let peer = Arc::new(
+            self.data_channels
+                .api
+                .new_peer_connection(RTCConfiguration {
+                    ice_servers: self.data_channels.ice_servers.clone(),
+                    ..Default::default()
+                })
+                .await
+                .context("Creating peer connection")?,
+        );
+        let (event_sender, events) = unbounded_channel();
+        let (opened_sender, opened_recv) = oneshot::channel();
+
+        let closed_sender = event_sender.clone();
+        // Released once the peer connection ends, or is dropped
+        let permits: Mutex<Option<(OwnedSemaphorePermit, OwnedSemaphorePermit)>> =
+            Mutex::new(Some(permits));
+        peer.on_peer_connection_state_change(Box::new(move |state| {
+            if matches!(
+                state,
+                RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+            ) {
+                permits.lock().take();
+                let _ = closed_sender.send(None);
+            }
+            Box::pin(async {})
+        }));
+
+        // Only the first data channel is used
+        let pending = Mutex::new(Some((event_sender, events, opened_sender)));
+        let weak_peer = Arc::downgrade(&peer);
+        let handoff_sender = self.sender.clone();
+        peer.on_data_channel(Box::new(move |channel| {
+            let Some((event_sender, events, opened_sender)) = pending.lock().take() else {
+                return Box::pin(async {});
+            };
+            listen(&channel, event_sender);
+
+            let weak_peer = weak_peer.clone();
+            let handoff_sender = handoff_sender.clone();
+            let open_channel = channel.clone();
+            channel.on_open(Box::new(move || {
+                Box::pin(async move {
+                    let Some(peer) = weak_peer.upgrade() else {
+                        return;
+                    };
+                    let _ = handoff_sender
+                        .send(DataChannel {
+                            peer,
+                            channel: open_channel,
+                            events,
+                        })
+                        .await;
+                    let _ = opened_sender.send(());
+                })
+            }));
+            Box::pin(async {})
+        }));
+
+        peer.set_remote_description(
+            RTCSessionDescription::offer(offer.0).context("Parsing offer")?,
+        )
+        .await
+        .context("Setting offer")?;
+        let answer = peer.create_answer(None).await.context("Creating answer")?;
+        let mut gathering_complete = peer.gathering_complete_promise().await;
+        peer.set_local_description(answer)
+            .await
+            .context("Setting answer")?;
+        let _ = gathering_complete.recv().await;
+        let answer = peer
+            .local_description()
+            .await
+            .context("Missing answer after gathering candidates")?;
+
+        // Keeps the peer connection alive until its data channel opens
+        spawn(async move {
+            if !matches!(timeout(OPEN_TIMEOUT, opened_recv).await, Ok(Ok(()))) {
+                let _ = peer.close().await;
+            }
+        });
+
+        Ok(answer.sdp.into())
+    }
+}
+
+fn listen(channel: &RTCDataChannel, event_sender: UnboundedSender<Option<DataChannelMessage>>) {
+    let message_sender = event_sender.clone();
+    channel.on_message(Box::new(move |msg| {
+        let _ = message_sender.send(Some(msg));
+        Box::pin(async {})
+    }));
+    channel.on_close(Box::new(move || {
+        let _ = event_sender.send(None);
+        Box::pin(async {})
+    }));
+}
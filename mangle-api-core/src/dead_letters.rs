@@ -0,0 +1,250 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{read, rename, File},
+    io::{self, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Error};
+use parking_lot::Mutex;
+use serde::{ser::SerializeTuple, Deserialize, Serialize, Serializer};
+use tokio::task::spawn_blocking;
+
+/// A message that has already been serialized with bincode
+///
+/// bincode does not prefix tuples with their length, so serializing the bytes
+/// as a tuple writes them out unchanged
+#[derive(Clone)]
+pub(crate) struct RawMessage(Vec<u8>);
+
+impl RawMessage {
+    pub(crate) fn new<T: Serialize>(msg: &T) -> Result<Self, bincode::Error> {
+        bincode::serialize(msg).map(Self)
+    }
+}
+
+impl Serialize for RawMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for byte in &self.0 {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeerDeadLetters {
+    pub queued: usize,
+    pub oldest_age: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeadLetterStats {
+    pub peers: HashMap<String, PeerDeadLetters>,
+    /// Messages dropped because the queue of their peer was full
+    pub dropped: u64,
+    pub expired: u64,
+    pub redelivered: u64,
+}
+
+/// How the dead letters are saved, as `Instant`s cannot be
+#[derive(Serialize, Deserialize)]
+struct SavedDeadLetters {
+    saved_at: SystemTime,
+    /// The age of each message when they were saved
+    peers: HashMap<String, Vec<(Duration, Vec<u8>)>>,
+}
+
+/// Messages that could not be delivered to a sibling, kept for redelivery
+pub(crate) struct DeadLetterQueue {
+    peers: Mutex<HashMap<String, VecDeque<(Instant, RawMessage)>>>,
+    capacity: usize,
+    ttl: Duration,
+    /// Where the messages are saved so that they survive restarts, if anywhere
+    path: Option<PathBuf>,
+    /// Held while saving, so that an older save cannot replace a newer one
+    save_lock: tokio::sync::Mutex<()>,
+    dropped: AtomicU64,
+    expired: AtomicU64,
+    redelivered: AtomicU64,
+}
+
+impl DeadLetterQueue {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            peers: Default::default(),
+            capacity,
+            ttl,
+            path: None,
+            save_lock: Default::default(),
+            dropped: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            redelivered: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn set_limits(&mut self, capacity: usize, ttl: Duration) {
+        self.capacity = capacity;
+        self.ttl = ttl;
+    }
+
+    /// Saves the messages to the file at the path from now on, after adding
+    /// the messages that were saved there before
+    pub(crate) fn set_path(&mut self, path: PathBuf) -> Result<(), Error> {
+        match read(&path) {
+            Ok(bytes) => {
+                let saved: SavedDeadLetters =
+                    bincode::deserialize(&bytes).context(format!("Parsing {path:?}"))?;
+                let since_saved = saved.saved_at.elapsed().unwrap_or_default();
+                let now = Instant::now();
+                let mut peers = self.peers.lock();
+                for (domain, messages) in saved.peers {
+                    let queue = peers.entry(domain).or_default();
+                    for (age, msg) in messages {
+                        let age = age.saturating_add(since_saved);
+                        // Messages too old for an Instant have long expired
+                        let Some(queued_at) = now.checked_sub(age) else {
+                            self.expired.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        };
+                        queue.push_back((queued_at, RawMessage(msg)));
+                    }
+                    while queue.len() > self.capacity {
+                        queue.pop_front();
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(format!("Reading {path:?}")),
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    /// Writes every queued message to the path, if there is one
+    pub(crate) async fn save(&self) -> Result<(), Error> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let _guard = self.save_lock.lock().await;
+        let saved = SavedDeadLetters {
+            saved_at: SystemTime::now(),
+            peers: self
+                .peers
+                .lock()
+                .iter()
+                .filter(|(_, queue)| !queue.is_empty())
+                .map(|(domain, queue)| {
+                    (
+                        domain.clone(),
+                        queue
+                            .iter()
+                            .map(|(queued_at, msg)| (queued_at.elapsed(), msg.0.clone()))
+                            .collect(),
+                    )
+                })
+                .collect(),
+        };
+        let bytes = bincode::serialize(&saved)?;
+
+        // Renaming is atomic, so a crash cannot leave a partially written file
+        spawn_blocking(move || {
+            let tmp_path = path.with_extension("tmp");
+            let mut file = File::create(&tmp_path).context(format!("Creating {tmp_path:?}"))?;
+            file.write_all(&bytes)
+                .and_then(|_| file.sync_all())
+                .context(format!("Writing {tmp_path:?}"))?;
+            rename(&tmp_path, &path).context(format!("Replacing {path:?}"))
+        })
+        .await?
+    }
+
+    /// Adds a message to the back of the queue of the given peer, dropping the
+    /// oldest message if the queue is full
+    pub(crate) fn push(&self, domain: &str, msg: RawMessage) {
+        if self.capacity == 0 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut peers = self.peers.lock();
+        let queue = peers.entry(domain.to_string()).or_default();
+        if queue.len() >= self.capacity {
+            queue.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.push_back((Instant::now(), msg));
+    }
+
+    /// Takes every message queued for the given peer, discarding expired ones
+    pub(crate) fn take(&self, domain: &str) -> VecDeque<(Instant, RawMessage)> {
+        let mut queue = self.peers.lock().remove(domain).unwrap_or_default();
+        let before = queue.len();
+        queue.retain(|(queued_at, _)| queued_at.elapsed() < self.ttl);
+        self.expired
+            .fetch_add((before - queue.len()) as u64, Ordering::Relaxed);
+        queue
+    }
+
+    /// Puts back the messages that could not be redelivered, ahead of any
+    /// messages that were queued in the meantime
+    pub(crate) fn restore(&self, domain: &str, mut queue: VecDeque<(Instant, RawMessage)>) {
+        let mut peers = self.peers.lock();
+        let current = peers.entry(domain.to_string()).or_default();
+        queue.append(current);
+        while queue.len() > self.capacity {
+            queue.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        *current = queue;
+    }
+
+    pub(crate) fn mark_redelivered(&self) {
+        self.redelivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn domains(&self) -> Vec<String> {
+        self.peers
+            .lock()
+            .iter()
+            .filter(|(_, queue)| !queue.is_empty())
+            .map(|(domain, _)| domain.clone())
+            .collect()
+    }
+
+    /// Discards the messages of the given peer, or of every peer
+    ///
+    /// Returns how many messages were discarded
+    pub(crate) fn clear(&self, domain: Option<&str>) -> usize {
+        let mut peers = self.peers.lock();
+        match domain {
+            Some(domain) => peers.remove(domain).map(|x| x.len()).unwrap_or_default(),
+            None => peers.drain().map(|(_, queue)| queue.len()).sum(),
+        }
+    }
+
+    pub(crate) fn get_stats(&self) -> DeadLetterStats {
+        DeadLetterStats {
+            peers: self
+                .peers
+                .lock()
+                .iter()
+                .map(|(domain, queue)| {
+                    (
+                        domain.clone(),
+                        PeerDeadLetters {
+                            queued: queue.len(),
+                            oldest_age: queue.front().map(|(queued_at, _)| queued_at.elapsed()),
+                        },
+                    )
+                })
+                .collect(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            redelivered: self.redelivered.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use dashmap::DashMap;
+use futures::future::join_all;
+use log::warn;
+use messagist::ExclusiveMessageHandler;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use super::{Node, ServerName};
+
+/// Asks a sibling for the time on its clock, which it replies to with a `ClockReading`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ClockProbe;
+
+impl ClockProbe {
+    pub fn answer(self) -> ClockReading {
+        ClockReading {
+            unix_millis: unix_millis(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ClockReading {
+    pub unix_millis: i64,
+}
+
+/// How far the clock of a sibling is from the clock of this node
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct SkewEstimate {
+    /// Positive if the clock of the sibling is ahead
+    pub offset_millis: i64,
+    /// The estimate is off by at most half of this
+    pub round_trip: Duration,
+}
+
+/// Estimates the clock skew of every sibling by probing them periodically
+pub struct ClockSkew<H, M>
+where
+    H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+{
+    node: &'static Node<H>,
+    to_message: fn(ClockProbe) -> M,
+    warn_threshold: Duration,
+    estimates: DashMap<String, SkewEstimate>,
+}
+
+impl<H, M> ClockSkew<H, M>
+where
+    H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+    M: Serialize + Send + Sync,
+{
+    /// `to_message` wraps probes in the messages the handler of siblings expects
+    pub fn new(node: &'static Node<H>, to_message: fn(ClockProbe) -> M) -> Self {
+        Self {
+            node,
+            to_message,
+            warn_threshold: Duration::from_secs(1),
+            estimates: DashMap::new(),
+        }
+    }
+
+    /// Logs a warning whenever a sibling is skewed by more than this
+    pub fn set_warn_threshold(mut self, warn_threshold: Duration) -> Self {
+        self.warn_threshold = warn_threshold;
+        self
+    }
+
+    /// The latest estimate of every sibling that has answered a probe, sorted by domain
+    pub fn get_estimates(&self) -> Vec<(String, SkewEstimate)> {
+        let mut estimates: Vec<_> = self
+            .estimates
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        estimates.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        estimates
+    }
+
+    /// Probes every sibling at once, keeping the estimates of those that answer
+    pub async fn probe(&self) {
+        let domains: Vec<_> = self
+            .node
+            .get_sibling_domains()
+            .map(ToString::to_string)
+            .collect();
+        join_all(domains.into_iter().map(|domain| async move {
+            if let Err(e) = self.probe_sibling(&domain).await {
+                warn!(target: "clock", "{:?}", e.context(format!("probing clock of {domain}")));
+            }
+        }))
+        .await;
+    }
+
+    /// Probes a single sibling, keeping and returning its estimate
+    pub async fn probe_sibling(&self, domain: &str) -> Result<SkewEstimate> {
+        let sent_at = Instant::now();
+        let sent_millis = unix_millis();
+        let reading = self
+            .node
+            .request::<_, ClockReading>(domain, (self.to_message)(ClockProbe))
+            .await?;
+        let round_trip = sent_at.elapsed();
+        // Assumes the sibling read its clock halfway through the round trip
+        let offset_millis = reading.unix_millis - sent_millis - round_trip.as_millis() as i64 / 2;
+
+        if offset_millis.unsigned_abs() > self.warn_threshold.as_millis() as u64 {
+            warn!(
+                target: "clock",
+                "Clock of {domain} is {offset_millis}ms off, give or take {}ms",
+                round_trip.as_millis() / 2
+            );
+        }
+        let estimate = SkewEstimate {
+            offset_millis,
+            round_trip,
+        };
+        self.estimates.insert(domain.to_string(), estimate);
+        Ok(estimate)
+    }
+
+    /// Calls `probe` every `interval`, forever
+    pub async fn probe_forever(&self, interval: Duration) {
+        loop {
+            self.probe().await;
+            sleep(interval).await;
+        }
+    }
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
@@ -0,0 +1,101 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use messagist::ExclusiveMessageHandler;
+
+use super::{Node, ServerName};
+
+/// 2023-01-01T00:00:00Z, in milliseconds since the unix epoch
+const EPOCH_MILLIS: u64 = 1_672_531_200_000;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_NODE_ID: u16 = (1 << NODE_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// Generates IDs that are unique across every node without coordinating with them
+///
+/// IDs are made of the milliseconds since 2023, the ID of this node, and a
+/// sequence number, so IDs from the same node always increase. If more than
+/// 4096 IDs are generated within a millisecond, later IDs borrow from the next
+/// millisecond instead of waiting for it
+pub struct IdGenerator {
+    node_id: u64,
+    /// The timestamp and sequence number of the last ID
+    last: AtomicU64,
+}
+
+impl IdGenerator {
+    pub fn new(node_id: u16) -> Result<Self, Error> {
+        if node_id > MAX_NODE_ID {
+            return Err(Error::msg(format!(
+                "Node ID {node_id} is larger than {MAX_NODE_ID}"
+            )));
+        }
+        Ok(Self {
+            node_id: node_id as u64,
+            last: AtomicU64::new(0),
+        })
+    }
+
+    /// Uses the position of `own_name` among the names of every node, which is
+    /// the same on every node as long as they share the same siblings
+    ///
+    /// `own_name` must be the domain that siblings know this node by
+    pub fn from_membership<H>(own_name: &str, node: &Node<H>) -> Result<Self, Error>
+    where
+        H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+    {
+        if node.get_sibling_domains().any(|domain| domain == own_name) {
+            return Err(Error::msg(format!(
+                "{own_name} is also the name of a sibling, so their node IDs would collide"
+            )));
+        }
+        let lower = node
+            .get_sibling_domains()
+            .filter(|domain| *domain < own_name)
+            .count();
+        let node_id = u16::try_from(lower)
+            .ok()
+            .filter(|x| *x <= MAX_NODE_ID)
+            .ok_or_else(|| Error::msg("Too many siblings to give each node an ID"))?;
+        Self::new(node_id)
+    }
+
+    pub fn get_node_id(&self) -> u16 {
+        self.node_id as u16
+    }
+
+    pub fn next_id(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let now = now.saturating_sub(EPOCH_MILLIS) << SEQUENCE_BITS;
+
+        // If the clock goes backwards, IDs carry on from the last one
+        let last = self
+            .last
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        let current = now.max(last + 1);
+
+        let timestamp = current >> SEQUENCE_BITS;
+        let sequence = current & MAX_SEQUENCE;
+        (timestamp << (NODE_BITS + SEQUENCE_BITS)) | (self.node_id << SEQUENCE_BITS) | sequence
+    }
+
+    /// When the given ID was generated
+    pub fn get_timestamp(id: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis((id >> (NODE_BITS + SEQUENCE_BITS)) + EPOCH_MILLIS)
+    }
+
+    /// The ID of the node that generated the given ID
+    pub fn get_node_id_of(id: u64) -> u16 {
+        ((id >> SEQUENCE_BITS) & MAX_NODE_ID as u64) as u16
+    }
+}
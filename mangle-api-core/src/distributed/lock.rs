@@ -0,0 +1,529 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use axum::async_trait;
+use futures::future::join_all;
+use log::warn;
+use messagist::{wire::WireType, ExclusiveMessageHandler};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{Node, ServerName};
+use crate::rng::SharedRng;
+
+/// Where leases are kept
+#[async_trait]
+pub trait LockBackend: Send + Sync {
+    /// Takes the lease on the given lock if it is free or expired
+    ///
+    /// Returns the fencing token of the lease if it was taken
+    async fn acquire(&self, name: &str, owner: u64, ttl: Duration) -> Result<Option<u64>, Error>;
+    /// Extends the lease of the given owner, returning false if it no longer holds it
+    async fn renew(&self, name: &str, owner: u64, ttl: Duration) -> Result<bool, Error>;
+    async fn release(&self, name: &str, owner: u64) -> Result<(), Error>;
+}
+
+/// Short lived locks that are shared by every node
+pub struct DistributedLocks {
+    backend: Box<dyn LockBackend>,
+    rng: SharedRng,
+}
+
+impl DistributedLocks {
+    pub fn new(backend: impl LockBackend + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+            rng: SharedRng::thread(),
+        }
+    }
+
+    /// Tries to take the given lock for `ttl`, returning None if it is held elsewhere
+    pub async fn acquire(&self, name: &str, ttl: Duration) -> Result<Option<Lease>, Error> {
+        let owner = self.rng.with(|rng| rng.next_u64());
+        let acquired_at = Instant::now();
+        let Some(fencing_token) = self.backend.acquire(name, owner, ttl).await? else {
+            return Ok(None);
+        };
+        Ok(Some(Lease {
+            locks: self,
+            name: name.to_string(),
+            owner,
+            fencing_token,
+            expires_at: acquired_at + ttl,
+        }))
+    }
+}
+
+/// A held lock, which expires unless it is renewed
+///
+/// Dropping a lease does not release it, so it stays held until it expires
+pub struct Lease<'a> {
+    locks: &'a DistributedLocks,
+    name: String,
+    owner: u64,
+    fencing_token: u64,
+    expires_at: Instant,
+}
+
+impl<'a> Lease<'a> {
+    /// Increases every time the lock is acquired
+    ///
+    /// Anything guarded by the lock should reject writes with a lower token than
+    /// the last one it saw, as the holder of that token may have lost its lease
+    pub fn get_fencing_token(&self) -> u64 {
+        self.fencing_token
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+
+    /// Extends the lease to `ttl` from now, returning false if it was lost
+    pub async fn renew(&mut self, ttl: Duration) -> Result<bool, Error> {
+        let renewed_at = Instant::now();
+        if !self
+            .locks
+            .backend
+            .renew(&self.name, self.owner, ttl)
+            .await?
+        {
+            return Ok(false);
+        }
+        self.expires_at = renewed_at + ttl;
+        Ok(true)
+    }
+
+    pub async fn release(self) -> Result<(), Error> {
+        self.locks.backend.release(&self.name, self.owner).await
+    }
+}
+
+/// A message sent between nodes to vote on a lock
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub enum LockRequest {
+    Acquire {
+        name: String,
+        owner: u64,
+        ttl: Duration,
+    },
+    /// Raises the fencing token of a lock so that later leases get higher tokens
+    Commit {
+        name: String,
+        fencing_token: u64,
+    },
+    Renew {
+        name: String,
+        owner: u64,
+        ttl: Duration,
+    },
+    Release {
+        name: String,
+        owner: u64,
+    },
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct LockResponse {
+    pub granted: bool,
+    pub fencing_token: u64,
+}
+
+impl WireType for LockRequest {
+    fn wire_samples() -> Vec<Self> {
+        let name = "lock".to_string();
+        vec![
+            LockRequest::Acquire {
+                name: name.clone(),
+                owner: u64::MAX,
+                ttl: Duration::from_millis(1500),
+            },
+            LockRequest::Commit {
+                name: name.clone(),
+                fencing_token: 1,
+            },
+            LockRequest::Renew {
+                name: name.clone(),
+                owner: u64::MAX,
+                ttl: Duration::from_millis(1500),
+            },
+            LockRequest::Release {
+                name,
+                owner: u64::MAX,
+            },
+        ]
+    }
+}
+
+impl WireType for LockResponse {
+    fn wire_samples() -> Vec<Self> {
+        vec![LockResponse {
+            granted: true,
+            fencing_token: 1,
+        }]
+    }
+}
+
+#[derive(Default)]
+struct LocalLock {
+    holder: Option<(u64, Instant)>,
+    fencing_token: u64,
+}
+
+/// The votes of this node on every lock
+///
+/// The handler of the node must answer every `LockRequest` with the response
+/// from `handle`
+#[derive(Default)]
+pub struct LockTable {
+    locks: Mutex<HashMap<String, LocalLock>>,
+}
+
+impl LockTable {
+    pub fn handle(&self, request: LockRequest) -> LockResponse {
+        let now = Instant::now();
+        let mut locks = self.locks.lock();
+
+        match request {
+            LockRequest::Acquire { name, owner, ttl } => {
+                let lock = locks.entry(name).or_default();
+                let held_elsewhere = matches!(
+                    lock.holder,
+                    Some((holder, expires_at)) if holder != owner && expires_at > now
+                );
+                if held_elsewhere {
+                    return LockResponse {
+                        granted: false,
+                        fencing_token: lock.fencing_token,
+                    };
+                }
+                lock.fencing_token += 1;
+                lock.holder = Some((owner, now + ttl));
+                LockResponse {
+                    granted: true,
+                    fencing_token: lock.fencing_token,
+                }
+            }
+            LockRequest::Commit {
+                name,
+                fencing_token,
+            } => {
+                let lock = locks.entry(name).or_default();
+                lock.fencing_token = lock.fencing_token.max(fencing_token);
+                LockResponse {
+                    granted: true,
+                    fencing_token: lock.fencing_token,
+                }
+            }
+            LockRequest::Renew { name, owner, ttl } => {
+                let lock = locks.entry(name).or_default();
+                let granted = matches!(
+                    lock.holder,
+                    Some((holder, expires_at)) if holder == owner && expires_at > now
+                );
+                if granted {
+                    lock.holder = Some((owner, now + ttl));
+                }
+                LockResponse {
+                    granted,
+                    fencing_token: lock.fencing_token,
+                }
+            }
+            LockRequest::Release { name, owner } => {
+                let lock = locks.entry(name).or_default();
+                let granted = matches!(lock.holder, Some((holder, _)) if holder == owner);
+                if granted {
+                    lock.holder = None;
+                }
+                LockResponse {
+                    granted,
+                    fencing_token: lock.fencing_token,
+                }
+            }
+        }
+    }
+}
+
+/// Keeps leases by having a majority of nodes, including this one, vote on them
+///
+/// A lease is only held once a majority has granted it and committed its
+/// fencing token. As any two majorities share a node, the fencing token of a
+/// lease is always higher than that of every earlier lease
+pub struct NodeLockBackend<H, M>
+where
+    H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+{
+    node: &'static Node<H>,
+    table: &'static LockTable,
+    to_message: fn(LockRequest) -> M,
+}
+
+impl<H, M> NodeLockBackend<H, M>
+where
+    H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+    M: Serialize + Send + Sync,
+{
+    /// `table` must be the table the handler of the node answers requests with,
+    /// and `to_message` wraps requests in the messages the handler expects
+    pub fn new(
+        node: &'static Node<H>,
+        table: &'static LockTable,
+        to_message: fn(LockRequest) -> M,
+    ) -> Self {
+        Self {
+            node,
+            table,
+            to_message,
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        (self.node.get_sibling_domains().count() + 1) / 2 + 1
+    }
+
+    /// Sends the request to this node and the given siblings, returning the
+    /// siblings that granted it along with their responses
+    async fn vote(
+        &self,
+        domains: impl IntoIterator<Item = String>,
+        request: LockRequest,
+    ) -> (LockResponse, Vec<(String, LockResponse)>) {
+        let local = self.table.handle(request.clone());
+        let responses = join_all(domains.into_iter().map(|domain| {
+            let message = (self.to_message)(request.clone());
+            async move {
+                let response = self.node.request::<_, LockResponse>(&domain, message).await;
+                (domain, response)
+            }
+        }))
+        .await;
+
+        let granted = responses
+            .into_iter()
+            .filter_map(|(domain, response)| match response {
+                Ok(response @ LockResponse { granted: true, .. }) => Some((domain, response)),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!(target: "locks", "{:?}", e.context(format!("requesting vote from {domain}")));
+                    None
+                }
+            })
+            .collect();
+
+        (local, granted)
+    }
+
+    fn sibling_domains(&self) -> Vec<String> {
+        self.node
+            .get_sibling_domains()
+            .map(ToString::to_string)
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<H, M> LockBackend for NodeLockBackend<H, M>
+where
+    H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+    M: Serialize + Send + Sync + 'static,
+{
+    async fn acquire(&self, name: &str, owner: u64, ttl: Duration) -> Result<Option<u64>, Error> {
+        let started_at = Instant::now();
+        let quorum = self.quorum();
+        let (local, granted) = self
+            .vote(
+                self.sibling_domains(),
+                LockRequest::Acquire {
+                    name: name.to_string(),
+                    owner,
+                    ttl,
+                },
+            )
+            .await;
+
+        let voters: Vec<_> = granted.iter().map(|(domain, _)| domain.clone()).collect();
+        let grants = voters.len() + local.granted as usize;
+        if grants < quorum {
+            self.release(name, owner).await?;
+            return Ok(None);
+        }
+
+        let fencing_token = granted
+            .iter()
+            .map(|(_, response)| response.fencing_token)
+            .chain(local.granted.then_some(local.fencing_token))
+            .max()
+            .unwrap_or_default();
+
+        let (local, committed) = self
+            .vote(
+                voters,
+                LockRequest::Commit {
+                    name: name.to_string(),
+                    fencing_token,
+                },
+            )
+            .await;
+
+        // The lease may have expired on some voters while waiting on the others
+        if committed.len() + local.granted as usize >= quorum && started_at.elapsed() < ttl {
+            Ok(Some(fencing_token))
+        } else {
+            self.release(name, owner).await?;
+            Ok(None)
+        }
+    }
+
+    async fn renew(&self, name: &str, owner: u64, ttl: Duration) -> Result<bool, Error> {
+        let started_at = Instant::now();
+        let (local, granted) = self
+            .vote(
+                self.sibling_domains(),
+                LockRequest::Renew {
+                    name: name.to_string(),
+                    owner,
+                    ttl,
+                },
+            )
+            .await;
+        Ok(granted.len() + local.granted as usize >= self.quorum() && started_at.elapsed() < ttl)
+    }
+
+    async fn release(&self, name: &str, owner: u64) -> Result<(), Error> {
+        self.vote(
+            self.sibling_domains(),
+            LockRequest::Release {
+                name: name.to_string(),
+                owner,
+            },
+        )
+        .await;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_backend::RedisLockBackend;
+
+#[cfg(feature = "redis")]
+mod redis_backend {
+    use std::time::Duration;
+
+    use anyhow::Error;
+    use axum::async_trait;
+    use redis::Script;
+    use tokio::task::spawn_blocking;
+
+    use super::LockBackend;
+    use crate::db::redis::RedisClient;
+
+    const RENEW_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+    const RELEASE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+    /// Keeps leases as keys that expire on their own, with a counter beside each
+    /// key for its fencing tokens
+    pub struct RedisLockBackend {
+        client: RedisClient,
+    }
+
+    impl RedisLockBackend {
+        pub fn new(client: RedisClient) -> Self {
+            Self { client }
+        }
+
+        /// Runs the given commands on a blocking thread, dropping the connection if they fail
+        async fn run<T: Send + 'static>(
+            &self,
+            f: impl FnOnce(&mut redis::cluster::ClusterConnection) -> redis::RedisResult<T>
+                + Send
+                + 'static,
+        ) -> Result<T, Error> {
+            let client = self.client.clone();
+            spawn_blocking(move || {
+                let mut connection = client.get_connection()?;
+                match f(&mut *connection) {
+                    Ok(x) => Ok(x),
+                    Err(e) => {
+                        connection.invalidate();
+                        Err(e.into())
+                    }
+                }
+            })
+            .await?
+        }
+    }
+
+    /// The hash tag keeps the lease and its counter on the same cluster node
+    fn keys(name: &str) -> (String, String) {
+        (
+            format!("{{lock:{name}}}"),
+            format!("{{lock:{name}}}:fencing"),
+        )
+    }
+
+    #[async_trait]
+    impl LockBackend for RedisLockBackend {
+        async fn acquire(
+            &self,
+            name: &str,
+            owner: u64,
+            ttl: Duration,
+        ) -> Result<Option<u64>, Error> {
+            let (key, fencing_key) = keys(name);
+            self.run(move |connection| {
+                let taken: Option<String> = redis::cmd("SET")
+                    .arg(&key)
+                    .arg(owner)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(ttl.as_millis() as u64)
+                    .query(connection)?;
+                if taken.is_none() {
+                    return Ok(None);
+                }
+                redis::cmd("INCR")
+                    .arg(&fencing_key)
+                    .query(connection)
+                    .map(Some)
+            })
+            .await
+        }
+
+        async fn renew(&self, name: &str, owner: u64, ttl: Duration) -> Result<bool, Error> {
+            let (key, _) = keys(name);
+            self.run(move |connection| {
+                Script::new(RENEW_SCRIPT)
+                    .key(key)
+                    .arg(owner)
+                    .arg(ttl.as_millis() as u64)
+                    .invoke::<i64>(connection)
+                    .map(|renewed| renewed == 1)
+            })
+            .await
+        }
+
+        async fn release(&self, name: &str, owner: u64) -> Result<(), Error> {
+            let (key, _) = keys(name);
+            self.run(move |connection| {
+                Script::new(RELEASE_SCRIPT)
+                    .key(key)
+                    .arg(owner)
+                    .invoke::<i64>(connection)
+                    .map(drop)
+            })
+            .await
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Error;
+use bimap::BiMap;
+use log::{error, warn};
+use messagist::{bin::BinaryMessageStream, ExclusiveMessageHandler, MessageStream};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    spawn,
+    task::JoinHandle,
+};
+use tokio_native_tls::{
+    native_tls::{Identity, TlsConnector},
+    TlsConnector as TlsConnectorWrapper,
+};
+
+use crate::{
+    crash::{panic_message, supervise},
+    dead_letters::{DeadLetterQueue, DeadLetterStats, RawMessage},
+    tcp::TcpConfig,
+    tls::SharedTlsAcceptor,
+};
+
+pub mod clock;
+pub mod ids;
+pub mod lock;
+pub mod signing;
+
+const DEFAULT_DEAD_LETTER_CAPACITY: usize = 256;
+const DEFAULT_DEAD_LETTER_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub struct ServerName(pub Arc<str>);
+
+pub struct Node<H>
+where
+    H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+{
+    sibling_domains: Arc<BiMap<Arc<str>, SocketAddr>>,
+    tls_builder: Option<TlsConnectorWrapper>,
+    tls_acceptor: Option<SharedTlsAcceptor>,
+    network_port: u16,
+    tcp_config: TcpConfig,
+    task_handle: JoinHandle<()>,
+    handler: H,
+    dead_letters: DeadLetterQueue,
+}
+
+impl<H> Drop for Node<H>
+where
+    H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.task_handle.abort();
+    }
+}
+
+impl<H> Node<H>
+where
+    H: ExclusiveMessageHandler<SessionState = ServerName> + Clone + Send + Sync + 'static,
+{
+    pub async fn new(
+        sibling_domains: impl IntoIterator<Item = (String, SocketAddr)>,
+        network_port: u16,
+        identity: Option<Identity>,
+        tcp_config: TcpConfig,
+        handler: H,
+    ) -> anyhow::Result<Self> {
+        let sibling_domains = Arc::new(
+            sibling_domains
+                .into_iter()
+                .map(|(domain, addr)| (Arc::from(domain.into_boxed_str()), addr))
+                .collect::<BiMap<_, _>>(),
+        );
+
+        let sibling_domains2 = sibling_domains.clone();
+
+        let tls_acceptor;
+        let tls_builder;
+
+        if let Some(identity) = identity {
+            tls_builder = Some(TlsConnectorWrapper::from(TlsConnector::builder().build()?));
+            tls_acceptor = Some(SharedTlsAcceptor::new(identity)?)
+        } else {
+            tls_builder = None;
+            tls_acceptor = None;
+        };
+        let acceptor = TcpListener::bind(("0.0.0.0", network_port)).await?;
+        let handler2 = handler.clone();
+        let tls_acceptor2 = tls_acceptor.clone();
+
+        let task_handle = spawn(async move {
+            loop {
+                let Ok((stream, addr)) = acceptor.accept().await else { continue };
+                if let Err(e) = tcp_config.apply_to_stream(&stream) {
+                    warn!("Failed to configure connection from {addr}: {e}");
+                }
+
+                let Some(connection_domain) = sibling_domains2.get_by_right(&addr).cloned() else {
+                    warn!(target: "security", "Got attempted connection from {addr}");
+                    return
+                };
+
+                let server_name = ServerName(connection_domain);
+
+                let mut handler2 = handler2.clone();
+                // Taken for each connection, so that renewed identities are used
+                let tls_acceptor = tls_acceptor2.as_ref().map(SharedTlsAcceptor::get);
+
+                spawn(async move {
+                    let domain = server_name.0.clone();
+                    let result = supervise(async {
+                        match &tls_acceptor {
+                            Some(tls_acceptor) => {
+                                let Ok(stream) = tls_acceptor.accept(stream).await else { return };
+                                handler2
+                                    .handle(BinaryMessageStream::from(stream), server_name)
+                                    .await
+                            }
+                            None => {
+                                handler2
+                                    .handle(BinaryMessageStream::from(stream), server_name)
+                                    .await
+                            }
+                        }
+                    })
+                    .await;
+
+                    if let Err(panic) = result {
+                        error!(
+                            target: "crash",
+                            "Handler for sibling {domain} panicked: {}",
+                            panic_message(&*panic)
+                        );
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            tls_builder,
+            tls_acceptor,
+            sibling_domains,
+            network_port,
+            tcp_config,
+            task_handle,
+            handler,
+            dead_letters: DeadLetterQueue::new(
+                DEFAULT_DEAD_LETTER_CAPACITY,
+                DEFAULT_DEAD_LETTER_TTL,
+            ),
+        })
+    }
+
+    /// The acceptor of connections from siblings, if they use TLS, which a
+    /// `CertificateRenewal` should also swap renewed identities into
+    pub fn get_tls_acceptor(&self) -> Option<&SharedTlsAcceptor> {
+        self.tls_acceptor.as_ref()
+    }
+
+    /// Sets how many undeliverable messages are kept for each sibling, and for how long
+    pub fn set_dead_letter_limits(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.dead_letters.set_limits(capacity, ttl);
+        self
+    }
+
+    /// Saves undeliverable messages to the file at the path, so that they are
+    /// still redelivered after a restart
+    ///
+    /// The messages already saved there are queued again
+    pub fn set_dead_letter_path(mut self, path: impl Into<PathBuf>) -> Result<Self, Error> {
+        self.dead_letters.set_path(path.into())?;
+        Ok(self)
+    }
+
+    async fn save_dead_letters(&self) {
+        if let Err(e) = self.dead_letters.save().await {
+            error!("{:?}", e.context("Saving dead letters"));
+        }
+    }
+
+    pub async fn send_message<T>(&self, domain: &str, message: T) -> Result<(), Error>
+    where
+        T: Serialize + Send + Sync,
+    {
+        if !self.sibling_domains.contains_left(domain) {
+            return Err(Error::msg(format!("{domain} is not a sibling")));
+        }
+
+        let connection = TcpStream::connect((domain, self.network_port)).await?;
+        self.tcp_config.apply_to_stream(&connection)?;
+
+        match &self.tls_builder {
+            Some(tls_builder) => {
+                BinaryMessageStream::from(tls_builder.connect(domain, connection).await?)
+                    .send_message(message)
+                    .await
+                    .map_err(Into::into)
+            }
+            None => BinaryMessageStream::from(connection)
+                .send_message(message)
+                .await
+                .map_err(Into::into),
+        }
+    }
+
+    /// Sends a message to the given sibling and waits for its reply on the same connection
+    ///
+    /// Unlike `send_message`, the handler of the sibling must reply to the message
+    pub async fn request<T, R>(&self, domain: &str, message: T) -> Result<R, Error>
+    where
+        T: Serialize + Send + Sync,
+        R: DeserializeOwned + Send + 'static,
+    {
+        if !self.sibling_domains.contains_left(domain) {
+            return Err(Error::msg(format!("{domain} is not a sibling")));
+        }
+
+        let connection = TcpStream::connect((domain, self.network_port)).await?;
+        self.tcp_config.apply_to_stream(&connection)?;
+
+        match &self.tls_builder {
+            Some(tls_builder) => {
+                let mut stream =
+                    BinaryMessageStream::from(tls_builder.connect(domain, connection).await?);
+                stream.send_message(message).await?;
+                stream.recv_message().await.map_err(Into::into)
+            }
+            None => {
+                let mut stream = BinaryMessageStream::from(connection);
+                stream.send_message(message).await?;
+                stream.recv_message().await.map_err(Into::into)
+            }
+        }
+    }
+
+    pub fn get_sibling_domains(&self) -> impl Iterator<Item = &str> {
+        self.sibling_domains.left_values().map(AsRef::as_ref)
+    }
+
+    pub async fn broadcast_message<T>(&self, message: T) -> Vec<(String, Error)>
+    where
+        T: Serialize + Send + Sync,
+    {
+        let mut results = vec![];
+        let domains = self
+            .sibling_domains
+            .left_values()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        for domain in domains {
+            let connection = match TcpStream::connect((domain.as_str(), self.network_port)).await {
+                Ok(x) => x,
+                Err(e) => {
+                    results.push((domain, e.into()));
+                    continue;
+                }
+            };
+            if let Err(e) = self.tcp_config.apply_to_stream(&connection) {
+                results.push((domain, e.into()));
+                continue;
+            }
+            match &self.tls_builder {
+                Some(tls_builder) => {
+                    match tls_builder.connect(&domain, connection).await {
+                        Ok(connection) => {
+                            let mut connection = BinaryMessageStream::from(connection);
+                            if let Err(e) = connection.send_message(&message).await {
+                                results.push((domain, e.into()));
+                            }
+                        }
+                        Err(e) => {
+                            results.push((domain, e.into()));
+                            continue;
+                        }
+                    };
+                }
+                None => {
+                    if let Err(e) = BinaryMessageStream::from(connection)
+                        .send_message(&message)
+                        .await
+                    {
+                        results.push((domain, e.into()));
+                    }
+                }
+            }
+        }
+
+        if !results.is_empty() {
+            match RawMessage::new(&message) {
+                Ok(raw) => {
+                    for (domain, _) in &results {
+                        self.dead_letters.push(domain, raw.clone());
+                    }
+                }
+                Err(e) => error!("Could not serialize dead letter: {e}"),
+            }
+            self.save_dead_letters().await;
+        }
+
+        results
+    }
+
+    /// Tries to send the messages that previously failed to reach each sibling, in order
+    ///
+    /// Returns how many messages were delivered
+    pub async fn redeliver_dead_letters(&self) -> usize {
+        let mut delivered = 0;
+
+        for domain in self.dead_letters.domains() {
+            let mut queue = self.dead_letters.take(&domain);
+
+            while let Some((queued_at, msg)) = queue.pop_front() {
+                if self.send_message(&domain, &msg).await.is_err() {
+                    queue.push_front((queued_at, msg));
+                    break;
+                }
+                self.dead_letters.mark_redelivered();
+                delivered += 1;
+            }
+
+            if !queue.is_empty() {
+                self.dead_letters.restore(&domain, queue);
+            }
+            self.save_dead_letters().await;
+        }
+
+        delivered
+    }
+
+    pub fn get_dead_letter_stats(&self) -> DeadLetterStats {
+        self.dead_letters.get_stats()
+    }
+
+    /// Discards the undelivered messages of the given sibling, or of every sibling
+    pub async fn clear_dead_letters(&self, domain: Option<&str>) -> usize {
+        let discarded = self.dead_letters.clear(domain);
+        self.save_dead_letters().await;
+        discarded
+    }
+
+    pub fn get_handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn get_mut_handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::read_to_string,
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Error, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use parking_lot::Mutex;
+use rand::RngCore;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+static SIGNER: OnceLock<MessageSigner> = OnceLock::new();
+/// Messages signed further in the past or future than this are dropped, which
+/// also bounds how long their nonces have to be remembered
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(120);
+
+/// How messages between siblings are signed and verified
+///
+/// Keys are rotated by giving every sibling the new public key of a node,
+/// switching the `key_id` of that node to the new key, then removing the old
+/// public key from every sibling
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SigningConfig {
+    /// Identifies the key of this node to its siblings
+    pub key_id: String,
+    /// A file holding the base64 encoded seed of the Ed25519 key of this node
+    pub private_key_path: String,
+    /// The base64 encoded public keys of each sibling, by the `node_name` of
+    /// the sibling then key ID
+    #[serde(default = "Default::default")]
+    pub sibling_keys: HashMap<String, HashMap<String, String>>,
+    /// Drops messages that are not signed, which should only be set once
+    /// every sibling signs its messages
+    #[serde(default = "Default::default")]
+    pub require_signatures: bool,
+}
+
+/// A message along with the signature of the node that sent it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignedPayload {
+    /// The `node_name` of the node that signed the message
+    pub sender: String,
+    pub key_id: String,
+    /// When the message was signed, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Random for every message, so that each can only be received once
+    pub nonce: u64,
+    /// The message, serialized with bincode
+    pub payload: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SignatureError {
+    #[error("Unknown key {key_id} of {sender}")]
+    UnknownKey { sender: String, key_id: String },
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Signed {0} seconds away from the local clock")]
+    Expired(u64),
+    #[error("Replayed message")]
+    Replayed,
+    #[error("Malformed payload {0}")]
+    Malformed(bincode::Error),
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+/// Signatures cover the sender, so that a sibling cannot pass off a message
+/// signed by another sibling as its own, and the timestamp and nonce, so that
+/// they cannot be changed to replay the message
+fn signed_bytes(sender: &str, timestamp: u64, nonce: u64, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(sender.len() + 17 + payload.len());
+    bytes.extend_from_slice(sender.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&timestamp.to_be_bytes());
+    bytes.extend_from_slice(&nonce.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+pub struct MessageSigner {
+    node_name: String,
+    key_id: String,
+    key_pair: Ed25519KeyPair,
+}
+
+impl MessageSigner {
+    pub fn from_config(config: &SigningConfig, node_name: String) -> Result<Self> {
+        let seed = read_to_string(&config.private_key_path)
+            .context(format!("Reading {}", config.private_key_path))?;
+        let seed = STANDARD
+            .decode(seed.trim())
+            .context("Decoding message signing key")?;
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|e| Error::msg(format!("Parsing message signing key: {e}")))?;
+        Ok(Self {
+            node_name,
+            key_id: config.key_id.clone(),
+            key_pair,
+        })
+    }
+
+    /// The base64 encoded public key that siblings verify messages from this node with
+    pub fn get_public_key(&self) -> String {
+        STANDARD.encode(self.key_pair.public_key())
+    }
+
+    pub fn sign<T: Serialize>(&self, message: &T) -> Result<SignedPayload, bincode::Error> {
+        let payload = bincode::serialize(message)?;
+        let timestamp = unix_now();
+        let nonce = rand::thread_rng().next_u64();
+        let signature = self
+            .key_pair
+            .sign(&signed_bytes(&self.node_name, timestamp, nonce, &payload))
+            .as_ref()
+            .to_vec();
+        Ok(SignedPayload {
+            sender: self.node_name.clone(),
+            key_id: self.key_id.clone(),
+            timestamp,
+            nonce,
+            payload,
+            signature,
+        })
+    }
+}
+
+/// Generates a new key, returning the base64 encoded seed and public key
+pub fn generate_key() -> Result<(String, String)> {
+    let mut seed = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut seed);
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(&seed)
+        .map_err(|e| Error::msg(format!("Generating message signing key: {e}")))?;
+    Ok((
+        STANDARD.encode(seed),
+        STANDARD.encode(key_pair.public_key()),
+    ))
+}
+
+/// Sets the signer used by `sign`, which can only be done once
+pub fn set_signer(signer: MessageSigner) -> Result<()> {
+    SIGNER
+        .set(signer)
+        .map_err(|_| Error::msg("The message signer was already set"))
+}
+
+/// Signs the message with the key of this node, or returns None if there is no signer
+pub fn sign<T: Serialize>(message: &T) -> Option<Result<SignedPayload, bincode::Error>> {
+    SIGNER.get().map(|signer| signer.sign(message))
+}
+
+/// The nonces of messages received within the replay window, in the order
+/// they were received
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<(String, u64)>,
+    order: VecDeque<(Instant, (String, u64))>,
+}
+
+/// Verifies the messages of siblings with their public keys
+pub struct SignatureVerifier {
+    keys: HashMap<String, HashMap<String, Vec<u8>>>,
+    require_signatures: bool,
+    seen: Mutex<SeenNonces>,
+}
+
+impl SignatureVerifier {
+    pub fn from_config(config: &SigningConfig) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for (sender, sibling_keys) in &config.sibling_keys {
+            let mut decoded = HashMap::new();
+            for (key_id, key) in sibling_keys {
+                decoded.insert(
+                    key_id.clone(),
+                    STANDARD
+                        .decode(key)
+                        .context(format!("Decoding key {key_id} of {sender}"))?,
+                );
+            }
+            keys.insert(sender.clone(), decoded);
+        }
+        Ok(Self {
+            keys,
+            require_signatures: config.require_signatures,
+            seen: Default::default(),
+        })
+    }
+
+    pub fn requires_signatures(&self) -> bool {
+        self.require_signatures
+    }
+
+    /// Checks that the payload was signed by its sender within the replay
+    /// window and was not received before, then deserializes it
+    pub fn verify<T: DeserializeOwned>(&self, signed: &SignedPayload) -> Result<T, SignatureError> {
+        let Some(key) = self
+            .keys
+            .get(&signed.sender)
+            .and_then(|keys| keys.get(&signed.key_id))
+        else {
+            return Err(SignatureError::UnknownKey {
+                sender: signed.sender.clone(),
+                key_id: signed.key_id.clone(),
+            });
+        };
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(
+                &signed_bytes(
+                    &signed.sender,
+                    signed.timestamp,
+                    signed.nonce,
+                    &signed.payload,
+                ),
+                &signed.signature,
+            )
+            .map_err(|_| SignatureError::InvalidSignature)?;
+
+        let skew = unix_now().abs_diff(signed.timestamp);
+        if skew >= REPLAY_WINDOW.as_secs() {
+            return Err(SignatureError::Expired(skew));
+        }
+        let now = Instant::now();
+        let mut seen = self.seen.lock();
+        // Nonces are remembered for long enough that their timestamp is
+        // outside of the window on both sides
+        while let Some((forget_at, _)) = seen.order.front() {
+            if *forget_at > now {
+                break;
+            }
+            let (_, key) = seen.order.pop_front().unwrap();
+            seen.nonces.remove(&key);
+        }
+        let key = (signed.sender.clone(), signed.nonce);
+        if !seen.nonces.insert(key.clone()) {
+            return Err(SignatureError::Replayed);
+        }
+        seen.order.push_back((now + REPLAY_WINDOW * 2, key));
+        drop(seen);
+
+        bincode::deserialize(&signed.payload).map_err(SignatureError::Malformed)
+    }
+}
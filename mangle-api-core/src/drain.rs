@@ -0,0 +1,89 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Notify;
+
+use crate::rejection::Rejection;
+
+/// Counts the requests being handled, so that shutdown can wait for them
+pub(crate) struct RequestDrain {
+    draining: AtomicBool,
+    active: AtomicUsize,
+    drained: Notify,
+    /// Sent in `Retry-After` to requests refused while draining
+    retry_after: HeaderValue,
+}
+
+impl RequestDrain {
+    pub(crate) fn new(drain_timeout: Duration) -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+            drained: Notify::new(),
+            retry_after: HeaderValue::from(drain_timeout.as_secs().max(1)),
+        }
+    }
+
+    /// Refuses new requests, then waits for every request being handled
+    pub(crate) async fn drain(&self) {
+        self.draining.store(true, Ordering::Release);
+        loop {
+            // Created before checking, so that a request finishing in between is not missed
+            let drained = self.drained.notified();
+            if self.get_active() == 0 {
+                break;
+            }
+            drained.await;
+        }
+    }
+
+    pub(crate) fn get_active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+struct ActiveRequest(Arc<RequestDrain>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+/// Tracks each request until its response is ready, responding with 503 once
+/// the server is draining
+pub(crate) async fn track_requests<B>(
+    drain: Arc<RequestDrain>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // Counted before checking, so that draining cannot miss a request in between
+    drain.active.fetch_add(1, Ordering::AcqRel);
+    let _active = ActiveRequest(drain.clone());
+    if drain.draining.load(Ordering::Acquire) {
+        let mut response = Rejection::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "shutting_down",
+            "The server is shutting down",
+        )
+        .negotiate(request.headers())
+        .into_response();
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, drain.retry_after.clone());
+        return response;
+    }
+    next.run(request).await
+}
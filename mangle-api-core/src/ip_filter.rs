@@ -0,0 +1,239 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr, sync::Arc};
+
+use anyhow::{Context, Error};
+use axum::{
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    log_targets,
+    rate_limit::{client_ip, forwarded_ip, peer_ip},
+    rejection::Rejection,
+};
+
+/// A range of addresses, such as `10.0.0.0/8` or `2001:db8::/32`
+///
+/// A single address, without a prefix length, is a range of one
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of IPv6 sockets are seen as mapped addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        // So are ranges of mapped addresses, such as `::ffff:10.0.0.0/104`
+        let (range, prefix_len) = match self.addr {
+            IpAddr::V6(v6) if self.prefix_len >= 96 => v6
+                .to_ipv4_mapped()
+                .map_or((self.addr, self.prefix_len), |v4| {
+                    (IpAddr::V4(v4), self.prefix_len - 96)
+                }),
+            range => (range, self.prefix_len),
+        };
+        match (range, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .context(format!("Parsing the address of {s}"))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(x) => x
+                .parse()
+                .context(format!("Parsing the prefix length of {s}"))?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(Error::msg(format!(
+                "{s} has a prefix length over {max_prefix_len}"
+            )));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Which addresses may make requests, checked before the API token
+///
+/// Denied addresses are answered with 403 and logged as suspicious
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct IpFilter {
+    /// If not empty, only addresses in these ranges are allowed
+    #[serde(default = "Default::default")]
+    pub allow: Vec<Cidr>,
+    /// Addresses in these ranges are denied, even if they are also allowed
+    #[serde(default = "Default::default")]
+    pub deny: Vec<Cidr>,
+    /// Uses the first address in `X-Forwarded-For`, which clients can forge
+    /// unless every request passes through a reverse proxy that sets it.
+    /// Not needed if the proxy is one of the trusted proxies of the API
+    #[serde(default = "Default::default")]
+    pub trust_forwarded_for: bool,
+}
+
+impl IpFilter {
+    /// Whether the given address may make requests. Unknown addresses, such
+    /// as those of local sockets, are only allowed if there is no allow list
+    pub fn is_allowed(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip else {
+            return self.allow.is_empty();
+        };
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+/// Responds with 403 to requests from addresses that the filter denies
+pub(crate) async fn enforce_ip_filter<B>(
+    filter: Arc<IpFilter>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = if filter.trust_forwarded_for {
+        forwarded_ip(request.headers()).or_else(|| peer_ip(&request))
+    } else {
+        client_ip(&request)
+    };
+    if filter.is_allowed(ip) {
+        return next.run(request).await;
+    }
+    warn!(
+        target: log_targets::SECURITY,
+        "Denied request to {} from {}",
+        request.uri().path(),
+        ip.map(|ip| ip.to_string())
+            .unwrap_or("an unknown address".into())
+    );
+    Rejection::new(
+        StatusCode::FORBIDDEN,
+        "address_denied",
+        "Requests from this address are not allowed",
+    )
+    .negotiate(request.headers())
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_prefixes_contain_their_whole_family() {
+        let v4 = cidr("0.0.0.0/0");
+        assert!(v4.contains(ip("0.0.0.0")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(!v4.contains(ip("2001:db8::1")));
+
+        let v6 = cidr("::/0");
+        assert!(v6.contains(ip("::1")));
+        assert!(v6.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!v6.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn full_prefixes_contain_one_address() {
+        let v4 = cidr("192.0.2.1/32");
+        assert_eq!(v4, cidr("192.0.2.1"));
+        assert!(v4.contains(ip("192.0.2.1")));
+        assert!(!v4.contains(ip("192.0.2.0")));
+        assert!(!v4.contains(ip("192.0.2.2")));
+
+        let v6 = cidr("2001:db8::1/128");
+        assert_eq!(v6, cidr("2001:db8::1"));
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db8::")));
+        assert!(!v6.contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn prefixes_longer_than_the_address_are_refused() {
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/-1".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn mapped_addresses_match_ipv4_ranges() {
+        let v4 = cidr("10.0.0.0/8");
+        assert!(v4.contains(ip("::ffff:10.1.2.3")));
+        assert!(!v4.contains(ip("::ffff:11.1.2.3")));
+
+        let mapped = cidr("::ffff:10.0.0.0/104");
+        assert!(mapped.contains(ip("10.1.2.3")));
+        assert!(mapped.contains(ip("::ffff:10.1.2.3")));
+        assert!(!mapped.contains(ip("11.1.2.3")));
+        assert!(cidr("::ffff:0:0/96").contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn denied_ranges_win_over_allowed_ones() {
+        let filter = IpFilter {
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("10.0.0.1/32")],
+            trust_forwarded_for: false,
+        };
+        assert!(filter.is_allowed(Some(ip("10.0.0.2"))));
+        assert!(!filter.is_allowed(Some(ip("10.0.0.1"))));
+        assert!(!filter.is_allowed(Some(ip("::ffff:10.0.0.1"))));
+        assert!(!filter.is_allowed(Some(ip("192.0.2.1"))));
+        assert!(!filter.is_allowed(None));
+        assert!(IpFilter::default().is_allowed(None));
+    }
+}
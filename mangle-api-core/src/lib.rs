@@ -3,13 +3,54 @@
 #![feature(associated_type_bounds)]
 #![feature(exclusive_wrapper)]
 #![feature(arbitrary_self_types)]
+#![feature(associated_type_defaults)]
 
-use axum::{http::HeaderValue, routing::MethodRouter, Router, Server};
+use axum::{
+    extract::DefaultBodyLimit, handler::Handler, http::HeaderValue, routing::MethodRouter, Router,
+    Server,
+};
 
+pub mod acme;
+pub mod app;
 pub mod auth;
+pub mod config;
+pub mod cors;
+pub mod crash;
+pub mod daemon;
+pub mod data_channel;
+pub mod dead_letters;
 pub mod distributed;
+mod drain;
+pub mod ip_filter;
+mod limits;
+pub mod log_buffer;
+pub mod log_format;
+pub mod log_levels;
+pub mod metrics;
 pub mod neo_api;
+pub mod openapi;
+pub mod pagination;
+pub mod persistent_queue;
+pub mod prelude;
+pub mod rate_limit;
+pub mod readiness;
+pub mod rejection;
+pub mod redact;
+pub mod reload;
+pub mod request_id;
+pub mod response_cache;
+pub mod rng;
+#[cfg(feature = "aws")]
+pub mod route53;
+pub mod route_layers;
+pub mod shutdown;
+pub mod static_routes;
+pub mod status;
+pub mod tasks;
+pub mod tcp;
+pub mod telemetry;
 pub mod tls;
+pub mod trusted_proxies;
 pub mod webrtc;
 pub mod ws;
 
@@ -18,44 +59,60 @@ pub mod db;
 
 use anyhow::{Context, Error, Result};
 use clap::{arg, builder::IntoResettable, ArgMatches, Command};
-use lers::{solver::Http01Solver, Directory, LETS_ENCRYPT_PRODUCTION_URL};
 use messagist::{
     pipes::{start_connection, start_listener, ListenerErrorHandler, ToLocalSocketName},
     ExclusiveMessageHandler,
 };
+use futures::future::{join, join_all, try_join_all};
 use std::{
     fmt::Display,
     future::{pending, Future},
+    iter::once,
+    pin::Pin,
 };
 
 use fern::{log_file, Dispatch};
-use log::{error, info, warn, LevelFilter};
-use parking_lot::Mutex;
+use log::{error, info, warn};
 use regex::{Regex, RegexSet};
-use serde::{de::DeserializeOwned, Deserialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     env,
     ffi::OsString,
-    fs::{read_to_string, File},
+    fs::File,
     future::Pending,
-    io::{Read, Write},
+    io::Read,
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::watch,
+    time::{timeout_at, Instant},
 };
 pub use tokio_native_tls::native_tls::Identity;
-use toml::from_str;
 use tower::ServiceBuilder;
 use tower_http::{
     auth::RequireAuthorizationLayer,
     compression::CompressionLayer,
-    cors::{AllowMethods, AllowOrigin, CorsLayer},
+    cors::{AllowMethods, AllowOrigin},
     trace::TraceLayer,
 };
 
-use auth::bearer::BearerAuth;
+use auth::{
+    audit::AuthAudit,
+    bearer::{BearerAuth, ScopedToken},
+    signed_requests::verify_signature,
+};
 
+#[cfg(feature = "aws")]
+pub use aws_sdk_route53;
 pub use bimap;
+pub use chrono;
+pub use clap;
 pub use fern;
+pub use opentelemetry;
 pub use parking_lot;
 pub use rand;
 #[cfg(any(feature = "redis"))]
@@ -65,24 +122,43 @@ pub use serde_json;
 pub use toml;
 pub use tower_http;
 
-use crate::tls::TlsAcceptor;
+use crate::{
+    acme::{obtain_certificate, renew_certificate, save_credentials, CertificateRenewal},
+    config::read_config,
+    cors::CorsConfig,
+    drain::{track_requests, RequestDrain},
+    ip_filter::{enforce_ip_filter, Cidr, IpFilter},
+    limits::{enforce_body_size, enforce_timeout, RouteLimit},
+    log_format::{LogFormat, LogFormats},
+    log_levels::{stderr_level_for, CRITICAL_LOG_LEVEL, ROUTING_LOG_LEVEL},
+    metrics::{instrument, metrics_route, Metrics},
+    openapi::{openapi_route, OpenApi, OPENAPI_PATH},
+    rate_limit::{add_rate_limit_headers, PeerAddr, RateLimiter, RateLimits},
+    readiness::HealthProbes,
+    reload::{HttpSettings, ReloadableConfig, ReloadableCorsLayer},
+    request_id::{make_span, propagate_request_id},
+    response_cache::serve_cached,
+    route_layers::{route_regex, RouteLayer},
+    shutdown::ShutdownNotifier,
+    static_routes::{StaticDir, StaticRoutes},
+    tasks::TaskManager,
+    tcp::TcpConfig,
+    tls::{SharedTlsAcceptor, TlsAcceptor},
+    trusted_proxies::{resolve_trusted_proxies, ForwardedHeader, TrustedProxies},
+};
 
 mod log_targets {
     pub const SECURITY: &str = "suspicious_security";
 }
 const ROUTING_REGEX_RAW: &str = "^(tower_http::trace|hyper::proto|mio|tracing|routing)";
 
-// Setup logger
-static CRITICAL_LOG_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
-static STDERR_LOG_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
-static ROUTING_LOG_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
-
 pub fn make_app<const N: usize>(
     name: &'static str,
     version: impl IntoResettable<clap::builder::Str>,
     about: &'static str,
     extra_log_targets: [&'static str; N],
 ) -> Command {
+    log_levels::register_targets(extra_log_targets);
     Command::new(name)
         .version(version)
         .author("manglemix")
@@ -90,14 +166,18 @@ pub fn make_app<const N: usize>(
         .subcommand(
             Command::new("start")
                 .about("Starts the web server in the current directory")
-                .arg(arg!([config_path] "An optional path to a config file")),
+                .arg(arg!([config_path] "An optional path to a config file"))
+                .arg(
+                    arg!(--daemon "Runs the server in the background, writing its PID to a file")
+                        .id(daemon::DAEMON_FLAG),
+                ),
         )
         .subcommand(
             Command::new("log_level")
-                .about("Sets or gets the log level of a specific log target")
+                .about("Sets or gets the log level of a specific log target, or lists every level")
                 .arg(
-                    arg!(<target> "The logging target to set or get").value_parser(
-                        ["stderr", "routing"]
+                    arg!([target] "The logging target to set or get").value_parser(
+                        ["critical", "stderr", "routing"]
                             .into_iter()
                             .chain(extra_log_targets)
                             .collect::<Vec<_>>(),
@@ -108,11 +188,38 @@ pub fn make_app<const N: usize>(
                         .value_parser(["off", "error", "warn", "info", "debug", "trace"]),
                 ),
         )
+        .subcommand(
+            Command::new("check-config")
+                .about("Validates the config file and prints the effective configuration")
+                .arg(arg!([config_path] "An optional path to a config file")),
+        )
         .subcommand(Command::new("status").about("Checks the status of the server"))
+        .subcommand(
+            Command::new("logs")
+                .about("Shows the recent logs of the currently running server")
+                .arg(arg!(--target <TARGET> "Only shows logs whose target starts with this"))
+                .arg(
+                    arg!(--level <LEVEL> "The most verbose level to show")
+                        .value_parser(["error", "warn", "info", "debug", "trace"])
+                        .default_value("trace"),
+                )
+                .arg(arg!(-f --follow "Keeps showing new logs as they come in")),
+        )
         .subcommand(Command::new("stop").about("Stops the currently running server"))
+        .subcommand(
+            Command::new("reload")
+                .about("Applies the settings in the config file that can change while running"),
+        )
+        .subcommand(
+            Command::new("drain")
+                .about("Takes the currently running server out of rotation before a deploy"),
+        )
+        .subcommand(
+            Command::new("undrain").about("Puts the currently running server back into rotation"),
+        )
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub enum BindAddress {
     #[serde(rename = "local")]
     Local(String),
@@ -120,6 +227,20 @@ pub enum BindAddress {
     HTTP(IpAddr),
     #[serde(rename = "network")]
     Network(SocketAddr),
+    /// Listens on the given port of every IPv4 and IPv6 interface
+    #[serde(rename = "dual_stack")]
+    DualStack(u16),
+}
+
+impl Display for BindAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindAddress::Local(addr) => write!(f, "local {addr}"),
+            BindAddress::HTTP(addr) => write!(f, "http {addr}"),
+            BindAddress::Network(addr) => write!(f, "{addr}"),
+            BindAddress::DualStack(port) => write!(f, "dual stack port {port}"),
+        }
+    }
 }
 
 pub fn get_pipe_name(pipe_name_env_var: &'static str, default_pipe_name: &'static str) -> OsString {
@@ -139,6 +260,20 @@ pub async fn pre_matches<'a, Config>(
     pipe_name: impl ToLocalSocketName<'a>,
     on_active_msg: Option<String>,
 ) -> Result<CommandMatchResult<Config>>
+where
+    Config: DeserializeOwned,
+{
+    pre_matches_with_env(matches, pipe_name, on_active_msg, None).await
+}
+
+/// Like `pre_matches`, but any field of the config can be overridden by an
+/// environment variable that starts with `env_prefix`, as with `config::read_config`
+pub async fn pre_matches_with_env<'a, Config>(
+    matches: &ArgMatches,
+    pipe_name: impl ToLocalSocketName<'a>,
+    on_active_msg: Option<String>,
+    env_prefix: Option<&str>,
+) -> Result<CommandMatchResult<Config>>
 where
     Config: DeserializeOwned,
 {
@@ -155,10 +290,7 @@ where
                 .get_one("config_path")
                 .cloned()
                 .unwrap_or("configs.toml".into());
-            let err_msg = format!("Reading configuration file: {config_path}");
-            from_str(&read_to_string(config_path).context(err_msg.clone())?)
-                .context(err_msg)
-                .map(CommandMatchResult::StartProgram)
+            read_config(&config_path, env_prefix).map(CommandMatchResult::StartProgram)
         }
         Some((name, matches)) => Ok(CommandMatchResult::Unmatched((name, matches))),
         None => Err(Error::msg(
@@ -171,6 +303,20 @@ pub fn setup_logger(
     stderr_log_path: &str,
     routing_log_path: &str,
     security_log_path: &str,
+) -> Result<Dispatch> {
+    setup_logger_with_formats(
+        stderr_log_path,
+        routing_log_path,
+        security_log_path,
+        LogFormats::default(),
+    )
+}
+
+pub fn setup_logger_with_formats(
+    stderr_log_path: &str,
+    routing_log_path: &str,
+    security_log_path: &str,
+    formats: LogFormats,
 ) -> Result<Dispatch> {
     let routing_regex = Regex::new(ROUTING_REGEX_RAW).unwrap();
     let non_stderr = Arc::new(
@@ -181,25 +327,15 @@ pub fn setup_logger(
         .unwrap(),
     );
     let non_stderr2 = non_stderr.clone();
+    let non_stderr3 = non_stderr.clone();
+    let routing_regex2 = routing_regex.clone();
 
     Ok(Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}:{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.level(),
-                record.target(),
-                record
-                    .line()
-                    .as_ref()
-                    .map(ToString::to_string)
-                    .unwrap_or("?".into()),
-                message
-            ))
-        })
         // Critical-Only Stderr to Stderr
         .chain(
-            Dispatch::new()
+            formats
+                .stderr
+                .dispatch()
                 .filter(move |metadata| {
                     !non_stderr.is_match(metadata.target())
                         && metadata.level() <= *CRITICAL_LOG_LEVEL.lock()
@@ -208,10 +344,12 @@ pub fn setup_logger(
         )
         // All Stderr to file
         .chain(
-            Dispatch::new()
+            formats
+                .file
+                .dispatch()
                 .filter(move |metadata| {
                     !non_stderr2.is_match(metadata.target())
-                        && metadata.level() <= *STDERR_LOG_LEVEL.lock()
+                        && metadata.level() <= stderr_level_for(metadata.target())
                 })
                 .chain(
                     log_file(stderr_log_path).context(format!("Opening {:?}", stderr_log_path))?,
@@ -219,7 +357,9 @@ pub fn setup_logger(
         )
         // Routing to file
         .chain(
-            Dispatch::new()
+            formats
+                .file
+                .dispatch()
                 .filter(move |metadata| {
                     routing_regex.is_match(metadata.target())
                         && metadata.level() <= *ROUTING_LOG_LEVEL.lock()
@@ -229,9 +369,26 @@ pub fn setup_logger(
                         .context(format!("Opening {:?}", routing_log_path))?,
                 ),
         )
+        // Everything that reaches a file to the buffer for the logs command,
+        // which is always shown as text
+        .chain(
+            LogFormat::Text
+                .dispatch()
+                .filter(move |metadata| {
+                    let target = metadata.target();
+                    (routing_regex2.is_match(target)
+                        && metadata.level() <= *ROUTING_LOG_LEVEL.lock())
+                        || (!non_stderr3.is_match(target)
+                            && metadata.level() <= stderr_level_for(target))
+                        || target.starts_with(log_targets::SECURITY)
+                })
+                .chain(fern::Output::call(log_buffer::push)),
+        )
         // Suspicious security to file (maybe more?)
         .chain(
-            Dispatch::new()
+            formats
+                .file
+                .dispatch()
                 .filter(|metadata| metadata.target().starts_with(log_targets::SECURITY))
                 .chain(
                     log_file(security_log_path)
@@ -273,62 +430,8 @@ pub async fn get_https_credentials(
 
     if certs.is_empty() {
         warn!("No certs were found, obtaining...");
-        if let BindAddress::Network(mut address) = bind_address {
-            let solver = Http01Solver::new();
-            address.set_port(80);
-            let handle = solver
-                .start(&address)
-                .context(format!("Binding ACME solver to {address}"))?;
-
-            // Create a new directory for Let's Encrypt Production
-            let directory = Directory::builder(LETS_ENCRYPT_PRODUCTION_URL)
-                .http01_solver(Box::new(solver))
-                .build()
-                .await
-                .context("Building ACME directory")?;
-
-            // Create an ACME account to order your certificate. In production, you should store
-            // the private key, so you can renew your certificate.
-            let account = directory
-                .account()
-                .terms_of_service_agreed(true)
-                .contacts(vec![format!("mailto:{https_email}")])
-                .create_if_not_exists()
-                .await
-                .context("Creating ACME account")?;
-
-            // Obtain your certificate
-            let certificate = account
-                .certificate()
-                .add_domain(https_domain)
-                .obtain()
-                .await
-                .context("Collecting certificate")?;
-
-            certs = certificate
-                .fullchain_to_pem()
-                .context("Converting certificate to pem")?;
-
-            key = certificate
-                .private_key_to_pem()
-                .context("Converting private key to pem")?;
-
-            handle.stop().await.context("Stopping ACME handle")?;
-
-            File::create(certs_path)
-                .context(format!("Opening {}", certs_path))?
-                .write_all(&certs)
-                .context(format!("Writing to {}", certs_path))?;
-
-            File::create(key_path)
-                .context(format!("Opening {}", key_path))?
-                .write_all(&key)
-                .context(format!("Writing to {}", key_path))?;
-        } else {
-            return Err(Error::msg(
-                "Failed to replace missing credentials as we are binded locally",
-            ));
-        }
+        (certs, key) = obtain_certificate(bind_address, https_email, https_domain).await?;
+        save_credentials(certs_path, key_path, &certs, &key)?;
     }
 
     Identity::from_pkcs8(&certs, &key).context("Loading HTTPS Credentials")
@@ -351,13 +454,37 @@ pub struct API<
     pipe_name: P,
     cors_allowed_methods: AllowMethods,
     cors_allowed_origins: AllowOrigin,
+    /// Replaces the allowed methods and origins if set
+    cors: Option<CorsConfig>,
     api_token: AT,
     bind_address: BA,
     public_paths: [&'static str; N1],
     routes: [(&'static str, MethodRouter<S>); N2],
     https_identity: Option<Identity>,
+    tcp_config: TcpConfig,
     control_handler: H,
     concurrent_fut: Fut,
+    rate_limits: RateLimits,
+    extra_bind_addresses: Vec<BindAddress>,
+    certificate_renewal: Option<CertificateRenewal>,
+    static_routes: StaticRoutes,
+    http_settings: Option<ReloadableConfig<HttpSettings>>,
+    router: Option<Router<S>>,
+    health_probes: Option<HealthProbes>,
+    metrics: Option<(String, Arc<Metrics>)>,
+    request_timeout: Option<Duration>,
+    max_body_size: Option<usize>,
+    ip_filter: Option<IpFilter>,
+    trusted_proxies: Option<TrustedProxies>,
+    auth_audit: Option<Arc<AuthAudit>>,
+    scoped_tokens: Vec<ScopedToken>,
+    task_manager: Option<TaskManager>,
+    drain_timeout: Option<Duration>,
+    drained_sessions: Vec<&'static ShutdownNotifier>,
+    route_layers: Vec<(&'static str, RouteLayer<S>)>,
+    /// And whether it is public
+    fallback: Option<(MethodRouter<S>, bool)>,
+    openapi: Option<OpenApi>,
 }
 
 pub fn new_api() -> API<Unset, Unset, Unset, Unset, 0, 0, Unset, Pending<()>> {
@@ -366,13 +493,35 @@ pub fn new_api() -> API<Unset, Unset, Unset, Unset, 0, 0, Unset, Pending<()>> {
         pipe_name: Unset,
         cors_allowed_methods: AllowMethods::from([]),
         cors_allowed_origins: AllowOrigin::from([]),
+        cors: None,
         api_token: Unset,
         bind_address: Unset,
         public_paths: [],
         routes: [],
         https_identity: None,
+        tcp_config: TcpConfig::default(),
         control_handler: Unset,
         concurrent_fut: pending(),
+        rate_limits: RateLimits::default(),
+        extra_bind_addresses: Vec::new(),
+        certificate_renewal: None,
+        static_routes: StaticRoutes::default(),
+        http_settings: None,
+        router: None,
+        health_probes: None,
+        metrics: None,
+        request_timeout: None,
+        max_body_size: None,
+        ip_filter: None,
+        trusted_proxies: None,
+        auth_audit: None,
+        scoped_tokens: Vec::new(),
+        task_manager: None,
+        drain_timeout: None,
+        drained_sessions: Vec::new(),
+        route_layers: Vec::new(),
+        fallback: None,
+        openapi: None,
     }
 }
 
@@ -389,13 +538,35 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes: [],
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: None,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: Vec::new(),
+            fallback: None,
+            openapi: self.openapi,
         }
     }
     pub fn set_pipe_name(self, pipe_name: OsString) -> API<S, OsString, AT, BO, N1, N2, H, Fut> {
@@ -404,13 +575,35 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes: self.routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
     pub fn set_cors_allowed_methods(
@@ -422,13 +615,35 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: cors_allowed_methods.into(),
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes: self.routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
     pub fn set_cors_allowed_origins(
@@ -440,15 +655,42 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: cors_allowed_origins.into(),
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes: self.routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
+    /// Sets every CORS setting, replacing the allowed methods and origins
+    pub fn set_cors(mut self, cors: CorsConfig) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.cors = Some(cors);
+        self
+    }
     pub fn set_api_token(
         self,
         api_token: HeaderValue,
@@ -458,13 +700,35 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes: self.routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
     pub fn set_bind_address(
@@ -476,15 +740,43 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address,
             public_paths: self.public_paths,
             routes: self.routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
+    /// Also serves the API on the given address, such as a local socket for a
+    /// reverse proxy alongside a network address
+    pub fn add_bind_address(mut self, bind_address: BindAddress) -> Self {
+        self.extra_bind_addresses.push(bind_address);
+        self
+    }
     pub fn set_public_paths<const N1_2: usize>(
         self,
         public_paths: [&'static str; N1_2],
@@ -494,13 +786,35 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths,
             routes: self.routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
     pub fn set_routes<const N2_2: usize>(
@@ -512,14 +826,194 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
+        }
+    }
+    /// Adds the given redirects and aliases, which are public
+    pub fn set_static_routes(
+        mut self,
+        mut static_routes: StaticRoutes,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        // Keeps the directories that were already added
+        for dir in self.static_routes.take_dirs() {
+            static_routes.add_dir(dir);
         }
+        self.static_routes = static_routes;
+        self
+    }
+    /// Serves the files in `dir` under `path`, such as `.serve_static("/assets", "./public")`
+    ///
+    /// The files are public, and are read on every request
+    pub fn serve_static(
+        self,
+        path: impl Into<String>,
+        dir: impl Into<PathBuf>,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.add_static_dir(StaticDir::new(path, dir))
+    }
+    /// Serves a directory with more options than `serve_static`, such as preloading
+    pub fn add_static_dir(mut self, dir: StaticDir) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.static_routes.add_dir(dir);
+        self
+    }
+    /// Serves `/healthz` and `/readyz`, which are public
+    pub fn set_health_probes(
+        mut self,
+        health_probes: HealthProbes,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.health_probes = Some(health_probes);
+        self
+    }
+    /// Instruments every request, serving the metrics at `path` in the Prometheus text format
+    ///
+    /// Like any other route, `path` needs the API token unless it is public
+    pub fn set_metrics(
+        mut self,
+        path: impl Into<String>,
+        metrics: Arc<Metrics>,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.metrics = Some((path.into(), metrics));
+        self
+    }
+    /// Responds with 408 to requests that are not handled within `timeout`,
+    /// logging them as suspicious
+    pub fn set_request_timeout(mut self, timeout: Duration) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.request_timeout = Some(timeout);
+        self
+    }
+    /// Responds with 413 to requests with a body larger than `max_body_size`
+    /// bytes, logging them as suspicious if they declare their length
+    pub fn set_max_body_size(mut self, max_body_size: usize) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.max_body_size = Some(max_body_size);
+        self
+    }
+    /// Responds with 403 to requests from addresses that `ip_filter` denies,
+    /// before their API token is checked
+    pub fn set_ip_filter(mut self, ip_filter: IpFilter) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.ip_filter = Some(ip_filter);
+        self
+    }
+    /// Trusts the `header` of requests from these ranges, such as load
+    /// balancers, taking the address of the client from it for security logs,
+    /// rate limits and the IP filter
+    ///
+    /// Without trusted proxies, clients are identified by their peer address
+    pub fn set_trusted_proxies(
+        mut self,
+        trusted_proxies: impl IntoIterator<Item = Cidr>,
+        header: ForwardedHeader,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.trusted_proxies = Some(TrustedProxies {
+            ranges: trusted_proxies.into_iter().collect(),
+            header,
+        });
+        self
+    }
+    /// Records requests with a wrong API token, locking out the IPs that give
+    /// too many of them
+    ///
+    /// The same audit can be given to token granters, so that failures of
+    /// every kind add up
+    pub fn set_auth_audit(
+        mut self,
+        auth_audit: Arc<AuthAudit>,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.auth_audit = Some(auth_audit);
+        self
+    }
+    /// Accepts these API tokens on top of the main one, each only for the
+    /// paths in its scope
+    pub fn set_scoped_tokens(
+        mut self,
+        scoped_tokens: Vec<ScopedToken>,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.scoped_tokens = scoped_tokens;
+        self
+    }
+    /// Runs the background tasks of `task_manager` alongside the API
+    pub fn set_task_manager(
+        mut self,
+        task_manager: TaskManager,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.task_manager = Some(task_manager);
+        self
+    }
+    /// On shutdown, waits up to `drain_timeout` for the requests being handled
+    /// and the sessions given to `add_drained_sessions`, while responding to
+    /// new requests with 503. Connections still open after that are cut
+    pub fn set_drain_timeout(
+        mut self,
+        drain_timeout: Duration,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.drain_timeout = Some(drain_timeout);
+        self
+    }
+    /// Also waits for these WebSocket sessions when draining, notifying them
+    /// that the server is shutting down
+    pub fn add_drained_sessions(
+        mut self,
+        sessions: &'static ShutdownNotifier,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.drained_sessions.push(sessions);
+        self
+    }
+    /// Adds settings and layers to a route given to `set_routes`, such as
+    /// `.add_route_layers("/ws_api", [RouteLayer::Public, RouteLayer::Timeout(timeout)])`
+    ///
+    /// Layers are applied in order, inside the layers of the API, so the
+    /// first one is closest to the handler
+    pub fn add_route_layers(
+        mut self,
+        route: &'static str,
+        layers: impl IntoIterator<Item = RouteLayer<S>>,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.route_layers
+            .extend(layers.into_iter().map(|layer| (route, layer)));
+        self
+    }
+    /// Serves the given document at `/openapi.json`, which is public
+    pub fn set_openapi(mut self, openapi: OpenApi) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.openapi = Some(openapi);
+        self
+    }
+    /// Takes the CORS settings and extra public paths from the given handle,
+    /// so that they can be reloaded while the server runs
+    ///
+    /// The CORS settings set on this API are ignored
+    pub fn set_http_settings(
+        mut self,
+        http_settings: ReloadableConfig<HttpSettings>,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.http_settings = Some(http_settings);
+        self
     }
     pub fn set_https_identity(self, https_identity: Identity) -> API<S, P, AT, BO, N1, N2, H, Fut> {
         API {
@@ -527,13 +1021,80 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes: self.routes,
             https_identity: Some(https_identity),
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
+        }
+    }
+    /// Renews the HTTPS identity before it expires, without restarting the servers
+    pub fn set_certificate_renewal(
+        mut self,
+        certificate_renewal: CertificateRenewal,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.certificate_renewal = Some(certificate_renewal);
+        self
+    }
+    pub fn set_tcp_config(self, tcp_config: TcpConfig) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        API {
+            state: self.state,
+            pipe_name: self.pipe_name,
+            cors_allowed_methods: self.cors_allowed_methods,
+            cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
+            api_token: self.api_token,
+            bind_address: self.bind_address,
+            public_paths: self.public_paths,
+            routes: self.routes,
+            https_identity: self.https_identity,
+            tcp_config,
+            control_handler: self.control_handler,
+            concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
     pub fn set_control_handler<H2>(self, control_handler: H2) -> API<S, P, AT, BO, N1, N2, H2, Fut>
@@ -545,15 +1106,46 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes: self.routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler,
             concurrent_fut: self.concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
+    /// Limits how often each client may request the given routes, responding
+    /// with 429 once a limit is hit
+    pub fn set_rate_limits(
+        mut self,
+        rate_limits: impl Into<RateLimits>,
+    ) -> API<S, P, AT, BO, N1, N2, H, Fut> {
+        self.rate_limits = rate_limits.into();
+        self
+    }
     pub fn set_concurrent_future<Fut2>(
         self,
         concurrent_fut: Fut2,
@@ -566,17 +1158,83 @@ impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N
             pipe_name: self.pipe_name,
             cors_allowed_methods: self.cors_allowed_methods,
             cors_allowed_origins: self.cors_allowed_origins,
+            cors: self.cors,
             api_token: self.api_token,
             bind_address: self.bind_address,
             public_paths: self.public_paths,
             routes: self.routes,
             https_identity: self.https_identity,
+            tcp_config: self.tcp_config,
             control_handler: self.control_handler,
             concurrent_fut,
+            rate_limits: self.rate_limits,
+            extra_bind_addresses: self.extra_bind_addresses,
+            certificate_renewal: self.certificate_renewal,
+            static_routes: self.static_routes,
+            http_settings: self.http_settings,
+            router: self.router,
+            health_probes: self.health_probes,
+            metrics: self.metrics,
+            request_timeout: self.request_timeout,
+            max_body_size: self.max_body_size,
+            ip_filter: self.ip_filter,
+            trusted_proxies: self.trusted_proxies,
+            auth_audit: self.auth_audit,
+            scoped_tokens: self.scoped_tokens,
+            task_manager: self.task_manager,
+            drain_timeout: self.drain_timeout,
+            drained_sessions: self.drained_sessions,
+            route_layers: self.route_layers,
+            fallback: self.fallback,
+            openapi: self.openapi,
         }
     }
 }
 
+impl<S, P, AT, BO, const N1: usize, const N2: usize, H, Fut> API<S, P, AT, BO, N1, N2, H, Fut>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    /// Merges the routes and fallback of the given router into the API, for
+    /// routers with their own layers or nested routers
+    ///
+    /// Like any other route, its paths need the API token unless they are public.
+    /// Routes that overlap with existing routes panic
+    pub fn merge_router(mut self, router: Router<S>) -> Self {
+        self.router = Some(match self.router {
+            Some(existing) => existing.merge(router),
+            None => router,
+        });
+        self
+    }
+    /// Serves the given router under `path`, as with `merge_router`
+    pub fn nest_router(self, path: &str, router: Router<S>) -> Self {
+        self.merge_router(Router::new().nest(path, router))
+    }
+    /// Responds to requests that match no route with `handler` instead of an
+    /// empty 404, such as `rejection::not_found`
+    ///
+    /// Replaces the fallback of merged routers. Like any other route, it needs
+    /// the API token, so unauthorized requests are rejected with 401
+    pub fn set_fallback<Hd, T>(mut self, handler: Hd) -> Self
+    where
+        Hd: Handler<T, S>,
+        T: 'static,
+    {
+        self.fallback = Some((axum::routing::any(handler), false));
+        self
+    }
+    /// Like `set_fallback`, but without needing the API token
+    pub fn set_public_fallback<Hd, T>(mut self, handler: Hd) -> Self
+    where
+        Hd: Handler<T, S>,
+        T: 'static,
+    {
+        self.fallback = Some((axum::routing::any(handler), true));
+        self
+    }
+}
+
 impl<S, const N1: usize, const N2: usize, H, Fut>
     API<S, OsString, HeaderValue, BindAddress, N1, N2, H, Fut>
 where
@@ -589,30 +1247,220 @@ where
         let control_listener = start_listener(self.pipe_name, self.control_handler)
             .context("Setting up control listener")?;
 
+        for path in self.static_routes.paths() {
+            if self.routes.iter().any(|(route, _)| *route == path) {
+                return Err(Error::msg(format!(
+                    "{path} is both a route and a redirect or alias"
+                )));
+            }
+        }
+        self.static_routes
+            .check_dirs()
+            .context("Checking static directories")?;
+        self.rate_limits.validate()?;
+
+        let mut timeouts = RouteLimit::new(self.request_timeout);
+        let mut max_body_sizes = RouteLimit::new(self.max_body_size);
+        let mut public_routes = Vec::new();
+        let mut custom_layers: HashMap<&str, Vec<RouteLayer<S>>> = HashMap::new();
+        for (route, layer) in self.route_layers {
+            if !self.routes.iter().any(|(x, _)| *x == route) {
+                return Err(Error::msg(format!(
+                    "{route} has route layers but is not a route"
+                )));
+            }
+            match layer {
+                RouteLayer::Public => public_routes.push(route_regex(route)),
+                RouteLayer::Timeout(timeout) => timeouts.set_override(route, timeout),
+                RouteLayer::MaxBodySize(max_body_size) => {
+                    max_body_sizes.set_override(route, max_body_size);
+                    // Replaces the limit of the API, which is set further out
+                    custom_layers
+                        .entry(route)
+                        .or_default()
+                        .push(RouteLayer::custom(DefaultBodyLimit::max(max_body_size)));
+                }
+                RouteLayer::Cache(cache) => {
+                    custom_layers
+                        .entry(route)
+                        .or_default()
+                        .push(RouteLayer::custom(axum::middleware::from_fn(
+                            move |req, next| serve_cached(cache.clone(), req, next),
+                        )))
+                }
+                RouteLayer::Signed(verifier) => {
+                    // The signature replaces the API token
+                    public_routes.push(route_regex(route));
+                    custom_layers
+                        .entry(route)
+                        .or_default()
+                        .push(RouteLayer::custom(axum::middleware::from_fn(
+                            move |req, next| verify_signature(verifier.clone(), req, next),
+                        )))
+                }
+                RouteLayer::Role(apply) => {
+                    // The token replaces the API token
+                    public_routes.push(route_regex(route));
+                    custom_layers
+                        .entry(route)
+                        .or_default()
+                        .push(RouteLayer::Custom(apply));
+                }
+                RouteLayer::Custom(_) => custom_layers.entry(route).or_default().push(layer),
+            }
+        }
+
+        let public_paths = self
+            .public_paths
+            .iter()
+            .map(ToString::to_string)
+            .chain(self.static_routes.public_paths())
+            .chain(public_routes)
+            .chain(
+                self.health_probes
+                    .iter()
+                    .flat_map(|_| HealthProbes::PATHS)
+                    .map(|path| format!("^{path}$")),
+            )
+            .chain(
+                self.openapi
+                    .iter()
+                    .map(|_| format!("^{}$", regex::escape(OPENAPI_PATH))),
+            )
+            .collect::<Vec<_>>();
+
+        let http_settings = match self.http_settings {
+            Some(x) => x,
+            None => ReloadableConfig::new(match self.cors {
+                Some(cors) => HttpSettings::from_cors_config(&cors, &[])?,
+                None => {
+                    HttpSettings::new(self.cors_allowed_methods, self.cors_allowed_origins, &[])?
+                }
+            }),
+        };
+
+        let openapi = match self.openapi {
+            Some(openapi) => {
+                if let Some(route) = openapi
+                    .routes()
+                    .find(|route| !self.routes.iter().any(|(x, _)| x == route))
+                {
+                    return Err(Error::msg(format!(
+                        "{route} is documented but is not a route"
+                    )));
+                }
+                let public_paths = RegexSet::new(&public_paths).context("Parsing public paths")?;
+                let http_settings = http_settings.get();
+                Some(
+                    openapi.render(|route| {
+                        public_paths.is_match(route) || http_settings.is_public(route)
+                    }),
+                )
+            }
+            None => None,
+        };
+
         // Setup Router
-        let mut router = Router::new();
+        let mut router = self.router.unwrap_or_default();
 
-        for (route, method) in self.routes {
+        for (route, mut method) in self.routes {
+            for layer in custom_layers.remove(route).into_iter().flatten() {
+                if let RouteLayer::Custom(apply) = layer {
+                    method = apply(method);
+                }
+            }
             router = router.route(route, method);
         }
+        router = self.static_routes.add_to(router);
+        if let Some(health_probes) = self.health_probes {
+            router = health_probes.add_to(router);
+        }
+        let public_fallback = match self.fallback {
+            Some((fallback, public)) => {
+                router = router.fallback(fallback);
+                public
+            }
+            None => false,
+        };
+        if let Some(openapi) = openapi {
+            router = router.route(OPENAPI_PATH, openapi_route(openapi));
+        }
+        let metrics = match self.metrics {
+            Some((path, metrics)) => {
+                router = router.route(&path, metrics_route(metrics.clone()));
+                Some(metrics)
+            }
+            None => None,
+        };
+
+        let mut bearer_auth = BearerAuth::new(
+            self.api_token,
+            RegexSet::new(public_paths).expect("Parsing open paths for Bearer Auth"),
+        )
+        .add_scoped_tokens(&self.scoped_tokens)?
+        .set_http_settings(http_settings.clone())
+        .set_public_fallback(public_fallback);
+        if let Some(auth_audit) = self.auth_audit {
+            bearer_auth = bearer_auth.set_audit(auth_audit);
+        }
+
+        let drain = self
+            .drain_timeout
+            .map(|drain_timeout| Arc::new(RequestDrain::new(drain_timeout)));
 
         let router = router.with_state(self.state).layer(
             ServiceBuilder::new()
                 .layer(CompressionLayer::new())
-                .layer(TraceLayer::new_for_http())
-                .layer(
-                    CorsLayer::new()
-                        .allow_methods(self.cors_allowed_methods)
-                        .allow_origin(self.cors_allowed_origins),
-                )
-                .layer(RequireAuthorizationLayer::custom(BearerAuth::new(
-                    self.api_token,
-                    RegexSet::new(self.public_paths).expect("Parsing open paths for Bearer Auth"),
-                ))),
+                .option_layer(self.trusted_proxies.map(|trusted_proxies| {
+                    let trusted_proxies = Arc::new(trusted_proxies);
+                    axum::middleware::from_fn(move |req, next| {
+                        resolve_trusted_proxies(trusted_proxies.clone(), req, next)
+                    })
+                }))
+                .layer(axum::middleware::from_fn(propagate_request_id))
+                .layer(TraceLayer::new_for_http().make_span_with(make_span))
+                .option_layer(drain.clone().map(|drain| {
+                    axum::middleware::from_fn(move |req, next| {
+                        track_requests(drain.clone(), req, next)
+                    })
+                }))
+                .option_layer(metrics.map(|metrics| {
+                    axum::middleware::from_fn(move |req, next| {
+                        instrument(metrics.clone(), req, next)
+                    })
+                }))
+                .option_layer(timeouts.is_set().then(|| {
+                    let timeouts = Arc::new(timeouts);
+                    axum::middleware::from_fn(move |req, next| {
+                        enforce_timeout(timeouts.clone(), req, next)
+                    })
+                }))
+                .option_layer(max_body_sizes.is_set().then(|| {
+                    let max_body_sizes = Arc::new(max_body_sizes);
+                    axum::middleware::from_fn(move |req, next| {
+                        enforce_body_size(max_body_sizes.clone(), req, next)
+                    })
+                }))
+                .option_layer(self.max_body_size.map(DefaultBodyLimit::max))
+                .layer(ReloadableCorsLayer(http_settings))
+                .option_layer(self.ip_filter.map(|ip_filter| {
+                    let ip_filter = Arc::new(ip_filter);
+                    axum::middleware::from_fn(move |req, next| {
+                        enforce_ip_filter(ip_filter.clone(), req, next)
+                    })
+                }))
+                .layer(RequireAuthorizationLayer::custom(RateLimiter::new(
+                    self.rate_limits,
+                )))
+                .layer(axum::middleware::from_fn(add_rate_limit_headers))
+                .layer(RequireAuthorizationLayer::custom(bearer_auth)),
         );
 
         let startup_msg = std::cell::RefCell::new(String::new());
+        let (shutdown_sender, shutdown) = watch::channel(false);
 
+        let drain_timeout = self.drain_timeout;
+        let drained_sessions = self.drained_sessions;
         // Setup side functionality, such as ctrl_c listener
         let fut = async {
             info!("{}", startup_msg.borrow());
@@ -633,64 +1481,166 @@ where
                     warn!("{msg}")
                 }
             }
+            let deadline = match (&drain, drain_timeout) {
+                (Some(drain), Some(drain_timeout)) => {
+                    info!("Draining connections for up to {drain_timeout:?}");
+                    let deadline = Instant::now() + drain_timeout;
+                    let sessions = join_all(
+                        drained_sessions
+                            .iter()
+                            .map(|sessions| sessions.shutdown(drain_timeout)),
+                    );
+                    if timeout_at(deadline, join(drain.drain(), sessions))
+                        .await
+                        .is_err()
+                    {
+                        warn!(
+                            "{} requests were still being handled when the drain timeout ended",
+                            drain.get_active()
+                        );
+                    }
+                    Some(deadline)
+                }
+                _ => None,
+            };
+            // Every server shuts down together
+            shutdown_sender.send_replace(true);
+            deadline
         };
 
+        let tls_acceptor = self
+            .https_identity
+            .map(SharedTlsAcceptor::new)
+            .transpose()
+            .context("Initializing https")?;
+        let renewal = match (&tls_acceptor, self.certificate_renewal) {
+            (Some(tls_acceptor), Some(renewal)) => Some(tokio::spawn(renew_certificate(
+                renewal,
+                tls_acceptor.clone(),
+            ))),
+            _ => None,
+        };
+        let tasks = self
+            .task_manager
+            .map(TaskManager::start)
+            .unwrap_or_default();
+
+        let mut servers: Vec<Pin<Box<dyn Future<Output = Result<()>>>>> = Vec::new();
+        let mut addrs = Vec::new();
+
         macro_rules! run {
             ($server:expr, $addr:expr) => {
-                *startup_msg.borrow_mut() = format!("Binded to {}", $addr);
-                $server
-                    .serve(router.into_make_service())
-                    .with_graceful_shutdown(fut)
-                    .await
-                    .context("Running the web server")?;
+                info!("Bound to {}", $addr);
+                addrs.push($addr.to_string());
+                let mut shutdown = shutdown.clone();
+                let server = $server
+                    .serve(
+                        router
+                            .clone()
+                            .into_make_service_with_connect_info::<PeerAddr>(),
+                    )
+                    .with_graceful_shutdown(async move {
+                        let _ = shutdown.changed().await;
+                    });
+                servers.push(Box::pin(async move {
+                    server.await.context("Running the web server")
+                }));
             };
         }
 
-        // Setup Server
-        match self.bind_address {
-            #[cfg(unix)]
-            BindAddress::Local(addr) => {
-                let listener = tokio::net::UnixListener::bind(&addr)
-                    .map_err(Into::<Error>::into)
-                    .context("Binding to local address")?;
-                let stream = tokio_stream::wrappers::UnixListenerStream::new(listener);
-                let acceptor = hyper::server::accept::from_stream(stream);
-                run!(Server::builder(acceptor), addr);
-            }
-            #[cfg(not(unix))]
-            BindAddress::Local(_) => {
-                return Err(Error::msg("Local Sockets are only supported on Unix"))
-            }
-            BindAddress::Network(addr) => {
-                if let Some(identity) = self.https_identity {
-                    if addr.port() != 443 {
-                        warn!("Serving HTTPS on a different port than 443")
+        // Setup Servers
+        for bind_address in once(self.bind_address).chain(self.extra_bind_addresses) {
+            match bind_address {
+                #[cfg(unix)]
+                BindAddress::Local(addr) => {
+                    let listener = tokio::net::UnixListener::bind(&addr)
+                        .map_err(Into::<Error>::into)
+                        .context("Binding to local address")?;
+                    let stream = tokio_stream::wrappers::UnixListenerStream::new(listener);
+                    let acceptor = hyper::server::accept::from_stream(stream);
+                    run!(Server::builder(acceptor), addr);
+                }
+                #[cfg(not(unix))]
+                BindAddress::Local(_) => {
+                    return Err(Error::msg("Local Sockets are only supported on Unix"))
+                }
+                BindAddress::Network(addr) => {
+                    if let Some(tls_acceptor) = &tls_acceptor {
+                        if addr.port() != 443 {
+                            warn!("Serving HTTPS on a different port than 443")
+                        }
+                        run!(
+                            Server::builder(TlsAcceptor::new(
+                                tls_acceptor.clone(),
+                                self.tcp_config.bind_incoming(&addr)?
+                            )),
+                            addr
+                        );
+                    } else {
+                        run!(Server::builder(self.tcp_config.bind_incoming(&addr)?), addr);
                     }
-                    run!(
-                        Server::builder(
-                            TlsAcceptor::new(identity, &addr).context("Initializing https")?
-                        ),
-                        addr
-                    );
-                } else {
-                    run!(Server::bind(&addr), addr);
                 }
-            }
-            BindAddress::HTTP(addr) => {
-                if let Some(identity) = self.https_identity {
-                    let addr = SocketAddr::new(addr, 443);
-                    run!(
-                        Server::builder(
-                            TlsAcceptor::new(identity, &addr).context("Initializing https")?
-                        ),
-                        addr
-                    );
-                } else {
-                    let addr = SocketAddr::new(addr, 80);
-                    run!(Server::bind(&addr), addr);
+                BindAddress::HTTP(addr) => {
+                    if let Some(tls_acceptor) = &tls_acceptor {
+                        let addr = SocketAddr::new(addr, 443);
+                        run!(
+                            Server::builder(TlsAcceptor::new(
+                                tls_acceptor.clone(),
+                                self.tcp_config.bind_incoming(&addr)?
+                            )),
+                            addr
+                        );
+                    } else {
+                        let addr = SocketAddr::new(addr, 80);
+                        run!(Server::builder(self.tcp_config.bind_incoming(&addr)?), addr);
+                    }
                 }
-            }
+                BindAddress::DualStack(port) => {
+                    if tls_acceptor.is_some() && port != 443 {
+                        warn!("Serving HTTPS on a different port than 443")
+                    }
+                    for (addr, incoming) in self
+                        .tcp_config
+                        .bind_dual_stack(port)
+                        .context(format!("Binding to dual stack port {port}"))?
+                    {
+                        if let Some(tls_acceptor) = &tls_acceptor {
+                            run!(
+                                Server::builder(TlsAcceptor::new(tls_acceptor.clone(), incoming)),
+                                addr
+                            );
+                        } else {
+                            run!(Server::builder(incoming), addr);
+                        }
+                    }
+                }
+            };
+        }
+
+        *startup_msg.borrow_mut() = format!("Binded to {}", addrs.join(", "));
+        let servers = try_join_all(servers);
+        tokio::pin!(servers);
+        let res = tokio::select! {
+            // A server only stops early if it failed
+            res = &mut servers => res,
+            deadline = fut => match deadline {
+                Some(deadline) => match timeout_at(deadline, &mut servers).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        warn!("Cutting the connections that were still open after draining");
+                        Ok(Vec::new())
+                    }
+                },
+                None => servers.await,
+            },
         };
+        if let Some(renewal) = renewal {
+            renewal.abort();
+        }
+        for task in tasks {
+            task.abort();
+        }
+        res?;
 
         Ok(())
     }
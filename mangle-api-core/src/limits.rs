@@ -0,0 +1,120 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::MatchedPath,
+    http::{header::CONTENT_LENGTH, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::warn;
+
+use crate::{
+    log_targets,
+    rate_limit::client_ip,
+    rejection::{Rejection, RejectionFormat},
+};
+
+fn describe_client<B>(request: &Request<B>) -> String {
+    client_ip(request)
+        .map(|ip| ip.to_string())
+        .unwrap_or("an unknown address".into())
+}
+
+/// A limit for every route, which single routes may replace
+pub(crate) struct RouteLimit<T> {
+    default: Option<T>,
+    /// Keyed by route
+    overrides: HashMap<&'static str, T>,
+}
+
+impl<T: Copy> RouteLimit<T> {
+    pub(crate) fn new(default: Option<T>) -> Self {
+        Self {
+            default,
+            overrides: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn set_override(&mut self, route: &'static str, limit: T) {
+        self.overrides.insert(route, limit);
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.default.is_some() || !self.overrides.is_empty()
+    }
+
+    fn get<B>(&self, request: &Request<B>) -> Option<T> {
+        request
+            .extensions()
+            .get::<MatchedPath>()
+            .and_then(|route| self.overrides.get(route.as_str()).copied())
+            .or(self.default)
+    }
+}
+
+/// Responds with 408 if the request is not handled within its timeout
+pub(crate) async fn enforce_timeout<B>(
+    timeouts: Arc<RouteLimit<Duration>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(timeout) = timeouts.get(&request) else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path().to_string();
+    let client = describe_client(&request);
+    let format = RejectionFormat::negotiate(request.headers());
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!(
+                target: log_targets::SECURITY,
+                "Request to {path} from {client} timed out after {}ms",
+                timeout.as_millis()
+            );
+            Rejection::new(
+                StatusCode::REQUEST_TIMEOUT,
+                "request_timeout",
+                "Request timed out",
+            )
+            .set_format(format)
+            .into_response()
+        }
+    }
+}
+
+/// Responds with 413 if the request declares a body larger than its max body size
+///
+/// Bodies without a length are capped by `DefaultBodyLimit` as they are read
+pub(crate) async fn enforce_body_size<B>(
+    max_body_sizes: Arc<RouteLimit<usize>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(max_body_size) = max_body_sizes.get(&request) else {
+        return next.run(request).await;
+    };
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok());
+    match length {
+        Some(length) if length > max_body_size as u64 => {
+            warn!(
+                target: log_targets::SECURITY,
+                "Request to {} from {} has a body of {length} bytes, over the limit of {max_body_size}",
+                request.uri().path(),
+                describe_client(&request)
+            );
+            Rejection::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "Request body is too large",
+            )
+            .negotiate(request.headers())
+            .into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
@@ -0,0 +1,87 @@
+use std::{collections::VecDeque, fmt::Display, str::FromStr, sync::OnceLock};
+
+use log::{Level, LevelFilter, Record};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+
+/// How many of the most recent log records are kept in memory
+const LOG_BUFFER_SIZE: usize = 1024;
+const LIVE_BUFFER_SIZE: usize = 256;
+
+static RECENT: Mutex<VecDeque<LogRecord>> = Mutex::new(VecDeque::new());
+static LIVE: OnceLock<Sender<LogRecord>> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogRecord {
+    pub level: String,
+    pub target: String,
+    /// The formatted log line
+    pub message: String,
+}
+
+impl Display for LogRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Which records to show when tailing logs
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogFilter {
+    /// Only shows records whose target starts with this
+    pub target: Option<String>,
+    /// The most verbose level to show, such as `info`
+    pub level: String,
+}
+
+impl LogFilter {
+    pub fn matches(&self, record: &LogRecord) -> bool {
+        let max_level = LevelFilter::from_str(&self.level).unwrap_or(LevelFilter::Trace);
+        let level = Level::from_str(&record.level).unwrap_or(Level::Trace);
+        level <= max_level
+            && self
+                .target
+                .as_ref()
+                .map(|target| record.target.starts_with(target.as_str()))
+                .unwrap_or(true)
+    }
+}
+
+fn live_sender() -> &'static Sender<LogRecord> {
+    LIVE.get_or_init(|| channel(LIVE_BUFFER_SIZE).0)
+}
+
+/// Records a formatted log record, used as a fern output
+pub(crate) fn push(record: &Record) {
+    let record = LogRecord {
+        level: record.level().to_string(),
+        target: record.target().to_string(),
+        message: record.args().to_string(),
+    };
+
+    {
+        let mut recent = RECENT.lock();
+        if recent.len() >= LOG_BUFFER_SIZE {
+            recent.pop_front();
+        }
+        recent.push_back(record.clone());
+    }
+
+    let _ = live_sender().send(record);
+}
+
+/// The most recent records that match the given filter, oldest first
+pub fn recent(filter: &LogFilter) -> Vec<LogRecord> {
+    RECENT
+        .lock()
+        .iter()
+        .filter(|record| filter.matches(record))
+        .cloned()
+        .collect()
+}
+
+/// Receives every record logged from now on
+pub fn subscribe() -> Receiver<LogRecord> {
+    live_sender().subscribe()
+}
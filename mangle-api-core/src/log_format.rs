@@ -0,0 +1,72 @@
+use std::fmt::Arguments;
+
+use fern::{Dispatch, FormatCallback};
+use log::Record;
+use serde::{Deserialize, Serialize};
+
+use crate::request_id::current_request_id;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `[date][time][LEVEL][target:line] message`, with `[request_id]` before
+    /// the message if logged while handling a request
+    #[default]
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `target`, `line`
+    /// and `message` fields, and `request_id` if logged while handling a request
+    Json,
+}
+
+/// The format of each log output
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug)]
+pub struct LogFormats {
+    /// The critical logs written to stderr
+    #[serde(default = "Default::default")]
+    pub stderr: LogFormat,
+    /// The stderr, routing and security log files
+    #[serde(default = "Default::default")]
+    pub file: LogFormat,
+}
+
+impl LogFormat {
+    /// A dispatch that formats every record in this format
+    pub(crate) fn dispatch(self) -> Dispatch {
+        match self {
+            Self::Text => Dispatch::new().format(format_text),
+            Self::Json => Dispatch::new().format(format_json),
+        }
+    }
+}
+
+fn format_text(out: FormatCallback, message: &Arguments, record: &Record) {
+    let request_id = current_request_id()
+        .map(|id| format!("[{}]", id.as_str()))
+        .unwrap_or_default();
+    out.finish(format_args!(
+        "{}[{}][{}:{}]{request_id} {}",
+        chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+        record.level(),
+        record.target(),
+        record
+            .line()
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or("?".into()),
+        message
+    ))
+}
+
+fn format_json(out: FormatCallback, message: &Arguments, record: &Record) {
+    let mut line = serde_json::json!({
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "line": record.line(),
+        "message": message.to_string(),
+    });
+    if let Some(id) = current_request_id() {
+        line["request_id"] = id.as_str().into();
+    }
+    out.finish(format_args!("{line}"))
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    fs::{read_to_string, write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{Context, Error, Result};
+use log::LevelFilter;
+use parking_lot::Mutex;
+
+/// The targets whose levels can be changed at runtime, other than `critical`,
+/// `stderr` and `routing`
+static EXTRA_TARGETS: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+/// The levels of extra targets that have been set. Unset targets follow `stderr`
+static OVERRIDES: Mutex<BTreeMap<String, LevelFilter>> = Mutex::new(BTreeMap::new());
+/// Where changed levels are saved, so that they are kept across restarts
+static STATE_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// The level of records that are also written to the console
+pub(crate) static CRITICAL_LOG_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
+pub(crate) static STDERR_LOG_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
+pub(crate) static ROUTING_LOG_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
+
+pub(crate) fn register_targets(targets: impl IntoIterator<Item = &'static str>) {
+    let mut extra = EXTRA_TARGETS.lock();
+    for target in targets {
+        if !extra.contains(&target) {
+            extra.push(target);
+        }
+    }
+}
+
+/// The level that records of the given target are logged to the stderr log at
+///
+/// The longest extra target that the given target starts with decides the level
+pub(crate) fn stderr_level_for(target: &str) -> LevelFilter {
+    let extra = EXTRA_TARGETS.lock();
+    let overrides = OVERRIDES.lock();
+    extra
+        .iter()
+        .filter(|extra| target.starts_with(**extra))
+        .max_by_key(|extra| extra.len())
+        .and_then(|extra| overrides.get(*extra))
+        .copied()
+        .unwrap_or_else(|| *STDERR_LOG_LEVEL.lock())
+}
+
+pub fn get_level(target: &str) -> Result<LevelFilter> {
+    match target {
+        "critical" => Ok(*CRITICAL_LOG_LEVEL.lock()),
+        "stderr" => Ok(*STDERR_LOG_LEVEL.lock()),
+        "routing" => Ok(*ROUTING_LOG_LEVEL.lock()),
+        _ => {
+            if !EXTRA_TARGETS.lock().contains(&target) {
+                return Err(Error::msg(format!("Unknown log target: {target}")));
+            }
+            Ok(OVERRIDES
+                .lock()
+                .get(target)
+                .copied()
+                .unwrap_or_else(|| *STDERR_LOG_LEVEL.lock()))
+        }
+    }
+}
+
+/// Sets the level of the given target, saving it to the state file if there is one
+pub fn set_level(target: &str, level: LevelFilter) -> Result<()> {
+    match target {
+        "critical" => *CRITICAL_LOG_LEVEL.lock() = level,
+        "stderr" => *STDERR_LOG_LEVEL.lock() = level,
+        "routing" => *ROUTING_LOG_LEVEL.lock() = level,
+        _ => {
+            if !EXTRA_TARGETS.lock().contains(&target) {
+                return Err(Error::msg(format!("Unknown log target: {target}")));
+            }
+            OVERRIDES.lock().insert(target.to_string(), level);
+        }
+    }
+    save()
+}
+
+/// Gets the level of the given target, or sets it first if `new_level` is given,
+/// as done by the `log_level` command
+pub fn get_or_set_level(target: &str, new_level: Option<&str>) -> Result<LevelFilter> {
+    if let Some(new_level) = new_level {
+        let level = LevelFilter::from_str(new_level)
+            .map_err(|_| Error::msg(format!("Invalid log level: {new_level}")))?;
+        set_level(target, level)?;
+    }
+    get_level(target)
+}
+
+/// Sets the levels of several targets, as given in a config, only setting
+/// them if every target and level is valid
+pub fn set_levels(levels: &BTreeMap<String, String>) -> Result<()> {
+    let levels = levels
+        .iter()
+        .map(|(target, level)| {
+            get_level(target)?;
+            let level = LevelFilter::from_str(level)
+                .map_err(|_| Error::msg(format!("Invalid log level: {level}")))?;
+            Ok((target, level))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (target, level) in levels {
+        set_level(target, level)?;
+    }
+    Ok(())
+}
+
+/// Every target whose level can be changed, with its current level
+pub fn get_levels() -> Vec<(String, LevelFilter)> {
+    let extra = EXTRA_TARGETS.lock().clone();
+    ["critical", "stderr", "routing"]
+        .into_iter()
+        .chain(extra)
+        .filter_map(|target| Some((target.to_string(), get_level(target).ok()?)))
+        .collect()
+}
+
+/// Keeps changed levels in the given file, applying the levels already in it
+pub fn set_state_file(path: impl Into<PathBuf>) -> Result<()> {
+    let path = path.into();
+    match read_to_string(&path) {
+        Ok(contents) => {
+            let levels: BTreeMap<String, String> = serde_json::from_str(&contents)
+                .context(format!("Parsing log levels in {path:?}"))?;
+            for (target, level) in levels {
+                let Ok(level) = LevelFilter::from_str(&level) else {
+                    continue;
+                };
+                match target.as_str() {
+                    "critical" => *CRITICAL_LOG_LEVEL.lock() = level,
+                    "stderr" => *STDERR_LOG_LEVEL.lock() = level,
+                    "routing" => *ROUTING_LOG_LEVEL.lock() = level,
+                    // Targets that are no longer registered are kept, but unused
+                    _ => {
+                        OVERRIDES.lock().insert(target, level);
+                    }
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(format!("Reading {path:?}")),
+    }
+    *STATE_FILE.lock() = Some(path);
+    Ok(())
+}
+
+fn save() -> Result<()> {
+    let Some(path) = STATE_FILE.lock().clone() else {
+        return Ok(());
+    };
+    let mut levels: BTreeMap<String, String> = OVERRIDES
+        .lock()
+        .iter()
+        .map(|(target, level)| (target.clone(), level.to_string()))
+        .collect();
+    levels.insert("critical".into(), CRITICAL_LOG_LEVEL.lock().to_string());
+    levels.insert("stderr".into(), STDERR_LOG_LEVEL.lock().to_string());
+    levels.insert("routing".into(), ROUTING_LOG_LEVEL.lock().to_string());
+    write(&path, serde_json::to_string_pretty(&levels)?).context(format!("Writing {path:?}"))
+}
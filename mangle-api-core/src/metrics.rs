@@ -0,0 +1,205 @@
+use std::{
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::MatchedPath,
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use dashmap::DashMap;
+
+/// The upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Requests that match no route share this label, so that scanners cannot
+/// create a series for every path they try
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+#[derive(Default)]
+struct Histogram {
+    /// Not cumulative, unlike the buckets that are rendered
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+type Gauge = Box<dyn Fn() -> f64 + Send + Sync>;
+
+/// Counts of requests and control commands, rendered in the Prometheus text format
+///
+/// Given to the API builder with `set_metrics`, which serves them and
+/// instruments every request
+#[derive(Default)]
+pub struct Metrics {
+    /// Keyed by method, route and status
+    responses: DashMap<(String, String, u16), u64>,
+    /// Keyed by method and route
+    latencies: DashMap<(String, String), Histogram>,
+    commands: DashMap<String, u64>,
+    gauges: Vec<(&'static str, &'static str, Gauge)>,
+}
+
+impl Metrics {
+    /// Adds a gauge that is read whenever the metrics are rendered, such as
+    /// the number of active WebSocket sessions
+    pub fn add_gauge(
+        mut self,
+        name: &'static str,
+        help: &'static str,
+        gauge: impl Fn() -> f64 + Send + Sync + 'static,
+    ) -> Self {
+        self.gauges.push((name, help, Box::new(gauge)));
+        self
+    }
+
+    /// Counts a command received over the control pipe
+    pub fn record_command(&self, command: &str) {
+        *self.commands.entry(command.to_string()).or_default() += 1;
+    }
+
+    fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        *self
+            .responses
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default() += 1;
+        self.latencies
+            .entry((method.to_string(), route.to_string()))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out += "# HELP http_requests_total HTTP requests by method, route and status\n";
+        out += "# TYPE http_requests_total counter\n";
+        let mut responses: Vec<_> = self
+            .responses
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        responses.sort_unstable();
+        for ((method, route, status), count) in responses {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{status}\"}} {count}",
+                escape(&method),
+                escape(&route)
+            );
+        }
+
+        out += "# HELP http_request_duration_seconds HTTP request latencies by method and route\n";
+        out += "# TYPE http_request_duration_seconds histogram\n";
+        let mut routes: Vec<_> = self.latencies.iter().map(|x| x.key().clone()).collect();
+        routes.sort_unstable();
+        for key in routes {
+            let Some(histogram) = self.latencies.get(&key) else {
+                continue;
+            };
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(&key.0), escape(&key.1));
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_sum{{{labels}}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_count{{{labels}}} {}",
+                histogram.count
+            );
+        }
+
+        out += "# HELP control_commands_total Commands received over the control pipe\n";
+        out += "# TYPE control_commands_total counter\n";
+        let mut commands: Vec<_> = self
+            .commands
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        commands.sort_unstable();
+        for (command, count) in commands {
+            let _ = writeln!(
+                out,
+                "control_commands_total{{command=\"{}\"}} {count}",
+                escape(&command)
+            );
+        }
+
+        for (name, help, gauge) in &self.gauges {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {}", gauge());
+        }
+        out
+    }
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Records the method, route, status and latency of every request
+pub(crate) async fn instrument(
+    metrics: Arc<Metrics>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let response = next.run(req).await;
+    metrics.record_request(&method, &route, response.status().as_u16(), start.elapsed());
+    response
+}
+
+/// Serves the rendered metrics
+pub(crate) fn metrics_route<S>(metrics: Arc<Metrics>) -> axum::routing::MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    axum::routing::get(move || async move {
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics.render(),
+        )
+            .into_response()
+    })
+}
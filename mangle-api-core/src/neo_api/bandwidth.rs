@@ -0,0 +1,315 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// Caps on the number of bytes sent and received within each window
+///
+/// The caps apply to each connection and to each user separately
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct BandwidthLimits {
+    pub window: Duration,
+    /// A warning is logged once this is exceeded
+    pub soft_limit: u64,
+    /// Frames are delayed until the window ends once this is exceeded
+    pub throttle_limit: u64,
+    /// The connection is closed once this is exceeded
+    pub hard_limit: u64,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// What should happen to a frame after it has been recorded
+///
+/// Ordered from least to most severe
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub(crate) enum BandwidthAction {
+    Allow,
+    Warn,
+    Throttle(Duration),
+    Disconnect,
+}
+
+struct Meter {
+    bytes_in: u64,
+    bytes_out: u64,
+    window_start: Instant,
+    window_bytes: u64,
+    warned: bool,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self {
+            bytes_in: 0,
+            bytes_out: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            warned: false,
+        }
+    }
+}
+
+impl Meter {
+    fn record(
+        &mut self,
+        bytes: u64,
+        direction: Direction,
+        limits: Option<&BandwidthLimits>,
+    ) -> BandwidthAction {
+        match direction {
+            Direction::Inbound => self.bytes_in += bytes,
+            Direction::Outbound => self.bytes_out += bytes,
+        }
+        let Some(limits) = limits else {
+            return BandwidthAction::Allow;
+        };
+
+        if self.window_start.elapsed() >= limits.window {
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+            self.warned = false;
+        }
+        self.window_bytes += bytes;
+
+        if self.window_bytes > limits.hard_limit {
+            BandwidthAction::Disconnect
+        } else if self.window_bytes > limits.throttle_limit {
+            BandwidthAction::Throttle(limits.window.saturating_sub(self.window_start.elapsed()))
+        } else if self.window_bytes > limits.soft_limit && !self.warned {
+            self.warned = true;
+            BandwidthAction::Warn
+        } else {
+            BandwidthAction::Allow
+        }
+    }
+}
+
+/// The user that a connection counts towards, along with the meter of that user
+type UserMeter = Option<(String, Arc<Mutex<Meter>>)>;
+
+#[derive(Default)]
+struct Talkers {
+    next_id: AtomicU64,
+    connections: DashMap<u64, (Option<String>, Arc<Mutex<Meter>>)>,
+    users: DashMap<String, Arc<Mutex<Meter>>>,
+}
+
+impl Talkers {
+    fn user_meter(&self, user: Option<String>) -> UserMeter {
+        user.map(|user| {
+            let meter = self.users.entry(user.clone()).or_default().clone();
+            (user, meter)
+        })
+    }
+
+    /// Forgets the user if the caller holds the last reference to its meter
+    /// besides the map
+    fn release_user(&self, user: &str) {
+        self.users
+            .remove_if(user, |_, meter| Arc::strong_count(meter) <= 2);
+    }
+}
+
+#[derive(Serialize)]
+pub struct ConnectionTalker {
+    pub connection_id: u64,
+    pub user: Option<String>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Serialize)]
+pub struct UserTalker {
+    pub user: String,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// The connections and users that have sent and received the most bytes
+#[derive(Serialize)]
+pub struct TopTalkers {
+    pub connections: Vec<ConnectionTalker>,
+    pub users: Vec<UserTalker>,
+}
+
+/// Tracks the bytes sent and received by every open connection, and by every
+/// user with an open connection
+#[derive(Default)]
+pub struct BandwidthRegistry {
+    limits: Option<BandwidthLimits>,
+    talkers: Arc<Talkers>,
+}
+
+impl BandwidthRegistry {
+    pub(crate) fn set_limits(&mut self, limits: BandwidthLimits) {
+        assert!(
+            limits.soft_limit <= limits.throttle_limit
+                && limits.throttle_limit <= limits.hard_limit,
+            "Bandwidth limits must be ordered soft, throttle, then hard"
+        );
+        self.limits = Some(limits);
+    }
+
+    pub fn get_limits(&self) -> Option<&BandwidthLimits> {
+        self.limits.as_ref()
+    }
+
+    /// Starts tracking a new connection, which stops being tracked once the
+    /// returned meter is dropped
+    pub(crate) fn connect(&self, user: Option<String>) -> ConnectionMeter {
+        let id = self.talkers.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Mutex::new(Meter::default()));
+        self.talkers
+            .connections
+            .insert(id, (user.clone(), connection.clone()));
+
+        ConnectionMeter {
+            id,
+            limits: self.limits,
+            connection,
+            user: Arc::new(Mutex::new(self.talkers.user_meter(user))),
+            talkers: self.talkers.clone(),
+        }
+    }
+
+    /// Whether the given user has an open connection
+    pub fn is_user_connected(&self, user: &str) -> bool {
+        self.talkers.users.contains_key(user)
+    }
+
+    pub fn top_talkers(&self, count: usize) -> TopTalkers {
+        let mut connections: Vec<_> = self
+            .talkers
+            .connections
+            .iter()
+            .map(|entry| {
+                let (user, meter) = entry.value();
+                let meter = meter.lock();
+                ConnectionTalker {
+                    connection_id: *entry.key(),
+                    user: user.clone(),
+                    bytes_in: meter.bytes_in,
+                    bytes_out: meter.bytes_out,
+                }
+            })
+            .collect();
+        connections.sort_unstable_by_key(|x| std::cmp::Reverse(x.bytes_in + x.bytes_out));
+        connections.truncate(count);
+
+        let mut users: Vec<_> = self
+            .talkers
+            .users
+            .iter()
+            .map(|entry| {
+                let meter = entry.value().lock();
+                UserTalker {
+                    user: entry.key().clone(),
+                    bytes_in: meter.bytes_in,
+                    bytes_out: meter.bytes_out,
+                }
+            })
+            .collect();
+        users.sort_unstable_by_key(|x| std::cmp::Reverse(x.bytes_in + x.bytes_out));
+        users.truncate(count);
+
+        TopTalkers { connections, users }
+    }
+}
+
+pub(crate) struct ConnectionMeter {
+    id: u64,
+    limits: Option<BandwidthLimits>,
+    connection: Arc<Mutex<Meter>>,
+    /// Shared with the `BandwidthUser` of the session, which may change it
+    user: Arc<Mutex<UserMeter>>,
+    talkers: Arc<Talkers>,
+}
+
+impl ConnectionMeter {
+    pub(crate) fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn get_user_handle(&self) -> BandwidthUser {
+        BandwidthUser {
+            connection_id: self.id,
+            user: self.user.clone(),
+            talkers: self.talkers.clone(),
+        }
+    }
+
+    pub(crate) fn record(&self, bytes: usize, direction: Direction) -> BandwidthAction {
+        let bytes = bytes as u64;
+        let limits = self.limits.as_ref();
+
+        let mut action = self.connection.lock().record(bytes, direction, limits);
+        if action == BandwidthAction::Warn {
+            warn!(target: "bandwidth", "Connection {} exceeded the soft bandwidth limit", self.id);
+        }
+
+        if let Some((user, meter)) = &*self.user.lock() {
+            let user_action = meter.lock().record(bytes, direction, limits);
+            if user_action == BandwidthAction::Warn {
+                warn!(target: "bandwidth", "User {user} exceeded the soft bandwidth limit");
+            }
+            action = action.max(user_action);
+        }
+
+        if action == BandwidthAction::Disconnect {
+            warn!(target: "bandwidth", "Disconnecting connection {} for exceeding the hard bandwidth limit", self.id);
+        }
+
+        action
+    }
+}
+
+impl Drop for ConnectionMeter {
+    fn drop(&mut self) {
+        self.talkers.connections.remove(&self.id);
+        // The map and this meter hold the only references if this was the
+        // last connection of the user
+        if let Some((user, _)) = &*self.user.lock() {
+            self.talkers.release_user(user);
+        }
+    }
+}
+
+/// Changes the user that the bandwidth of a connection counts towards, such
+/// as when the session logs in or out
+#[derive(Clone)]
+pub struct BandwidthUser {
+    connection_id: u64,
+    user: Arc<Mutex<UserMeter>>,
+    talkers: Arc<Talkers>,
+}
+
+impl BandwidthUser {
+    pub fn set(&self, user: Option<String>) {
+        let mut current = self.user.lock();
+        if current.as_ref().map(|(x, _)| x) == user.as_ref() {
+            return;
+        }
+        let new = self.talkers.user_meter(user.clone());
+        if let Some((old, _)) = &*current {
+            self.talkers.release_user(old);
+        }
+        *current = new;
+        drop(current);
+        if let Some(mut connection) = self.talkers.connections.get_mut(&self.connection_id) {
+            connection.0 = user;
+        }
+    }
+}
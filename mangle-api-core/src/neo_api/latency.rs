@@ -0,0 +1,165 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+const LOG_TARGET: &str = "slow_handlers";
+
+struct SessionContext {
+    session_id: u64,
+    /// The calls to dependencies made while handling the current message
+    dependencies: RefCell<Vec<DependencyTiming>>,
+}
+
+tokio::task_local! {
+    static SESSION: SessionContext;
+}
+
+/// Runs a session so that the dependencies its messages call are timed
+pub(crate) async fn scope<F: Future>(session_id: u64, fut: F) -> F::Output {
+    SESSION
+        .scope(
+            SessionContext {
+                session_id,
+                dependencies: Default::default(),
+            },
+            fut,
+        )
+        .await
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DependencyTiming {
+    pub name: String,
+    pub elapsed: Duration,
+}
+
+/// Records a call to a dependency, such as DynamoDB, which is reported if the
+/// message it was made for is handled slowly
+///
+/// Does nothing outside of a WebSocket session
+pub fn record_dependency(name: impl Into<String>, elapsed: Duration) {
+    let _ = SESSION.try_with(|session| {
+        session.dependencies.borrow_mut().push(DependencyTiming {
+            name: name.into(),
+            elapsed,
+        })
+    });
+}
+
+/// Awaits `fut`, recording how long it took with `record_dependency`
+pub async fn time_dependency<F: Future>(name: impl Into<String>, fut: F) -> F::Output {
+    let start = Instant::now();
+    let output = fut.await;
+    record_dependency(name, start.elapsed());
+    output
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct LatencyStats {
+    pub count: u64,
+    /// Messages that took at least the slow threshold
+    pub slow: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+/// Logged under the `slow_handlers` target as JSON
+#[derive(Serialize, Clone, Debug)]
+pub struct SlowHandlerEvent {
+    /// The same ID that the connection has in the bandwidth registry
+    pub session_id: Option<u64>,
+    pub message_type: &'static str,
+    pub elapsed: Duration,
+    pub dependencies: Vec<DependencyTiming>,
+}
+
+/// The latency of handling each type of message, logging those that are slow
+pub struct MessageLatencies {
+    slow_threshold: Duration,
+    stats: DashMap<&'static str, LatencyStats>,
+}
+
+impl MessageLatencies {
+    pub fn new(slow_threshold: Duration) -> Self {
+        Self {
+            slow_threshold,
+            stats: DashMap::new(),
+        }
+    }
+
+    /// Times the handling of a message until the returned timer is dropped
+    pub fn start(&self, message_type: &'static str) -> HandlerTimer<'_> {
+        // Anything recorded before this message belongs to no message
+        let _ = SESSION.try_with(|session| session.dependencies.borrow_mut().clear());
+        HandlerTimer {
+            latencies: self,
+            message_type,
+            start: Instant::now(),
+        }
+    }
+
+    /// The stats of every type of message, sorted by type
+    pub fn get_stats(&self) -> Vec<(&'static str, LatencyStats)> {
+        let mut stats: Vec<_> = self
+            .stats
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        stats.sort_unstable_by_key(|x| x.0);
+        stats
+    }
+
+    fn finish(&self, message_type: &'static str, elapsed: Duration) {
+        let slow = elapsed >= self.slow_threshold;
+        {
+            let mut stats = self.stats.entry(message_type).or_default();
+            stats.count += 1;
+            stats.total += elapsed;
+            stats.max = stats.max.max(elapsed);
+            if slow {
+                stats.slow += 1;
+            }
+        }
+
+        let (session_id, dependencies) = SESSION
+            .try_with(|session| (Some(session.session_id), session.dependencies.take()))
+            .unwrap_or_default();
+        if !slow {
+            return;
+        }
+        let event = SlowHandlerEvent {
+            session_id,
+            message_type,
+            elapsed,
+            dependencies,
+        };
+        match serde_json::to_string(&event) {
+            Ok(json) => warn!(target: LOG_TARGET, "{json}"),
+            Err(_) => warn!(
+                target: LOG_TARGET,
+                "{message_type} took {}ms",
+                elapsed.as_millis()
+            ),
+        }
+    }
+}
+
+/// Records the latency of a message when dropped
+pub struct HandlerTimer<'a> {
+    latencies: &'a MessageLatencies,
+    message_type: &'static str,
+    start: Instant,
+}
+
+impl<'a> Drop for HandlerTimer<'a> {
+    fn drop(&mut self) {
+        self.latencies
+            .finish(self.message_type, self.start.elapsed());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    any::type_name,
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use axum::async_trait;
+use log::{debug, log_enabled, Level};
+use messagist::{AliasableMessageHandler, MessageStream};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::redact::redact_json;
+
+const LOG_TARGET: &str = "ws_messages";
+
+/// Wraps a handler in behaviour that many handlers share, such as logging or rate limiting
+///
+/// Layers usually wrap the stream that is given to the inner handler, so that
+/// they can see every message that it sends and receives
+pub trait HandlerLayer<H: AliasableMessageHandler> {
+    type Handler: AliasableMessageHandler<SessionState = H::SessionState>;
+
+    fn layer(self, inner: H) -> Self::Handler;
+}
+
+/// Logs every message sent and received under the `ws_messages` target
+///
+/// Credentials in sent messages, such as tokens, are redacted
+pub struct LoggingLayer;
+
+pub struct Logged<H>(H);
+
+impl<H: AliasableMessageHandler + Send + Sync> HandlerLayer<H> for LoggingLayer {
+    type Handler = Logged<H>;
+
+    fn layer(self, inner: H) -> Self::Handler {
+        Logged(inner)
+    }
+}
+
+#[async_trait]
+impl<H: AliasableMessageHandler + Send + Sync> AliasableMessageHandler for Logged<H> {
+    type SessionState = H::SessionState;
+
+    async fn handle<S: MessageStream>(&self, stream: S, session_state: Self::SessionState) {
+        self.0.handle(LoggedStream(stream), session_state).await
+    }
+}
+
+pub struct LoggedStream<S>(S);
+
+#[async_trait]
+impl<S: MessageStream> MessageStream for LoggedStream<S> {
+    type Error = S::Error;
+
+    async fn recv_message<T>(&mut self) -> Result<T, Self::Error>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let result = self.0.recv_message().await;
+        match &result {
+            Ok(_) => debug!(target: LOG_TARGET, "Received {}", type_name::<T>()),
+            Err(e) => debug!(target: LOG_TARGET, "Could not receive {}: {e}", type_name::<T>()),
+        }
+        result
+    }
+
+    async fn send_message<T: Serialize + Send + Sync>(
+        &mut self,
+        msg: T,
+    ) -> Result<(), Self::Error> {
+        if log_enabled!(target: LOG_TARGET, Level::Debug) {
+            match serde_json::to_value(&msg) {
+                Ok(mut json) => {
+                    redact_json(&mut json);
+                    debug!(target: LOG_TARGET, "Sending {}: {json}", type_name::<T>())
+                }
+                Err(_) => debug!(target: LOG_TARGET, "Sending {}", type_name::<T>()),
+            }
+        }
+        self.0.send_message(msg).await
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        self.0.wait_for_error().await
+    }
+}
+
+/// Lets each connection receive at most `max_messages` every `period`
+///
+/// Messages past the limit are not dropped. Instead, nothing more is read from
+/// the connection until the period is over, which slows down the client
+pub struct RateLimitLayer {
+    max_messages: usize,
+    period: Duration,
+    notify: bool,
+}
+
+impl RateLimitLayer {
+    pub fn new(max_messages: usize, period: Duration) -> Result<Self, Error> {
+        if max_messages == 0 {
+            return Err(Error::msg("At least one message must be allowed"));
+        }
+        Ok(Self {
+            max_messages,
+            period,
+            notify: false,
+        })
+    }
+
+    /// Sends a `SessionNotice` to the client whenever it is throttled, which
+    /// the client must be able to receive at any time
+    pub fn set_notify(mut self, notify: bool) -> Self {
+        self.notify = notify;
+        self
+    }
+}
+
+/// Sent to clients outside of the protocol of the handler
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SessionNotice {
+    /// Nothing more is read from the client until `retry_after_ms` has passed
+    Throttled {
+        max_messages: usize,
+        period_ms: u64,
+        retry_after_ms: u64,
+    },
+}
+
+pub struct RateLimited<H> {
+    inner: H,
+    max_messages: usize,
+    period: Duration,
+    notify: bool,
+}
+
+impl<H> RateLimited<H> {
+    pub fn get_inner(&self) -> &H {
+        &self.inner
+    }
+}
+
+impl<H: AliasableMessageHandler + Send + Sync> HandlerLayer<H> for RateLimitLayer {
+    type Handler = RateLimited<H>;
+
+    fn layer(self, inner: H) -> Self::Handler {
+        RateLimited {
+            inner,
+            max_messages: self.max_messages,
+            period: self.period,
+            notify: self.notify,
+        }
+    }
+}
+
+#[async_trait]
+impl<H: AliasableMessageHandler + Send + Sync> AliasableMessageHandler for RateLimited<H> {
+    type SessionState = H::SessionState;
+
+    async fn handle<S: MessageStream>(&self, stream: S, session_state: Self::SessionState) {
+        let stream = RateLimitedStream {
+            stream,
+            max_messages: self.max_messages,
+            period: self.period,
+            notify: self.notify,
+            notified: false,
+            period_start: Instant::now(),
+            received: 0,
+        };
+        self.inner.handle(stream, session_state).await
+    }
+}
+
+pub struct RateLimitedStream<S> {
+    stream: S,
+    max_messages: usize,
+    period: Duration,
+    notify: bool,
+    /// Whether the client was told of the current throttle, so that it is
+    /// only told once if receiving is cancelled and restarted
+    notified: bool,
+    period_start: Instant,
+    received: usize,
+}
+
+#[async_trait]
+impl<S: MessageStream> MessageStream for RateLimitedStream<S> {
+    type Error = S::Error;
+
+    async fn recv_message<T>(&mut self) -> Result<T, Self::Error>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        if self.received >= self.max_messages {
+            let elapsed = self.period_start.elapsed();
+            if elapsed < self.period {
+                let retry_after = self.period - elapsed;
+                if self.notify && !self.notified {
+                    self.notified = true;
+                    self.stream
+                        .send_message(SessionNotice::Throttled {
+                            max_messages: self.max_messages,
+                            period_ms: self.period.as_millis() as u64,
+                            retry_after_ms: retry_after.as_millis() as u64,
+                        })
+                        .await?;
+                }
+                sleep(retry_after).await;
+            }
+            self.period_start = Instant::now();
+            self.received = 0;
+            self.notified = false;
+        }
+        // Only counted once received, so that cancelled receives are not counted
+        let msg = self.stream.recv_message().await;
+        self.received += 1;
+        msg
+    }
+
+    async fn send_message<T: Serialize + Send + Sync>(
+        &mut self,
+        msg: T,
+    ) -> Result<(), Self::Error> {
+        self.stream.send_message(msg).await
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        self.stream.wait_for_error().await
+    }
+}
+
+/// Closes connections whose session state does not pass the check, such as
+/// connections without a valid token, before the inner handler sees them
+pub struct GuardLayer<F>(F);
+
+impl<F> GuardLayer<F> {
+    pub fn new(check: F) -> Self {
+        Self(check)
+    }
+}
+
+pub struct Guarded<H, F> {
+    inner: H,
+    check: F,
+}
+
+impl<H, F> HandlerLayer<H> for GuardLayer<F>
+where
+    H: AliasableMessageHandler + Send + Sync,
+    F: Fn(&H::SessionState) -> bool + Send + Sync,
+{
+    type Handler = Guarded<H, F>;
+
+    fn layer(self, inner: H) -> Self::Handler {
+        Guarded {
+            inner,
+            check: self.0,
+        }
+    }
+}
+
+#[async_trait]
+impl<H, F> AliasableMessageHandler for Guarded<H, F>
+where
+    H: AliasableMessageHandler + Send + Sync,
+    F: Fn(&H::SessionState) -> bool + Send + Sync,
+{
+    type SessionState = H::SessionState;
+
+    async fn handle<S: MessageStream>(&self, stream: S, session_state: Self::SessionState) {
+        if !(self.check)(&session_state) {
+            debug!(target: LOG_TARGET, "Guard rejected connection");
+            return;
+        }
+        self.inner.handle(stream, session_state).await
+    }
+}
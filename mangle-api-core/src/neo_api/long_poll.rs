@@ -0,0 +1,441 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    extract::{FromRequest, Query, State},
+    http::{header::CONTENT_TYPE, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+    Json,
+};
+use dashmap::DashMap;
+use log::warn;
+use messagist::{text::JsonMessageStream, AliasableMessageHandler, TextStream};
+use parking_lot::Mutex;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{mpsc, Notify},
+    time::{sleep, Instant},
+};
+
+use super::{
+    bandwidth::{BandwidthAction, ConnectionMeter, Direction},
+    latency,
+    metrics::SessionMetrics,
+    NeoApiConfig,
+};
+use crate::{crash::supervise, rejection::Rejection, shutdown::ShutdownListener};
+
+const SESSION_TOKEN_SIZE: usize = 32;
+/// How long a poll waits for messages before it is answered with 204
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+/// Sessions that are not polled for this long are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Messages the client has sent that the handler has not received yet
+const INBOUND_BUFFER_SIZE: usize = 64;
+/// Messages the server has sent that the client has not acknowledged yet
+const MAX_BACKLOG: usize = 256;
+
+#[derive(thiserror::Error, Debug)]
+pub enum LongPollError {
+    /// The client closed the session, or stopped polling it
+    #[error("Closed")]
+    Closed,
+    /// The client has not acknowledged too many messages
+    #[error("Backlogged")]
+    Backlogged,
+    #[error("BandwidthExceeded")]
+    BandwidthExceeded,
+    /// The server is stopping, so no more messages will be received
+    #[error("ShuttingDown")]
+    ShuttingDown,
+}
+
+struct Outbox {
+    /// The sequence number of the first message in `messages`
+    first_seq: u64,
+    messages: VecDeque<String>,
+}
+
+struct PollSession {
+    inbound: mpsc::Sender<String>,
+    /// The sequence number of the next message the client sends
+    next_inbound: Mutex<u64>,
+    outbox: Mutex<Outbox>,
+    outbox_notify: Notify,
+    last_polled: Mutex<Instant>,
+    closed: AtomicBool,
+    /// Shared by polls and sends, so that both count towards the bandwidth limits
+    meter: ConnectionMeter,
+}
+
+impl PollSession {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.outbox_notify.notify_waiters();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// The sessions of clients that use long polling instead of a WebSocket
+#[derive(Default)]
+pub(crate) struct LongPollSessions {
+    sessions: DashMap<String, Arc<PollSession>>,
+}
+
+/// The stream given to the handler of a long polling session, which behaves
+/// like a WebSocket that only carries text
+pub struct PollStream {
+    session: Arc<PollSession>,
+    inbound: mpsc::Receiver<String>,
+    metrics: Arc<SessionMetrics>,
+    /// Counts the session as active until it closes, so that shutdowns wait for it
+    shutdown: ShutdownListener,
+}
+
+#[async_trait]
+impl TextStream for PollStream {
+    type Error = LongPollError;
+
+    async fn recv_string(&mut self) -> Result<String, Self::Error> {
+        loop {
+            let idle_deadline = *self.session.last_polled.lock() + IDLE_TIMEOUT;
+            select! {
+                msg = self.inbound.recv() => break msg.ok_or(LongPollError::Closed),
+                () = self.shutdown.wait() => break Err(LongPollError::ShuttingDown),
+                () = tokio::time::sleep_until(idle_deadline) => {
+                    // Polls may have come in while sleeping
+                    if self.session.last_polled.lock().elapsed() >= IDLE_TIMEOUT {
+                        break Err(LongPollError::Closed);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send_string(&mut self, msg: String) -> Result<(), Self::Error> {
+        if self.session.is_closed() {
+            return Err(LongPollError::Closed);
+        }
+        match self.session.meter.record(msg.len(), Direction::Outbound) {
+            BandwidthAction::Allow | BandwidthAction::Warn => {}
+            BandwidthAction::Throttle(delay) => sleep(delay).await,
+            BandwidthAction::Disconnect => {
+                self.session.close();
+                return Err(LongPollError::BandwidthExceeded);
+            }
+        }
+        let mut outbox = self.session.outbox.lock();
+        if outbox.messages.len() >= MAX_BACKLOG {
+            return Err(LongPollError::Backlogged);
+        }
+        outbox.messages.push_back(msg);
+        drop(outbox);
+        self.metrics.record_message(Direction::Outbound);
+        self.session.outbox_notify.notify_waiters();
+        Ok(())
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        loop {
+            if let Err(e) = self.recv_string().await {
+                break e;
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PollQuery {
+    session: Option<String>,
+    /// For polls, how many messages the client has received. For sends, the
+    /// sequence number of the first message in the body
+    #[serde(default)]
+    seq: u64,
+}
+
+#[derive(Serialize)]
+struct Opened<'a> {
+    session: &'a str,
+}
+
+fn unknown_session() -> Response {
+    Rejection::new(
+        StatusCode::NOT_FOUND,
+        "unknown_session",
+        "The session does not exist or has closed",
+    )
+    .into_response()
+}
+
+/// Opens a session, or waits for the messages after `seq` if a session is given
+///
+/// Messages are answered as a JSON object with the sequence number of the
+/// first message and an array of the messages, or 204 if there were none in time
+async fn poll<S, H, R>(
+    State(state): State<S>,
+    Query(query): Query<PollQuery>,
+    request: Request<Body>,
+) -> Response
+where
+    S: Send + Sync + Clone + 'static,
+    H: AliasableMessageHandler<SessionState = R> + Send + Sync + 'static,
+    S: AsRef<NeoApiConfig<H>>,
+    R: FromRequest<S, Body> + Send + Sync + 'static,
+{
+    let Some(token) = query.session else {
+        return open::<S, H, R>(state, request).await;
+    };
+    let Some(session) = state
+        .as_ref()
+        .long_poll
+        .sessions
+        .get(&token)
+        .map(|x| x.clone())
+    else {
+        return unknown_session();
+    };
+    *session.last_polled.lock() = Instant::now();
+
+    let deadline = sleep(POLL_TIMEOUT);
+    tokio::pin!(deadline);
+    loop {
+        // Registered before checking, so that no message is missed in between
+        let notified = session.outbox_notify.notified();
+        {
+            let mut outbox = session.outbox.lock();
+            // Messages before `seq` were received, so they are not sent again
+            while outbox.first_seq < query.seq && outbox.messages.pop_front().is_some() {
+                outbox.first_seq += 1;
+            }
+            if !outbox.messages.is_empty() {
+                let body = format!(
+                    "{{\"seq\":{},\"messages\":[{}]}}",
+                    outbox.first_seq,
+                    outbox
+                        .messages
+                        .iter()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(",")
+                );
+                return ([(CONTENT_TYPE, "application/json")], body).into_response();
+            }
+        }
+        if session.is_closed() {
+            return unknown_session();
+        }
+        select! {
+            () = notified => {}
+            () = &mut deadline => return StatusCode::NO_CONTENT.into_response(),
+        }
+    }
+}
+
+async fn open<S, H, R>(state: S, request: Request<Body>) -> Response
+where
+    S: Send + Sync + Clone + 'static,
+    H: AliasableMessageHandler<SessionState = R> + Send + Sync + 'static,
+    S: AsRef<NeoApiConfig<H>>,
+    R: FromRequest<S, Body> + Send + Sync + 'static,
+{
+    if state.as_ref().shutdown.is_shutting_down() {
+        return Rejection::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "shutting_down",
+            "The server is shutting down",
+        )
+        .into_response();
+    }
+    let mut session_state = match R::from_request(request, &state).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response(),
+    };
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_TOKEN_SIZE)
+        .map(char::from)
+        .collect();
+    let config = state.as_ref();
+    // Metered like a WebSocket, so that the session counts as connected
+    let user = config
+        .bandwidth_user
+        .as_ref()
+        .and_then(|(bandwidth_user, _)| bandwidth_user(&session_state));
+    let meter = config.bandwidth.connect(user);
+    if let Some((_, attach)) = &config.bandwidth_user {
+        attach(&mut session_state, meter.get_user_handle());
+    }
+    let shutdown = config.shutdown.listen();
+    let (sender, receiver) = mpsc::channel(INBOUND_BUFFER_SIZE);
+    let session = Arc::new(PollSession {
+        inbound: sender,
+        next_inbound: Mutex::new(0),
+        outbox: Mutex::new(Outbox {
+            first_seq: 0,
+            messages: VecDeque::new(),
+        }),
+        outbox_notify: Notify::new(),
+        last_polled: Mutex::new(Instant::now()),
+        closed: AtomicBool::new(false),
+        meter,
+    });
+    config
+        .long_poll
+        .sessions
+        .insert(token.clone(), session.clone());
+
+    let response = Json(Opened { session: &token }).into_response();
+    tokio::spawn(async move {
+        let config = state.as_ref();
+        let _open_session = config.metrics.open();
+        let stream = PollStream {
+            session: session.clone(),
+            inbound: receiver,
+            metrics: config.metrics.clone(),
+            shutdown,
+        };
+        if supervise(latency::scope(
+            session.meter.get_id(),
+            config
+                .handler
+                .handle(JsonMessageStream::from(stream), session_state),
+        ))
+        .await
+        .is_err()
+        {
+            warn!("Long polling session panicked");
+        }
+        session.close();
+        config.long_poll.sessions.remove(&token);
+    });
+    response
+}
+
+/// Gives the handler the messages in the body, which is a JSON array
+///
+/// Messages the session already received are skipped, so sends can be retried
+async fn send<S, H>(State(state): State<S>, Query(query): Query<PollQuery>, body: Bytes) -> Response
+where
+    S: Send + Sync + Clone + 'static,
+    H: AliasableMessageHandler + Send + Sync + 'static,
+    S: AsRef<NeoApiConfig<H>>,
+{
+    let config = state.as_ref();
+    let Some((token, session)) = query.session.and_then(|token| {
+        let session = config.long_poll.sessions.get(&token).map(|x| x.clone())?;
+        Some((token, session))
+    }) else {
+        return unknown_session();
+    };
+    let messages: Vec<serde_json::Value> = match serde_json::from_slice(&body) {
+        Ok(x) => x,
+        Err(e) => {
+            return Rejection::new(StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+                .into_response()
+        }
+    };
+
+    match session.meter.record(body.len(), Direction::Inbound) {
+        BandwidthAction::Allow | BandwidthAction::Warn => {}
+        // The client resends the messages once the window ends
+        BandwidthAction::Throttle(delay) => {
+            return Rejection::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "throttled",
+                format!("Bandwidth is throttled for {}s", delay.as_secs() + 1),
+            )
+            .into_response()
+        }
+        BandwidthAction::Disconnect => {
+            config.long_poll.sessions.remove(&token);
+            session.close();
+            return Rejection::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "bandwidth_exceeded",
+                "The session was closed for exceeding the bandwidth limit",
+            )
+            .into_response();
+        }
+    }
+
+    let mut next_inbound = session.next_inbound.lock();
+    if query.seq > *next_inbound {
+        return Rejection::new(
+            StatusCode::CONFLICT,
+            "out_of_order",
+            format!("Expected the message numbered {}", *next_inbound),
+        )
+        .into_response();
+    }
+    let skip = (*next_inbound - query.seq) as usize;
+    for msg in messages.into_iter().skip(skip) {
+        match session.inbound.try_send(msg.to_string()) {
+            Ok(()) => {
+                *next_inbound += 1;
+                config.metrics.record_message(Direction::Inbound);
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                // The client resends the rest, starting from `next_inbound`
+                return Rejection::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "backlogged",
+                    format!("Only accepted messages before {}", *next_inbound),
+                )
+                .into_response();
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return unknown_session(),
+        }
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Closes the session
+async fn close<S, H>(State(state): State<S>, Query(query): Query<PollQuery>) -> StatusCode
+where
+    S: Send + Sync + Clone + 'static,
+    H: AliasableMessageHandler + Send + Sync + 'static,
+    S: AsRef<NeoApiConfig<H>>,
+{
+    if let Some((_, session)) = query
+        .session
+        .and_then(|token| state.as_ref().long_poll.sessions.remove(&token))
+    {
+        session.close();
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// A fallback for clients that cannot keep a WebSocket open, such as those
+/// behind proxies that close idle connections
+///
+/// Sessions only use JSON, and are driven by the same handler as `ws_api_route`:
+/// - `GET` without a session opens one, responding with its token as `session`
+/// - `GET ?session=..&seq=n` waits for the messages after the first `n`
+/// - `POST ?session=..&seq=n` sends the JSON array in the body, where the
+///   first message is numbered `n`
+/// - `DELETE ?session=..` closes the session
+pub fn long_poll_route<S, H, R>() -> MethodRouter<S>
+where
+    S: Send + Sync + Clone + 'static,
+    H: AliasableMessageHandler<SessionState = R> + Send + Sync + 'static,
+    S: AsRef<NeoApiConfig<H>>,
+    R: FromRequest<S, Body> + Send + Sync + 'static,
+{
+    axum::routing::get(poll::<S, H, R>)
+        .post(send::<S, H>)
+        .delete(close::<S, H>)
+}
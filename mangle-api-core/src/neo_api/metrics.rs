@@ -0,0 +1,72 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::bandwidth::Direction;
+
+/// Aggregate counts of the WebSocket sessions of this node, without any
+/// information about individual users
+#[derive(Default)]
+pub struct SessionMetrics {
+    active: AtomicU64,
+    /// The most sessions open at once since the last snapshot
+    peak: AtomicU64,
+    opened: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+}
+
+/// The counts of sessions and messages since the previous snapshot
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct SessionMetricsSnapshot {
+    /// The sessions open when the snapshot was taken
+    pub active: u64,
+    pub peak_active: u64,
+    pub opened: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+}
+
+impl SessionMetrics {
+    /// Counts a session as open until the returned guard is dropped
+    pub(crate) fn open(self: &Arc<Self>) -> OpenSession {
+        let active = self.active.fetch_add(1, Ordering::AcqRel) + 1;
+        self.peak.fetch_max(active, Ordering::AcqRel);
+        self.opened.fetch_add(1, Ordering::Relaxed);
+        OpenSession(self.clone())
+    }
+
+    pub(crate) fn record_message(&self, direction: Direction) {
+        match direction {
+            Direction::Inbound => self.messages_in.fetch_add(1, Ordering::Relaxed),
+            Direction::Outbound => self.messages_out.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn get_active(&self) -> u64 {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Takes the counts since the previous snapshot, starting new counts
+    pub fn take_snapshot(&self) -> SessionMetricsSnapshot {
+        let active = self.get_active();
+        SessionMetricsSnapshot {
+            active,
+            peak_active: self.peak.swap(active, Ordering::AcqRel).max(active),
+            opened: self.opened.swap(0, Ordering::Relaxed),
+            messages_in: self.messages_in.swap(0, Ordering::Relaxed),
+            messages_out: self.messages_out.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct OpenSession(Arc<SessionMetrics>);
+
+impl Drop for OpenSession {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
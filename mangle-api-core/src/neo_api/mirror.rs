@@ -0,0 +1,386 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Error;
+use axum::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::{info, warn};
+use messagist::{
+    bin::BincodeMessageStream,
+    msgpack::MsgPackMessageStream,
+    text::{JsonMessageStream, TextStream},
+    AliasableMessageHandler, BytesStream,
+};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+use super::MessageFormat;
+use crate::ws::{ManagedWebSocket, WsError};
+
+/// How many frames the shadow and the differ may fall behind the primary,
+/// after which the session stops being mirrored
+const MIRROR_BUFFER_SIZE: usize = 256;
+
+/// A single frame sent or received over a WebSocket
+#[derive(PartialEq, Eq, Debug)]
+enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    /// Describes the frame without its contents, which may hold tokens
+    fn redacted(&self) -> String {
+        let (kind, bytes) = match self {
+            Frame::Text(x) => ("text", x.as_bytes()),
+            Frame::Binary(x) => ("binary", x.as_slice()),
+        };
+        format!(
+            "{kind} of {} bytes hashing to {}",
+            bytes.len(),
+            URL_SAFE_NO_PAD.encode(&Sha256::digest(bytes)[..8])
+        )
+    }
+}
+
+fn redacted(frame: &Option<Frame>) -> String {
+    frame
+        .as_ref()
+        .map(Frame::redacted)
+        .unwrap_or("nothing".into())
+}
+
+/// Runs the given handler with the stream wrapped in the given format
+pub(super) async fn handle_with_format<H, W>(
+    handler: &H,
+    ws: W,
+    format: MessageFormat,
+    session_state: H::SessionState,
+) where
+    H: AliasableMessageHandler + Sync,
+    W: TextStream<Error: Sync> + BytesStream<Error: Sync> + Send + Sync,
+{
+    match format {
+        MessageFormat::Json => {
+            handler
+                .handle(JsonMessageStream::from(ws), session_state)
+                .await
+        }
+        MessageFormat::MessagePack => {
+            handler
+                .handle(MsgPackMessageStream::from(ws), session_state)
+                .await
+        }
+        MessageFormat::Bincode => {
+            handler
+                .handle(BincodeMessageStream::from(ws), session_state)
+                .await
+        }
+    }
+}
+
+/// A handler that receives a copy of the messages sent to the primary handler
+pub(super) trait ShadowHandler<R>: Send + Sync {
+    fn handle<'a>(
+        &'a self,
+        stream: ShadowStream,
+        format: MessageFormat,
+        session_state: &R,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+}
+
+struct ShadowHandlerImpl<M, F> {
+    handler: M,
+    make_state: F,
+}
+
+impl<R, M, F> ShadowHandler<R> for ShadowHandlerImpl<M, F>
+where
+    M: AliasableMessageHandler<SessionState: 'static> + Send + Sync,
+    F: Fn(&R) -> M::SessionState + Send + Sync,
+{
+    fn handle<'a>(
+        &'a self,
+        stream: ShadowStream,
+        format: MessageFormat,
+        session_state: &R,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        let session_state = (self.make_state)(session_state);
+        Box::pin(handle_with_format(
+            &self.handler,
+            stream,
+            format,
+            session_state,
+        ))
+    }
+}
+
+pub(super) struct Mirror<R> {
+    handler: Box<dyn ShadowHandler<R>>,
+    percentage: f32,
+}
+
+impl<R> Mirror<R> {
+    pub(super) fn new<M, F>(handler: M, percentage: f32, make_state: F) -> Result<Self, Error>
+    where
+        M: AliasableMessageHandler<SessionState: 'static> + Send + Sync + 'static,
+        F: Fn(&R) -> M::SessionState + Send + Sync + 'static,
+    {
+        // NaN is not contained in any range either
+        if !(0.0..=100.0).contains(&percentage) {
+            return Err(Error::msg(format!(
+                "Mirror percentage must be between 0 and 100, not {percentage}"
+            )));
+        }
+        Ok(Self {
+            handler: Box::new(ShadowHandlerImpl {
+                handler,
+                make_state,
+            }),
+            percentage,
+        })
+    }
+
+    /// Decides whether a new connection should be mirrored
+    pub(super) fn should_mirror(&self) -> bool {
+        thread_rng().gen_range(0.0..100.0) < self.percentage
+    }
+
+    /// Runs the primary and shadow handlers side by side until both finish,
+    /// logging every response of the shadow that differs from the primary's
+    pub(super) async fn run<H>(
+        &self,
+        primary: &H,
+        ws: ManagedWebSocket,
+        format: MessageFormat,
+        session_state: H::SessionState,
+    ) where
+        H: AliasableMessageHandler + Sync,
+    {
+        let (inbound_sender, inbound) = channel(MIRROR_BUFFER_SIZE);
+        let (primary_sender, primary_outputs) = channel(MIRROR_BUFFER_SIZE);
+        let (shadow_sender, shadow_outputs) = channel(MIRROR_BUFFER_SIZE);
+        let abandoned = Arc::new(AtomicBool::new(false));
+
+        let shadow = self.handler.handle(
+            ShadowStream {
+                inbound,
+                outputs: shadow_sender,
+                abandoned: abandoned.clone(),
+            },
+            format,
+            &session_state,
+        );
+        let primary = handle_with_format(
+            primary,
+            MirroredWebSocket {
+                ws,
+                inbound: Some(inbound_sender),
+                outputs: Some(primary_sender),
+                abandoned: abandoned.clone(),
+            },
+            format,
+            session_state,
+        );
+
+        tokio::join!(
+            primary,
+            shadow,
+            diff_outputs(primary_outputs, shadow_outputs, &abandoned)
+        );
+    }
+}
+
+/// Compares the outputs of both handlers in the order they were sent
+///
+/// Only the length and a hash of differing outputs are logged
+async fn diff_outputs(
+    mut primary: Receiver<Frame>,
+    mut shadow: Receiver<Frame>,
+    abandoned: &AtomicBool,
+) {
+    let mut matched = 0usize;
+    let mut mismatched = 0usize;
+
+    loop {
+        let outputs = (primary.recv().await, shadow.recv().await);
+        // The outputs no longer line up once either side was dropped
+        if abandoned.load(Ordering::Acquire) {
+            warn!(target: "mirror", "Stopped mirroring a session that fell more than {MIRROR_BUFFER_SIZE} frames behind");
+            break;
+        }
+        match outputs {
+            (None, None) => break,
+            (Some(primary), Some(shadow)) if primary == shadow => matched += 1,
+            (primary, shadow) => {
+                mismatched += 1;
+                warn!(
+                    target: "mirror",
+                    "Shadow output differs from primary. Primary: {}, Shadow: {}",
+                    redacted(&primary),
+                    redacted(&shadow)
+                );
+            }
+        }
+    }
+
+    info!(target: "mirror", "Mirrored session ended with {matched} matching and {mismatched} mismatched outputs");
+}
+
+/// The WebSocket given to the primary handler, which copies every frame it
+/// receives to the shadow, and every frame it sends to the differ
+struct MirroredWebSocket {
+    ws: ManagedWebSocket,
+    /// Both are dropped once the mirror is abandoned, which ends the shadow
+    inbound: Option<Sender<Frame>>,
+    outputs: Option<Sender<Frame>>,
+    abandoned: Arc<AtomicBool>,
+}
+
+impl MirroredWebSocket {
+    /// Copies the frame without waiting, abandoning the mirror if the shadow
+    /// or the differ is too far behind
+    fn copy(&mut self, frame: Frame, inbound: bool) {
+        let sender = if inbound {
+            &self.inbound
+        } else {
+            &self.outputs
+        };
+        let Some(sender) = sender else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(frame) {
+            self.abandoned.store(true, Ordering::Release);
+            self.inbound = None;
+            self.outputs = None;
+        }
+    }
+}
+
+#[async_trait]
+impl TextStream for MirroredWebSocket {
+    type Error = WsError;
+
+    async fn recv_string(&mut self) -> Result<String, Self::Error> {
+        let msg = self.ws.recv_string().await?;
+        self.copy(Frame::Text(msg.clone()), true);
+        Ok(msg)
+    }
+
+    async fn send_string(&mut self, msg: String) -> Result<(), Self::Error> {
+        self.copy(Frame::Text(msg.clone()), false);
+        self.ws.send_string(msg).await
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        loop {
+            if let Err(e) = self.recv_string().await {
+                break e;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BytesStream for MirroredWebSocket {
+    type Error = WsError;
+
+    async fn recv_bytes(&mut self) -> Result<Vec<u8>, Self::Error> {
+        let msg = self.ws.recv_bytes().await?;
+        self.copy(Frame::Binary(msg.clone()), true);
+        Ok(msg)
+    }
+
+    async fn send_bytes(&mut self, msg: Vec<u8>) -> Result<(), Self::Error> {
+        self.copy(Frame::Binary(msg.clone()), false);
+        self.ws.send_bytes(msg).await
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        loop {
+            if let Err(e) = self.recv_bytes().await {
+                break e;
+            }
+        }
+    }
+}
+
+/// The stream given to the shadow handler
+///
+/// It receives copies of the frames the client sent to the primary handler, and
+/// its outputs never reach the client. It closes once the client disconnects
+pub(super) struct ShadowStream {
+    inbound: Receiver<Frame>,
+    outputs: Sender<Frame>,
+    abandoned: Arc<AtomicBool>,
+}
+
+impl ShadowStream {
+    /// Ends the shadow if the differ is too far behind
+    fn output(&self, frame: Frame) -> Result<(), WsError> {
+        match self.outputs.try_send(frame) {
+            Err(TrySendError::Full(_)) => {
+                self.abandoned.store(true, Ordering::Release);
+                Err(WsError::AlreadyClosed)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl TextStream for ShadowStream {
+    type Error = WsError;
+
+    async fn recv_string(&mut self) -> Result<String, Self::Error> {
+        match self.inbound.recv().await {
+            Some(Frame::Text(x)) => Ok(x),
+            Some(Frame::Binary(x)) => Err(WsError::NotAString(x)),
+            None => Err(WsError::AlreadyClosed),
+        }
+    }
+
+    async fn send_string(&mut self, msg: String) -> Result<(), Self::Error> {
+        self.output(Frame::Text(msg))
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        loop {
+            if let Err(e) = self.recv_string().await {
+                break e;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BytesStream for ShadowStream {
+    type Error = WsError;
+
+    async fn recv_bytes(&mut self) -> Result<Vec<u8>, Self::Error> {
+        match self.inbound.recv().await {
+            Some(Frame::Binary(x)) => Ok(x),
+            Some(Frame::Text(x)) => Err(WsError::NotBytes(x)),
+            None => Err(WsError::AlreadyClosed),
+        }
+    }
+
+    async fn send_bytes(&mut self, msg: Vec<u8>) -> Result<(), Self::Error> {
+        self.output(Frame::Binary(msg))
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        loop {
+            if let Err(e) = self.recv_bytes().await {
+                break e;
+            }
+        }
+    }
+}
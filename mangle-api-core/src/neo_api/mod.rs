@@ -1,34 +1,232 @@
-use std::time::Duration;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
+use anyhow::Error;
 use axum::{
-    extract::{FromRequest, State, WebSocketUpgrade},
+    extract::{FromRequest, Query, State, WebSocketUpgrade},
     response::Response,
     routing::MethodRouter,
 };
-use messagist::{text::JsonMessageStream, AliasableMessageHandler};
+use log::info;
+use messagist::AliasableMessageHandler;
+use opentelemetry::{
+    trace::{Span, SpanKind, Tracer},
+    KeyValue,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crash::{supervise, CrashContext},
+    data_channel::{DataChannelHandoff, DataChannels},
+    shutdown::{ShutdownConfig, ShutdownNotifier},
+    telemetry,
+    ws::{ManagedWebSocket, SessionEnd, SessionSummary},
+};
+
+use self::{
+    bandwidth::{BandwidthLimits, BandwidthRegistry, BandwidthUser},
+    layer::HandlerLayer,
+    long_poll::LongPollSessions,
+    metrics::SessionMetrics,
+    mirror::{handle_with_format, Mirror},
+};
 
-use crate::ws::ManagedWebSocket;
+pub mod bandwidth;
+pub mod latency;
+pub mod layer;
+pub mod long_poll;
+pub mod metrics;
+mod mirror;
+
+/// A serialization format that a client can request for its connection
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Bincode,
+}
+
+impl MessageFormat {
+    /// The name of this format, used both as the WebSocket subprotocol and the
+    /// `format` query parameter
+    pub const fn name(self) -> &'static str {
+        match self {
+            MessageFormat::Json => "json",
+            MessageFormat::MessagePack => "msgpack",
+            MessageFormat::Bincode => "bincode",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(MessageFormat::Json),
+            "msgpack" => Some(MessageFormat::MessagePack),
+            "bincode" => Some(MessageFormat::Bincode),
+            _ => None,
+        }
+    }
+}
 
 pub struct NeoApiConfig<H: AliasableMessageHandler + Send + Sync> {
     ping_delay: Duration,
     handler: H,
+    formats: Vec<MessageFormat>,
+    mirror: Option<Mirror<H::SessionState>>,
+    bandwidth: BandwidthRegistry,
+    bandwidth_user: Option<(
+        Box<dyn Fn(&H::SessionState) -> Option<String> + Send + Sync>,
+        Box<dyn Fn(&mut H::SessionState, BandwidthUser) + Send + Sync>,
+    )>,
+    data_channels: Option<(
+        Arc<DataChannels>,
+        Box<dyn Fn(&mut H::SessionState, DataChannelHandoff) + Send + Sync>,
+    )>,
+    shutdown: Arc<ShutdownNotifier>,
+    metrics: Arc<SessionMetrics>,
+    long_poll: LongPollSessions,
 }
 
 impl<H: AliasableMessageHandler + Send + Sync> NeoApiConfig<H> {
+    /// Creates a config that only accepts json, which is what older clients expect
     pub fn new(ping_delay: Duration, handler: H) -> Self {
         Self {
             ping_delay,
             handler,
+            formats: vec![MessageFormat::Json],
+            mirror: None,
+            bandwidth: Default::default(),
+            bandwidth_user: None,
+            data_channels: None,
+            shutdown: Default::default(),
+            metrics: Default::default(),
+            long_poll: Default::default(),
+        }
+    }
+    /// Sets the formats that clients are allowed to pick from
+    ///
+    /// The first format is used whenever a client does not request one
+    pub fn set_formats(
+        mut self,
+        formats: impl IntoIterator<Item = MessageFormat>,
+    ) -> Result<Self, Error> {
+        let formats: Vec<_> = formats.into_iter().collect();
+        if formats.is_empty() {
+            return Err(Error::msg("At least one message format must be allowed"));
+        }
+        self.formats = formats;
+        Ok(self)
+    }
+    /// Duplicates the messages of the given percentage of connections to a shadow handler
+    ///
+    /// The outputs of the shadow handler are never sent to the client. Instead, they are
+    /// compared against the outputs of the primary handler, and any differences are logged.
+    /// `make_state` creates the session state of the shadow from that of the primary.
+    /// Any side effects of the shadow handler, such as database writes, still happen.
+    /// Fails if the percentage is not between 0 and 100
+    pub fn set_mirror<M, F>(
+        mut self,
+        handler: M,
+        percentage: f32,
+        make_state: F,
+    ) -> Result<Self, Error>
+    where
+        M: AliasableMessageHandler<SessionState: 'static> + Send + Sync + 'static,
+        F: Fn(&H::SessionState) -> M::SessionState + Send + Sync + 'static,
+    {
+        self.mirror = Some(Mirror::new(handler, percentage, make_state)?);
+        Ok(self)
+    }
+    /// Sets the bandwidth caps of each connection and each user
+    ///
+    /// Without caps, bandwidth is still tracked but never limited
+    pub fn set_bandwidth_limits(mut self, limits: BandwidthLimits) -> Self {
+        self.bandwidth.set_limits(limits);
+        self
+    }
+    /// Sets how the user of a connection is identified, so that the bandwidth of all
+    /// connections of the same user is counted together
+    ///
+    /// `attach` gives the session state of each connection the handle that it
+    /// changes its user with, such as when it logs in later on
+    pub fn set_bandwidth_user(
+        mut self,
+        user: impl Fn(&H::SessionState) -> Option<String> + Send + Sync + 'static,
+        attach: impl Fn(&mut H::SessionState, BandwidthUser) + Send + Sync + 'static,
+    ) -> Self {
+        self.bandwidth_user = Some((Box::new(user), Box::new(attach)));
+        self
+    }
+    /// Lets connections move onto WebRTC data channels
+    ///
+    /// `attach` gives the session state of each connection the handoff it
+    /// negotiates data channels with
+    pub fn set_data_channels(
+        mut self,
+        data_channels: DataChannels,
+        attach: impl Fn(&mut H::SessionState, DataChannelHandoff) + Send + Sync + 'static,
+    ) -> Self {
+        self.data_channels = Some((Arc::new(data_channels), Box::new(attach)));
+        self
+    }
+    /// Sets the close frame that sessions are closed with when the server stops
+    pub fn set_shutdown(mut self, config: &ShutdownConfig) -> Self {
+        self.shutdown = Arc::new(ShutdownNotifier::new(config));
+        self
+    }
+    /// Wraps the handler in the given layer
+    ///
+    /// Each layer wraps every layer added before it, so the last layer added is
+    /// the first to see each connection. The shadow handler of a mirror is not wrapped
+    pub fn layer<L>(self, layer: L) -> NeoApiConfig<L::Handler>
+    where
+        L: HandlerLayer<H>,
+        L::Handler: Send + Sync,
+    {
+        NeoApiConfig {
+            ping_delay: self.ping_delay,
+            handler: layer.layer(self.handler),
+            formats: self.formats,
+            mirror: self.mirror,
+            bandwidth: self.bandwidth,
+            bandwidth_user: self.bandwidth_user,
+            data_channels: self.data_channels,
+            shutdown: self.shutdown,
+            metrics: self.metrics,
+            long_poll: self.long_poll,
         }
     }
     pub fn get_handler(&self) -> &H {
         &self.handler
     }
+    /// Notifies every open session when the server stops
+    pub fn get_shutdown(&self) -> &ShutdownNotifier {
+        &self.shutdown
+    }
+    /// Counts the sessions and messages of every connection
+    pub fn get_metrics(&self) -> &SessionMetrics {
+        &self.metrics
+    }
+    pub fn get_formats(&self) -> &[MessageFormat] {
+        &self.formats
+    }
+    pub fn get_bandwidth(&self) -> &BandwidthRegistry {
+        &self.bandwidth
+    }
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    format: String,
 }
 
 async fn ws_api_route_internal<S, B, H, R>(
     ws: WebSocketUpgrade,
     State(state): State<S>,
+    format_query: Option<Query<FormatQuery>>,
     request: R,
 ) -> Response
 where
@@ -38,16 +236,120 @@ where
     S: AsRef<NeoApiConfig<H>>,
     R: FromRequest<S, B> + Send + Sync + 'static,
 {
-    ws.on_upgrade(move |ws| async move {
-        let config = state.as_ref();
-        config
-            .handler
-            .handle(
-                JsonMessageStream::from(ManagedWebSocket::new(ws, config.ping_delay)),
-                request,
-            )
+    let formats = state.as_ref().get_formats();
+
+    // The query parameter takes precedence over the subprotocol, as some
+    // clients are unable to set subprotocols
+    let queried_format = format_query
+        .and_then(|Query(FormatQuery { format })| MessageFormat::from_name(&format))
+        .filter(|format| formats.contains(format));
+    let mirrored = state
+        .as_ref()
+        .mirror
+        .as_ref()
+        .map(Mirror::should_mirror)
+        .unwrap_or_default();
+
+    ws.protocols(formats.iter().map(|format| format.name()))
+        .on_upgrade(move |ws| async move {
+            let config = state.as_ref();
+            let format = queried_format
+                .or_else(|| {
+                    ws.protocol()
+                        .and_then(|protocol| protocol.to_str().ok())
+                        .and_then(MessageFormat::from_name)
+                })
+                .unwrap_or(config.formats[0]);
+            let mut request = request;
+            let user = config
+                .bandwidth_user
+                .as_ref()
+                .and_then(|(bandwidth_user, _)| bandwidth_user(&request));
+            let crash_context = Arc::new(CrashContext::default());
+            let summary = Arc::new(SessionSummary::default());
+            let started = SystemTime::now();
+            let session = format!(
+                "{} connection of {}",
+                format.name(),
+                user.as_deref().unwrap_or("unknown user")
+            );
+            let meter = config.bandwidth.connect(user);
+            let session_id = meter.get_id();
+            if let Some((_, attach)) = &config.bandwidth_user {
+                attach(&mut request, meter.get_user_handle());
+            }
+            let mut ws = ManagedWebSocket::new(ws, config.ping_delay)
+                .with_meter(meter)
+                .with_crash_context(crash_context.clone())
+                .with_summary(summary.clone())
+                .with_shutdown(config.shutdown.listen())
+                .with_metrics(config.metrics.clone());
+            let _open_session = config.metrics.open();
+            if let Some((data_channels, attach)) = &config.data_channels {
+                let (handoff, handoff_recv) = data_channels.handoff();
+                attach(&mut request, handoff);
+                ws = ws.with_handoff(handoff_recv);
+            }
+
+            let result = supervise(latency::scope(session_id, async {
+                match &config.mirror {
+                    Some(mirror) if mirrored => {
+                        mirror.run(&config.handler, ws, format, request).await
+                    }
+                    _ => handle_with_format(&config.handler, ws, format, request).await,
+                }
+            }))
             .await;
-    })
+
+            if let Err(panic) = result {
+                crash_context.report(&session, &*panic);
+            }
+            report_session(&session, format, started, summary.get_end());
+        })
+}
+
+/// Logs how the session ended, and records it as a span if telemetry is enabled
+fn report_session(
+    session: &str,
+    format: MessageFormat,
+    started: SystemTime,
+    end: Option<SessionEnd>,
+) {
+    let duration = started.elapsed().unwrap_or_default();
+    let mut attributes = vec![
+        KeyValue::new("session.format", format.name()),
+        KeyValue::new("session.duration_ms", duration.as_millis() as i64),
+    ];
+    match &end {
+        Some(SessionEnd::Closed(status)) => {
+            info!(target: "ws_sessions", "{session} closed by client with {status} after {duration:?}");
+            attributes.push(KeyValue::new("session.end", "closed"));
+            attributes.push(KeyValue::new("session.close_code", status.code as i64));
+            attributes.push(KeyValue::new("session.close_reason", status.reason.clone()));
+            attributes.push(KeyValue::new("session.clean_close", status.is_normal()));
+        }
+        Some(SessionEnd::Dropped) => {
+            info!(target: "ws_sessions", "{session} dropped after {duration:?}");
+            attributes.push(KeyValue::new("session.end", "dropped"));
+            attributes.push(KeyValue::new("session.clean_close", false));
+        }
+        None => {
+            info!(target: "ws_sessions", "{session} ended by server after {duration:?}");
+            attributes.push(KeyValue::new("session.end", "server"));
+        }
+    }
+
+    if !telemetry::is_enabled() {
+        return;
+    }
+    let tracer = telemetry::tracer();
+    tracer
+        .span_builder("ws session")
+        .with_kind(SpanKind::Server)
+        .with_start_time(started)
+        .with_attributes(attributes)
+        .start(&tracer)
+        .end();
 }
 
 pub fn ws_api_route<S, B, H, R>() -> MethodRouter<S, B>
@@ -0,0 +1,334 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use axum::{
+    body::Bytes,
+    http::{header, Method},
+    response::IntoResponse,
+    routing::MethodRouter,
+};
+use serde_json::{json, Map, Value};
+
+use crate::pagination::Page;
+
+/// Where the OpenAPI document is served, which is public
+pub const OPENAPI_PATH: &str = "/openapi.json";
+
+/// A type that can describe itself with a JSON schema, so that it can be
+/// documented as the body of a request or response
+///
+/// Implemented by hand, usually with `object_schema` and `enum_schema`
+pub trait ApiSchema {
+    fn schema() -> Value;
+}
+
+macro_rules! impl_api_schema {
+    ($schema: tt, $($ty: ty),+) => {
+        $(
+            impl ApiSchema for $ty {
+                fn schema() -> Value {
+                    json!($schema)
+                }
+            }
+        )+
+    };
+}
+
+impl_api_schema!({ "type": "string" }, String, str);
+impl_api_schema!({ "type": "boolean" }, bool);
+impl_api_schema!(
+    { "type": "integer" },
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize
+);
+impl_api_schema!({ "type": "number" }, f32, f64);
+impl_api_schema!({}, Value);
+
+impl<T: ApiSchema + ?Sized> ApiSchema for &T {
+    fn schema() -> Value {
+        T::schema()
+    }
+}
+
+impl<T: ApiSchema + ?Sized> ApiSchema for Box<T> {
+    fn schema() -> Value {
+        T::schema()
+    }
+}
+
+impl<T: ApiSchema + ?Sized> ApiSchema for Arc<T> {
+    fn schema() -> Value {
+        T::schema()
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for Option<T> {
+    fn schema() -> Value {
+        let mut schema = T::schema();
+        if let Value::Object(schema) = &mut schema {
+            schema.insert("nullable".into(), true.into());
+        }
+        schema
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for HashMap<String, T> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": T::schema() })
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for BTreeMap<String, T> {
+    fn schema() -> Value {
+        HashMap::<String, T>::schema()
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for Page<T> {
+    fn schema() -> Value {
+        object_schema(
+            [
+                ("items", Vec::<T>::schema()),
+                ("next_cursor", Option::<String>::schema()),
+            ],
+            &["items"],
+        )
+    }
+}
+
+/// Describes an object with the given fields, where those in `required`
+/// are never missing or null
+pub fn object_schema<'a>(
+    fields: impl IntoIterator<Item = (&'a str, Value)>,
+    required: &[&str],
+) -> Value {
+    let properties: Map<String, Value> = fields
+        .into_iter()
+        .map(|(name, schema)| (name.to_string(), schema))
+        .collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// Describes a string that is one of the given variants, as with unit enums
+pub fn enum_schema(variants: &[&str]) -> Value {
+    json!({ "type": "string", "enum": variants })
+}
+
+/// Documents a single method of a route
+pub struct Operation {
+    method: Method,
+    summary: Option<String>,
+    /// The name, schema and whether it is required
+    query_params: Vec<(String, Value, bool)>,
+    request: Option<Value>,
+    response: Option<Value>,
+}
+
+impl Operation {
+    pub fn new(method: Method) -> Self {
+        Self {
+            method,
+            summary: None,
+            query_params: Vec::new(),
+            request: None,
+            response: None,
+        }
+    }
+
+    pub fn get() -> Self {
+        Self::new(Method::GET)
+    }
+
+    pub fn post() -> Self {
+        Self::new(Method::POST)
+    }
+
+    pub fn put() -> Self {
+        Self::new(Method::PUT)
+    }
+
+    pub fn delete() -> Self {
+        Self::new(Method::DELETE)
+    }
+
+    pub fn set_summary(mut self, summary: impl Into<String>) -> Self {
+        self.summary = Some(summary.into());
+        self
+    }
+
+    pub fn add_query_param<T: ApiSchema + ?Sized>(
+        mut self,
+        name: impl Into<String>,
+        required: bool,
+    ) -> Self {
+        self.query_params.push((name.into(), T::schema(), required));
+        self
+    }
+
+    /// The type of the JSON body of the request
+    pub fn set_request<T: ApiSchema + ?Sized>(mut self) -> Self {
+        self.request = Some(T::schema());
+        self
+    }
+
+    /// The type of the JSON body of successful responses
+    pub fn set_response<T: ApiSchema + ?Sized>(mut self) -> Self {
+        self.response = Some(T::schema());
+        self
+    }
+
+    fn render(&self, path_params: &[&str], public: bool) -> Value {
+        let mut out = Map::new();
+        if let Some(summary) = &self.summary {
+            out.insert("summary".into(), summary.as_str().into());
+        }
+
+        let parameters: Vec<_> = path_params
+            .iter()
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": String::schema(),
+                })
+            })
+            .chain(self.query_params.iter().map(|(name, schema, required)| {
+                json!({
+                    "name": name,
+                    "in": "query",
+                    "required": required,
+                    "schema": schema,
+                })
+            }))
+            .collect();
+        if !parameters.is_empty() {
+            out.insert("parameters".into(), parameters.into());
+        }
+
+        if let Some(request) = &self.request {
+            out.insert(
+                "requestBody".into(),
+                json!({
+                    "required": true,
+                    "content": { "application/json": { "schema": request } },
+                }),
+            );
+        }
+
+        let mut responses = Map::new();
+        responses.insert(
+            "200".into(),
+            match &self.response {
+                Some(response) => json!({
+                    "description": "OK",
+                    "content": { "application/json": { "schema": response } },
+                }),
+                None => json!({ "description": "OK" }),
+            },
+        );
+        if public {
+            // Overrides the security of the document
+            out.insert("security".into(), json!([]));
+        } else {
+            responses.insert(
+                "401".into(),
+                json!({ "description": "Missing or invalid API token" }),
+            );
+        }
+        out.insert("responses".into(), responses.into());
+        out.into()
+    }
+}
+
+/// An OpenAPI 3.0 document of the routes of an API, given to the API builder
+/// with `set_openapi`, which serves it at `OPENAPI_PATH`
+pub struct OpenApi {
+    title: String,
+    version: String,
+    /// Keyed by route, in the syntax of axum
+    operations: Vec<(&'static str, Operation)>,
+}
+
+impl OpenApi {
+    pub fn new(title: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            version: version.into(),
+            operations: Vec::new(),
+        }
+    }
+
+    /// Documents a method of a route given to `set_routes`
+    pub fn document(mut self, route: &'static str, operation: Operation) -> Self {
+        self.operations.push((route, operation));
+        self
+    }
+
+    pub(crate) fn routes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.operations.iter().map(|(route, _)| *route)
+    }
+
+    /// Renders the document, where routes that are not public need the API token
+    pub(crate) fn render(&self, is_public: impl Fn(&str) -> bool) -> Value {
+        let mut paths: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+        for (route, operation) in &self.operations {
+            let mut path_params = Vec::new();
+            // OpenAPI writes `/:id` as `/{id}`
+            let path = route
+                .split('/')
+                .map(|segment| match segment.strip_prefix([':', '*']) {
+                    Some(name) => {
+                        path_params.push(name);
+                        format!("{{{name}}}")
+                    }
+                    None => segment.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            paths.entry(path).or_default().insert(
+                operation.method.as_str().to_ascii_lowercase(),
+                operation.render(&path_params, is_public(route)),
+            );
+        }
+
+        json!({
+            "openapi": "3.0.3",
+            "info": { "title": self.title, "version": self.version },
+            "paths": paths,
+            "components": {
+                "securitySchemes": {
+                    "bearerAuth": { "type": "http", "scheme": "bearer" }
+                }
+            },
+            "security": [{ "bearerAuth": [] }],
+        })
+    }
+}
+
+/// Serves a rendered document
+pub(crate) fn openapi_route<S>(document: Value) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let document = Bytes::from(document.to_string());
+    axum::routing::get(move || async move {
+        ([(header::CONTENT_TYPE, "application/json")], document).into_response()
+    })
+}
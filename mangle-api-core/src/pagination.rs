@@ -0,0 +1,165 @@
+use axum::{
+    async_trait,
+    extract::{rejection::QueryRejection, FromRequestParts, Query},
+    http::request::Parts,
+};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE_LIMIT: usize = 20;
+pub const MAX_PAGE_LIMIT: usize = 100;
+
+/// An opaque position in a listing, pointing just after the last item of a page
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(transparent)]
+pub struct Cursor(String);
+
+impl Cursor {
+    pub fn new(position: impl Into<String>) -> Self {
+        Self(position.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A single page of a listing
+///
+/// If `next_cursor` is None, this is the last page
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Cursor>,
+}
+
+impl<T> Page<T> {
+    /// Takes the page described by `pagination` out of items sorted by `key`
+    ///
+    /// The cursor of each page is the key of its last item, so the keys must be unique
+    pub fn from_sorted<K>(
+        items: impl IntoIterator<Item = T>,
+        pagination: &Pagination,
+        key: impl Fn(&T) -> K,
+    ) -> Self
+    where
+        K: AsRef<str>,
+    {
+        let mut items = items
+            .into_iter()
+            .skip_while(|item| match &pagination.cursor {
+                Some(cursor) => key(item).as_ref() <= cursor.as_str(),
+                None => false,
+            })
+            .take(pagination.limit + 1)
+            .collect::<Vec<_>>();
+
+        let next_cursor = if items.len() > pagination.limit {
+            items.truncate(pagination.limit);
+            items.last().map(|item| Cursor::new(key(item).as_ref()))
+        } else {
+            None
+        };
+
+        Self { items, next_cursor }
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PaginationQuery {
+    cursor: Option<Cursor>,
+    limit: Option<usize>,
+}
+
+/// The `cursor` and `limit` query parameters of a request for a listing
+///
+/// The limit is always between 1 and `MAX_PAGE_LIMIT`. Filters are not included,
+/// and should be extracted separately with `Query`
+#[derive(Clone, Debug)]
+pub struct Pagination {
+    pub cursor: Option<Cursor>,
+    pub limit: usize,
+}
+
+impl Pagination {
+    pub fn new(cursor: Option<Cursor>, limit: Option<usize>) -> Self {
+        Self {
+            cursor,
+            limit: limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT),
+        }
+    }
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Pagination {
+    type Rejection = QueryRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<PaginationQuery>::from_request_parts(parts, state).await?;
+        Ok(Self::new(query.cursor, query.limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(cursor: Option<&str>, limit: usize) -> Page<&'static str> {
+        Page::from_sorted(
+            ["a", "b", "c", "d", "e"],
+            &Pagination::new(cursor.map(Cursor::new), Some(limit)),
+            |x| *x,
+        )
+    }
+
+    #[test]
+    fn pages_through_sorted_items() {
+        let first = page(None, 2);
+        assert_eq!(first.items, ["a", "b"]);
+        assert_eq!(first.next_cursor, Some(Cursor::new("b")));
+
+        let second = page(Some("b"), 2);
+        assert_eq!(second.items, ["c", "d"]);
+        assert_eq!(second.next_cursor, Some(Cursor::new("d")));
+
+        let last = page(Some("d"), 2);
+        assert_eq!(last.items, ["e"]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn full_last_page_has_no_cursor() {
+        let last = page(Some("a"), 4);
+        assert_eq!(last.items, ["b", "c", "d", "e"]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn cursor_past_the_end_is_empty() {
+        let empty = page(Some("z"), 2);
+        assert!(empty.items.is_empty());
+        assert_eq!(empty.next_cursor, None);
+    }
+
+    #[test]
+    fn clamps_limit() {
+        assert_eq!(Pagination::new(None, None).limit, DEFAULT_PAGE_LIMIT);
+        assert_eq!(Pagination::new(None, Some(0)).limit, 1);
+        assert_eq!(
+            Pagination::new(None, Some(MAX_PAGE_LIMIT + 1)).limit,
+            MAX_PAGE_LIMIT
+        );
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fs::{create_dir_all, read, read_dir, remove_file, rename, write, File, OpenOptions},
+    io::{self, Write},
+    marker::PhantomData,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+use log::{error, warn};
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{sync::Notify, task::spawn_blocking};
+
+const SEGMENT_EXTENSION: &str = "seg";
+const CURSOR_FILE: &str = "cursor";
+const DEFAULT_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+/// The id of the item followed by the length of its bytes
+const HEADER_LEN: usize = 12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PushError {
+    #[error("Queue is full")]
+    Full,
+    #[error("Encoding item: {0}")]
+    Encode(#[from] bincode::Error),
+    #[error("Writing item: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueStats {
+    /// Items waiting to be delivered
+    pub queued: usize,
+    /// Items delivered but not acknowledged yet
+    pub in_flight: usize,
+    pub segments: usize,
+    pub bytes_on_disk: u64,
+    pub pushed: u64,
+    /// Items rejected because the queue was full
+    pub rejected: u64,
+    pub acked: u64,
+    /// Items that were not acknowledged before they were dropped
+    pub redelivered: u64,
+    /// Items that were not acknowledged before the last restart
+    pub recovered: u64,
+}
+
+struct Segment {
+    path: PathBuf,
+    first_id: u64,
+    len: u64,
+}
+
+struct Inner {
+    /// Oldest first, where items are only appended to the last segment
+    segments: VecDeque<Segment>,
+    file: File,
+    pending: VecDeque<(u64, Vec<u8>)>,
+    in_flight: BTreeMap<u64, Vec<u8>>,
+    /// Items that were written but are not on disk yet
+    syncing: BTreeSet<u64>,
+    next_id: u64,
+    /// Every item before this id has been acknowledged
+    cursor: u64,
+}
+
+/// A bounded queue of items that survives restarts, stored as segment files in a directory
+///
+/// Items are delivered at least once: an item is only removed once its
+/// `Delivery` is acknowledged, and is delivered again if the delivery is
+/// dropped or the process stops first. Applications usually leak the queue
+/// into their state so that handlers can push to it
+pub struct PersistentQueue<T> {
+    dir: PathBuf,
+    capacity: usize,
+    segment_size: u64,
+    inner: Mutex<Inner>,
+    notify: Notify,
+    pushed: AtomicU64,
+    rejected: AtomicU64,
+    acked: AtomicU64,
+    redelivered: AtomicU64,
+    recovered: u64,
+    _phantom: PhantomData<fn(T) -> T>,
+}
+
+impl<T> PersistentQueue<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Opens the queue in `dir`, recovering every item that was not acknowledged
+    ///
+    /// `capacity` counts both queued items and items in flight
+    pub fn open(dir: impl Into<PathBuf>, capacity: usize) -> Result<Self> {
+        let dir = dir.into();
+        create_dir_all(&dir).context(format!("Creating {dir:?}"))?;
+
+        let cursor = match std::fs::read_to_string(dir.join(CURSOR_FILE)) {
+            Ok(x) => x.trim().parse().context("Parsing cursor of queue")?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).context("Reading cursor of queue"),
+        };
+
+        let mut segments = Vec::new();
+        for entry in read_dir(&dir).context(format!("Reading {dir:?}"))? {
+            let path = entry.context(format!("Reading {dir:?}"))?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let Some(first_id) = path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.parse().ok())
+            else {
+                warn!(target: "persistent_queue", "Ignoring unknown segment {path:?}");
+                continue;
+            };
+            segments.push(Segment {
+                path,
+                first_id,
+                len: 0,
+            });
+        }
+        segments.sort_unstable_by_key(|x| x.first_id);
+
+        let mut pending = VecDeque::new();
+        let mut next_id = cursor;
+        for segment in &mut segments {
+            let bytes = read(&segment.path).context(format!("Reading {:?}", segment.path))?;
+            let mut offset = 0;
+            while let Some((id, item)) = parse_record(&bytes[offset..]) {
+                offset += HEADER_LEN + item.len();
+                if id >= cursor {
+                    pending.push_back((id, item.to_vec()));
+                }
+                next_id = next_id.max(id + 1);
+            }
+            if offset < bytes.len() {
+                // Left behind by a crash in the middle of a write
+                warn!(
+                    target: "persistent_queue",
+                    "Truncating {} bytes from {:?}",
+                    bytes.len() - offset,
+                    segment.path
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&segment.path)
+                    .and_then(|file| file.set_len(offset as u64))
+                    .context(format!("Truncating {:?}", segment.path))?;
+            }
+            segment.len = offset as u64;
+        }
+
+        let mut segments: VecDeque<_> = segments.into();
+        let file = match segments.back() {
+            Some(segment) => OpenOptions::new()
+                .append(true)
+                .open(&segment.path)
+                .context(format!("Opening {:?}", segment.path))?,
+            None => {
+                let (segment, file) = new_segment(&dir, next_id)?;
+                segments.push_back(segment);
+                file
+            }
+        };
+
+        let recovered = pending.len() as u64;
+        let mut inner = Inner {
+            segments,
+            file,
+            pending,
+            in_flight: BTreeMap::new(),
+            syncing: BTreeSet::new(),
+            next_id,
+            cursor,
+        };
+        inner.remove_acked_segments();
+
+        Ok(Self {
+            dir,
+            capacity,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            inner: Mutex::new(inner),
+            notify: Notify::new(),
+            pushed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            acked: AtomicU64::new(0),
+            redelivered: AtomicU64::new(0),
+            recovered,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// How large a segment may grow before items are appended to a new one
+    pub fn set_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Appends an item, returning once it has been written to disk
+    ///
+    /// Items whose push failed while syncing, or was cancelled, may still be
+    /// delivered after a restart
+    pub async fn push(&self, item: &T) -> Result<(), PushError> {
+        let bytes = bincode::serialize(item)?;
+        let (syncing, file) = {
+            let mut inner = self.inner.lock();
+            if inner.pending.len() + inner.in_flight.len() + inner.syncing.len() >= self.capacity {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(PushError::Full);
+            }
+
+            let id = inner.next_id;
+            let current_len = inner.segments.back().map(|x| x.len).unwrap_or_default();
+            if current_len >= self.segment_size {
+                let (segment, file) = new_segment(&self.dir, id)?;
+                inner.segments.push_back(segment);
+                inner.file = file;
+            }
+            let file = inner.file.try_clone()?;
+
+            let mut record = Vec::with_capacity(HEADER_LEN + bytes.len());
+            record.extend_from_slice(&id.to_le_bytes());
+            record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            record.extend_from_slice(&bytes);
+            if let Err(e) = inner.file.write_all(&record) {
+                // A partial record would hide every record written after it
+                let len = inner.segments.back().map(|x| x.len).unwrap_or_default();
+                if let Err(e) = inner.file.set_len(len) {
+                    error!(target: "persistent_queue", "Truncating a partial record in {:?}: {e}", self.dir);
+                }
+                return Err(e.into());
+            }
+
+            if let Some(segment) = inner.segments.back_mut() {
+                segment.len += record.len() as u64;
+            }
+            inner.next_id += 1;
+            inner.syncing.insert(id);
+            (
+                Syncing {
+                    inner: &self.inner,
+                    id,
+                },
+                file,
+            )
+        };
+
+        // Other pushes can write while this one waits for the disk
+        spawn_blocking(move || file.sync_data())
+            .await
+            .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)))?;
+        syncing.finish(bytes);
+
+        self.pushed.fetch_add(1, Ordering::Relaxed);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Takes the next item, if there is one
+    pub fn try_recv(&self) -> Option<Delivery<'_, T>> {
+        loop {
+            let mut inner = self.inner.lock();
+            let (id, bytes) = inner.pending.pop_front()?;
+            match bincode::deserialize(&bytes) {
+                Ok(item) => {
+                    inner.in_flight.insert(id, bytes);
+                    return Some(Delivery {
+                        queue: self,
+                        id,
+                        item,
+                        acked: false,
+                    });
+                }
+                Err(e) => {
+                    // Skipped, as it would never decode no matter how often it is redelivered
+                    error!(target: "persistent_queue", "Discarding item {id} in {:?}: {e}", self.dir);
+                    inner.in_flight.insert(id, bytes);
+                    drop(inner);
+                    if let Err(e) = self.ack(id) {
+                        error!(target: "persistent_queue", "{e:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits for the next item
+    pub async fn recv(&self) -> Delivery<'_, T> {
+        loop {
+            if let Some(delivery) = self.try_recv() {
+                return delivery;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn get_stats(&self) -> QueueStats {
+        let inner = self.inner.lock();
+        QueueStats {
+            queued: inner.pending.len(),
+            in_flight: inner.in_flight.len(),
+            segments: inner.segments.len(),
+            bytes_on_disk: inner.segments.iter().map(|x| x.len).sum(),
+            pushed: self.pushed.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            acked: self.acked.load(Ordering::Relaxed),
+            redelivered: self.redelivered.load(Ordering::Relaxed),
+            recovered: self.recovered,
+        }
+    }
+
+    fn ack(&self, id: u64) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.in_flight.remove(&id).is_none() {
+            return Ok(());
+        }
+        self.acked.fetch_add(1, Ordering::Relaxed);
+
+        let cursor = inner
+            .in_flight
+            .keys()
+            .next()
+            .copied()
+            .into_iter()
+            .chain(inner.pending.front().map(|x| x.0))
+            .chain(inner.syncing.first().copied())
+            .min()
+            .unwrap_or(inner.next_id);
+        if cursor == inner.cursor {
+            return Ok(());
+        }
+        // Written to a temporary file first so that a crash never leaves a partial cursor
+        let tmp = self.dir.join(format!("{CURSOR_FILE}.tmp"));
+        write(&tmp, cursor.to_string()).context("Writing cursor of queue")?;
+        rename(&tmp, self.dir.join(CURSOR_FILE)).context("Replacing cursor of queue")?;
+        inner.cursor = cursor;
+        inner.remove_acked_segments();
+        Ok(())
+    }
+
+    fn requeue(&self, id: u64) {
+        let mut inner = self.inner.lock();
+        let Some(bytes) = inner.in_flight.remove(&id) else {
+            return;
+        };
+        // Keeps the pending items in the order they were pushed
+        let index = inner.pending.partition_point(|x| x.0 < id);
+        inner.pending.insert(index, (id, bytes));
+        drop(inner);
+        self.redelivered.fetch_add(1, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+}
+
+/// An item being synced, which holds back the cursor until its push finishes
+/// or is cancelled
+struct Syncing<'a> {
+    inner: &'a Mutex<Inner>,
+    id: u64,
+}
+
+impl Syncing<'_> {
+    /// Queues the item for delivery once it is on disk
+    fn finish(self, bytes: Vec<u8>) {
+        let mut inner = self.inner.lock();
+        inner.syncing.remove(&self.id);
+        // Pushes may finish syncing out of order
+        let index = inner.pending.partition_point(|x| x.0 < self.id);
+        inner.pending.insert(index, (self.id, bytes));
+        drop(inner);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Syncing<'_> {
+    fn drop(&mut self) {
+        self.inner.lock().syncing.remove(&self.id);
+    }
+}
+
+impl Inner {
+    /// Deletes every segment whose items have all been acknowledged, except the current segment
+    fn remove_acked_segments(&mut self) {
+        while self.segments.len() > 1 && self.segments[1].first_id <= self.cursor {
+            let Some(segment) = self.segments.pop_front() else {
+                break;
+            };
+            if let Err(e) = remove_file(&segment.path) {
+                error!(target: "persistent_queue", "Removing {:?}: {e}", segment.path);
+            }
+        }
+    }
+}
+
+fn new_segment(dir: &Path, first_id: u64) -> io::Result<(Segment, File)> {
+    let path = dir.join(format!("{first_id:020}.{SEGMENT_EXTENSION}"));
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    Ok((
+        Segment {
+            path,
+            first_id,
+            len: 0,
+        },
+        file,
+    ))
+}
+
+/// Returns None if there is not a whole record left
+fn parse_record(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let id = u64::from_le_bytes(header[..8].try_into().ok()?);
+    let len = u32::from_le_bytes(header[8..].try_into().ok()?) as usize;
+    let item = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    Some((id, item))
+}
+
+/// An item taken from a `PersistentQueue`, which is delivered again unless it is acknowledged
+pub struct Delivery<'a, T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    queue: &'a PersistentQueue<T>,
+    id: u64,
+    item: T,
+    acked: bool,
+}
+
+impl<'a, T> Delivery<'a, T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Removes the item from the queue for good
+    pub fn ack(mut self) -> Result<()> {
+        self.acked = true;
+        self.queue.ack(self.id)
+    }
+}
+
+impl<'a, T> Deref for Delivery<'a, T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.item
+    }
+}
+
+impl<'a, T> Drop for Delivery<'a, T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn drop(&mut self) {
+        if !self.acked {
+            self.queue.requeue(self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("persistent_queue_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn recovers_unacknowledged_items() {
+        let dir = test_dir("recovers");
+        {
+            let queue = PersistentQueue::<String>::open(&dir, 10).unwrap();
+            for item in ["a", "b", "c"] {
+                queue.push(&item.to_string()).await.unwrap();
+            }
+            queue.try_recv().unwrap().ack().unwrap();
+            // Dropped without being acknowledged, so it is delivered again
+            assert_eq!(*queue.try_recv().unwrap(), "b");
+        }
+
+        let queue = PersistentQueue::<String>::open(&dir, 10).unwrap();
+        assert_eq!(queue.get_stats().recovered, 2);
+        assert_eq!(*queue.try_recv().unwrap(), "b");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn truncates_partial_records() {
+        let dir = test_dir("truncates");
+        {
+            let queue = PersistentQueue::<String>::open(&dir, 10).unwrap();
+            queue.push(&"a".to_string()).await.unwrap();
+        }
+        let segment = read_dir(&dir)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .find(|x| x.extension().and_then(|x| x.to_str()) == Some(SEGMENT_EXTENSION))
+            .unwrap();
+        let len = std::fs::metadata(&segment).unwrap().len();
+        OpenOptions::new()
+            .append(true)
+            .open(&segment)
+            .unwrap()
+            .write_all(&[1, 2, 3])
+            .unwrap();
+
+        let queue = PersistentQueue::<String>::open(&dir, 10).unwrap();
+        assert_eq!(std::fs::metadata(&segment).unwrap().len(), len);
+        queue.push(&"b".to_string()).await.unwrap();
+        assert_eq!(*queue.try_recv().unwrap(), "a");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn rejects_items_beyond_capacity() {
+        let dir = test_dir("capacity");
+        let queue = PersistentQueue::<u32>::open(&dir, 2).unwrap();
+        queue.push(&1).await.unwrap();
+        let delivery = queue.try_recv().unwrap();
+        queue.push(&2).await.unwrap();
+        // Items in flight still count
+        assert!(matches!(queue.push(&3).await, Err(PushError::Full)));
+        delivery.ack().unwrap();
+        queue.push(&3).await.unwrap();
+        assert_eq!(queue.get_stats().rejected, 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,42 @@
+//! The items that most services import, so that they can start with
+//! `use mangle_api_core::prelude::*;`
+
+pub use anyhow::{Context as _, Error, Result};
+pub use axum::{
+    extract::State,
+    http::{HeaderValue, StatusCode},
+    response::Response,
+    Json,
+};
+pub use log::{debug, error, info, warn};
+pub use messagist::{
+    pipes::{connect_with_retry, RetryConfig},
+    AliasableMessageHandler, MessageStream,
+};
+
+pub use crate::{
+    acme::CertificateRenewal,
+    app::{ApiApp, AppStart, ServiceConfig, StartedApp},
+    auth::{
+        roles::{RequireRole, Role},
+        token::{HeaderTokenConfig, SignedTokenGranter, TokenConfig, TokenGranter, TokenPair},
+    },
+    clap::{arg, value_parser, ArgMatches, Command},
+    get_https_credentials,
+    ip_filter::IpFilter,
+    neo_api::{long_poll::long_poll_route, ws_api_route, MessageFormat, NeoApiConfig},
+    new_api,
+    openapi::{ApiSchema, OpenApi, Operation},
+    persistent_queue::PersistentQueue,
+    rate_limit::{RateLimit, RateLimits},
+    readiness::{health_route, HealthProbes, Readiness},
+    rejection::Rejection,
+    reload::{HttpSettings, ReloadableConfig},
+    response_cache::ResponseCache,
+    route_layers::RouteLayer,
+    static_routes::StaticDir,
+    status::StatusResponse,
+    tasks::{RestartPolicy, TaskManager},
+    telemetry::{shutdown_telemetry, TelemetryConfig},
+    BindAddress,
+};
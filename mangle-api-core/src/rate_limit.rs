@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use axum::{
+    body::{BoxBody, HttpBody},
+    extract::{connect_info::Connected, ConnectInfo},
+    http::{header::HeaderName, Extensions, HeaderMap, HeaderValue, Request, Response, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use hyper::server::conn::AddrStream;
+use log::warn;
+use parking_lot::Mutex;
+use tokio_native_tls::TlsStream;
+use tower_http::auth::AuthorizeRequest;
+
+use crate::{log_targets, rejection::Rejection};
+
+/// Buckets are forgotten once this many exist, if they have refilled completely
+const PRUNE_THRESHOLD: usize = 10_000;
+/// Buckets are pruned at most this often, so that clients with buckets that
+/// have not refilled cannot make every request prune
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+/// New clients are rejected once this many buckets exist
+const MAX_BUCKETS: usize = 100_000;
+
+/// A token bucket that refills `requests` over `period`, and holds up to `burst` requests
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Allows `requests` every minute, all at once if needed
+    pub const fn per_minute(requests: u32) -> Self {
+        Self {
+            requests,
+            period: Duration::from_secs(60),
+            burst: requests,
+        }
+    }
+
+    pub const fn per_second(requests: u32) -> Self {
+        Self {
+            requests,
+            period: Duration::from_secs(1),
+            burst: requests,
+        }
+    }
+
+    pub const fn set_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    fn per_sec(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+/// The rate limits of each route, as set on the API builder
+///
+/// Clients are identified by their IP address, and by their login token if
+/// a token header is set, with each having its own bucket. The IP address is
+/// only taken from forwarding headers that trusted proxies set
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    routes: HashMap<&'static str, RateLimit>,
+    token_header: Option<HeaderName>,
+}
+
+impl<const N: usize> From<[(&'static str, RateLimit); N]> for RateLimits {
+    fn from(routes: [(&'static str, RateLimit); N]) -> Self {
+        Self {
+            routes: routes.into(),
+            token_header: None,
+        }
+    }
+}
+
+impl RateLimits {
+    /// Also limits each login token in this header, which keeps clients behind
+    /// a shared IP address from limiting each other too early
+    pub fn set_token_header(mut self, header: &str) -> Self {
+        self.token_header =
+            Some(HeaderName::from_bytes(header.as_bytes()).expect("Parsing token header"));
+        self
+    }
+
+    /// Refuses limits that would never refill, as their buckets could not say
+    /// when to retry
+    pub fn validate(&self) -> Result<(), Error> {
+        for (route, limit) in &self.routes {
+            if limit.requests == 0 || limit.period.is_zero() || limit.burst == 0 {
+                return Err(Error::msg(format!(
+                    "The rate limit of {route} must allow at least one request"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum ClientKey {
+    Ip(IpAddr),
+    Token(HeaderValue),
+}
+
+/// What a client is told about its rate limit through the `X-RateLimit-*` headers
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitStatus {
+    /// The most requests that can be made at once
+    pub limit: u32,
+    pub remaining: u32,
+    /// How long until every request is available again
+    pub reset: Duration,
+}
+
+impl RateLimitStatus {
+    /// The status of whichever bucket of a client is closer to running out
+    fn tightest(self, other: Self) -> Self {
+        Self {
+            limit: self.limit.min(other.limit),
+            remaining: self.remaining.min(other.remaining),
+            reset: self.reset.max(other.reset),
+        }
+    }
+
+    /// Sets the `X-RateLimit-*` headers, with the reset rounded up to the next second
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("X-RateLimit-Limit", self.limit.into());
+        headers.insert("X-RateLimit-Remaining", self.remaining.into());
+        headers.insert(
+            "X-RateLimit-Reset",
+            (self.reset.as_secs_f64().ceil() as u64).into(),
+        );
+    }
+}
+
+struct Bucket {
+    available: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * limit.per_sec()).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// How long until a request is available, if there is none
+    fn wait(&self, limit: &RateLimit) -> Option<Duration> {
+        (self.available < 1.0)
+            .then(|| Duration::from_secs_f64((1.0 - self.available) / limit.per_sec()))
+    }
+
+    fn status(&self, limit: &RateLimit) -> RateLimitStatus {
+        RateLimitStatus {
+            limit: limit.burst,
+            remaining: self.available as u32,
+            reset: Duration::from_secs_f64((limit.burst as f64 - self.available) / limit.per_sec()),
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.available + elapsed * limit.per_sec() >= limit.burst as f64
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<(&'static str, ClientKey), Bucket>,
+    last_prune: Instant,
+}
+
+/// Rejects requests with 429 once a client has used up the rate limit of a route
+///
+/// The status of the rate limit is left in the extensions of accepted requests,
+/// for `add_rate_limit_headers` to send back
+pub struct RateLimiter<ResBody> {
+    limits: Arc<RateLimits>,
+    buckets: Arc<Mutex<Buckets>>,
+    _phantom: PhantomData<ResBody>,
+}
+
+// Derive clone would require ResBody to implement Clone
+impl<ResBody> Clone for RateLimiter<ResBody> {
+    fn clone(&self) -> Self {
+        Self {
+            limits: self.limits.clone(),
+            buckets: self.buckets.clone(),
+            _phantom: self._phantom,
+        }
+    }
+}
+
+impl<ResBody> RateLimiter<ResBody> {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: Arc::new(limits),
+            buckets: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_prune: Instant::now(),
+            })),
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Takes a request from the bucket of every key, but only if all of them
+    /// have one, otherwise returning how long until they do
+    ///
+    /// The status is that of the bucket closest to running out
+    fn try_take(
+        &self,
+        route: &'static str,
+        limit: &RateLimit,
+        keys: &[ClientKey],
+    ) -> (Option<RateLimitStatus>, Result<(), Duration>) {
+        let now = Instant::now();
+        let mut guard = self.buckets.lock();
+        let Buckets {
+            buckets,
+            last_prune,
+        } = &mut *guard;
+        if buckets.len() >= PRUNE_THRESHOLD && now.duration_since(*last_prune) >= PRUNE_INTERVAL {
+            *last_prune = now;
+            buckets.retain(|(route, _), bucket| {
+                self.limits
+                    .routes
+                    .get(route)
+                    .map(|limit| !bucket.is_full(limit))
+                    .unwrap_or_default()
+            });
+        }
+        for key in keys {
+            let key = (route, key.clone());
+            if buckets.contains_key(&key) {
+                continue;
+            }
+            if buckets.len() >= MAX_BUCKETS {
+                return (
+                    None,
+                    Err(Duration::from_secs_f64(1.0 / limit.per_sec()).max(PRUNE_INTERVAL)),
+                );
+            }
+            buckets.insert(
+                key,
+                Bucket {
+                    available: limit.burst as f64,
+                    last_refill: now,
+                },
+            );
+        }
+
+        let mut wait: Option<Duration> = None;
+        for key in keys {
+            let bucket = buckets
+                .get_mut(&(route, key.clone()))
+                .expect("bucket to have been inserted");
+            bucket.refill(limit, now);
+            if let Some(x) = bucket.wait(limit) {
+                wait = Some(wait.map_or(x, |wait| wait.max(x)));
+            }
+        }
+        let mut status: Option<RateLimitStatus> = None;
+        for key in keys {
+            let bucket = buckets
+                .get_mut(&(route, key.clone()))
+                .expect("bucket to have been inserted");
+            if wait.is_none() {
+                bucket.available -= 1.0;
+            }
+            let bucket_status = bucket.status(limit);
+            status = Some(status.map_or(bucket_status, |x| x.tightest(bucket_status)));
+        }
+        (status, wait.map_or(Ok(()), Err))
+    }
+}
+
+/// The address of the peer of a connection, which local sockets do not have
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PeerAddr(pub(crate) Option<SocketAddr>);
+
+impl Connected<&AddrStream> for PeerAddr {
+    fn connect_info(target: &AddrStream) -> Self {
+        Self(Some(target.remote_addr()))
+    }
+}
+
+impl Connected<&TlsStream<AddrStream>> for PeerAddr {
+    fn connect_info(target: &TlsStream<AddrStream>) -> Self {
+        Self(Some(target.get_ref().get_ref().get_ref().remote_addr()))
+    }
+}
+
+#[cfg(unix)]
+impl Connected<&tokio::net::UnixStream> for PeerAddr {
+    fn connect_info(_target: &tokio::net::UnixStream) -> Self {
+        Self(None)
+    }
+}
+
+/// The address of the client
+///
+/// If the API has trusted proxies, the peer address has already been resolved
+/// from their headers. Otherwise it is the peer address, as forwarding headers
+/// can be sent by clients themselves
+pub(crate) fn client_ip<B>(request: &Request<B>) -> Option<IpAddr> {
+    parts_client_ip(request.extensions())
+}
+
+/// Like `client_ip`, for extractors that only have the parts of the request
+pub(crate) fn parts_client_ip(extensions: &Extensions) -> Option<IpAddr> {
+    parts_peer_ip(extensions)
+}
+
+/// The address that the request was received from, which could be a reverse proxy
+pub(crate) fn peer_ip<B>(request: &Request<B>) -> Option<IpAddr> {
+    parts_peer_ip(request.extensions())
+}
+
+fn parts_peer_ip(extensions: &Extensions) -> Option<IpAddr> {
+    extensions
+        .get::<ConnectInfo<PeerAddr>>()
+        .and_then(|ConnectInfo(PeerAddr(addr))| addr.map(|addr| addr.ip()))
+}
+
+/// The first address in `X-Forwarded-For`, which clients can forge
+pub(crate) fn forwarded_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get("X-Forwarded-For")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Only implemented for the body of axum responses, so that rejections can have a body
+impl<ReqBody> AuthorizeRequest<ReqBody> for RateLimiter<BoxBody>
+where
+    ReqBody: HttpBody,
+{
+    type ResponseBody = BoxBody;
+
+    fn authorize(
+        &mut self,
+        request: &mut Request<ReqBody>,
+    ) -> Result<(), Response<Self::ResponseBody>> {
+        let Some((&route, limit)) = self.limits.routes.get_key_value(request.uri().path()) else {
+            return Ok(());
+        };
+        let ip = client_ip(request);
+        let token = self
+            .limits
+            .token_header
+            .as_ref()
+            .and_then(|header| request.headers().get(header))
+            .cloned();
+
+        let keys: Vec<_> = ip
+            .map(ClientKey::Ip)
+            .into_iter()
+            .chain(token.map(ClientKey::Token))
+            .collect();
+        let (status, result) = self.try_take(route, limit, &keys);
+        let Err(retry_after) = result else {
+            if let Some(status) = status {
+                request.extensions_mut().insert(status);
+            }
+            return Ok(());
+        };
+
+        warn!(
+            target: log_targets::SECURITY,
+            "Rate limit of {route} hit by {}",
+            ip.map(|ip| ip.to_string()).unwrap_or("an unknown address".into())
+        );
+        let mut response = Rejection::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "Too many requests",
+        )
+        .set_retry_after(retry_after)
+        .negotiate(request.headers())
+        .into_response();
+        if let Some(status) = status {
+            status.add_headers(response.headers_mut());
+        }
+        Err(response)
+    }
+}
+
+/// Sends back the status of the rate limit that `RateLimiter` left on the request
+///
+/// Must be layered inside of the rate limiter, so that it sees the request after it
+pub async fn add_rate_limit_headers<B>(request: Request<B>, next: Next<B>) -> Response<BoxBody> {
+    let status = request.extensions().get::<RateLimitStatus>().copied();
+    let mut response = next.run(request).await;
+    if let Some(status) = status {
+        status.add_headers(response.headers_mut());
+    }
+    response
+}
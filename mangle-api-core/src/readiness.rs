@@ -0,0 +1,304 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, MethodRouter},
+    Json, Router,
+};
+use dashmap::DashMap;
+use futures::{
+    future::{join_all, BoxFuture},
+    FutureExt,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{watch, Mutex},
+    time::timeout,
+};
+
+/// Tracks whether this node should be receiving new players
+///
+/// A node is ready when every registered check is ready and the node is not draining
+pub struct Readiness {
+    checks: DashMap<&'static str, bool>,
+    draining: AtomicBool,
+    ready_sender: watch::Sender<bool>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self {
+            checks: DashMap::new(),
+            draining: AtomicBool::new(false),
+            ready_sender: watch::channel(true).0,
+        }
+    }
+}
+
+impl Readiness {
+    /// Adds a check, which starts off as not ready
+    pub fn register(&self, check: &'static str) {
+        self.checks.insert(check, false);
+        self.update();
+    }
+
+    pub fn set_ready(&self, check: &'static str, ready: bool) {
+        self.checks.insert(check, ready);
+        self.update();
+    }
+
+    /// Takes this node out of rotation until `undrain` is called, without
+    /// affecting players that are already connected
+    pub fn drain(&self) {
+        info!("Draining node");
+        self.draining.store(true, Ordering::Release);
+        self.update();
+    }
+
+    pub fn undrain(&self) {
+        info!("Undraining node");
+        self.draining.store(false, Ordering::Release);
+        self.update();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !self.is_draining() && self.checks.iter().all(|entry| *entry.value())
+    }
+
+    /// Receives the readiness of this node every time it changes
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.ready_sender.subscribe()
+    }
+
+    fn update(&self) {
+        let ready = self.is_ready();
+        self.ready_sender.send_if_modified(|old| {
+            let modified = *old != ready;
+            *old = ready;
+            modified
+        });
+    }
+}
+
+/// A route for load balancers and DNS health checks, which responds with
+/// 503 whenever the node is not ready
+pub fn health_route<S, B>() -> MethodRouter<S, B>
+where
+    S: AsRef<Readiness> + Send + Sync + Clone + 'static,
+    B: Send + Sync + axum::body::HttpBody + 'static,
+{
+    axum::routing::get(|State(state): State<S>| async move {
+        if state.as_ref().is_ready() {
+            (StatusCode::OK, "Ready")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "Not Ready")
+        }
+    })
+}
+
+type Probe = Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// The result of probing a single dependency
+///
+/// Errors are only logged, as `/readyz` is public
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProbeResult {
+    pub ok: bool,
+    pub latency_ms: u64,
+    /// Whether the node is only ready while this probe succeeds
+    pub required: bool,
+}
+
+/// The body of `/readyz`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HealthReport {
+    /// Whether every required dependency is reachable and the node is not draining
+    pub ready: bool,
+    pub draining: bool,
+    pub dependencies: BTreeMap<String, ProbeResult>,
+}
+
+struct RegisteredProbe {
+    name: String,
+    probe: Probe,
+    required: bool,
+}
+
+/// Probes of the dependencies of this node, such as databases and siblings,
+/// which are served at `/healthz` and `/readyz`
+///
+/// `/healthz` only reports that the server is up, while `/readyz` runs every
+/// probe and responds with 503 if any required probe fails. Reports are
+/// cached, so that requests to `/readyz` cannot flood the dependencies
+pub struct HealthProbes {
+    probes: Vec<RegisteredProbe>,
+    timeout: Duration,
+    cache_duration: Duration,
+    readiness: Option<&'static Readiness>,
+    cached: Mutex<Option<(Instant, HealthReport)>>,
+}
+
+impl Default for HealthProbes {
+    fn default() -> Self {
+        Self {
+            probes: Vec::new(),
+            timeout: Duration::from_secs(5),
+            cache_duration: Duration::from_secs(5),
+            readiness: None,
+            cached: Mutex::new(None),
+        }
+    }
+}
+
+impl HealthProbes {
+    /// Adds a probe that the node needs to be ready, which fails if it returns
+    /// an error or times out
+    pub fn add_probe<F, Fut>(self, name: impl Into<String>, probe: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.push_probe(name.into(), probe, true)
+    }
+
+    /// Adds a probe that is reported without affecting readiness, such as of
+    /// a sibling, whose failure should not take every other node out of
+    /// rotation with it
+    pub fn add_informational_probe<F, Fut>(self, name: impl Into<String>, probe: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.push_probe(name.into(), probe, false)
+    }
+
+    fn push_probe<F, Fut>(mut self, name: String, probe: F, required: bool) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.probes.push(RegisteredProbe {
+            name,
+            probe: Box::new(move || probe().boxed()),
+            required,
+        });
+        self
+    }
+
+    /// How long a report is served before the probes are run again
+    pub fn set_cache_duration(mut self, cache_duration: Duration) -> Self {
+        self.cache_duration = cache_duration;
+        self
+    }
+
+    /// How long each probe may take before it counts as failed
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Also reports the node as not ready while it is draining or any of its checks are not ready
+    pub fn set_readiness(mut self, readiness: &'static Readiness) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    /// Runs every probe at once, unless a recent report is cached
+    ///
+    /// Readiness and draining are always current
+    pub async fn check(&self) -> HealthReport {
+        // Held while probing, so that concurrent requests wait for one report
+        let mut cached = self.cached.lock().await;
+        let dependencies = match &*cached {
+            Some((checked_at, report)) if checked_at.elapsed() < self.cache_duration => {
+                report.dependencies.clone()
+            }
+            _ => {
+                let dependencies = self.probe_all().await;
+                let report = self.report(dependencies.clone());
+                *cached = Some((Instant::now(), report));
+                dependencies
+            }
+        };
+        drop(cached);
+        self.report(dependencies)
+    }
+
+    fn report(&self, dependencies: BTreeMap<String, ProbeResult>) -> HealthReport {
+        let draining = self
+            .readiness
+            .map(Readiness::is_draining)
+            .unwrap_or_default();
+        HealthReport {
+            ready: self.readiness.map(Readiness::is_ready).unwrap_or(true)
+                && dependencies.values().all(|x| x.ok || !x.required),
+            draining,
+            dependencies,
+        }
+    }
+
+    async fn probe_all(&self) -> BTreeMap<String, ProbeResult> {
+        let results = join_all(self.probes.iter().map(
+            |RegisteredProbe {
+                 name,
+                 probe,
+                 required,
+             }| async move {
+                let start = Instant::now();
+                let result = match timeout(self.timeout, probe()).await {
+                    Ok(x) => x,
+                    Err(_) => Err(Error::msg("Timed out")),
+                };
+                let latency_ms = start.elapsed().as_millis() as u64;
+                if let Err(e) = &result {
+                    warn!(target: "health", "Probe of {name} failed: {e:#}");
+                }
+                let result = ProbeResult {
+                    ok: result.is_ok(),
+                    latency_ms,
+                    required: *required,
+                };
+                (name.clone(), result)
+            },
+        ))
+        .await;
+        results.into_iter().collect()
+    }
+
+    /// The paths of the routes, which are public
+    pub(crate) const PATHS: [&'static str; 2] = ["/healthz", "/readyz"];
+
+    pub(crate) fn add_to<S>(self, router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let probes = Arc::new(self);
+        router.route("/healthz", get(|| async { "OK" })).route(
+            "/readyz",
+            get(move || async move {
+                let report = probes.check().await;
+                let status = if report.ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                (status, Json(report))
+            }),
+        )
+    }
+}
@@ -0,0 +1,40 @@
+use serde::Serializer;
+use serde_json::Value;
+
+const REDACTED: &str = "<redacted>";
+/// Object keys that hold credentials, such as login tokens, in any case
+const SECRET_KEYS: [&str; 4] = ["token", "secret", "password", "authorization"];
+
+/// Serializes a secret without revealing it, for use with `#[serde(serialize_with = "redact")]`
+///
+/// Empty secrets are left empty, so that it is still clear whether a secret was set
+pub fn redact<T, S>(secret: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<str>,
+    S: Serializer,
+{
+    if secret.as_ref().is_empty() {
+        serializer.serialize_str("")
+    } else {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+/// Replaces the values of every key that names a credential, however deeply
+/// nested, so that the JSON can be logged
+pub fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = key.to_ascii_lowercase();
+                if SECRET_KEYS.iter().any(|x| key.contains(x)) {
+                    *value = Value::String(REDACTED.into());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
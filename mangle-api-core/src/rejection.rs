@@ -0,0 +1,118 @@
+use std::{borrow::Cow, time::Duration};
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+/// The body of every rejection sent as JSON, so that clients can tell
+/// rejections apart without parsing messages
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorEnvelope {
+    /// Stable and snake_case, such as `invalid_token`
+    pub error_code: Cow<'static, str>,
+    pub message: Cow<'static, str>,
+    /// In seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum RejectionFormat {
+    #[default]
+    Json,
+    /// Just the message, for browsers
+    Text,
+}
+
+impl RejectionFormat {
+    /// Picks text for clients that accept html or plain text but not JSON,
+    /// such as browsers, and JSON for everyone else
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let Some(accept) = headers.get(header::ACCEPT).and_then(|x| x.to_str().ok()) else {
+            return Self::Json;
+        };
+        if accept.contains("application/json") {
+            Self::Json
+        } else if accept.contains("text/html") || accept.contains("text/plain") {
+            Self::Text
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// A rejected request, sent as an `ErrorEnvelope` or as text
+pub struct Rejection {
+    status: StatusCode,
+    envelope: ErrorEnvelope,
+    format: RejectionFormat,
+}
+
+impl Rejection {
+    pub fn new(
+        status: StatusCode,
+        error_code: &'static str,
+        message: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        Self {
+            status,
+            envelope: ErrorEnvelope {
+                error_code: error_code.into(),
+                message: message.into(),
+                retry_after: None,
+            },
+            format: RejectionFormat::Json,
+        }
+    }
+
+    /// Also sets the `Retry-After` header, rounding up to the next second
+    pub fn set_retry_after(mut self, retry_after: Duration) -> Self {
+        self.envelope.retry_after = Some(retry_after.as_secs_f64().ceil() as u64);
+        self
+    }
+
+    pub fn set_format(mut self, format: RejectionFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the format to the one preferred by the `Accept` header
+    pub fn negotiate(self, headers: &HeaderMap) -> Self {
+        self.set_format(RejectionFormat::negotiate(headers))
+    }
+
+    pub fn get_envelope(&self) -> &ErrorEnvelope {
+        &self.envelope
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let retry_after = self.envelope.retry_after;
+        let mut response = match self.format {
+            RejectionFormat::Json => (self.status, Json(self.envelope)).into_response(),
+            RejectionFormat::Text => {
+                (self.status, self.envelope.message.into_owned()).into_response()
+            }
+        };
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+/// A fallback that responds to unknown paths with a `not_found` rejection
+pub async fn not_found(headers: HeaderMap) -> Rejection {
+    Rejection::new(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        "Nothing is at this path",
+    )
+    .negotiate(&headers)
+}
@@ -0,0 +1,130 @@
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use axum::http::{header::HeaderName, Request, Response};
+use regex::RegexSet;
+use tokio::sync::watch;
+use tower::{Layer, Service};
+use tower_http::cors::{AllowMethods, AllowOrigin, CorsLayer, ResponseFuture};
+
+use crate::cors::{CorsConfig, ALWAYS_EXPOSED};
+
+/// A handle to settings that can be replaced while the server runs, such as
+/// by the `reload` command
+///
+/// Clones share the same settings
+pub struct ReloadableConfig<T> {
+    sender: Arc<watch::Sender<Arc<T>>>,
+}
+
+impl<T> Clone for ReloadableConfig<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> ReloadableConfig<T> {
+    pub fn new(settings: T) -> Self {
+        Self {
+            sender: Arc::new(watch::channel(Arc::new(settings)).0),
+        }
+    }
+
+    /// The current settings
+    pub fn get(&self) -> Arc<T> {
+        self.sender.borrow().clone()
+    }
+
+    /// Replaces the settings, notifying every subscriber
+    pub fn reload(&self, settings: T) {
+        self.sender.send_replace(Arc::new(settings));
+    }
+
+    /// Receives the settings every time they are reloaded
+    pub fn subscribe(&self) -> watch::Receiver<Arc<T>> {
+        self.sender.subscribe()
+    }
+}
+
+/// The settings of the web server that can be reloaded
+#[derive(Clone)]
+pub struct HttpSettings {
+    cors: CorsLayer,
+    /// Regexes of paths that need no API token, on top of those set in code
+    public_paths: RegexSet,
+}
+
+impl HttpSettings {
+    pub fn new(
+        cors_allowed_methods: impl Into<AllowMethods>,
+        cors_allowed_origins: impl Into<AllowOrigin>,
+        public_paths: &[String],
+    ) -> Result<Self> {
+        Ok(Self {
+            cors: CorsLayer::new()
+                .allow_methods(cors_allowed_methods)
+                .allow_origin(cors_allowed_origins)
+                .expose_headers(ALWAYS_EXPOSED.map(HeaderName::from_static)),
+            public_paths: RegexSet::new(public_paths).context("Parsing public paths")?,
+        })
+    }
+
+    /// Like `new`, but with every CORS setting, such as allowed headers
+    pub fn from_cors_config(cors: &CorsConfig, public_paths: &[String]) -> Result<Self> {
+        Ok(Self {
+            cors: cors.to_layer()?,
+            public_paths: RegexSet::new(public_paths).context("Parsing public paths")?,
+        })
+    }
+
+    pub(crate) fn is_public(&self, path: &str) -> bool {
+        self.public_paths.is_match(path)
+    }
+}
+
+/// Applies the CORS settings that are current when each request arrives
+#[derive(Clone)]
+pub(crate) struct ReloadableCorsLayer(pub(crate) ReloadableConfig<HttpSettings>);
+
+impl<S> Layer<S> for ReloadableCorsLayer {
+    type Service = ReloadableCors<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ReloadableCors {
+            inner,
+            settings: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ReloadableCors<S> {
+    inner: S,
+    settings: ReloadableConfig<HttpSettings>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ReloadableCors<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // Only the service that was polled is ready, so the clone is kept for the next request
+        let inner = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, inner);
+        self.settings.get().cors.layer(inner).call(req)
+    }
+}
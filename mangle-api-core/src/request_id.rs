@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use axum::{
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tracing::Span;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const REQUEST_ID_SIZE: usize = 16;
+/// Incoming IDs that are longer are replaced
+const MAX_REQUEST_ID_SIZE: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+/// Identifies a request in the logs it makes and in its response, as the
+/// `X-Request-Id` header
+///
+/// Available from the extensions of each request
+#[derive(Clone, Debug)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The ID of the request that the current task is handling, if any
+pub fn current_request_id() -> Option<RequestId> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Whether an incoming ID can be used as it is, which it cannot if it could
+/// forge or break log lines
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_SIZE
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Gives every request an ID, reusing the `X-Request-Id` of the request if valid
+pub(crate) async fn propagate_request_id<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let id = match request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| is_valid(x))
+    {
+        Some(id) => id.into(),
+        None => thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(REQUEST_ID_SIZE)
+            .map(char::from)
+            .collect::<String>()
+            .into(),
+    };
+    let id = RequestId(id);
+    request.extensions_mut().insert(id.clone());
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// The span of each request in the trace layer, which includes its ID
+pub(crate) fn make_span<B>(request: &Request<B>) -> Span {
+    let request_id = request.extensions().get::<RequestId>();
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id = request_id.map_or("", RequestId::as_str),
+    )
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{boxed, Bytes, Full},
+    http::{header::HeaderName, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::error;
+use parking_lot::Mutex;
+
+struct CachedResponse {
+    expires: Instant,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    fn to_response(&self) -> Response {
+        let mut response = Response::new(boxed(Full::from(self.body.clone())));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+/// Path and query, then the values of the vary headers
+type CacheKey = (String, Vec<Option<HeaderValue>>);
+
+#[derive(Default)]
+struct Entries {
+    responses: HashMap<CacheKey, CachedResponse>,
+    /// Every response lives as long, so they expire in the order they were
+    /// inserted. Keys whose response was replaced or removed are skipped once
+    /// they reach the front
+    expiry_order: VecDeque<(Instant, CacheKey)>,
+}
+
+impl Entries {
+    /// Removes the key at the front of the expiry order, along with its
+    /// response if it was not replaced since
+    ///
+    /// Returns false if there were no keys left
+    fn pop_oldest(&mut self) -> bool {
+        let Some((expires, key)) = self.expiry_order.pop_front() else {
+            return false;
+        };
+        if self
+            .responses
+            .get(&key)
+            .map_or(false, |x| x.expires == expires)
+        {
+            self.responses.remove(&key);
+        }
+        true
+    }
+}
+
+/// An in memory cache of the successful GET responses of a route, given to
+/// the API builder as `RouteLayer::Cache`
+///
+/// Responses are keyed by path, query and the headers given to
+/// `add_vary_header`, so the route must respond the same to every client
+/// that sends the same request
+#[derive(Clone)]
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    vary: Vec<HeaderName>,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            vary: Vec::new(),
+            entries: Default::default(),
+        }
+    }
+
+    /// Caches a separate response for each value of this header
+    pub fn add_vary_header(mut self, header: HeaderName) -> Self {
+        self.vary.push(header);
+        self
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Response> {
+        let mut entries = self.entries.lock();
+        let entry = entries.responses.get(key)?;
+        if entry.expires > Instant::now() {
+            return Some(entry.to_response());
+        }
+        entries.responses.remove(key);
+        None
+    }
+
+    fn insert(&self, key: CacheKey, response: CachedResponse) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        let now = Instant::now();
+        while entries
+            .expiry_order
+            .front()
+            .map_or(false, |(expires, _)| *expires <= now)
+        {
+            entries.pop_oldest();
+        }
+        // Evicts whichever entries would have expired first
+        while entries.responses.len() >= self.max_entries && !entries.responses.contains_key(&key) {
+            if !entries.pop_oldest() {
+                break;
+            }
+        }
+        entries
+            .expiry_order
+            .push_back((response.expires, key.clone()));
+        entries.responses.insert(key, response);
+    }
+}
+
+/// Serves GET requests from the cache if possible, caching successful responses
+pub(crate) async fn serve_cached<B>(
+    cache: ResponseCache,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if request.method() != Method::GET {
+        return next.run(request).await;
+    }
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    let vary = cache
+        .vary
+        .iter()
+        .map(|header| request.headers().get(header).cloned())
+        .collect();
+    let key = (path, vary);
+    if let Some(response) = cache.get(&key) {
+        return response;
+    }
+
+    let response = next.run(request).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Buffering the response to {}: {e}", key.0);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let cached = CachedResponse {
+        expires: Instant::now() + cache.ttl,
+        status: parts.status,
+        headers: parts.headers,
+        body,
+    };
+    let response = cached.to_response();
+    cache.insert(key, cached);
+    response
+}
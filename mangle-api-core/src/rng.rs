@@ -0,0 +1,27 @@
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, thread_rng, RngCore, SeedableRng};
+
+/// The source of randomness for generated ids and tokens
+///
+/// Production code should use the default, which is `thread_rng`. A seeded
+/// source makes the generated values reproducible, such as in tests
+#[derive(Default)]
+pub struct SharedRng(Option<Mutex<StdRng>>);
+
+impl SharedRng {
+    pub fn thread() -> Self {
+        Self(None)
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self(Some(Mutex::new(StdRng::seed_from_u64(seed))))
+    }
+
+    /// Runs the given function with the underlying generator
+    pub fn with<T>(&self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        match &self.0 {
+            Some(rng) => f(&mut *rng.lock()),
+            None => f(&mut thread_rng()),
+        }
+    }
+}
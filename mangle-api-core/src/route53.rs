@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use anyhow::{Context, Error};
+use aws_sdk_route53::{
+    model::{Change, ChangeAction, ChangeBatch, ResourceRecord, ResourceRecordSet, RrType},
+    Client,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::readiness::Readiness;
+
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// The weighted record that points at this node
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Route53Config {
+    pub hosted_zone_id: String,
+    /// The domain name of the record, such as `api.example.com`
+    pub record_name: String,
+    #[serde(default = "record_type")]
+    pub record_type: String,
+    /// Distinguishes the record of this node from those of its siblings
+    pub set_identifier: String,
+    /// The address of this node
+    pub value: String,
+    /// The weight of the record whenever this node is ready
+    #[serde(default = "weight")]
+    pub weight: i64,
+    #[serde(default = "ttl")]
+    pub ttl: i64,
+    /// A Route53 health check to attach to the record, which should poll the health route
+    #[serde(default = "Default::default")]
+    pub health_check_id: Option<String>,
+}
+
+fn record_type() -> String {
+    "A".into()
+}
+
+fn weight() -> i64 {
+    100
+}
+
+fn ttl() -> i64 {
+    60
+}
+
+async fn set_weight(client: &Client, config: &Route53Config, weight: i64) -> Result<(), Error> {
+    let record_set = ResourceRecordSet::builder()
+        .name(&config.record_name)
+        .r#type(RrType::from(config.record_type.as_str()))
+        .set_identifier(&config.set_identifier)
+        .weight(weight)
+        .ttl(config.ttl)
+        .resource_records(ResourceRecord::builder().value(&config.value).build())
+        .set_health_check_id(config.health_check_id.clone())
+        .build();
+
+    client
+        .change_resource_record_sets()
+        .hosted_zone_id(&config.hosted_zone_id)
+        .change_batch(
+            ChangeBatch::builder()
+                .changes(
+                    Change::builder()
+                        .action(ChangeAction::Upsert)
+                        .resource_record_set(record_set)
+                        .build(),
+                )
+                .build(),
+        )
+        .send()
+        .await
+        .context("Updating weighted record")?;
+
+    Ok(())
+}
+
+/// Keeps the weight of the record of this node in sync with its readiness
+///
+/// The weight is set to 0 whenever the node is not ready, so that DNS stops
+/// sending new players to it. Never returns
+pub async fn sync_route53_weight(client: Client, config: Route53Config, readiness: &Readiness) {
+    let mut ready_recv = readiness.subscribe();
+
+    loop {
+        let ready = *ready_recv.borrow_and_update();
+        let weight = if ready { config.weight } else { 0 };
+
+        match set_weight(&client, &config, weight).await {
+            Ok(()) => {
+                info!(target: "route53", "Set weight of {} to {weight}", config.record_name);
+                if ready_recv.changed().await.is_err() {
+                    return std::future::pending().await;
+                }
+            }
+            Err(e) => {
+                error!(target: "route53", "{e:?}");
+                sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
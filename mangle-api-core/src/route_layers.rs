@@ -0,0 +1,89 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    body::Body,
+    http::Request,
+    response::IntoResponse,
+    routing::{MethodRouter, Route},
+};
+use tower::{Layer, Service};
+
+use crate::{
+    auth::{
+        roles::{require_role, Role},
+        signed_requests::RequestVerifier,
+        token::HeaderTokenConfig,
+    },
+    response_cache::ResponseCache,
+};
+
+type ApplyLayer<S> = Box<dyn FnOnce(MethodRouter<S>) -> MethodRouter<S> + Send>;
+
+/// A setting or layer that applies to a single route, given to the API
+/// builder with `add_route_layers`
+pub enum RouteLayer<S> {
+    /// The route does not need the API token
+    Public,
+    /// Replaces the request timeout of the API for this route
+    Timeout(Duration),
+    /// Replaces the max body size of the API for this route
+    MaxBodySize(usize),
+    /// Caches the successful GET responses of this route
+    Cache(ResponseCache),
+    /// Requests must be signed with one of the keys of the verifier, instead
+    /// of having the API token
+    Signed(RequestVerifier),
+    /// Requests must have a token with a role, instead of the API token, as
+    /// made with `RouteLayer::require_role`
+    Role(ApplyLayer<S>),
+    /// Any other tower layer, as made with `RouteLayer::custom`
+    Custom(ApplyLayer<S>),
+}
+
+impl<S> RouteLayer<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    /// Wraps the route in `layer`, inside the layers of the API
+    pub fn custom<L>(layer: L) -> Self
+    where
+        L: Layer<Route<Body>> + Clone + Send + 'static,
+        L::Service: Service<Request<Body>> + Clone + Send + 'static,
+        <L::Service as Service<Request<Body>>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request<Body>>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+    {
+        Self::Custom(Box::new(move |method: MethodRouter<S>| method.layer(layer)))
+    }
+
+    /// Rejects requests whose token of `R::Config` lacks the role `R`
+    pub fn require_role<R>(state: S) -> Self
+    where
+        R: Role,
+        S: AsRef<<R::Config as HeaderTokenConfig>::Granter>,
+    {
+        Self::Role(Box::new(move |method: MethodRouter<S>| {
+            method.layer(axum::middleware::from_fn_with_state(
+                state,
+                require_role::<S, R, Body>,
+            ))
+        }))
+    }
+}
+
+/// A regex that matches the same paths as `route`, for bearer auth
+pub(crate) fn route_regex(route: &str) -> String {
+    let segments: Vec<_> = route
+        .split('/')
+        .map(|segment| {
+            if segment.starts_with(':') {
+                "[^/]+".to_string()
+            } else if segment.starts_with('*') {
+                ".*".to_string()
+            } else {
+                regex::escape(segment)
+            }
+        })
+        .collect();
+    format!("^{}$", segments.join("/"))
+}
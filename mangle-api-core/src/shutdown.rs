@@ -0,0 +1,159 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::extract::ws::CloseFrame;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{watch, Notify},
+    time::timeout,
+};
+
+use crate::ws::WebSocketCode;
+
+/// How WebSocket sessions are closed when the server stops
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ShutdownConfig {
+    /// The code of the close frame sent to each client
+    #[serde(default = "close_code")]
+    pub close_code: u16,
+    #[serde(default = "close_reason")]
+    pub close_reason: String,
+    /// How long sessions are given to send their last messages and close
+    #[serde(default = "grace_period")]
+    pub grace_period: Duration,
+    /// If set, how long the server waits for requests and sessions to finish
+    /// before it stops, while refusing new requests
+    #[serde(default = "Default::default")]
+    pub drain_timeout: Option<Duration>,
+}
+
+fn close_code() -> u16 {
+    WebSocketCode::GoingAway as u16
+}
+
+fn close_reason() -> String {
+    "Server Shutting Down".into()
+}
+
+fn grace_period() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            close_code: close_code(),
+            close_reason: close_reason(),
+            grace_period: grace_period(),
+            drain_timeout: None,
+        }
+    }
+}
+
+/// Tells every WebSocket session that the server is stopping
+///
+/// Once notified, receiving from a session returns `WsError::ShuttingDown`,
+/// but sending still works, so that handlers can flush their last messages.
+/// The close frame is sent once the session is dropped
+pub struct ShutdownNotifier {
+    signal: watch::Sender<bool>,
+    active: AtomicUsize,
+    drained: Notify,
+    close_code: u16,
+    close_reason: Cow<'static, str>,
+}
+
+impl Default for ShutdownNotifier {
+    fn default() -> Self {
+        Self::new(&ShutdownConfig::default())
+    }
+}
+
+impl ShutdownNotifier {
+    pub fn new(config: &ShutdownConfig) -> Self {
+        Self {
+            signal: watch::channel(false).0,
+            active: AtomicUsize::new(0),
+            drained: Notify::new(),
+            close_code: config.close_code,
+            close_reason: config.close_reason.clone().into(),
+        }
+    }
+
+    /// Starts tracking a session, until the returned listener is dropped
+    pub fn listen(self: &Arc<Self>) -> ShutdownListener {
+        self.active.fetch_add(1, Ordering::AcqRel);
+        ShutdownListener {
+            notifier: self.clone(),
+            signal: self.signal.subscribe(),
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    pub fn get_active_sessions(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Notifies every session, then waits up to `grace_period` for all of them to close
+    ///
+    /// Returns how many sessions were still open when the grace period ended
+    pub async fn shutdown(&self, grace_period: Duration) -> usize {
+        self.signal.send_replace(true);
+        let _ = timeout(grace_period, async {
+            loop {
+                // Created before checking, so that a session closing in between is not missed
+                let drained = self.drained.notified();
+                if self.get_active_sessions() == 0 {
+                    break;
+                }
+                drained.await;
+            }
+        })
+        .await;
+        self.get_active_sessions()
+    }
+}
+
+/// Held by each session for as long as it is open
+pub struct ShutdownListener {
+    notifier: Arc<ShutdownNotifier>,
+    signal: watch::Receiver<bool>,
+}
+
+impl ShutdownListener {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Resolves once the server starts shutting down
+    pub async fn wait(&mut self) {
+        while !*self.signal.borrow_and_update() {
+            // The notifier outlives every listener, so this never fails
+            let _ = self.signal.changed().await;
+        }
+    }
+
+    pub fn close_frame(&self) -> CloseFrame<'static> {
+        CloseFrame {
+            code: self.notifier.close_code,
+            reason: self.notifier.close_reason.clone(),
+        }
+    }
+}
+
+impl Drop for ShutdownListener {
+    fn drop(&mut self) {
+        if self.notifier.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.notifier.drained.notify_waiters();
+        }
+    }
+}
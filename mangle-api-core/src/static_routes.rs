@@ -0,0 +1,357 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{read, read_dir},
+    path::{Component, Path as FsPath, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Error, Result};
+use axum::{
+    extract::Path,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use bytes::Bytes;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// Redirects requests for `from` to `to`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RedirectConfig {
+    pub from: String,
+    pub to: String,
+    /// Must be a redirection status, such as 301 or 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    307
+}
+
+/// Serves the contents of `file` at `path`, as read once at startup
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AliasConfig {
+    pub path: String,
+    pub file: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+fn default_content_type() -> String {
+    "application/octet-stream".into()
+}
+
+/// Serves the files in `dir` under `path`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StaticDirConfig {
+    pub path: String,
+    pub dir: String,
+    /// How long clients may cache the files, in seconds
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+    /// Reads every file once at startup instead of on each request
+    #[serde(default = "Default::default")]
+    pub preload: bool,
+}
+
+fn default_max_age() -> u64 {
+    60 * 60
+}
+
+/// A directory of files served under a path, with the content type of each
+/// file guessed from its extension
+pub struct StaticDir {
+    path: String,
+    dir: PathBuf,
+    cache_control: HeaderValue,
+    /// Keyed by the path of each file relative to `dir`, with `/` separators
+    preloaded: Option<HashMap<String, Bytes>>,
+}
+
+impl StaticDir {
+    pub fn new(path: impl Into<String>, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            dir: dir.into(),
+            cache_control: cache_control(Duration::from_secs(default_max_age())),
+            preloaded: None,
+        }
+    }
+
+    pub fn from_config(config: &StaticDirConfig) -> Result<Self> {
+        let dir = Self::new(config.path.clone(), config.dir.clone())
+            .set_max_age(Duration::from_secs(config.max_age));
+        if config.preload {
+            dir.preload()
+        } else {
+            Ok(dir)
+        }
+    }
+
+    /// How long clients may cache the files
+    pub fn set_max_age(mut self, max_age: Duration) -> Self {
+        self.cache_control = cache_control(max_age);
+        self
+    }
+
+    /// Reads every file into memory now, so files added or changed later are not served
+    pub fn preload(mut self) -> Result<Self> {
+        let mut preloaded = HashMap::new();
+        let mut pending = vec![(self.dir.clone(), String::new())];
+        while let Some((dir, prefix)) = pending.pop() {
+            for entry in read_dir(&dir).context(format!("Reading {dir:?}"))? {
+                let entry = entry.context(format!("Reading {dir:?}"))?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let relative = format!("{prefix}{name}");
+                if entry.file_type()?.is_dir() {
+                    pending.push((entry.path(), relative + "/"));
+                } else {
+                    let contents =
+                        read(entry.path()).context(format!("Reading {:?}", entry.path()))?;
+                    preloaded.insert(relative, contents.into());
+                }
+            }
+        }
+        self.preloaded = Some(preloaded);
+        Ok(self)
+    }
+
+    async fn serve(&self, file: &str) -> Response {
+        // Anything but plain names could escape the directory
+        if !FsPath::new(file)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        let contents = match &self.preloaded {
+            Some(preloaded) => match preloaded.get(file) {
+                Some(contents) => contents.clone(),
+                None => return StatusCode::NOT_FOUND.into_response(),
+            },
+            None => match tokio::fs::read(self.dir.join(file)).await {
+                Ok(contents) => contents.into(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return StatusCode::NOT_FOUND.into_response()
+                }
+                Err(e) => {
+                    error!("Reading {file} in {:?}: {e}", self.dir);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            },
+        };
+        (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(content_type(file)),
+                ),
+                (header::CACHE_CONTROL, self.cache_control.clone()),
+            ],
+            contents,
+        )
+            .into_response()
+    }
+}
+
+fn cache_control(max_age: Duration) -> HeaderValue {
+    format!("public, max-age={}", max_age.as_secs())
+        .try_into()
+        .expect("Formatting Cache-Control")
+}
+
+/// Guesses the content type of a file from its extension
+fn content_type(file: &str) -> &'static str {
+    let extension = file.rsplit_once('.').map(|(_, x)| x).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+struct Redirect {
+    from: String,
+    to: HeaderValue,
+    status: StatusCode,
+}
+
+struct Alias {
+    path: String,
+    content_type: HeaderValue,
+    contents: Bytes,
+}
+
+/// Public routes that are generated from configuration instead of code
+#[derive(Default)]
+pub struct StaticRoutes {
+    redirects: Vec<Redirect>,
+    aliases: Vec<Alias>,
+    dirs: Vec<StaticDir>,
+}
+
+impl StaticRoutes {
+    /// Validates every redirect and alias, reading the file of each alias
+    pub fn new(redirects: &[RedirectConfig], aliases: &[AliasConfig]) -> Result<Self> {
+        let mut paths = HashSet::new();
+        let mut check_path = |path: &str| {
+            if !path.starts_with('/') {
+                return Err(Error::msg(format!("{path} does not start with /")));
+            }
+            // Captures and wildcards would make the route match more than the path
+            if path.contains([':', '*']) {
+                return Err(Error::msg(format!("{path} is not a static path")));
+            }
+            if !paths.insert(path.to_string()) {
+                return Err(Error::msg(format!("{path} is routed more than once")));
+            }
+            Ok(())
+        };
+
+        let mut out = Self::default();
+        for redirect in redirects {
+            check_path(&redirect.from)?;
+            let status = StatusCode::from_u16(redirect.status)
+                .ok()
+                .filter(StatusCode::is_redirection)
+                .ok_or_else(|| {
+                    Error::msg(format!(
+                        "{} is not a redirection status, for {}",
+                        redirect.status, redirect.from
+                    ))
+                })?;
+            out.redirects.push(Redirect {
+                from: redirect.from.clone(),
+                to: HeaderValue::from_str(&redirect.to)
+                    .context(format!("Parsing redirect target of {}", redirect.from))?,
+                status,
+            });
+        }
+        for alias in aliases {
+            check_path(&alias.path)?;
+            out.aliases.push(Alias {
+                path: alias.path.clone(),
+                content_type: HeaderValue::from_str(&alias.content_type)
+                    .context(format!("Parsing content type of {}", alias.path))?,
+                contents: read(&alias.file)
+                    .context(format!("Reading {}", alias.file))?
+                    .into(),
+            });
+        }
+        Ok(out)
+    }
+
+    pub(crate) fn add_dir(&mut self, dir: StaticDir) {
+        self.dirs.push(dir);
+    }
+
+    pub(crate) fn take_dirs(&mut self) -> Vec<StaticDir> {
+        std::mem::take(&mut self.dirs)
+    }
+
+    /// Checks that each directory is under its own path, which no other static route is in
+    pub(crate) fn check_dirs(&self) -> Result<()> {
+        for (i, dir) in self.dirs.iter().enumerate() {
+            let path = &dir.path;
+            if !path.starts_with('/') || path.ends_with('/') {
+                return Err(Error::msg(format!(
+                    "{path} must start with / and not end with /"
+                )));
+            }
+            if path.contains([':', '*']) {
+                return Err(Error::msg(format!("{path} is not a static path")));
+            }
+            let prefix = format!("{path}/");
+            if let Some(other) = self
+                .paths()
+                .chain(self.dirs[i + 1..].iter().map(|x| x.path.as_str()))
+                .find(|other| {
+                    *other == path.as_str()
+                        || other.starts_with(&prefix)
+                        || prefix.starts_with(&format!("{other}/"))
+                })
+            {
+                return Err(Error::msg(format!("{path} overlaps with {other}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// The paths of redirects and aliases
+    pub(crate) fn paths(&self) -> impl Iterator<Item = &str> {
+        self.redirects
+            .iter()
+            .map(|x| x.from.as_str())
+            .chain(self.aliases.iter().map(|x| x.path.as_str()))
+    }
+
+    /// Regexes of every path served by these routes, which are all public
+    pub(crate) fn public_paths(&self) -> impl Iterator<Item = String> + '_ {
+        self.paths()
+            .map(|path| format!("^{}$", regex::escape(path)))
+            .chain(
+                self.dirs
+                    .iter()
+                    .map(|dir| format!("^{}/", regex::escape(&dir.path))),
+            )
+    }
+
+    pub(crate) fn add_to<S>(self, mut router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        for Redirect { from, to, status } in self.redirects {
+            router = router.route(
+                &from,
+                get(move || async move { (status, [(header::LOCATION, to)]) }),
+            );
+        }
+        for Alias {
+            path,
+            content_type,
+            contents,
+        } in self.aliases
+        {
+            router = router.route(
+                &path,
+                get(move || async move { ([(header::CONTENT_TYPE, content_type)], contents) }),
+            );
+        }
+        for dir in self.dirs {
+            let route = format!("{}/*file", dir.path);
+            let dir = Arc::new(dir);
+            router = router.route(
+                &route,
+                get(move |Path(file): Path<String>| async move {
+                    dir.serve(file.trim_start_matches('/')).await
+                }),
+            );
+        }
+        router
+    }
+}
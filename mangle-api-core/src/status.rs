@@ -0,0 +1,58 @@
+use std::{fmt::Display, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tasks::TaskStatus;
+
+/// What a running server reports to the `status` command
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatusResponse {
+    pub version: String,
+    pub uptime: Duration,
+    pub bind_addresses: Vec<String>,
+    pub ready: bool,
+    pub draining: bool,
+    /// How many of each kind of connection are open, such as WebSocket sessions
+    pub connections: Vec<(String, u64)>,
+    /// How far ahead the clock of each sibling is, in milliseconds
+    pub clock_skew: Vec<(String, i64)>,
+    /// The background tasks of the task manager
+    pub tasks: Vec<TaskStatus>,
+    /// The effective configuration, with secrets redacted
+    pub config: String,
+}
+
+impl Display for StatusResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.uptime.as_secs();
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(
+            f,
+            "Uptime: {}d {}h {}m {}s",
+            secs / 86400,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60
+        )?;
+        writeln!(f, "Bound to: {}", self.bind_addresses.join(", "))?;
+        writeln!(f, "Ready: {}", self.ready)?;
+        writeln!(f, "Draining: {}", self.draining)?;
+        writeln!(f, "Connections:")?;
+        for (kind, count) in &self.connections {
+            writeln!(f, "  {kind}: {count}")?;
+        }
+        writeln!(f, "Clock skew:")?;
+        for (domain, offset) in &self.clock_skew {
+            writeln!(f, "  {domain}: {offset:+}ms")?;
+        }
+        writeln!(f, "Tasks:")?;
+        for task in &self.tasks {
+            writeln!(
+                f,
+                "  {}: {} ({} restarts)",
+                task.name, task.state, task.restarts
+            )?;
+        }
+        write!(f, "Config: {}", self.config)
+    }
+}
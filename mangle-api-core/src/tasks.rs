@@ -0,0 +1,165 @@
+use std::{fmt::Display, future::Future, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use log::{error, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinHandle, time::sleep};
+
+use crate::crash::{panic_message, supervise};
+
+/// What happens when a background task stops
+#[derive(Clone, Copy, Debug)]
+pub enum RestartPolicy {
+    Never,
+    /// Restarts after the delay if the task failed or panicked
+    OnFailure(Duration),
+    /// Restarts after the delay whenever the task stops
+    Always(Duration),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    /// Waiting to be restarted
+    Restarting,
+    /// Stopped without an error, and will not be restarted
+    Finished,
+    /// Failed or panicked, and will not be restarted
+    Failed(String),
+}
+
+impl Display for TaskState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskState::Running => write!(f, "running"),
+            TaskState::Restarting => write!(f, "restarting"),
+            TaskState::Finished => write!(f, "finished"),
+            TaskState::Failed(e) => write!(f, "failed: {e}"),
+        }
+    }
+}
+
+/// What a background task is doing, as reported to the `status` command
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    pub restarts: u32,
+}
+
+/// A handle to the status of every background task of a `TaskManager`
+///
+/// Clones share the same statuses
+#[derive(Clone, Default)]
+pub struct TaskStatuses(Arc<Mutex<Vec<TaskStatus>>>);
+
+impl TaskStatuses {
+    pub fn get(&self) -> Vec<TaskStatus> {
+        self.0.lock().clone()
+    }
+
+    fn update(&self, index: usize, f: impl FnOnce(&mut TaskStatus)) {
+        f(&mut self.0.lock()[index]);
+    }
+}
+
+type MakeTask = Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send>;
+
+struct Task {
+    name: String,
+    policy: RestartPolicy,
+    make: MakeTask,
+}
+
+/// Named background tasks that run alongside the API, given to the API
+/// builder with `set_task_manager`
+///
+/// Unlike the concurrent future, a task stopping does not stop the server.
+/// Tasks are started by `run`, and restarted according to their policies
+#[derive(Default)]
+pub struct TaskManager {
+    tasks: Vec<Task>,
+    statuses: TaskStatuses,
+}
+
+impl TaskManager {
+    /// Adds a task, which is made again by `make` every time it is restarted
+    pub fn add_task<F, Fut>(
+        mut self,
+        name: impl Into<String>,
+        policy: RestartPolicy,
+        make: F,
+    ) -> Self
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        self.statuses.0.lock().push(TaskStatus {
+            name: name.clone(),
+            state: TaskState::Restarting,
+            restarts: 0,
+        });
+        self.tasks.push(Task {
+            name,
+            policy,
+            make: Box::new(move || Box::pin(make())),
+        });
+        self
+    }
+
+    /// The statuses of the tasks, such as for the control handler
+    pub fn statuses(&self) -> TaskStatuses {
+        self.statuses.clone()
+    }
+
+    pub(crate) fn start(self) -> Vec<JoinHandle<()>> {
+        self.tasks
+            .into_iter()
+            .enumerate()
+            .map(|(index, task)| tokio::spawn(run_task(task, index, self.statuses.clone())))
+            .collect()
+    }
+}
+
+async fn run_task(task: Task, index: usize, statuses: TaskStatuses) {
+    loop {
+        statuses.update(index, |status| status.state = TaskState::Running);
+        let result = match supervise((task.make)()).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(format!("{e:?}")),
+            Err(panic) => Err(format!("panicked: {}", panic_message(panic.as_ref()))),
+        };
+
+        let delay = match (task.policy, &result) {
+            (RestartPolicy::Always(delay), _) | (RestartPolicy::OnFailure(delay), Err(_)) => delay,
+            (_, Ok(())) => {
+                warn!("Background task {} finished", task.name);
+                statuses.update(index, |status| status.state = TaskState::Finished);
+                return;
+            }
+            (_, Err(e)) => {
+                error!("Background task {} failed: {e}", task.name);
+                statuses.update(index, |status| status.state = TaskState::Failed(e.clone()));
+                return;
+            }
+        };
+        match &result {
+            Ok(()) => warn!(
+                "Background task {} finished, restarting in {delay:?}",
+                task.name
+            ),
+            Err(e) => error!(
+                "Background task {} failed, restarting in {delay:?}: {e}",
+                task.name
+            ),
+        }
+        statuses.update(index, |status| {
+            status.state = TaskState::Restarting;
+            status.restarts += 1;
+        });
+        sleep(delay).await;
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use hyper::server::conn::AddrIncoming;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Socket level options for long lived connections
+///
+/// Some networks, such as those of mobile carriers, silently drop idle
+/// connections, which is only noticed with keepalive probes or a user timeout
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug)]
+pub struct TcpConfig {
+    /// How long a connection can be idle before keepalive probes are sent
+    #[serde(default = "Default::default")]
+    pub keepalive: Option<Duration>,
+    #[serde(default = "Default::default")]
+    pub nodelay: bool,
+    /// How long sent data can remain unacknowledged before the connection is
+    /// closed. Only supported on Linux
+    #[serde(default = "Default::default")]
+    pub user_timeout: Option<Duration>,
+}
+
+impl TcpConfig {
+    fn apply(&self, socket: SockRef) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        socket.set_tcp_user_timeout(self.user_timeout)?;
+        Ok(())
+    }
+
+    pub fn apply_to_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        self.apply(SockRef::from(stream))
+    }
+
+    /// Binds a listener for hyper with these options
+    ///
+    /// The user timeout is set on the listening socket, which accepted
+    /// connections inherit
+    pub fn bind_incoming(&self, addr: &SocketAddr) -> anyhow::Result<AddrIncoming> {
+        self.incoming(std::net::TcpListener::bind(addr)?)
+    }
+
+    /// Binds a listener for IPv4 and another for IPv6 on every interface,
+    /// returning each with the address it is bound to
+    ///
+    /// The IPv6 listener only accepts IPv6, so that it does not conflict with
+    /// the IPv4 listener on systems where IPv6 sockets also accept IPv4
+    pub fn bind_dual_stack(&self, port: u16) -> anyhow::Result<[(SocketAddr, AddrIncoming); 2]> {
+        let v4 = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+        let v6 = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(true)?;
+        // As std does when binding
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.bind(&v6.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+
+        Ok([
+            (v4, self.bind_incoming(&v4)?),
+            (v6, self.incoming(socket.into())?),
+        ])
+    }
+
+    fn incoming(&self, listener: std::net::TcpListener) -> anyhow::Result<AddrIncoming> {
+        listener.set_nonblocking(true)?;
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        SockRef::from(&listener).set_tcp_user_timeout(self.user_timeout)?;
+
+        let mut incoming = AddrIncoming::from_listener(TcpListener::from_std(listener)?)?;
+        incoming.set_nodelay(self.nodelay);
+        incoming.set_keepalive(self.keepalive);
+        Ok(incoming)
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{Context as _, Error};
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    propagation::TextMapPropagator,
+    sdk::{
+        propagation::TraceContextPropagator,
+        trace::{self, Sampler},
+        Resource,
+    },
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use serde::{Deserialize, Serialize};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TelemetryConfig {
+    /// The OTLP collector that traces are exported to over gRPC, such as `http://localhost:4317`
+    pub otlp_endpoint: String,
+    #[serde(default = "service_name")]
+    pub service_name: String,
+    /// The fraction of traces that are kept, from 0 to 1
+    #[serde(default = "sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+fn service_name() -> String {
+    "mangle-api".into()
+}
+
+fn sample_ratio() -> f64 {
+    1.0
+}
+
+/// Exports traces to the configured collector
+///
+/// Must be called from within a tokio runtime. Until this is called, spans
+/// are not recorded and trace contexts are not sent to siblings
+pub fn setup_telemetry(config: &TelemetryConfig) -> Result<(), Error> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&config.otlp_endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    config.sample_ratio,
+                ))))
+                .with_resource(Resource::new([KeyValue::new(
+                    "service.name",
+                    config.service_name.clone(),
+                )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+        .context("Installing OTLP pipeline")?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Flushes any spans that have not been exported yet
+pub fn shutdown_telemetry() {
+    if is_enabled() {
+        global::shutdown_tracer_provider();
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn tracer() -> BoxedTracer {
+    global::tracer("mangle-api")
+}
+
+/// The W3C trace context headers of a span, for sending it to another node
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct TraceContext(HashMap<String, String>);
+
+impl TraceContext {
+    /// The trace context of the span that is currently active
+    pub fn current() -> Self {
+        let mut headers = HashMap::new();
+        TraceContextPropagator::new().inject_context(&Context::current(), &mut headers);
+        Self(headers)
+    }
+
+    /// The context to start the spans of the receiving node with, so that they
+    /// are children of the sending span
+    pub fn to_context(&self) -> Context {
+        TraceContextPropagator::new().extract(&self.0)
+    }
+}
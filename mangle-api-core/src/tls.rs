@@ -1,5 +1,4 @@
 use std::{
-    net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{self, Poll},
@@ -12,24 +11,49 @@ use hyper::server::{
     conn::{AddrIncoming, AddrStream},
 };
 use log::error;
+use parking_lot::RwLock;
 use tokio_native_tls::{
     native_tls::{Identity, TlsAcceptor as InnerTlsAcceptor},
     TlsAcceptor as TlsAcceptorWrapper, TlsStream,
 };
 
+/// The TLS acceptor shared by every HTTPS server, whose identity can be
+/// swapped without restarting them
+///
+/// Connections that are already open keep the identity they were accepted with
+#[derive(Clone)]
+pub struct SharedTlsAcceptor(Arc<RwLock<Arc<TlsAcceptorWrapper>>>);
+
+impl SharedTlsAcceptor {
+    pub fn new(identity: Identity) -> anyhow::Result<Self> {
+        Ok(Self(Arc::new(RwLock::new(Arc::new(
+            TlsAcceptorWrapper::from(InnerTlsAcceptor::new(identity)?),
+        )))))
+    }
+
+    pub fn set_identity(&self, identity: Identity) -> anyhow::Result<()> {
+        *self.0.write() = Arc::new(TlsAcceptorWrapper::from(InnerTlsAcceptor::new(identity)?));
+        Ok(())
+    }
+
+    pub(crate) fn get(&self) -> Arc<TlsAcceptorWrapper> {
+        self.0.read().clone()
+    }
+}
+
 pub struct TlsAcceptor<'a> {
     incoming: AddrIncoming,
     acceptor_loop: Option<BoxFuture<'a, Result<TlsStream<AddrStream>, Error>>>,
-    tls_acceptor: Arc<TlsAcceptorWrapper>,
+    tls_acceptor: SharedTlsAcceptor,
 }
 
 impl<'a> TlsAcceptor<'a> {
-    pub fn new(identity: Identity, addr: &SocketAddr) -> anyhow::Result<Self> {
-        Ok(Self {
-            incoming: AddrIncoming::bind(addr)?,
+    pub fn new(tls_acceptor: SharedTlsAcceptor, incoming: AddrIncoming) -> Self {
+        Self {
+            incoming,
             acceptor_loop: None,
-            tls_acceptor: Arc::new(TlsAcceptorWrapper::from(InnerTlsAcceptor::new(identity)?)),
-        })
+            tls_acceptor,
+        }
     }
 }
 
@@ -44,7 +68,7 @@ impl<'a> Accept for TlsAcceptor<'a> {
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
         if let Some(acceptor_loop) = &mut self.acceptor_loop {
             let Poll::Ready(result) = acceptor_loop.as_mut().poll(cx) else {
-                return Poll::Pending
+                return Poll::Pending;
             };
 
             self.acceptor_loop = None;
@@ -67,7 +91,7 @@ impl<'a> Accept for TlsAcceptor<'a> {
             Poll::Pending => return Poll::Pending,
         };
 
-        let tls = self.tls_acceptor.clone();
+        let tls = self.tls_acceptor.get();
         self.acceptor_loop = Some(Box::pin(async move {
             tls.accept(stream).await.map_err(Into::into)
         }));
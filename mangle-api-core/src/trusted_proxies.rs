@@ -0,0 +1,234 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ip_filter::Cidr,
+    rate_limit::{peer_ip, PeerAddr},
+};
+
+/// The header that trusted proxies write the address of the client to
+///
+/// Only this header is read, as proxies pass on the other one unchanged from
+/// the client
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedHeader {
+    /// Written by most load balancers, such as AWS ALB and nginx
+    #[default]
+    XForwardedFor,
+    /// The standard header of RFC 7239
+    Forwarded,
+}
+
+/// Proxies whose forwarding header gives the address of the client
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+    pub ranges: Vec<Cidr>,
+    pub header: ForwardedHeader,
+}
+
+/// The addresses that a request was forwarded through, starting with the
+/// client
+///
+/// Hops that cannot be parsed, such as `unknown` or obfuscated identifiers,
+/// are `None`
+fn forwarded_chain(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<SocketAddr>> {
+    match header {
+        ForwardedHeader::Forwarded => headers
+            .get_all("Forwarded")
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node.trim().trim_matches('"')))
+            })
+            .collect(),
+        ForwardedHeader::XForwardedFor => headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .map(|node| parse_node(node.trim()))
+            .collect(),
+    }
+}
+
+/// Parses an address with an optional port, such as `192.0.2.60`,
+/// `192.0.2.60:4711` or `[2001:db8::17]:4711`
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    node.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| SocketAddr::new(ip, 0))
+}
+
+/// The client that trusted proxies forwarded the request of, if the peer is one
+///
+/// Hops are read from the nearest to the furthest, stopping at the first that
+/// is not trusted, so clients cannot choose their address by sending the
+/// headers themselves
+fn forwarded_client(
+    trusted: &TrustedProxies,
+    peer: IpAddr,
+    headers: &HeaderMap,
+) -> Option<SocketAddr> {
+    let is_trusted = |ip: IpAddr| trusted.ranges.iter().any(|range| range.contains(ip));
+
+    let mut client = None;
+    let mut current = peer;
+    for hop in forwarded_chain(headers, trusted.header).into_iter().rev() {
+        if !is_trusted(current) {
+            break;
+        }
+        let Some(hop) = hop else {
+            break;
+        };
+        current = hop.ip();
+        client = Some(hop);
+    }
+    client
+}
+
+/// Replaces the peer address of requests from trusted proxies with the address
+/// of the client that they forwarded
+pub(crate) async fn resolve_trusted_proxies<B>(
+    trusted: Arc<TrustedProxies>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(peer) = peer_ip(&request) else {
+        return next.run(request).await;
+    };
+
+    if let Some(client) = forwarded_client(&trusted, peer, request.headers()) {
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(PeerAddr(Some(client))));
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn proxies(header: ForwardedHeader) -> TrustedProxies {
+        TrustedProxies {
+            ranges: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            header,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn client(peer: &str, pairs: &[(&'static str, &'static str)]) -> Option<SocketAddr> {
+        forwarded_client(
+            &proxies(ForwardedHeader::XForwardedFor),
+            peer.parse().unwrap(),
+            &headers(pairs),
+        )
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_forward() {
+        let spoofed = [("X-Forwarded-For", "10.0.0.1")];
+        assert_eq!(client("203.0.113.5", &spoofed), None);
+        assert_eq!(client("2001:db8::5", &spoofed), None);
+    }
+
+    #[test]
+    fn trusted_peers_give_the_client() {
+        assert_eq!(
+            client("10.0.0.2", &[("X-Forwarded-For", "198.51.100.7")]),
+            Some("198.51.100.7:0".parse().unwrap())
+        );
+        // Mapped addresses of IPv6 sockets are trusted like their IPv4 form
+        assert_eq!(
+            client(
+                "::ffff:10.0.0.2",
+                &[("X-Forwarded-For", "198.51.100.7:4711")]
+            ),
+            Some("198.51.100.7:4711".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn hops_written_by_clients_are_ignored() {
+        // The client sent its own header, which the proxy appended to
+        assert_eq!(
+            client("10.0.0.2", &[("X-Forwarded-For", "10.0.0.1, 198.51.100.7")]),
+            Some("198.51.100.7:0".parse().unwrap())
+        );
+        assert_eq!(
+            client(
+                "10.0.0.2",
+                &[
+                    ("X-Forwarded-For", "10.0.0.1"),
+                    ("X-Forwarded-For", "198.51.100.7")
+                ]
+            ),
+            Some("198.51.100.7:0".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn chains_of_trusted_proxies_are_followed() {
+        assert_eq!(
+            client("10.0.0.2", &[("X-Forwarded-For", "198.51.100.7, fd00::3")]),
+            Some("198.51.100.7:0".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn unknown_hops_end_the_chain() {
+        assert_eq!(
+            client("10.0.0.2", &[("X-Forwarded-For", "198.51.100.7, unknown")]),
+            None
+        );
+        assert_eq!(client("10.0.0.2", &[]), None);
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let spoofed = headers(&[
+            ("X-Forwarded-For", "203.0.113.9"),
+            ("Forwarded", r#"for="[2001:db8::17]:4711""#),
+        ]);
+        let peer = "10.0.0.2".parse().unwrap();
+        assert_eq!(
+            forwarded_client(&proxies(ForwardedHeader::Forwarded), peer, &spoofed),
+            Some("[2001:db8::17]:4711".parse().unwrap())
+        );
+        assert_eq!(
+            forwarded_client(&proxies(ForwardedHeader::XForwardedFor), peer, &spoofed),
+            Some("203.0.113.9:0".parse().unwrap())
+        );
+    }
+}
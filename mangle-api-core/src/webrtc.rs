@@ -1,18 +1,24 @@
 use derive_more::From;
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::{future::join_all, stream::FuturesUnordered, StreamExt};
 use std::{
+    collections::HashSet,
     hash::Hash,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, RangeInclusive},
+    time::{Duration, Instant},
 };
 use tokio::{
     select,
     sync::{broadcast, mpsc, oneshot},
+    time::{sleep, timeout, timeout_at},
 };
 
 use dashmap::{
     mapref::{entry::Entry, one::RefMut},
     DashMap,
 };
+use rand::RngCore;
+
+use crate::rng::SharedRng;
 
 #[derive(From)]
 pub struct SDPOffer(pub String);
@@ -24,8 +30,9 @@ pub struct ICECandidate(pub String);
 pub struct ICEReceiver(oneshot::Receiver<ICECandidate>);
 
 impl ICEReceiver {
-    pub async fn get_ice(self) -> ICECandidate {
-        self.0.await.expect("ice to be received")
+    /// Returns None if the joiner left before sending it
+    pub async fn get_ice(self) -> Option<ICECandidate> {
+        self.0.await.ok()
     }
 }
 
@@ -35,13 +42,13 @@ pub struct SDPAnswerStreamSender {
 }
 
 impl SDPAnswerStreamSender {
-    pub fn send_answer(self, sdp_answer: SDPAnswer, ice: ICECandidate) -> ICEReceiver {
+    /// Returns None if the joiner left before the answer was sent
+    pub fn send_answer(self, sdp_answer: SDPAnswer, ice: ICECandidate) -> Option<ICEReceiver> {
         let (ice_sender, ice_recv) = oneshot::channel();
         self.answer_sender
             .send((self.index, sdp_answer, ice, ICESender(ice_sender)))
-            .or(Err(()))
-            .expect("send to work");
-        ICEReceiver(ice_recv)
+            .ok()?;
+        Some(ICEReceiver(ice_recv))
     }
 }
 
@@ -56,49 +63,69 @@ pub struct ConnectionReceiver {
 }
 
 impl ConnectionReceiver {
+    /// Returns None once the session ends
+    ///
+    /// Cancel safe, so no connection is lost if it is raced against other futures
     pub async fn wait_for_conn(&mut self) -> Option<SDPOfferStream> {
         select! {
-            conn = self.conn_stream_recv.recv() => {
-                Some(conn.expect("recv to work"))
-            }
-            _ = self.alive_recv.recv() => {
-                None
-            }
+            conn = self.conn_stream_recv.recv() => conn,
+            _ = self.alive_recv.recv() => None,
         }
     }
 }
 
-pub struct HostConnectionReceiver<'a, K: Hash + Eq + Clone> {
-    conn_recv: ConnectionReceiver,
+/// The connection receiver of a member of a session
+///
+/// Once dropped, the member can resume the session with its resume token
+/// until the reconnect grace period of the manager ends. Connections sent to
+/// the member in the meantime are kept for when it resumes
+pub struct MemberConnectionReceiver<'a, K: Hash + Eq + Clone> {
+    // Only ever None while being dropped
+    conn_recv: Option<ConnectionReceiver>,
     id: K,
+    resume_token: u64,
     manager: &'a WebRTCSessionManager<K>,
 }
 
-impl<'a, K: Hash + Eq + Clone> Deref for HostConnectionReceiver<'a, K> {
+/// The host is always the first member of a session, and the session ends if
+/// the host does not resume it in time
+pub type HostConnectionReceiver<'a, K> = MemberConnectionReceiver<'a, K>;
+
+impl<'a, K: Hash + Eq + Clone> MemberConnectionReceiver<'a, K> {
+    pub fn get_resume_token(&self) -> u64 {
+        self.resume_token
+    }
+}
+
+impl<'a, K: Hash + Eq + Clone> Deref for MemberConnectionReceiver<'a, K> {
     type Target = ConnectionReceiver;
 
     fn deref(&self) -> &Self::Target {
-        &self.conn_recv
+        self.conn_recv.as_ref().unwrap()
     }
 }
 
-impl<'a, K: Hash + Eq + Clone> DerefMut for HostConnectionReceiver<'a, K> {
+impl<'a, K: Hash + Eq + Clone> DerefMut for MemberConnectionReceiver<'a, K> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.conn_recv
+        self.conn_recv.as_mut().unwrap()
     }
 }
 
-impl<'a, K: Hash + Eq + Clone> Drop for HostConnectionReceiver<'a, K> {
+impl<'a, K: Hash + Eq + Clone> Drop for MemberConnectionReceiver<'a, K> {
     fn drop(&mut self) {
-        self.manager.sessions.remove(&self.id);
+        if let Some(conn_recv) = self.conn_recv.take() {
+            self.manager
+                .disconnect(&self.id, self.resume_token, conn_recv.conn_stream_recv);
+        }
     }
 }
 
 pub struct ICESender(oneshot::Sender<ICECandidate>);
 
 impl ICESender {
-    pub async fn send(self, ice: ICECandidate) {
-        self.0.send(ice).or(Err(())).expect("ice to be sent");
+    /// Does nothing if the member left
+    pub fn send(self, ice: ICECandidate) {
+        let _ = self.0.send(ice);
     }
 }
 
@@ -107,43 +134,82 @@ pub struct SDPAnswerStreamReceivers(
 );
 
 impl SDPAnswerStreamReceivers {
+    /// Returns None once every member answered or left
+    ///
+    /// Cancel safe, so no answer is lost if it is raced against other futures
     pub async fn wait_for_an_answer(
         &mut self,
     ) -> Option<(usize, SDPAnswer, ICECandidate, ICESender)> {
-        self.0.next().await.map(|x| x.expect("recv to work"))
+        while let Some(answer) = self.0.next().await {
+            if let Ok(answer) = answer {
+                return Some(answer);
+            }
+        }
+        None
     }
 }
 
-pub struct SDPOfferStreamSender<'a, K> {
-    ref_mut: RefMut<'a, K, WebRTCSession>,
+/// A slot reserved for a joiner in a session, which is released if this is
+/// dropped before the offers of the joiner are sent
+pub struct SDPOfferStreamSender<'a, K: Hash + Eq + Clone> {
     member_count: usize,
-    max_size: usize,
+    id: K,
+    manager: &'a WebRTCSessionManager<K>,
+    /// False once the joiner became a member
+    reserved: bool,
+    /// Whether releasing the slot wakes late joiners waiting for backfill,
+    /// which must not happen for the slot that one of them held while asking
+    wake_on_release: bool,
+}
+
+/// Why the offers of a joiner could not be sent
+pub enum SendOffersError<'a, K: Hash + Eq + Clone> {
+    /// The number of members changed, which the slot now gives, so the joiner
+    /// must make that many offers
+    WrongOfferCount(SDPOfferStreamSender<'a, K>),
+    SessionEnded,
 }
 
 impl<'a, K> SDPOfferStreamSender<'a, K>
 where
-    K: Hash + Eq,
+    K: Hash + Eq + Clone,
 {
     pub fn get_member_count(&self) -> usize {
         self.member_count
     }
 
+    pub fn get_id(&self) -> &K {
+        &self.id
+    }
+
+    /// Sends an offer to each member, making the joiner a member
+    ///
+    /// Offers are sent to every member at once, so that they gather their ICE
+    /// candidates concurrently. The session is not locked while they are sent
     pub async fn send_sdp_offers(
         mut self,
         offers: Vec<SDPOffer>,
-    ) -> Result<
-        (ConnectionReceiver, SDPAnswerStreamReceivers),
-        (SDPOfferStreamSender<'a, K>, Vec<SDPOffer>),
-    > {
+    ) -> Result<(MemberConnectionReceiver<'a, K>, SDPAnswerStreamReceivers), SendOffersError<'a, K>>
+    {
+        let (offer_senders, max_size) = {
+            let Some(session) = self.manager.sessions.get(&self.id) else {
+                return Err(SendOffersError::SessionEnded);
+            };
+            self.member_count = session.members.len();
+            let offer_senders: Vec<_> = session
+                .members
+                .iter()
+                .map(|member| member.offer_sender.clone())
+                .collect();
+            (offer_senders, session.max_size)
+        };
         if offers.len() != self.member_count {
-            return Err((self, offers));
+            return Err(SendOffersError::WrongOfferCount(self));
         }
 
-        let answer_receivers = FuturesUnordered::new();
-
-        for (fut, stream_sender) in offers
+        let (sends, answer_receivers): (Vec<_>, FuturesUnordered<_>) = offers
             .into_iter()
-            .zip(self.ref_mut.peers.iter())
+            .zip(offer_senders)
             .enumerate()
             .map(|(index, (sdp_offer, offer_sender))| {
                 let (answer_sender, answer_recv) = oneshot::channel();
@@ -151,42 +217,126 @@ where
                     index,
                     answer_sender,
                 };
-
-                (
-                    offer_sender.send(SDPOfferStream {
-                        sdp_offer,
-                        answer_stream,
-                    }),
-                    answer_recv,
-                )
+                let send = async move {
+                    // Members that left meanwhile never answer, which the
+                    // joiner sees as their answer not arriving
+                    let _ = offer_sender
+                        .send(SDPOfferStream {
+                            sdp_offer,
+                            answer_stream,
+                        })
+                        .await;
+                };
+                (send, answer_recv)
             })
-        {
-            fut.await.or(Err(())).expect("send to work");
-            answer_receivers.push(stream_sender);
-        }
-
-        let (offer_sender, conn_stream_recv) = mpsc::channel(self.max_size);
-        self.ref_mut.peers.push(offer_sender);
-        let alive_recv = self.ref_mut.alive_sender.subscribe();
+            .unzip();
+        join_all(sends).await;
+
+        let (offer_sender, conn_stream_recv) = mpsc::channel(max_size);
+        let resume_token = self.manager.new_resume_token();
+        let Some(mut session) = self.manager.sessions.get_mut(&self.id) else {
+            return Err(SendOffersError::SessionEnded);
+        };
+        session.reserved -= 1;
+        self.reserved = false;
+        session.members.push(Member {
+            offer_sender,
+            resume_token,
+            parked: None,
+        });
+        let alive_recv = session.alive_sender.subscribe();
+        drop(session);
 
         Ok((
-            ConnectionReceiver {
-                conn_stream_recv,
-                alive_recv,
+            MemberConnectionReceiver {
+                conn_recv: Some(ConnectionReceiver {
+                    conn_stream_recv,
+                    alive_recv,
+                }),
+                id: self.id.clone(),
+                resume_token,
+                manager: self.manager,
             },
             SDPAnswerStreamReceivers(answer_receivers),
         ))
     }
 }
 
+impl<'a, K: Hash + Eq + Clone> Drop for SDPOfferStreamSender<'a, K> {
+    fn drop(&mut self) {
+        if self.reserved {
+            self.manager.release_slot(&self.id, self.wake_on_release);
+        }
+    }
+}
+
+struct Member {
+    offer_sender: mpsc::Sender<SDPOfferStream>,
+    resume_token: u64,
+    /// The receiver of a disconnected member and when its grace period ends
+    parked: Option<(mpsc::Receiver<SDPOfferStream>, Instant)>,
+}
+
+impl Member {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.parked
+            .as_ref()
+            .map_or(false, |(_, deadline)| *deadline <= now)
+    }
+}
+
+/// How many join requests can wait for the host of a session open for backfill
+const JOIN_REQUEST_BUFFER_SIZE: usize = 4;
+/// How long a late joiner waits for a host to answer before trying another session
+pub const JOIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A late joiner that was routed to a session open for backfill
+pub struct JoinRequest {
+    rating: u32,
+    decision: oneshot::Sender<bool>,
+}
+
+impl JoinRequest {
+    pub fn get_rating(&self) -> u32 {
+        self.rating
+    }
+
+    pub fn accept(self) {
+        let _ = self.decision.send(true);
+    }
+
+    pub fn decline(self) {
+        let _ = self.decision.send(false);
+    }
+}
+
+/// Receives the join requests of a session while it is open for backfill
+pub struct JoinRequestReceiver(mpsc::Receiver<JoinRequest>);
+
+impl JoinRequestReceiver {
+    /// Returns None once the session is closed to backfill or ends
+    pub async fn wait_for_request(&mut self) -> Option<JoinRequest> {
+        self.0.recv().await
+    }
+}
+
+struct Backfill {
+    rating_band: RangeInclusive<u32>,
+    request_sender: mpsc::Sender<JoinRequest>,
+}
+
 pub struct WebRTCSession {
-    peers: Vec<mpsc::Sender<SDPOfferStream>>,
+    /// The host is always first
+    members: Vec<Member>,
+    /// Slots held for joiners that have yet to send their offers
+    reserved: usize,
     max_size: usize,
     alive_sender: broadcast::Sender<()>,
+    backfill: Option<Backfill>,
 }
 
 pub trait RandomID: Sized {
-    fn generate() -> Self;
+    fn generate(rng: &mut dyn RngCore) -> Self;
 }
 
 pub struct WebRTCSessionManager<K>
@@ -194,6 +344,10 @@ where
     K: Hash + Eq + Clone,
 {
     sessions: DashMap<K, WebRTCSession>,
+    rng: SharedRng,
+    reconnect_grace: Duration,
+    /// Wakes late joiners waiting for backfill whenever a slot may have opened
+    backfill_opened: broadcast::Sender<()>,
 }
 
 pub enum JoinSessionError {
@@ -201,50 +355,289 @@ pub enum JoinSessionError {
     Full,
 }
 
+pub enum ResumeSessionError {
+    /// The session or member does not exist, or the grace period is over
+    NotFound,
+    /// The member has not disconnected
+    StillConnected,
+}
+
 pub struct ExistingSessionError;
 
 impl<K> WebRTCSessionManager<K>
 where
     K: Hash + Eq + Clone,
 {
+    /// Creates a manager that generates random ids with the given source of randomness
+    pub fn new(rng: SharedRng) -> Self {
+        Self {
+            sessions: DashMap::default(),
+            rng,
+            reconnect_grace: Duration::ZERO,
+            backfill_opened: broadcast::channel(1).0,
+        }
+    }
+
+    /// Sets how long a disconnected member has to resume its session
+    ///
+    /// Without a grace period, a session ends as soon as its host disconnects
+    pub fn set_reconnect_grace(mut self, reconnect_grace: Duration) -> Self {
+        self.reconnect_grace = reconnect_grace;
+        self
+    }
+
+    fn new_resume_token(&self) -> u64 {
+        self.rng.with(|rng| rng.next_u64())
+    }
+
     pub fn host_session(
         &self,
         id: K,
         max_size: usize,
     ) -> Result<HostConnectionReceiver<K>, ExistingSessionError> {
         let Entry::Vacant(slot) = self.sessions.entry(id.clone()) else { return Err(ExistingSessionError)};
-        let (sender, conn_stream_recv) = mpsc::channel(max_size);
-        let (alive_sender, alive_recv) = broadcast::channel(0);
+        let (offer_sender, conn_stream_recv) = mpsc::channel(max_size);
+        // Nothing is ever sent, as receivers only wait for it to close
+        let (alive_sender, alive_recv) = broadcast::channel(1);
+        let resume_token = self.new_resume_token();
 
         slot.insert(WebRTCSession {
-            peers: vec![sender],
+            members: vec![Member {
+                offer_sender,
+                resume_token,
+                parked: None,
+            }],
+            reserved: 0,
             max_size,
             alive_sender,
+            backfill: None,
         });
         Ok(HostConnectionReceiver {
             manager: self,
             id,
-            conn_recv: ConnectionReceiver {
+            resume_token,
+            conn_recv: Some(ConnectionReceiver {
                 conn_stream_recv,
                 alive_recv,
-            },
+            }),
         })
     }
 
+    /// Reserves a slot in the session for a joiner, until its offers are sent
     pub fn join_session(&self, id: &K) -> Result<SDPOfferStreamSender<K>, JoinSessionError> {
-        {
-            let session = self.sessions.get(id).ok_or(JoinSessionError::NotFound)?;
-            if session.peers.len() >= session.max_size {
-                return Err(JoinSessionError::Full);
-            }
+        let mut session = self
+            .sessions
+            .get_mut(id)
+            .ok_or(JoinSessionError::NotFound)?;
+        if session.members.len() + session.reserved >= session.max_size {
+            return Err(JoinSessionError::Full);
         }
-        let ref_mut = self.sessions.get_mut(id).unwrap();
+        session.reserved += 1;
         Ok(SDPOfferStreamSender {
-            member_count: ref_mut.peers.len(),
-            max_size: ref_mut.max_size,
-            ref_mut,
+            member_count: session.members.len(),
+            id: id.clone(),
+            manager: self,
+            reserved: true,
+            wake_on_release: true,
         })
     }
+
+    fn release_slot(&self, id: &K, wake_joiners: bool) {
+        if let Some(mut session) = self.sessions.get_mut(id) {
+            session.reserved -= 1;
+        }
+        if wake_joiners {
+            let _ = self.backfill_opened.send(());
+        }
+    }
+
+    /// Rebinds a disconnected member to the session with the given id, without
+    /// affecting the other members
+    pub fn resume_session(
+        &self,
+        id: &K,
+        resume_token: u64,
+    ) -> Result<MemberConnectionReceiver<K>, ResumeSessionError> {
+        let mut session = self.sessions.get_mut(id).ok_or(ResumeSessionError::NotFound)?;
+        let now = Instant::now();
+        let member = session
+            .members
+            .iter_mut()
+            .find(|member| member.resume_token == resume_token && !member.is_expired(now))
+            .ok_or(ResumeSessionError::NotFound)?;
+        let Some((conn_stream_recv, _)) = member.parked.take() else {
+            return Err(ResumeSessionError::StillConnected)
+        };
+        let alive_recv = session.alive_sender.subscribe();
+
+        Ok(MemberConnectionReceiver {
+            conn_recv: Some(ConnectionReceiver {
+                conn_stream_recv,
+                alive_recv,
+            }),
+            id: id.clone(),
+            resume_token,
+            manager: self,
+        })
+    }
+
+    /// Keeps the receiver of a disconnected member until it resumes, or removes
+    /// the member immediately if there is no grace period
+    fn disconnect(
+        &self,
+        id: &K,
+        resume_token: u64,
+        conn_stream_recv: mpsc::Receiver<SDPOfferStream>,
+    ) {
+        let Some(mut session) = self.sessions.get_mut(id) else { return };
+        let Some(index) = session
+            .members
+            .iter()
+            .position(|member| member.resume_token == resume_token) else { return };
+
+        if !self.reconnect_grace.is_zero() {
+            session.members[index].parked =
+                Some((conn_stream_recv, Instant::now() + self.reconnect_grace));
+        } else if index == 0 {
+            drop(session);
+            self.sessions.remove(id);
+        } else {
+            session.members.remove(index);
+            drop(session);
+            let _ = self.backfill_opened.send(());
+        }
+    }
+
+    /// Removes the members whose grace period is over, along with the sessions
+    /// whose host did not resume in time
+    pub fn remove_expired(&self) {
+        let now = Instant::now();
+        let mut removed_any = false;
+        self.sessions.retain(|_, session| {
+            if session.members[0].is_expired(now) {
+                return false;
+            }
+            let member_count = session.members.len();
+            session.members.retain(|member| !member.is_expired(now));
+            removed_any |= session.members.len() < member_count;
+            true
+        });
+        if removed_any {
+            let _ = self.backfill_opened.send(());
+        }
+    }
+
+    /// Lets matchmaking route late joiners whose rating is within the given
+    /// band to the session with the given id, until it is full or closed
+    ///
+    /// Returns None if there is no such session
+    pub fn open_for_backfill(
+        &self,
+        id: &K,
+        rating_band: RangeInclusive<u32>,
+    ) -> Option<JoinRequestReceiver> {
+        let mut session = self.sessions.get_mut(id)?;
+        let (request_sender, request_recv) = mpsc::channel(JOIN_REQUEST_BUFFER_SIZE);
+        session.backfill = Some(Backfill {
+            rating_band,
+            request_sender,
+        });
+        drop(session);
+        let _ = self.backfill_opened.send(());
+        Some(JoinRequestReceiver(request_recv))
+    }
+
+    pub fn close_backfill(&self, id: &K) {
+        if let Some(mut session) = self.sessions.get_mut(id) {
+            session.backfill = None;
+        }
+    }
+
+    /// Asks the hosts of compatible sessions open for backfill to take a late
+    /// joiner with the given rating, fullest sessions first
+    ///
+    /// If no host accepts, the joiner waits for sessions to open for backfill
+    /// or to free a slot, asking again each time, for up to `max_wait`
+    ///
+    /// Returns the slot reserved for the joiner in the session whose host
+    /// accepted, which the joiner must then send its offers to
+    pub async fn request_backfill(
+        &self,
+        rating: u32,
+        max_wait: Duration,
+    ) -> Option<SDPOfferStreamSender<K>> {
+        let deadline = tokio::time::Instant::now() + max_wait;
+        // Hosts are only asked once
+        let mut asked = HashSet::new();
+        loop {
+            // Subscribed before asking, so that slots opening meanwhile are not missed
+            let mut opened = self.backfill_opened.subscribe();
+            if let Some(slot) = self.try_backfill(rating, &mut asked).await {
+                return Some(slot);
+            }
+            // Lagging behind only means that slots opened
+            timeout_at(deadline, opened.recv()).await.ok()?;
+        }
+    }
+
+    async fn try_backfill(
+        &self,
+        rating: u32,
+        asked: &mut HashSet<K>,
+    ) -> Option<SDPOfferStreamSender<K>> {
+        let mut candidates: Vec<_> = self
+            .sessions
+            .iter()
+            .filter_map(|entry| {
+                let session = entry.value();
+                let backfill = session.backfill.as_ref()?;
+                if asked.contains(entry.key()) {
+                    return None;
+                }
+                let taken = session.members.len() + session.reserved;
+                if taken >= session.max_size || !backfill.rating_band.contains(&rating) {
+                    return None;
+                }
+                Some((
+                    entry.key().clone(),
+                    session.max_size - taken,
+                    backfill.request_sender.clone(),
+                ))
+            })
+            .collect();
+        candidates.sort_by_key(|(_, open_slots, _)| *open_slots);
+
+        for (id, _, request_sender) in candidates {
+            // Reserved while the host decides, so that the slot is still free
+            // once accepted. Dropping it releases the slot again
+            let Ok(mut slot) = self.join_session(&id) else {
+                continue;
+            };
+            slot.wake_on_release = false;
+            let (decision, decision_recv) = oneshot::channel();
+            // Hosts with a backlog of requests are skipped instead of waited on
+            if request_sender
+                .try_send(JoinRequest { rating, decision })
+                .is_err()
+            {
+                continue;
+            }
+            asked.insert(id);
+            if let Ok(Ok(true)) = timeout(JOIN_REQUEST_TIMEOUT, decision_recv).await {
+                slot.wake_on_release = true;
+                return Some(slot);
+            }
+        }
+        None
+    }
+
+    /// Calls `remove_expired` every `interval`, forever
+    pub async fn reap_expired(&self, interval: Duration) {
+        loop {
+            sleep(interval).await;
+            self.remove_expired();
+        }
+    }
 }
 
 impl<K> WebRTCSessionManager<K>
@@ -253,7 +646,7 @@ where
 {
     pub fn host_session_random_id(&self, max_size: usize) -> (HostConnectionReceiver<K>, K) {
         loop {
-            let id = K::generate();
+            let id = self.rng.with(K::generate);
             let Ok(handle) = self.host_session(id.clone(), max_size) else { continue };
             break (handle, id);
         }
@@ -265,8 +658,133 @@ where
     K: Hash + Eq + Clone,
 {
     fn default() -> Self {
-        Self {
-            sessions: DashMap::default(),
+        Self::new(SharedRng::thread())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    struct TestCode(u64);
+
+    impl RandomID for TestCode {
+        fn generate(rng: &mut dyn RngCore) -> Self {
+            Self(rng.next_u64())
         }
     }
+
+    fn host(
+        manager: &WebRTCSessionManager<TestCode>,
+        code: u64,
+        max_size: usize,
+    ) -> HostConnectionReceiver<TestCode> {
+        manager
+            .host_session(TestCode(code), max_size)
+            .ok()
+            .expect("hosting a new session")
+    }
+
+    #[test]
+    fn joining_stops_at_max_size() {
+        let manager = WebRTCSessionManager::default();
+        let _host = host(&manager, 1, 2);
+        let slot = manager.join_session(&TestCode(1)).ok();
+        assert!(slot.is_some());
+        assert!(matches!(
+            manager.join_session(&TestCode(1)),
+            Err(JoinSessionError::Full)
+        ));
+        // Dropping a reserved slot frees it again
+        drop(slot);
+        assert!(manager.join_session(&TestCode(1)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn backfill_only_asks_hosts_within_the_rating_band() {
+        let manager = WebRTCSessionManager::default();
+        let _low = host(&manager, 1, 4);
+        let _high = host(&manager, 2, 4);
+        let mut low_requests = manager.open_for_backfill(&TestCode(1), 0..=100).unwrap();
+        let mut high_requests = manager.open_for_backfill(&TestCode(2), 200..=300).unwrap();
+
+        let (slot, rating) = tokio::join!(
+            manager.request_backfill(250, Duration::from_secs(5)),
+            async {
+                let request = high_requests.wait_for_request().await.unwrap();
+                let rating = request.get_rating();
+                request.accept();
+                rating
+            }
+        );
+        assert_eq!(slot.unwrap().get_id(), &TestCode(2));
+        assert_eq!(rating, 250);
+        assert!(low_requests.0.try_recv().is_err());
+
+        // Nobody is asked to take a joiner outside of every band
+        assert!(manager
+            .request_backfill(150, Duration::from_millis(50))
+            .await
+            .is_none());
+        assert!(low_requests.0.try_recv().is_err());
+        assert!(high_requests.0.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn backfill_skips_full_sessions() {
+        let manager = WebRTCSessionManager::default();
+        let _host = host(&manager, 1, 1);
+        let mut requests = manager.open_for_backfill(&TestCode(1), 0..=100).unwrap();
+        assert!(manager
+            .request_backfill(50, Duration::from_millis(50))
+            .await
+            .is_none());
+        assert!(requests.0.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn declined_joiners_are_asked_for_by_the_next_host() {
+        let manager = WebRTCSessionManager::default();
+        // The fullest session is asked first
+        let _full = host(&manager, 1, 2);
+        let _roomy = host(&manager, 2, 4);
+        let mut full_requests = manager.open_for_backfill(&TestCode(1), 0..=100).unwrap();
+        let mut roomy_requests = manager.open_for_backfill(&TestCode(2), 0..=100).unwrap();
+
+        let (slot, ()) = tokio::join!(
+            manager.request_backfill(50, Duration::from_secs(5)),
+            async {
+                full_requests.wait_for_request().await.unwrap().decline();
+                roomy_requests.wait_for_request().await.unwrap().accept();
+            }
+        );
+        assert_eq!(slot.unwrap().get_id(), &TestCode(2));
+        // The slot held while the full session's host declined was released
+        assert!(manager.join_session(&TestCode(1)).is_ok());
+    }
+
+    #[test]
+    fn seeded_managers_generate_the_same_room_codes() {
+        let first = WebRTCSessionManager::<TestCode>::new(SharedRng::seeded(7));
+        let second = WebRTCSessionManager::<TestCode>::new(SharedRng::seeded(7));
+        let mut hosts = vec![];
+        for _ in 0..4 {
+            let (first_host, first_code) = first.host_session_random_id(2);
+            let (second_host, second_code) = second.host_session_random_id(2);
+            assert_eq!(first_code, second_code);
+            assert_eq!(
+                first_host.get_resume_token(),
+                second_host.get_resume_token()
+            );
+            hosts.push((first_host, second_host));
+        }
+
+        let same = WebRTCSessionManager::<TestCode>::new(SharedRng::seeded(7));
+        let other = WebRTCSessionManager::<TestCode>::new(SharedRng::seeded(8));
+        assert_ne!(
+            same.host_session_random_id(2).1,
+            other.host_session_random_id(2).1
+        );
+    }
 }
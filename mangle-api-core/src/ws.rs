@@ -1,11 +1,32 @@
-use std::{borrow::Cow, sync::Exclusive, time::Duration};
+use std::{
+    borrow::Cow,
+    future::pending,
+    sync::{Arc, Exclusive},
+    time::Duration,
+};
 
 use axum::{
     async_trait,
     extract::ws::{CloseFrame, Message, WebSocket},
 };
-use messagist::text::TextStream;
-use tokio::time::sleep;
+use log::warn;
+use messagist::{text::TextStream, BytesStream};
+use parking_lot::Mutex;
+use tokio::{
+    spawn,
+    sync::mpsc,
+    time::{sleep, sleep_until, Instant},
+};
+
+use crate::{
+    crash::CrashContext,
+    data_channel::DataChannel,
+    neo_api::{
+        bandwidth::{BandwidthAction, ConnectionMeter, Direction},
+        metrics::SessionMetrics,
+    },
+    shutdown::ShutdownListener,
+};
 
 const WEBSOCKET_PING: &str = "PING!!";
 
@@ -17,18 +38,173 @@ pub enum WsError {
     AlreadyClosed,
     #[error("NotAString")]
     NotAString(Vec<u8>),
+    #[error("NotBytes")]
+    NotBytes(String),
+    #[error("BandwidthExceeded")]
+    BandwidthExceeded,
+    /// The client closed the connection with a close frame
+    #[error("Closed {0}")]
+    Closed(CloseStatus),
+    /// The server is stopping, so nothing more will be received. Messages can
+    /// still be sent until the WebSocket is dropped
+    #[error("ShuttingDown")]
+    ShuttingDown,
+}
+
+/// The code and reason of the close frame that a client sent
+#[derive(Clone, Debug)]
+pub struct CloseStatus {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseStatus {
+    /// Close frames without a status are given 1005, as in RFC 6455
+    const NO_STATUS: u16 = 1005;
+
+    /// Whether the client closed the connection with `WebSocketCode::Ok`
+    pub fn is_normal(&self) -> bool {
+        self.code == WebSocketCode::Ok as u16
+    }
+}
+
+impl std::fmt::Display for CloseStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.reason.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{} ({})", self.code, self.reason)
+        }
+    }
+}
+
+impl From<Option<CloseFrame<'_>>> for CloseStatus {
+    fn from(frame: Option<CloseFrame<'_>>) -> Self {
+        match frame {
+            Some(frame) => Self {
+                code: frame.code,
+                reason: frame.reason.into_owned(),
+            },
+            None => Self {
+                code: Self::NO_STATUS,
+                reason: String::new(),
+            },
+        }
+    }
+}
+
+/// Finds the close status of the client in the given error or its sources, so
+/// that handlers can tell a client that closed cleanly from a dropped connection
+/// without knowing which stream they were given
+pub fn find_close_status<'a>(
+    mut error: &'a (dyn std::error::Error + 'static),
+) -> Option<&'a CloseStatus> {
+    loop {
+        if let Some(WsError::Closed(status)) = error.downcast_ref::<WsError>() {
+            break Some(status);
+        }
+        error = error.source()?;
+    }
+}
+
+/// How a session ended, as seen by its WebSocket
+#[derive(Clone, Debug)]
+pub enum SessionEnd {
+    /// The client sent a close frame
+    Closed(CloseStatus),
+    /// The connection ended without a close frame, such as a dropped TCP connection
+    Dropped,
+}
+
+/// Shared with whoever started the session, so that it can report how the
+/// session ended after the WebSocket has been dropped
+#[derive(Default)]
+pub struct SessionSummary {
+    end: Mutex<Option<SessionEnd>>,
+}
+
+impl SessionSummary {
+    /// Only the first end is kept, as later errors are caused by it
+    fn record(&self, end: SessionEnd) {
+        self.end.lock().get_or_insert(end);
+    }
+
+    /// None if the session was ended by the server
+    pub fn get_end(&self) -> Option<SessionEnd> {
+        self.end.lock().clone()
+    }
 }
 
 #[repr(u16)]
 pub enum WebSocketCode {
     Ok = 1000,
+    GoingAway = 1001,
     BadPayload = 1007,
+    PolicyViolation = 1008,
     InternalError = 1011,
 }
 
 pub struct ManagedWebSocket {
-    ws: Exclusive<WebSocket>,
+    // Only ever None while being dropped
+    ws: Exclusive<Option<WebSocket>>,
     ping_delay: Duration,
+    meter: Option<ConnectionMeter>,
+    crash_context: Option<Arc<CrashContext>>,
+    summary: Option<Arc<SessionSummary>>,
+    shutdown: Option<ShutdownListener>,
+    metrics: Option<Arc<SessionMetrics>>,
+    handoff: Option<mpsc::Receiver<DataChannel>>,
+    /// Used instead of the WebSocket for every message while it is open
+    data_channel: Option<DataChannel>,
+    /// Nothing is read until then, as received frames are throttled before
+    /// the next read so that no frame is lost if receiving is cancelled
+    throttled_until: Option<Instant>,
+}
+
+async fn recv_handoff(handoff: &mut Option<mpsc::Receiver<DataChannel>>) -> Option<DataChannel> {
+    match handoff {
+        Some(handoff) => handoff.recv().await,
+        None => pending().await,
+    }
+}
+
+async fn recv_shutdown(shutdown: &mut Option<ShutdownListener>) {
+    match shutdown {
+        Some(shutdown) => shutdown.wait().await,
+        None => pending().await,
+    }
+}
+
+async fn recv_data_channel(data_channel: &mut Option<DataChannel>) -> Option<Message> {
+    match data_channel {
+        Some(data_channel) => data_channel.recv().await,
+        None => pending().await,
+    }
+}
+
+impl Drop for ManagedWebSocket {
+    fn drop(&mut self) {
+        // A panicking handler never gets to close the WebSocket itself
+        let frame = if std::thread::panicking() {
+            CloseFrame {
+                code: WebSocketCode::InternalError as u16,
+                reason: "Internal Error".into(),
+            }
+        } else if let Some(shutdown) = self.shutdown.as_ref().filter(|x| x.is_shutting_down()) {
+            shutdown.close_frame()
+        } else {
+            return;
+        };
+        let Some(mut ws) = self.ws.get_mut().take() else {
+            return
+        };
+        // The session is only counted as closed once the close frame is sent
+        let shutdown = self.shutdown.take();
+        spawn(async move {
+            let _ = ws.send(Message::Close(Some(frame))).await;
+            drop(shutdown);
+        });
+    }
 }
 
 impl ManagedWebSocket {
@@ -37,8 +213,84 @@ impl ManagedWebSocket {
     /// The timer for pinging is reset every time a message is sent or received
     pub fn new(ws: WebSocket, ping_delay: Duration) -> Self {
         Self {
-            ws: Exclusive::new(ws),
+            ws: Exclusive::new(Some(ws)),
             ping_delay,
+            meter: None,
+            crash_context: None,
+            summary: None,
+            shutdown: None,
+            metrics: None,
+            handoff: None,
+            data_channel: None,
+            throttled_until: None,
+        }
+    }
+
+    fn socket(&mut self) -> &mut WebSocket {
+        self.ws.get_mut().as_mut().unwrap()
+    }
+
+    /// Records every message received into the given context, for crash reports
+    pub(crate) fn with_crash_context(mut self, crash_context: Arc<CrashContext>) -> Self {
+        self.crash_context = Some(crash_context);
+        self
+    }
+
+    /// Records how the session ended into the given summary
+    pub(crate) fn with_summary(mut self, summary: Arc<SessionSummary>) -> Self {
+        self.summary = Some(summary);
+        self
+    }
+
+    /// Stops receiving once the server starts shutting down, and sends the
+    /// close frame of the notifier when dropped after that
+    pub(crate) fn with_shutdown(mut self, shutdown: ShutdownListener) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Counts every Text and Binary frame sent and received
+    pub(crate) fn with_metrics(mut self, metrics: Arc<SessionMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Records every frame sent and received against the given meter,
+    /// enforcing its bandwidth limits
+    pub(crate) fn with_meter(mut self, meter: ConnectionMeter) -> Self {
+        self.meter = Some(meter);
+        self
+    }
+
+    /// Moves the messages of this connection onto every data channel received
+    /// from the given receiver
+    pub(crate) fn with_handoff(mut self, handoff: mpsc::Receiver<DataChannel>) -> Self {
+        self.handoff = Some(handoff);
+        self
+    }
+
+    async fn record(&mut self, bytes: usize, direction: Direction) -> Result<(), WsError> {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_message(direction);
+        }
+        let Some(meter) = &self.meter else {
+            return Ok(())
+        };
+        match meter.record(bytes, direction) {
+            BandwidthAction::Allow | BandwidthAction::Warn => Ok(()),
+            BandwidthAction::Throttle(delay) => {
+                match direction {
+                    Direction::Inbound => self.throttled_until = Some(Instant::now() + delay),
+                    Direction::Outbound => sleep(delay).await,
+                }
+                Ok(())
+            }
+            BandwidthAction::Disconnect => {
+                let _ = self
+                    .close(WebSocketCode::PolicyViolation, "Bandwidth Exceeded")
+                    .await;
+                Err(WsError::BandwidthExceeded)
+            }
         }
     }
 
@@ -47,8 +299,7 @@ impl ManagedWebSocket {
         code: WebSocketCode,
         reason: impl Into<Cow<'static, str>>,
     ) -> Result<(), WsError> {
-        self.ws
-            .get_mut()
+        self.socket()
             .send(Message::Close(Some(CloseFrame {
                 code: code as u16,
                 reason: reason.into(),
@@ -56,42 +307,112 @@ impl ManagedWebSocket {
             .await
             .map_err(Into::into)
     }
-}
 
-#[async_trait]
-impl TextStream for ManagedWebSocket {
-    type Error = WsError;
-    async fn recv_string(&mut self) -> Result<String, Self::Error> {
+    fn record_end(&self, end: SessionEnd) {
+        if let Some(summary) = &self.summary {
+            summary.record(end);
+        }
+    }
+
+    /// Sends a Text or Binary frame through the data channel if there is one,
+    /// otherwise through the WebSocket
+    async fn send_frame(&mut self, msg: Message) -> Result<(), WsError> {
+        if let Some(data_channel) = &self.data_channel {
+            match data_channel.send(&msg).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!(target: "data_channel", "{:?}", e.context("sending through data channel"));
+                    self.data_channel = None;
+                }
+            }
+        }
+        self.socket().send(msg).await.map_err(Into::into)
+    }
+
+    /// Receives the next Text or Binary frame, pinging the client if it has been idle
+    async fn recv_frame(&mut self) -> Result<Message, WsError> {
+        if let Some(throttled_until) = self.throttled_until {
+            sleep_until(throttled_until).await;
+            self.throttled_until = None;
+        }
         loop {
             let result;
             tokio::select! {
                 () = sleep(self.ping_delay) => {
-                    self.ws.get_mut().send(Message::Ping(WEBSOCKET_PING.as_bytes().to_vec())).await?;
+                    self.socket().send(Message::Ping(WEBSOCKET_PING.as_bytes().to_vec())).await?;
                     continue
                 }
-                res = self.ws.get_mut().recv() => {
+                res = self.ws.get_mut().as_mut().unwrap().recv() => {
                     result = res;
                 }
+                () = recv_shutdown(&mut self.shutdown) => {
+                    break Err(WsError::ShuttingDown)
+                }
+                Some(data_channel) = recv_handoff(&mut self.handoff) => {
+                    self.data_channel = Some(data_channel);
+                    continue
+                }
+                msg = recv_data_channel(&mut self.data_channel) => {
+                    let Some(msg) = msg else {
+                        // Falls back to the WebSocket
+                        self.data_channel = None;
+                        continue
+                    };
+                    result = Some(Ok(msg));
+                }
             }
-            let Some(msg) = result else {
-                break Err(WsError::AlreadyClosed)
+            let msg = match result {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    self.record_end(SessionEnd::Dropped);
+                    break Err(e.into());
+                }
+                None => {
+                    self.record_end(SessionEnd::Dropped);
+                    break Err(WsError::AlreadyClosed);
+                }
             };
-            match msg? {
-                Message::Text(x) => break Ok(x),
-                Message::Binary(x) => break Err(x.into()),
+            match msg {
                 Message::Ping(_) => unreachable!(),
                 Message::Pong(_) => continue,
-                Message::Close(_) => break Err(WsError::AlreadyClosed),
+                Message::Close(frame) => {
+                    let status = CloseStatus::from(frame);
+                    self.record_end(SessionEnd::Closed(status.clone()));
+                    break Err(WsError::Closed(status));
+                }
+                Message::Text(msg) => {
+                    if let Some(crash_context) = &self.crash_context {
+                        crash_context.record_text(&msg);
+                    }
+                    self.record(msg.len(), Direction::Inbound).await?;
+                    break Ok(Message::Text(msg))
+                }
+                Message::Binary(msg) => {
+                    if let Some(crash_context) = &self.crash_context {
+                        crash_context.record_binary(&msg);
+                    }
+                    self.record(msg.len(), Direction::Inbound).await?;
+                    break Ok(Message::Binary(msg))
+                }
             }
         }
     }
+}
+
+#[async_trait]
+impl TextStream for ManagedWebSocket {
+    type Error = WsError;
+    async fn recv_string(&mut self) -> Result<String, Self::Error> {
+        match self.recv_frame().await? {
+            Message::Text(x) => Ok(x),
+            Message::Binary(x) => Err(x.into()),
+            _ => unreachable!(),
+        }
+    }
 
     async fn send_string(&mut self, msg: String) -> Result<(), Self::Error> {
-        self.ws
-            .get_mut()
-            .send(Message::Text(msg))
-            .await
-            .map_err(Into::into)
+        self.record(msg.len(), Direction::Outbound).await?;
+        self.send_frame(Message::Text(msg)).await
     }
 
     async fn wait_for_error(&mut self) -> Self::Error {
@@ -102,3 +423,28 @@ impl TextStream for ManagedWebSocket {
         }
     }
 }
+
+#[async_trait]
+impl BytesStream for ManagedWebSocket {
+    type Error = WsError;
+    async fn recv_bytes(&mut self) -> Result<Vec<u8>, Self::Error> {
+        match self.recv_frame().await? {
+            Message::Binary(x) => Ok(x),
+            Message::Text(x) => Err(WsError::NotBytes(x)),
+            _ => unreachable!(),
+        }
+    }
+
+    async fn send_bytes(&mut self, msg: Vec<u8>) -> Result<(), Self::Error> {
+        self.record(msg.len(), Direction::Outbound).await?;
+        self.send_frame(Message::Binary(msg)).await
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        loop {
+            if let Err(e) = self.recv_bytes().await {
+                break e;
+            }
+        }
+    }
+}
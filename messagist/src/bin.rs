@@ -1,8 +1,9 @@
 use async_trait::async_trait;
+use bincode::Options;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::MessageStream;
+use crate::{BytesStream, MessageStream};
 
 pub struct BinaryMessageStream<T: AsyncRead + AsyncWrite + Unpin + Send>(pub(crate) T);
 
@@ -93,3 +94,87 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> From<T> for BinaryMessageStream<T
         BinaryMessageStream(value)
     }
 }
+
+/// Deserializes a message sent by `bincode::serialize`, without reading past the frame
+///
+/// Length prefixes are untrusted, so decoding is limited to the size of the frame
+/// instead of whatever a string or sequence claims to hold
+fn deserialize_frame<M: DeserializeOwned>(frame: &[u8]) -> bincode::Result<M> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(frame.len() as u64)
+        .deserialize(frame)
+}
+
+/// Serializes messages with bincode, sending each message as a single frame
+pub struct BincodeMessageStream<T>(T);
+
+#[derive(thiserror::Error, Debug)]
+pub enum BytesBincodeError<E: std::error::Error> {
+    #[error("BytesError {0}")]
+    BytesError(#[source] E),
+    #[error("DeserializeError {0}")]
+    DeserializeError(bincode::Error),
+}
+
+#[async_trait]
+impl<S: BytesStream<Error: Sync> + Send + Sync> MessageStream for BincodeMessageStream<S> {
+    type Error = BytesBincodeError<S::Error>;
+
+    async fn recv_message<M>(&mut self) -> Result<M, Self::Error>
+    where
+        M: DeserializeOwned + Send + 'static,
+    {
+        let msg = self
+            .0
+            .recv_bytes()
+            .await
+            .map_err(BytesBincodeError::BytesError)?;
+        deserialize_frame(&msg).map_err(BytesBincodeError::DeserializeError)
+    }
+
+    async fn send_message<M: Serialize + Send + Sync>(
+        &mut self,
+        msg: M,
+    ) -> Result<(), Self::Error> {
+        self.0
+            .send_bytes(bincode::serialize(&msg).unwrap())
+            .await
+            .map_err(BytesBincodeError::BytesError)
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        BytesBincodeError::BytesError(self.0.wait_for_error().await)
+    }
+}
+
+impl<S> From<S> for BincodeMessageStream<S> {
+    fn from(value: S) -> Self {
+        Self(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let msg = (String::from("hello"), vec![1u32, 2, 3]);
+        let frame = bincode::serialize(&msg).unwrap();
+        assert_eq!(
+            deserialize_frame::<(String, Vec<u32>)>(&frame).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn oversized_length_prefixes_are_refused() {
+        let mut frame = u64::MAX.to_le_bytes().to_vec();
+        frame.extend_from_slice(b"short");
+        assert!(deserialize_frame::<String>(&frame).is_err());
+        assert!(deserialize_frame::<Vec<u64>>(&frame).is_err());
+        assert!(deserialize_frame::<Vec<Vec<u8>>>(&frame).is_err());
+    }
+}
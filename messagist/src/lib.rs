@@ -8,10 +8,15 @@ use serde::{de::DeserializeOwned, Serialize};
 
 #[cfg(feature = "bincode")]
 pub mod bin;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
 #[cfg(feature = "pipes")]
 pub mod pipes;
+pub mod protocol;
 #[cfg(feature = "json")]
 pub mod text;
+#[cfg(feature = "json")]
+pub mod wire;
 
 pub enum Ref<'a, T> {
     Owned(T),
@@ -43,6 +48,8 @@ impl<'a, T> From<&'a T> for Ref<'a, T> {
 pub trait MessageStream: Sized + Send {
     type Error: std::error::Error + Send + Sync + 'static;
 
+    /// Must be cancel safe, as handlers race it against their other events
+    /// with `select!`
     async fn recv_message<T>(&mut self) -> Result<T, Self::Error>
     where
         T: DeserializeOwned + Send + 'static;
@@ -52,6 +59,18 @@ pub trait MessageStream: Sized + Send {
     async fn wait_for_error(&mut self) -> Self::Error;
 }
 
+/// A stream of discrete binary frames, such as a WebSocket
+///
+/// Unlike `MessageStream`, the frames are not interpreted in any way
+#[async_trait]
+pub trait BytesStream: Sized {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    async fn recv_bytes(&mut self) -> Result<Vec<u8>, Self::Error>;
+    async fn send_bytes(&mut self, msg: Vec<u8>) -> Result<(), Self::Error>;
+    async fn wait_for_error(&mut self) -> Self::Error;
+}
+
 #[async_trait]
 pub trait AliasableMessageHandler: Sized {
     type SessionState: Send;
@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{BytesStream, MessageStream};
+
+/// Serializes messages with MessagePack, sending each message as a single frame
+///
+/// Structs are serialized as maps so that field names are preserved, just like json
+pub struct MsgPackMessageStream<T>(T);
+
+#[derive(thiserror::Error, Debug)]
+pub enum BytesMsgPackError<E: std::error::Error> {
+    #[error("BytesError {0}")]
+    BytesError(#[source] E),
+    #[error("DeserializeError {0}")]
+    DeserializeError(rmp_serde::decode::Error),
+}
+
+#[async_trait]
+impl<S: BytesStream<Error: Sync> + Send + Sync> MessageStream for MsgPackMessageStream<S> {
+    type Error = BytesMsgPackError<S::Error>;
+
+    async fn recv_message<T>(&mut self) -> Result<T, Self::Error>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let msg = self
+            .0
+            .recv_bytes()
+            .await
+            .map_err(BytesMsgPackError::BytesError)?;
+        rmp_serde::from_slice(&msg).map_err(BytesMsgPackError::DeserializeError)
+    }
+
+    async fn send_message<T: Serialize + Send + Sync>(
+        &mut self,
+        msg: T,
+    ) -> Result<(), Self::Error> {
+        self.0
+            .send_bytes(rmp_serde::to_vec_named(&msg).unwrap())
+            .await
+            .map_err(BytesMsgPackError::BytesError)
+    }
+
+    async fn wait_for_error(&mut self) -> Self::Error {
+        BytesMsgPackError::BytesError(self.0.wait_for_error().await)
+    }
+}
+
+impl<S> From<S> for MsgPackMessageStream<S> {
+    fn from(value: S) -> Self {
+        Self(value)
+    }
+}
@@ -1,10 +1,20 @@
-use std::{future::Future, io::Error, pin::Pin, task::Poll};
-
-use crate::{bin::BinaryMessageStream, ExclusiveMessageHandler};
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    task::Poll,
+    time::Duration,
+};
+
+use crate::{bin::BinaryMessageStream, AliasableMessageHandler, ExclusiveMessageHandler};
 use async_trait::async_trait;
 use interprocess::local_socket::tokio::{LocalSocketListener, LocalSocketStream};
 pub use interprocess::local_socket::ToLocalSocketName;
-use tokio::{spawn, task::JoinHandle};
+use tokio::{
+    spawn,
+    task::JoinHandle,
+    time::{sleep, timeout, Instant},
+};
 use tokio_util::compat::{Compat, FuturesAsyncWriteCompatExt};
 
 pub type LocalStream = Compat<LocalSocketStream>;
@@ -89,6 +99,42 @@ where
     })
 }
 
+/// Like `start_listener`, but handles every connection in its own task, so
+/// that connections that stay open do not hold up the others
+pub fn start_concurrent_listener<'a, H>(
+    addr: impl ToLocalSocketName<'a>,
+    handler: H,
+) -> Result<ListenerHandle, Error>
+where
+    H: AliasableMessageHandler<SessionState = ()> + Clone + Send + Sync + ListenerErrorHandler,
+{
+    let listener = LocalSocketListener::bind(addr)?;
+
+    Ok(ListenerHandle {
+        handle: spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        handler.handle_error(e).await;
+                        continue;
+                    }
+                };
+
+                let handler = handler.clone();
+                spawn(async move {
+                    AliasableMessageHandler::handle(
+                        &handler,
+                        BinaryMessageStream::from(FuturesAsyncWriteCompatExt::compat_write(stream)),
+                        (),
+                    )
+                    .await;
+                });
+            }
+        }),
+    })
+}
+
 pub async fn start_connection<'a>(
     addr: impl ToLocalSocketName<'a>,
 ) -> Result<BinaryMessageStream<LocalStream>, Error> {
@@ -97,6 +143,75 @@ pub async fn start_connection<'a>(
     ))
 }
 
+/// How `connect_with_retry` should retry failed connection attempts
+#[derive(Clone, Copy, Debug)]
+pub struct RetryConfig {
+    /// The delay after the first failed attempt, which doubles after every attempt
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// The total amount of time to spend trying to connect
+    pub timeout: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectError {
+    #[error("The server is not running ({0})")]
+    NotRunning(Error),
+    #[error("The server is unresponsive")]
+    Unresponsive,
+    #[error("IOError {0}")]
+    IOError(Error),
+}
+
+/// Connects to the given local socket, retrying with exponential backoff
+///
+/// This allows clients to connect to a server that is in the middle of restarting.
+/// If the socket never appears, or nothing ever listens on it, `NotRunning` is returned.
+/// If a connection attempt hangs past the timeout, `Unresponsive` is returned
+pub async fn connect_with_retry<'a>(
+    addr: impl ToLocalSocketName<'a> + Clone,
+    retry: RetryConfig,
+) -> Result<BinaryMessageStream<LocalStream>, ConnectError> {
+    let deadline = Instant::now() + retry.timeout;
+    let mut delay = retry.initial_delay;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        let err = match timeout(remaining, LocalSocketStream::connect(addr.clone())).await {
+            Ok(Ok(stream)) => {
+                return Ok(BinaryMessageStream::from(
+                    FuturesAsyncWriteCompatExt::compat_write(stream),
+                ))
+            }
+            Ok(Err(e)) => e,
+            Err(_) => return Err(ConnectError::Unresponsive),
+        };
+
+        if !matches!(err.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) {
+            return Err(ConnectError::IOError(err));
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ConnectError::NotRunning(err));
+        }
+
+        sleep(delay.min(remaining)).await;
+        delay = (delay * 2).min(retry.max_delay);
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use crate::{ExclusiveMessageHandler, MessageStream};
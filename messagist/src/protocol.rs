@@ -0,0 +1,285 @@
+use std::{
+    fmt::Debug,
+    future::{pending, Future},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    select,
+    time::{sleep_until, Instant},
+};
+
+use crate::MessageStream;
+
+/// What a `Protocol` should do when it spends too long in a state
+pub enum TimeoutAction<St> {
+    /// Sends the given message to the peer, then ends the protocol
+    Close(&'static str),
+    /// Moves to the given state, which starts its own timeout. Whatever timed out
+    /// keeps waiting in the new state
+    Transition(St),
+}
+
+/// A single state in a protocol
+pub trait ProtocolState: Copy + Eq + Debug + Send + Sync {
+    /// How long the protocol may stay in this state, or None if it may stay forever
+    fn timeout(&self) -> Option<Duration>;
+    fn can_transition_to(&self, next: &Self) -> bool;
+    fn on_timeout(&self) -> TimeoutAction<Self>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProtocolError<E: std::error::Error, St: Debug> {
+    #[error("StreamError {0}")]
+    StreamError(#[source] E),
+    #[error("TimedOut while in {0:?}")]
+    TimedOut(St),
+    #[error("InvalidTransition from {from:?} to {to:?}")]
+    InvalidTransition { from: St, to: St },
+}
+
+/// The output of `Protocol::wait_or_recv`
+pub enum Raced<A, B> {
+    Future(A),
+    Message(B),
+}
+
+/// Drives a `MessageStream` through a series of states, each with its own deadline
+///
+/// Every receive or wait is bounded by the deadline of the current state. When the
+/// deadline passes, the `TimeoutAction` of the state is carried out. Closing returns
+/// `ProtocolError::TimedOut` with the state that timed out, while transitioning
+/// carries on with the deadline of the next state
+pub struct Protocol<'a, S: MessageStream, St: ProtocolState> {
+    stream: &'a mut S,
+    state: St,
+    deadline: Option<Instant>,
+}
+
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => pending().await,
+    }
+}
+
+impl<'a, S: MessageStream, St: ProtocolState> Protocol<'a, S, St> {
+    pub fn new(stream: &'a mut S, initial: St) -> Self {
+        Self {
+            stream,
+            state: initial,
+            deadline: initial.timeout().map(|x| Instant::now() + x),
+        }
+    }
+
+    pub fn get_state(&self) -> St {
+        self.state
+    }
+
+    /// Moves to the given state, resetting the deadline
+    pub fn transition(&mut self, next: St) -> Result<(), ProtocolError<S::Error, St>> {
+        if !self.state.can_transition_to(&next) {
+            return Err(ProtocolError::InvalidTransition {
+                from: self.state,
+                to: next,
+            });
+        }
+        self.state = next;
+        self.deadline = next.timeout().map(|x| Instant::now() + x);
+        Ok(())
+    }
+
+    /// Carries out the timeout of the current state, returning an error only if
+    /// the protocol has to end
+    async fn timed_out(&mut self) -> Option<ProtocolError<S::Error, St>> {
+        let state = self.state;
+        match state.on_timeout() {
+            TimeoutAction::Close(msg) => {
+                let _ = self.stream.send_message(msg).await;
+                Some(ProtocolError::TimedOut(state))
+            }
+            TimeoutAction::Transition(next) => {
+                self.state = next;
+                self.deadline = next.timeout().map(|x| Instant::now() + x);
+                None
+            }
+        }
+    }
+
+    pub async fn send<T: Serialize + Send + Sync>(
+        &mut self,
+        msg: T,
+    ) -> Result<(), ProtocolError<S::Error, St>> {
+        self.stream
+            .send_message(msg)
+            .await
+            .map_err(ProtocolError::StreamError)
+    }
+
+    pub async fn recv<T>(&mut self) -> Result<T, ProtocolError<S::Error, St>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        loop {
+            let deadline = self.deadline;
+            let result = select! {
+                res = self.stream.recv_message() => Some(res.map_err(ProtocolError::StreamError)),
+                () = wait_until(deadline) => None,
+            };
+            match result {
+                Some(x) => break x,
+                None => {
+                    if let Some(e) = self.timed_out().await {
+                        break Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits for the given future to complete within the deadline
+    pub async fn wait_for<F: Future>(
+        &mut self,
+        fut: F,
+    ) -> Result<F::Output, ProtocolError<S::Error, St>> {
+        tokio::pin!(fut);
+        loop {
+            let result = select! {
+                out = &mut fut => Some(out),
+                () = wait_until(self.deadline) => None,
+            };
+            match result {
+                Some(x) => break Ok(x),
+                None => {
+                    if let Some(e) = self.timed_out().await {
+                        break Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits for either the given future to complete or a message to be received,
+    /// whichever comes first, within the deadline
+    pub async fn wait_or_recv<F, T>(
+        &mut self,
+        fut: F,
+    ) -> Result<Raced<F::Output, T>, ProtocolError<S::Error, St>>
+    where
+        F: Future,
+        T: DeserializeOwned + Send + 'static,
+    {
+        tokio::pin!(fut);
+        loop {
+            let deadline = self.deadline;
+            let result = select! {
+                out = &mut fut => Some(Ok(Raced::Future(out))),
+                res = self.stream.recv_message() => {
+                    Some(res.map(Raced::Message).map_err(ProtocolError::StreamError))
+                }
+                () = wait_until(deadline) => None,
+            };
+            match result {
+                Some(x) => break x,
+                None => {
+                    if let Some(e) = self.timed_out().await {
+                        break Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    /// Never receives anything, and counts what it sends
+    #[derive(Default)]
+    struct SilentStream {
+        sent: usize,
+    }
+
+    #[async_trait]
+    impl MessageStream for SilentStream {
+        type Error = std::io::Error;
+
+        async fn recv_message<T>(&mut self) -> Result<T, Self::Error>
+        where
+            T: DeserializeOwned + Send + 'static,
+        {
+            pending().await
+        }
+
+        async fn send_message<T: Serialize + Send + Sync>(
+            &mut self,
+            _msg: T,
+        ) -> Result<(), Self::Error> {
+            self.sent += 1;
+            Ok(())
+        }
+
+        async fn wait_for_error(&mut self) -> Self::Error {
+            pending().await
+        }
+    }
+
+    /// Waits for a short while, then gets a longer grace period before closing
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    enum TestState {
+        Waiting,
+        Grace,
+    }
+
+    impl ProtocolState for TestState {
+        fn timeout(&self) -> Option<Duration> {
+            match self {
+                TestState::Waiting => Some(Duration::from_millis(20)),
+                TestState::Grace => Some(Duration::from_millis(300)),
+            }
+        }
+
+        fn can_transition_to(&self, _next: &Self) -> bool {
+            true
+        }
+
+        fn on_timeout(&self) -> TimeoutAction<Self> {
+            match self {
+                TestState::Waiting => TimeoutAction::Transition(TestState::Grace),
+                TestState::Grace => TimeoutAction::Close("Timed Out"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn transition_timeouts_keep_waiting_in_the_next_state() {
+        let mut stream = SilentStream::default();
+        let mut protocol = Protocol::new(&mut stream, TestState::Waiting);
+        let out = protocol
+            .wait_for(async {
+                sleep(Duration::from_millis(100)).await;
+                5
+            })
+            .await;
+        assert!(matches!(out, Ok(5)));
+        assert_eq!(protocol.get_state(), TestState::Grace);
+        assert_eq!(stream.sent, 0);
+    }
+
+    #[tokio::test]
+    async fn close_timeouts_end_the_protocol() {
+        let mut stream = SilentStream::default();
+        let mut protocol = Protocol::new(&mut stream, TestState::Waiting);
+        let out = protocol.recv::<()>().await;
+        assert!(matches!(
+            out,
+            Err(ProtocolError::TimedOut(TestState::Grace))
+        ));
+        assert_eq!(stream.sent, 1);
+    }
+}
@@ -72,7 +72,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> MessageStream
 #[derive(thiserror::Error, Debug)]
 pub enum TextJsonError<E: std::error::Error> {
     #[error("TextError {0}")]
-    TextError(E),
+    TextError(#[source] E),
     #[error("DeserializeError {0}")]
     DeserializeError(serde_json::Error),
 }
@@ -0,0 +1,111 @@
+use std::fmt::Display;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Marks types that are sent through message streams
+///
+/// Each stream format supports a different subset of serde, so a type that
+/// works as json may fail under bincode, such as untagged enums or `u128`.
+/// Implementing this lets a test pass the type to `WireCheck`, which catches
+/// that before the type is ever sent. Nothing checks a type that is never
+/// passed to `WireCheck`, so every implementor should be
+pub trait WireType: Serialize + DeserializeOwned {
+    /// Values that cover the shape of the type, such as one of every variant
+    fn wire_samples() -> Vec<Self>;
+}
+
+#[derive(Debug)]
+pub struct WireFailure {
+    pub type_name: &'static str,
+    pub format: &'static str,
+    /// The index of the sample that failed
+    pub sample: usize,
+    pub error: String,
+}
+
+impl Display for WireFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} sample {} failed under {}: {}",
+            self.type_name, self.sample, self.format, self.error
+        )
+    }
+}
+
+/// Round trips the samples of wire types through every enabled stream format
+///
+/// Decoded samples are compared with the originals through their json form,
+/// unless the type cannot be represented as json in the first place
+#[derive(Default)]
+pub struct WireCheck {
+    checked: usize,
+    failures: Vec<WireFailure>,
+}
+
+impl WireCheck {
+    pub fn check<T: WireType>(mut self) -> Self {
+        let type_name = std::any::type_name::<T>();
+
+        for (index, sample) in T::wire_samples().iter().enumerate() {
+            let expected = serde_json::to_value(sample).ok();
+
+            for (format, result) in round_trips(sample) {
+                let error = match result {
+                    Ok(decoded) => match (&expected, serde_json::to_value(&decoded)) {
+                        (Some(expected), Ok(decoded)) if *expected != decoded => {
+                            Some(format!("Decoded {decoded} instead of {expected}"))
+                        }
+                        _ => None,
+                    },
+                    Err(e) => Some(e),
+                };
+                if let Some(error) = error {
+                    self.failures.push(WireFailure {
+                        type_name,
+                        format,
+                        sample: index,
+                        error,
+                    });
+                }
+            }
+            self.checked += 1;
+        }
+
+        self
+    }
+
+    /// Returns how many samples were checked, or every failure if there were any
+    pub fn finish(self) -> Result<usize, Vec<WireFailure>> {
+        if self.failures.is_empty() {
+            Ok(self.checked)
+        } else {
+            Err(self.failures)
+        }
+    }
+}
+
+fn round_trips<T: WireType>(sample: &T) -> Vec<(&'static str, Result<T, String>)> {
+    #[allow(unused_mut)]
+    let mut results = vec![(
+        "json",
+        serde_json::to_string(sample)
+            .and_then(|x| serde_json::from_str(&x))
+            .map_err(|e| e.to_string()),
+    )];
+    #[cfg(feature = "bincode")]
+    results.push((
+        "bincode",
+        bincode::serialize(sample)
+            .and_then(|x| bincode::deserialize(&x))
+            .map_err(|e| e.to_string()),
+    ));
+    #[cfg(feature = "msgpack")]
+    results.push((
+        "msgpack",
+        rmp_serde::to_vec_named(sample)
+            .map_err(|e| e.to_string())
+            .and_then(|x| rmp_serde::from_slice(&x).map_err(|e| e.to_string())),
+    ));
+    results
+}